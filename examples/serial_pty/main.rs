@@ -0,0 +1,33 @@
+use virtual_usb::{
+    devices::{cdc_acm::CdcAcmBridge, Device},
+    vhci_hcd::load_vhci_hcd,
+};
+
+fn main() {
+    use simple_logger::SimpleLogger;
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    // Ensure the vhci_hcd kernel module is loaded
+    if let Err(e) = load_vhci_hcd() {
+        log::error!("{:?}", e);
+        return;
+    }
+
+    // Create a virtual USB serial adapter bridged to a new PTY
+    let mut bridge = match CdcAcmBridge::to_pty() {
+        Ok(bridge) => bridge,
+        Err(e) => {
+            log::error!("Error opening PTY: {e:?}");
+            return;
+        }
+    };
+    log::info!("Serial adapter bridged to {}", bridge.pty_path().display());
+
+    // Pump data between the PTY and the host until an error occurs
+    if let Err(e) = bridge.run() {
+        log::error!("Error running bridge: {e:?}");
+    }
+}
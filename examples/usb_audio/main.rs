@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+use virtual_usb::{
+    devices::{
+        audio::{AudioFormat, AudioFunction, UacVersion, UsbAudio},
+        Device,
+    },
+    vhci_hcd::load_vhci_hcd,
+};
+
+fn main() {
+    use simple_logger::SimpleLogger;
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    // Ensure the vhci_hcd kernel module is loaded
+    if let Err(e) = load_vhci_hcd() {
+        log::error!("{:?}", e);
+        return;
+    }
+
+    // Create a virtual stereo headset, 16 bits at 48 or 44.1 kHz
+    let format = AudioFormat::new(2, 16, &[48_000, 44_100]);
+    let mut audio = UsbAudio::new(UacVersion::Uac2, AudioFunction::Headset, format);
+    if let Err(e) = audio.start() {
+        log::error!("Error starting audio device: {e:?}");
+        return;
+    }
+
+    // Send the audio played by the host back to it as recorded audio
+    let mut buf = vec![0; 4096];
+    loop {
+        if let Err(e) = audio.poll(Duration::from_millis(1)) {
+            log::error!("Error running audio device: {e:?}");
+            return;
+        }
+        let len = audio.playback().read(&mut buf);
+        audio.capture().write(&buf[..len]);
+    }
+}
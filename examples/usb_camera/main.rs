@@ -0,0 +1,103 @@
+use std::time::{Duration, Instant};
+
+use virtual_usb::{
+    devices::{
+        ptp::{event, MemoryStore, PtpDevice, PtpMode, ROOT_PARENT},
+        Device,
+    },
+    vhci_hcd::load_vhci_hcd,
+};
+
+/// Capacity of the memory card of the camera
+const CAPACITY: u64 = 64 * 1024 * 1024;
+/// Time between two pictures taken by the camera
+const PICTURE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Returns a 24-bit BMP picture of the given size, with a color gradient
+/// shifted by the given offset
+fn picture(width: u32, height: u32, offset: u32) -> Vec<u8> {
+    let row_size = (width * 3).next_multiple_of(4);
+    let size = 54 + row_size * height;
+    let mut data = Vec::with_capacity(size as usize);
+    data.extend_from_slice(b"BM");
+    data.extend_from_slice(&size.to_le_bytes());
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&54u32.to_le_bytes());
+    data.extend_from_slice(&40u32.to_le_bytes());
+    data.extend_from_slice(&width.to_le_bytes());
+    data.extend_from_slice(&height.to_le_bytes());
+    data.extend_from_slice(&1u16.to_le_bytes());
+    data.extend_from_slice(&24u16.to_le_bytes());
+    data.extend_from_slice(&[0; 24]);
+    for y in 0..height {
+        for x in 0..width {
+            data.extend_from_slice(&[(x + offset) as u8, (y + offset) as u8, offset as u8]);
+        }
+        data.resize(data.len() + (row_size - width * 3) as usize, 0);
+    }
+    data
+}
+
+fn main() {
+    use simple_logger::SimpleLogger;
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    // Ensure the vhci_hcd kernel module is loaded
+    if let Err(e) = load_vhci_hcd() {
+        log::error!("{:?}", e);
+        return;
+    }
+
+    // Present an MTP device instead of a camera with `--mtp`
+    let mode = match std::env::args().nth(1).as_deref() {
+        Some("--mtp") => PtpMode::Mtp,
+        _ => PtpMode::Ptp,
+    };
+
+    // Create a virtual camera with a few pictures, e.g. for `gphoto2 -L`
+    let mut store = MemoryStore::new(CAPACITY);
+    let folder = store.add_folder(ROOT_PARENT, "DCIM");
+    for i in 0..3 {
+        store.add_file(
+            folder,
+            &format!("IMG_{i:04}.BMP"),
+            picture(320, 240, i * 64),
+        );
+    }
+    let mut camera = match PtpDevice::new(store, mode) {
+        Ok(camera) => camera,
+        Err(e) => {
+            log::error!("Error creating camera: {e:?}");
+            return;
+        }
+    };
+    if let Err(e) = camera.start() {
+        log::error!("Error starting camera: {e:?}");
+        return;
+    }
+
+    // Take a new picture from time to time
+    let mut next = Instant::now() + PICTURE_INTERVAL;
+    let mut count = 3;
+    loop {
+        if Instant::now() >= next {
+            next += PICTURE_INTERVAL;
+            let name = format!("IMG_{count:04}.BMP");
+            let handle = camera
+                .store_mut()
+                .add_file(folder, &name, picture(320, 240, count * 64));
+            count += 1;
+            log::info!("Took picture {name}");
+            if let Err(e) = camera.send_event(event::OBJECT_ADDED, &[handle]) {
+                log::warn!("Error sending event: {e:?}");
+            }
+        }
+        if let Err(e) = camera.poll(Duration::from_millis(1)) {
+            log::error!("Error running camera: {e:?}");
+            return;
+        }
+    }
+}
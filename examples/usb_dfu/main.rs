@@ -0,0 +1,38 @@
+use virtual_usb::{
+    devices::{
+        dfu::{DfuDevice, DfuMode, FileFirmware, DEFAULT_ATTRIBUTES},
+        Device,
+    },
+    vhci_hcd::load_vhci_hcd,
+};
+
+/// Largest firmware accepted by the device
+const MAX_FIRMWARE_SIZE: usize = 1024 * 1024;
+
+fn main() {
+    use simple_logger::SimpleLogger;
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    // Ensure the vhci_hcd kernel module is loaded
+    if let Err(e) = load_vhci_hcd() {
+        log::error!("{:?}", e);
+        return;
+    }
+
+    // Store the firmware in the file given as argument
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "firmware.bin".to_string());
+    log::info!("Storing firmware in {path}");
+
+    // Create a virtual device in runtime mode, which switches to DFU mode
+    // on DETACH (e.g. `dfu-util -d 1d6b:0104 -e`)
+    let firmware = FileFirmware::new(&path, MAX_FIRMWARE_SIZE);
+    let mut device = DfuDevice::new(firmware, DfuMode::Runtime, DEFAULT_ATTRIBUTES);
+    if let Err(e) = device.run() {
+        log::error!("Error running DFU device: {e:?}");
+    }
+}
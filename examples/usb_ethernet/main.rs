@@ -0,0 +1,44 @@
+use virtual_usb::{
+    devices::{
+        ethernet::{EthernetBridge, TapDevice},
+        Device,
+    },
+    vhci_hcd::load_vhci_hcd,
+};
+
+fn main() {
+    use simple_logger::SimpleLogger;
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    // Ensure the vhci_hcd kernel module is loaded
+    if let Err(e) = load_vhci_hcd() {
+        log::error!("{:?}", e);
+        return;
+    }
+
+    // Open a TAP interface for the device side of the link
+    let tap = match TapDevice::open("usbtap0") {
+        Ok(tap) => tap,
+        Err(e) => {
+            log::error!("Error opening TAP device: {e:?}");
+            return;
+        }
+    };
+    log::info!("Forwarding frames to {}", tap.name());
+
+    // Create a virtual adapter of the function given as argument (NCM by
+    // default). The host side interface will use the given locally
+    // administered MAC address.
+    let mac_address = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+    let mut bridge = match std::env::args().nth(1).as_deref() {
+        Some("ecm") => EthernetBridge::ecm(tap, mac_address),
+        Some("rndis") => EthernetBridge::rndis(tap, mac_address),
+        _ => EthernetBridge::ncm(tap, mac_address),
+    };
+    if let Err(e) = bridge.run() {
+        log::error!("Error running bridge: {e:?}");
+    }
+}
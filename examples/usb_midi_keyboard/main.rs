@@ -0,0 +1,66 @@
+use std::time::{Duration, Instant};
+
+use virtual_usb::{
+    devices::{midi::UsbMidi, Device},
+    vhci_hcd::load_vhci_hcd,
+};
+
+/// Notes of the scale played by the keyboard (C major)
+const SCALE: [u8; 8] = [60, 62, 64, 65, 67, 69, 71, 72];
+/// Duration of each note
+const NOTE_LENGTH: Duration = Duration::from_millis(500);
+
+fn main() {
+    use simple_logger::SimpleLogger;
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    // Ensure the vhci_hcd kernel module is loaded
+    if let Err(e) = load_vhci_hcd() {
+        log::error!("{:?}", e);
+        return;
+    }
+
+    // Create a virtual MIDI keyboard with one port, e.g. for `aseqdump` or
+    // `amidi -d`
+    let mut keyboard = match UsbMidi::new(1) {
+        Ok(keyboard) => keyboard,
+        Err(e) => {
+            log::error!("Error creating MIDI keyboard: {e:?}");
+            return;
+        }
+    };
+    keyboard.on_message(|cable, message| {
+        log::info!("Received on cable {cable}: {message:02x?}");
+    });
+    if let Err(e) = keyboard.start() {
+        log::error!("Error starting MIDI keyboard: {e:?}");
+        return;
+    }
+
+    // Play the scale over and over on channel 1
+    let mut notes = SCALE.iter().cycle();
+    let mut playing: Option<u8> = None;
+    let mut next = Instant::now();
+    loop {
+        if Instant::now() >= next {
+            next += NOTE_LENGTH;
+            let mut bytes = Vec::new();
+            if let Some(note) = playing.take() {
+                bytes.extend_from_slice(&[0x80, note, 0]);
+            }
+            let note = *notes.next().unwrap();
+            bytes.extend_from_slice(&[0x90, note, 100]);
+            playing = Some(note);
+            if let Err(e) = keyboard.send_midi(0, &bytes) {
+                log::warn!("Error sending notes: {e:?}");
+            }
+        }
+        if let Err(e) = keyboard.poll(Duration::from_millis(1)) {
+            log::error!("Error running MIDI keyboard: {e:?}");
+            return;
+        }
+    }
+}
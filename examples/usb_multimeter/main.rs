@@ -0,0 +1,47 @@
+use std::time::{Duration, Instant};
+
+use virtual_usb::{
+    devices::{
+        usbtmc::{Instrument, Multimeter},
+        Device,
+    },
+    vhci_hcd::load_vhci_hcd,
+};
+
+fn main() {
+    use simple_logger::SimpleLogger;
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    // Ensure the vhci_hcd kernel module is loaded
+    if let Err(e) = load_vhci_hcd() {
+        log::error!("{:?}", e);
+        return;
+    }
+
+    // Create a virtual multimeter, e.g. for pyvisa-py or /dev/usbtmc0
+    let mut instrument = match Instrument::new(Multimeter::new()) {
+        Ok(instrument) => instrument,
+        Err(e) => {
+            log::error!("Error creating instrument: {e:?}");
+            return;
+        }
+    };
+    if let Err(e) = instrument.start() {
+        log::error!("Error starting instrument: {e:?}");
+        return;
+    }
+
+    // Measure a slowly varying voltage
+    let start = Instant::now();
+    loop {
+        let voltage = 5.0 + (start.elapsed().as_secs_f64() / 10.0).sin();
+        instrument.handler_mut().set_voltage(voltage);
+        if let Err(e) = instrument.poll(Duration::from_millis(1)) {
+            log::error!("Error running instrument: {e:?}");
+            return;
+        }
+    }
+}
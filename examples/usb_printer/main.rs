@@ -0,0 +1,32 @@
+use virtual_usb::{
+    devices::{
+        printer::{FileSink, Printer, PrinterProtocol, DEFAULT_DEVICE_ID},
+        Device,
+    },
+    vhci_hcd::load_vhci_hcd,
+};
+
+fn main() {
+    use simple_logger::SimpleLogger;
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    // Ensure the vhci_hcd kernel module is loaded
+    if let Err(e) = load_vhci_hcd() {
+        log::error!("{:?}", e);
+        return;
+    }
+
+    // Write jobs to the directory given as argument
+    let dir = std::env::args().nth(1).unwrap_or_else(|| ".".to_string());
+    log::info!("Writing print jobs to {dir}");
+
+    // Create a virtual bidirectional printer
+    let sink = FileSink::new(&dir);
+    let mut printer = Printer::new(sink, PrinterProtocol::Bidirectional, DEFAULT_DEVICE_ID);
+    if let Err(e) = printer.run() {
+        log::error!("Error running printer: {e:?}");
+    }
+}
@@ -0,0 +1,43 @@
+use std::io::BufRead;
+
+use virtual_usb::{
+    devices::{
+        fido::{SecurityKey, SoftAuthenticator},
+        Device,
+    },
+    vhci_hcd::load_vhci_hcd,
+};
+
+fn main() {
+    use simple_logger::SimpleLogger;
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    // Ensure the vhci_hcd kernel module is loaded
+    if let Err(e) = load_vhci_hcd() {
+        log::error!("{:?}", e);
+        return;
+    }
+
+    // Create a virtual security key with credentials kept in memory
+    let mut key = SecurityKey::new(SoftAuthenticator::new());
+
+    // Press enter to touch the key, or pass "--always-present" to confirm
+    // every request without a touch
+    let presence = key.authenticator().presence().clone();
+    if std::env::args().any(|arg| arg == "--always-present") {
+        presence.set_always_present(true);
+    }
+    std::thread::spawn(move || {
+        log::info!("Press enter to touch the key");
+        for _ in std::io::stdin().lock().lines() {
+            presence.touch();
+        }
+    });
+
+    if let Err(e) = key.run() {
+        log::error!("Error running security key: {e:?}");
+    }
+}
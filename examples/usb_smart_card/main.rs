@@ -0,0 +1,55 @@
+use std::io::BufRead;
+
+use virtual_usb::{
+    devices::{
+        smart_card::{CcidReader, MemoryCard},
+        Device,
+    },
+    vhci_hcd::load_vhci_hcd,
+};
+
+/// AID of the application on the test card
+const AID: &[u8] = &[0xf0, 0x76, 0x75, 0x73, 0x62, 0x00, 0x01];
+
+fn main() {
+    use simple_logger::SimpleLogger;
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    // Ensure the vhci_hcd kernel module is loaded
+    if let Err(e) = load_vhci_hcd() {
+        log::error!("{:?}", e);
+        return;
+    }
+
+    // Create a test card with a greeting in file 0x0101
+    let mut card = MemoryCard::new(AID);
+    card.add_file(0x0101, b"Hello from virtual-usb!");
+
+    // Create a virtual reader with the card inserted
+    let mut reader = match CcidReader::new() {
+        Ok(reader) => reader,
+        Err(e) => {
+            log::error!("Error creating reader: {e:?}");
+            return;
+        }
+    };
+    reader.insert(card.clone());
+
+    // Press enter to remove or insert the card
+    let slot = reader.slot().clone();
+    std::thread::spawn(move || {
+        log::info!("Press enter to remove or insert the card");
+        for _ in std::io::stdin().lock().lines() {
+            if slot.remove().is_none() {
+                slot.insert(card.clone());
+            }
+        }
+    });
+
+    if let Err(e) = reader.run() {
+        log::error!("Error running reader: {e:?}");
+    }
+}
@@ -0,0 +1,41 @@
+use virtual_usb::{
+    devices::{
+        mass_storage::{FileImage, MassStorage},
+        Device,
+    },
+    vhci_hcd::load_vhci_hcd,
+};
+
+fn main() {
+    use simple_logger::SimpleLogger;
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    // Ensure the vhci_hcd kernel module is loaded
+    if let Err(e) = load_vhci_hcd() {
+        log::error!("{:?}", e);
+        return;
+    }
+
+    // Open the disk image given as argument
+    let Some(path) = std::env::args().nth(1) else {
+        log::error!("Usage: usb_stick <disk image>");
+        return;
+    };
+    let image = match FileImage::open(&path) {
+        Ok(image) => image,
+        Err(e) => {
+            log::error!("Error opening disk image {path}: {e:?}");
+            return;
+        }
+    };
+    log::info!("Serving {path}");
+
+    // Create a virtual USB flash drive backed by the image
+    let mut stick = MassStorage::new(image);
+    if let Err(e) = stick.run() {
+        log::error!("Error running drive: {e:?}");
+    }
+}
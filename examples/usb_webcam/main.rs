@@ -0,0 +1,34 @@
+use virtual_usb::{
+    devices::{
+        webcam::{default_formats, TestPattern, Transport, Webcam},
+        Device,
+    },
+    vhci_hcd::load_vhci_hcd,
+};
+
+fn main() {
+    use simple_logger::SimpleLogger;
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    // Ensure the vhci_hcd kernel module is loaded
+    if let Err(e) = load_vhci_hcd() {
+        log::error!("{:?}", e);
+        return;
+    }
+
+    // Stream over an isochronous endpoint instead of a bulk one with
+    // `--isochronous`
+    let transport = match std::env::args().nth(1).as_deref() {
+        Some("--isochronous") => Transport::Isochronous,
+        _ => Transport::Bulk,
+    };
+
+    // Create a virtual webcam streaming moving color bars
+    let mut webcam = Webcam::with_transport(TestPattern::new(), default_formats(), transport);
+    if let Err(e) = webcam.run() {
+        log::error!("Error running webcam: {e:?}");
+    }
+}
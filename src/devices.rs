@@ -0,0 +1,83 @@
+//! Emulation of specific real-world USB devices
+
+use std::{
+    error::Error,
+    io::{self, Read},
+    time::Duration,
+};
+
+use crate::virtual_usb::{BulkOut, VirtualUSBDevice};
+
+#[cfg(feature = "audio")]
+pub mod audio;
+#[cfg(feature = "cdc-acm")]
+pub mod cdc_acm;
+#[cfg(feature = "dfu")]
+pub mod dfu;
+#[cfg(feature = "ethernet")]
+pub mod ethernet;
+#[cfg(feature = "fido")]
+pub mod fido;
+#[cfg(feature = "mass-storage")]
+pub mod mass_storage;
+#[cfg(feature = "midi")]
+pub mod midi;
+#[cfg(feature = "printer")]
+pub mod printer;
+#[cfg(feature = "ptp")]
+pub mod ptp;
+#[cfg(feature = "smart-card")]
+pub mod smart_card;
+#[cfg(feature = "steam-deck")]
+pub mod steam_deck;
+#[cfg(feature = "usbtmc")]
+pub mod usbtmc;
+#[cfg(feature = "webcam")]
+pub mod webcam;
+
+/// Time [Device::run] waits between checks for USB transfers
+pub const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Largest bulk packet at high speed, read at once from bulk OUT endpoints
+const MAX_PACKET_SIZE: usize = 512;
+
+/// A device emulated on top of a [VirtualUSBDevice], which answers the USB
+/// transfers and host requests of the device when polled
+pub trait Device {
+    /// The virtual USB device
+    fn device(&self) -> &VirtualUSBDevice;
+
+    /// Mutable access to the virtual USB device, e.g. to stop it or queue
+    /// interrupt reports
+    fn device_mut(&mut self) -> &mut VirtualUSBDevice;
+
+    /// Handle pending USB transfers and host requests. Waits up to the given
+    /// timeout if there was nothing to handle.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>>;
+
+    /// Attach the device to the host
+    fn start(&mut self) -> Result<(), Box<dyn Error>> {
+        self.device_mut().start()
+    }
+
+    /// Attach the device and poll it until an error occurs
+    fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        self.start()?;
+        loop {
+            self.poll(POLL_INTERVAL)?;
+        }
+    }
+}
+
+/// Append the data received so far on the given bulk OUT endpoint to the
+/// buffer, without waiting for more
+pub fn read_available(bulk_out: &mut BulkOut, data: &mut Vec<u8>) -> io::Result<()> {
+    let mut buf = [0; MAX_PACKET_SIZE];
+    loop {
+        match bulk_out.read(&mut buf) {
+            Ok(len) => data.extend_from_slice(&buf[..len]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
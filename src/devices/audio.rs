@@ -0,0 +1,472 @@
+//! USB sound card exchanging PCM audio through [AudioRing] buffers
+//!
+//! Emulates a UAC 1.0 or 2.0 speaker, microphone or headset, which the host
+//! binds with the snd-usb-audio driver and exposes as an ALSA card. PCM
+//! data played by the host is written to the playback ring, and the data
+//! recorded by the host is read from the capture ring. The rings can be
+//! cloned and shared with other threads producing or consuming the audio.
+//!
+//! Muted streams carry silence. The volume set by the host is only reported
+//! through [UsbAudio::playback_status] and [UsbAudio::capture_status], and
+//! is left for the consumer to apply.
+//!
+//! Audio is exchanged over isochronous endpoints. Transfers are completed in
+//! real time, one packet per millisecond, so the host plays and records at
+//! the selected sample rate.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+pub use crate::usb::uac::{AudioFormat, UacEvent, UacVersion};
+use crate::{
+    devices::Device,
+    usb::{
+        uac::{UacControlInterfaceBuilder, UacStreamingInterfaceBuilder},
+        ConfigurationBuilder, DeviceClass, Direction, LangId,
+    },
+    usbip::UsbIpDirection,
+    virtual_usb::{IsoXfer, Reply, VirtualUSBDevice, VirtualUSBDeviceBuilder, Xfer},
+};
+
+/// Vendor ID of the sound card (Linux Foundation)
+pub const VENDOR_ID: u16 = 0x1d6b;
+/// Product ID of the sound card (Linux-USB Audio Gadget)
+pub const PRODUCT_ID: u16 = 0x0101;
+/// Number of the isochronous OUT endpoint of the playback stream
+pub const PLAYBACK_ENDPOINT: u8 = 1;
+/// Number of the isochronous IN endpoint of the capture stream
+pub const CAPTURE_ENDPOINT: u8 = 2;
+/// Default length of audio the rings can hold
+pub const DEFAULT_BUFFER_TIME: Duration = Duration::from_millis(200);
+/// Service interval of the isochronous endpoints
+const PACKET_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Streams of the sound card
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AudioFunction {
+    /// Playback stream only
+    Speaker,
+    /// Capture stream only
+    Microphone,
+    /// Playback and capture streams
+    Headset,
+}
+
+impl AudioFunction {
+    /// Returns the directions of the streams, playback first
+    fn directions(&self) -> &'static [Direction] {
+        match self {
+            AudioFunction::Speaker => &[Direction::Out],
+            AudioFunction::Microphone => &[Direction::In],
+            AudioFunction::Headset => &[Direction::Out, Direction::In],
+        }
+    }
+}
+
+/// Bounded buffer of PCM bytes shared between the sound card and the code
+/// producing or consuming the audio
+#[derive(Debug, Clone)]
+pub struct AudioRing {
+    buffer: Arc<Mutex<VecDeque<u8>>>,
+    capacity: usize,
+}
+
+impl AudioRing {
+    /// Create a ring holding up to the given number of bytes
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffer: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Returns the number of bytes the ring can hold
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of bytes in the ring
+    pub fn len(&self) -> usize {
+        self.buffer.lock().map(|buffer| buffer.len()).unwrap_or(0)
+    }
+
+    /// Returns true if the ring holds no data
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Append as much of the given data as fits in the ring. Returns the
+    /// number of bytes written.
+    pub fn write(&self, data: &[u8]) -> usize {
+        let Ok(mut buffer) = self.buffer.lock() else {
+            return 0;
+        };
+        let len = data.len().min(self.capacity - buffer.len());
+        buffer.extend(&data[..len]);
+        len
+    }
+
+    /// Remove data from the ring into the given buffer. Returns the number
+    /// of bytes read.
+    pub fn read(&self, data: &mut [u8]) -> usize {
+        let Ok(mut buffer) = self.buffer.lock() else {
+            return 0;
+        };
+        let len = data.len().min(buffer.len());
+        for (dst, src) in data.iter_mut().zip(buffer.drain(..len)) {
+            *dst = src;
+        }
+        len
+    }
+
+    /// Discard the data in the ring
+    pub fn clear(&self) {
+        if let Ok(mut buffer) = self.buffer.lock() {
+            buffer.clear();
+        }
+    }
+}
+
+/// State of a stream as set by the host
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StreamStatus {
+    /// Whether the host selected the streaming alternate setting
+    pub streaming: bool,
+    pub sample_rate: u32,
+    pub mute: bool,
+    /// Volume in 1/256 dB
+    pub volume: i16,
+}
+
+/// Timing of the packets of an isochronous stream
+#[derive(Debug, Copy, Clone)]
+struct StreamClock {
+    /// Time the next transfer is due
+    next: Instant,
+    /// Fraction of a frame carried over between packets, in 1/1000 frames
+    remainder: u32,
+}
+
+impl StreamClock {
+    fn new() -> Self {
+        Self {
+            next: Instant::now(),
+            remainder: 0,
+        }
+    }
+
+    /// Returns the number of frames of the next packet at the given sample
+    /// rate, spreading fractional rates such as 44.1 kHz over packets
+    fn packet_frames(&mut self, sample_rate: u32) -> usize {
+        let frames = sample_rate + self.remainder;
+        self.remainder = frames % 1000;
+        (frames / 1000) as usize
+    }
+
+    /// Returns true if a transfer with the given number of packets is due,
+    /// and schedules the next one
+    fn advance(&mut self, packets: usize) -> bool {
+        let now = Instant::now();
+        if now < self.next {
+            return false;
+        }
+        // Restart from now after a stall instead of catching up in a burst
+        let next = self.next + PACKET_INTERVAL * packets as u32;
+        self.next = if next < now { now } else { next };
+        true
+    }
+}
+
+/// Virtual USB sound card exchanging PCM audio through [AudioRing] buffers
+#[derive(Debug)]
+pub struct UsbAudio {
+    device: VirtualUSBDevice,
+    function: AudioFunction,
+    format: AudioFormat,
+    events: Receiver<UacEvent>,
+    /// Audio played by the host
+    playback: AudioRing,
+    /// Audio recorded by the host
+    capture: AudioRing,
+    playback_status: StreamStatus,
+    capture_status: StreamStatus,
+    /// Isochronous OUT transfers waiting to be consumed
+    pending_out: VecDeque<IsoXfer>,
+    /// Isochronous IN transfers waiting for audio
+    pending_in: VecDeque<IsoXfer>,
+    playback_clock: StreamClock,
+    capture_clock: StreamClock,
+}
+
+impl UsbAudio {
+    /// Create a sound card with the given class version, streams and PCM
+    /// format, used by all streams
+    pub fn new(version: UacVersion, function: AudioFunction, format: AudioFormat) -> Self {
+        let (tx, rx) = channel();
+
+        let mut control = UacControlInterfaceBuilder::new(version);
+        let control_tx = tx.clone();
+        control.on_event(move |event| {
+            let _ = control_tx.send(event);
+        });
+        let mut config = ConfigurationBuilder::new();
+        config.max_power(100);
+        let mut streams = Vec::new();
+        for direction in function.directions() {
+            control.stream(*direction, format.clone());
+            let ep = match direction {
+                Direction::Out => PLAYBACK_ENDPOINT,
+                Direction::In => CAPTURE_ENDPOINT,
+            };
+            let tx = tx.clone();
+            let stream = UacStreamingInterfaceBuilder::new(version, *direction, format.clone())
+                .isochronous_endpoint(ep)
+                .on_event(move |event| {
+                    let _ = tx.send(event);
+                })
+                .build();
+            streams.push(stream);
+        }
+        config.interface(control.build());
+        for stream in streams {
+            config.interface(stream);
+        }
+
+        // Audio functions use an interface association
+        let device = VirtualUSBDeviceBuilder::new(VENDOR_ID, PRODUCT_ID)
+            .class(DeviceClass::Miscellaneous)
+            .subclass(0x02)
+            .protocol(0x01)
+            .supported_langs(vec![LangId::EnglishUnitedStates])
+            .manufacturer("Linux")
+            .product("Audio Gadget")
+            .max_packet_size(64)
+            .configuration(config.build())
+            .build();
+
+        let status = StreamStatus {
+            streaming: false,
+            sample_rate: format.default_sample_rate(),
+            mute: false,
+            volume: 0,
+        };
+        let capacity = Self::buffer_size(&format, DEFAULT_BUFFER_TIME);
+
+        Self {
+            device,
+            function,
+            format,
+            events: rx,
+            playback: AudioRing::new(capacity),
+            capture: AudioRing::new(capacity),
+            playback_status: status,
+            capture_status: status,
+            pending_out: VecDeque::new(),
+            pending_in: VecDeque::new(),
+            playback_clock: StreamClock::new(),
+            capture_clock: StreamClock::new(),
+        }
+    }
+
+    /// Returns the number of bytes of the given length of audio at the
+    /// highest sample rate of the format
+    fn buffer_size(format: &AudioFormat, time: Duration) -> usize {
+        let max_rate = format.sample_rates.iter().copied().max().unwrap_or(48_000);
+        let frames = (max_rate as u128 * time.as_micros() / 1_000_000) as usize;
+        frames * format.frame_size()
+    }
+
+    /// Replace the rings with rings holding the given length of audio
+    pub fn set_buffer_time(&mut self, time: Duration) {
+        let capacity = Self::buffer_size(&self.format, time);
+        self.playback = AudioRing::new(capacity);
+        self.capture = AudioRing::new(capacity);
+    }
+
+    /// The streams of the sound card
+    pub fn function(&self) -> AudioFunction {
+        self.function
+    }
+
+    /// The PCM format of the streams
+    pub fn format(&self) -> &AudioFormat {
+        &self.format
+    }
+
+    /// The ring receiving the audio played by the host
+    pub fn playback(&self) -> &AudioRing {
+        &self.playback
+    }
+
+    /// The ring providing the audio recorded by the host
+    pub fn capture(&self) -> &AudioRing {
+        &self.capture
+    }
+
+    /// The state of the playback stream
+    pub fn playback_status(&self) -> StreamStatus {
+        self.playback_status
+    }
+
+    /// The state of the capture stream
+    pub fn capture_status(&self) -> StreamStatus {
+        self.capture_status
+    }
+
+    /// Apply a state change requested by the host
+    fn handle_event(&mut self, event: UacEvent) {
+        #[cfg(feature = "log")]
+        log::info!("{event:?}");
+        let (UacEvent::SampleRate(direction, _)
+        | UacEvent::Mute(direction, _)
+        | UacEvent::Volume(direction, _)
+        | UacEvent::Streaming(direction, _)) = event;
+        let status = self.status_mut(direction);
+        match event {
+            UacEvent::SampleRate(_, rate) => status.sample_rate = rate,
+            UacEvent::Mute(_, mute) => status.mute = mute,
+            UacEvent::Volume(_, volume) => status.volume = volume,
+            UacEvent::Streaming(_, streaming) => status.streaming = streaming,
+        }
+        // Start streams without stale audio
+        if let UacEvent::Streaming(_, true) = event {
+            match direction {
+                Direction::Out => {
+                    self.playback.clear();
+                    self.playback_clock = StreamClock::new();
+                }
+                Direction::In => {
+                    self.capture.clear();
+                    self.capture_clock = StreamClock::new();
+                }
+            }
+        }
+    }
+
+    fn status_mut(&mut self, direction: Direction) -> &mut StreamStatus {
+        match direction {
+            Direction::Out => &mut self.playback_status,
+            Direction::In => &mut self.capture_status,
+        }
+    }
+
+    /// Handle a transfer that was not handled by the device itself
+    fn handle_xfer(&mut self, xfer: Xfer) -> Result<(), Box<dyn Error>> {
+        let xfer = match xfer.into_iso() {
+            Ok(xfer) => {
+                match (xfer.direction(), xfer.ep()) {
+                    (UsbIpDirection::Out, PLAYBACK_ENDPOINT) => self.pending_out.push_back(xfer),
+                    (UsbIpDirection::In, CAPTURE_ENDPOINT) => self.pending_in.push_back(xfer),
+                    (_direction, _ep) => {
+                        #[cfg(feature = "log")]
+                        log::debug!("Ignoring {_direction:?} transfer on endpoint {_ep}");
+                    }
+                }
+                return Ok(());
+            }
+            Err(xfer) => xfer,
+        };
+        #[cfg(feature = "log")]
+        log::debug!(
+            "Ignoring non-isochronous {:?} transfer on endpoint {}",
+            xfer.direction(),
+            xfer.ep
+        );
+        if xfer.direction() == UsbIpDirection::In {
+            self.device.write(Reply::from_xfer(xfer, &[]))?;
+        }
+        Ok(())
+    }
+
+    /// Consume the playback transfers that are due into the playback ring
+    fn complete_playback(&mut self) -> Result<(), Box<dyn Error>> {
+        self.pending_out
+            .retain(|xfer| !self.device.is_unlinked(xfer.seqnum()));
+        while let Some(xfer) = self.pending_out.front() {
+            if !self.playback_clock.advance(xfer.packets().len()) {
+                break;
+            }
+            let Some(xfer) = self.pending_out.pop_front() else {
+                break;
+            };
+            for i in 0..xfer.packets().len() {
+                let data = xfer.packet_data(i).unwrap_or_default();
+                let _written = if self.playback_status.mute {
+                    self.playback.write(&vec![0; data.len()])
+                } else {
+                    self.playback.write(data)
+                };
+                #[cfg(feature = "log")]
+                if _written < data.len() {
+                    log::trace!("Playback overrun, dropped {} bytes", data.len() - _written);
+                }
+            }
+            self.device.write(Reply::from_iso_xfer(xfer, &[]))?;
+        }
+        Ok(())
+    }
+
+    /// Answer the capture transfers that are due with audio from the
+    /// capture ring, padded with silence on underrun
+    fn complete_capture(&mut self) -> Result<(), Box<dyn Error>> {
+        self.pending_in
+            .retain(|xfer| !self.device.is_unlinked(xfer.seqnum()));
+        let frame_size = self.format.frame_size().max(1);
+        while let Some(xfer) = self.pending_in.front() {
+            if !self.capture_clock.advance(xfer.packets().len()) {
+                break;
+            }
+            let Some(xfer) = self.pending_in.pop_front() else {
+                break;
+            };
+            let mut packets = Vec::with_capacity(xfer.packets().len());
+            for packet in xfer.packets() {
+                let frames = self
+                    .capture_clock
+                    .packet_frames(self.capture_status.sample_rate);
+                let len = (frames * frame_size).min(packet.length / frame_size * frame_size);
+                let mut data = vec![0; len];
+                if !self.capture_status.mute {
+                    self.capture.read(&mut data);
+                }
+                packets.push(data);
+            }
+            let packets: Vec<&[u8]> = packets.iter().map(Vec::as_slice).collect();
+            self.device.write(Reply::from_iso_xfer(xfer, &packets))?;
+        }
+        Ok(())
+    }
+}
+
+impl Device for UsbAudio {
+    fn device(&self) -> &VirtualUSBDevice {
+        &self.device
+    }
+
+    fn device_mut(&mut self) -> &mut VirtualUSBDevice {
+        &mut self.device
+    }
+
+    /// Handle the next pending USB transfer or host request, if any, and
+    /// complete the isochronous transfers that are due. Waits up to the
+    /// given timeout if there was nothing to handle.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        match self.device.read()? {
+            Some(xfer) => self.handle_xfer(xfer)?,
+            None => thread::sleep(timeout),
+        }
+        while let Ok(event) = self.events.try_recv() {
+            self.handle_event(event);
+        }
+        self.complete_playback()?;
+        self.complete_capture()
+    }
+}
@@ -0,0 +1,420 @@
+//! CDC-ACM serial adapter bridged to a pseudo-terminal
+//!
+//! Emulates a USB serial adapter whose data is read from and written to the
+//! master side of a PTY pair. Any program that opens the slave side of the
+//! PTY (see [CdcAcmBridge::pty_path]) then talks to the host as if it were
+//! connected to a real serial port, e.g. /dev/ttyACM0.
+//!
+//! Line coding changes requested by the host are applied to the termios of
+//! the slave side, so the program can observe the baud rate with
+//! tcgetattr(). Linux PTYs always use 8 data bits without parity, so only the
+//! baud rate and stop bits are visible there. DTR and RTS are mirrored to the
+//! modem lines of the PTY where the kernel supports it.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    ffi::CStr,
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, RawFd},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver},
+    time::Duration,
+};
+
+use crate::{
+    devices::Device,
+    usb::{
+        cdc::{
+            CdcDataInterfaceBuilder, CdcEvent, CdcInterfaceBuilder, LineCoding, Parity, StopBits,
+        },
+        ConfigurationBuilder, DeviceClass, LangId,
+    },
+    usbip::UsbIpDirection,
+    virtual_usb::{Reply, VirtualUSBDevice, VirtualUSBDeviceBuilder, Xfer},
+};
+
+/// Vendor ID of the serial adapter (NetChip, used by the Linux serial gadget)
+pub const VENDOR_ID: u16 = 0x0525;
+/// Product ID of the serial adapter (Gadget Serial)
+pub const PRODUCT_ID: u16 = 0xa4a7;
+/// Interface number of the communication interface
+pub const COMM_INTERFACE: u8 = 0;
+/// Number of the interrupt IN notification endpoint
+pub const NOTIFICATION_ENDPOINT: u8 = 2;
+/// Number of the bulk IN and bulk OUT data endpoints
+pub const DATA_ENDPOINT: u8 = 1;
+/// Max packet size of the bulk endpoints (high speed)
+const MAX_PACKET_SIZE: u16 = 512;
+/// Maximum number of bytes sent by the host that are buffered while the PTY
+/// is not being read. Any more data is dropped, like a real UART would.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+/// Virtual CDC-ACM serial adapter that pumps data between its bulk
+/// endpoints and a local pseudo-terminal.
+#[derive(Debug)]
+pub struct CdcAcmBridge {
+    device: VirtualUSBDevice,
+    pty: Pty,
+    events: Receiver<CdcEvent>,
+    /// Bulk IN transfers waiting for data from the PTY
+    pending_in: VecDeque<Xfer>,
+    /// Data from the host waiting to be written to the PTY
+    pending_out: VecDeque<u8>,
+}
+
+impl CdcAcmBridge {
+    /// Open a new pseudo-terminal pair and create a virtual serial adapter
+    /// bridged to it. The device is attached to the host with
+    /// [CdcAcmBridge::start] or [CdcAcmBridge::run].
+    pub fn to_pty() -> Result<Self, Box<dyn Error>> {
+        let pty = Pty::open()?;
+        let (tx, rx) = channel();
+
+        let device = VirtualUSBDeviceBuilder::new(VENDOR_ID, PRODUCT_ID)
+            .class(DeviceClass::Cdc)
+            .supported_langs(vec![LangId::EnglishUnitedStates])
+            .manufacturer("Linux")
+            .product("Gadget Serial")
+            .max_packet_size(64)
+            .configuration(
+                ConfigurationBuilder::new()
+                    .max_power(100)
+                    .interface(
+                        CdcInterfaceBuilder::new()
+                            .notification_endpoint(NOTIFICATION_ENDPOINT)
+                            .on_event(move |event| {
+                                let _ = tx.send(event);
+                            })
+                            .build(),
+                    )
+                    .interface(
+                        CdcDataInterfaceBuilder::new()
+                            .bulk_endpoints(DATA_ENDPOINT, DATA_ENDPOINT, MAX_PACKET_SIZE)
+                            .build(),
+                    )
+                    .build(),
+            )
+            .build();
+
+        Ok(Self {
+            device,
+            pty,
+            events: rx,
+            pending_in: VecDeque::new(),
+            pending_out: VecDeque::new(),
+        })
+    }
+
+    /// Path of the slave side of the PTY (e.g. /dev/pts/3) that serial
+    /// programs should open
+    pub fn pty_path(&self) -> &Path {
+        self.pty.path.as_path()
+    }
+
+    /// Notify the host of the given UART state (see
+    /// [crate::usb::cdc::serial_state])
+    pub fn send_serial_state(&mut self, state: u16) -> Result<(), Box<dyn Error>> {
+        self.device.send_serial_state(COMM_INTERFACE, state)
+    }
+
+    /// Handle a transfer that was not handled by the device itself
+    fn handle_xfer(&mut self, xfer: Xfer) {
+        match (xfer.direction(), xfer.ep) {
+            (UsbIpDirection::In, DATA_ENDPOINT) => self.pending_in.push_back(xfer),
+            (UsbIpDirection::Out, DATA_ENDPOINT) => {
+                let space = MAX_BUFFERED.saturating_sub(self.pending_out.len());
+                if xfer.data.len() > space {
+                    #[cfg(feature = "log")]
+                    log::warn!("PTY is not being read, dropping data from host");
+                }
+                let len = xfer.data.len().min(space);
+                self.pending_out.extend(&xfer.data[..len]);
+            }
+            (_direction, _ep) => {
+                #[cfg(feature = "log")]
+                log::debug!("Ignoring {_direction:?} transfer on endpoint {_ep}");
+            }
+        }
+    }
+
+    /// Mirror the serial line state requested by the host to the PTY
+    fn handle_event(&mut self, event: CdcEvent) {
+        let result = match event {
+            CdcEvent::LineCoding(line_coding) => self.pty.set_line_coding(&line_coding),
+            CdcEvent::ControlLineState { dtr, rts } => self.pty.set_modem_lines(dtr, rts),
+            _ => Ok(()),
+        };
+        if let Err(_e) = result {
+            #[cfg(feature = "log")]
+            log::debug!("Unable to apply {event:?} to PTY: {_e}");
+        }
+    }
+
+    /// Write data sent by the host to the PTY
+    fn write_pty(&mut self) -> Result<(), Box<dyn Error>> {
+        let (data, _) = self.pending_out.as_slices();
+        match self.pty.master.write(data) {
+            Ok(len) => {
+                self.pending_out.drain(..len);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+
+    /// Reply to the oldest bulk IN transfer with data from the PTY
+    fn read_pty(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(xfer) = self.pending_in.front() else {
+            return Ok(());
+        };
+        let mut data = vec![0; xfer.buffer_length()];
+        let len = match self.pty.master.read(&mut data) {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(xfer) = self.pending_in.pop_front() {
+            self.device.write(Reply::from_xfer(xfer, &data[..len]))?;
+        }
+        Ok(())
+    }
+}
+
+impl Device for CdcAcmBridge {
+    fn device(&self) -> &VirtualUSBDevice {
+        &self.device
+    }
+
+    fn device_mut(&mut self) -> &mut VirtualUSBDevice {
+        &mut self.device
+    }
+
+    /// Handle pending USB transfers and host requests, then wait up to the
+    /// given timeout for data from the PTY and forward it to the host.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        if let Some(xfer) = self.device.read()? {
+            self.handle_xfer(xfer);
+        }
+        while let Ok(event) = self.events.try_recv() {
+            self.handle_event(event);
+        }
+
+        // Wait for the PTY only when there is something to transfer
+        self.pending_in
+            .retain(|xfer| !self.device.is_unlinked(xfer.seqnum()));
+        let readable = !self.pending_in.is_empty();
+        let writable = !self.pending_out.is_empty();
+        let (readable, writable) = self.pty.poll(readable, writable, timeout)?;
+
+        if writable {
+            self.write_pty()?;
+        }
+        if readable {
+            self.read_pty()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Pseudo-terminal pair. The slave side is kept open so the master can be
+/// read without errors while no program has the PTY open.
+#[derive(Debug)]
+struct Pty {
+    master: File,
+    slave: File,
+    path: PathBuf,
+}
+
+impl Pty {
+    /// Open a new PTY pair in raw mode
+    fn open() -> io::Result<Self> {
+        // SAFETY: the returned descriptors are checked and owned by File
+        let master = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            master
+        };
+
+        let mut name = [0 as libc::c_char; 128];
+        // SAFETY: the buffer is valid for its whole length
+        if unsafe { libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: ptsname_r wrote a nul terminated string into the buffer
+        let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+        let path = PathBuf::from(name.to_string_lossy().into_owned());
+
+        // SAFETY: the returned descriptor is checked and owned by File
+        let slave = unsafe {
+            let fd = libc::open(name.as_ptr(), libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            File::from_raw_fd(fd)
+        };
+
+        // Pass data through unmodified
+        let mut termios = get_termios(slave.as_raw_fd())?;
+        // SAFETY: termios is a valid termios struct
+        unsafe { libc::cfmakeraw(&mut termios) };
+        set_termios(slave.as_raw_fd(), &termios)?;
+
+        Ok(Self {
+            master,
+            slave,
+            path,
+        })
+    }
+
+    /// Wait until the master side is readable or writable. Returns whether
+    /// it is (readable, writable).
+    fn poll(&self, read: bool, write: bool, timeout: Duration) -> io::Result<(bool, bool)> {
+        let mut events = 0;
+        if read {
+            events |= libc::POLLIN;
+        }
+        if write {
+            events |= libc::POLLOUT;
+        }
+        let mut fds = [libc::pollfd {
+            fd: self.master.as_raw_fd(),
+            events,
+            revents: 0,
+        }];
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        // SAFETY: fds is a valid array of one pollfd
+        if unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok((false, false));
+            }
+            return Err(e);
+        }
+        let revents = fds[0].revents;
+        Ok((
+            read && revents & libc::POLLIN != 0,
+            write && revents & libc::POLLOUT != 0,
+        ))
+    }
+
+    /// Apply the given line coding to the termios of the slave side
+    fn set_line_coding(&self, line_coding: &LineCoding) -> io::Result<()> {
+        let fd = self.slave.as_raw_fd();
+        let mut termios = get_termios(fd)?;
+
+        if let Some(speed) = baud_rate(line_coding.rate()) {
+            // SAFETY: termios is a valid termios struct
+            unsafe {
+                libc::cfsetispeed(&mut termios, speed);
+                libc::cfsetospeed(&mut termios, speed);
+            }
+        }
+
+        termios.c_cflag &=
+            !(libc::CSIZE | libc::PARENB | libc::PARODD | libc::CMSPAR | libc::CSTOPB);
+        termios.c_cflag |= match line_coding.data_bits() {
+            5 => libc::CS5,
+            6 => libc::CS6,
+            7 => libc::CS7,
+            _ => libc::CS8,
+        };
+        termios.c_cflag |= match line_coding.parity() {
+            Some(Parity::Odd) => libc::PARENB | libc::PARODD,
+            Some(Parity::Even) => libc::PARENB,
+            Some(Parity::Mark) => libc::PARENB | libc::CMSPAR | libc::PARODD,
+            Some(Parity::Space) => libc::PARENB | libc::CMSPAR,
+            Some(Parity::None) | None => 0,
+        };
+        if matches!(
+            line_coding.stop_bits(),
+            Some(StopBits::OneAndHalf) | Some(StopBits::Two)
+        ) {
+            termios.c_cflag |= libc::CSTOPB;
+        }
+
+        set_termios(fd, &termios)
+    }
+
+    /// Set the DTR and RTS modem lines of the PTY. Linux does not support
+    /// modem lines on PTYs, in which case an error is returned.
+    fn set_modem_lines(&self, dtr: bool, rts: bool) -> io::Result<()> {
+        let fd = self.master.as_raw_fd();
+        for (line, enabled) in [(libc::TIOCM_DTR, dtr), (libc::TIOCM_RTS, rts)] {
+            let request = if enabled {
+                libc::TIOCMBIS
+            } else {
+                libc::TIOCMBIC
+            };
+            // SAFETY: the request takes a pointer to an int
+            if unsafe { libc::ioctl(fd, request, &line) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn get_termios(fd: RawFd) -> io::Result<libc::termios> {
+    // SAFETY: termios is plain data and is filled in by tcgetattr
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(termios)
+}
+
+fn set_termios(fd: RawFd, termios: &libc::termios) -> io::Result<()> {
+    // SAFETY: termios is a valid termios struct
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns the termios speed for the given baud rate, if it is a standard rate
+fn baud_rate(rate: u32) -> Option<libc::speed_t> {
+    let speed = match rate {
+        50 => libc::B50,
+        75 => libc::B75,
+        110 => libc::B110,
+        134 => libc::B134,
+        150 => libc::B150,
+        200 => libc::B200,
+        300 => libc::B300,
+        600 => libc::B600,
+        1200 => libc::B1200,
+        1800 => libc::B1800,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        460800 => libc::B460800,
+        500000 => libc::B500000,
+        576000 => libc::B576000,
+        921600 => libc::B921600,
+        1000000 => libc::B1000000,
+        1152000 => libc::B1152000,
+        1500000 => libc::B1500000,
+        2000000 => libc::B2000000,
+        2500000 => libc::B2500000,
+        3000000 => libc::B3000000,
+        3500000 => libc::B3500000,
+        4000000 => libc::B4000000,
+        _ => return None,
+    };
+    Some(speed)
+}
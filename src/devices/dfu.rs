@@ -0,0 +1,327 @@
+//! DFU target receiving firmware in a [FirmwareSink]
+//!
+//! Emulates a device implementing DFU 1.1, which the host flashes with tools
+//! like `dfu-util`. The device starts with a runtime mode interface, and
+//! re-enumerates with the DFU mode descriptor set when the host sends
+//! DETACH. Downloaded firmware is delivered to a [FirmwareSink], such as a
+//! [FileFirmware] writing the image to a file, or a [MemoryFirmware] keeping
+//! it for tests.
+//!
+//! The virtual hub never forwards USB resets to the device, so the device
+//! re-enumerates shortly after DETACH whether or not it has the
+//! [attributes::WILL_DETACH] attribute. Devices that are not manifestation
+//! tolerant stay in the dfuMANIFEST-WAIT-RESET state until [DfuDevice::reset]
+//! is called.
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+pub use crate::usb::dfu::{attributes, DfuEvent, DfuMode, DfuStatus, FirmwareSink};
+use crate::{
+    devices::Device,
+    usb::{
+        dfu::{DfuInterfaceBuilder, SharedFirmwareSink},
+        ConfigurationBuilder, DeviceClass, LangId,
+    },
+    virtual_usb::{VirtualUSBDevice, VirtualUSBDeviceBuilder, Xfer},
+};
+
+/// Vendor ID of the device (Linux Foundation)
+pub const VENDOR_ID: u16 = 0x1d6b;
+/// Product ID of the device (Multifunction Composite Gadget), the same in
+/// runtime and DFU mode
+pub const PRODUCT_ID: u16 = 0x0104;
+/// Default capabilities: download, upload, manifestation tolerant and
+/// detaching without a reset
+pub const DEFAULT_ATTRIBUTES: u8 = attributes::CAN_DNLOAD
+    | attributes::CAN_UPLOAD
+    | attributes::MANIFESTATION_TOLERANT
+    | attributes::WILL_DETACH;
+/// Time between DETACH and re-enumeration, which lets the reply to DETACH
+/// reach the host
+const DETACH_DELAY: Duration = Duration::from_millis(50);
+
+/// Firmware kept in memory, up to a maximum size
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFirmware {
+    image: Vec<u8>,
+    download: Vec<u8>,
+    max_size: usize,
+    manifested: usize,
+}
+
+impl MemoryFirmware {
+    /// Accept firmware of up to the given size
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            ..Self::default()
+        }
+    }
+
+    /// Firmware installed by the last download, or set with
+    /// [MemoryFirmware::set_image]
+    pub fn image(&self) -> &[u8] {
+        self.image.as_slice()
+    }
+
+    /// Set the firmware uploaded to the host
+    pub fn set_image(&mut self, image: Vec<u8>) {
+        self.image = image;
+    }
+
+    /// Number of downloads that completed
+    pub fn manifested(&self) -> usize {
+        self.manifested
+    }
+}
+
+impl FirmwareSink for MemoryFirmware {
+    fn begin(&mut self) -> Result<(), DfuStatus> {
+        self.download.clear();
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), DfuStatus> {
+        if offset != self.download.len() || offset + data.len() > self.max_size {
+            return Err(DfuStatus::ErrAddress);
+        }
+        self.download.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn manifest(&mut self) -> Result<(), DfuStatus> {
+        self.image = std::mem::take(&mut self.download);
+        self.manifested += 1;
+        Ok(())
+    }
+
+    fn abort(&mut self) {
+        self.download.clear();
+    }
+
+    fn read(&mut self, offset: usize, len: usize) -> Result<Vec<u8>, DfuStatus> {
+        let start = offset.min(self.image.len());
+        let end = offset.saturating_add(len).min(self.image.len());
+        Ok(self.image[start..end].to_vec())
+    }
+}
+
+/// Firmware stored in a file, which is replaced once a download completes
+#[derive(Debug)]
+pub struct FileFirmware {
+    path: PathBuf,
+    download: MemoryFirmware,
+}
+
+impl FileFirmware {
+    /// Store firmware of up to the given size in the given file
+    pub fn new<P: AsRef<Path>>(path: P, max_size: usize) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            download: MemoryFirmware::new(max_size),
+        }
+    }
+
+    /// Path of the firmware file
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+}
+
+impl FirmwareSink for FileFirmware {
+    fn begin(&mut self) -> Result<(), DfuStatus> {
+        self.download.begin()
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), DfuStatus> {
+        self.download.write(offset, data)
+    }
+
+    fn manifest(&mut self) -> Result<(), DfuStatus> {
+        self.download.manifest()?;
+        #[cfg(feature = "log")]
+        log::info!("Writing firmware to {}", self.path.display());
+        std::fs::write(&self.path, self.download.image()).map_err(|_e| {
+            #[cfg(feature = "log")]
+            log::error!("Failed to write firmware: {_e:?}");
+            DfuStatus::ErrWrite
+        })
+    }
+
+    fn abort(&mut self) {
+        self.download.abort();
+    }
+
+    fn read(&mut self, offset: usize, len: usize) -> Result<Vec<u8>, DfuStatus> {
+        let Ok(mut file) = File::open(&self.path) else {
+            return Ok(Vec::new());
+        };
+        let mut data = Vec::with_capacity(len);
+        file.seek(SeekFrom::Start(offset as u64))
+            .and_then(|_| file.take(len as u64).read_to_end(&mut data))
+            .map_err(|_| DfuStatus::ErrFile)?;
+        Ok(data)
+    }
+}
+
+/// Virtual DFU device delivering firmware to a [FirmwareSink]
+#[derive(Debug)]
+pub struct DfuDevice<F: FirmwareSink + Send + 'static> {
+    device: VirtualUSBDevice,
+    firmware: Arc<Mutex<F>>,
+    mode: DfuMode,
+    attributes: u8,
+    events: Receiver<DfuEvent>,
+    events_tx: Sender<DfuEvent>,
+    /// Events waiting for [DfuDevice::take_events]
+    pending_events: Vec<DfuEvent>,
+    /// Time to re-enumerate in DFU mode after DETACH
+    detach_at: Option<Instant>,
+}
+
+impl<F: FirmwareSink + Send + 'static> DfuDevice<F> {
+    /// Create a device in the given mode with the given capabilities (see
+    /// [DEFAULT_ATTRIBUTES]), delivering firmware to the given sink
+    pub fn new(firmware: F, mode: DfuMode, attributes: u8) -> Self {
+        let (tx, rx) = channel();
+        let firmware = Arc::new(Mutex::new(firmware));
+        let device = Self::build_device(&firmware, mode, attributes, &tx);
+
+        Self {
+            device,
+            firmware,
+            mode,
+            attributes,
+            events: rx,
+            events_tx: tx,
+            pending_events: Vec::new(),
+            detach_at: None,
+        }
+    }
+
+    /// Build the virtual USB device with the descriptor set of the given
+    /// mode
+    fn build_device(
+        firmware: &Arc<Mutex<F>>,
+        mode: DfuMode,
+        attributes: u8,
+        tx: &Sender<DfuEvent>,
+    ) -> VirtualUSBDevice {
+        let tx = tx.clone();
+        let mut iface = DfuInterfaceBuilder::new();
+        iface
+            .mode(mode)
+            .attributes(attributes)
+            .on_event(move |event| {
+                let _ = tx.send(event);
+            });
+        if mode == DfuMode::Dfu {
+            let firmware: SharedFirmwareSink = firmware.clone();
+            iface.firmware(firmware);
+        }
+
+        VirtualUSBDeviceBuilder::new(VENDOR_ID, PRODUCT_ID)
+            .class(DeviceClass::UseInterface)
+            .supported_langs(vec![LangId::EnglishUnitedStates])
+            .manufacturer("Linux")
+            .product("DFU Gadget")
+            .serial("0123456789AB")
+            .max_packet_size(64)
+            .configuration(
+                ConfigurationBuilder::new()
+                    .max_power(100)
+                    .interface(iface.build())
+                    .build(),
+            )
+            .build()
+    }
+
+    /// The current mode of the device
+    pub fn mode(&self) -> DfuMode {
+        self.mode
+    }
+
+    /// The sink firmware is delivered to
+    pub fn firmware(&self) -> &Arc<Mutex<F>> {
+        &self.firmware
+    }
+
+    /// Remove and return the events of the DFU interface received so far
+    pub fn take_events(&mut self) -> Vec<DfuEvent> {
+        std::mem::take(&mut self.pending_events)
+    }
+
+    /// Re-enumerate in runtime mode, like a device resetting to run its new
+    /// firmware
+    pub fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        self.detach_at = None;
+        self.reenumerate(DfuMode::Runtime)
+    }
+
+    /// Detach the device and attach it again with the descriptor set of the
+    /// given mode
+    fn reenumerate(&mut self, mode: DfuMode) -> Result<(), Box<dyn Error>> {
+        #[cfg(feature = "log")]
+        log::info!("Re-enumerating in {mode:?} mode");
+        self.device.stop();
+        self.device = Self::build_device(&self.firmware, mode, self.attributes, &self.events_tx);
+        self.mode = mode;
+        self.device.start()
+    }
+
+    /// Handle a transfer that was not handled by the device itself. DFU
+    /// interfaces have no endpoints, so these are unknown requests.
+    fn handle_xfer(&mut self, _xfer: Xfer) {
+        #[cfg(feature = "log")]
+        log::debug!(
+            "Ignoring {:?} transfer on endpoint {}",
+            _xfer.direction(),
+            _xfer.ep
+        );
+    }
+}
+
+impl<F: FirmwareSink + Send + 'static> Device for DfuDevice<F> {
+    fn device(&self) -> &VirtualUSBDevice {
+        &self.device
+    }
+
+    fn device_mut(&mut self) -> &mut VirtualUSBDevice {
+        &mut self.device
+    }
+
+    /// Handle the next pending USB transfer, if any, and re-enumerate in DFU
+    /// mode once the host sent DETACH. Waits up to the given timeout if
+    /// there was nothing to handle.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        match self.device.read()? {
+            Some(xfer) => self.handle_xfer(xfer),
+            None => thread::sleep(timeout),
+        }
+
+        while let Ok(event) = self.events.try_recv() {
+            #[cfg(feature = "log")]
+            log::info!("DFU event: {event:?}");
+            if let DfuEvent::Detach { .. } = event {
+                self.detach_at = Some(Instant::now() + DETACH_DELAY);
+            }
+            self.pending_events.push(event);
+        }
+        if self.detach_at.is_some_and(|at| Instant::now() >= at) {
+            self.detach_at = None;
+            self.reenumerate(DfuMode::Dfu)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,462 @@
+//! USB Ethernet adapter bridged to a TAP device or an in-memory channel
+//!
+//! Emulates a CDC-ECM, CDC-NCM or RNDIS network adapter, which the host binds
+//! with the cdc_ether, cdc_ncm or rndis_host driver. Ethernet frames sent by the host on the
+//! bulk OUT endpoint are forwarded to an [EthernetBackend], and frames from
+//! the backend are sent to the host on the bulk IN endpoint. A [TapDevice]
+//! backend connects the adapter to the local network stack, while a
+//! [FrameChannel] lets tests exchange frames with the host directly.
+//!
+//! The Ethernet packet filter set by the host is not applied; all frames are
+//! forwarded.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    devices::Device,
+    usb::{
+        cdc::{
+            ncm::{parse_ntb16, Ntb16Builder, DEFAULT_NTB_MAX_SIZE},
+            rndis::{build_packet, parse_packets},
+            CdcDataInterfaceBuilder, CdcEvent, CdcInterfaceBuilder,
+        },
+        ConfigurationBuilder, DeviceClass, LangId,
+    },
+    usbip::UsbIpDirection,
+    virtual_usb::{Reply, VirtualUSBDevice, VirtualUSBDeviceBuilder, Xfer},
+};
+
+/// Vendor ID of the adapter (NetChip, used by the Linux Ethernet gadget)
+pub const VENDOR_ID: u16 = 0x0525;
+/// Product ID of the adapter (Linux-USB Ethernet Gadget)
+pub const PRODUCT_ID: u16 = 0xa4a1;
+/// Product ID of the RNDIS adapter (Linux-USB Ethernet/RNDIS Gadget)
+pub const RNDIS_PRODUCT_ID: u16 = 0xa4a2;
+/// Interface number of the communication interface
+pub const COMM_INTERFACE: u8 = 0;
+/// Interface number of the data interface
+pub const DATA_INTERFACE: u8 = 1;
+/// Number of the interrupt IN notification endpoint
+pub const NOTIFICATION_ENDPOINT: u8 = 2;
+/// Number of the bulk IN and bulk OUT data endpoints
+pub const DATA_ENDPOINT: u8 = 1;
+/// Link speed reported to the host in bits per second
+pub const LINK_SPEED: u32 = 100_000_000;
+/// Max packet size of the bulk endpoints (high speed)
+const MAX_PACKET_SIZE: u16 = 512;
+/// Maximum number of frames from the backend waiting for the host
+const MAX_QUEUED_FRAMES: usize = 256;
+/// Data interface protocol of NCM functions (NCM transfer blocks)
+const NCM_DATA_PROTOCOL: u8 = 0x01;
+
+/// Source and sink of the Ethernet frames exchanged with the host
+pub trait EthernetBackend {
+    /// Deliver a frame sent by the host
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+    /// Wait up to the given timeout for a frame to send to the host
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// In-memory backend exchanging frames with the other end of the channel
+#[derive(Debug)]
+pub struct FrameChannel {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+impl FrameChannel {
+    /// Create a connected pair of channels. Frames sent on one end are
+    /// received on the other.
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, b_rx) = channel();
+        let (b_tx, a_rx) = channel();
+        let a = Self { tx: a_tx, rx: a_rx };
+        let b = Self { tx: b_tx, rx: b_rx };
+        (a, b)
+    }
+}
+
+impl EthernetBackend for FrameChannel {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.tx
+            .send(frame.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+/// Linux TAP network interface backend. Opening a TAP device requires
+/// CAP_NET_ADMIN.
+#[derive(Debug)]
+pub struct TapDevice {
+    file: File,
+    name: String,
+}
+
+impl TapDevice {
+    /// Create or attach to the TAP interface with the given name (e.g.
+    /// "usb0"). An empty name lets the kernel pick one.
+    pub fn open(name: &str) -> io::Result<Self> {
+        /// _IOW('T', 202, int)
+        const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/net/tun")?;
+
+        // SAFETY: ifreq is plain data
+        let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
+        let c_name = CString::new(name)?;
+        let bytes = c_name.as_bytes();
+        if bytes.len() >= ifreq.ifr_name.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Name too long"));
+        }
+        for (dst, src) in ifreq.ifr_name.iter_mut().zip(bytes) {
+            *dst = *src as libc::c_char;
+        }
+        ifreq.ifr_ifru.ifru_flags = (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short;
+
+        // SAFETY: TUNSETIFF takes a pointer to an ifreq
+        if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF, &mut ifreq) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The kernel writes back the name of the interface
+        let name = ifreq
+            .ifr_name
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8 as char)
+            .collect();
+
+        Ok(Self { file, name })
+    }
+
+    /// Name of the TAP network interface
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+}
+
+impl EthernetBackend for TapDevice {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        match self.file.write(frame) {
+            Ok(_) => Ok(()),
+            // The interface is down or its queue is full, so drop the frame
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        let mut fds = [libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        // SAFETY: fds is a valid array of one pollfd
+        if unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout) } <= 0 {
+            return Ok(None);
+        }
+
+        let mut frame = vec![0; 65536];
+        match self.file.read(&mut frame) {
+            Ok(len) => {
+                frame.truncate(len);
+                Ok(Some(frame))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Networking function of an [EthernetBridge]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EthernetFunction {
+    /// Ethernet Control Model: one frame per bulk transfer
+    Ecm,
+    /// Network Control Model: frames are batched in NCM transfer blocks
+    Ncm,
+    /// Remote NDIS: each frame is wrapped in an RNDIS packet message
+    Rndis,
+}
+
+/// Virtual USB Ethernet adapter that forwards frames between its bulk
+/// endpoints and an [EthernetBackend].
+#[derive(Debug)]
+pub struct EthernetBridge<B: EthernetBackend> {
+    device: VirtualUSBDevice,
+    backend: B,
+    function: EthernetFunction,
+    events: Receiver<CdcEvent>,
+    /// Bulk IN transfers waiting for frames from the backend
+    pending_in: VecDeque<Xfer>,
+    /// Frames from the backend waiting for bulk IN transfers
+    frames: VecDeque<Vec<u8>>,
+    /// Maximum size of NTBs sent to the host
+    ntb_input_size: usize,
+    /// Sequence number of the next NTB sent to the host
+    sequence: u16,
+    /// Whether the host has been notified that the link is up
+    connected: bool,
+}
+
+impl<B: EthernetBackend> EthernetBridge<B> {
+    /// Create a CDC-ECM adapter backed by the given backend. The host uses
+    /// the given MAC address for its network interface.
+    pub fn ecm(backend: B, mac_address: [u8; 6]) -> Self {
+        Self::new(backend, mac_address, EthernetFunction::Ecm)
+    }
+
+    /// Create a CDC-NCM adapter backed by the given backend. The host uses
+    /// the given MAC address for its network interface.
+    pub fn ncm(backend: B, mac_address: [u8; 6]) -> Self {
+        Self::new(backend, mac_address, EthernetFunction::Ncm)
+    }
+
+    /// Create an RNDIS adapter backed by the given backend, as used by
+    /// Windows style USB tethering. The host uses the given MAC address for
+    /// its network interface.
+    pub fn rndis(backend: B, mac_address: [u8; 6]) -> Self {
+        Self::new(backend, mac_address, EthernetFunction::Rndis)
+    }
+
+    fn new(backend: B, mac_address: [u8; 6], function: EthernetFunction) -> Self {
+        let (tx, rx) = channel();
+
+        let mut comm = CdcInterfaceBuilder::new();
+        let mut data = CdcDataInterfaceBuilder::new();
+        match function {
+            EthernetFunction::Ecm => comm.ethernet(mac_address),
+            EthernetFunction::Ncm => {
+                data.protocol(NCM_DATA_PROTOCOL);
+                comm.ncm(mac_address)
+            }
+            EthernetFunction::Rndis => comm.rndis(mac_address),
+        };
+        comm.notification_endpoint(NOTIFICATION_ENDPOINT)
+            .on_event(move |event| {
+                let _ = tx.send(event);
+            });
+        // RNDIS data interfaces have no alternate setting without endpoints
+        if function != EthernetFunction::Rndis {
+            data.alternate_setting();
+        }
+        data.bulk_endpoints(DATA_ENDPOINT, DATA_ENDPOINT, MAX_PACKET_SIZE);
+
+        let product_id = match function {
+            EthernetFunction::Rndis => RNDIS_PRODUCT_ID,
+            _ => PRODUCT_ID,
+        };
+        let device = VirtualUSBDeviceBuilder::new(VENDOR_ID, product_id)
+            .class(DeviceClass::Cdc)
+            .supported_langs(vec![LangId::EnglishUnitedStates])
+            .manufacturer("Linux")
+            .product("Ethernet Gadget")
+            .max_packet_size(64)
+            .configuration(
+                ConfigurationBuilder::new()
+                    .max_power(100)
+                    .interface(comm.build())
+                    .interface(data.build())
+                    .build(),
+            )
+            .build();
+
+        Self {
+            device,
+            backend,
+            function,
+            events: rx,
+            pending_in: VecDeque::new(),
+            frames: VecDeque::new(),
+            ntb_input_size: DEFAULT_NTB_MAX_SIZE as usize,
+            sequence: 0,
+            connected: false,
+        }
+    }
+
+    /// The networking function of the adapter
+    pub fn function(&self) -> EthernetFunction {
+        self.function
+    }
+
+    /// The backend frames are forwarded to
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// The backend frames are forwarded to
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Handle a transfer that was not handled by the device itself
+    fn handle_xfer(&mut self, xfer: Xfer) -> Result<(), Box<dyn Error>> {
+        match (xfer.direction(), xfer.ep) {
+            (UsbIpDirection::In, DATA_ENDPOINT) => self.pending_in.push_back(xfer),
+            (UsbIpDirection::Out, DATA_ENDPOINT) => match self.function {
+                EthernetFunction::Ecm => self.backend.send(&xfer.data)?,
+                EthernetFunction::Ncm => match parse_ntb16(&xfer.data) {
+                    Ok(frames) => {
+                        for frame in frames {
+                            self.backend.send(&frame)?;
+                        }
+                    }
+                    Err(_e) => {
+                        #[cfg(feature = "log")]
+                        log::warn!("Dropping invalid NTB from host: {_e}");
+                    }
+                },
+                EthernetFunction::Rndis => match parse_packets(&xfer.data) {
+                    Ok(frames) => {
+                        for frame in frames {
+                            self.backend.send(&frame)?;
+                        }
+                    }
+                    Err(_e) => {
+                        #[cfg(feature = "log")]
+                        log::warn!("Dropping invalid RNDIS packet from host: {_e}");
+                    }
+                },
+            },
+            (_direction, _ep) => {
+                #[cfg(feature = "log")]
+                log::debug!("Ignoring {_direction:?} transfer on endpoint {_ep}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Report the link as up once the host has selected the alternate
+    /// setting of the data interface that holds the endpoints
+    fn update_link(&mut self) -> Result<(), Box<dyn Error>> {
+        // RNDIS reports the link state with OID_GEN_MEDIA_CONNECT_STATUS
+        if self.function == EthernetFunction::Rndis {
+            return Ok(());
+        }
+        let active = self.device.alternate_setting(DATA_INTERFACE) == 1;
+        if active == self.connected {
+            return Ok(());
+        }
+        self.connected = active;
+        if !active {
+            return Ok(());
+        }
+
+        #[cfg(feature = "log")]
+        log::info!("Host activated data interface, reporting link up");
+        if self.function == EthernetFunction::Ncm {
+            self.device
+                .send_connection_speed_change(COMM_INTERFACE, LINK_SPEED, LINK_SPEED)?;
+        }
+        self.device.send_network_connection(COMM_INTERFACE, true)
+    }
+
+    /// Build an NTB from as many queued frames as fit in the given transfer
+    /// buffer length. Returns None if the next frame can never fit, in which
+    /// case it is dropped.
+    fn build_ntb(&mut self, buffer_length: usize) -> Option<Vec<u8>> {
+        let mut ntb = Ntb16Builder::new(self.ntb_input_size.min(buffer_length));
+        while let Some(frame) = self.frames.front() {
+            if !ntb.push(frame) {
+                break;
+            }
+            self.frames.pop_front();
+        }
+        if ntb.is_empty() {
+            #[cfg(feature = "log")]
+            log::warn!("Dropping frame larger than the NTB size");
+            self.frames.pop_front();
+            return None;
+        }
+
+        let data = ntb.build(self.sequence);
+        self.sequence = self.sequence.wrapping_add(1);
+        Some(data)
+    }
+}
+
+impl<B: EthernetBackend> Device for EthernetBridge<B> {
+    fn device(&self) -> &VirtualUSBDevice {
+        &self.device
+    }
+
+    fn device_mut(&mut self) -> &mut VirtualUSBDevice {
+        &mut self.device
+    }
+
+    /// Handle pending USB transfers and host requests, then wait up to the
+    /// given timeout for frames from the backend and forward them to the
+    /// host.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        if let Some(xfer) = self.device.read()? {
+            self.handle_xfer(xfer)?;
+        }
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                CdcEvent::NtbInputSize(size) => self.ntb_input_size = size as usize,
+                CdcEvent::RndisState(_state) => {
+                    #[cfg(feature = "log")]
+                    log::info!("RNDIS state changed to {_state:?}");
+                }
+                _ => (),
+            }
+        }
+        self.update_link()?;
+
+        // Only take frames from the backend when the host can receive them
+        self.pending_in
+            .retain(|xfer| !self.device.is_unlinked(xfer.seqnum()));
+        if self.pending_in.is_empty() {
+            thread::sleep(timeout);
+            return Ok(());
+        }
+        let mut wait = timeout;
+        while self.frames.len() < MAX_QUEUED_FRAMES {
+            let Some(frame) = self.backend.recv(wait)? else {
+                break;
+            };
+            self.frames.push_back(frame);
+            wait = Duration::ZERO;
+        }
+
+        while !self.frames.is_empty() {
+            let Some(xfer) = self.pending_in.pop_front() else {
+                break;
+            };
+            let data = match self.function {
+                EthernetFunction::Ecm => self.frames.pop_front(),
+                EthernetFunction::Ncm => self.build_ntb(xfer.buffer_length()),
+                EthernetFunction::Rndis => self.frames.pop_front().map(|f| build_packet(&f)),
+            };
+            match data {
+                Some(data) => self.device.write(Reply::from_xfer(xfer, &data))?,
+                None => self.pending_in.push_front(xfer),
+            }
+        }
+
+        Ok(())
+    }
+}
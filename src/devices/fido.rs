@@ -0,0 +1,483 @@
+//! FIDO security key
+//!
+//! Emulates a roaming authenticator on the FIDO Alliance HID usage page, so
+//! browsers, libfido2 and other WebAuthn clients can register and use
+//! credentials on a headless machine. The [SecurityKey] implements the
+//! CTAPHID transport itself: channel allocation with INIT, reassembly of
+//! continuation packets, PING, WINK, LOCK, CANCEL and KEEPALIVE messages
+//! while a request waits for the user. Complete U2F (MSG) and CTAP2 (CBOR)
+//! requests are passed to an [Authenticator].
+//!
+//! The built-in [SoftAuthenticator] implements CTAP2 with ES256 credentials
+//! kept in memory. Touches of the key are simulated through its
+//! [UserPresence] handle.
+
+pub mod cbor;
+pub mod ctap2;
+pub mod ctaphid;
+pub mod software;
+
+use std::{
+    error::Error,
+    sync::mpsc::{channel, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+use packed_struct::prelude::*;
+
+use self::ctaphid::{
+    capability, error_code, fragment, keepalive_status, Assembly, Command, Packet, BROADCAST_CID,
+    MAX_MESSAGE_SIZE, PROTOCOL_VERSION, REPORT_SIZE,
+};
+pub use self::software::{Credential, SoftAuthenticator, UserPresence};
+use crate::{
+    devices::Device,
+    usb::{
+        hid::{HidInterfaceBuilder, HidReportType},
+        ConfigurationBuilder, DeviceClass, Direction, EndpointBuilder, LangId, SynchronizationType,
+        TransferType, UsageType,
+    },
+    virtual_usb::{VirtualUSBDevice, VirtualUSBDeviceBuilder},
+};
+
+/// Vendor ID of the security key (Linux Foundation)
+pub const VENDOR_ID: u16 = 0x1d6b;
+/// Product ID of the security key (Multifunction Composite Gadget)
+pub const PRODUCT_ID: u16 = 0x0104;
+/// Number of the interrupt IN and interrupt OUT endpoints
+pub const REPORT_ENDPOINT: u8 = 1;
+/// Device version reported by INIT (major, minor, build)
+pub const DEVICE_VERSION: [u8; 3] = [1, 0, 0];
+/// Polling interval of the interrupt endpoints
+const INTERVAL: u8 = 5;
+/// Time without packets after which a partially received message is dropped
+const TRANSACTION_TIMEOUT: Duration = Duration::from_millis(500);
+/// Time between KEEPALIVE messages while a request waits for the user
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(100);
+/// Time a request waits for the user before failing
+const USER_PRESENCE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest lock of the device by a channel, in seconds
+const MAX_LOCK_SECONDS: u8 = 10;
+/// U2F status word of requests that need a touch of the key
+const SW_CONDITIONS_NOT_SATISFIED: [u8; 2] = [0x69, 0x85];
+/// U2F status word of unsupported instructions
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6d, 0x00];
+
+/// Report descriptor of a FIDO authenticator with 64 byte input and output
+/// reports (CTAP 2.1, section 11.2.8.1)
+#[rustfmt::skip]
+pub const FIDO_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0xd0, 0xf1, // Usage Page (FIDO Alliance)
+    0x09, 0x01,       // Usage (U2F Authenticator Device)
+    0xa1, 0x01,       // Collection (Application)
+    0x09, 0x20,       //   Usage (Input Report Data)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x40,       //   Report Count (64)
+    0x81, 0x02,       //   Input (Data,Var,Abs)
+    0x09, 0x21,       //   Usage (Output Report Data)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x40,       //   Report Count (64)
+    0x91, 0x02,       //   Output (Data,Var,Abs)
+    0xc0,             // End Collection
+];
+
+/// Outcome of a request passed to an [Authenticator]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The response message is ready
+    Ready(Vec<u8>),
+    /// The request needs the user to touch the key. The host is kept waiting
+    /// with KEEPALIVE messages and the same request is passed again until it
+    /// completes, so it must not have any side effect yet.
+    UserPresenceNeeded,
+}
+
+/// Authenticator processing the requests framed by a [SecurityKey]
+pub trait Authenticator {
+    /// Returns the capabilities reported by INIT (see
+    /// [ctaphid::capability]), which decide whether MSG, CBOR and WINK are
+    /// accepted
+    fn capabilities(&self) -> u8 {
+        capability::WINK | capability::CBOR
+    }
+    /// Process a U2F request APDU sent with MSG and return the response APDU
+    fn msg(&mut self, _request: &[u8]) -> Response {
+        Response::Ready(SW_INS_NOT_SUPPORTED.to_vec())
+    }
+    /// Process a CTAP2 request sent with CBOR, made of the command byte and
+    /// its CBOR parameters, and return the status byte followed by the CBOR
+    /// response (see [ctap2])
+    fn cbor(&mut self, request: &[u8]) -> Response;
+    /// Identify the authenticator to the user, e.g. by blinking an LED
+    fn wink(&mut self) {}
+}
+
+/// Request waiting for the presence of the user
+#[derive(Debug)]
+struct PendingRequest {
+    cid: u32,
+    cmd: Command,
+    data: Vec<u8>,
+    started: Instant,
+    last_keepalive: Instant,
+}
+
+/// Virtual USB FIDO security key passing requests to an [Authenticator]
+#[derive(Debug)]
+pub struct SecurityKey<A: Authenticator> {
+    device: VirtualUSBDevice,
+    authenticator: A,
+    /// Output reports received on the interrupt OUT endpoint
+    reports: Receiver<Vec<u8>>,
+    /// Channel ID allocated by the next INIT on the broadcast channel
+    next_cid: u32,
+    /// Message being received, with the time its last packet arrived
+    assembly: Option<(Assembly, Instant)>,
+    pending: Option<PendingRequest>,
+    /// Channel holding the lock of the device, and the end of the lock
+    lock: Option<(u32, Instant)>,
+}
+
+impl<A: Authenticator> SecurityKey<A> {
+    /// Create a security key passing requests to the given authenticator
+    pub fn new(authenticator: A) -> Self {
+        let (tx, rx) = channel();
+        let endpoint = |direction| {
+            EndpointBuilder::new()
+                .address_num(REPORT_ENDPOINT)
+                .direction(direction)
+                .transfer_type(TransferType::Interrupt)
+                .sync_type(SynchronizationType::NoSynchronization)
+                .usage_type(UsageType::Data)
+                .max_packet_size(REPORT_SIZE as u16)
+                .interval(INTERVAL)
+                .build()
+        };
+
+        let device = VirtualUSBDeviceBuilder::new(VENDOR_ID, PRODUCT_ID)
+            .class(DeviceClass::UseInterface)
+            .supported_langs(vec![LangId::EnglishUnitedStates])
+            .manufacturer("Linux")
+            .product("FIDO Security Key")
+            .serial("0123456789AB")
+            .max_packet_size(64)
+            .configuration(
+                ConfigurationBuilder::new()
+                    .max_power(100)
+                    .interface(
+                        HidInterfaceBuilder::new()
+                            .report_descriptor(FIDO_REPORT_DESCRIPTOR)
+                            .endpoint_descriptor(endpoint(Direction::In))
+                            .endpoint_descriptor(endpoint(Direction::Out))
+                            .on_set_report(HidReportType::Output, 0, move |report| {
+                                let _ = tx.send(report.to_vec());
+                            })
+                            .build(),
+                    )
+                    .build(),
+            )
+            .build();
+
+        Self {
+            device,
+            authenticator,
+            reports: rx,
+            next_cid: 1,
+            assembly: None,
+            pending: None,
+            lock: None,
+        }
+    }
+
+    /// The authenticator processing requests
+    pub fn authenticator(&self) -> &A {
+        &self.authenticator
+    }
+
+    /// The authenticator processing requests
+    pub fn authenticator_mut(&mut self) -> &mut A {
+        &mut self.authenticator
+    }
+
+    /// Handle a packet sent by the host
+    fn handle_report(&mut self, report: &[u8]) -> Result<(), Box<dyn Error>> {
+        match Packet::parse(report) {
+            Some(Packet::Init {
+                cid,
+                cmd,
+                length,
+                data,
+            }) => self.handle_init_packet(cid, cmd, length, data),
+            Some(Packet::Cont { cid, seq, data }) => {
+                // Continuation packets of other channels are ignored
+                let Some((assembly, last)) = self.assembly.as_mut() else {
+                    return Ok(());
+                };
+                if assembly.cid != cid {
+                    return Ok(());
+                }
+                if !assembly.push(seq, data) {
+                    self.assembly = None;
+                    return self.send_error(cid, error_code::INVALID_SEQ);
+                }
+                *last = Instant::now();
+                if assembly.is_complete() {
+                    if let Some((assembly, _)) = self.assembly.take() {
+                        self.handle_message(assembly)?;
+                    }
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Handle the first packet of a message
+    fn handle_init_packet(
+        &mut self,
+        cid: u32,
+        cmd: u8,
+        length: usize,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let allocated = cid != 0 && cid < self.next_cid;
+        let allocating = cid == BROADCAST_CID && cmd == Command::Init as u8;
+        if !(allocated || allocating) {
+            return self.send_error(cid, error_code::INVALID_CHANNEL);
+        }
+
+        // INIT and CANCEL are handled immediately, even while busy
+        match Command::from_primitive(cmd) {
+            Some(Command::Init) => {
+                // Resynchronize the channel, aborting its transaction
+                if self.assembly.as_ref().is_some_and(|(a, _)| a.cid == cid) {
+                    self.assembly = None;
+                }
+                if self.pending.as_ref().is_some_and(|p| p.cid == cid) {
+                    self.pending = None;
+                }
+                if length != 8 || data.len() < 8 {
+                    return self.send_error(cid, error_code::INVALID_LEN);
+                }
+                return self.init(cid, &data[..8]);
+            }
+            Some(Command::Cancel) => {
+                if self.pending.as_ref().is_some_and(|p| p.cid == cid) {
+                    #[cfg(feature = "log")]
+                    log::info!("Request cancelled by the host");
+                    self.pending = None;
+                    return self.send(cid, Command::Cbor, &[ctap2::status::KEEPALIVE_CANCEL]);
+                }
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        // One transaction at a time, and none on other channels while locked
+        let busy = self.assembly.as_ref().map(|(a, _)| a.cid);
+        let busy = busy.or(self.pending.as_ref().map(|p| p.cid));
+        let locked = self.lock.is_some_and(|(owner, _)| owner != cid);
+        if busy == Some(cid) && self.pending.is_none() {
+            self.assembly = None;
+            return self.send_error(cid, error_code::INVALID_SEQ);
+        }
+        if busy.is_some() || locked {
+            return self.send_error(cid, error_code::CHANNEL_BUSY);
+        }
+        if length > MAX_MESSAGE_SIZE {
+            return self.send_error(cid, error_code::INVALID_LEN);
+        }
+
+        let assembly = Assembly::new(cid, cmd, length, data);
+        if assembly.is_complete() {
+            return self.handle_message(assembly);
+        }
+        self.assembly = Some((assembly, Instant::now()));
+        Ok(())
+    }
+
+    /// Handle a complete message
+    fn handle_message(&mut self, assembly: Assembly) -> Result<(), Box<dyn Error>> {
+        let cid = assembly.cid;
+        let capabilities = self.authenticator.capabilities();
+        let cmd = Command::from_primitive(assembly.cmd);
+        #[cfg(feature = "log")]
+        log::debug!("{cmd:?} on channel {cid:#010x}");
+        let data = assembly.into_data();
+        match cmd {
+            Some(Command::Ping) => self.send(cid, Command::Ping, &data),
+            Some(Command::Msg) if capabilities & capability::NMSG == 0 => {
+                self.request(cid, Command::Msg, data)
+            }
+            Some(Command::Cbor) if capabilities & capability::CBOR != 0 => {
+                if data.is_empty() {
+                    return self.send_error(cid, error_code::INVALID_LEN);
+                }
+                self.request(cid, Command::Cbor, data)
+            }
+            Some(Command::Wink) if capabilities & capability::WINK != 0 => {
+                self.authenticator.wink();
+                self.send(cid, Command::Wink, &[])
+            }
+            Some(Command::Lock) => match data.as_slice() {
+                [0] => {
+                    self.lock = None;
+                    self.send(cid, Command::Lock, &[])
+                }
+                [seconds] if *seconds <= MAX_LOCK_SECONDS => {
+                    let until = Instant::now() + Duration::from_secs(*seconds as u64);
+                    self.lock = Some((cid, until));
+                    self.send(cid, Command::Lock, &[])
+                }
+                _ => self.send_error(cid, error_code::INVALID_PAR),
+            },
+            _ => self.send_error(cid, error_code::INVALID_CMD),
+        }
+    }
+
+    /// Answer INIT with the channel allocated for the given nonce
+    fn init(&mut self, cid: u32, nonce: &[u8]) -> Result<(), Box<dyn Error>> {
+        let new_cid = match cid {
+            BROADCAST_CID => {
+                self.next_cid += 1;
+                self.next_cid - 1
+            }
+            cid => cid,
+        };
+        #[cfg(feature = "log")]
+        log::debug!("Allocated channel {new_cid:#010x}");
+
+        let mut response = nonce.to_vec();
+        response.extend_from_slice(&new_cid.to_be_bytes());
+        response.push(PROTOCOL_VERSION);
+        response.extend_from_slice(&DEVICE_VERSION);
+        response.push(self.authenticator.capabilities());
+        self.send(cid, Command::Init, &response)
+    }
+
+    /// Pass a MSG or CBOR request to the authenticator, and keep it pending
+    /// if it needs the user
+    fn request(&mut self, cid: u32, cmd: Command, data: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        match self.process(cmd, &data) {
+            Response::Ready(response) => self.send(cid, cmd, &response),
+            Response::UserPresenceNeeded => {
+                #[cfg(feature = "log")]
+                log::info!("Waiting for the user to touch the key");
+                let now = Instant::now();
+                self.pending = Some(PendingRequest {
+                    cid,
+                    cmd,
+                    data,
+                    started: now,
+                    last_keepalive: now,
+                });
+                self.send_keepalive(cid)
+            }
+        }
+    }
+
+    fn process(&mut self, cmd: Command, data: &[u8]) -> Response {
+        match cmd {
+            Command::Msg => self.authenticator.msg(data),
+            _ => self.authenticator.cbor(data),
+        }
+    }
+
+    /// Retry the request waiting for the user, and fail it once it timed out
+    fn process_pending(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(mut pending) = self.pending.take() else {
+            return Ok(());
+        };
+        if pending.started.elapsed() >= USER_PRESENCE_TIMEOUT {
+            #[cfg(feature = "log")]
+            log::info!("Timed out waiting for the user");
+            let response = match pending.cmd {
+                Command::Msg => SW_CONDITIONS_NOT_SATISFIED.to_vec(),
+                _ => vec![ctap2::status::USER_ACTION_TIMEOUT],
+            };
+            return self.send(pending.cid, pending.cmd, &response);
+        }
+
+        match self.process(pending.cmd, &pending.data) {
+            Response::Ready(response) => self.send(pending.cid, pending.cmd, &response),
+            Response::UserPresenceNeeded => {
+                if pending.last_keepalive.elapsed() >= KEEPALIVE_INTERVAL {
+                    pending.last_keepalive = Instant::now();
+                    self.send_keepalive(pending.cid)?;
+                }
+                self.pending = Some(pending);
+                Ok(())
+            }
+        }
+    }
+
+    fn send_keepalive(&mut self, cid: u32) -> Result<(), Box<dyn Error>> {
+        self.send(cid, Command::Keepalive, &[keepalive_status::UP_NEEDED])
+    }
+
+    fn send_error(&mut self, cid: u32, code: u8) -> Result<(), Box<dyn Error>> {
+        #[cfg(feature = "log")]
+        log::debug!("Error {code:#04x} on channel {cid:#010x}");
+        self.send(cid, Command::Error, &[code])
+    }
+
+    /// Send a message to the host on the given channel
+    fn send(&mut self, cid: u32, cmd: Command, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        for report in fragment(cid, cmd, data) {
+            self.device.queue_report(REPORT_ENDPOINT, &report)?;
+        }
+        Ok(())
+    }
+}
+
+impl<A: Authenticator> Device for SecurityKey<A> {
+    fn device(&self) -> &VirtualUSBDevice {
+        &self.device
+    }
+
+    fn device_mut(&mut self) -> &mut VirtualUSBDevice {
+        &mut self.device
+    }
+
+    /// Handle the next pending USB transfer or host request, if any, process
+    /// the messages received and keep the host informed about the request
+    /// waiting for the user. Waits up to the given timeout if there was
+    /// nothing to handle.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        match self.device.read()? {
+            Some(_xfer) => {
+                #[cfg(feature = "log")]
+                log::debug!(
+                    "Ignoring {:?} transfer on endpoint {}",
+                    _xfer.direction(),
+                    _xfer.ep
+                );
+            }
+            None => thread::sleep(timeout),
+        }
+
+        while let Ok(report) = self.reports.try_recv() {
+            self.handle_report(&report)?;
+        }
+
+        let timed_out = self
+            .assembly
+            .as_ref()
+            .is_some_and(|(_, last)| last.elapsed() >= TRANSACTION_TIMEOUT);
+        if timed_out {
+            if let Some((assembly, _)) = self.assembly.take() {
+                self.send_error(assembly.cid, error_code::MSG_TIMEOUT)?;
+            }
+        }
+        if self.lock.is_some_and(|(_, until)| until <= Instant::now()) {
+            self.lock = None;
+        }
+
+        self.process_pending()
+    }
+}
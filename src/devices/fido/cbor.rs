@@ -0,0 +1,485 @@
+//! Minimal CBOR (RFC 8949) codec for CTAP2 messages
+//!
+//! Only the data types used by CTAP2 are supported: integers, byte and text
+//! strings, arrays, maps, booleans and null. Maps are encoded with their keys
+//! in the CTAP2 canonical order.
+
+use std::{error::Error, fmt::Display};
+
+/// Maximum nesting depth of arrays and maps accepted by [Value::decode]
+const MAX_DEPTH: usize = 16;
+
+/// Major types of data items
+mod major {
+    pub const UNSIGNED: u8 = 0;
+    pub const NEGATIVE: u8 = 1;
+    pub const BYTES: u8 = 2;
+    pub const TEXT: u8 = 3;
+    pub const ARRAY: u8 = 4;
+    pub const MAP: u8 = 5;
+    pub const SIMPLE: u8 = 7;
+}
+
+/// Simple values of major type 7
+mod simple {
+    pub const FALSE: u8 = 20;
+    pub const TRUE: u8 = 21;
+    pub const NULL: u8 = 22;
+}
+
+/// Error decoding CBOR data
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeError(&'static str);
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid CBOR: {}", self.0)
+    }
+}
+
+impl Error for DecodeError {}
+
+/// CBOR data item
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Bool(bool),
+    Null,
+}
+
+impl Value {
+    /// Create a map from the given entries
+    pub fn map<K: Into<Value>, V: Into<Value>>(entries: impl IntoIterator<Item = (K, V)>) -> Self {
+        Self::Map(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect(),
+        )
+    }
+
+    /// Returns the value of the given key if this is a map
+    pub fn get<K: Into<Value>>(&self, key: K) -> Option<&Value> {
+        let key = key.into();
+        match self {
+            Self::Map(entries) => entries.iter().find(|(k, _)| *k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Self::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::Bytes(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Self::Text(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Self::Array(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Decode a single data item, which must span the whole data
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let mut decoder = Decoder { data, pos: 0 };
+        let value = decoder.value(0)?;
+        if decoder.pos != data.len() {
+            return Err(DecodeError("trailing data"));
+        }
+        Ok(value)
+    }
+
+    /// Encode the data item
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.encode_to(&mut data);
+        data
+    }
+
+    fn encode_to(&self, data: &mut Vec<u8>) {
+        match self {
+            Self::Integer(value) if *value >= 0 => {
+                encode_head(data, major::UNSIGNED, *value as u64)
+            }
+            Self::Integer(value) => encode_head(data, major::NEGATIVE, !*value as u64),
+            Self::Bytes(value) => {
+                encode_head(data, major::BYTES, value.len() as u64);
+                data.extend_from_slice(value);
+            }
+            Self::Text(value) => {
+                encode_head(data, major::TEXT, value.len() as u64);
+                data.extend_from_slice(value.as_bytes());
+            }
+            Self::Array(values) => {
+                encode_head(data, major::ARRAY, values.len() as u64);
+                for value in values {
+                    value.encode_to(data);
+                }
+            }
+            Self::Map(entries) => {
+                // Canonical CTAP2 order sorts the encoded keys by length,
+                // then bytewise
+                let mut entries: Vec<_> = entries
+                    .iter()
+                    .map(|(key, value)| (key.encode(), value))
+                    .collect();
+                entries.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then(a.cmp(b)));
+                encode_head(data, major::MAP, entries.len() as u64);
+                for (key, value) in entries {
+                    data.extend_from_slice(&key);
+                    value.encode_to(data);
+                }
+            }
+            Self::Bool(false) => data.push(major::SIMPLE << 5 | simple::FALSE),
+            Self::Bool(true) => data.push(major::SIMPLE << 5 | simple::TRUE),
+            Self::Null => data.push(major::SIMPLE << 5 | simple::NULL),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Self::Integer(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Self::Integer(value as i64)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Self::Integer(value as i64)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Self::Text(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Self::Text(value)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(value: &[u8]) -> Self {
+        Self::Bytes(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Self::Bytes(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(value: Vec<Value>) -> Self {
+        Self::Array(value)
+    }
+}
+
+/// Encode the initial byte and argument of a data item, using the shortest
+/// form
+fn encode_head(data: &mut Vec<u8>, major: u8, argument: u64) {
+    let major = major << 5;
+    match argument {
+        0..=23 => data.push(major | argument as u8),
+        24..=0xff => data.extend_from_slice(&[major | 24, argument as u8]),
+        0x100..=0xffff => {
+            data.push(major | 25);
+            data.extend_from_slice(&(argument as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            data.push(major | 26);
+            data.extend_from_slice(&(argument as u32).to_be_bytes());
+        }
+        _ => {
+            data.push(major | 27);
+            data.extend_from_slice(&argument.to_be_bytes());
+        }
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], DecodeError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len());
+        let Some(end) = end else {
+            return Err(DecodeError("unexpected end of data"));
+        };
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Returns the major type, additional information and argument of the
+    /// next data item
+    fn head(&mut self) -> Result<(u8, u8, u64), DecodeError> {
+        let initial = self.take(1)?[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        let argument = match info {
+            0..=23 => info as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap_or_default()) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap_or_default()) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap_or_default()),
+            _ => return Err(DecodeError("indefinite lengths are not supported")),
+        };
+        Ok((major, info, argument))
+    }
+
+    /// Returns the length of a string or container, which cannot exceed the
+    /// remaining data
+    fn length(&self, argument: u64) -> Result<usize, DecodeError> {
+        match usize::try_from(argument) {
+            Ok(len) if len <= self.data.len() - self.pos => Ok(len),
+            _ => Err(DecodeError("length exceeds data")),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(DecodeError("nesting is too deep"));
+        }
+        let (major, info, argument) = self.head()?;
+        let value = match major {
+            major::UNSIGNED => match i64::try_from(argument) {
+                Ok(value) => Value::Integer(value),
+                Err(_) => return Err(DecodeError("integer out of range")),
+            },
+            major::NEGATIVE => match i64::try_from(argument) {
+                Ok(value) => Value::Integer(!value),
+                Err(_) => return Err(DecodeError("integer out of range")),
+            },
+            major::BYTES => {
+                let len = self.length(argument)?;
+                Value::Bytes(self.take(len)?.to_vec())
+            }
+            major::TEXT => {
+                let len = self.length(argument)?;
+                match String::from_utf8(self.take(len)?.to_vec()) {
+                    Ok(text) => Value::Text(text),
+                    Err(_) => return Err(DecodeError("text is not UTF-8")),
+                }
+            }
+            major::ARRAY => {
+                let len = self.length(argument)?;
+                let values: Result<Vec<_>, _> = (0..len).map(|_| self.value(depth + 1)).collect();
+                Value::Array(values?)
+            }
+            major::MAP => {
+                let len = self.length(argument)?;
+                let mut entries = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let key = self.value(depth + 1)?;
+                    let value = self.value(depth + 1)?;
+                    entries.push((key, value));
+                }
+                Value::Map(entries)
+            }
+            major::SIMPLE => match info {
+                simple::FALSE => Value::Bool(false),
+                simple::TRUE => Value::Bool(true),
+                simple::NULL => Value::Null,
+                _ => return Err(DecodeError("unsupported simple value or float")),
+            },
+            _ => return Err(DecodeError("tags are not supported")),
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_known_values() {
+        // Examples from RFC 8949, appendix A
+        let examples: [(Value, &[u8]); 14] = [
+            (0.into(), &[0x00]),
+            (23.into(), &[0x17]),
+            (24.into(), &[0x18, 0x18]),
+            (1000.into(), &[0x19, 0x03, 0xe8]),
+            (1_000_000.into(), &[0x1a, 0x00, 0x0f, 0x42, 0x40]),
+            (
+                1_000_000_000_000i64.into(),
+                &[0x1b, 0x00, 0x00, 0x00, 0xe8, 0xd4, 0xa5, 0x10, 0x00],
+            ),
+            ((-1).into(), &[0x20]),
+            ((-100).into(), &[0x38, 0x63]),
+            ("a".into(), &[0x61, 0x61]),
+            (vec![1u8, 2, 3, 4].into(), &[0x44, 0x01, 0x02, 0x03, 0x04]),
+            (
+                vec![Value::from(1), vec![Value::from(2), Value::from(3)].into()].into(),
+                &[0x82, 0x01, 0x82, 0x02, 0x03],
+            ),
+            (false.into(), &[0xf4]),
+            (true.into(), &[0xf5]),
+            (Value::Null, &[0xf6]),
+        ];
+        for (value, data) in examples {
+            assert_eq!(value.encode(), data, "{value:?}");
+            assert_eq!(Value::decode(data), Ok(value));
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = [
+            Value::Integer(i64::MAX),
+            Value::Integer(i64::MIN),
+            Value::Integer(0xffff_ffff + 1),
+            Value::Bytes(vec![0xa5; 300]),
+            Value::Text("ünïcödé".to_string()),
+            Value::Array(Vec::new()),
+            // Entries in canonical order
+            Value::map([
+                (Value::from(1), Value::from("fido")),
+                (Value::from(-7), Value::map([("id", vec![0u8; 16])])),
+                (Value::from("rk"), Value::from(true)),
+            ]),
+        ];
+        for value in values {
+            assert_eq!(Value::decode(&value.encode()), Ok(value));
+        }
+    }
+
+    #[test]
+    fn canonical_map_order() {
+        let map = Value::map([
+            (Value::from("bb"), Value::Null),
+            (Value::from("a"), Value::Null),
+            (Value::from(-1), Value::Null),
+            (Value::from(256), Value::Null),
+            (Value::from(10), Value::Null),
+        ]);
+        let data = map.encode();
+        let decoded = Value::decode(&data).unwrap();
+        let Value::Map(entries) = decoded else {
+            panic!("Decoded {decoded:?} instead of a map");
+        };
+        let keys: Vec<Value> = entries.into_iter().map(|(key, _)| key).collect();
+        let expected = [10.into(), (-1).into(), "a".into(), 256.into(), "bb".into()];
+        assert_eq!(keys, expected);
+
+        // Lookup does not depend on the order
+        assert_eq!(map.get("a"), Some(&Value::Null));
+        assert_eq!(map.get("c"), None);
+    }
+
+    #[test]
+    fn depth_limit() {
+        // Arrays nested up to the maximum depth, around an empty array
+        let mut data = vec![0x81; MAX_DEPTH];
+        data.push(0x80);
+        assert!(Value::decode(&data).is_ok());
+
+        let mut data = vec![0x81; MAX_DEPTH + 1];
+        data.push(0x80);
+        assert_eq!(
+            Value::decode(&data),
+            Err(DecodeError("nesting is too deep"))
+        );
+
+        // Maps count towards the depth as well
+        let mut data = [0xa1, 0x00].repeat(MAX_DEPTH + 1);
+        data.push(0xf6);
+        assert_eq!(
+            Value::decode(&data),
+            Err(DecodeError("nesting is too deep"))
+        );
+    }
+
+    #[test]
+    fn length_limits() {
+        let too_long = DecodeError("length exceeds data");
+        // Strings longer than the data
+        assert_eq!(Value::decode(&[0x45, 0x01, 0x02]), Err(too_long.clone()));
+        assert_eq!(
+            Value::decode(&[0x7a, 0xff, 0xff, 0xff, 0xff]),
+            Err(too_long.clone())
+        );
+        // Containers announcing more items than bytes left
+        assert_eq!(
+            Value::decode(&[0x9a, 0xff, 0xff, 0xff, 0xff, 0x00]),
+            Err(too_long.clone())
+        );
+        let huge_map = [0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(Value::decode(&huge_map), Err(too_long));
+
+        // Truncated heads and missing items
+        let end = DecodeError("unexpected end of data");
+        assert_eq!(Value::decode(&[]), Err(end.clone()));
+        assert_eq!(Value::decode(&[0x19, 0x01]), Err(end.clone()));
+        assert_eq!(Value::decode(&[0x82, 0x01, 0x18]), Err(end));
+    }
+
+    #[test]
+    fn reject_unsupported_data() {
+        let invalid = [
+            // Trailing data after the item
+            &[0x01, 0x02][..],
+            // Unsigned integer larger than i64::MAX
+            &[0x1b, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            // Indefinite length byte string
+            &[0x5f, 0x41, 0x00, 0xff],
+            // Tagged item
+            &[0xc1, 0x00],
+            // Half precision float
+            &[0xf9, 0x3c, 0x00],
+            // Text that is not UTF-8
+            &[0x62, 0xff, 0xfe],
+        ];
+        for data in invalid {
+            assert!(Value::decode(data).is_err(), "{data:02x?}");
+        }
+    }
+}
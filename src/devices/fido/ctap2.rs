@@ -0,0 +1,48 @@
+//! CTAP2 commands and status codes (CTAP 2.1, sections 6 and 8)
+//!
+//! CBOR requests start with the command byte, and responses with the status
+//! byte, followed by the CBOR encoded parameters.
+
+/// Authenticator API commands
+pub mod command {
+    pub const MAKE_CREDENTIAL: u8 = 0x01;
+    pub const GET_ASSERTION: u8 = 0x02;
+    pub const GET_INFO: u8 = 0x04;
+    pub const CLIENT_PIN: u8 = 0x06;
+    pub const RESET: u8 = 0x07;
+    pub const GET_NEXT_ASSERTION: u8 = 0x08;
+    pub const SELECTION: u8 = 0x0b;
+}
+
+/// Status codes
+pub mod status {
+    pub const OK: u8 = 0x00;
+    pub const INVALID_COMMAND: u8 = 0x01;
+    pub const INVALID_LENGTH: u8 = 0x03;
+    pub const CBOR_UNEXPECTED_TYPE: u8 = 0x11;
+    pub const INVALID_CBOR: u8 = 0x12;
+    pub const MISSING_PARAMETER: u8 = 0x14;
+    pub const CREDENTIAL_EXCLUDED: u8 = 0x19;
+    pub const UNSUPPORTED_ALGORITHM: u8 = 0x26;
+    pub const UNSUPPORTED_OPTION: u8 = 0x2b;
+    pub const INVALID_OPTION: u8 = 0x2c;
+    pub const KEEPALIVE_CANCEL: u8 = 0x2d;
+    pub const NO_CREDENTIALS: u8 = 0x2e;
+    pub const USER_ACTION_TIMEOUT: u8 = 0x2f;
+    pub const NOT_ALLOWED: u8 = 0x30;
+    pub const PIN_NOT_SET: u8 = 0x35;
+    pub const OTHER: u8 = 0x7f;
+}
+
+/// COSE algorithm identifier of ECDSA with SHA-256 on P-256
+pub const ES256: i64 = -7;
+
+/// Flags of the authenticator data
+pub mod auth_data_flags {
+    /// User present
+    pub const UP: u8 = 0x01;
+    /// User verified
+    pub const UV: u8 = 0x04;
+    /// Attested credential data included
+    pub const AT: u8 = 0x40;
+}
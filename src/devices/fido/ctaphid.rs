@@ -0,0 +1,305 @@
+//! CTAPHID framing of CTAP messages in HID reports (CTAP 2.1, section 11.2)
+//!
+//! A message starts with an initialization packet carrying the channel ID,
+//! the command and the length of the message, and continues with up to 128
+//! continuation packets numbered from 0.
+
+use packed_struct::prelude::*;
+
+/// Size of the input and output reports
+pub const REPORT_SIZE: usize = 64;
+/// Size of the data of an initialization packet
+pub const INIT_DATA_SIZE: usize = REPORT_SIZE - 7;
+/// Size of the data of a continuation packet
+pub const CONT_DATA_SIZE: usize = REPORT_SIZE - 5;
+/// Largest message that fits in an initialization packet and 128
+/// continuation packets
+pub const MAX_MESSAGE_SIZE: usize = INIT_DATA_SIZE + 128 * CONT_DATA_SIZE;
+/// Channel ID used to allocate channels with INIT
+pub const BROADCAST_CID: u32 = 0xffff_ffff;
+/// Version of the CTAPHID protocol reported by INIT
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// CTAPHID commands, without the bit marking initialization packets
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum Command {
+    Ping = 0x01,
+    Msg = 0x03,
+    Lock = 0x04,
+    Init = 0x06,
+    Wink = 0x08,
+    Cbor = 0x10,
+    Cancel = 0x11,
+    Keepalive = 0x3b,
+    Error = 0x3f,
+}
+
+/// Error codes sent with the ERROR command
+pub mod error_code {
+    pub const INVALID_CMD: u8 = 0x01;
+    pub const INVALID_PAR: u8 = 0x02;
+    pub const INVALID_LEN: u8 = 0x03;
+    pub const INVALID_SEQ: u8 = 0x04;
+    pub const MSG_TIMEOUT: u8 = 0x05;
+    pub const CHANNEL_BUSY: u8 = 0x06;
+    pub const LOCK_REQUIRED: u8 = 0x0a;
+    pub const INVALID_CHANNEL: u8 = 0x0b;
+    pub const OTHER: u8 = 0x7f;
+}
+
+/// Capability flags reported by INIT
+pub mod capability {
+    /// The authenticator implements WINK
+    pub const WINK: u8 = 0x01;
+    /// The authenticator implements CBOR
+    pub const CBOR: u8 = 0x04;
+    /// The authenticator does not implement MSG
+    pub const NMSG: u8 = 0x08;
+}
+
+/// Status sent with the KEEPALIVE command
+pub mod keepalive_status {
+    pub const PROCESSING: u8 = 0x01;
+    pub const UP_NEEDED: u8 = 0x02;
+}
+
+/// Packet parsed from an output report
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet<'a> {
+    /// First packet of a message, with the command byte and the total length
+    /// of the message
+    Init {
+        cid: u32,
+        cmd: u8,
+        length: usize,
+        data: &'a [u8],
+    },
+    /// Following packet of a message, with its sequence number
+    Cont { cid: u32, seq: u8, data: &'a [u8] },
+}
+
+impl<'a> Packet<'a> {
+    /// Parse an output report, which is padded to [REPORT_SIZE] by the host.
+    /// Returns None if the report is too short to hold a packet header.
+    pub fn parse(report: &'a [u8]) -> Option<Self> {
+        let cid = u32::from_be_bytes(report.get(..4)?.try_into().ok()?);
+        let kind = *report.get(4)?;
+        if kind & 0x80 == 0 {
+            return Some(Self::Cont {
+                cid,
+                seq: kind,
+                data: &report[5..],
+            });
+        }
+        let length = u16::from_be_bytes([*report.get(5)?, *report.get(6)?]) as usize;
+        Some(Self::Init {
+            cid,
+            cmd: kind & 0x7f,
+            length,
+            data: &report[7..],
+        })
+    }
+
+    /// Returns the channel ID of the packet
+    pub fn cid(&self) -> u32 {
+        match self {
+            Self::Init { cid, .. } | Self::Cont { cid, .. } => *cid,
+        }
+    }
+}
+
+/// Split a message into the input reports sending it on the given channel
+pub fn fragment(cid: u32, cmd: Command, data: &[u8]) -> Vec<[u8; REPORT_SIZE]> {
+    let mut reports = Vec::new();
+    let mut init = [0; REPORT_SIZE];
+    init[..4].copy_from_slice(&cid.to_be_bytes());
+    init[4] = 0x80 | cmd.to_primitive();
+    init[5..7].copy_from_slice(&(data.len() as u16).to_be_bytes());
+    let (first, rest) = data.split_at(data.len().min(INIT_DATA_SIZE));
+    init[7..7 + first.len()].copy_from_slice(first);
+    reports.push(init);
+
+    for (seq, chunk) in rest.chunks(CONT_DATA_SIZE).enumerate() {
+        let mut cont = [0; REPORT_SIZE];
+        cont[..4].copy_from_slice(&cid.to_be_bytes());
+        cont[4] = seq as u8;
+        cont[5..5 + chunk.len()].copy_from_slice(chunk);
+        reports.push(cont);
+    }
+    reports
+}
+
+/// Message being reassembled from its packets
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    pub cid: u32,
+    pub cmd: u8,
+    length: usize,
+    data: Vec<u8>,
+    /// Sequence number of the next continuation packet
+    seq: u8,
+}
+
+impl Assembly {
+    /// Start reassembling a message from its initialization packet
+    pub fn new(cid: u32, cmd: u8, length: usize, data: &[u8]) -> Self {
+        let mut assembly = Self {
+            cid,
+            cmd,
+            length,
+            data: Vec::with_capacity(length),
+            seq: 0,
+        };
+        assembly.append(data);
+        assembly
+    }
+
+    /// Add the data of a continuation packet with the given sequence number.
+    /// Returns false if the packet is out of sequence.
+    pub fn push(&mut self, seq: u8, data: &[u8]) -> bool {
+        if seq != self.seq || seq > 0x7f {
+            return false;
+        }
+        self.seq += 1;
+        self.append(data);
+        true
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        let missing = self.length - self.data.len();
+        self.data
+            .extend_from_slice(&data[..data.len().min(missing)]);
+    }
+
+    /// Returns true once all the data of the message was received
+    pub fn is_complete(&self) -> bool {
+        self.data.len() == self.length
+    }
+
+    /// Returns the data of the message
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CID: u32 = 0x0102_0304;
+
+    /// Reassemble the message sent in the given reports
+    fn reassemble(reports: &[[u8; REPORT_SIZE]]) -> Option<Assembly> {
+        let Some(Packet::Init {
+            cid,
+            cmd,
+            length,
+            data,
+        }) = Packet::parse(&reports[0])
+        else {
+            return None;
+        };
+        let mut assembly = Assembly::new(cid, cmd, length, data);
+        for report in &reports[1..] {
+            let Some(Packet::Cont { cid, seq, data }) = Packet::parse(report) else {
+                return None;
+            };
+            assert_eq!(cid, assembly.cid);
+            if !assembly.push(seq, data) {
+                return None;
+            }
+        }
+        Some(assembly)
+    }
+
+    #[test]
+    fn parse_packets() {
+        let mut report = [0; REPORT_SIZE];
+        report[..7].copy_from_slice(&[0x01, 0x02, 0x03, 0x04, 0x90, 0x01, 0x00]);
+        let packet = Packet::parse(&report).unwrap();
+        assert_eq!(packet.cid(), CID);
+        assert_eq!(
+            packet,
+            Packet::Init {
+                cid: CID,
+                cmd: Command::Cbor.to_primitive(),
+                length: 256,
+                data: &[0; INIT_DATA_SIZE],
+            }
+        );
+
+        // Continuation packets carry data in place of the length
+        report[4] = 0x05;
+        let mut data = [0; CONT_DATA_SIZE];
+        data[0] = 0x01;
+        assert_eq!(
+            Packet::parse(&report),
+            Some(Packet::Cont {
+                cid: CID,
+                seq: 5,
+                data: &data,
+            })
+        );
+
+        // Reports too short for their header
+        assert_eq!(Packet::parse(&report[..4]), None);
+        report[4] = 0x86;
+        assert_eq!(Packet::parse(&report[..6]), None);
+        assert!(Packet::parse(&report[..7]).is_some());
+    }
+
+    #[test]
+    fn reassemble_fragments() {
+        let message: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let reports = fragment(CID, Command::Msg, &message);
+        assert_eq!(reports.len(), 4);
+        let continuations: Vec<u8> = reports[1..].iter().map(|r| r[4]).collect();
+        assert_eq!(continuations, [0, 1, 2]);
+
+        let assembly = reassemble(&reports).unwrap();
+        assert!(assembly.is_complete());
+        assert_eq!(assembly.cmd, Command::Msg.to_primitive());
+        // The padding of the last report is not part of the message
+        assert_eq!(assembly.into_data(), message);
+
+        // Messages that fit in the initialization packet are complete at once
+        let reports = fragment(CID, Command::Ping, b"ping");
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reassemble(&reports).unwrap().into_data(), b"ping");
+    }
+
+    #[test]
+    fn reassemble_largest_message() {
+        let message = vec![0xa5; MAX_MESSAGE_SIZE];
+        let reports = fragment(CID, Command::Cbor, &message);
+        assert_eq!(reports.len(), 129);
+        assert_eq!(reports[128][4], 127);
+        let assembly = reassemble(&reports).unwrap();
+        assert!(assembly.is_complete());
+        assert_eq!(assembly.into_data(), message);
+    }
+
+    #[test]
+    fn reject_out_of_sequence_packets() {
+        let message = vec![0x5a; 200];
+        let mut assembly = Assembly::new(CID, Command::Msg.to_primitive(), 200, &[0x5a; 57]);
+        let data = [0x5a; CONT_DATA_SIZE];
+
+        // Continuations start at 0
+        assert!(!assembly.push(1, &data));
+        assert!(assembly.push(0, &data));
+        // Repeated and skipped packets are rejected without adding data
+        assert!(!assembly.push(0, &data));
+        assert!(!assembly.push(2, &data));
+        assert!(assembly.push(1, &data));
+        assert!(!assembly.is_complete());
+        assert!(assembly.push(2, &data));
+        assert!(assembly.is_complete());
+        assert_eq!(assembly.into_data(), message);
+
+        // A swapped pair of reports breaks the reassembly
+        let mut reports = fragment(CID, Command::Msg, &message);
+        reports.swap(1, 2);
+        assert!(reassemble(&reports).is_none());
+    }
+}
@@ -0,0 +1,519 @@
+//! Software CTAP2 authenticator keeping its credentials in memory
+//!
+//! Credentials use ES256 keys, and are attested with packed self
+//! attestation. The authenticator has no PIN or built-in user verification,
+//! so it only sets the user present flag, after a touch simulated with
+//! [UserPresence::touch].
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use sha2::{Digest, Sha256};
+
+use super::{
+    cbor::Value,
+    ctap2::{auth_data_flags, command, status, ES256},
+    ctaphid::capability,
+    Authenticator, Response,
+};
+
+/// AAGUID identifying the model of the authenticator
+pub const AAGUID: [u8; 16] = [
+    0x76, 0x75, 0x73, 0x62, 0x2d, 0x73, 0x6f, 0x66, 0x74, 0x2d, 0x66, 0x69, 0x64, 0x6f, 0x00, 0x01,
+];
+/// Largest CBOR request accepted by the authenticator
+const MAX_MSG_SIZE: u32 = 1200;
+
+#[derive(Debug, Default)]
+struct PresenceState {
+    touched: bool,
+    always: bool,
+}
+
+/// Simulated presence of the user, which can be shared with other threads
+#[derive(Debug, Clone, Default)]
+pub struct UserPresence(Arc<Mutex<PresenceState>>);
+
+impl UserPresence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Simulate a touch of the key, which confirms the presence of the user
+    /// for the next request that needs it
+    pub fn touch(&self) {
+        if let Ok(mut state) = self.0.lock() {
+            state.touched = true;
+        }
+    }
+
+    /// Confirm the presence of the user for every request without waiting
+    /// for a touch, for unattended tests
+    pub fn set_always_present(&self, always: bool) {
+        if let Ok(mut state) = self.0.lock() {
+            state.always = always;
+        }
+    }
+
+    /// Returns true if the user is present, consuming the touch
+    fn take(&self) -> bool {
+        let Ok(mut state) = self.0.lock() else {
+            return false;
+        };
+        state.always || std::mem::take(&mut state.touched)
+    }
+}
+
+/// Credential created by the host with MakeCredential
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub id: Vec<u8>,
+    pub rp_id: String,
+    pub user_id: Vec<u8>,
+    /// Whether the credential is discoverable, i.e. a resident key that can
+    /// be used without its ID
+    pub discoverable: bool,
+    key: SigningKey,
+}
+
+impl Credential {
+    /// Returns the public key of the credential as an uncompressed SEC1
+    /// point
+    pub fn public_key(&self) -> Vec<u8> {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        point.as_bytes().to_vec()
+    }
+
+    /// Returns the public key of the credential as a COSE_Key
+    fn cose_key(&self) -> Value {
+        let point = self.public_key();
+        Value::map([
+            // kty: EC2
+            (1, Value::Integer(2)),
+            (3, Value::Integer(ES256)),
+            // crv: P-256
+            (-1, Value::Integer(1)),
+            (-2, Value::from(&point[1..33])),
+            (-3, Value::from(&point[33..])),
+        ])
+    }
+}
+
+/// Error processing a CTAP2 request
+enum CtapError {
+    Status(u8),
+    UserPresenceNeeded,
+}
+
+impl From<u8> for CtapError {
+    fn from(status: u8) -> Self {
+        Self::Status(status)
+    }
+}
+
+type CtapResult = Result<Option<Value>, CtapError>;
+
+/// Assertions left to be returned by GetNextAssertion
+#[derive(Debug)]
+struct NextAssertions {
+    credentials: VecDeque<usize>,
+    flags: u8,
+    client_data_hash: Vec<u8>,
+}
+
+/// CTAP2 authenticator storing ES256 credentials in memory
+#[derive(Debug)]
+pub struct SoftAuthenticator {
+    /// Secret the IDs and keys of credentials are derived from
+    seed: [u8; 32],
+    credentials: Vec<Credential>,
+    /// Number of credentials created, which derives the next credential
+    created: u64,
+    sign_count: u32,
+    presence: UserPresence,
+    next_assertions: Option<NextAssertions>,
+    winks: usize,
+}
+
+impl SoftAuthenticator {
+    /// Create an authenticator with a seed derived from the current time
+    pub fn new() -> Self {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut hasher = Sha256::new();
+        hasher.update(now.as_nanos().to_le_bytes());
+        hasher.update(std::process::id().to_le_bytes());
+        Self::with_seed(hasher.finalize().into())
+    }
+
+    /// Create an authenticator deriving the IDs and keys of its credentials
+    /// from the given seed, so tests can reproduce them
+    pub fn with_seed(seed: [u8; 32]) -> Self {
+        Self {
+            seed,
+            credentials: Vec::new(),
+            created: 0,
+            sign_count: 0,
+            presence: UserPresence::new(),
+            next_assertions: None,
+            winks: 0,
+        }
+    }
+
+    /// The simulated presence of the user
+    pub fn presence(&self) -> &UserPresence {
+        &self.presence
+    }
+
+    /// The credentials created by the host
+    pub fn credentials(&self) -> &[Credential] {
+        &self.credentials
+    }
+
+    /// The signature counter, incremented by every signature
+    pub fn sign_count(&self) -> u32 {
+        self.sign_count
+    }
+
+    /// Number of WINK requests received
+    pub fn winks(&self) -> usize {
+        self.winks
+    }
+
+    fn require_presence(&self) -> Result<(), CtapError> {
+        match self.presence.take() {
+            true => Ok(()),
+            false => Err(CtapError::UserPresenceNeeded),
+        }
+    }
+
+    /// Create a credential for the given relying party and user
+    fn create_credential(&mut self, rp_id: &str, user_id: &[u8]) -> Result<Credential, CtapError> {
+        self.created += 1;
+        let mut hasher = Sha256::new();
+        hasher.update(self.seed);
+        hasher.update(b"credential");
+        hasher.update(self.created.to_be_bytes());
+        let id = hasher.finalize().to_vec();
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.seed);
+        hasher.update(&id);
+        let key = SigningKey::from_slice(&hasher.finalize()).map_err(|_| status::OTHER)?;
+
+        Ok(Credential {
+            id,
+            rp_id: rp_id.to_string(),
+            user_id: user_id.to_vec(),
+            discoverable: false,
+            key,
+        })
+    }
+
+    /// Returns the authenticator data for the given relying party, which
+    /// increments the signature counter
+    fn auth_data(&mut self, rp_id: &str, flags: u8) -> Vec<u8> {
+        self.sign_count += 1;
+        let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+        data
+    }
+
+    fn get_info(&self) -> CtapResult {
+        Ok(Some(Value::map([
+            (1, Value::Array(vec!["FIDO_2_0".into()])),
+            (3, Value::Bytes(AAGUID.to_vec())),
+            (4, Value::map([("rk", true), ("up", true), ("plat", false)])),
+            (5, Value::from(MAX_MSG_SIZE)),
+        ])))
+    }
+
+    fn make_credential(&mut self, params: &Value) -> CtapResult {
+        let client_data_hash = bytes_param(params, 1)?;
+        let rp_id = params.get(2).and_then(|rp| rp.get("id"));
+        let rp_id = rp_id
+            .and_then(Value::as_text)
+            .ok_or(status::MISSING_PARAMETER)?;
+        let user_id = params.get(3).and_then(|user| user.get("id"));
+        let user_id = user_id
+            .and_then(Value::as_bytes)
+            .ok_or(status::MISSING_PARAMETER)?;
+        let algorithms = params.get(4).and_then(Value::as_array);
+        let algorithms = algorithms.ok_or(status::MISSING_PARAMETER)?;
+        let es256 = algorithms.iter().any(|param| {
+            param.get("type").and_then(Value::as_text) == Some("public-key")
+                && param.get("alg").and_then(Value::as_integer) == Some(ES256)
+        });
+        if !es256 {
+            return Err(status::UNSUPPORTED_ALGORITHM.into());
+        }
+        let discoverable = option(params, 7, "rk")?.unwrap_or(false);
+        if option(params, 7, "uv")? == Some(true) {
+            return Err(status::UNSUPPORTED_OPTION.into());
+        }
+        if option(params, 7, "up")? == Some(false) {
+            return Err(status::INVALID_OPTION.into());
+        }
+        self.check_pin_auth(params, 8)?;
+
+        let excluded = credential_ids(params, 5)?;
+        if self.find(rp_id, &excluded).next().is_some() {
+            self.require_presence()?;
+            return Err(status::CREDENTIAL_EXCLUDED.into());
+        }
+        self.require_presence()?;
+
+        let mut credential = self.create_credential(rp_id, user_id)?;
+        credential.discoverable = discoverable;
+        if discoverable {
+            // A new resident key replaces the one of the same user
+            self.credentials
+                .retain(|c| !(c.discoverable && c.rp_id == rp_id && c.user_id == user_id));
+        }
+        #[cfg(feature = "log")]
+        log::info!("Created credential for {rp_id}");
+
+        let mut auth_data = self.auth_data(rp_id, auth_data_flags::UP | auth_data_flags::AT);
+        auth_data.extend_from_slice(&AAGUID);
+        auth_data.extend_from_slice(&(credential.id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&credential.id);
+        auth_data.extend_from_slice(&credential.cose_key().encode());
+        let signature = sign(&credential.key, &auth_data, client_data_hash);
+        self.credentials.push(credential);
+
+        Ok(Some(Value::map([
+            (1, Value::from("packed")),
+            (2, Value::Bytes(auth_data)),
+            (
+                3,
+                Value::map([
+                    ("alg", Value::Integer(ES256)),
+                    ("sig", Value::Bytes(signature)),
+                ]),
+            ),
+        ])))
+    }
+
+    fn get_assertion(&mut self, params: &Value) -> CtapResult {
+        let rp_id = params.get(1).and_then(Value::as_text);
+        let rp_id = rp_id.ok_or(status::MISSING_PARAMETER)?;
+        let client_data_hash = bytes_param(params, 2)?;
+        let allowed = credential_ids(params, 3)?;
+        if option(params, 5, "uv")? == Some(true) {
+            return Err(status::UNSUPPORTED_OPTION.into());
+        }
+        if option(params, 5, "rk")?.is_some() {
+            return Err(status::UNSUPPORTED_OPTION.into());
+        }
+        let user_present = option(params, 5, "up")?.unwrap_or(true);
+        self.check_pin_auth(params, 6)?;
+
+        // Without an allow list, use the discoverable credentials, newest
+        // first
+        let mut matching: VecDeque<usize> = match allowed.is_empty() {
+            true => (0..self.credentials.len())
+                .rev()
+                .filter(|i| {
+                    let credential = &self.credentials[*i];
+                    credential.discoverable && credential.rp_id == rp_id
+                })
+                .collect(),
+            false => self.find(rp_id, &allowed).collect(),
+        };
+        let Some(first) = matching.pop_front() else {
+            return Err(status::NO_CREDENTIALS.into());
+        };
+        if user_present {
+            self.require_presence()?;
+        }
+
+        let flags = match user_present {
+            true => auth_data_flags::UP,
+            false => 0,
+        };
+        let count = matching.len() + 1;
+        self.next_assertions = match matching.is_empty() {
+            true => None,
+            false => Some(NextAssertions {
+                credentials: matching,
+                flags,
+                client_data_hash: client_data_hash.to_vec(),
+            }),
+        };
+        let count = (count > 1).then_some(count);
+        Ok(Some(self.assertion(first, flags, client_data_hash, count)))
+    }
+
+    fn get_next_assertion(&mut self) -> CtapResult {
+        let Some(next) = self.next_assertions.as_mut() else {
+            return Err(status::NOT_ALLOWED.into());
+        };
+        let Some(index) = next.credentials.pop_front() else {
+            return Err(status::NOT_ALLOWED.into());
+        };
+        let (flags, client_data_hash) = (next.flags, next.client_data_hash.clone());
+        Ok(Some(self.assertion(index, flags, &client_data_hash, None)))
+    }
+
+    /// Returns the assertion of the credential with the given index
+    fn assertion(
+        &mut self,
+        index: usize,
+        flags: u8,
+        client_data_hash: &[u8],
+        count: Option<usize>,
+    ) -> Value {
+        let rp_id = self.credentials[index].rp_id.clone();
+        let auth_data = self.auth_data(&rp_id, flags);
+        let credential = &self.credentials[index];
+        let signature = sign(&credential.key, &auth_data, client_data_hash);
+
+        let mut response = vec![
+            (
+                Value::Integer(1),
+                Value::map([
+                    ("id", Value::Bytes(credential.id.clone())),
+                    ("type", Value::from("public-key")),
+                ]),
+            ),
+            (Value::Integer(2), Value::Bytes(auth_data)),
+            (Value::Integer(3), Value::Bytes(signature)),
+        ];
+        if credential.discoverable {
+            let user = Value::map([("id", Value::Bytes(credential.user_id.clone()))]);
+            response.push((Value::Integer(4), user));
+        }
+        if let Some(count) = count {
+            response.push((Value::Integer(5), Value::Integer(count as i64)));
+        }
+        Value::Map(response)
+    }
+
+    fn reset(&mut self) -> CtapResult {
+        self.require_presence()?;
+        #[cfg(feature = "log")]
+        log::info!("Deleting all credentials");
+        self.credentials.clear();
+        self.next_assertions = None;
+        Ok(None)
+    }
+
+    /// The authenticator has no PIN, so requests authenticated with one are
+    /// refused once the user touched the key
+    fn check_pin_auth(&self, params: &Value, key: i64) -> Result<(), CtapError> {
+        if params.get(key).is_none() {
+            return Ok(());
+        }
+        self.require_presence()?;
+        Err(status::PIN_NOT_SET.into())
+    }
+
+    /// Returns the indices of the credentials of the relying party with the
+    /// given IDs
+    fn find<'a>(&'a self, rp_id: &'a str, ids: &'a [Vec<u8>]) -> impl Iterator<Item = usize> + 'a {
+        self.credentials
+            .iter()
+            .enumerate()
+            .filter(move |(_, c)| c.rp_id == rp_id && ids.contains(&c.id))
+            .map(|(i, _)| i)
+    }
+}
+
+impl Default for SoftAuthenticator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Authenticator for SoftAuthenticator {
+    fn capabilities(&self) -> u8 {
+        capability::WINK | capability::CBOR | capability::NMSG
+    }
+
+    fn cbor(&mut self, request: &[u8]) -> Response {
+        let Some((&cmd, params)) = request.split_first() else {
+            return Response::Ready(vec![status::INVALID_LENGTH]);
+        };
+        let params = match params.is_empty() {
+            true => Value::Map(Vec::new()),
+            false => match Value::decode(params) {
+                Ok(params @ Value::Map(_)) => params,
+                Ok(_) => return Response::Ready(vec![status::CBOR_UNEXPECTED_TYPE]),
+                Err(_) => return Response::Ready(vec![status::INVALID_CBOR]),
+            },
+        };
+
+        let result = match cmd {
+            command::GET_INFO => self.get_info(),
+            command::MAKE_CREDENTIAL => self.make_credential(&params),
+            command::GET_ASSERTION => self.get_assertion(&params),
+            command::GET_NEXT_ASSERTION => self.get_next_assertion(),
+            command::RESET => self.reset(),
+            command::SELECTION => self.require_presence().map(|_| None),
+            _ => Err(status::INVALID_COMMAND.into()),
+        };
+        match result {
+            Ok(Some(value)) => {
+                let mut response = vec![status::OK];
+                response.extend_from_slice(&value.encode());
+                Response::Ready(response)
+            }
+            Ok(None) => Response::Ready(vec![status::OK]),
+            Err(CtapError::Status(status)) => Response::Ready(vec![status]),
+            Err(CtapError::UserPresenceNeeded) => Response::UserPresenceNeeded,
+        }
+    }
+
+    fn wink(&mut self) {
+        #[cfg(feature = "log")]
+        log::info!("Wink");
+        self.winks += 1;
+    }
+}
+
+/// Returns the byte string parameter with the given key
+fn bytes_param(params: &Value, key: i64) -> Result<&[u8], CtapError> {
+    match params.get(key) {
+        Some(Value::Bytes(bytes)) => Ok(bytes),
+        Some(_) => Err(status::CBOR_UNEXPECTED_TYPE.into()),
+        None => Err(status::MISSING_PARAMETER.into()),
+    }
+}
+
+/// Returns the given option from the options map with the given key
+fn option(params: &Value, key: i64, name: &str) -> Result<Option<bool>, CtapError> {
+    match params.get(key).and_then(|options| options.get(name)) {
+        Some(Value::Bool(value)) => Ok(Some(*value)),
+        Some(_) => Err(status::CBOR_UNEXPECTED_TYPE.into()),
+        None => Ok(None),
+    }
+}
+
+/// Returns the IDs of the list of credential descriptors with the given key
+fn credential_ids(params: &Value, key: i64) -> Result<Vec<Vec<u8>>, CtapError> {
+    let Some(list) = params.get(key) else {
+        return Ok(Vec::new());
+    };
+    let list = list.as_array().ok_or(status::CBOR_UNEXPECTED_TYPE)?;
+    list.iter()
+        .map(|descriptor| match descriptor.get("id") {
+            Some(Value::Bytes(id)) => Ok(id.clone()),
+            _ => Err(status::CBOR_UNEXPECTED_TYPE.into()),
+        })
+        .collect()
+}
+
+/// Returns the DER encoded ES256 signature of the authenticator data and
+/// client data hash
+fn sign(key: &SigningKey, auth_data: &[u8], client_data_hash: &[u8]) -> Vec<u8> {
+    let mut message = auth_data.to_vec();
+    message.extend_from_slice(client_data_hash);
+    let signature: Signature = key.sign(&message);
+    signature.to_der().as_bytes().to_vec()
+}
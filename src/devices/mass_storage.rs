@@ -0,0 +1,336 @@
+//! USB flash drive backed by a block device
+//!
+//! Emulates a Bulk-Only Transport mass storage device with a single SCSI
+//! logical unit, which the host binds with the usb-storage driver. The medium
+//! is a [BlockDevice], such as a [FileImage] holding a disk image, a
+//! [MemoryDisk] or a [ReadOnly] view of either.
+//!
+//! Data stages shorter than requested by the host are padded with zeros and
+//! the difference is reported as residue in the Command Status Wrapper.
+//! Commands whose data stage does not match the direction or length expected
+//! by the host are reported as phase errors.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    sync::mpsc::{channel, Receiver},
+    thread,
+    time::Duration,
+};
+
+use packed_struct::PackedStructSlice;
+
+pub use crate::usb::msc::block::{BlockDevice, FileImage, MemoryDisk, ReadOnly};
+use crate::{
+    devices::Device,
+    usb::{
+        msc::{
+            scsi::ScsiDisk, CommandBlockWrapper, CommandStatusWrapper, CswStatus,
+            MscInterfaceBuilder, CBW_SIZE,
+        },
+        ConfigurationBuilder, DeviceClass, Direction, LangId,
+    },
+    usbip::UsbIpDirection,
+    virtual_usb::{Reply, VirtualUSBDevice, VirtualUSBDeviceBuilder, Xfer},
+};
+
+/// Vendor ID of the drive (NetChip, used by the Linux mass storage gadget)
+pub const VENDOR_ID: u16 = 0x0525;
+/// Product ID of the drive (File-backed Storage Gadget)
+pub const PRODUCT_ID: u16 = 0xa4a5;
+/// Number of the bulk IN and bulk OUT endpoints
+pub const DATA_ENDPOINT: u8 = 1;
+/// Max packet size of the bulk endpoints (high speed)
+const MAX_PACKET_SIZE: u16 = 512;
+
+/// Phase of the Bulk-Only Transport
+#[derive(Debug)]
+enum BotState {
+    /// Waiting for a Command Block Wrapper
+    Command,
+    /// Receiving the data of a command from the host
+    DataOut {
+        cbw: CommandBlockWrapper,
+        data: Vec<u8>,
+    },
+    /// Sending the data of a command to the host, followed by the status
+    DataIn {
+        data: VecDeque<u8>,
+        csw: CommandStatusWrapper,
+    },
+    /// Sending the Command Status Wrapper
+    Status(CommandStatusWrapper),
+}
+
+/// Virtual USB flash drive serving SCSI commands from a [BlockDevice]
+#[derive(Debug)]
+pub struct MassStorage<D: BlockDevice> {
+    device: VirtualUSBDevice,
+    disk: ScsiDisk<D>,
+    resets: Receiver<()>,
+    state: BotState,
+    /// Bulk IN transfers waiting for data or status
+    pending_in: VecDeque<Xfer>,
+    /// Whether the next command reports a phase error
+    phase_error: bool,
+}
+
+impl<D: BlockDevice> MassStorage<D> {
+    /// Create a flash drive with the given block device as medium
+    pub fn new(disk: D) -> Self {
+        let (tx, rx) = channel();
+
+        let mut iface = MscInterfaceBuilder::new();
+        iface
+            .bulk_endpoints(DATA_ENDPOINT, DATA_ENDPOINT, MAX_PACKET_SIZE)
+            .on_reset(move || {
+                let _ = tx.send(());
+            });
+
+        // The Bulk-Only Transport requires a serial number
+        let device = VirtualUSBDeviceBuilder::new(VENDOR_ID, PRODUCT_ID)
+            .class(DeviceClass::UseInterface)
+            .supported_langs(vec![LangId::EnglishUnitedStates])
+            .manufacturer("Linux")
+            .product("File-Stor Gadget")
+            .serial("0123456789AB")
+            .max_packet_size(64)
+            .configuration(
+                ConfigurationBuilder::new()
+                    .max_power(100)
+                    .interface(iface.build())
+                    .build(),
+            )
+            .build();
+
+        Self {
+            device,
+            disk: ScsiDisk::new(disk),
+            resets: rx,
+            state: BotState::Command,
+            pending_in: VecDeque::new(),
+            phase_error: false,
+        }
+    }
+
+    /// The SCSI logical unit of the drive
+    pub fn disk(&self) -> &ScsiDisk<D> {
+        &self.disk
+    }
+
+    /// The SCSI logical unit of the drive, e.g. to change the INQUIRY
+    /// strings before the drive is attached, or to swap, eject or write
+    /// protect the medium and inject faults between calls to
+    /// [MassStorage::poll]
+    pub fn disk_mut(&mut self) -> &mut ScsiDisk<D> {
+        &mut self.disk
+    }
+
+    /// Make the next command fail with a phase error in its Command Status
+    /// Wrapper without being executed
+    pub fn inject_phase_error(&mut self) {
+        self.phase_error = true;
+    }
+
+    /// Return to the command phase after BULK_ONLY_RESET
+    fn handle_resets(&mut self) {
+        while self.resets.try_recv().is_ok() {
+            #[cfg(feature = "log")]
+            log::debug!("Bulk-Only Transport reset");
+            self.state = BotState::Command;
+        }
+    }
+
+    /// Handle a transfer that was not handled by the device itself
+    fn handle_xfer(&mut self, xfer: Xfer) -> Result<(), Box<dyn Error>> {
+        match (xfer.direction(), xfer.ep) {
+            (UsbIpDirection::In, DATA_ENDPOINT) => {
+                self.pending_in.push_back(xfer);
+                self.send_pending()
+            }
+            (UsbIpDirection::Out, DATA_ENDPOINT) => {
+                self.handle_data_out(&xfer.data);
+                self.send_pending()
+            }
+            (_direction, _ep) => {
+                #[cfg(feature = "log")]
+                log::debug!("Ignoring {_direction:?} transfer on endpoint {_ep}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Handle data sent by the host on the bulk OUT endpoint
+    fn handle_data_out(&mut self, data: &[u8]) {
+        match &mut self.state {
+            BotState::Command => self.handle_cbw(data),
+            BotState::DataOut {
+                cbw,
+                data: received,
+            } => {
+                received.extend_from_slice(data);
+                if received.len() < cbw.data_transfer_length() {
+                    return;
+                }
+                let cbw = *cbw;
+                let data = std::mem::take(received);
+                let csw = self.execute_data_out(&cbw, &data);
+                self.state = BotState::Status(csw);
+            }
+            _ => {
+                #[cfg(feature = "log")]
+                log::warn!("Ignoring {} bytes outside of a data stage", data.len());
+            }
+        }
+    }
+
+    /// Execute a command once the data announced in its Command Block
+    /// Wrapper has been received
+    fn execute_data_out(&mut self, cbw: &CommandBlockWrapper, data: &[u8]) -> CommandStatusWrapper {
+        let expected = cbw.data_transfer_length();
+        let needed = self.disk.data_out_length(cbw.command_block());
+
+        // The host sends less data than the command needs (Ho < Do)
+        if std::mem::take(&mut self.phase_error) || needed > expected {
+            return self.phase_error_csw(cbw);
+        }
+        let residue = (expected - needed) as u32;
+        match self.disk.execute(cbw.command_block(), data) {
+            // The command sends data while the host sends data (Ho <> Di)
+            Ok(data) if !data.is_empty() => self.phase_error_csw(cbw),
+            Ok(_) => CommandStatusWrapper::new(cbw, residue, CswStatus::Passed),
+            Err(_) => CommandStatusWrapper::new(cbw, residue, CswStatus::Failed),
+        }
+    }
+
+    /// Execute the command of a Command Block Wrapper
+    fn handle_cbw(&mut self, data: &[u8]) {
+        let cbw = match data.len() {
+            CBW_SIZE => CommandBlockWrapper::unpack_from_slice(data).ok(),
+            _ => None,
+        };
+        let Some(cbw) = cbw.filter(|cbw| cbw.is_valid()) else {
+            #[cfg(feature = "log")]
+            log::warn!("Ignoring invalid CBW of {} bytes", data.len());
+            return;
+        };
+
+        let expected = cbw.data_transfer_length();
+        if cbw.direction() == Direction::Out && expected > 0 {
+            // The host controls the expected length, so only reserve what
+            // the command can use
+            let needed = self.disk.data_out_length(cbw.command_block());
+            self.state = BotState::DataOut {
+                cbw,
+                data: Vec::with_capacity(needed.min(expected)),
+            };
+            return;
+        }
+
+        // The command needs data while the host expects none or wants to
+        // receive data (Hn < Do, Hi <> Do)
+        let needed = self.disk.data_out_length(cbw.command_block());
+        let (data, csw) = if std::mem::take(&mut self.phase_error) || needed > 0 {
+            (Vec::new(), self.phase_error_csw(&cbw))
+        } else {
+            match self.disk.execute(cbw.command_block(), &[]) {
+                // The command sends more data than the host expects (Hn < Di,
+                // Hi < Di)
+                Ok(data) if data.len() > expected => (Vec::new(), self.phase_error_csw(&cbw)),
+                Ok(data) => {
+                    let residue = (expected - data.len()) as u32;
+                    let csw = CommandStatusWrapper::new(&cbw, residue, CswStatus::Passed);
+                    (data, csw)
+                }
+                Err(_) => {
+                    let csw = CommandStatusWrapper::new(&cbw, expected as u32, CswStatus::Failed);
+                    (Vec::new(), csw)
+                }
+            }
+        };
+        if expected == 0 {
+            self.state = BotState::Status(csw);
+            return;
+        }
+
+        // Less data than the host expects ends with a short packet, and the
+        // CSW residue reports the difference
+        self.state = BotState::DataIn {
+            data: data.into(),
+            csw,
+        };
+    }
+
+    /// Returns a Command Status Wrapper reporting a phase error for the
+    /// given command. The host answers it with a reset recovery.
+    fn phase_error_csw(&self, cbw: &CommandBlockWrapper) -> CommandStatusWrapper {
+        #[cfg(feature = "log")]
+        log::warn!("Phase error on command {:#x?}", cbw.command_block().first());
+        CommandStatusWrapper::new(cbw, 0, CswStatus::PhaseError)
+    }
+
+    /// Answer pending bulk IN transfers with data or status
+    fn send_pending(&mut self) -> Result<(), Box<dyn Error>> {
+        self.pending_in
+            .retain(|xfer| !self.device.is_unlinked(xfer.seqnum()));
+        loop {
+            let reply = match &mut self.state {
+                BotState::DataIn { data, csw } => {
+                    let Some(xfer) = self.pending_in.pop_front() else {
+                        break;
+                    };
+                    let len = xfer.buffer_length().min(data.len());
+                    let chunk: Vec<u8> = data.drain(..len).collect();
+                    if data.is_empty() {
+                        self.state = BotState::Status(*csw);
+                    }
+                    Reply::from_xfer(xfer, &chunk)
+                }
+                BotState::Status(csw) => {
+                    let Some(xfer) = self.pending_in.pop_front() else {
+                        break;
+                    };
+                    let csw = csw.pack_to_vec()?;
+                    self.state = BotState::Command;
+                    Reply::from_xfer(xfer, &csw)
+                }
+                _ => break,
+            };
+            self.device.write(reply)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> Device for MassStorage<D> {
+    fn device(&self) -> &VirtualUSBDevice {
+        &self.device
+    }
+
+    fn device_mut(&mut self) -> &mut VirtualUSBDevice {
+        &mut self.device
+    }
+
+    /// Handle the next pending USB transfer or host request, if any. Waits up
+    /// to the given timeout if there was nothing to handle.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        match self.device.read()? {
+            Some(xfer) => self.handle_xfer(xfer)?,
+            None => thread::sleep(timeout),
+        }
+        self.handle_resets();
+        Ok(())
+    }
+
+    /// Attach the virtual drive and serve commands until an error occurs.
+    /// Transfers are waited for without polling.
+    fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        self.start()?;
+        loop {
+            if let Some(xfer) = self.device.blocking_read()? {
+                self.handle_xfer(xfer)?;
+            }
+            self.handle_resets();
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! USB MIDI interface exchanging MIDI messages with the host
+//!
+//! Emulates a USB MIDI 1.0 device, which the host binds with the
+//! snd-usb-audio driver and exposes as ALSA rawmidi and sequencer ports, one
+//! per virtual cable and direction. Messages are sent to the host with
+//! [UsbMidi::send_midi], and the messages sent by the host are passed to the
+//! handlers registered with [UsbMidi::on_message].
+//!
+//! The byte stream of each cable is split into messages, so handlers receive
+//! complete messages, with running status expanded and SysEx messages
+//! joined.
+
+use std::{error::Error, fmt::Debug, thread, time::Duration};
+
+pub use crate::usb::midi::MAX_CABLES;
+use crate::{
+    devices::{read_available, Device},
+    usb::{
+        midi::{
+            packet_cable, MidiStreamingInterfaceBuilder, PacketDecoder, PacketEncoder,
+            EVENT_PACKET_SIZE,
+        },
+        uac::{UacControlInterfaceBuilder, UacVersion},
+        ConfigurationBuilder, DeviceClass, LangId,
+    },
+    virtual_usb::{BulkIn, BulkOut, VirtualUSBDevice, VirtualUSBDeviceBuilder},
+};
+
+/// Vendor ID of the MIDI interface (Grey Innovation, used by the Linux MIDI
+/// gadget)
+pub const VENDOR_ID: u16 = 0x17b3;
+/// Product ID of the MIDI interface (Linux-USB MIDI Gadget)
+pub const PRODUCT_ID: u16 = 0x0004;
+/// Number of the bulk OUT and bulk IN endpoints
+pub const BULK_ENDPOINT: u8 = 1;
+/// Max packet size of the bulk endpoints (full speed, as most MIDI devices)
+const MAX_PACKET_SIZE: u16 = 64;
+
+/// Handler of the messages sent by the host, called with the cable number
+/// and a complete MIDI message
+type MessageHandler = Box<dyn FnMut(u8, &[u8]) + Send>;
+
+/// Virtual USB MIDI interface with up to 16 cables in each direction
+pub struct UsbMidi {
+    device: VirtualUSBDevice,
+    cables: u8,
+    bulk_in: BulkIn,
+    bulk_out: BulkOut,
+    /// Encoders of the cables to the host
+    encoders: Vec<PacketEncoder>,
+    /// Decoders of the cables from the host
+    decoders: Vec<PacketDecoder>,
+    /// Bytes of a packet split across reads
+    packet: Vec<u8>,
+    handlers: Vec<MessageHandler>,
+}
+
+impl Debug for UsbMidi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UsbMidi")
+            .field("device", &self.device)
+            .field("cables", &self.cables)
+            .field("handlers", &self.handlers.len())
+            .finish()
+    }
+}
+
+impl UsbMidi {
+    /// Create a MIDI interface with the given number of cables in each
+    /// direction, up to 16
+    pub fn new(cables: u8) -> Result<Self, Box<dyn Error>> {
+        if cables == 0 || cables > MAX_CABLES {
+            return Err(format!("Invalid number of cables: {cables}").into());
+        }
+
+        // MIDI functions use an interface association
+        let mut device = VirtualUSBDeviceBuilder::new(VENDOR_ID, PRODUCT_ID)
+            .class(DeviceClass::Miscellaneous)
+            .subclass(0x02)
+            .protocol(0x01)
+            .supported_langs(vec![LangId::EnglishUnitedStates])
+            .manufacturer("Linux")
+            .product("MIDI Gadget")
+            .max_packet_size(64)
+            .configuration(
+                ConfigurationBuilder::new()
+                    .max_power(100)
+                    .interface(
+                        UacControlInterfaceBuilder::new(UacVersion::Uac1)
+                            .midi_streaming()
+                            .build(),
+                    )
+                    .interface(
+                        MidiStreamingInterfaceBuilder::new()
+                            .cables(cables, cables)
+                            .bulk_endpoints(BULK_ENDPOINT, BULK_ENDPOINT, MAX_PACKET_SIZE)
+                            .build(),
+                    )
+                    .build(),
+            )
+            .build();
+        let bulk_in = device.bulk_in(BULK_ENDPOINT)?;
+        let bulk_out = device.bulk_out(BULK_ENDPOINT)?;
+
+        Ok(Self {
+            device,
+            cables,
+            bulk_in,
+            bulk_out,
+            encoders: vec![PacketEncoder::new(); cables as usize],
+            decoders: vec![PacketDecoder::new(); cables as usize],
+            packet: Vec::with_capacity(EVENT_PACKET_SIZE),
+            handlers: Vec::new(),
+        })
+    }
+
+    /// The number of cables in each direction
+    pub fn cables(&self) -> u8 {
+        self.cables
+    }
+
+    /// Send MIDI bytes to the host on the given cable. Messages may span
+    /// several calls, and may use running status.
+    pub fn send_midi(&mut self, cable: u8, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        let Some(encoder) = self.encoders.get_mut(cable as usize) else {
+            return Err(format!("No cable {cable}").into());
+        };
+        let packets = encoder.encode(cable, bytes);
+        if packets.is_empty() {
+            return Ok(());
+        }
+        self.bulk_in.write_transfer(&packets.concat())?;
+        Ok(())
+    }
+
+    /// Call the given handler with the cable number and each complete
+    /// message sent by the host
+    pub fn on_message<F>(&mut self, handler: F)
+    where
+        F: FnMut(u8, &[u8]) + Send + 'static,
+    {
+        self.handlers.push(Box::new(handler));
+    }
+
+    /// Decode the event packets received on the bulk OUT endpoint
+    fn handle_packets(&mut self) -> Result<(), Box<dyn Error>> {
+        read_available(&mut self.bulk_out, &mut self.packet)?;
+        let len = self.packet.len() - self.packet.len() % EVENT_PACKET_SIZE;
+        let data: Vec<u8> = self.packet.drain(..len).collect();
+        for chunk in data.chunks_exact(EVENT_PACKET_SIZE) {
+            let mut packet = [0; EVENT_PACKET_SIZE];
+            packet.copy_from_slice(chunk);
+            self.handle_packet(&packet);
+        }
+        Ok(())
+    }
+
+    /// Decode an event packet, and pass the message it completes to the
+    /// handlers
+    fn handle_packet(&mut self, packet: &[u8; EVENT_PACKET_SIZE]) {
+        let cable = packet_cable(packet);
+        let Some(decoder) = self.decoders.get_mut(cable as usize) else {
+            #[cfg(feature = "log")]
+            log::debug!("Dropping packet for unknown cable {cable}: {packet:02x?}");
+            return;
+        };
+        let Some(message) = decoder.decode(packet) else {
+            return;
+        };
+        #[cfg(feature = "log")]
+        log::debug!("MIDI message on cable {cable}: {message:02x?}");
+        for handler in self.handlers.iter_mut() {
+            handler(cable, &message);
+        }
+    }
+}
+
+impl Device for UsbMidi {
+    fn device(&self) -> &VirtualUSBDevice {
+        &self.device
+    }
+
+    fn device_mut(&mut self) -> &mut VirtualUSBDevice {
+        &mut self.device
+    }
+
+    /// Handle the next pending USB transfer or host request, if any, and
+    /// pass the messages received to the handlers. Waits up to the given
+    /// timeout if there was nothing to handle.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        match self.device.read()? {
+            Some(_xfer) => {
+                #[cfg(feature = "log")]
+                log::debug!(
+                    "Ignoring {:?} transfer on endpoint {}",
+                    _xfer.direction(),
+                    _xfer.ep
+                );
+            }
+            None => thread::sleep(timeout),
+        }
+        self.handle_packets()
+    }
+}
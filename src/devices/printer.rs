@@ -0,0 +1,331 @@
+//! USB printer writing print jobs to a [PrintSink]
+//!
+//! Emulates a USB printer class device, which the host binds with the usblp
+//! driver or accesses with the CUPS `usb` backend. Data sent by the host on
+//! the bulk OUT endpoint is delivered to a [PrintSink], such as a [FileSink]
+//! writing each job to its own file, or a [MemorySink] collecting the jobs
+//! for tests.
+//!
+//! USB printers have no notion of jobs, so a job ends when the host stops
+//! sending data for the job timeout, or sends SOFT_RESET. The port status
+//! reported to the host can be changed through [Printer::status], e.g. to
+//! simulate a printer running out of paper.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub use crate::usb::printer::{PortStatus, PrinterProtocol};
+use crate::{
+    devices::Device,
+    usb::{printer::PrinterInterfaceBuilder, ConfigurationBuilder, DeviceClass, Direction, LangId},
+    usbip::UsbIpDirection,
+    virtual_usb::{Reply, VirtualUSBDevice, VirtualUSBDeviceBuilder, Xfer},
+};
+
+/// Vendor ID of the printer (NetChip, used by the Linux printer gadget)
+pub const VENDOR_ID: u16 = 0x0525;
+/// Product ID of the printer (Linux-USB Printer Gadget)
+pub const PRODUCT_ID: u16 = 0xa4a8;
+/// Number of the bulk OUT endpoint and of the bulk IN endpoint of
+/// bidirectional printers
+pub const DATA_ENDPOINT: u8 = 1;
+/// Default IEEE 1284 device ID
+pub const DEFAULT_DEVICE_ID: &str = "MFG:Linux;MDL:Virtual Printer;CMD:PS,PDF;CLS:PRINTER;";
+/// Default time without data after which a job ends
+pub const DEFAULT_JOB_TIMEOUT: Duration = Duration::from_secs(2);
+/// Max packet size of the bulk endpoints (high speed)
+const MAX_PACKET_SIZE: u16 = 512;
+
+/// Destination of the print data sent by the host
+pub trait PrintSink {
+    /// Deliver data of the current job. The first call after
+    /// [PrintSink::end_job] starts a new job.
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+    /// End the current job
+    fn end_job(&mut self) -> io::Result<()>;
+}
+
+/// Sink writing each job to a new file in a directory, named `job-0001.prn`,
+/// `job-0002.prn` and so on
+#[derive(Debug)]
+pub struct FileSink {
+    dir: PathBuf,
+    file: Option<File>,
+    jobs: Vec<PathBuf>,
+}
+
+impl FileSink {
+    /// Write jobs to the given directory, which must exist
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            file: None,
+            jobs: Vec::new(),
+        }
+    }
+
+    /// Paths of the files written so far, including the current job
+    pub fn jobs(&self) -> &[PathBuf] {
+        self.jobs.as_slice()
+    }
+}
+
+impl PrintSink for FileSink {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => {
+                let path = self.dir.join(format!("job-{:04}.prn", self.jobs.len() + 1));
+                #[cfg(feature = "log")]
+                log::info!("Writing print job to {}", path.display());
+                let file = File::create(&path)?;
+                self.jobs.push(path);
+                self.file.insert(file)
+            }
+        };
+        file.write_all(data)
+    }
+
+    fn end_job(&mut self) -> io::Result<()> {
+        match self.file.take() {
+            Some(file) => file.sync_all(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Sink keeping jobs in memory
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    current: Option<Vec<u8>>,
+    jobs: Vec<Vec<u8>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Completed jobs
+    pub fn jobs(&self) -> &[Vec<u8>] {
+        self.jobs.as_slice()
+    }
+
+    /// Data of the job being received, if any
+    pub fn current_job(&self) -> Option<&[u8]> {
+        self.current.as_deref()
+    }
+
+    /// Remove and return the completed jobs
+    pub fn take_jobs(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.jobs)
+    }
+}
+
+impl PrintSink for MemorySink {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.current
+            .get_or_insert_with(Vec::new)
+            .extend_from_slice(data);
+        Ok(())
+    }
+
+    fn end_job(&mut self) -> io::Result<()> {
+        if let Some(job) = self.current.take() {
+            self.jobs.push(job);
+        }
+        Ok(())
+    }
+}
+
+/// Virtual USB printer delivering print data to a [PrintSink]
+#[derive(Debug)]
+pub struct Printer<S: PrintSink> {
+    device: VirtualUSBDevice,
+    sink: S,
+    status: PortStatus,
+    resets: Receiver<()>,
+    /// Time without data after which a job ends
+    job_timeout: Duration,
+    /// Time data was last received for the current job
+    last_data: Option<Instant>,
+    /// Bulk IN transfers waiting for back channel data
+    pending_in: VecDeque<Xfer>,
+    /// Back channel data waiting for bulk IN transfers
+    back_channel: VecDeque<Vec<u8>>,
+}
+
+impl<S: PrintSink> Printer<S> {
+    /// Create a printer with the given protocol and IEEE 1284 device ID (see
+    /// [DEFAULT_DEVICE_ID]) delivering jobs to the given sink
+    pub fn new(sink: S, protocol: PrinterProtocol, device_id: &str) -> Self {
+        let (tx, rx) = channel();
+        let status = PortStatus::new();
+
+        let mut iface = PrinterInterfaceBuilder::new();
+        iface
+            .protocol(protocol)
+            .device_id(device_id)
+            .port_status(&status)
+            .on_soft_reset(move || {
+                let _ = tx.send(());
+            })
+            .bulk_endpoint(DATA_ENDPOINT, Direction::Out, MAX_PACKET_SIZE);
+        if protocol != PrinterProtocol::Unidirectional {
+            iface.bulk_endpoint(DATA_ENDPOINT, Direction::In, MAX_PACKET_SIZE);
+        }
+
+        let device = VirtualUSBDeviceBuilder::new(VENDOR_ID, PRODUCT_ID)
+            .class(DeviceClass::UseInterface)
+            .supported_langs(vec![LangId::EnglishUnitedStates])
+            .manufacturer("Linux")
+            .product("Printer Gadget")
+            .serial("0123456789AB")
+            .max_packet_size(64)
+            .configuration(
+                ConfigurationBuilder::new()
+                    .max_power(100)
+                    .interface(iface.build())
+                    .build(),
+            )
+            .build();
+
+        Self {
+            device,
+            sink,
+            status,
+            resets: rx,
+            job_timeout: DEFAULT_JOB_TIMEOUT,
+            last_data: None,
+            pending_in: VecDeque::new(),
+            back_channel: VecDeque::new(),
+        }
+    }
+
+    /// Set the time without data after which a job ends
+    pub fn set_job_timeout(&mut self, timeout: Duration) {
+        self.job_timeout = timeout;
+    }
+
+    /// The port status reported to the host with GET_PORT_STATUS
+    pub fn status(&self) -> &PortStatus {
+        &self.status
+    }
+
+    /// The sink jobs are delivered to
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    /// The sink jobs are delivered to
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    /// Queue data for the host to read on the bulk IN endpoint of a
+    /// bidirectional printer, e.g. a PJL status reply
+    pub fn send_to_host(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.back_channel.push_back(data.to_vec());
+        self.send_pending()
+    }
+
+    /// End the current job, if any
+    pub fn end_job(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.last_data.take().is_some() {
+            #[cfg(feature = "log")]
+            log::info!("Print job ended");
+            self.sink.end_job()?;
+        }
+        Ok(())
+    }
+
+    /// Handle a transfer that was not handled by the device itself
+    fn handle_xfer(&mut self, xfer: Xfer) -> Result<(), Box<dyn Error>> {
+        match (xfer.direction(), xfer.ep) {
+            (UsbIpDirection::In, DATA_ENDPOINT) => {
+                self.pending_in.push_back(xfer);
+                self.send_pending()
+            }
+            (UsbIpDirection::Out, DATA_ENDPOINT) => {
+                if xfer.data.is_empty() {
+                    return Ok(());
+                }
+                #[cfg(feature = "log")]
+                if self.last_data.is_none() {
+                    log::info!("Print job started");
+                }
+                self.sink.write(&xfer.data)?;
+                self.last_data = Some(Instant::now());
+                Ok(())
+            }
+            (_direction, _ep) => {
+                #[cfg(feature = "log")]
+                log::debug!("Ignoring {_direction:?} transfer on endpoint {_ep}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Answer pending bulk IN transfers with back channel data
+    fn send_pending(&mut self) -> Result<(), Box<dyn Error>> {
+        self.pending_in
+            .retain(|xfer| !self.device.is_unlinked(xfer.seqnum()));
+        while !self.back_channel.is_empty() {
+            let Some(xfer) = self.pending_in.pop_front() else {
+                break;
+            };
+            let Some(data) = self.back_channel.front_mut() else {
+                break;
+            };
+            let len = xfer.buffer_length().min(data.len());
+            let chunk: Vec<u8> = data.drain(..len).collect();
+            if data.is_empty() {
+                self.back_channel.pop_front();
+            }
+            self.device.write(Reply::from_xfer(xfer, &chunk))?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: PrintSink> Device for Printer<S> {
+    fn device(&self) -> &VirtualUSBDevice {
+        &self.device
+    }
+
+    fn device_mut(&mut self) -> &mut VirtualUSBDevice {
+        &mut self.device
+    }
+
+    /// Handle the next pending USB transfer or host request, if any, and end
+    /// the current job if it timed out. Waits up to the given timeout if
+    /// there was nothing to handle.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        match self.device.read()? {
+            Some(xfer) => self.handle_xfer(xfer)?,
+            None => thread::sleep(timeout),
+        }
+
+        while self.resets.try_recv().is_ok() {
+            #[cfg(feature = "log")]
+            log::debug!("Soft reset");
+            self.back_channel.clear();
+            self.end_job()?;
+        }
+        if self
+            .last_data
+            .is_some_and(|last| last.elapsed() >= self.job_timeout)
+        {
+            self.end_job()?;
+        }
+        Ok(())
+    }
+}
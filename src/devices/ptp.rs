@@ -0,0 +1,993 @@
+//! PTP camera or MTP player serving the objects of an [ObjectStore]
+//!
+//! Emulates a still image device, which photo import tools such as gphoto2
+//! access with PTP, or an MTP device for libmtp and the gvfs MTP backend.
+//! The objects come from an [ObjectStore], such as a [MemoryStore] filled
+//! by the application or a [DirectoryStore] serving the files of a local
+//! directory. The host may download, upload and delete objects, and objects
+//! added or removed by the application are announced with
+//! [PtpDevice::send_event].
+//!
+//! A single storage is exposed, and objects have no thumbnails or
+//! properties.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    error::Error,
+    ffi::CString,
+    fs,
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use packed_struct::prelude::*;
+
+pub use crate::usb::ptp::{event, format, DeviceStatus, ObjectInfo, StorageInfo, ROOT_PARENT};
+use crate::{
+    devices::{read_available, Device},
+    usb::{
+        ptp::{
+            operation, response, Container, ContainerHeader, ContainerType, DataWriter, DeviceInfo,
+            PtpEvent, PtpInterfaceBuilder, ALL_OBJECTS, CONTAINER_HEADER_SIZE, GENERIC_FOLDER,
+            MTP_INTERFACE_NAME, MTP_VENDOR_EXTENSION_DESC, MTP_VENDOR_EXTENSION_ID,
+            MTP_VENDOR_EXTENSION_VERSION,
+        },
+        ConfigurationBuilder, DeviceClass, LangId,
+    },
+    virtual_usb::{BulkIn, BulkOut, VirtualUSBDevice, VirtualUSBDeviceBuilder},
+};
+
+/// Vendor ID of the device (Linux Foundation)
+pub const VENDOR_ID: u16 = 0x1d6b;
+/// Product ID of the device (PTP Gadget)
+pub const PRODUCT_ID: u16 = 0x0100;
+/// Number of the bulk OUT and bulk IN endpoints
+pub const BULK_ENDPOINT: u8 = 1;
+/// Number of the interrupt IN endpoint carrying events
+pub const INTERRUPT_ENDPOINT: u8 = 2;
+/// ID of the storage holding the objects
+pub const STORAGE_ID: u32 = 0x0001_0001;
+/// Max packet size of the bulk endpoints (high speed)
+const MAX_PACKET_SIZE: u16 = 512;
+/// Operations answered by the device
+const OPERATIONS: [u16; 14] = [
+    operation::GET_DEVICE_INFO,
+    operation::OPEN_SESSION,
+    operation::CLOSE_SESSION,
+    operation::GET_STORAGE_IDS,
+    operation::GET_STORAGE_INFO,
+    operation::GET_NUM_OBJECTS,
+    operation::GET_OBJECT_HANDLES,
+    operation::GET_OBJECT_INFO,
+    operation::GET_OBJECT,
+    operation::GET_THUMB,
+    operation::DELETE_OBJECT,
+    operation::SEND_OBJECT_INFO,
+    operation::SEND_OBJECT,
+    operation::GET_PARTIAL_OBJECT,
+];
+/// Events sent by the device
+const EVENTS: [u16; 4] = [
+    event::OBJECT_ADDED,
+    event::OBJECT_REMOVED,
+    event::STORE_FULL,
+    event::STORAGE_INFO_CHANGED,
+];
+/// Formats of the objects the device can hold
+const FORMATS: [u16; 14] = [
+    format::UNDEFINED,
+    format::ASSOCIATION,
+    format::TEXT,
+    format::HTML,
+    format::WAV,
+    format::MP3,
+    format::AVI,
+    format::MPEG,
+    format::EXIF_JPEG,
+    format::BMP,
+    format::GIF,
+    format::JFIF,
+    format::PNG,
+    format::TIFF,
+];
+
+/// Protocol presented to the host
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PtpMode {
+    /// Plain PTP, as digital cameras
+    Ptp,
+    /// PTP with the MTP vendor extension, as phones and media players
+    Mtp,
+}
+
+/// Storage of the objects served by a [PtpDevice]. Handles are nonzero
+/// and never reused.
+pub trait ObjectStore {
+    /// Describe the storage
+    fn storage_info(&self) -> StorageInfo;
+    /// Returns the handles of all objects, including those in folders
+    fn handles(&self) -> Vec<u32>;
+    /// Describe the object with the given handle. The storage ID is filled
+    /// by the device.
+    fn object_info(&self, handle: u32) -> Option<ObjectInfo>;
+    /// Returns the data of the object with the given handle
+    fn read(&mut self, handle: u32) -> io::Result<Vec<u8>>;
+    /// Create an empty object, or a folder, described by the given info in
+    /// the folder given by its parent. Returns the handle of the object.
+    fn create(&mut self, info: &ObjectInfo) -> io::Result<u32>;
+    /// Replace the data of the object with the given handle
+    fn write(&mut self, handle: u32, data: &[u8]) -> io::Result<()>;
+    /// Delete the object with the given handle, and the content of folders
+    fn delete(&mut self, handle: u32) -> io::Result<()>;
+}
+
+/// Returns the object format matching the extension of the given file name
+pub fn format_of(filename: &str) -> u16 {
+    let extension = filename
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("jpg" | "jpeg") => format::EXIF_JPEG,
+        Some("png") => format::PNG,
+        Some("gif") => format::GIF,
+        Some("bmp") => format::BMP,
+        Some("tif" | "tiff") => format::TIFF,
+        Some("txt") => format::TEXT,
+        Some("htm" | "html") => format::HTML,
+        Some("wav") => format::WAV,
+        Some("mp3") => format::MP3,
+        Some("avi") => format::AVI,
+        Some("mpg" | "mpeg") => format::MPEG,
+        _ => format::UNDEFINED,
+    }
+}
+
+/// Format the given time as a PTP date (`YYYYMMDDThhmmss`, UTC)
+pub fn ptp_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+
+    // Convert days since the epoch to a civil date
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Object of a [MemoryStore]
+#[derive(Debug, Clone)]
+struct MemoryObject {
+    info: ObjectInfo,
+    data: Vec<u8>,
+}
+
+/// Store keeping objects in memory, up to a capacity
+#[derive(Debug, Clone)]
+pub struct MemoryStore {
+    capacity: u64,
+    objects: BTreeMap<u32, MemoryObject>,
+    next_handle: u32,
+}
+
+impl MemoryStore {
+    /// Create an empty store holding up to the given number of bytes
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            objects: BTreeMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    fn insert(&mut self, info: ObjectInfo, data: Vec<u8>) -> u32 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.objects.insert(handle, MemoryObject { info, data });
+        handle
+    }
+
+    /// Add a file in the given folder, or in the root with [ROOT_PARENT].
+    /// Returns the handle of the file.
+    pub fn add_file(&mut self, parent: u32, filename: &str, data: Vec<u8>) -> u32 {
+        let date = ptp_date(SystemTime::now());
+        let info = ObjectInfo {
+            format: format_of(filename),
+            size: data.len() as u64,
+            parent,
+            filename: filename.to_string(),
+            capture_date: date.clone(),
+            modification_date: date,
+            ..ObjectInfo::default()
+        };
+        self.insert(info, data)
+    }
+
+    /// Add a folder in the given folder, or in the root with
+    /// [ROOT_PARENT]. Returns the handle of the folder.
+    pub fn add_folder(&mut self, parent: u32, name: &str) -> u32 {
+        let info = ObjectInfo {
+            format: format::ASSOCIATION,
+            association_type: GENERIC_FOLDER,
+            parent,
+            filename: name.to_string(),
+            modification_date: ptp_date(SystemTime::now()),
+            ..ObjectInfo::default()
+        };
+        self.insert(info, Vec::new())
+    }
+
+    /// Returns the data of the object with the given handle
+    pub fn data(&self, handle: u32) -> Option<&[u8]> {
+        self.objects
+            .get(&handle)
+            .map(|object| object.data.as_slice())
+    }
+
+    /// Returns the handle of the object with the given name in the given
+    /// folder
+    pub fn find(&self, parent: u32, filename: &str) -> Option<u32> {
+        self.objects
+            .iter()
+            .find(|(_, object)| object.info.parent == parent && object.info.filename == filename)
+            .map(|(&handle, _)| handle)
+    }
+
+    /// Returns the number of bytes used by the objects
+    pub fn used(&self) -> u64 {
+        self.objects.values().map(|o| o.data.len() as u64).sum()
+    }
+}
+
+impl ObjectStore for MemoryStore {
+    fn storage_info(&self) -> StorageInfo {
+        StorageInfo {
+            max_capacity: self.capacity,
+            free_space: self.capacity.saturating_sub(self.used()),
+            description: "Memory".to_string(),
+            volume_label: "Virtual".to_string(),
+            ..StorageInfo::default()
+        }
+    }
+
+    fn handles(&self) -> Vec<u32> {
+        self.objects.keys().copied().collect()
+    }
+
+    fn object_info(&self, handle: u32) -> Option<ObjectInfo> {
+        self.objects.get(&handle).map(|object| object.info.clone())
+    }
+
+    fn read(&mut self, handle: u32) -> io::Result<Vec<u8>> {
+        match self.objects.get(&handle) {
+            Some(object) => Ok(object.data.clone()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn create(&mut self, info: &ObjectInfo) -> io::Result<u32> {
+        let handle = match info.is_folder() {
+            true => self.add_folder(info.parent, &info.filename),
+            false => self.add_file(info.parent, &info.filename, Vec::new()),
+        };
+        if let Some(object) = self.objects.get_mut(&handle) {
+            object.info.format = info.format;
+            object.info.size = info.size;
+        }
+        Ok(handle)
+    }
+
+    fn write(&mut self, handle: u32, data: &[u8]) -> io::Result<()> {
+        let used = self.used();
+        let Some(object) = self.objects.get_mut(&handle) else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        if used - object.data.len() as u64 + data.len() as u64 > self.capacity {
+            return Err(io::ErrorKind::StorageFull.into());
+        }
+        object.data = data.to_vec();
+        object.info.size = data.len() as u64;
+        Ok(())
+    }
+
+    fn delete(&mut self, handle: u32) -> io::Result<()> {
+        if self.objects.remove(&handle).is_none() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let children: Vec<u32> = self
+            .objects
+            .iter()
+            .filter(|(_, object)| object.info.parent == handle)
+            .map(|(&handle, _)| handle)
+            .collect();
+        for child in children {
+            self.delete(child)?;
+        }
+        Ok(())
+    }
+}
+
+/// Entry of a [DirectoryStore]
+#[derive(Debug, Clone)]
+struct DirectoryEntry {
+    path: PathBuf,
+    parent: u32,
+}
+
+/// Store serving the files and folders of a local directory. The directory
+/// is scanned when the store is created.
+#[derive(Debug, Clone)]
+pub struct DirectoryStore {
+    root: PathBuf,
+    entries: BTreeMap<u32, DirectoryEntry>,
+    next_handle: u32,
+    read_only: bool,
+}
+
+impl DirectoryStore {
+    /// Serve the content of the given directory
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let mut store = Self {
+            root: root.as_ref().to_path_buf(),
+            entries: BTreeMap::new(),
+            next_handle: 1,
+            read_only: false,
+        };
+        let root = store.root.clone();
+        store.scan(&root, ROOT_PARENT)?;
+        Ok(store)
+    }
+
+    /// Add the content of the given directory, in name order
+    fn scan(&mut self, dir: &Path, parent: u32) -> io::Result<()> {
+        let mut paths = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        paths.sort();
+        for path in paths {
+            let is_dir = path.is_dir();
+            let handle = self.insert(path.clone(), parent);
+            if is_dir {
+                self.scan(&path, handle)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, path: PathBuf, parent: u32) -> u32 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.entries.insert(handle, DirectoryEntry { path, parent });
+        handle
+    }
+
+    /// Prevent the host from changing the content of the directory
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Returns the directory served by the store
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the path of the object with the given handle
+    pub fn path(&self, handle: u32) -> Option<&Path> {
+        self.entries.get(&handle).map(|entry| entry.path.as_path())
+    }
+
+    /// Returns the size and available space of the filesystem of the
+    /// directory
+    fn filesystem_space(&self) -> io::Result<(u64, u64)> {
+        let path = CString::new(self.root.as_os_str().as_bytes())?;
+        // SAFETY: the path is nul terminated and statvfs is a valid struct
+        let stat = unsafe {
+            let mut stat: libc::statvfs = std::mem::zeroed();
+            if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            stat
+        };
+        let block_size = stat.f_frsize as u64;
+        Ok((
+            stat.f_blocks as u64 * block_size,
+            stat.f_bavail as u64 * block_size,
+        ))
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        match self.read_only {
+            true => Err(io::ErrorKind::ReadOnlyFilesystem.into()),
+            false => Ok(()),
+        }
+    }
+}
+
+impl ObjectStore for DirectoryStore {
+    fn storage_info(&self) -> StorageInfo {
+        let (max_capacity, free_space) = self.filesystem_space().unwrap_or_default();
+        let label = self.root.file_name().unwrap_or(self.root.as_os_str());
+        StorageInfo {
+            read_only: self.read_only,
+            max_capacity,
+            free_space,
+            description: "Directory".to_string(),
+            volume_label: label.to_string_lossy().into_owned(),
+            ..StorageInfo::default()
+        }
+    }
+
+    fn handles(&self) -> Vec<u32> {
+        self.entries.keys().copied().collect()
+    }
+
+    fn object_info(&self, handle: u32) -> Option<ObjectInfo> {
+        let entry = self.entries.get(&handle)?;
+        let metadata = fs::metadata(&entry.path).ok()?;
+        let filename = entry.path.file_name()?.to_string_lossy().into_owned();
+        let modified = metadata.modified().map(ptp_date).unwrap_or_default();
+        let mut info = ObjectInfo {
+            format: format_of(&filename),
+            protected: self.read_only,
+            size: metadata.len(),
+            parent: entry.parent,
+            filename,
+            capture_date: modified.clone(),
+            modification_date: modified,
+            ..ObjectInfo::default()
+        };
+        if metadata.is_dir() {
+            info.format = format::ASSOCIATION;
+            info.association_type = GENERIC_FOLDER;
+            info.size = 0;
+        }
+        Some(info)
+    }
+
+    fn read(&mut self, handle: u32) -> io::Result<Vec<u8>> {
+        match self.entries.get(&handle) {
+            Some(entry) => fs::read(&entry.path),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn create(&mut self, info: &ObjectInfo) -> io::Result<u32> {
+        self.check_writable()?;
+        let name = info.filename.as_str();
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let dir = match info.parent {
+            ROOT_PARENT => self.root.clone(),
+            parent => match self.entries.get(&parent) {
+                Some(entry) if entry.path.is_dir() => entry.path.clone(),
+                _ => return Err(io::ErrorKind::NotFound.into()),
+            },
+        };
+        let path = dir.join(name);
+        match info.is_folder() {
+            true => fs::create_dir(&path)?,
+            false => drop(fs::File::create_new(&path)?),
+        }
+        Ok(self.insert(path, info.parent))
+    }
+
+    fn write(&mut self, handle: u32, data: &[u8]) -> io::Result<()> {
+        self.check_writable()?;
+        match self.entries.get(&handle) {
+            Some(entry) => fs::write(&entry.path, data),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn delete(&mut self, handle: u32) -> io::Result<()> {
+        self.check_writable()?;
+        let Some(entry) = self.entries.get(&handle) else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        if entry.path.is_dir() {
+            fs::remove_dir_all(&entry.path)?;
+        } else {
+            fs::remove_file(&entry.path)?;
+        }
+        let path = entry.path.clone();
+        self.entries
+            .retain(|_, entry| !entry.path.starts_with(&path));
+        Ok(())
+    }
+}
+
+/// Returns the response code reporting the given store error
+fn error_response(error: io::Error) -> u16 {
+    match error.kind() {
+        io::ErrorKind::NotFound => response::INVALID_OBJECT_HANDLE,
+        io::ErrorKind::StorageFull => response::STORE_FULL,
+        io::ErrorKind::ReadOnlyFilesystem => response::STORE_READ_ONLY,
+        io::ErrorKind::PermissionDenied => response::ACCESS_DENIED,
+        io::ErrorKind::InvalidInput => response::INVALID_PARAMETER,
+        _ => response::GENERAL_ERROR,
+    }
+}
+
+/// Data sent to the host and parameters of the OK response of an
+/// operation, or the response code of its failure
+type OperationResult = Result<(Option<Vec<u8>>, Vec<u32>), u16>;
+
+/// Virtual PTP or MTP device serving the objects of an [ObjectStore]
+#[derive(Debug)]
+pub struct PtpDevice<S: ObjectStore> {
+    device: VirtualUSBDevice,
+    store: S,
+    mode: PtpMode,
+    status: DeviceStatus,
+    events: Receiver<PtpEvent>,
+    bulk_in: BulkIn,
+    bulk_out: BulkOut,
+    /// ID of the open session
+    session: Option<u32>,
+    /// Data of the containers being received
+    messages: Vec<u8>,
+    /// Operation waiting for its data phase
+    pending: Option<Container>,
+    /// Object created by SendObjectInfo, waiting for SendObject
+    sent_object: Option<u32>,
+    /// Data container being sent
+    output: VecDeque<u8>,
+    /// Response to send once the data container is sent
+    response: Option<Vec<u8>>,
+}
+
+impl<S: ObjectStore> PtpDevice<S> {
+    /// Create a device serving the objects of the given store
+    pub fn new(store: S, mode: PtpMode) -> Result<Self, Box<dyn Error>> {
+        let (tx, rx) = channel();
+        let status = DeviceStatus::new();
+
+        let mut iface = PtpInterfaceBuilder::new();
+        iface
+            .bulk_endpoints(BULK_ENDPOINT, BULK_ENDPOINT, MAX_PACKET_SIZE)
+            .interrupt_endpoint(INTERRUPT_ENDPOINT)
+            .status(&status)
+            .on_event(move |event| {
+                let _ = tx.send(event);
+            });
+        let product = match mode {
+            PtpMode::Ptp => "PTP Camera",
+            PtpMode::Mtp => {
+                iface.name(MTP_INTERFACE_NAME);
+                "MTP Device"
+            }
+        };
+
+        let mut device = VirtualUSBDeviceBuilder::new(VENDOR_ID, PRODUCT_ID)
+            .class(DeviceClass::UseInterface)
+            .supported_langs(vec![LangId::EnglishUnitedStates])
+            .manufacturer("Linux")
+            .product(product)
+            .serial("0123456789AB")
+            .max_packet_size(64)
+            .configuration(
+                ConfigurationBuilder::new()
+                    .max_power(100)
+                    .interface(iface.build())
+                    .build(),
+            )
+            .build();
+        let bulk_in = device.bulk_in(BULK_ENDPOINT)?;
+        let bulk_out = device.bulk_out(BULK_ENDPOINT)?;
+
+        Ok(Self {
+            device,
+            store,
+            mode,
+            status,
+            events: rx,
+            bulk_in,
+            bulk_out,
+            session: None,
+            messages: Vec::new(),
+            pending: None,
+            sent_object: None,
+            output: VecDeque::new(),
+            response: None,
+        })
+    }
+
+    /// The protocol presented to the host
+    pub fn mode(&self) -> PtpMode {
+        self.mode
+    }
+
+    /// The store of the objects
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// The store of the objects. Changes should be announced with
+    /// [PtpDevice::send_event].
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// The status read by the host with GET_DEVICE_STATUS
+    pub fn status(&self) -> &DeviceStatus {
+        &self.status
+    }
+
+    /// Returns true if the host opened a session
+    pub fn session_open(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Send an event with the given code and parameters on the interrupt
+    /// endpoint (see [event]), e.g. OBJECT_ADDED with the handle of an
+    /// object added to the store. Events are dropped while no session is
+    /// open.
+    pub fn send_event(&mut self, code: u16, parameters: &[u32]) -> Result<(), Box<dyn Error>> {
+        if self.session.is_none() {
+            return Ok(());
+        }
+        let event = Container::event(code, 0, parameters).pack_to_vec()?;
+        self.device.queue_report(INTERRUPT_ENDPOINT, &event)
+    }
+
+    /// Drop the transaction cancelled by the host, or the whole state on
+    /// reset
+    fn handle_event(&mut self, event: PtpEvent) {
+        #[cfg(feature = "log")]
+        log::debug!("PTP event: {event:?}");
+        self.messages.clear();
+        self.pending = None;
+        self.output.clear();
+        self.response = None;
+        if event == PtpEvent::Reset {
+            self.session = None;
+            self.sent_object = None;
+        }
+    }
+
+    /// Process the complete containers received on the bulk OUT endpoint
+    fn handle_containers(&mut self) -> Result<(), Box<dyn Error>> {
+        read_available(&mut self.bulk_out, &mut self.messages)?;
+
+        while self.messages.len() >= CONTAINER_HEADER_SIZE {
+            let header =
+                ContainerHeader::unpack_from_slice(&self.messages[..CONTAINER_HEADER_SIZE])?;
+            if header.length() < CONTAINER_HEADER_SIZE || header.container_type().is_none() {
+                // Containers carry no marker to find the start of the next one
+                #[cfg(feature = "log")]
+                log::warn!("Dropping container with invalid header: {header:?}");
+                self.messages.clear();
+                break;
+            }
+            if self.messages.len() < header.length() {
+                break;
+            }
+            let data: Vec<u8> = self.messages.drain(..header.length()).collect();
+            let container = Container::unpack(&data)?;
+            self.handle_container(container)?;
+        }
+        Ok(())
+    }
+
+    /// Answer an operation, or keep it until its data phase
+    fn handle_container(&mut self, container: Container) -> Result<(), Box<dyn Error>> {
+        let (operation, data) = match container.container_type {
+            ContainerType::Command => {
+                #[cfg(feature = "log")]
+                log::debug!(
+                    "PTP operation {:#06x} with parameters {:x?}",
+                    container.code,
+                    container.parameters()
+                );
+                if matches!(
+                    container.code,
+                    operation::SEND_OBJECT_INFO | operation::SEND_OBJECT
+                ) {
+                    self.pending = Some(container);
+                    return Ok(());
+                }
+                (container, None)
+            }
+            ContainerType::Data => match self.pending.take() {
+                Some(operation) if operation.transaction_id == container.transaction_id => {
+                    (operation, Some(container.payload))
+                }
+                _ => {
+                    #[cfg(feature = "log")]
+                    log::warn!("Dropping unexpected data container");
+                    return Ok(());
+                }
+            },
+            _ => {
+                #[cfg(feature = "log")]
+                log::warn!("Dropping {:?} container", container.container_type);
+                return Ok(());
+            }
+        };
+
+        let id = operation.transaction_id;
+        let response = match self.handle_operation(&operation, data) {
+            Ok((data, parameters)) => {
+                if let Some(data) = data {
+                    let container = Container::data(operation.code, id, data);
+                    self.output.extend(container.pack_to_vec()?);
+                }
+                Container::response(response::OK, id, &parameters)
+            }
+            Err(code) => {
+                #[cfg(feature = "log")]
+                log::debug!(
+                    "PTP operation {:#06x} failed with {code:#06x}",
+                    operation.code
+                );
+                Container::response(code, id, &[])
+            }
+        };
+        self.response = Some(response.pack_to_vec()?);
+        Ok(())
+    }
+
+    /// Execute an operation with the data sent by the host, if any
+    fn handle_operation(&mut self, op: &Container, data: Option<Vec<u8>>) -> OperationResult {
+        match op.code {
+            operation::GET_DEVICE_INFO => return Ok((Some(self.device_info()), vec![])),
+            operation::OPEN_SESSION => {
+                return match (self.session, op.parameter(0)) {
+                    (Some(_), _) => Err(response::SESSION_ALREADY_OPEN),
+                    (None, 0) => Err(response::INVALID_PARAMETER),
+                    (None, id) => {
+                        self.session = Some(id);
+                        Ok((None, vec![]))
+                    }
+                };
+            }
+            _ if self.session.is_none() => return Err(response::SESSION_NOT_OPEN),
+            _ => (),
+        }
+
+        match op.code {
+            operation::CLOSE_SESSION => {
+                self.session = None;
+                Ok((None, vec![]))
+            }
+            operation::GET_STORAGE_IDS => {
+                let mut data = DataWriter::new();
+                data.u32_array(&[STORAGE_ID]);
+                Ok((Some(data.into_vec()), vec![]))
+            }
+            operation::GET_STORAGE_INFO => {
+                self.check_storage(op.parameter(0), false)?;
+                Ok((Some(self.store.storage_info().pack_to_vec()), vec![]))
+            }
+            operation::GET_NUM_OBJECTS => {
+                let handles =
+                    self.object_handles(op.parameter(0), op.parameter(1) as u16, op.parameter(2))?;
+                Ok((None, vec![handles.len() as u32]))
+            }
+            operation::GET_OBJECT_HANDLES => {
+                let handles =
+                    self.object_handles(op.parameter(0), op.parameter(1) as u16, op.parameter(2))?;
+                let mut data = DataWriter::new();
+                data.u32_array(&handles);
+                Ok((Some(data.into_vec()), vec![]))
+            }
+            operation::GET_OBJECT_INFO => {
+                let info = self.object_info(op.parameter(0))?;
+                Ok((Some(info.pack_to_vec()), vec![]))
+            }
+            operation::GET_OBJECT => {
+                self.object_info(op.parameter(0))?;
+                let data = self.store.read(op.parameter(0)).map_err(error_response)?;
+                Ok((Some(data), vec![]))
+            }
+            operation::GET_PARTIAL_OBJECT => {
+                self.object_info(op.parameter(0))?;
+                let data = self.store.read(op.parameter(0)).map_err(error_response)?;
+                let offset = (op.parameter(1) as usize).min(data.len());
+                let len = (op.parameter(2) as usize).min(data.len() - offset);
+                let data = data[offset..offset + len].to_vec();
+                Ok((Some(data), vec![len as u32]))
+            }
+            operation::GET_THUMB => {
+                self.object_info(op.parameter(0))?;
+                Err(response::NO_THUMBNAIL_PRESENT)
+            }
+            operation::DELETE_OBJECT => self.delete_object(op.parameter(0)),
+            operation::SEND_OBJECT_INFO => self.send_object_info(op, data),
+            operation::SEND_OBJECT => {
+                let Some(handle) = self.sent_object.take() else {
+                    return Err(response::NO_VALID_OBJECT_INFO);
+                };
+                let data = data.unwrap_or_default();
+                self.store.write(handle, &data).map_err(error_response)?;
+                Ok((None, vec![]))
+            }
+            _ => Err(response::OPERATION_NOT_SUPPORTED),
+        }
+    }
+
+    /// Returns the DeviceInfo dataset
+    fn device_info(&self) -> Vec<u8> {
+        let mut info = DeviceInfo {
+            operations: OPERATIONS.to_vec(),
+            events: EVENTS.to_vec(),
+            image_formats: FORMATS.to_vec(),
+            manufacturer: "Linux".to_string(),
+            model: "Virtual PTP Device".to_string(),
+            device_version: env!("CARGO_PKG_VERSION").to_string(),
+            serial_number: "0123456789AB".to_string(),
+            ..DeviceInfo::default()
+        };
+        if self.mode == PtpMode::Mtp {
+            info.vendor_extension_id = MTP_VENDOR_EXTENSION_ID;
+            info.vendor_extension_version = MTP_VENDOR_EXTENSION_VERSION;
+            info.vendor_extension_desc = MTP_VENDOR_EXTENSION_DESC.to_string();
+            info.model = "Virtual MTP Device".to_string();
+        }
+        info.pack_to_vec()
+    }
+
+    /// Check the given storage ID, which may stand for all storages
+    fn check_storage(&self, storage_id: u32, allow_all: bool) -> Result<(), u16> {
+        match storage_id {
+            STORAGE_ID => Ok(()),
+            ALL_OBJECTS if allow_all => Ok(()),
+            _ => Err(response::INVALID_STORAGE_ID),
+        }
+    }
+
+    /// Describe the object with the given handle
+    fn object_info(&self, handle: u32) -> Result<ObjectInfo, u16> {
+        let Some(mut info) = self.store.object_info(handle) else {
+            return Err(response::INVALID_OBJECT_HANDLE);
+        };
+        info.storage_id = STORAGE_ID;
+        Ok(info)
+    }
+
+    /// Returns the handles of the objects selected by the storage, format
+    /// and parent parameters of GetNumObjects and GetObjectHandles
+    fn object_handles(&self, storage_id: u32, format: u16, parent: u32) -> Result<Vec<u32>, u16> {
+        self.check_storage(storage_id, true)?;
+        let parent = match parent {
+            0 => None,
+            ALL_OBJECTS => Some(ROOT_PARENT),
+            folder => match self.store.object_info(folder) {
+                Some(info) if info.is_folder() => Some(folder),
+                _ => return Err(response::INVALID_PARENT_OBJECT),
+            },
+        };
+        let handles = self.store.handles().into_iter().filter(|&handle| {
+            let Some(info) = self.store.object_info(handle) else {
+                return false;
+            };
+            (format == 0 || info.format == format) && parent.is_none_or(|p| info.parent == p)
+        });
+        Ok(handles.collect())
+    }
+
+    /// Delete the object with the given handle, or all objects
+    fn delete_object(&mut self, handle: u32) -> OperationResult {
+        if self.store.storage_info().read_only {
+            return Err(response::STORE_READ_ONLY);
+        }
+        // Deleting the objects at the root deletes the content of folders
+        let handles = match handle {
+            ALL_OBJECTS => self.object_handles(ALL_OBJECTS, 0, ALL_OBJECTS)?,
+            handle => vec![handle],
+        };
+        for handle in handles {
+            if self.object_info(handle)?.protected {
+                return Err(response::OBJECT_WRITE_PROTECTED);
+            }
+            self.store.delete(handle).map_err(error_response)?;
+        }
+        Ok((None, vec![]))
+    }
+
+    /// Create the object described by the host. Its data follows with
+    /// SendObject, except for folders.
+    fn send_object_info(&mut self, op: &Container, data: Option<Vec<u8>>) -> OperationResult {
+        let storage = self.store.storage_info();
+        if storage.read_only {
+            return Err(response::STORE_READ_ONLY);
+        }
+        match op.parameter(0) {
+            0 | STORAGE_ID => (),
+            _ => return Err(response::INVALID_STORAGE_ID),
+        }
+        let Some(mut info) = data.as_deref().and_then(ObjectInfo::unpack) else {
+            return Err(response::INVALID_PARAMETER);
+        };
+        // The root is given as 0 or 0xFFFFFFFF
+        info.parent = match op.parameter(1) {
+            ROOT_PARENT | ALL_OBJECTS => ROOT_PARENT,
+            folder => match self.store.object_info(folder) {
+                Some(parent) if parent.is_folder() => folder,
+                _ => return Err(response::INVALID_PARENT_OBJECT),
+            },
+        };
+        if info.size > storage.free_space {
+            return Err(response::STORE_FULL);
+        }
+
+        let handle = self.store.create(&info).map_err(error_response)?;
+        self.sent_object = (!info.is_folder()).then_some(handle);
+        #[cfg(feature = "log")]
+        log::info!("Host created {:?} with handle {handle}", info.filename);
+        Ok((None, vec![STORAGE_ID, op.parameter(1), handle]))
+    }
+
+    /// Send the pending data container and response to the host
+    fn send_output(&mut self) -> Result<(), Box<dyn Error>> {
+        while !self.output.is_empty() {
+            let (data, _) = self.output.as_slices();
+            match self.bulk_in.write(data) {
+                Ok(len) => drop(self.output.drain(..len)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // Writing the response ends the transfer of the data container
+        if let Some(response) = self.response.as_ref() {
+            match self.bulk_in.write_transfer(response) {
+                Ok(()) => self.response = None,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: ObjectStore> Device for PtpDevice<S> {
+    fn device(&self) -> &VirtualUSBDevice {
+        &self.device
+    }
+
+    fn device_mut(&mut self) -> &mut VirtualUSBDevice {
+        &mut self.device
+    }
+
+    /// Handle the next pending USB transfer or host request, if any, and
+    /// answer the operations received. Waits up to the given timeout if
+    /// there was nothing to handle.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        match self.device.read()? {
+            Some(_xfer) => {
+                #[cfg(feature = "log")]
+                log::debug!(
+                    "Ignoring {:?} transfer on endpoint {}",
+                    _xfer.direction(),
+                    _xfer.ep
+                );
+            }
+            None => thread::sleep(timeout),
+        }
+
+        while let Ok(event) = self.events.try_recv() {
+            self.handle_event(event);
+        }
+        self.handle_containers()?;
+        self.send_output()
+    }
+}
@@ -0,0 +1,539 @@
+//! USB smart card reader backed by a software [SmartCard]
+//!
+//! Emulates a single slot CCID reader, which pcscd binds with the libccid
+//! driver so PC/SC applications such as OpenSC can talk to the card. The
+//! reader exchanges whole APDUs with the card, so cards only implement
+//! [SmartCard::transmit] and never see T=0 or T=1 framing. The built-in
+//! [MemoryCard] serves files and random challenges for tests.
+//!
+//! Cards are inserted and removed through the [CardSlot] of the reader,
+//! which can be shared with other threads. The host is notified of every
+//! change on the interrupt endpoint.
+
+pub mod apdu;
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::Debug,
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+
+use packed_struct::prelude::*;
+
+use self::apdu::{instruction, response, status_word, CommandApdu};
+pub use crate::usb::ccid::{IccStatus, PcToRdr, RdrToPc};
+use crate::{
+    devices::{read_available, Device},
+    usb::{
+        ccid::{
+            slot_error, slot_status, CcidHeader, CcidInterfaceBuilder, CcidNotification,
+            CommandStatus, CCID_HEADER_SIZE, DEFAULT_CLOCK, DEFAULT_DATA_RATE, MAX_APDU_SIZE,
+        },
+        ConfigurationBuilder, DeviceClass, LangId,
+    },
+    virtual_usb::{BulkIn, BulkOut, VirtualUSBDevice, VirtualUSBDeviceBuilder},
+};
+
+/// Vendor ID of the reader (Linux Foundation)
+pub const VENDOR_ID: u16 = 0x1d6b;
+/// Product ID of the reader (FunctionFS Gadget, used by userspace CCID
+/// gadgets)
+pub const PRODUCT_ID: u16 = 0x0105;
+/// Number of the bulk IN and bulk OUT message endpoints
+pub const BULK_ENDPOINT: u8 = 1;
+/// Number of the interrupt IN endpoint notifying slot changes
+pub const INTERRUPT_ENDPOINT: u8 = 2;
+/// Max packet size of the bulk endpoints (high speed)
+const MAX_PACKET_SIZE: u16 = 512;
+/// Protocol data of the T=1 protocol reported by GetParameters: Fi/Di,
+/// checksum, guard time, waiting integers, clock stop, IFSC and NAD
+const T1_PARAMETERS: [u8; 7] = [0x11, 0x10, 0x00, 0x4d, 0x00, 0xfe, 0x00];
+
+/// Card answering the APDUs sent by the host
+pub trait SmartCard {
+    /// Returns the Answer To Reset sent to the host when the card is powered
+    /// on
+    fn atr(&self) -> Vec<u8>;
+    /// Process a command APDU and return the response APDU, which ends with
+    /// the status word (see [apdu::response])
+    fn transmit(&mut self, apdu: &[u8]) -> Vec<u8>;
+    /// Called when the card is powered on, before its ATR is read
+    fn power_on(&mut self) {}
+    /// Called when the card is powered off or removed
+    fn power_off(&mut self) {}
+}
+
+/// Card held by a [CardSlot]
+pub type BoxedCard = Box<dyn SmartCard + Send>;
+
+#[derive(Default)]
+struct SlotState {
+    card: Option<BoxedCard>,
+    powered: bool,
+    /// Whether the presence of the card changed since the host was last
+    /// notified
+    changed: bool,
+}
+
+impl SlotState {
+    fn icc_status(&self) -> IccStatus {
+        match (&self.card, self.powered) {
+            (None, _) => IccStatus::Absent,
+            (Some(_), false) => IccStatus::Inactive,
+            (Some(_), true) => IccStatus::Active,
+        }
+    }
+}
+
+/// Slot of the reader, shared between the reader and the code inserting and
+/// removing cards
+#[derive(Clone, Default)]
+pub struct CardSlot(Arc<Mutex<SlotState>>);
+
+impl CardSlot {
+    /// Create an empty slot
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, SlotState>> {
+        self.0
+            .lock()
+            .map_err(|_| io::Error::other("Card slot state is poisoned"))
+    }
+
+    /// Insert the given card, removing the current one
+    pub fn insert<C: SmartCard + Send + 'static>(&self, card: C) {
+        self.remove();
+        if let Ok(mut slot) = self.lock() {
+            slot.card = Some(Box::new(card));
+            slot.changed = true;
+        }
+    }
+
+    /// Remove the card, and return it if the slot held one
+    pub fn remove(&self) -> Option<BoxedCard> {
+        let mut slot = self.lock().ok()?;
+        let mut card = slot.card.take()?;
+        if slot.powered {
+            card.power_off();
+        }
+        slot.powered = false;
+        slot.changed = true;
+        Some(card)
+    }
+
+    /// Returns the state of the card in the slot
+    pub fn status(&self) -> IccStatus {
+        self.lock()
+            .map(|slot| slot.icc_status())
+            .unwrap_or(IccStatus::Absent)
+    }
+
+    /// Returns true if the slot holds a card
+    pub fn is_present(&self) -> bool {
+        self.status() != IccStatus::Absent
+    }
+
+    /// Returns whether a card is present if that changed since the last call
+    fn take_change(&self) -> Option<bool> {
+        let mut slot = self.lock().ok()?;
+        if !slot.changed {
+            return None;
+        }
+        slot.changed = false;
+        Some(slot.card.is_some())
+    }
+}
+
+impl Debug for CardSlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CardSlot")
+            .field("status", &self.status())
+            .finish()
+    }
+}
+
+/// Card storing transparent files in memory, selected by file identifier,
+/// with an application selected by its AID. It supports SELECT, READ
+/// BINARY, UPDATE BINARY and GET CHALLENGE.
+#[derive(Debug, Clone)]
+pub struct MemoryCard {
+    aid: Vec<u8>,
+    files: BTreeMap<u16, Vec<u8>>,
+    selected: Option<u16>,
+    /// State of the generator of challenges
+    random: u64,
+}
+
+impl MemoryCard {
+    /// Historical bytes of the ATR
+    const HISTORICAL_BYTES: &'static [u8] = b"vusb";
+
+    /// Create a card with the given application identifier and no files
+    pub fn new(aid: &[u8]) -> Self {
+        Self {
+            aid: aid.to_vec(),
+            files: BTreeMap::new(),
+            selected: None,
+            random: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Add a file with the given identifier and contents
+    pub fn add_file(&mut self, fid: u16, data: &[u8]) -> &mut Self {
+        self.files.insert(fid, data.to_vec());
+        self
+    }
+
+    /// Returns the contents of the file with the given identifier
+    pub fn file(&self, fid: u16) -> Option<&[u8]> {
+        self.files.get(&fid).map(Vec::as_slice)
+    }
+
+    fn select(&mut self, apdu: &CommandApdu) -> u16 {
+        match (apdu.p1, apdu.data.as_slice()) {
+            // Select by DF name
+            (0x04, aid) if aid == self.aid.as_slice() => {
+                self.selected = None;
+                status_word::SUCCESS
+            }
+            (0x04, _) => status_word::FILE_NOT_FOUND,
+            // Select by file identifier
+            (0x00 | 0x02, [hi, lo]) => {
+                let fid = u16::from_be_bytes([*hi, *lo]);
+                if fid == 0x3f00 {
+                    self.selected = None;
+                    return status_word::SUCCESS;
+                }
+                if !self.files.contains_key(&fid) {
+                    return status_word::FILE_NOT_FOUND;
+                }
+                self.selected = Some(fid);
+                status_word::SUCCESS
+            }
+            (0x00 | 0x02, _) => status_word::WRONG_DATA,
+            _ => status_word::INCORRECT_P1_P2,
+        }
+    }
+
+    /// Returns the offset of READ BINARY and UPDATE BINARY
+    fn offset(apdu: &CommandApdu) -> Option<usize> {
+        if apdu.p1 & 0x80 != 0 {
+            return None;
+        }
+        Some(u16::from_be_bytes([apdu.p1, apdu.p2]) as usize)
+    }
+
+    fn read_binary(&self, apdu: &CommandApdu) -> Vec<u8> {
+        let Some(file) = self.selected.and_then(|fid| self.files.get(&fid)) else {
+            return response(&[], status_word::NO_CURRENT_EF);
+        };
+        let Some(offset) = Self::offset(apdu).filter(|offset| *offset <= file.len()) else {
+            return response(&[], status_word::WRONG_P1_P2);
+        };
+        let le = apdu.le.unwrap_or(256);
+        let end = file.len().min(offset + le);
+        response(&file[offset..end], status_word::SUCCESS)
+    }
+
+    fn update_binary(&mut self, apdu: &CommandApdu) -> Vec<u8> {
+        let Some(file) = self.selected.and_then(|fid| self.files.get_mut(&fid)) else {
+            return response(&[], status_word::NO_CURRENT_EF);
+        };
+        let Some(offset) = Self::offset(apdu).filter(|offset| *offset <= file.len()) else {
+            return response(&[], status_word::WRONG_P1_P2);
+        };
+        let end = offset + apdu.data.len();
+        if end > file.len() {
+            file.resize(end, 0);
+        }
+        file[offset..end].copy_from_slice(&apdu.data);
+        response(&[], status_word::SUCCESS)
+    }
+
+    fn get_challenge(&mut self, apdu: &CommandApdu) -> Vec<u8> {
+        let Some(le) = apdu.le else {
+            return response(&[], status_word::WRONG_LENGTH);
+        };
+        // xorshift64* is plenty for test challenges
+        let challenge: Vec<u8> = (0..le)
+            .map(|_| {
+                self.random ^= self.random >> 12;
+                self.random ^= self.random << 25;
+                self.random ^= self.random >> 27;
+                (self.random.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8
+            })
+            .collect();
+        response(&challenge, status_word::SUCCESS)
+    }
+}
+
+impl SmartCard for MemoryCard {
+    /// ATR of a T=1 card with the historical bytes "vusb"
+    fn atr(&self) -> Vec<u8> {
+        let historical = Self::HISTORICAL_BYTES;
+        let mut atr = vec![0x3b, 0x80 | historical.len() as u8, 0x80, 0x01];
+        atr.extend_from_slice(historical);
+        // TCK is the XOR of all bytes after TS
+        let tck = atr[1..].iter().fold(0, |tck, byte| tck ^ byte);
+        atr.push(tck);
+        atr
+    }
+
+    fn transmit(&mut self, apdu: &[u8]) -> Vec<u8> {
+        let Some(apdu) = CommandApdu::parse(apdu) else {
+            return response(&[], status_word::WRONG_LENGTH);
+        };
+        if apdu.cla & 0x80 != 0 {
+            return response(&[], status_word::CLA_NOT_SUPPORTED);
+        }
+        match apdu.ins {
+            instruction::SELECT => response(&[], self.select(&apdu)),
+            instruction::READ_BINARY => self.read_binary(&apdu),
+            instruction::UPDATE_BINARY => self.update_binary(&apdu),
+            instruction::GET_CHALLENGE => self.get_challenge(&apdu),
+            _ => response(&[], status_word::INS_NOT_SUPPORTED),
+        }
+    }
+
+    fn power_on(&mut self) {
+        self.selected = None;
+    }
+}
+
+/// Virtual USB smart card reader with a single [CardSlot]
+#[derive(Debug)]
+pub struct CcidReader {
+    device: VirtualUSBDevice,
+    slot: CardSlot,
+    bulk_in: BulkIn,
+    bulk_out: BulkOut,
+    /// Data of the message being received
+    message: Vec<u8>,
+}
+
+impl CcidReader {
+    /// Create a reader with an empty slot
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let mut device = VirtualUSBDeviceBuilder::new(VENDOR_ID, PRODUCT_ID)
+            .class(DeviceClass::UseInterface)
+            .supported_langs(vec![LangId::EnglishUnitedStates])
+            .manufacturer("Linux")
+            .product("Smart Card Reader")
+            .max_packet_size(64)
+            .configuration(
+                ConfigurationBuilder::new()
+                    .max_power(100)
+                    .interface(
+                        CcidInterfaceBuilder::new()
+                            .bulk_endpoints(BULK_ENDPOINT, BULK_ENDPOINT, MAX_PACKET_SIZE)
+                            .interrupt_endpoint(INTERRUPT_ENDPOINT)
+                            .build(),
+                    )
+                    .build(),
+            )
+            .build();
+        let bulk_in = device.bulk_in(BULK_ENDPOINT)?;
+        let bulk_out = device.bulk_out(BULK_ENDPOINT)?;
+        // Room for the largest response to an extended APDU
+        bulk_in.set_capacity(CCID_HEADER_SIZE + MAX_APDU_SIZE);
+
+        Ok(Self {
+            device,
+            slot: CardSlot::new(),
+            bulk_in,
+            bulk_out,
+            message: Vec::new(),
+        })
+    }
+
+    /// The slot of the reader, which can be cloned to insert and remove
+    /// cards from another thread
+    pub fn slot(&self) -> &CardSlot {
+        &self.slot
+    }
+
+    /// Insert the given card, removing the current one
+    pub fn insert<C: SmartCard + Send + 'static>(&self, card: C) {
+        self.slot.insert(card);
+    }
+
+    /// Remove the card, and return it if the slot held one
+    pub fn remove(&self) -> Option<BoxedCard> {
+        self.slot.remove()
+    }
+
+    /// Answer the complete messages received on the bulk OUT endpoint
+    fn handle_messages(&mut self) -> Result<(), Box<dyn Error>> {
+        read_available(&mut self.bulk_out, &mut self.message)?;
+
+        while self.message.len() >= CCID_HEADER_SIZE {
+            let header = CcidHeader::unpack_from_slice(&self.message[..CCID_HEADER_SIZE])?;
+            if header.length() > MAX_APDU_SIZE {
+                // Drop everything received, as the end of the message is unknown
+                #[cfg(feature = "log")]
+                log::warn!("Dropping message of {} bytes", header.length());
+                self.message.clear();
+                let status = slot_status(self.slot.status(), CommandStatus::Failed);
+                let reply = Self::message(RdrToPc::SlotStatus, &header, [status, 1, 0], &[])?;
+                self.bulk_in.write_transfer(&reply)?;
+                break;
+            }
+            let end = CCID_HEADER_SIZE + header.length();
+            if self.message.len() < end {
+                break;
+            }
+            let data: Vec<u8> = self.message.drain(..end).skip(CCID_HEADER_SIZE).collect();
+            let reply = self.handle_message(&header, &data)?;
+            self.bulk_in.write_transfer(&reply)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the response to the given message
+    fn handle_message(&self, header: &CcidHeader, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let slot = &mut *self.slot.lock()?;
+        let failed = |slot: &SlotState, error: u8| {
+            [
+                slot_status(slot.icc_status(), CommandStatus::Failed),
+                error,
+                0,
+            ]
+        };
+        let Some(message) = PcToRdr::from_primitive(header.b_message_type) else {
+            #[cfg(feature = "log")]
+            log::debug!("Unsupported message type {:#04x}", header.b_message_type);
+            let params = failed(slot, slot_error::CMD_NOT_SUPPORTED);
+            return Self::message(RdrToPc::SlotStatus, header, params, &[]);
+        };
+        #[cfg(feature = "log")]
+        log::debug!("{message:?} with {} bytes", data.len());
+        let kind = message.response();
+        if header.b_slot != 0 {
+            let params = [
+                slot_status(IccStatus::Absent, CommandStatus::Failed),
+                slot_error::BAD_SLOT,
+                0,
+            ];
+            return Self::message(kind, header, params, &[]);
+        }
+
+        let ok = |slot: &SlotState, param: u8| {
+            [
+                slot_status(slot.icc_status(), CommandStatus::Processed),
+                0,
+                param,
+            ]
+        };
+        let (params, reply) = match message {
+            PcToRdr::IccPowerOn => match slot.card.as_mut() {
+                Some(card) => {
+                    card.power_on();
+                    let atr = card.atr();
+                    slot.powered = true;
+                    (ok(slot, 0), atr)
+                }
+                None => (failed(slot, slot_error::ICC_MUTE), Vec::new()),
+            },
+            PcToRdr::IccPowerOff => {
+                if let (Some(card), true) = (slot.card.as_mut(), slot.powered) {
+                    card.power_off();
+                }
+                slot.powered = false;
+                (ok(slot, 0), Vec::new())
+            }
+            PcToRdr::GetSlotStatus | PcToRdr::Abort => (ok(slot, 0), Vec::new()),
+            PcToRdr::XfrBlock => match (slot.card.as_mut(), slot.powered) {
+                (Some(card), true) => {
+                    let reply = card.transmit(data);
+                    (ok(slot, 0), reply)
+                }
+                _ => (failed(slot, slot_error::ICC_MUTE), Vec::new()),
+            },
+            // The reader negotiates the protocol itself, so it always
+            // reports T=1 and ignores the parameters set by the host
+            PcToRdr::GetParameters | PcToRdr::ResetParameters | PcToRdr::SetParameters => {
+                match slot.card {
+                    Some(_) => (ok(slot, 1), T1_PARAMETERS.to_vec()),
+                    None => (failed(slot, slot_error::ICC_MUTE), Vec::new()),
+                }
+            }
+            PcToRdr::SetDataRateAndClockFrequency => {
+                let mut reply = DEFAULT_CLOCK.to_le_bytes().to_vec();
+                reply.extend_from_slice(&DEFAULT_DATA_RATE.to_le_bytes());
+                (ok(slot, 0), reply)
+            }
+            PcToRdr::Escape
+            | PcToRdr::Secure
+            | PcToRdr::T0Apdu
+            | PcToRdr::IccClock
+            | PcToRdr::Mechanical => (failed(slot, slot_error::CMD_NOT_SUPPORTED), Vec::new()),
+        };
+
+        Self::message(kind, header, params, &reply)
+    }
+
+    /// Returns a message of the given type answering the message with the
+    /// given header, with the given bStatus, bError and specific byte
+    fn message(
+        kind: RdrToPc,
+        header: &CcidHeader,
+        params: [u8; 3],
+        data: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let reply = CcidHeader {
+            b_message_type: kind.to_primitive(),
+            dw_length: Integer::from_primitive(data.len() as u32),
+            b_slot: header.b_slot,
+            b_seq: header.b_seq,
+            params,
+        };
+        let mut message = reply.pack()?.to_vec();
+        message.extend_from_slice(data);
+        Ok(message)
+    }
+}
+
+impl Device for CcidReader {
+    fn device(&self) -> &VirtualUSBDevice {
+        &self.device
+    }
+
+    fn device_mut(&mut self) -> &mut VirtualUSBDevice {
+        &mut self.device
+    }
+
+    /// Handle the next pending USB transfer or host request, if any, notify
+    /// the host of card changes and answer the messages received. Waits up
+    /// to the given timeout if there was nothing to handle.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        match self.device.read()? {
+            Some(_xfer) => {
+                #[cfg(feature = "log")]
+                log::debug!(
+                    "Ignoring {:?} transfer on endpoint {}",
+                    _xfer.direction(),
+                    _xfer.ep
+                );
+            }
+            None => thread::sleep(timeout),
+        }
+
+        // Notify the host with the new presence state and the changed bit
+        if let Some(present) = self.slot.take_change() {
+            #[cfg(feature = "log")]
+            log::info!("Card {}", if present { "inserted" } else { "removed" });
+            let slot_state = present as u8 | 0x02;
+            let notification = [CcidNotification::NotifySlotChange as u8, slot_state];
+            self.device
+                .queue_report(INTERRUPT_ENDPOINT, &notification)?;
+        }
+
+        self.handle_messages()
+    }
+}
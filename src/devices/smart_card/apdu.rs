@@ -0,0 +1,103 @@
+//! ISO/IEC 7816-4 command and response APDUs
+
+/// Status words (SW1-SW2) ending response APDUs
+pub mod status_word {
+    pub const SUCCESS: u16 = 0x9000;
+    pub const WRONG_LENGTH: u16 = 0x6700;
+    pub const NO_CURRENT_EF: u16 = 0x6986;
+    pub const WRONG_DATA: u16 = 0x6a80;
+    pub const FILE_NOT_FOUND: u16 = 0x6a82;
+    pub const INCORRECT_P1_P2: u16 = 0x6a86;
+    pub const WRONG_P1_P2: u16 = 0x6b00;
+    pub const INS_NOT_SUPPORTED: u16 = 0x6d00;
+    pub const CLA_NOT_SUPPORTED: u16 = 0x6e00;
+}
+
+/// Instruction bytes (INS) of common commands
+pub mod instruction {
+    pub const SELECT: u8 = 0xa4;
+    pub const READ_BINARY: u8 = 0xb0;
+    pub const UPDATE_BINARY: u8 = 0xd6;
+    pub const GET_CHALLENGE: u8 = 0x84;
+}
+
+/// Command APDU sent to a card
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandApdu {
+    pub cla: u8,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    /// Command data, of length Lc
+    pub data: Vec<u8>,
+    /// Maximum length of the response data, if any is expected
+    pub le: Option<usize>,
+}
+
+impl CommandApdu {
+    /// Parse a command APDU with short or extended length fields. Returns
+    /// None if the length fields do not match the length of the APDU.
+    pub fn parse(apdu: &[u8]) -> Option<Self> {
+        let (header, body) = (apdu.get(..4)?, &apdu[4..]);
+        let (data, le): (&[u8], Option<usize>) = match body {
+            // Case 1
+            [] => (&[], None),
+            // Case 2S
+            [le] => (&[], Some(short_le(*le))),
+            // Case 2E
+            [0, le @ ..] if le.len() == 2 => (&[], Some(extended_le(le))),
+            // Cases 3E and 4E
+            [0, lc_hi, lc_lo, rest @ ..] if *lc_hi != 0 || *lc_lo != 0 => {
+                let lc = u16::from_be_bytes([*lc_hi, *lc_lo]) as usize;
+                match rest.len().checked_sub(lc)? {
+                    0 => (rest, None),
+                    2 => (&rest[..lc], Some(extended_le(&rest[lc..]))),
+                    _ => return None,
+                }
+            }
+            // Cases 3S and 4S
+            [lc, rest @ ..] => {
+                let lc = *lc as usize;
+                match rest.len().checked_sub(lc)? {
+                    0 => (rest, None),
+                    1 => (&rest[..lc], Some(short_le(rest[lc]))),
+                    _ => return None,
+                }
+            }
+        };
+
+        Some(Self {
+            cla: header[0],
+            ins: header[1],
+            p1: header[2],
+            p2: header[3],
+            data: data.to_vec(),
+            le,
+        })
+    }
+}
+
+/// Returns the value of a short Le field, where 0 means 256
+fn short_le(le: u8) -> usize {
+    if le == 0 {
+        256
+    } else {
+        le as usize
+    }
+}
+
+/// Returns the value of an extended Le field, where 0 means 65536
+fn extended_le(le: &[u8]) -> usize {
+    match u16::from_be_bytes([le[0], le[1]]) {
+        0 => 65536,
+        le => le as usize,
+    }
+}
+
+/// Returns a response APDU made of the given data and status word
+pub fn response(data: &[u8], status: u16) -> Vec<u8> {
+    let mut apdu = Vec::with_capacity(data.len() + 2);
+    apdu.extend_from_slice(data);
+    apdu.extend_from_slice(&status.to_be_bytes());
+    apdu
+}
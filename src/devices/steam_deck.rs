@@ -0,0 +1,498 @@
+//! Steam Deck controller
+//!
+//! Emulates the built-in controller of the Steam Deck, which is made of a
+//! mouse, a keyboard ("lizard mode") and a vendor specific controller HID
+//! interface. Steam talks to the controller interface with 64 byte feature
+//! reports, where the first byte is the [ReportType] of the command. The
+//! [SteamDeck] answers those commands itself, keeps a register file of the
+//! controller settings, and surfaces haptic and rumble commands as
+//! [SteamDeckEvent]s.
+//!
+//! Source: https://github.com/libsdl-org/SDL/blob/main/src/joystick/hidapi/SDL_hidapi_steamdeck.c
+
+pub mod descriptor;
+pub mod hid_report;
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use packed_struct::{
+    types::{Integer, SizedInteger},
+    PackedStruct, PackedStructSlice,
+};
+
+use crate::{
+    usb::{
+        hid::{HidInterfaceBuilder, HidReportType, HidSubclass, InterfaceProtocol},
+        ConfigurationBuilder, DeviceClass, Direction, EndpointBuilder, LangId, SynchronizationType,
+        TransferType, UsageType,
+    },
+    virtual_usb::{VirtualUSBDevice, VirtualUSBDeviceBuilder},
+};
+
+use self::{
+    descriptor::{CONTROLLER_DESCRIPTOR, KEYBOARD_DESCRIPTOR, MOUSE_DESCRIPTOR},
+    hid_report::{PackedHapticPulseReport, PackedInputDataReport, PackedRumbleReport, ReportType},
+};
+
+/// Vendor ID of the Steam Deck controller (Valve Software)
+pub const VENDOR_ID: u16 = 0x28de;
+/// Product ID of the Steam Deck controller
+pub const PRODUCT_ID: u16 = 0x1205;
+/// Number of the interrupt IN endpoint of the controller interface
+pub const CONTROLLER_ENDPOINT: u8 = 3;
+/// Size of controller input and feature reports
+pub const REPORT_SIZE: usize = 64;
+
+/// Attribute data sent by the real device in reply to GetAttrib. The meaning
+/// of these bytes is unknown.
+#[rustfmt::skip]
+const ATTRIBUTES: [u8; 43] = [
+    ReportType::GetAttrib as u8, 0x2d, 0x01, 0x05, 0x12, 0x00, 0x00, 0x02, 0x00,
+    0x00, 0x00, 0x00, 0x0a, 0x2b, 0x12, 0xa9, 0x62, 0x04, 0xad, 0xf1, 0xe4, 0x65,
+    0x09, 0x2e, 0x00, 0x00, 0x00, 0x0b, 0xa0, 0x0f, 0x00, 0x00, 0x0d, 0x00, 0x00,
+    0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x0e,
+];
+
+/// Controller buttons
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Button {
+    A,
+    B,
+    X,
+    Y,
+    L1,
+    R1,
+    /// Digital state of the left trigger
+    L2,
+    /// Digital state of the right trigger
+    R2,
+    L3,
+    R3,
+    L4,
+    R4,
+    L5,
+    R5,
+    Up,
+    Down,
+    Left,
+    Right,
+    /// Hamburger (☰) button above the right stick
+    Menu,
+    /// Overlapping squares (⧉) button above the left stick
+    Options,
+    Steam,
+    QuickAccess,
+    LeftPadPress,
+    RightPadPress,
+}
+
+/// Side of the controller for sticks, triggers and trackpads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// Commands sent by the host to the controller that the user should act on
+#[derive(Debug, Clone, PartialEq)]
+pub enum SteamDeckEvent {
+    /// Haptic pulse on one or both trackpads
+    HapticPulse(PackedHapticPulseReport),
+    /// Rumble of the controller motors
+    Rumble(PackedRumbleReport),
+    /// Lizard mode (keyboard and mouse emulation) was enabled or disabled
+    LizardMode(bool),
+    /// A settings register was written
+    RegisterWrite { register: u8, value: u16 },
+}
+
+/// State shared with the report handlers of the controller interface
+#[derive(Debug)]
+struct Controller {
+    serial_number: String,
+    lizard_mode: bool,
+    /// The last command sent with SET_REPORT, which determines the reply to
+    /// the next GET_REPORT.
+    current_report: ReportType,
+    registers: BTreeMap<u8, u16>,
+    /// Registers requested with the last ReadRegister command
+    read_registers: Vec<u8>,
+    events: Sender<SteamDeckEvent>,
+}
+
+impl Controller {
+    /// Handle a feature report sent by the host
+    fn set_report(&mut self, data: &[u8]) {
+        let Some(first_byte) = data.first() else {
+            #[cfg(feature = "log")]
+            log::warn!("Unable to determine report type from empty report");
+            return;
+        };
+        let Ok(report_type) = ReportType::try_from(*first_byte) else {
+            #[cfg(feature = "log")]
+            log::warn!("Invalid report type: {first_byte}");
+            return;
+        };
+        #[cfg(feature = "log")]
+        log::debug!("Got SetReport with type: {report_type:?}");
+
+        // The second byte holds the length of the command payload
+        let length = data.get(1).copied().unwrap_or_default() as usize;
+        let payload = data.get(2..).unwrap_or_default();
+        let payload = &payload[..length.min(payload.len())];
+
+        match report_type {
+            // ClearMappings gets called to take the controller out of lizard
+            // mode so that Steam can control it directly.
+            ReportType::ClearMappings => self.set_lizard_mode(false),
+            // DefaultMappings sets the device in lizard mode, so it can run
+            // without Steam.
+            ReportType::DefaultMappings => self.set_lizard_mode(true),
+            ReportType::WriteRegister => {
+                for chunk in payload.chunks_exact(3) {
+                    let register = chunk[0];
+                    let value = u16::from_le_bytes([chunk[1], chunk[2]]);
+                    self.registers.insert(register, value);
+                    let _ = self
+                        .events
+                        .send(SteamDeckEvent::RegisterWrite { register, value });
+                }
+            }
+            ReportType::ClearRegister => self.registers.clear(),
+            ReportType::ReadRegister => {
+                self.read_registers = payload.to_vec();
+                self.current_report = report_type;
+            }
+            ReportType::GetAttrib | ReportType::GetSerial => self.current_report = report_type,
+            ReportType::TriggerHapticPulse => {
+                let size = PackedHapticPulseReport::packed_bytes_size(None).unwrap_or_default();
+                let Some(Ok(report)) = data
+                    .get(..size)
+                    .map(PackedHapticPulseReport::unpack_from_slice)
+                else {
+                    #[cfg(feature = "log")]
+                    log::warn!("Invalid haptic pulse report: {data:?}");
+                    return;
+                };
+                let _ = self.events.send(SteamDeckEvent::HapticPulse(report));
+            }
+            ReportType::TriggerRumbleCommand => {
+                let size = PackedRumbleReport::packed_bytes_size(None).unwrap_or_default();
+                let Some(Ok(report)) = data.get(..size).map(PackedRumbleReport::unpack_from_slice)
+                else {
+                    #[cfg(feature = "log")]
+                    log::warn!("Invalid rumble report: {data:?}");
+                    return;
+                };
+                let _ = self.events.send(SteamDeckEvent::Rumble(report));
+            }
+            _ => (),
+        }
+    }
+
+    /// Returns the feature report requested by the host, based on the last
+    /// command sent with SET_REPORT.
+    fn get_report(&self) -> Vec<u8> {
+        let mut data = match self.current_report {
+            ReportType::GetAttrib => ATTRIBUTES.to_vec(),
+            ReportType::GetSerial => {
+                let mut data = vec![ReportType::GetSerial as u8, 0x14, 0x01];
+                data.extend_from_slice(self.serial_number.as_bytes());
+                data
+            }
+            ReportType::ReadRegister => {
+                let mut data = vec![ReportType::ReadRegister as u8, 0];
+                for register in self.read_registers.iter() {
+                    let value = self.registers.get(register).copied().unwrap_or_default();
+                    data.push(*register);
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+                data[1] = (data.len() - 2) as u8;
+                data
+            }
+            _ => return Vec::new(),
+        };
+        data.resize(REPORT_SIZE, 0);
+        data
+    }
+
+    fn set_lizard_mode(&mut self, enabled: bool) {
+        #[cfg(feature = "log")]
+        log::info!("Setting lizard mode enabled: {enabled}");
+        self.lizard_mode = enabled;
+        let _ = self.events.send(SteamDeckEvent::LizardMode(enabled));
+    }
+}
+
+/// Emulated Steam Deck controller
+pub struct SteamDeck {
+    state: PackedInputDataReport,
+    controller: Arc<Mutex<Controller>>,
+    events: Receiver<SteamDeckEvent>,
+}
+
+impl SteamDeck {
+    /// Create a new Steam Deck controller with the given serial number
+    pub fn new(serial_number: &str) -> Self {
+        let (tx, rx) = channel();
+        let controller = Controller {
+            serial_number: serial_number.to_string(),
+            lizard_mode: true,
+            current_report: ReportType::InputData,
+            registers: BTreeMap::new(),
+            read_registers: Vec::new(),
+            events: tx,
+        };
+
+        Self {
+            state: PackedInputDataReport::default(),
+            controller: Arc::new(Mutex::new(controller)),
+            events: rx,
+        }
+    }
+
+    /// Build the virtual USB device of the controller. Feature reports sent by
+    /// the host are handled by this [SteamDeck].
+    pub fn build_device(&self) -> VirtualUSBDevice {
+        let set_controller = self.controller.clone();
+        let get_controller = self.controller.clone();
+
+        // Configuration values can be obtained from a real device with "sudo lsusb -v"
+        VirtualUSBDeviceBuilder::new(VENDOR_ID, PRODUCT_ID)
+            .class(DeviceClass::UseInterface)
+            .supported_langs(vec![LangId::EnglishUnitedStates])
+            .manufacturer("Valve Software")
+            .product("Steam Controller")
+            .max_packet_size(64)
+            .configuration(
+                ConfigurationBuilder::new()
+                    .max_power(500)
+                    // Mouse (iface 0)
+                    .interface(
+                        HidInterfaceBuilder::new()
+                            .country_code(0)
+                            .protocol(InterfaceProtocol::Mouse)
+                            .subclass(HidSubclass::None)
+                            .report_descriptor(&MOUSE_DESCRIPTOR)
+                            .endpoint_descriptor(
+                                EndpointBuilder::new()
+                                    .address_num(1)
+                                    .direction(Direction::In)
+                                    .transfer_type(TransferType::Interrupt)
+                                    .sync_type(SynchronizationType::NoSynchronization)
+                                    .usage_type(UsageType::Data)
+                                    .max_packet_size(0x0008)
+                                    .build(),
+                            )
+                            .build(),
+                    )
+                    // Keyboard (iface 1)
+                    .interface(
+                        HidInterfaceBuilder::new()
+                            .country_code(33)
+                            .protocol(InterfaceProtocol::Keyboard)
+                            .subclass(HidSubclass::Boot)
+                            .report_descriptor(&KEYBOARD_DESCRIPTOR)
+                            .endpoint_descriptor(
+                                EndpointBuilder::new()
+                                    .address_num(2)
+                                    .direction(Direction::In)
+                                    .transfer_type(TransferType::Interrupt)
+                                    .sync_type(SynchronizationType::NoSynchronization)
+                                    .usage_type(UsageType::Data)
+                                    .max_packet_size(0x0008)
+                                    .build(),
+                            )
+                            .build(),
+                    )
+                    // Controller (iface 2)
+                    .interface(
+                        HidInterfaceBuilder::new()
+                            .country_code(33)
+                            .protocol(InterfaceProtocol::None)
+                            .subclass(HidSubclass::None)
+                            .report_descriptor(&CONTROLLER_DESCRIPTOR)
+                            .endpoint_descriptor(
+                                EndpointBuilder::new()
+                                    .address_num(CONTROLLER_ENDPOINT)
+                                    .direction(Direction::In)
+                                    .transfer_type(TransferType::Interrupt)
+                                    .sync_type(SynchronizationType::NoSynchronization)
+                                    .usage_type(UsageType::Data)
+                                    .max_packet_size(REPORT_SIZE as u16)
+                                    .build(),
+                            )
+                            .on_set_report(HidReportType::Feature, 0, move |data| {
+                                if let Ok(mut controller) = set_controller.lock() {
+                                    controller.set_report(data);
+                                }
+                            })
+                            .on_get_report(HidReportType::Feature, 0, move || match get_controller
+                                .lock()
+                            {
+                                Ok(controller) => controller.get_report(),
+                                Err(_) => Vec::new(),
+                            })
+                            .build(),
+                    )
+                    .build(),
+            )
+            .build()
+    }
+
+    /// Set the state of the given button
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let state = &mut self.state;
+        let field = match button {
+            Button::A => &mut state.a,
+            Button::B => &mut state.b,
+            Button::X => &mut state.x,
+            Button::Y => &mut state.y,
+            Button::L1 => &mut state.l1,
+            Button::R1 => &mut state.r1,
+            Button::L2 => &mut state.l2,
+            Button::R2 => &mut state.r2,
+            Button::L3 => &mut state.l3,
+            Button::R3 => &mut state.r3,
+            Button::L4 => &mut state.l4,
+            Button::R4 => &mut state.r4,
+            Button::L5 => &mut state.l5,
+            Button::R5 => &mut state.r5,
+            Button::Up => &mut state.up,
+            Button::Down => &mut state.down,
+            Button::Left => &mut state.left,
+            Button::Right => &mut state.right,
+            Button::Menu => &mut state.menu,
+            Button::Options => &mut state.options,
+            Button::Steam => &mut state.steam,
+            Button::QuickAccess => &mut state.quick_access,
+            Button::LeftPadPress => &mut state.l_pad_press,
+            Button::RightPadPress => &mut state.r_pad_press,
+        };
+        *field = pressed;
+    }
+
+    /// Set the position of the given stick. Note that the hardware uses an
+    /// inverted Y axis, where positive values point up.
+    pub fn set_stick(&mut self, side: Side, x: i16, y: i16) {
+        let (stick_x, stick_y) = match side {
+            Side::Left => (&mut self.state.l_stick_x, &mut self.state.l_stick_y),
+            Side::Right => (&mut self.state.r_stick_x, &mut self.state.r_stick_y),
+        };
+        *stick_x = Integer::from_primitive(x);
+        *stick_y = Integer::from_primitive(y);
+    }
+
+    /// Set whether the given stick is touched, and the force of the touch
+    /// reported by its capacitive sensor.
+    pub fn set_stick_touch(&mut self, side: Side, force: Option<u16>) {
+        let (touch, stick_force) = match side {
+            Side::Left => (&mut self.state.l_stick_touch, &mut self.state.l_stick_force),
+            Side::Right => (&mut self.state.r_stick_touch, &mut self.state.r_stick_force),
+        };
+        *touch = force.is_some();
+        *stick_force = Integer::from_primitive(force.unwrap_or_default());
+    }
+
+    /// Set the analog value of the given trigger (0-32767)
+    pub fn set_trigger(&mut self, side: Side, value: u16) {
+        let trigger = match side {
+            Side::Left => &mut self.state.l_trigg,
+            Side::Right => &mut self.state.r_trigg,
+        };
+        *trigger = Integer::from_primitive(value);
+    }
+
+    /// Set the touch position of the given trackpad, or None if the trackpad
+    /// is not touched.
+    pub fn set_pad(&mut self, side: Side, position: Option<(i16, i16)>) {
+        let (touch, pad_x, pad_y) = match side {
+            Side::Left => (
+                &mut self.state.l_pad_touch,
+                &mut self.state.l_pad_x,
+                &mut self.state.l_pad_y,
+            ),
+            Side::Right => (
+                &mut self.state.r_pad_touch,
+                &mut self.state.r_pad_x,
+                &mut self.state.r_pad_y,
+            ),
+        };
+        let (x, y) = position.unwrap_or_default();
+        *touch = position.is_some();
+        *pad_x = Integer::from_primitive(x);
+        *pad_y = Integer::from_primitive(y);
+    }
+
+    /// Set the pressure applied to the given trackpad
+    pub fn set_pad_force(&mut self, side: Side, force: u16) {
+        let pad_force = match side {
+            Side::Left => &mut self.state.l_pad_force,
+            Side::Right => &mut self.state.r_pad_force,
+        };
+        *pad_force = Integer::from_primitive(force);
+    }
+
+    /// Set the accelerometer values
+    pub fn set_accel(&mut self, x: i16, y: i16, z: i16) {
+        self.state.accel_x = Integer::from_primitive(x);
+        self.state.accel_y = Integer::from_primitive(y);
+        self.state.accel_z = Integer::from_primitive(z);
+    }
+
+    /// Set the gyroscope values
+    pub fn set_gyro(&mut self, pitch: i16, yaw: i16, roll: i16) {
+        self.state.pitch = Integer::from_primitive(pitch);
+        self.state.yaw = Integer::from_primitive(yaw);
+        self.state.roll = Integer::from_primitive(roll);
+    }
+
+    /// Returns the current input state
+    pub fn state(&self) -> &PackedInputDataReport {
+        &self.state
+    }
+
+    /// Returns the current input state for direct modification
+    pub fn state_mut(&mut self) -> &mut PackedInputDataReport {
+        &mut self.state
+    }
+
+    /// Advance the frame counter and return the input report for the current
+    /// state, to be set on the [CONTROLLER_ENDPOINT].
+    pub fn report(&mut self) -> Vec<u8> {
+        let frame = self.state.frame.to_primitive();
+        self.state.frame = Integer::from_primitive(frame.wrapping_add(1));
+        self.state.pack().map(|r| r.to_vec()).unwrap_or_default()
+    }
+
+    /// Returns whether lizard mode (keyboard and mouse emulation) is enabled
+    pub fn lizard_mode(&self) -> bool {
+        self.controller
+            .lock()
+            .map(|c| c.lizard_mode)
+            .unwrap_or_default()
+    }
+
+    /// Returns the value of the given settings register, if it was written
+    pub fn register(&self, register: u8) -> Option<u16> {
+        let controller = self.controller.lock().ok()?;
+        controller.registers.get(&register).copied()
+    }
+
+    /// Returns the next command sent by the host, if any
+    pub fn poll_event(&self) -> Option<SteamDeckEvent> {
+        self.events.try_recv().ok()
+    }
+}
+
+impl Default for SteamDeck {
+    fn default() -> Self {
+        Self::new("INPU7PLUMB3R")
+    }
+}
@@ -81,4 +81,3 @@ pub const CONTROLLER_DESCRIPTOR: [u8; 38] = [
     0xb1, 0x02, //  Feature (Data,Var,Abs)
     0xc0, // End Collection
 ];
-
@@ -1,6 +1,5 @@
 //! Source: https://gitlab.com/open-sd/opensd/-/blob/main/src/opensdd/drivers/gamepad/hid_reports.hpp
 //! Source: https://github.com/torvalds/linux/blob/master/drivers/hid/hid-steam.c
-#![allow(warnings)]
 use packed_struct::prelude::*;
 
 // Input report axis ranges
@@ -89,6 +88,8 @@ impl TryFrom<u8> for ReportType {
     }
 }
 
+/// Controller settings registers
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Register {
     LPadMode = 0x07,
     RPadMode = 0x08,
@@ -0,0 +1,468 @@
+//! USBTMC instrument passing SCPI commands to a [ScpiHandler]
+//!
+//! Emulates a USB488 test and measurement device, which the host accesses
+//! with the usbtmc driver or with VISA libraries like pyvisa-py. Messages
+//! sent by the host are split into lines and passed to a [ScpiHandler],
+//! such as the built-in [Multimeter], and its responses are queued for the
+//! host to read with REQUEST_DEV_DEP_MSG_IN.
+//!
+//! Service requests are sent on the interrupt endpoint with
+//! [Instrument::request_service], and the status byte read by the host is
+//! shared through [Instrument::status].
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    sync::mpsc::{channel, Receiver},
+    thread,
+    time::Duration,
+};
+
+use packed_struct::prelude::*;
+
+pub use crate::usb::usbtmc::{status_byte, StatusByte, TmcEvent};
+use crate::{
+    devices::{read_available, Device},
+    usb::{
+        usbtmc::{transfer_attributes, BulkHeader, MsgId, TmcInterfaceBuilder, BULK_HEADER_SIZE},
+        ConfigurationBuilder, DeviceClass, LangId,
+    },
+    virtual_usb::{BulkIn, BulkOut, VirtualUSBDevice, VirtualUSBDeviceBuilder},
+};
+
+/// Vendor ID of the instrument (Linux Foundation)
+pub const VENDOR_ID: u16 = 0x1d6b;
+/// Product ID of the instrument (Multifunction Composite Gadget)
+pub const PRODUCT_ID: u16 = 0x0104;
+/// Number of the bulk OUT and bulk IN endpoints
+pub const BULK_ENDPOINT: u8 = 1;
+/// Number of the interrupt IN endpoint sending service requests
+pub const INTERRUPT_ENDPOINT: u8 = 2;
+/// Largest block of the response sent in one DEV_DEP_MSG_IN message
+pub const MAX_TRANSFER_SIZE: usize = 64 * 1024;
+/// Largest message accepted from the host
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+/// Max packet size of the bulk endpoints (high speed)
+const MAX_PACKET_SIZE: u16 = 512;
+
+/// Handler of the SCPI commands sent to an instrument
+pub trait ScpiHandler {
+    /// Handle a line of program messages, and return the response to
+    /// queries
+    fn handle(&mut self, line: &str) -> Option<String>;
+    /// Called when the host triggers the device with a TRIGGER message
+    fn trigger(&mut self) {}
+    /// Called when the host clears the device
+    fn clear(&mut self) {}
+}
+
+impl<F> ScpiHandler for F
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    fn handle(&mut self, line: &str) -> Option<String> {
+        self(line)
+    }
+}
+
+/// Returns true if the given SCPI program header matches the given pattern,
+/// whose nodes are written in long form with the short form in upper case
+/// (e.g. `MEASure:VOLTage:DC?`)
+pub fn header_matches(header: &str, pattern: &str) -> bool {
+    let header = header.trim_start_matches(':');
+    let (header, query) = match header.strip_suffix('?') {
+        Some(header) => (header, true),
+        None => (header, false),
+    };
+    let (pattern, pattern_query) = match pattern.strip_suffix('?') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    if query != pattern_query || header.split(':').count() != pattern.split(':').count() {
+        return false;
+    }
+    header
+        .split(':')
+        .zip(pattern.split(':'))
+        .all(|(node, long)| {
+            let short: String = long.chars().filter(|c| !c.is_ascii_lowercase()).collect();
+            node.eq_ignore_ascii_case(long) || node.eq_ignore_ascii_case(&short)
+        })
+}
+
+/// Format a number in the SCPI NR3 format (e.g. `+1.234500E+00`)
+pub fn format_nr3(value: f64) -> String {
+    let formatted = format!("{value:.6E}");
+    let (mantissa, exponent) = formatted.split_once('E').unwrap_or((&formatted, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or_default();
+    let sign = if mantissa.starts_with('-') { "" } else { "+" };
+    format!("{sign}{mantissa}E{exponent:+03}")
+}
+
+/// Simulated digital multimeter measuring a DC voltage set with
+/// [Multimeter::set_voltage]
+#[derive(Debug, Clone)]
+pub struct Multimeter {
+    identity: String,
+    voltage: f64,
+    /// SCPI error queue
+    errors: VecDeque<String>,
+}
+
+impl Multimeter {
+    pub fn new() -> Self {
+        Self {
+            identity: "Linux,Virtual Multimeter,0123456789AB,1.0".to_string(),
+            voltage: 0.0,
+            errors: VecDeque::new(),
+        }
+    }
+
+    /// The identification returned by `*IDN?`
+    pub fn set_identity(&mut self, identity: &str) {
+        self.identity = identity.to_string();
+    }
+
+    /// The DC voltage returned by measurements
+    pub fn voltage(&self) -> f64 {
+        self.voltage
+    }
+
+    /// Set the DC voltage returned by measurements
+    pub fn set_voltage(&mut self, voltage: f64) {
+        self.voltage = voltage;
+    }
+
+    /// Handle a single program message
+    fn command(&mut self, message: &str) -> Option<String> {
+        let header = message.split_whitespace().next().unwrap_or_default();
+        let is = |pattern| header_matches(header, pattern);
+
+        if is("*IDN?") {
+            Some(self.identity.clone())
+        } else if is("*RST") || is("CONFigure:VOLTage:DC") {
+            None
+        } else if is("*CLS") {
+            self.errors.clear();
+            None
+        } else if is("*OPC?") {
+            Some("1".to_string())
+        } else if is("*TST?") {
+            Some("0".to_string())
+        } else if is("SYSTem:ERRor?") || is("SYSTem:ERRor:NEXT?") {
+            let error = self.errors.pop_front();
+            Some(error.unwrap_or_else(|| "+0,\"No error\"".to_string()))
+        } else if is("MEASure:VOLTage:DC?") || is("READ?") || is("FETCh?") {
+            Some(format_nr3(self.voltage))
+        } else {
+            #[cfg(feature = "log")]
+            log::debug!("Undefined SCPI header: {header}");
+            self.errors
+                .push_back("-113,\"Undefined header\"".to_string());
+            None
+        }
+    }
+}
+
+impl Default for Multimeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScpiHandler for Multimeter {
+    /// Handle the program messages of the line, separated by semicolons.
+    /// The responses to queries are joined with semicolons.
+    fn handle(&mut self, line: &str) -> Option<String> {
+        let responses: Vec<String> = line
+            .split(';')
+            .map(str::trim)
+            .filter(|message| !message.is_empty())
+            .filter_map(|message| self.command(message))
+            .collect();
+        (!responses.is_empty()).then(|| responses.join(";"))
+    }
+
+    fn clear(&mut self) {
+        self.errors.clear();
+    }
+}
+
+/// REQUEST_DEV_DEP_MSG_IN waiting for a response
+#[derive(Debug, Copy, Clone)]
+struct ResponseRequest {
+    tag: u8,
+    max_size: usize,
+    term_char: Option<u8>,
+}
+
+/// Virtual USB488 instrument passing SCPI commands to a [ScpiHandler]
+#[derive(Debug)]
+pub struct Instrument<H: ScpiHandler> {
+    device: VirtualUSBDevice,
+    handler: H,
+    status: StatusByte,
+    events: Receiver<TmcEvent>,
+    bulk_in: BulkIn,
+    bulk_out: BulkOut,
+    /// Data of the bulk messages being received
+    messages: Vec<u8>,
+    /// Device dependent message being received, until its EOM
+    input: Vec<u8>,
+    /// Responses waiting to be read by the host
+    output: VecDeque<u8>,
+    /// Request for a response waiting for output
+    request: Option<ResponseRequest>,
+}
+
+impl<H: ScpiHandler> Instrument<H> {
+    /// Create an instrument passing commands to the given handler
+    pub fn new(handler: H) -> Result<Self, Box<dyn Error>> {
+        let (tx, rx) = channel();
+        let status = StatusByte::new();
+
+        let mut device = VirtualUSBDeviceBuilder::new(VENDOR_ID, PRODUCT_ID)
+            .class(DeviceClass::UseInterface)
+            .supported_langs(vec![LangId::EnglishUnitedStates])
+            .manufacturer("Linux")
+            .product("USBTMC Instrument")
+            .serial("0123456789AB")
+            .max_packet_size(64)
+            .configuration(
+                ConfigurationBuilder::new()
+                    .max_power(100)
+                    .interface(
+                        TmcInterfaceBuilder::new()
+                            .bulk_endpoints(BULK_ENDPOINT, BULK_ENDPOINT, MAX_PACKET_SIZE)
+                            .interrupt_endpoint(INTERRUPT_ENDPOINT)
+                            .status_byte(&status)
+                            .on_event(move |event| {
+                                let _ = tx.send(event);
+                            })
+                            .build(),
+                    )
+                    .build(),
+            )
+            .build();
+        let bulk_in = device.bulk_in(BULK_ENDPOINT)?;
+        let bulk_out = device.bulk_out(BULK_ENDPOINT)?;
+        // Room for the largest DEV_DEP_MSG_IN message
+        bulk_in.set_capacity(BULK_HEADER_SIZE + MAX_TRANSFER_SIZE + 3);
+
+        Ok(Self {
+            device,
+            handler,
+            status,
+            events: rx,
+            bulk_in,
+            bulk_out,
+            messages: Vec::new(),
+            input: Vec::new(),
+            output: VecDeque::new(),
+            request: None,
+        })
+    }
+
+    /// The status byte read by the host with READ_STATUS_BYTE
+    pub fn status(&self) -> &StatusByte {
+        &self.status
+    }
+
+    /// The handler commands are passed to
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// The handler commands are passed to
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// Request service from the host with the given status byte, to which
+    /// RQS is added
+    pub fn request_service(&mut self, status: u8) -> Result<(), Box<dyn Error>> {
+        let status = status | status_byte::RQS;
+        self.status.set(status);
+        self.device
+            .queue_report(INTERRUPT_ENDPOINT, &[0x81, status])
+    }
+
+    /// Reset the message state aborted or cleared by the host
+    fn handle_event(&mut self, event: TmcEvent) {
+        #[cfg(feature = "log")]
+        log::debug!("USBTMC event: {event:?}");
+        match event {
+            TmcEvent::Clear => {
+                self.messages.clear();
+                self.input.clear();
+                self.output.clear();
+                self.request = None;
+                self.handler.clear();
+            }
+            TmcEvent::AbortBulkOut { .. } => {
+                self.messages.clear();
+                self.input.clear();
+            }
+            TmcEvent::AbortBulkIn { .. } => {
+                self.output.clear();
+                self.request = None;
+            }
+            TmcEvent::IndicatorPulse => {
+                #[cfg(feature = "log")]
+                log::info!("Indicator pulse");
+            }
+            _ => (),
+        }
+    }
+
+    /// Process the complete messages received on the bulk OUT endpoint
+    fn handle_messages(&mut self) -> Result<(), Box<dyn Error>> {
+        read_available(&mut self.bulk_out, &mut self.messages)?;
+
+        while self.messages.len() >= BULK_HEADER_SIZE {
+            let header = BulkHeader::unpack_from_slice(&self.messages[..BULK_HEADER_SIZE])?;
+            if !header.has_valid_tag() || header.transfer_size() > MAX_MESSAGE_SIZE {
+                // Message boundaries are lost, so discard the data until the
+                // host aborts the transfer and starts a new message
+                #[cfg(feature = "log")]
+                log::warn!("Dropping message with invalid header: {header:?}");
+                self.messages.clear();
+                break;
+            }
+            let end = header.message_size();
+            if self.messages.len() < end {
+                break;
+            }
+            let data: Vec<u8> = self
+                .messages
+                .drain(..end)
+                .skip(BULK_HEADER_SIZE)
+                .take(header.transfer_size())
+                .collect();
+            self.handle_message(&header, &data);
+        }
+        Ok(())
+    }
+
+    /// Process a message from the host
+    fn handle_message(&mut self, header: &BulkHeader, data: &[u8]) {
+        match MsgId::from_primitive(header.msg_id) {
+            Some(MsgId::DevDepMsgOut) => {
+                if self.input.len() + data.len() > MAX_MESSAGE_SIZE {
+                    #[cfg(feature = "log")]
+                    log::warn!("Dropping message longer than {MAX_MESSAGE_SIZE} bytes");
+                    self.input.clear();
+                }
+                self.input.extend_from_slice(data);
+                if header.bm_transfer_attributes & transfer_attributes::EOM != 0 {
+                    let input = std::mem::take(&mut self.input);
+                    self.handle_input(&input);
+                }
+            }
+            Some(MsgId::RequestDevDepMsgIn) => {
+                let term_char = header.bm_transfer_attributes & transfer_attributes::TERM_CHAR;
+                self.request = Some(ResponseRequest {
+                    tag: header.b_tag,
+                    max_size: header.transfer_size().min(MAX_TRANSFER_SIZE),
+                    term_char: (term_char != 0).then_some(header.term_char),
+                });
+            }
+            Some(MsgId::Trigger) => self.handler.trigger(),
+            _id => {
+                #[cfg(feature = "log")]
+                log::debug!("Ignoring message {_id:?} ({:#04x})", header.msg_id);
+            }
+        }
+    }
+
+    /// Pass the lines of a complete message to the handler, and queue the
+    /// responses
+    fn handle_input(&mut self, input: &[u8]) {
+        let input = String::from_utf8_lossy(input);
+        for line in input.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            #[cfg(feature = "log")]
+            log::debug!("SCPI: {line}");
+            if let Some(response) = self.handler.handle(line) {
+                self.output.extend(response.as_bytes());
+                if !response.ends_with('\n') {
+                    self.output.push_back(b'\n');
+                }
+            }
+        }
+        self.update_mav();
+    }
+
+    /// Answer the pending request for a response once output is available
+    fn send_response(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.output.is_empty() {
+            return Ok(());
+        }
+        let Some(request) = self.request.take() else {
+            return Ok(());
+        };
+
+        let mut len = self.output.len().min(request.max_size);
+        let mut attributes = 0;
+        if let Some(term_char) = request.term_char {
+            if let Some(pos) = self.output.iter().take(len).position(|b| *b == term_char) {
+                len = pos + 1;
+                attributes |= transfer_attributes::TERM_CHAR;
+            }
+        }
+        let data: Vec<u8> = self.output.drain(..len).collect();
+        if self.output.is_empty() {
+            attributes |= transfer_attributes::EOM;
+        }
+
+        let header = BulkHeader::dev_dep_msg_in(request.tag, len as u32, attributes);
+        let mut message = header.pack()?.to_vec();
+        message.extend_from_slice(&data);
+        message.resize(header.message_size(), 0);
+        self.bulk_in.write_transfer(&message)?;
+        self.update_mav();
+        Ok(())
+    }
+
+    /// Set the Message Available bit of the status byte while responses
+    /// are queued
+    fn update_mav(&self) {
+        let status = self.status.get() & !status_byte::MAV;
+        match self.output.is_empty() {
+            true => self.status.set(status),
+            false => self.status.set(status | status_byte::MAV),
+        }
+    }
+}
+
+impl<H: ScpiHandler> Device for Instrument<H> {
+    fn device(&self) -> &VirtualUSBDevice {
+        &self.device
+    }
+
+    fn device_mut(&mut self) -> &mut VirtualUSBDevice {
+        &mut self.device
+    }
+
+    /// Handle the next pending USB transfer or host request, if any, and
+    /// answer the messages received. Waits up to the given timeout if there
+    /// was nothing to handle.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        match self.device.read()? {
+            Some(_xfer) => {
+                #[cfg(feature = "log")]
+                log::debug!(
+                    "Ignoring {:?} transfer on endpoint {}",
+                    _xfer.direction(),
+                    _xfer.ep
+                );
+            }
+            None => thread::sleep(timeout),
+        }
+
+        while let Ok(event) = self.events.try_recv() {
+            self.handle_event(event);
+        }
+        self.handle_messages()?;
+        self.send_response()
+    }
+}
@@ -0,0 +1,453 @@
+//! USB webcam streaming frames from a [FrameSource]
+//!
+//! Emulates a UVC camera, which the host binds with the uvcvideo driver and
+//! exposes as a `/dev/video*` device. The camera offers uncompressed YUY2
+//! and MJPEG video at the frame sizes and rates given to [Webcam::new], and
+//! the frames are produced by a [FrameSource] such as the built-in
+//! [TestPattern].
+//!
+//! Video is streamed over a bulk endpoint, one payload per frame, or over an
+//! isochronous endpoint in alternate setting 1, one payload per packet (see
+//! [Transport]). Frames are produced at the frame interval committed by the
+//! host, as long as the host is reading from the endpoint.
+
+pub mod jpeg;
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io,
+    sync::mpsc::{channel, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub use crate::usb::uvc::{StreamFormat, UvcFormat, VideoFormat, VideoFrame};
+use crate::{
+    devices::Device,
+    usb::{
+        uvc::{
+            payload_header, UvcControlInterfaceBuilder, UvcEvent, UvcStreamingInterfaceBuilder,
+            PAYLOAD_HEADER_SIZE,
+        },
+        ConfigurationBuilder, DeviceClass, LangId,
+    },
+    usbip::UsbIpDirection,
+    virtual_usb::{IsoXfer, Reply, VirtualUSBDevice, VirtualUSBDeviceBuilder, Xfer},
+};
+
+/// Vendor ID of the camera (NetChip, used by the Linux gadgets)
+pub const VENDOR_ID: u16 = 0x0525;
+/// Product ID of the camera (Linux-USB Video Gadget)
+pub const PRODUCT_ID: u16 = 0xa4a9;
+/// Number of the bulk or isochronous IN video endpoint
+pub const VIDEO_ENDPOINT: u8 = 1;
+/// Max packet size of the bulk endpoint (high speed)
+const MAX_PACKET_SIZE: u16 = 512;
+/// Max packet size of the isochronous endpoint: three transactions of 1024
+/// bytes per micro-frame (high speed, high bandwidth)
+const ISO_MAX_PACKET_SIZE: u16 = 1024 | (2 << 11);
+/// Service interval of the isochronous endpoint (one micro-frame)
+const PACKET_INTERVAL: Duration = Duration::from_micros(125);
+
+/// Type of the endpoint the video is streamed on
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Transport {
+    /// Bulk endpoint, sending each frame in a single payload
+    #[default]
+    Bulk,
+    /// Isochronous endpoint in alternate setting 1, sending a payload in
+    /// every packet at a fixed rate
+    Isochronous,
+}
+
+/// Source of the video frames sent to the host
+pub trait FrameSource {
+    /// Returns the next frame in the given format: packed YUY2 pixels for
+    /// [VideoFormat::Yuy2], or a JPEG image for [VideoFormat::Mjpeg]
+    fn next_frame(&mut self, format: &StreamFormat) -> io::Result<Vec<u8>>;
+}
+
+/// Moving color bars, scrolling a few pixels to the left on every frame
+#[derive(Debug, Clone, Default)]
+pub struct TestPattern {
+    frame: u64,
+}
+
+impl TestPattern {
+    /// Colors of the bars: white, yellow, cyan, green, magenta, red, blue
+    /// and black
+    const BARS: [[u8; 3]; 8] = [
+        [255, 255, 255],
+        [255, 255, 0],
+        [0, 255, 255],
+        [0, 255, 0],
+        [255, 0, 255],
+        [255, 0, 0],
+        [0, 0, 255],
+        [0, 0, 0],
+    ];
+    /// Number of pixels the bars move on every frame
+    const SPEED: usize = 4;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of frames produced so far
+    pub fn frame_count(&self) -> u64 {
+        self.frame
+    }
+
+    /// Returns the color of the pixel in the given column of the current
+    /// frame
+    fn color(&self, x: usize, width: usize) -> [u8; 3] {
+        let offset = (self.frame as usize).wrapping_mul(Self::SPEED);
+        let x = x.wrapping_add(offset) % width.max(1);
+        Self::BARS[x * Self::BARS.len() / width.max(1)]
+    }
+
+    /// Returns the current frame as RGB pixels
+    fn rgb(&self, width: usize, height: usize) -> Vec<u8> {
+        let row: Vec<u8> = (0..width).flat_map(|x| self.color(x, width)).collect();
+        row.repeat(height)
+    }
+
+    /// Returns the current frame as YUY2 pixels
+    fn yuy2(&self, width: usize, height: usize) -> Vec<u8> {
+        let row: Vec<u8> = (0..width)
+            .step_by(2)
+            .flat_map(|x| {
+                let left = yuv(self.color(x, width));
+                let right = yuv(self.color(x + 1, width));
+                let u = ((left[1] as u16 + right[1] as u16) / 2) as u8;
+                let v = ((left[2] as u16 + right[2] as u16) / 2) as u8;
+                [left[0], u, right[0], v]
+            })
+            .collect();
+        row.repeat(height)
+    }
+}
+
+impl FrameSource for TestPattern {
+    fn next_frame(&mut self, format: &StreamFormat) -> io::Result<Vec<u8>> {
+        let (width, height) = (format.width as usize, format.height as usize);
+        let frame = match format.format {
+            VideoFormat::Yuy2 => self.yuy2(width, height),
+            VideoFormat::Mjpeg => {
+                jpeg::encode_rgb(format.width, format.height, &self.rgb(width, height))
+            }
+        };
+        self.frame += 1;
+        Ok(frame)
+    }
+}
+
+/// Convert an RGB color to limited range BT.601 YUV
+fn yuv([r, g, b]: [u8; 3]) -> [u8; 3] {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = 16 + ((66 * r + 129 * g + 25 * b + 128) >> 8);
+    let u = 128 + ((-38 * r - 74 * g + 112 * b + 128) >> 8);
+    let v = 128 + ((112 * r - 94 * g - 18 * b + 128) >> 8);
+    [y, u, v].map(|value| value.clamp(0, 255) as u8)
+}
+
+/// Returns the formats offered by default: YUY2 and MJPEG at 640x480 and
+/// 320x240, at 30 or 15 frames per second
+pub fn default_formats() -> Vec<UvcFormat> {
+    let frames = vec![
+        VideoFrame::new(640, 480, &[30, 15]),
+        VideoFrame::new(320, 240, &[30, 15]),
+    ];
+    vec![
+        UvcFormat::new(VideoFormat::Yuy2, frames.clone()),
+        UvcFormat::new(VideoFormat::Mjpeg, frames),
+    ]
+}
+
+/// Virtual USB webcam streaming the frames of a [FrameSource]
+#[derive(Debug)]
+pub struct Webcam<S: FrameSource> {
+    device: VirtualUSBDevice,
+    source: S,
+    events: Receiver<UvcEvent>,
+    /// Stream parameters committed by the host
+    stream: Option<StreamFormat>,
+    transport: Transport,
+    /// Bulk IN transfers waiting for video data
+    pending_in: VecDeque<Xfer>,
+    /// Isochronous IN transfers waiting for video data
+    pending_iso: VecDeque<IsoXfer>,
+    /// Time the next isochronous transfer is due
+    next_iso: Instant,
+    /// Remaining data of the payload being sent, or of the frame being sent
+    /// for isochronous streams
+    payload: VecDeque<u8>,
+    /// Whether the payload ended with a full transfer, which must be
+    /// followed by a zero length transfer to mark its end
+    zero_length_pending: bool,
+    /// Frame ID bit of the frame being sent
+    frame_id: bool,
+    /// Time the next frame is due
+    next_frame: Instant,
+}
+
+impl<S: FrameSource> Webcam<S> {
+    /// Create a webcam offering the given formats (see [default_formats])
+    /// and streaming the frames of the given source over a bulk endpoint
+    pub fn new(source: S, formats: Vec<UvcFormat>) -> Self {
+        Self::with_transport(source, formats, Transport::Bulk)
+    }
+
+    /// Create a webcam offering the given formats and streaming the frames
+    /// of the given source over the given type of endpoint
+    pub fn with_transport(source: S, formats: Vec<UvcFormat>, transport: Transport) -> Self {
+        let (tx, rx) = channel();
+
+        let control = UvcControlInterfaceBuilder::new();
+        let mut streaming = UvcStreamingInterfaceBuilder::new();
+        for format in formats {
+            streaming.format(format);
+        }
+        match transport {
+            Transport::Bulk => streaming.bulk_endpoint(VIDEO_ENDPOINT, MAX_PACKET_SIZE),
+            Transport::Isochronous => {
+                streaming.isochronous_endpoint(VIDEO_ENDPOINT, ISO_MAX_PACKET_SIZE)
+            }
+        };
+        streaming.on_event(move |event| {
+            let _ = tx.send(event);
+        });
+
+        // Video functions use an interface association
+        let device = VirtualUSBDeviceBuilder::new(VENDOR_ID, PRODUCT_ID)
+            .class(DeviceClass::Miscellaneous)
+            .subclass(0x02)
+            .protocol(0x01)
+            .supported_langs(vec![LangId::EnglishUnitedStates])
+            .manufacturer("Linux")
+            .product("Video Gadget")
+            .max_packet_size(64)
+            .configuration(
+                ConfigurationBuilder::new()
+                    .max_power(250)
+                    .interface(control.build())
+                    .interface(streaming.build())
+                    .build(),
+            )
+            .build();
+
+        Self {
+            device,
+            source,
+            events: rx,
+            stream: None,
+            transport,
+            pending_in: VecDeque::new(),
+            pending_iso: VecDeque::new(),
+            next_iso: Instant::now(),
+            payload: VecDeque::new(),
+            zero_length_pending: false,
+            frame_id: false,
+            next_frame: Instant::now(),
+        }
+    }
+
+    /// The type of endpoint the video is streamed on
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// The stream parameters committed by the host, if it started streaming
+    pub fn stream_format(&self) -> Option<StreamFormat> {
+        self.stream
+    }
+
+    /// The source frames are taken from
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    /// The source frames are taken from
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// Handle a transfer that was not handled by the device itself
+    fn handle_xfer(&mut self, xfer: Xfer) {
+        let xfer = match xfer.into_iso() {
+            Ok(xfer) if xfer.direction() == UsbIpDirection::In && xfer.ep() == VIDEO_ENDPOINT => {
+                self.pending_iso.push_back(xfer);
+                return;
+            }
+            Ok(_xfer) => {
+                #[cfg(feature = "log")]
+                log::debug!(
+                    "Ignoring {:?} transfer on endpoint {}",
+                    _xfer.direction(),
+                    _xfer.ep()
+                );
+                return;
+            }
+            Err(xfer) => xfer,
+        };
+        match (xfer.direction(), xfer.ep) {
+            (UsbIpDirection::In, VIDEO_ENDPOINT) => self.pending_in.push_back(xfer),
+            (_direction, _ep) => {
+                #[cfg(feature = "log")]
+                log::debug!("Ignoring {_direction:?} transfer on endpoint {_ep}");
+            }
+        }
+    }
+
+    /// Answer pending bulk IN transfers with video data
+    fn send_pending(&mut self) -> Result<(), Box<dyn Error>> {
+        self.pending_in
+            .retain(|xfer| !self.device.is_unlinked(xfer.seqnum()));
+        while !self.pending_in.is_empty() {
+            if self.zero_length_pending {
+                if let Some(xfer) = self.pending_in.pop_front() {
+                    self.zero_length_pending = false;
+                    self.device.write(Reply::from_xfer(xfer, &[]))?;
+                }
+                continue;
+            }
+            if self.payload.is_empty() && !self.next_payload()? {
+                break;
+            }
+            let Some(xfer) = self.pending_in.pop_front() else {
+                break;
+            };
+
+            // The host detects the end of a payload by a short transfer
+            let len = xfer.buffer_length().min(self.payload.len());
+            let chunk: Vec<u8> = self.payload.drain(..len).collect();
+            self.zero_length_pending = self.payload.is_empty() && len == xfer.buffer_length();
+            self.device.write(Reply::from_xfer(xfer, &chunk))?;
+        }
+        Ok(())
+    }
+
+    /// Answer the pending isochronous IN transfers that are due, splitting
+    /// the frames into one payload per packet. Packets are sent empty while
+    /// no frame is due.
+    fn send_pending_iso(&mut self) -> Result<(), Box<dyn Error>> {
+        self.pending_iso
+            .retain(|xfer| !self.device.is_unlinked(xfer.seqnum()));
+        while let Some(xfer) = self.pending_iso.front() {
+            let now = Instant::now();
+            if now < self.next_iso {
+                break;
+            }
+            // Restart from now after a stall instead of catching up in a burst
+            let packets = xfer.packets().len() as u32;
+            self.next_iso = (self.next_iso + PACKET_INTERVAL * packets).max(now);
+            let Some(xfer) = self.pending_iso.pop_front() else {
+                break;
+            };
+
+            let mut packets = Vec::with_capacity(xfer.packets().len());
+            for packet in xfer.packets() {
+                if self.payload.is_empty() && !self.next_payload()? {
+                    packets.push(Vec::new());
+                    continue;
+                }
+                let len = packet
+                    .length
+                    .saturating_sub(PAYLOAD_HEADER_SIZE)
+                    .min(self.payload.len());
+                let mut info = payload_header::EOH;
+                if self.frame_id {
+                    info |= payload_header::FID;
+                }
+                if len == self.payload.len() {
+                    info |= payload_header::EOF;
+                }
+                let mut data = vec![PAYLOAD_HEADER_SIZE as u8, info];
+                data.extend(self.payload.drain(..len));
+                packets.push(data);
+            }
+            let packets: Vec<&[u8]> = packets.iter().map(Vec::as_slice).collect();
+            self.device.write(Reply::from_iso_xfer(xfer, &packets))?;
+        }
+        Ok(())
+    }
+
+    /// Take the next frame from the source if it is due, and queue it as a
+    /// payload. Frames of isochronous streams are queued without a header,
+    /// which is added to every packet instead. Returns false if no frame is
+    /// due yet.
+    fn next_payload(&mut self) -> Result<bool, Box<dyn Error>> {
+        let Some(stream) = self.stream else {
+            return Ok(false);
+        };
+        let now = Instant::now();
+        if now < self.next_frame {
+            return Ok(false);
+        }
+        // Skip frames that are overdue instead of sending them in a burst
+        self.next_frame = (self.next_frame + stream.interval()).max(now);
+
+        let mut frame = self.source.next_frame(&stream)?;
+        let max_size = match self.transport {
+            Transport::Bulk => (stream.max_payload_transfer_size as usize)
+                .saturating_sub(PAYLOAD_HEADER_SIZE)
+                .min(stream.max_video_frame_size as usize),
+            Transport::Isochronous => stream.max_video_frame_size as usize,
+        };
+        if frame.len() > max_size {
+            #[cfg(feature = "log")]
+            log::warn!("Truncating frame of {} bytes to {max_size}", frame.len());
+            frame.truncate(max_size);
+        }
+
+        // The frame ID bit toggles on every frame
+        self.frame_id = !self.frame_id;
+        if self.transport == Transport::Bulk {
+            let mut info = payload_header::EOH | payload_header::EOF;
+            if self.frame_id {
+                info |= payload_header::FID;
+            }
+            self.payload.extend([PAYLOAD_HEADER_SIZE as u8, info]);
+        }
+        self.payload.extend(frame);
+        Ok(true)
+    }
+}
+
+impl<S: FrameSource> Device for Webcam<S> {
+    fn device(&self) -> &VirtualUSBDevice {
+        &self.device
+    }
+
+    fn device_mut(&mut self) -> &mut VirtualUSBDevice {
+        &mut self.device
+    }
+
+    /// Handle the next pending USB transfer or host request, if any, and
+    /// send video data to the host. Waits up to the given timeout if there
+    /// was nothing to handle.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        match self.device.read()? {
+            Some(xfer) => self.handle_xfer(xfer),
+            None => thread::sleep(timeout),
+        }
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                UvcEvent::Commit(format) => {
+                    #[cfg(feature = "log")]
+                    log::info!("Streaming {format:?}");
+                    self.stream = Some(format);
+                    self.payload.clear();
+                    self.zero_length_pending = false;
+                    self.next_frame = Instant::now();
+                    self.next_iso = Instant::now();
+                }
+            }
+        }
+        match self.transport {
+            Transport::Bulk => self.send_pending(),
+            Transport::Isochronous => self.send_pending_iso(),
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Minimal baseline JPEG encoder for MJPEG frames
+//!
+//! Each 8x8 block is encoded with its average color only (all AC
+//! coefficients are zero), which keeps the encoder tiny at the cost of a
+//! blocky image. This is enough for synthetic test patterns made of large
+//! areas of flat color.
+
+/// Quantization step of the DC coefficients
+const DC_QUANTIZER: i32 = 8;
+
+/// Code lengths of the standard luminance DC Huffman table (ITU T.81 K.3)
+const DC_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+/// Symbols of the standard luminance DC Huffman table
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+/// Code lengths of the AC Huffman table, which only holds the end of block
+/// symbol with the 1-bit code 0
+const AC_BITS: [u8; 16] = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+/// Symbols of the AC Huffman table
+const AC_VALUES: [u8; 1] = [0x00];
+
+/// Encode the given RGB image (3 bytes per pixel, row by row) as a baseline
+/// JPEG image with 4:4:4 sampling
+pub fn encode_rgb(width: u16, height: u16, rgb: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_headers(&mut out, width, height);
+
+    // Encode the blocks of each component, interleaved one MCU at a time
+    let dc_codes = huffman_codes(&DC_BITS, &DC_VALUES);
+    let mut writer = BitWriter::new(&mut out);
+    let mut previous = [0i32; 3];
+    let (w, h) = (width as usize, height as usize);
+    for block_y in (0..h).step_by(8) {
+        for block_x in (0..w).step_by(8) {
+            let average = block_average(rgb, w, h, block_x, block_y);
+            for (component, value) in ycbcr(average).into_iter().enumerate() {
+                // The DC coefficient of a flat block is 8 times its level
+                // shifted value
+                let dc = ((value - 128) * 8) / DC_QUANTIZER;
+                let diff = dc - previous[component];
+                previous[component] = dc;
+
+                let (size, bits) = magnitude(diff);
+                let (code, len) = dc_codes[size as usize];
+                writer.write(code, len);
+                writer.write(bits, size);
+                // End of block
+                writer.write(0, 1);
+            }
+        }
+    }
+    writer.flush();
+
+    // End of image
+    out.extend_from_slice(&[0xff, 0xd9]);
+    out
+}
+
+/// Write the markers preceding the entropy coded data
+fn write_headers(out: &mut Vec<u8>, width: u16, height: u16) {
+    // Start of image and JFIF header
+    out.extend_from_slice(&[0xff, 0xd8]);
+    out.extend_from_slice(&[0xff, 0xe0, 0x00, 0x10]);
+    out.extend_from_slice(b"JFIF\0");
+    out.extend_from_slice(&[0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00]);
+
+    // Quantization table 0, used by all components
+    out.extend_from_slice(&[0xff, 0xdb, 0x00, 0x43, 0x00]);
+    out.extend_from_slice(&[DC_QUANTIZER as u8; 64]);
+
+    // Baseline frame with three components without subsampling
+    out.extend_from_slice(&[0xff, 0xc0, 0x00, 0x11, 0x08]);
+    out.extend_from_slice(&height.to_be_bytes());
+    out.extend_from_slice(&width.to_be_bytes());
+    out.push(3);
+    for id in 1..=3 {
+        out.extend_from_slice(&[id, 0x11, 0x00]);
+    }
+
+    // Huffman tables 0, used by all components
+    let len = 2 + (1 + 16 + DC_VALUES.len()) + (1 + 16 + AC_VALUES.len());
+    out.extend_from_slice(&[0xff, 0xc4]);
+    out.extend_from_slice(&(len as u16).to_be_bytes());
+    out.push(0x00);
+    out.extend_from_slice(&DC_BITS);
+    out.extend_from_slice(&DC_VALUES);
+    out.push(0x10);
+    out.extend_from_slice(&AC_BITS);
+    out.extend_from_slice(&AC_VALUES);
+
+    // Start of scan with all components
+    out.extend_from_slice(&[0xff, 0xda, 0x00, 0x0c, 0x03]);
+    for id in 1..=3 {
+        out.extend_from_slice(&[id, 0x00]);
+    }
+    out.extend_from_slice(&[0x00, 0x3f, 0x00]);
+}
+
+/// Returns the average color of the 8x8 block at the given position,
+/// ignoring pixels outside of the image
+fn block_average(rgb: &[u8], width: usize, height: usize, x: usize, y: usize) -> [i32; 3] {
+    let mut sum = [0u32; 3];
+    let mut count = 0;
+    for row in y..(y + 8).min(height) {
+        for col in x..(x + 8).min(width) {
+            let offset = (row * width + col) * 3;
+            let Some(pixel) = rgb.get(offset..offset + 3) else {
+                continue;
+            };
+            for (sum, value) in sum.iter_mut().zip(pixel) {
+                *sum += *value as u32;
+            }
+            count += 1;
+        }
+    }
+    let count = count.max(1);
+    sum.map(|sum| (sum / count) as i32)
+}
+
+/// Convert an RGB color to full range YCbCr as used by JFIF
+fn ycbcr([r, g, b]: [i32; 3]) -> [i32; 3] {
+    let y = (299 * r + 587 * g + 114 * b) / 1000;
+    let cb = 128 + (-169 * r - 331 * g + 500 * b) / 1000;
+    let cr = 128 + (500 * r - 419 * g - 81 * b) / 1000;
+    [y, cb, cr].map(|value| value.clamp(0, 255))
+}
+
+/// Returns the size category of the given coefficient difference and its
+/// additional bits
+fn magnitude(value: i32) -> (u8, u32) {
+    let size = (32 - value.unsigned_abs().leading_zeros()) as u8;
+    let bits = if value < 0 {
+        (value - 1) as u32 & ((1 << size) - 1)
+    } else {
+        value as u32
+    };
+    (size, bits)
+}
+
+/// Returns the canonical Huffman code and its length for each symbol,
+/// indexed by symbol
+fn huffman_codes(bits: &[u8; 16], values: &[u8]) -> Vec<(u32, u8)> {
+    let mut codes = vec![(0, 0); 256];
+    let mut code = 0u32;
+    let mut symbols = values.iter();
+    for (i, count) in bits.iter().enumerate() {
+        for _ in 0..*count {
+            if let Some(symbol) = symbols.next() {
+                codes[*symbol as usize] = (code, i as u8 + 1);
+            }
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+/// Writes entropy coded data, stuffing a zero byte after every 0xFF byte
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    buffer: u32,
+    count: u8,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        Self {
+            out,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    /// Write the lowest `len` bits of the given value, most significant first
+    fn write(&mut self, value: u32, len: u8) {
+        for i in (0..len).rev() {
+            self.buffer = (self.buffer << 1) | ((value >> i) & 1);
+            self.count += 1;
+            if self.count == 8 {
+                self.push_byte();
+            }
+        }
+    }
+
+    /// Pad the last byte with 1 bits
+    fn flush(&mut self) {
+        while self.count != 0 {
+            self.write(1, 1);
+        }
+    }
+
+    fn push_byte(&mut self) {
+        let byte = self.buffer as u8;
+        self.out.push(byte);
+        if byte == 0xff {
+            self.out.push(0x00);
+        }
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
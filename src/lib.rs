@@ -1,3 +1,4 @@
+pub mod devices;
 pub mod usb;
 pub mod usbip;
 pub mod vhci_hcd;
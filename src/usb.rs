@@ -1,14 +1,35 @@
 //! Reference:
 //! https://github.com/toasterllc/Toastbox/blob/d3b1770c6816eb648ee2e0a754c2dd9c3bd5342f/USB.h
 
+pub mod ccid;
 pub mod cdc;
+pub mod dfu;
 pub mod hid;
+pub mod midi;
+pub mod msc;
+pub mod printer;
+pub mod ptp;
+pub mod uac;
+pub mod usbtmc;
+pub mod uvc;
 
 use std::fmt::Display;
 
 use packed_struct::prelude::*;
 
-use self::hid::HidInterface;
+use self::{
+    ccid::CcidInterface,
+    cdc::{CdcDataInterface, CdcInterface},
+    dfu::DfuInterface,
+    hid::HidInterface,
+    midi::MidiStreamingInterface,
+    msc::MscInterface,
+    printer::PrinterInterface,
+    ptp::PtpInterface,
+    uac::{UacControlInterface, UacStreamingInterface},
+    usbtmc::TmcInterface,
+    uvc::{UvcControlInterface, UvcStreamingInterface},
+};
 
 pub const ENDPOINT_MAX_COUNT_OUT: u8 = 16;
 pub const ENDPOINT_MAX_COUNT_IN: u8 = 16;
@@ -71,8 +92,8 @@ pub struct SetupRequest {
     #[packed_field(bits = "3..=7", ty = "enum")]
     pub bm_request_type_recipient: Recipient,
     // byte 1
-    #[packed_field(bytes = "1", ty = "enum")]
-    pub b_request: StandardRequest,
+    #[packed_field(bytes = "1")]
+    pub b_request: u8,
     // byte 2-3
     #[packed_field(bytes = "2..=3", endian = "lsb")]
     pub w_value: Integer<u16, packed_bits::Bits<16>>,
@@ -91,7 +112,7 @@ impl SetupRequest {
         self.bm_request_type_direction == Direction::Out
             && self.bm_request_type_kind == Type::Standard
             && self.bm_request_type_recipient == Recipient::Device
-            && self.b_request == StandardRequest::GetStatus
+            && self.b_request == StandardRequest::GetStatus.to_primitive()
             && self.w_value.to_primitive() == 0
             && self.w_index.to_primitive() == 0
             && self.w_length.to_primitive() == 0
@@ -120,6 +141,21 @@ impl SetupRequest {
         self.bm_request_type_recipient
     }
 
+    /// The raw request code (bRequest). Its meaning depends on the request
+    /// type and recipient.
+    pub fn request(&self) -> u8 {
+        self.b_request
+    }
+
+    /// The standard request code, if this is a standard request with a known
+    /// request code.
+    pub fn standard_request(&self) -> Option<StandardRequest> {
+        if !self.is_standard() {
+            return None;
+        }
+        StandardRequest::from_primitive(self.b_request)
+    }
+
     /// The value of the request.
     pub fn value(&self) -> u16 {
         self.w_value.to_primitive()
@@ -148,6 +184,7 @@ pub enum DescriptorType {
     OtherSpeedConfiguration = 7,
     InterfacePower = 8,
     Debug = 0x0a,
+    InterfaceAssociation = 0x0b,
 }
 
 /// Class code (assigned by the USB-IF).
@@ -495,6 +532,19 @@ impl Default for ConfigurationDescriptor {
 #[derive(Debug, Clone)]
 pub enum Interface {
     Hid(HidInterface),
+    Cdc(CdcInterface),
+    CdcData(CdcDataInterface),
+    MassStorage(MscInterface),
+    Printer(PrinterInterface),
+    SmartCard(CcidInterface),
+    Dfu(DfuInterface),
+    TestMeasurement(TmcInterface),
+    StillImage(PtpInterface),
+    VideoControl(UvcControlInterface),
+    VideoStreaming(UvcStreamingInterface),
+    AudioControl(UacControlInterface),
+    AudioStreaming(UacStreamingInterface),
+    MidiStreaming(MidiStreamingInterface),
 }
 
 impl Interface {
@@ -502,6 +552,19 @@ impl Interface {
     pub fn set_interface_number(&mut self, num: u8) {
         match self {
             Interface::Hid(iface) => iface.set_interface_number(num),
+            Interface::Cdc(iface) => iface.set_interface_number(num),
+            Interface::CdcData(iface) => iface.set_interface_number(num),
+            Interface::MassStorage(iface) => iface.set_interface_number(num),
+            Interface::Printer(iface) => iface.set_interface_number(num),
+            Interface::SmartCard(iface) => iface.set_interface_number(num),
+            Interface::Dfu(iface) => iface.set_interface_number(num),
+            Interface::TestMeasurement(iface) => iface.set_interface_number(num),
+            Interface::StillImage(iface) => iface.set_interface_number(num),
+            Interface::VideoControl(iface) => iface.set_interface_number(num),
+            Interface::VideoStreaming(iface) => iface.set_interface_number(num),
+            Interface::AudioControl(iface) => iface.set_interface_number(num),
+            Interface::AudioStreaming(iface) => iface.set_interface_number(num),
+            Interface::MidiStreaming(iface) => iface.set_interface_number(num),
         }
     }
 
@@ -509,6 +572,19 @@ impl Interface {
     pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
         match self {
             Interface::Hid(iface) => iface.pack_to_vec(),
+            Interface::Cdc(iface) => iface.pack_to_vec(),
+            Interface::CdcData(iface) => iface.pack_to_vec(),
+            Interface::MassStorage(iface) => iface.pack_to_vec(),
+            Interface::Printer(iface) => iface.pack_to_vec(),
+            Interface::SmartCard(iface) => iface.pack_to_vec(),
+            Interface::Dfu(iface) => iface.pack_to_vec(),
+            Interface::TestMeasurement(iface) => iface.pack_to_vec(),
+            Interface::StillImage(iface) => iface.pack_to_vec(),
+            Interface::VideoControl(iface) => iface.pack_to_vec(),
+            Interface::VideoStreaming(iface) => iface.pack_to_vec(),
+            Interface::AudioControl(iface) => iface.pack_to_vec(),
+            Interface::AudioStreaming(iface) => iface.pack_to_vec(),
+            Interface::MidiStreaming(iface) => iface.pack_to_vec(),
         }
     }
 
@@ -516,6 +592,19 @@ impl Interface {
     pub fn get_size(&self) -> usize {
         match self {
             Interface::Hid(iface) => iface.get_size(),
+            Interface::Cdc(iface) => iface.get_size(),
+            Interface::CdcData(iface) => iface.get_size(),
+            Interface::MassStorage(iface) => iface.get_size(),
+            Interface::Printer(iface) => iface.get_size(),
+            Interface::SmartCard(iface) => iface.get_size(),
+            Interface::Dfu(iface) => iface.get_size(),
+            Interface::TestMeasurement(iface) => iface.get_size(),
+            Interface::StillImage(iface) => iface.get_size(),
+            Interface::VideoControl(iface) => iface.get_size(),
+            Interface::VideoStreaming(iface) => iface.get_size(),
+            Interface::AudioControl(iface) => iface.get_size(),
+            Interface::AudioStreaming(iface) => iface.get_size(),
+            Interface::MidiStreaming(iface) => iface.get_size(),
         }
     }
 
@@ -523,6 +612,49 @@ impl Interface {
     pub fn get_class(&self) -> InterfaceClass {
         match self {
             Interface::Hid(iface) => iface.get_class(),
+            Interface::Cdc(iface) => iface.get_class(),
+            Interface::CdcData(iface) => iface.get_class(),
+            Interface::MassStorage(iface) => iface.get_class(),
+            Interface::Printer(iface) => iface.get_class(),
+            Interface::SmartCard(iface) => iface.get_class(),
+            Interface::Dfu(iface) => iface.get_class(),
+            Interface::TestMeasurement(iface) => iface.get_class(),
+            Interface::StillImage(iface) => iface.get_class(),
+            Interface::VideoControl(iface) => iface.get_class(),
+            Interface::VideoStreaming(iface) => iface.get_class(),
+            Interface::AudioControl(iface) => iface.get_class(),
+            Interface::AudioStreaming(iface) => iface.get_class(),
+            Interface::MidiStreaming(iface) => iface.get_class(),
+        }
+    }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        match self {
+            Interface::Hid(iface) => iface.get_endpoints(),
+            Interface::Cdc(iface) => iface.get_endpoints(),
+            Interface::CdcData(iface) => iface.get_endpoints(),
+            Interface::MassStorage(iface) => iface.get_endpoints(),
+            Interface::Printer(iface) => iface.get_endpoints(),
+            Interface::SmartCard(iface) => iface.get_endpoints(),
+            Interface::Dfu(iface) => iface.get_endpoints(),
+            Interface::TestMeasurement(iface) => iface.get_endpoints(),
+            Interface::StillImage(iface) => iface.get_endpoints(),
+            Interface::VideoControl(iface) => iface.get_endpoints(),
+            Interface::VideoStreaming(iface) => iface.get_endpoints(),
+            Interface::AudioControl(iface) => iface.get_endpoints(),
+            Interface::AudioStreaming(iface) => iface.get_endpoints(),
+            Interface::MidiStreaming(iface) => iface.get_endpoints(),
+        }
+    }
+
+    /// Returns the number of alternate settings of the interface
+    pub fn alternate_settings(&self) -> u8 {
+        match self {
+            Interface::CdcData(iface) => iface.alternate_settings(),
+            Interface::VideoStreaming(iface) => iface.alternate_settings(),
+            Interface::AudioStreaming(iface) => iface.alternate_settings(),
+            _ => 1,
         }
     }
 }
@@ -557,6 +689,60 @@ pub enum InterfaceClass {
     VendorSpecific = 0xff,
 }
 
+/// The Interface Association Descriptor groups consecutive interfaces that
+/// belong to the same function, such as the communication and data
+/// interfaces of a CDC device. It is placed before the first interface of
+/// the function.
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "8")]
+pub struct InterfaceAssociationDescriptor {
+    /// Size of this descriptor in bytes.
+    #[packed_field(bytes = "0")]
+    pub b_length: u8,
+    /// Interface Association Descriptor Type = 11.
+    #[packed_field(bytes = "1")]
+    pub b_descriptor_type: u8,
+    /// Number of the first interface associated with this function.
+    #[packed_field(bytes = "2")]
+    pub b_first_interface: u8,
+    /// Number of contiguous interfaces associated with this function.
+    #[packed_field(bytes = "3")]
+    pub b_interface_count: u8,
+    /// Class code (assigned by the USB-IF).
+    #[packed_field(bytes = "4")]
+    pub b_function_class: u8,
+    /// Subclass code (assigned by the USB-IF).
+    #[packed_field(bytes = "5")]
+    pub b_function_sub_class: u8,
+    /// Protocol code (assigned by the USB-IF).
+    #[packed_field(bytes = "6")]
+    pub b_function_protocol: u8,
+    /// Index of string descriptor describing this function.
+    #[packed_field(bytes = "7")]
+    pub i_function: u8,
+}
+
+impl InterfaceAssociationDescriptor {
+    pub fn new() -> Self {
+        Self {
+            b_length: 8,
+            b_descriptor_type: DescriptorType::InterfaceAssociation as u8,
+            b_first_interface: 0,
+            b_interface_count: 0,
+            b_function_class: 0,
+            b_function_sub_class: 0,
+            b_function_protocol: 0,
+            i_function: 0,
+        }
+    }
+}
+
+impl Default for InterfaceAssociationDescriptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
 #[packed_struct(bit_numbering = "msb0", size_bytes = "9")]
 pub struct InterfaceDescriptor {
@@ -800,6 +986,27 @@ impl EndpointDescriptor {
             b_interval: 1,
         }
     }
+
+    /// Returns the endpoint number
+    pub fn number(&self) -> u8 {
+        self.b_endpoint_address_num.to_primitive()
+    }
+
+    /// Returns the endpoint direction
+    pub fn direction(&self) -> Direction {
+        self.b_endpoint_address_direction
+    }
+
+    /// Returns the endpoint transfer type
+    pub fn transfer_type(&self) -> TransferType {
+        self.bm_attributes_xfer_type
+    }
+
+    /// Returns the maximum packet size in bytes, without the additional
+    /// transaction opportunities of high speed endpoints
+    pub fn max_packet_size(&self) -> u16 {
+        self.w_max_packet_size.to_primitive() & 0x07ff
+    }
 }
 
 impl Default for EndpointDescriptor {
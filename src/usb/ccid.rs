@@ -0,0 +1,449 @@
+//! CCID (Chip/Smart Card Interface Devices) class
+//! https://www.usb.org/sites/default/files/DWG_Smart-Card_CCID_Rev110.pdf
+
+use std::fmt::Display;
+
+use packed_struct::prelude::*;
+
+use super::{
+    Direction, EndpointBuilder, EndpointDescriptor, Interface, InterfaceClass, InterfaceDescriptor,
+    SynchronizationType, TransferType, UsageType,
+};
+
+/// Descriptor type of the CCID class descriptor
+pub const CCID_DESCRIPTOR_TYPE: u8 = 0x21;
+/// Size of the header of CCID bulk messages
+pub const CCID_HEADER_SIZE: usize = 10;
+/// Size of the CCID class descriptor
+pub const CCID_DESCRIPTOR_SIZE: usize = 54;
+/// Default clock frequency of the reader in kHz
+pub const DEFAULT_CLOCK: u32 = 3580;
+/// Default data rate of the reader in bps
+pub const DEFAULT_DATA_RATE: u32 = 9600;
+/// Largest extended APDU exchanged with the card, with its header, Lc and
+/// Le fields
+pub const MAX_APDU_SIZE: usize = 4 + 3 + 65535 + 3;
+
+/// Bits of the dwFeatures field of the class descriptor
+pub mod features {
+    /// Automatic parameter configuration based on the ATR
+    pub const AUTO_PARAMETERS: u32 = 0x0000_0002;
+    /// Automatic ICC voltage selection
+    pub const AUTO_VOLTAGE: u32 = 0x0000_0008;
+    /// Automatic ICC clock frequency change
+    pub const AUTO_CLOCK: u32 = 0x0000_0010;
+    /// Automatic baud rate change
+    pub const AUTO_BAUD: u32 = 0x0000_0020;
+    /// Automatic PPS made by the reader
+    pub const AUTO_PPS: u32 = 0x0000_0080;
+    /// Short APDU level exchange
+    pub const SHORT_APDU: u32 = 0x0002_0000;
+    /// Short and extended APDU level exchange
+    pub const EXTENDED_APDU: u32 = 0x0004_0000;
+}
+
+/// Class requests (bRequest)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum CcidRequest {
+    Abort = 0x01,
+    GetClockFrequencies = 0x02,
+    GetDataRates = 0x03,
+}
+
+/// Messages sent by the host on the bulk OUT endpoint (bMessageType)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum PcToRdr {
+    SetParameters = 0x61,
+    IccPowerOn = 0x62,
+    IccPowerOff = 0x63,
+    GetSlotStatus = 0x65,
+    Secure = 0x69,
+    T0Apdu = 0x6a,
+    Escape = 0x6b,
+    GetParameters = 0x6c,
+    ResetParameters = 0x6d,
+    IccClock = 0x6e,
+    XfrBlock = 0x6f,
+    Mechanical = 0x71,
+    Abort = 0x72,
+    SetDataRateAndClockFrequency = 0x73,
+}
+
+impl PcToRdr {
+    /// Returns the type of the message answering this message
+    pub fn response(&self) -> RdrToPc {
+        match self {
+            PcToRdr::IccPowerOn | PcToRdr::XfrBlock | PcToRdr::Secure => RdrToPc::DataBlock,
+            PcToRdr::GetParameters | PcToRdr::ResetParameters | PcToRdr::SetParameters => {
+                RdrToPc::Parameters
+            }
+            PcToRdr::Escape => RdrToPc::Escape,
+            PcToRdr::SetDataRateAndClockFrequency => RdrToPc::DataRateAndClockFrequency,
+            _ => RdrToPc::SlotStatus,
+        }
+    }
+}
+
+/// Messages sent by the reader on the bulk IN endpoint (bMessageType)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum RdrToPc {
+    DataBlock = 0x80,
+    SlotStatus = 0x81,
+    Parameters = 0x82,
+    Escape = 0x83,
+    DataRateAndClockFrequency = 0x84,
+}
+
+/// Messages sent by the reader on the interrupt IN endpoint
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum CcidNotification {
+    NotifySlotChange = 0x50,
+    HardwareError = 0x51,
+}
+
+/// State of the card in a slot (bmICCStatus)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum IccStatus {
+    /// A card is present and powered
+    Active = 0,
+    /// A card is present and not powered
+    Inactive = 1,
+    /// No card is present
+    Absent = 2,
+}
+
+/// Result of a command (bmCommandStatus)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CommandStatus {
+    Processed = 0,
+    Failed = 1,
+    TimeExtension = 2,
+}
+
+/// Slot error codes (bError) of failed commands
+pub mod slot_error {
+    /// The command is not supported
+    pub const CMD_NOT_SUPPORTED: u8 = 0x00;
+    /// The slot index (offset of bSlot) does not exist
+    pub const BAD_SLOT: u8 = 0x05;
+    /// The command was aborted
+    pub const CMD_ABORTED: u8 = 0xff;
+    /// The card does not answer, or is absent
+    pub const ICC_MUTE: u8 = 0xfe;
+    /// The card is busy with another command
+    pub const CMD_SLOT_BUSY: u8 = 0xe0;
+}
+
+/// Returns the bStatus byte of a message with the given card and command
+/// status
+pub fn slot_status(icc: IccStatus, command: CommandStatus) -> u8 {
+    icc as u8 | (command as u8) << 6
+}
+
+/// Header of the messages exchanged on the bulk endpoints
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "10")]
+pub struct CcidHeader {
+    #[packed_field(bytes = "0")]
+    pub b_message_type: u8,
+    /// Length of the data following the header
+    #[packed_field(bytes = "1..=4", endian = "lsb")]
+    pub dw_length: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "5")]
+    pub b_slot: u8,
+    /// Sequence number, echoed in the response
+    #[packed_field(bytes = "6")]
+    pub b_seq: u8,
+    /// Message specific bytes. For responses: bStatus, bError and a message
+    /// specific byte.
+    #[packed_field(bytes = "7..=9")]
+    pub params: [u8; 3],
+}
+
+impl CcidHeader {
+    /// Returns the length of the data following the header
+    pub fn length(&self) -> usize {
+        self.dw_length.to_primitive() as usize
+    }
+}
+
+/// CCID class descriptor
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "54")]
+pub struct CcidClassDescriptor {
+    #[packed_field(bytes = "0")]
+    pub b_length: u8,
+    #[packed_field(bytes = "1")]
+    pub b_descriptor_type: u8,
+    #[packed_field(bytes = "2..=3", endian = "lsb")]
+    pub bcd_ccid: Integer<u16, packed_bits::Bits<16>>,
+    /// Index of the highest slot
+    #[packed_field(bytes = "4")]
+    pub b_max_slot_index: u8,
+    /// Supported voltages: 5V (bit 0), 3V (bit 1) and 1.8V (bit 2)
+    #[packed_field(bytes = "5")]
+    pub b_voltage_support: u8,
+    /// Supported protocols: T=0 (bit 0) and T=1 (bit 1)
+    #[packed_field(bytes = "6..=9", endian = "lsb")]
+    pub dw_protocols: Integer<u32, packed_bits::Bits<32>>,
+    /// Default clock frequency in kHz
+    #[packed_field(bytes = "10..=13", endian = "lsb")]
+    pub dw_default_clock: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "14..=17", endian = "lsb")]
+    pub dw_maximum_clock: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "18")]
+    pub b_num_clock_supported: u8,
+    /// Default data rate in bps
+    #[packed_field(bytes = "19..=22", endian = "lsb")]
+    pub dw_data_rate: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "23..=26", endian = "lsb")]
+    pub dw_max_data_rate: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "27")]
+    pub b_num_data_rates_supported: u8,
+    /// Maximum IFSD supported for T=1
+    #[packed_field(bytes = "28..=31", endian = "lsb")]
+    pub dw_max_ifsd: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "32..=35", endian = "lsb")]
+    pub dw_synch_protocols: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "36..=39", endian = "lsb")]
+    pub dw_mechanical: Integer<u32, packed_bits::Bits<32>>,
+    /// Features of the reader (see [features])
+    #[packed_field(bytes = "40..=43", endian = "lsb")]
+    pub dw_features: Integer<u32, packed_bits::Bits<32>>,
+    /// Largest message, header included
+    #[packed_field(bytes = "44..=47", endian = "lsb")]
+    pub dw_max_ccid_message_length: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "48")]
+    pub b_class_get_response: u8,
+    #[packed_field(bytes = "49")]
+    pub b_class_envelope: u8,
+    #[packed_field(bytes = "50..=51", endian = "lsb")]
+    pub w_lcd_layout: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "52")]
+    pub b_pin_support: u8,
+    #[packed_field(bytes = "53")]
+    pub b_max_ccid_busy_slots: u8,
+}
+
+impl CcidClassDescriptor {
+    /// Create the descriptor of a single slot reader exchanging extended
+    /// APDUs with the card, which requires no knowledge of the card
+    /// protocol from the host
+    pub fn new() -> Self {
+        let apdu_features = features::AUTO_PARAMETERS
+            | features::AUTO_VOLTAGE
+            | features::AUTO_CLOCK
+            | features::AUTO_BAUD
+            | features::AUTO_PPS
+            | features::EXTENDED_APDU;
+        Self {
+            b_length: CCID_DESCRIPTOR_SIZE as u8,
+            b_descriptor_type: CCID_DESCRIPTOR_TYPE,
+            bcd_ccid: Integer::from_primitive(0x0110),
+            b_max_slot_index: 0,
+            b_voltage_support: 0x07,
+            dw_protocols: Integer::from_primitive(0x03),
+            dw_default_clock: Integer::from_primitive(DEFAULT_CLOCK),
+            dw_maximum_clock: Integer::from_primitive(DEFAULT_CLOCK),
+            b_num_clock_supported: 0,
+            dw_data_rate: Integer::from_primitive(DEFAULT_DATA_RATE),
+            dw_max_data_rate: Integer::from_primitive(DEFAULT_DATA_RATE),
+            b_num_data_rates_supported: 0,
+            dw_max_ifsd: Integer::from_primitive(254),
+            dw_synch_protocols: Integer::from_primitive(0),
+            dw_mechanical: Integer::from_primitive(0),
+            dw_features: Integer::from_primitive(apdu_features),
+            dw_max_ccid_message_length: Integer::from_primitive(
+                (CCID_HEADER_SIZE + MAX_APDU_SIZE) as u32,
+            ),
+            b_class_get_response: 0xff,
+            b_class_envelope: 0xff,
+            w_lcd_layout: Integer::from_primitive(0),
+            b_pin_support: 0,
+            b_max_ccid_busy_slots: 1,
+        }
+    }
+}
+
+impl Default for CcidClassDescriptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Smart card reader interface definition
+#[derive(Debug, Clone)]
+pub struct CcidInterface {
+    pub iface: InterfaceDescriptor,
+    pub class_descriptor: CcidClassDescriptor,
+    pub endpoint_descriptors: Vec<EndpointDescriptor>,
+}
+
+impl CcidInterface {
+    pub fn new() -> Self {
+        let iface = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            b_interface_class: InterfaceClass::SmartCard,
+            b_interface_subclass: 0,
+            b_interface_protocol: 0,
+            ..InterfaceDescriptor::new()
+        };
+
+        Self {
+            iface,
+            class_descriptor: CcidClassDescriptor::new(),
+            endpoint_descriptors: Vec::new(),
+        }
+    }
+
+    /// Returns the reply to GET_CLOCK_FREQUENCIES: the supported clock
+    /// frequencies in kHz
+    pub fn clock_frequencies(&self) -> Vec<u8> {
+        self.class_descriptor
+            .dw_default_clock
+            .to_primitive()
+            .to_le_bytes()
+            .to_vec()
+    }
+
+    /// Returns the reply to GET_DATA_RATES: the supported data rates in bps
+    pub fn data_rates(&self) -> Vec<u8> {
+        self.class_descriptor
+            .dw_data_rate
+            .to_primitive()
+            .to_le_bytes()
+            .to_vec()
+    }
+
+    /// Returns the endpoint number of the interrupt IN endpoint
+    pub fn interrupt_endpoint(&self) -> Option<u8> {
+        self.endpoint_descriptors
+            .iter()
+            .find(|desc| {
+                desc.direction() == Direction::In && desc.transfer_type() == TransferType::Interrupt
+            })
+            .map(|desc| desc.number())
+    }
+
+    /// Serialize the interface into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.get_size());
+        result.append(&mut self.iface.pack_to_vec()?);
+        result.append(&mut self.class_descriptor.pack_to_vec()?);
+        for endpoint_desc in self.endpoint_descriptors.iter() {
+            result.append(&mut endpoint_desc.pack_to_vec()?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the byte serialized size of the interface
+    pub fn get_size(&self) -> usize {
+        9 + CCID_DESCRIPTOR_SIZE + (7 * self.endpoint_descriptors.len())
+    }
+
+    /// Returns the interface class
+    pub fn get_class(&self) -> InterfaceClass {
+        self.iface.b_interface_class
+    }
+
+    /// Set the interface number for this interface
+    pub fn set_interface_number(&mut self, num: u8) {
+        self.iface.b_interface_number = num;
+    }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        self.endpoint_descriptors.as_slice()
+    }
+}
+
+impl Display for CcidInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut text = vec![
+            format!("{}", self.iface),
+            format!("{}", self.class_descriptor),
+        ];
+        for desc in self.endpoint_descriptors.iter() {
+            text.push(format!("{}", desc));
+        }
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+impl Default for CcidInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [Interface] builder for constructing a smart card reader interface.
+pub struct CcidInterfaceBuilder {
+    iface: CcidInterface,
+}
+
+impl CcidInterfaceBuilder {
+    pub fn new() -> Self {
+        Self {
+            iface: CcidInterface::default(),
+        }
+    }
+
+    /// Construct the new Interface configuration.
+    pub fn build(&self) -> Interface {
+        #[cfg(feature = "log")]
+        log::debug!("CCID Interface: {}", self.iface);
+        Interface::SmartCard(self.iface.clone())
+    }
+
+    /// Replace the class descriptor of the interface
+    pub fn class_descriptor(&mut self, descriptor: CcidClassDescriptor) -> &mut Self {
+        self.iface.class_descriptor = descriptor;
+        self
+    }
+
+    /// Add a bulk IN and a bulk OUT endpoint with the given endpoint numbers
+    /// and max packet size (64 for full speed, 512 for high speed).
+    pub fn bulk_endpoints(&mut self, in_num: u8, out_num: u8, max_packet_size: u16) -> &mut Self {
+        for (num, direction) in [(in_num, Direction::In), (out_num, Direction::Out)] {
+            let descriptor = EndpointBuilder::new()
+                .address_num(num)
+                .direction(direction)
+                .transfer_type(TransferType::Bulk)
+                .sync_type(SynchronizationType::NoSynchronization)
+                .usage_type(UsageType::Data)
+                .max_packet_size(max_packet_size)
+                .build();
+            self.endpoint_descriptor(descriptor);
+        }
+        self
+    }
+
+    /// Add an interrupt IN endpoint with the given endpoint number, used to
+    /// notify the host of card insertion and removal
+    pub fn interrupt_endpoint(&mut self, num: u8) -> &mut Self {
+        let descriptor = EndpointBuilder::new()
+            .address_num(num)
+            .direction(Direction::In)
+            .transfer_type(TransferType::Interrupt)
+            .sync_type(SynchronizationType::NoSynchronization)
+            .usage_type(UsageType::Data)
+            .max_packet_size(8)
+            .interval(8)
+            .build();
+        self.endpoint_descriptor(descriptor)
+    }
+
+    /// Add the given endpoint to the interface
+    pub fn endpoint_descriptor(&mut self, descriptor: EndpointDescriptor) -> &mut Self {
+        self.iface.endpoint_descriptors.push(descriptor);
+        self.iface.iface.b_num_endpoints = self.iface.endpoint_descriptors.len() as u8;
+        self
+    }
+}
+
+impl Default for CcidInterfaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,43 +1,942 @@
+//! CDC (Communication Device Class)
+//! https://www.usb.org/document-library/class-definitions-communication-devices-12
+
+pub mod ncm;
+pub mod rndis;
+
+use std::{
+    error::Error,
+    fmt::{Debug, Display},
+    sync::{Arc, Mutex},
+};
+
 use packed_struct::prelude::*;
 
+use self::{
+    ncm::{NtbParameters, DEFAULT_NTB_MAX_SIZE},
+    rndis::{RndisFunction, RndisState, RESPONSE_AVAILABLE},
+};
+
+use super::{
+    Direction, EndpointBuilder, EndpointDescriptor, Interface, InterfaceAssociationDescriptor,
+    InterfaceClass, InterfaceDescriptor, SynchronizationType, TransferType, UsageType,
+};
+
+/// Version of the CDC specification the descriptors comply with (1.10)
+pub const BCD_CDC: u16 = 0x0110;
+/// Class-specific interface descriptor type (bDescriptorType)
+pub const CS_INTERFACE: u8 = 0x24;
+
+/// Communication interface subclass codes
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum CdcSubclass {
     None = 0x00,
     DirectLineControlModel = 0x01,
+    AbstractControlModel = 0x02,
+    TelephoneControlModel = 0x03,
+    MultiChannelControlModel = 0x04,
+    CapiControlModel = 0x05,
+    EthernetNetworkingControlModel = 0x06,
+    AtmNetworkingControlModel = 0x07,
+    WirelessHandsetControlModel = 0x08,
+    DeviceManagement = 0x09,
+    MobileDirectLineModel = 0x0a,
+    Obex = 0x0b,
+    EthernetEmulationModel = 0x0c,
+    NetworkControlModel = 0x0d,
+}
+
+/// Communication interface protocol codes
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CdcProtocol {
+    /// No class specific protocol required
+    None = 0x00,
+    /// AT Commands: V.250 etc
+    AtV250 = 0x01,
+    /// Vendor-specific
+    VendorSpecific = 0xff,
+}
+
+/// Functional descriptor subtypes (bDescriptorSubtype)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FunctionalDescriptorSubtype {
+    Header = 0x00,
+    CallManagement = 0x01,
+    AbstractControlManagement = 0x02,
+    Union = 0x06,
+    EthernetNetworking = 0x0f,
+    Ncm = 0x1a,
+}
+
+/// Capabilities of the Abstract Control Management functional descriptor
+/// (bmCapabilities)
+pub mod acm_capabilities {
+    /// Supports Set/Clear/Get_Comm_Feature
+    pub const COMM_FEATURE: u8 = 0x01;
+    /// Supports Set_Line_Coding, Set_Control_Line_State, Get_Line_Coding and
+    /// the Serial_State notification
+    pub const LINE_CODING: u8 = 0x02;
+    /// Supports Send_Break
+    pub const SEND_BREAK: u8 = 0x04;
+    /// Supports the Network_Connection notification
+    pub const NETWORK_CONNECTION: u8 = 0x08;
+}
+
+/// Capabilities of the NCM functional descriptor (bmNetworkCapabilities)
+pub mod ncm_capabilities {
+    /// Supports SetEthernetPacketFilter
+    pub const ETHERNET_PACKET_FILTER: u8 = 0x01;
+    /// Supports GetNetAddress and SetNetAddress
+    pub const NET_ADDRESS: u8 = 0x02;
+    /// Supports GetMaxDatagramSize and SetMaxDatagramSize
+    pub const MAX_DATAGRAM_SIZE: u8 = 0x08;
+}
+
+/// Ethernet packet filter bits (SET_ETHERNET_PACKET_FILTER wValue)
+pub mod packet_filter {
+    pub const PROMISCUOUS: u16 = 0x01;
+    pub const ALL_MULTICAST: u16 = 0x02;
+    pub const DIRECTED: u16 = 0x04;
+    pub const BROADCAST: u16 = 0x08;
+    pub const MULTICAST: u16 = 0x10;
+}
+
+/// Version of the NCM specification the descriptors comply with (1.00)
+pub const BCD_NCM: u16 = 0x0100;
+/// Maximum Ethernet frame size without FCS
+pub const MAX_SEGMENT_SIZE: u16 = 1514;
+
+/// CDC class-specific request codes (bRequest)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum CdcRequest {
+    SendEncapsulatedCommand = 0x00,
+    GetEncapsulatedResponse = 0x01,
+    SetCommFeature = 0x02,
+    GetCommFeature = 0x03,
+    ClearCommFeature = 0x04,
+    SetLineCoding = 0x20,
+    GetLineCoding = 0x21,
+    SetControlLineState = 0x22,
+    SendBreak = 0x23,
+    SetEthernetMulticastFilters = 0x40,
+    SetEthernetPowerManagementPatternFilter = 0x41,
+    GetEthernetPowerManagementPatternFilter = 0x42,
+    SetEthernetPacketFilter = 0x43,
+    GetEthernetStatistic = 0x44,
+}
+
+/// CDC notification codes (bNotification)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum CdcNotificationCode {
+    NetworkConnection = 0x00,
+    ResponseAvailable = 0x01,
+    SerialState = 0x20,
+    ConnectionSpeedChange = 0x2a,
+}
+
+/// Bits of the SERIAL_STATE notification (UART state bitmap)
+pub mod serial_state {
+    /// State of the receiver carrier detection mechanism (DCD)
+    pub const DCD: u16 = 0x01;
+    /// State of the transmission carrier (DSR)
+    pub const DSR: u16 = 0x02;
+    /// State of the break detection mechanism
+    pub const BREAK: u16 = 0x04;
+    /// State of the ring signal detection
+    pub const RING_SIGNAL: u16 = 0x08;
+    /// A framing error has occurred
+    pub const FRAMING: u16 = 0x10;
+    /// A parity error has occurred
+    pub const PARITY: u16 = 0x20;
+    /// Received data has been discarded due to an overrun
+    pub const OVERRUN: u16 = 0x40;
+}
+
+/// State change of a CDC-ACM interface requested by the host
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CdcEvent {
+    /// The host changed the line coding with SET_LINE_CODING
+    LineCoding(LineCoding),
+    /// The host changed the control signals with SET_CONTROL_LINE_STATE
+    ControlLineState { dtr: bool, rts: bool },
+    /// The host requested a break with the given duration in milliseconds.
+    /// A duration of 0xFFFF lasts until a break with a duration of 0 is sent.
+    SendBreak(u16),
+    /// The host changed the Ethernet packet filter (see [packet_filter])
+    PacketFilter(u16),
+    /// The host changed the maximum size of NTBs sent to it
+    NtbInputSize(u32),
+    /// The RNDIS state changed after a control message from the host
+    RndisState(RndisState),
+}
+
+/// Callback receiving the state changes requested by the host
+pub type CdcEventHandler = Arc<Mutex<dyn FnMut(CdcEvent) + Send>>;
+
+/// Event handlers of a CDC-ACM interface
+#[derive(Clone, Default)]
+pub struct CdcEventHandlers(Vec<CdcEventHandler>);
+
+impl CdcEventHandlers {
+    /// Dispatch the given event to all handlers
+    pub fn dispatch(&self, event: CdcEvent) {
+        for handler in self.0.iter() {
+            if let Ok(mut handler) = handler.lock() {
+                handler(event);
+            }
+        }
+    }
+}
+
+impl Debug for CdcEventHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CdcEventHandlers")
+            .field("count", &self.0.len())
+            .finish()
+    }
+}
+
+/// Serial line state of a CDC-ACM interface as set by the host
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct SerialLineState {
+    pub line_coding: LineCoding,
+    /// Data Terminal Ready
+    pub dtr: bool,
+    /// Request To Send
+    pub rts: bool,
+}
+
+/// Network state of a CDC-ECM or CDC-NCM interface as set by the host
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NetworkState {
+    /// Ethernet packet filter (see [packet_filter])
+    pub packet_filter: u16,
+    /// Maximum size of NTBs sent to the host (NCM only)
+    pub ntb_input_size: u32,
+    /// Maximum size of datagrams (NCM only)
+    pub max_datagram_size: u16,
+}
+
+impl Default for NetworkState {
+    fn default() -> Self {
+        Self {
+            packet_filter: packet_filter::DIRECTED | packet_filter::BROADCAST,
+            ntb_input_size: DEFAULT_NTB_MAX_SIZE,
+            max_datagram_size: MAX_SEGMENT_SIZE,
+        }
+    }
+}
+
+/// CDC communication interface definition. The data interface that belongs
+/// to it must be added to the configuration right after it.
+#[derive(Debug, Clone)]
+pub struct CdcInterface {
+    /// Optional association grouping the communication and data interfaces,
+    /// needed for composite devices.
+    pub association: Option<InterfaceAssociationDescriptor>,
+    pub iface: InterfaceDescriptor,
+    pub header: HeaderFunctionalDescriptor,
+    /// Call management descriptor of ACM interfaces
+    pub call_management: Option<CallManagementFunctionalDescriptor>,
+    /// Abstract control management descriptor of ACM interfaces
+    pub acm: Option<AbstractControlManagementFunctionalDescriptor>,
+    pub union: UnionFunctionalDescriptor,
+    /// Ethernet networking descriptor of ECM and NCM interfaces
+    pub ethernet: Option<EthernetNetworkingFunctionalDescriptor>,
+    /// NCM descriptor of NCM interfaces
+    pub ncm: Option<NcmFunctionalDescriptor>,
+    /// MAC address of ECM and NCM interfaces, used by the host for its
+    /// network interface
+    pub mac_address: Option<[u8; 6]>,
+    /// RNDIS control state machine of RNDIS interfaces, shared by all clones
+    /// of the interface
+    pub rndis: Option<Arc<Mutex<RndisFunction>>>,
+    pub endpoint_descriptors: Vec<EndpointDescriptor>,
+    /// Serial line state, shared by all clones of the interface
+    pub line_state: Arc<Mutex<SerialLineState>>,
+    /// Network state, shared by all clones of the interface
+    pub network_state: Arc<Mutex<NetworkState>>,
+    pub handlers: CdcEventHandlers,
 }
 
-///// [Interface] builder for constructing an CDC (Communication Device Class)
-///// interface descriptor.
-//pub struct CdcInterfaceBuilder {
-//    iface: Interface,
-//}
-//
-//impl CdcInterfaceBuilder {
-//    pub fn new() -> Self {
-//        let mut iface = Interface::new();
-//        iface.iface_desc.b_interface_class = InterfaceClass::Cdc;
-//
-//        Self { iface }
-//    }
-//
-//    /// Construct the new Interface configuration
-//    pub fn build(&self) -> Interface {
-//        self.iface.clone()
-//    }
-//
-//    /// Set the interface subclass
-//    pub fn subclass(&mut self, subclass: u8) -> &mut Self {
-//        self.iface.iface_desc.b_interface_subclass = subclass;
-//        self
-//    }
-//}
-
-//pub struct CDC {
-//    header_func_descs: Vec<HeaderFunctionalDescriptor>,
-//    call_management_func_descs: Vec<CallManagementFunctionalDescriptor>,
-//    acm_func_descs: Vec<AbstractControlManagementFunctionalDescriptor>,
-//    union_func_descs: Vec<UnionFunctionalDescriptor>,
-//    endpoint_descs: Vec<EndpointDescriptor>,
-//}
+impl CdcInterface {
+    pub fn new() -> Self {
+        let iface = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            b_interface_class: InterfaceClass::Cdc,
+            b_interface_subclass: CdcSubclass::AbstractControlModel as u8,
+            b_interface_protocol: CdcProtocol::None as u8,
+            ..InterfaceDescriptor::new()
+        };
+
+        Self {
+            association: None,
+            iface,
+            header: HeaderFunctionalDescriptor {
+                b_function_length: 5,
+                b_descriptor_type: CS_INTERFACE,
+                b_descriptor_subtype: FunctionalDescriptorSubtype::Header as u8,
+                bcd_cdc: Integer::from_primitive(BCD_CDC),
+            },
+            call_management: Some(CallManagementFunctionalDescriptor {
+                b_function_length: 5,
+                b_descriptor_type: CS_INTERFACE,
+                b_descriptor_subtype: FunctionalDescriptorSubtype::CallManagement as u8,
+                bm_capabilities: 0,
+                b_data_interface: 1,
+            }),
+            acm: Some(AbstractControlManagementFunctionalDescriptor {
+                b_function_length: 4,
+                b_descriptor_type: CS_INTERFACE,
+                b_descriptor_subtype: FunctionalDescriptorSubtype::AbstractControlManagement as u8,
+                bm_capabilities: acm_capabilities::LINE_CODING | acm_capabilities::SEND_BREAK,
+            }),
+            union: UnionFunctionalDescriptor {
+                b_function_length: 5,
+                b_descriptor_type: CS_INTERFACE,
+                b_descriptor_subtype: FunctionalDescriptorSubtype::Union as u8,
+                b_master_interface: 0,
+                b_slave_interface0: 1,
+            },
+            ethernet: None,
+            ncm: None,
+            mac_address: None,
+            rndis: None,
+            endpoint_descriptors: Vec::new(),
+            line_state: Arc::new(Mutex::new(SerialLineState::default())),
+            network_state: Arc::new(Mutex::new(NetworkState::default())),
+            handlers: CdcEventHandlers::default(),
+        }
+    }
+
+    /// Register a handler for the state changes requested by the host
+    pub fn on_event<F>(&mut self, handler: F)
+    where
+        F: FnMut(CdcEvent) + Send + 'static,
+    {
+        let handler: CdcEventHandler = Arc::new(Mutex::new(handler));
+        self.handlers.0.push(handler);
+    }
+
+    /// Returns the current serial line state
+    pub fn line_state(&self) -> SerialLineState {
+        self.line_state
+            .lock()
+            .map(|state| *state)
+            .unwrap_or_default()
+    }
+
+    /// Returns the current line coding, as requested by GET_LINE_CODING
+    pub fn line_coding(&self) -> LineCoding {
+        self.line_state().line_coding
+    }
+
+    /// Handle SET_LINE_CODING with the given request data. Returns false if
+    /// the data is not a valid line coding.
+    pub fn handle_set_line_coding(&self, data: &[u8]) -> bool {
+        let Ok(line_coding) = LineCoding::unpack_from_slice(data) else {
+            return false;
+        };
+        if let Ok(mut state) = self.line_state.lock() {
+            state.line_coding = line_coding;
+        }
+        self.handlers.dispatch(CdcEvent::LineCoding(line_coding));
+        true
+    }
+
+    /// Handle SET_CONTROL_LINE_STATE with the given request value
+    pub fn handle_set_control_line_state(&self, value: u16) {
+        let dtr = value & 0x01 != 0;
+        let rts = value & 0x02 != 0;
+        if let Ok(mut state) = self.line_state.lock() {
+            state.dtr = dtr;
+            state.rts = rts;
+        }
+        self.handlers
+            .dispatch(CdcEvent::ControlLineState { dtr, rts });
+    }
+
+    /// Handle SEND_BREAK with the given duration in milliseconds
+    pub fn handle_send_break(&self, duration: u16) {
+        self.handlers.dispatch(CdcEvent::SendBreak(duration));
+    }
+
+    /// Returns the current network state
+    pub fn network_state(&self) -> NetworkState {
+        self.network_state
+            .lock()
+            .map(|state| *state)
+            .unwrap_or_default()
+    }
+
+    /// Handle SET_ETHERNET_PACKET_FILTER with the given request value
+    pub fn handle_set_packet_filter(&self, value: u16) {
+        if let Ok(mut state) = self.network_state.lock() {
+            state.packet_filter = value;
+        }
+        self.handlers.dispatch(CdcEvent::PacketFilter(value));
+    }
+
+    /// Handle SET_NTB_INPUT_SIZE with the given request data. Returns false
+    /// if the data is too short.
+    pub fn handle_set_ntb_input_size(&self, data: &[u8]) -> bool {
+        let Some(size) = data.get(..4) else {
+            return false;
+        };
+        let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]);
+        if let Ok(mut state) = self.network_state.lock() {
+            state.ntb_input_size = size;
+        }
+        self.handlers.dispatch(CdcEvent::NtbInputSize(size));
+        true
+    }
+
+    /// Handle SET_MAX_DATAGRAM_SIZE with the given request data. Returns
+    /// false if the data is too short.
+    pub fn handle_set_max_datagram_size(&self, data: &[u8]) -> bool {
+        let Some(size) = data.get(..2) else {
+            return false;
+        };
+        if let Ok(mut state) = self.network_state.lock() {
+            state.max_datagram_size = u16::from_le_bytes([size[0], size[1]]);
+        }
+        true
+    }
+
+    /// Returns the NTB parameters reported with GET_NTB_PARAMETERS
+    pub fn ntb_parameters(&self) -> NtbParameters {
+        NtbParameters::new()
+    }
+
+    /// Handle an RNDIS control message sent with SEND_ENCAPSULATED_COMMAND.
+    /// Returns the RESPONSE_AVAILABLE notification to send if a response was
+    /// queued.
+    pub fn handle_encapsulated_command(
+        &self,
+        data: &[u8],
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let Some(rndis) = self.rndis.as_ref() else {
+            return Err("Interface is not an RNDIS interface".into());
+        };
+        let Ok(mut rndis) = rndis.lock() else {
+            return Err("RNDIS state is poisoned".into());
+        };
+        let state = rndis.state;
+        let available = rndis.handle_command(data)?;
+        let new_state = rndis.state;
+        drop(rndis);
+
+        if new_state != state {
+            self.handlers.dispatch(CdcEvent::RndisState(new_state));
+        }
+        Ok(available.then(|| RESPONSE_AVAILABLE.to_vec()))
+    }
+
+    /// Returns the next RNDIS response for GET_ENCAPSULATED_RESPONSE
+    pub fn encapsulated_response(&self) -> Option<Vec<u8>> {
+        let mut rndis = self.rndis.as_ref()?.lock().ok()?;
+        Some(rndis.next_response())
+    }
+
+    /// Returns the MAC address as reported in the iMACAddress string
+    /// descriptor (e.g. "0200DEADBEEF")
+    pub fn mac_address_string(&self) -> Option<String> {
+        let mac = self.mac_address?;
+        Some(mac.iter().map(|byte| format!("{byte:02X}")).collect())
+    }
+
+    /// Set the index of the string descriptor holding the MAC address
+    pub fn set_mac_address_index(&mut self, index: u8) {
+        if let Some(ethernet) = self.ethernet.as_mut() {
+            ethernet.i_mac_address = index;
+        }
+    }
+
+    /// Returns the endpoint number of the interrupt IN notification endpoint
+    pub fn notification_endpoint(&self) -> Option<u8> {
+        self.endpoint_descriptors
+            .iter()
+            .find(|desc| {
+                desc.direction() == Direction::In && desc.transfer_type() == TransferType::Interrupt
+            })
+            .map(|desc| desc.number())
+    }
+
+    /// Build a SERIAL_STATE notification with the given UART state bitmap
+    /// (see [serial_state]).
+    pub fn serial_state_notification(&self, state: u16) -> Result<Vec<u8>, PackingError> {
+        self.notification(CdcNotificationCode::SerialState, 0, &state.to_le_bytes())
+    }
+
+    /// Build a NETWORK_CONNECTION notification with the given link state
+    pub fn network_connection_notification(
+        &self,
+        connected: bool,
+    ) -> Result<Vec<u8>, PackingError> {
+        self.notification(
+            CdcNotificationCode::NetworkConnection,
+            connected as u16,
+            &[],
+        )
+    }
+
+    /// Build a CONNECTION_SPEED_CHANGE notification with the given downlink
+    /// and uplink bit rates in bits per second
+    pub fn connection_speed_change_notification(
+        &self,
+        downlink: u32,
+        uplink: u32,
+    ) -> Result<Vec<u8>, PackingError> {
+        let mut rates = downlink.to_le_bytes().to_vec();
+        rates.extend_from_slice(&uplink.to_le_bytes());
+        self.notification(CdcNotificationCode::ConnectionSpeedChange, 0, &rates)
+    }
+
+    /// Build a notification for this interface with the given data
+    fn notification(
+        &self,
+        code: CdcNotificationCode,
+        value: u16,
+        data: &[u8],
+    ) -> Result<Vec<u8>, PackingError> {
+        let header = CdcNotification {
+            w_value: Integer::from_primitive(value),
+            w_index: Integer::from_primitive(self.iface.b_interface_number as u16),
+            w_length: Integer::from_primitive(data.len() as u16),
+            ..CdcNotification::new(code)
+        };
+        let mut notification = header.pack_to_vec()?;
+        notification.extend_from_slice(data);
+        Ok(notification)
+    }
+
+    /// Serialize the interface into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.get_size());
+        if let Some(association) = self.association.as_ref() {
+            result.append(&mut association.pack_to_vec()?);
+        }
+        result.append(&mut self.iface.pack_to_vec()?);
+        result.append(&mut self.header.pack_to_vec()?);
+        if let Some(call_management) = self.call_management.as_ref() {
+            result.append(&mut call_management.pack_to_vec()?);
+        }
+        if let Some(acm) = self.acm.as_ref() {
+            result.append(&mut acm.pack_to_vec()?);
+        }
+        result.append(&mut self.union.pack_to_vec()?);
+        if let Some(ethernet) = self.ethernet.as_ref() {
+            result.append(&mut ethernet.pack_to_vec()?);
+        }
+        if let Some(ncm) = self.ncm.as_ref() {
+            result.append(&mut ncm.pack_to_vec()?);
+        }
+        for endpoint_desc in self.endpoint_descriptors.iter() {
+            result.append(&mut endpoint_desc.pack_to_vec()?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the byte serialized size of the interface
+    pub fn get_size(&self) -> usize {
+        // [IAD] + InterfaceDesc + Header + [CallManagement] + [ACM] + Union +
+        // [Ethernet] + [NCM] + (EndpointDesc * count)
+        let optional = [
+            (self.association.is_some(), 8),
+            (self.call_management.is_some(), 5),
+            (self.acm.is_some(), 4),
+            (self.ethernet.is_some(), 13),
+            (self.ncm.is_some(), 6),
+        ];
+        let optional: usize = optional
+            .iter()
+            .filter(|(present, _)| *present)
+            .map(|(_, size)| size)
+            .sum();
+        optional + 9 + 5 + 5 + (7 * self.endpoint_descriptors.len())
+    }
+
+    /// Returns the interface class
+    pub fn get_class(&self) -> InterfaceClass {
+        self.iface.b_interface_class
+    }
+
+    /// Set the interface number for this interface. The data interface is
+    /// expected to be the next interface.
+    pub fn set_interface_number(&mut self, num: u8) {
+        self.iface.b_interface_number = num;
+        self.union.b_master_interface = num;
+        self.union.b_slave_interface0 = num + 1;
+        if let Some(call_management) = self.call_management.as_mut() {
+            call_management.b_data_interface = num + 1;
+        }
+        if let Some(association) = self.association.as_mut() {
+            association.b_first_interface = num;
+        }
+    }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        self.endpoint_descriptors.as_slice()
+    }
+}
+
+impl Display for CdcInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut text = Vec::new();
+        if let Some(association) = self.association.as_ref() {
+            text.push(format!("{}", association));
+        }
+        text.push(format!("{}", self.iface));
+        text.push(format!("{}", self.header));
+        if let Some(call_management) = self.call_management.as_ref() {
+            text.push(format!("{}", call_management));
+        }
+        if let Some(acm) = self.acm.as_ref() {
+            text.push(format!("{}", acm));
+        }
+        text.push(format!("{}", self.union));
+        if let Some(ethernet) = self.ethernet.as_ref() {
+            text.push(format!("{}", ethernet));
+        }
+        if let Some(ncm) = self.ncm.as_ref() {
+            text.push(format!("{}", ncm));
+        }
+        for desc in self.endpoint_descriptors.iter() {
+            text.push(format!("{}", desc));
+        }
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+impl Default for CdcInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [Interface] builder for constructing a CDC (Communication Device Class)
+/// communication interface.
+pub struct CdcInterfaceBuilder {
+    iface: CdcInterface,
+}
+
+impl CdcInterfaceBuilder {
+    pub fn new() -> Self {
+        Self {
+            iface: CdcInterface::default(),
+        }
+    }
+
+    /// Construct the new Interface configuration.
+    pub fn build(&self) -> Interface {
+        #[cfg(feature = "log")]
+        log::debug!("CDC Interface: {}", self.iface);
+        Interface::Cdc(self.iface.clone())
+    }
+
+    /// Set the interface subclass
+    pub fn subclass(&mut self, subclass: CdcSubclass) -> &mut Self {
+        self.iface.iface.b_interface_subclass = subclass as u8;
+        if let Some(association) = self.iface.association.as_mut() {
+            association.b_function_sub_class = subclass as u8;
+        }
+        self
+    }
+
+    /// Set the interface protocol
+    pub fn protocol(&mut self, protocol: CdcProtocol) -> &mut Self {
+        self.iface.iface.b_interface_protocol = protocol as u8;
+        if let Some(association) = self.iface.association.as_mut() {
+            association.b_function_protocol = protocol as u8;
+        }
+        self
+    }
+
+    /// Set the capabilities of the Abstract Control Management functional
+    /// descriptor (see [acm_capabilities])
+    pub fn acm_capabilities(&mut self, capabilities: u8) -> &mut Self {
+        if let Some(acm) = self.iface.acm.as_mut() {
+            acm.bm_capabilities = capabilities;
+        }
+        self
+    }
+
+    /// Make this an Ethernet Control Model (ECM) interface. The host uses
+    /// the given MAC address for its network interface. The MAC address
+    /// string descriptor is added when the configuration is added to the
+    /// device.
+    pub fn ethernet(&mut self, mac_address: [u8; 6]) -> &mut Self {
+        self.subclass(CdcSubclass::EthernetNetworkingControlModel);
+        self.protocol(CdcProtocol::None);
+        self.iface.call_management = None;
+        self.iface.acm = None;
+        self.iface.ethernet = Some(EthernetNetworkingFunctionalDescriptor {
+            b_function_length: 13,
+            b_descriptor_type: CS_INTERFACE,
+            b_descriptor_subtype: FunctionalDescriptorSubtype::EthernetNetworking as u8,
+            i_mac_address: 0,
+            bm_ethernet_statistics: Integer::from_primitive(0),
+            w_max_segment_size: Integer::from_primitive(MAX_SEGMENT_SIZE),
+            w_number_mc_filters: Integer::from_primitive(0),
+            b_number_power_filters: 0,
+        });
+        self.iface.mac_address = Some(mac_address);
+        self
+    }
+
+    /// Make this a Network Control Model (NCM) interface. The host uses the
+    /// given MAC address for its network interface.
+    pub fn ncm(&mut self, mac_address: [u8; 6]) -> &mut Self {
+        self.ethernet(mac_address);
+        self.subclass(CdcSubclass::NetworkControlModel);
+        self.iface.ncm = Some(NcmFunctionalDescriptor {
+            b_function_length: 6,
+            b_descriptor_type: CS_INTERFACE,
+            b_descriptor_subtype: FunctionalDescriptorSubtype::Ncm as u8,
+            bcd_ncm_version: Integer::from_primitive(BCD_NCM),
+            bm_network_capabilities: ncm_capabilities::ETHERNET_PACKET_FILTER,
+        });
+        self
+    }
+
+    /// Make this a Remote NDIS (RNDIS) interface, as used by Windows style
+    /// USB tethering. The host uses the given MAC address for its network
+    /// interface. The data interface must not use alternate settings.
+    pub fn rndis(&mut self, mac_address: [u8; 6]) -> &mut Self {
+        self.subclass(CdcSubclass::AbstractControlModel);
+        self.protocol(CdcProtocol::VendorSpecific);
+        self.acm_capabilities(0);
+        self.iface.ethernet = None;
+        self.iface.ncm = None;
+        self.iface.mac_address = None;
+        self.iface.rndis = Some(Arc::new(Mutex::new(RndisFunction::new(mac_address))));
+        self
+    }
+
+    /// Group the communication and data interfaces with an Interface
+    /// Association Descriptor. This is needed when the CDC function is part
+    /// of a composite device, which should then use
+    /// [super::DeviceClass::Miscellaneous] as device class.
+    pub fn interface_association(&mut self) -> &mut Self {
+        self.iface.association = Some(InterfaceAssociationDescriptor {
+            b_interface_count: 2,
+            b_function_class: InterfaceClass::Cdc as u8,
+            b_function_sub_class: self.iface.iface.b_interface_subclass,
+            b_function_protocol: self.iface.iface.b_interface_protocol,
+            ..InterfaceAssociationDescriptor::new()
+        });
+        self
+    }
+
+    /// Handle the state changes requested by the host. See
+    /// [CdcInterface::on_event].
+    pub fn on_event<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: FnMut(CdcEvent) + Send + 'static,
+    {
+        self.iface.on_event(handler);
+        self
+    }
+
+    /// Add an interrupt IN notification endpoint with the given endpoint number
+    pub fn notification_endpoint(&mut self, num: u8) -> &mut Self {
+        let descriptor = EndpointBuilder::new()
+            .address_num(num)
+            .direction(Direction::In)
+            .transfer_type(TransferType::Interrupt)
+            .sync_type(SynchronizationType::NoSynchronization)
+            .usage_type(UsageType::Data)
+            .max_packet_size(16)
+            .interval(8)
+            .build();
+        self.endpoint_descriptor(descriptor)
+    }
+
+    /// Add the given endpoint to the interface
+    pub fn endpoint_descriptor(&mut self, descriptor: EndpointDescriptor) -> &mut Self {
+        self.iface.endpoint_descriptors.push(descriptor);
+        self.iface.iface.b_num_endpoints = self.iface.endpoint_descriptors.len() as u8;
+        self
+    }
+}
+
+impl Default for CdcInterfaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CDC data interface definition, which carries the data of the preceding
+/// communication interface on a pair of bulk endpoints.
+#[derive(Debug, Clone)]
+pub struct CdcDataInterface {
+    pub iface: InterfaceDescriptor,
+    /// If true, the endpoints belong to alternate setting 1 and alternate
+    /// setting 0 has no endpoints, as required by ECM and NCM.
+    pub alternate_setting: bool,
+    pub endpoint_descriptors: Vec<EndpointDescriptor>,
+}
+
+impl CdcDataInterface {
+    pub fn new() -> Self {
+        let iface = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            b_interface_class: InterfaceClass::CdcData,
+            ..InterfaceDescriptor::new()
+        };
+
+        Self {
+            iface,
+            alternate_setting: false,
+            endpoint_descriptors: Vec::new(),
+        }
+    }
+
+    /// Returns the interface descriptors of alternate setting 0 and, if
+    /// present, alternate setting 1
+    fn interface_descriptors(&self) -> Vec<InterfaceDescriptor> {
+        if !self.alternate_setting {
+            return vec![self.iface];
+        }
+        let inactive = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            ..self.iface
+        };
+        let active = InterfaceDescriptor {
+            b_alternate_setting: 1,
+            ..self.iface
+        };
+        vec![inactive, active]
+    }
+
+    /// Returns the number of alternate settings of the interface
+    pub fn alternate_settings(&self) -> u8 {
+        if self.alternate_setting {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Serialize the interface into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.get_size());
+        for iface in self.interface_descriptors() {
+            result.append(&mut iface.pack_to_vec()?);
+        }
+        for endpoint_desc in self.endpoint_descriptors.iter() {
+            result.append(&mut endpoint_desc.pack_to_vec()?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the byte serialized size of the interface
+    pub fn get_size(&self) -> usize {
+        (9 * self.alternate_settings() as usize) + (7 * self.endpoint_descriptors.len())
+    }
+
+    /// Returns the interface class
+    pub fn get_class(&self) -> InterfaceClass {
+        self.iface.b_interface_class
+    }
+
+    /// Set the interface number for this interface
+    pub fn set_interface_number(&mut self, num: u8) {
+        self.iface.b_interface_number = num;
+    }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        self.endpoint_descriptors.as_slice()
+    }
+}
+
+impl Display for CdcDataInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut text: Vec<String> = self
+            .interface_descriptors()
+            .iter()
+            .map(|iface| format!("{}", iface))
+            .collect();
+        for desc in self.endpoint_descriptors.iter() {
+            text.push(format!("{}", desc));
+        }
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+impl Default for CdcDataInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [Interface] builder for constructing a CDC data interface.
+pub struct CdcDataInterfaceBuilder {
+    iface: CdcDataInterface,
+}
+
+impl CdcDataInterfaceBuilder {
+    pub fn new() -> Self {
+        Self {
+            iface: CdcDataInterface::default(),
+        }
+    }
+
+    /// Construct the new Interface configuration.
+    pub fn build(&self) -> Interface {
+        #[cfg(feature = "log")]
+        log::debug!("CDC Data Interface: {}", self.iface);
+        Interface::CdcData(self.iface.clone())
+    }
+
+    /// Set the interface protocol (e.g. 0x01 for NCM transfer blocks)
+    pub fn protocol(&mut self, protocol: u8) -> &mut Self {
+        self.iface.iface.b_interface_protocol = protocol;
+        self
+    }
+
+    /// Move the endpoints to alternate setting 1, leaving alternate setting
+    /// 0 without endpoints. ECM and NCM hosts select alternate setting 1 with
+    /// SET_INTERFACE to start the data transfer.
+    pub fn alternate_setting(&mut self) -> &mut Self {
+        self.iface.alternate_setting = true;
+        self
+    }
+
+    /// Add a bulk IN and a bulk OUT endpoint with the given endpoint numbers
+    /// and max packet size (64 for full speed, 512 for high speed).
+    pub fn bulk_endpoints(&mut self, in_num: u8, out_num: u8, max_packet_size: u16) -> &mut Self {
+        for (num, direction) in [(in_num, Direction::In), (out_num, Direction::Out)] {
+            let descriptor = EndpointBuilder::new()
+                .address_num(num)
+                .direction(direction)
+                .transfer_type(TransferType::Bulk)
+                .sync_type(SynchronizationType::NoSynchronization)
+                .usage_type(UsageType::Data)
+                .max_packet_size(max_packet_size)
+                .build();
+            self.endpoint_descriptor(descriptor);
+        }
+        self
+    }
+
+    /// Add the given endpoint to the interface
+    pub fn endpoint_descriptor(&mut self, descriptor: EndpointDescriptor) -> &mut Self {
+        self.iface.endpoint_descriptors.push(descriptor);
+        self.iface.iface.b_num_endpoints = self.iface.endpoint_descriptors.len() as u8;
+        self
+    }
+}
+
+impl Default for CdcDataInterfaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
 #[packed_struct(bit_numbering = "msb0", size_bytes = "5")]
@@ -95,6 +994,64 @@ pub struct CallManagementFunctionalDescriptor {
     pub b_data_interface: u8,
 }
 
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "13")]
+pub struct EthernetNetworkingFunctionalDescriptor {
+    #[packed_field(bytes = "0")]
+    pub b_function_length: u8,
+    #[packed_field(bytes = "1")]
+    pub b_descriptor_type: u8,
+    #[packed_field(bytes = "2")]
+    pub b_descriptor_subtype: u8,
+    /// Index of the string descriptor holding the MAC address
+    #[packed_field(bytes = "3")]
+    pub i_mac_address: u8,
+    #[packed_field(bytes = "4..=7", endian = "lsb")]
+    pub bm_ethernet_statistics: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "8..=9", endian = "lsb")]
+    pub w_max_segment_size: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "10..=11", endian = "lsb")]
+    pub w_number_mc_filters: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "12")]
+    pub b_number_power_filters: u8,
+}
+
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "6")]
+pub struct NcmFunctionalDescriptor {
+    #[packed_field(bytes = "0")]
+    pub b_function_length: u8,
+    #[packed_field(bytes = "1")]
+    pub b_descriptor_type: u8,
+    #[packed_field(bytes = "2")]
+    pub b_descriptor_subtype: u8,
+    #[packed_field(bytes = "3..=4", endian = "lsb")]
+    pub bcd_ncm_version: Integer<u16, packed_bits::Bits<16>>,
+    /// See [ncm_capabilities]
+    #[packed_field(bytes = "5")]
+    pub bm_network_capabilities: u8,
+}
+
+/// Number of stop bits of a [LineCoding] (bCharFormat)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum StopBits {
+    One = 0,
+    OneAndHalf = 1,
+    Two = 2,
+}
+
+/// Parity of a [LineCoding] (bParityType)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum Parity {
+    None = 0,
+    Odd = 1,
+    Even = 2,
+    Mark = 3,
+    Space = 4,
+}
+
+/// Asynchronous serial line settings, used by SET_LINE_CODING and
+/// GET_LINE_CODING.
 #[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
 #[packed_struct(bit_numbering = "msb0", size_bytes = "7")]
 pub struct LineCoding {
@@ -107,3 +1064,71 @@ pub struct LineCoding {
     #[packed_field(bytes = "6")]
     pub b_data_bits: u8,
 }
+
+impl LineCoding {
+    pub fn new(rate: u32, stop_bits: StopBits, parity: Parity, data_bits: u8) -> Self {
+        Self {
+            dw_dte_rate: Integer::from_primitive(rate),
+            b_char_format: stop_bits.to_primitive(),
+            b_parity_type: parity.to_primitive(),
+            b_data_bits: data_bits,
+        }
+    }
+
+    /// Data terminal rate in bits per second
+    pub fn rate(&self) -> u32 {
+        self.dw_dte_rate.to_primitive()
+    }
+
+    /// Number of stop bits
+    pub fn stop_bits(&self) -> Option<StopBits> {
+        StopBits::from_primitive(self.b_char_format)
+    }
+
+    /// Parity
+    pub fn parity(&self) -> Option<Parity> {
+        Parity::from_primitive(self.b_parity_type)
+    }
+
+    /// Number of data bits (5, 6, 7, 8 or 16)
+    pub fn data_bits(&self) -> u8 {
+        self.b_data_bits
+    }
+}
+
+impl Default for LineCoding {
+    /// 9600 baud, 8 data bits, no parity, 1 stop bit
+    fn default() -> Self {
+        Self::new(9600, StopBits::One, Parity::None, 8)
+    }
+}
+
+/// Header of a notification sent on the interrupt IN endpoint of a
+/// communication interface. The notification data follows the header.
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "8")]
+pub struct CdcNotification {
+    #[packed_field(bytes = "0")]
+    pub bm_request_type: u8,
+    #[packed_field(bytes = "1", ty = "enum")]
+    pub b_notification: CdcNotificationCode,
+    #[packed_field(bytes = "2..=3", endian = "lsb")]
+    pub w_value: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "4..=5", endian = "lsb")]
+    pub w_index: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "6..=7", endian = "lsb")]
+    pub w_length: Integer<u16, packed_bits::Bits<16>>,
+}
+
+impl CdcNotification {
+    pub fn new(notification: CdcNotificationCode) -> Self {
+        Self {
+            // Device to host, class, interface
+            bm_request_type: 0xa1,
+            b_notification: notification,
+            w_value: Integer::from_primitive(0),
+            w_index: Integer::from_primitive(0),
+            w_length: Integer::from_primitive(0),
+        }
+    }
+}
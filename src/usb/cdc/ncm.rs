@@ -0,0 +1,386 @@
+//! NCM (Network Control Model) transfer block framing
+//! https://www.usb.org/document-library/network-control-model-devices-specification-v10-and-errata-and-adopters-agreement
+//!
+//! NCM functions exchange Ethernet frames in NCM Transfer Blocks (NTBs). Each
+//! NTB starts with an NTH16 header that points to a chain of datagram pointer
+//! tables (NDP16), which in turn point to the datagrams in the block. Only
+//! 16-bit NTBs without CRC are supported.
+
+use std::error::Error;
+
+use packed_struct::prelude::*;
+
+/// Signature of the NTB header ("NCMH")
+pub const NTH16_SIGNATURE: u32 = 0x484d_434e;
+/// Signature of a datagram pointer table without CRC ("NCM0")
+pub const NDP16_SIGNATURE: u32 = 0x304d_434e;
+/// Size of the NTB header
+pub const NTH16_SIZE: usize = 12;
+/// Size of a datagram pointer table header
+pub const NDP16_SIZE: usize = 8;
+/// Size of a datagram pointer entry (wDatagramIndex, wDatagramLength)
+const NDP16_ENTRY_SIZE: usize = 4;
+/// bmNtbFormatsSupported bit for 16-bit NTBs
+pub const NTB_FORMAT_16: u16 = 0x01;
+/// Default maximum size of NTBs in both directions
+pub const DEFAULT_NTB_MAX_SIZE: u32 = 16384;
+/// Alignment of datagrams and pointer tables in NTBs built by the device
+const ALIGNMENT: usize = 4;
+/// Maximum number of pointer tables followed in a single NTB
+const MAX_NDP_COUNT: usize = 32;
+
+/// NCM class-specific request codes (bRequest)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum NcmRequest {
+    GetNtbParameters = 0x80,
+    GetNetAddress = 0x81,
+    SetNetAddress = 0x82,
+    GetNtbFormat = 0x83,
+    SetNtbFormat = 0x84,
+    GetNtbInputSize = 0x85,
+    SetNtbInputSize = 0x86,
+    GetMaxDatagramSize = 0x87,
+    SetMaxDatagramSize = 0x88,
+    GetCrcMode = 0x89,
+    SetCrcMode = 0x8a,
+}
+
+/// NTB parameter structure returned by GET_NTB_PARAMETERS
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "28")]
+pub struct NtbParameters {
+    #[packed_field(bytes = "0..=1", endian = "lsb")]
+    pub w_length: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "2..=3", endian = "lsb")]
+    pub bm_ntb_formats_supported: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "4..=7", endian = "lsb")]
+    pub dw_ntb_in_max_size: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "8..=9", endian = "lsb")]
+    pub w_ndp_in_divisor: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "10..=11", endian = "lsb")]
+    pub w_ndp_in_payload_remainder: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "12..=13", endian = "lsb")]
+    pub w_ndp_in_alignment: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "14..=15", endian = "lsb")]
+    pub w_reserved: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "16..=19", endian = "lsb")]
+    pub dw_ntb_out_max_size: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "20..=21", endian = "lsb")]
+    pub w_ndp_out_divisor: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "22..=23", endian = "lsb")]
+    pub w_ndp_out_payload_remainder: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "24..=25", endian = "lsb")]
+    pub w_ndp_out_alignment: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "26..=27", endian = "lsb")]
+    pub w_ntb_out_max_datagrams: Integer<u16, packed_bits::Bits<16>>,
+}
+
+impl NtbParameters {
+    pub fn new() -> Self {
+        Self {
+            w_length: Integer::from_primitive(28),
+            bm_ntb_formats_supported: Integer::from_primitive(NTB_FORMAT_16),
+            dw_ntb_in_max_size: Integer::from_primitive(DEFAULT_NTB_MAX_SIZE),
+            w_ndp_in_divisor: Integer::from_primitive(ALIGNMENT as u16),
+            w_ndp_in_payload_remainder: Integer::from_primitive(0),
+            w_ndp_in_alignment: Integer::from_primitive(ALIGNMENT as u16),
+            w_reserved: Integer::from_primitive(0),
+            dw_ntb_out_max_size: Integer::from_primitive(DEFAULT_NTB_MAX_SIZE),
+            w_ndp_out_divisor: Integer::from_primitive(ALIGNMENT as u16),
+            w_ndp_out_payload_remainder: Integer::from_primitive(0),
+            w_ndp_out_alignment: Integer::from_primitive(ALIGNMENT as u16),
+            w_ntb_out_max_datagrams: Integer::from_primitive(32),
+        }
+    }
+}
+
+impl Default for NtbParameters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 16-bit NTB header
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "12")]
+pub struct Nth16 {
+    #[packed_field(bytes = "0..=3", endian = "lsb")]
+    pub dw_signature: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "4..=5", endian = "lsb")]
+    pub w_header_length: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "6..=7", endian = "lsb")]
+    pub w_sequence: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "8..=9", endian = "lsb")]
+    pub w_block_length: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "10..=11", endian = "lsb")]
+    pub w_ndp_index: Integer<u16, packed_bits::Bits<16>>,
+}
+
+/// 16-bit datagram pointer table header, followed by pairs of datagram
+/// index and length terminated by a null entry.
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "8")]
+pub struct Ndp16 {
+    #[packed_field(bytes = "0..=3", endian = "lsb")]
+    pub dw_signature: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "4..=5", endian = "lsb")]
+    pub w_length: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "6..=7", endian = "lsb")]
+    pub w_next_ndp_index: Integer<u16, packed_bits::Bits<16>>,
+}
+
+/// Returns the datagrams contained in the given 16-bit NTB sent by the host
+pub fn parse_ntb16(ntb: &[u8]) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let Some(header) = ntb.get(..NTH16_SIZE) else {
+        return Err("NTB is shorter than its header".into());
+    };
+    let nth = Nth16::unpack_from_slice(header)?;
+    if nth.dw_signature.to_primitive() != NTH16_SIGNATURE {
+        return Err("Invalid NTH16 signature".into());
+    }
+    let block_length = match nth.w_block_length.to_primitive() as usize {
+        0 => ntb.len(),
+        length => length.min(ntb.len()),
+    };
+    let ntb = &ntb[..block_length];
+
+    let mut datagrams = Vec::new();
+    let mut ndp_index = nth.w_ndp_index.to_primitive() as usize;
+    for _ in 0..MAX_NDP_COUNT {
+        if ndp_index == 0 {
+            break;
+        }
+        let Some(header) = ntb.get(ndp_index..ndp_index + NDP16_SIZE) else {
+            return Err(format!("NDP16 at {ndp_index} is out of bounds").into());
+        };
+        let ndp = Ndp16::unpack_from_slice(header)?;
+        if ndp.dw_signature.to_primitive() != NDP16_SIGNATURE {
+            return Err(format!("Invalid NDP16 signature at {ndp_index}").into());
+        }
+        let end = ndp_index + ndp.w_length.to_primitive() as usize;
+        let Some(entries) = ntb.get(ndp_index + NDP16_SIZE..end) else {
+            return Err(format!("NDP16 at {ndp_index} is out of bounds").into());
+        };
+
+        for entry in entries.chunks_exact(NDP16_ENTRY_SIZE) {
+            let index = u16::from_le_bytes([entry[0], entry[1]]) as usize;
+            let length = u16::from_le_bytes([entry[2], entry[3]]) as usize;
+            if index == 0 || length == 0 {
+                break;
+            }
+            let Some(datagram) = ntb.get(index..index + length) else {
+                return Err(format!("Datagram at {index} is out of bounds").into());
+            };
+            datagrams.push(datagram.to_vec());
+        }
+
+        ndp_index = ndp.w_next_ndp_index.to_primitive() as usize;
+    }
+
+    Ok(datagrams)
+}
+
+/// Builder for 16-bit NTBs sent to the host. Datagrams are placed after the
+/// header, followed by a single pointer table.
+#[derive(Debug, Clone)]
+pub struct Ntb16Builder {
+    max_size: usize,
+    datagrams: Vec<Vec<u8>>,
+}
+
+impl Ntb16Builder {
+    /// Create a builder for NTBs of at most the given size
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            datagrams: Vec::new(),
+        }
+    }
+
+    /// Add the given datagram to the NTB. Returns false if it does not fit.
+    pub fn push(&mut self, datagram: &[u8]) -> bool {
+        let lengths = self.datagrams.iter().map(|d| d.len());
+        let size = Self::size(lengths.chain([datagram.len()]));
+        if size > self.max_size {
+            return false;
+        }
+        self.datagrams.push(datagram.to_vec());
+        true
+    }
+
+    /// Returns true if no datagrams have been added
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// Serialize the NTB with the given sequence number
+    pub fn build(&self, sequence: u16) -> Vec<u8> {
+        let lengths = self.datagrams.iter().map(|d| d.len());
+        let size = Self::size(lengths);
+        let mut ntb = vec![0; NTH16_SIZE];
+
+        // Datagrams
+        let mut entries = Vec::with_capacity(self.datagrams.len());
+        for datagram in self.datagrams.iter() {
+            ntb.resize(align(ntb.len()), 0);
+            entries.push((ntb.len() as u16, datagram.len() as u16));
+            ntb.extend_from_slice(datagram);
+        }
+
+        // Datagram pointer table
+        ntb.resize(align(ntb.len()), 0);
+        let ndp_index = ntb.len();
+        let ndp = Ndp16 {
+            dw_signature: Integer::from_primitive(NDP16_SIGNATURE),
+            w_length: Integer::from_primitive(Self::ndp_size(entries.len()) as u16),
+            w_next_ndp_index: Integer::from_primitive(0),
+        };
+        ntb.extend_from_slice(&ndp.pack().unwrap_or_default());
+        for (index, length) in entries {
+            ntb.extend_from_slice(&index.to_le_bytes());
+            ntb.extend_from_slice(&length.to_le_bytes());
+        }
+        ntb.extend_from_slice(&[0; NDP16_ENTRY_SIZE]);
+
+        // Header
+        let nth = Nth16 {
+            dw_signature: Integer::from_primitive(NTH16_SIGNATURE),
+            w_header_length: Integer::from_primitive(NTH16_SIZE as u16),
+            w_sequence: Integer::from_primitive(sequence),
+            w_block_length: Integer::from_primitive(size as u16),
+            w_ndp_index: Integer::from_primitive(ndp_index as u16),
+        };
+        ntb[..NTH16_SIZE].copy_from_slice(&nth.pack().unwrap_or_default());
+
+        ntb
+    }
+
+    /// Size of an NTB holding datagrams with the given lengths
+    fn size(lengths: impl Iterator<Item = usize>) -> usize {
+        let mut size = NTH16_SIZE;
+        let mut count = 0;
+        for length in lengths {
+            size = align(size) + length;
+            count += 1;
+        }
+        align(size) + Self::ndp_size(count)
+    }
+
+    /// Size of a pointer table with the given number of datagrams, including
+    /// the null entry
+    fn ndp_size(count: usize) -> usize {
+        NDP16_SIZE + NDP16_ENTRY_SIZE * (count + 1)
+    }
+}
+
+/// Round the given offset up to the NTB alignment
+fn align(offset: usize) -> usize {
+    offset.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nth16(block_length: u16, ndp_index: u16) -> Vec<u8> {
+        let nth = Nth16 {
+            dw_signature: Integer::from_primitive(NTH16_SIGNATURE),
+            w_header_length: Integer::from_primitive(NTH16_SIZE as u16),
+            w_sequence: Integer::from_primitive(0),
+            w_block_length: Integer::from_primitive(block_length),
+            w_ndp_index: Integer::from_primitive(ndp_index),
+        };
+        nth.pack().unwrap().to_vec()
+    }
+
+    fn ndp16(entries: &[(u16, u16)], next_ndp_index: u16) -> Vec<u8> {
+        let ndp = Ndp16 {
+            dw_signature: Integer::from_primitive(NDP16_SIGNATURE),
+            w_length: Integer::from_primitive((NDP16_SIZE + 4 * (entries.len() + 1)) as u16),
+            w_next_ndp_index: Integer::from_primitive(next_ndp_index),
+        };
+        let mut data = ndp.pack().unwrap().to_vec();
+        for (index, length) in entries.iter().chain([&(0, 0)]) {
+            data.extend_from_slice(&index.to_le_bytes());
+            data.extend_from_slice(&length.to_le_bytes());
+        }
+        data
+    }
+
+    /// NTB with two pointer tables, the first placed before the datagrams
+    fn ntb() -> Vec<u8> {
+        let mut ntb = nth16(66, 12);
+        ntb.extend(ndp16(&[(32, 5), (40, 3)], 48));
+        ntb.extend_from_slice(b"hello\0\0\0abc\0\0\0\0\0");
+        ntb.extend(ndp16(&[(64, 2)], 0));
+        ntb.extend_from_slice(b"hi");
+        ntb
+    }
+
+    #[test]
+    fn parse_datagrams() {
+        let ntb = ntb();
+        assert_eq!(ntb.len(), 66);
+        let datagrams = parse_ntb16(&ntb).unwrap();
+        assert_eq!(datagrams, [&b"hello"[..], b"abc", b"hi"]);
+
+        // A zero block length covers the whole transfer
+        let mut ntb = ntb;
+        ntb[8..10].copy_from_slice(&0u16.to_le_bytes());
+        assert_eq!(parse_ntb16(&ntb).unwrap().len(), 3);
+
+        // NTBs without pointer tables are empty
+        assert!(parse_ntb16(&nth16(12, 0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_built_ntb() {
+        let mut builder = Ntb16Builder::new(DEFAULT_NTB_MAX_SIZE as usize);
+        assert!(builder.push(b"first"));
+        assert!(builder.push(&[0x55; 1514]));
+        let datagrams = parse_ntb16(&builder.build(7)).unwrap();
+        assert_eq!(datagrams, [b"first".to_vec(), vec![0x55; 1514]]);
+    }
+
+    #[test]
+    fn reject_bad_signature() {
+        let mut ntb = ntb();
+        ntb[0] = b'X';
+        assert!(parse_ntb16(&ntb).is_err());
+
+        let mut ntb = self::ntb();
+        ntb[48] = b'X';
+        assert!(parse_ntb16(&ntb).is_err());
+
+        assert!(parse_ntb16(&ntb[..NTH16_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn reject_out_of_bounds_indexes() {
+        // Pointer table past the end of the block
+        let mut ntb = ntb();
+        ntb[10..12].copy_from_slice(&70u16.to_le_bytes());
+        assert!(parse_ntb16(&ntb).is_err());
+
+        // Pointer table entries past the end of the block
+        let mut ntb = self::ntb();
+        ntb[52..54].copy_from_slice(&32u16.to_le_bytes());
+        assert!(parse_ntb16(&ntb).is_err());
+
+        // Pointer table shorter than its header
+        let mut ntb = self::ntb();
+        ntb[16..18].copy_from_slice(&4u16.to_le_bytes());
+        assert!(parse_ntb16(&ntb).is_err());
+
+        // Datagram past the end of the block
+        let mut ntb = self::ntb();
+        ntb[20..22].copy_from_slice(&64u16.to_le_bytes());
+        ntb[22..24].copy_from_slice(&8u16.to_le_bytes());
+        assert!(parse_ntb16(&ntb).is_err());
+
+        // Datagram within the transfer but past the block length
+        let mut ntb = self::ntb();
+        ntb[8..10].copy_from_slice(&65u16.to_le_bytes());
+        assert!(parse_ntb16(&ntb).is_err());
+    }
+}
@@ -0,0 +1,701 @@
+//! RNDIS (Remote Network Driver Interface Specification)
+//! https://learn.microsoft.com/en-us/windows-hardware/drivers/network/remote-ndis--rndis-2
+//!
+//! RNDIS functions use a CDC communication interface with a vendor specific
+//! protocol. The host sends control messages with SEND_ENCAPSULATED_COMMAND,
+//! is notified with RESPONSE_AVAILABLE on the interrupt endpoint, and reads
+//! the reply with GET_ENCAPSULATED_RESPONSE. Ethernet frames are wrapped in
+//! packet messages on the bulk endpoints.
+
+use std::{collections::VecDeque, error::Error};
+
+use packed_struct::prelude::*;
+
+/// RESPONSE_AVAILABLE notification sent on the interrupt endpoint
+pub const RESPONSE_AVAILABLE: [u8; 8] = [0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+/// Size of a packet message header
+pub const PACKET_HEADER_SIZE: usize = 44;
+/// Maximum size of a transfer: one packet message with a full Ethernet frame
+pub const MAX_TRANSFER_SIZE: u32 = PACKET_HEADER_SIZE as u32 + 1514;
+
+/// RNDIS message types
+#[derive(PrimitiveEnum_u32, Debug, Copy, Clone, PartialEq)]
+#[repr(u32)]
+pub enum RndisMessageType {
+    Packet = 0x0000_0001,
+    Initialize = 0x0000_0002,
+    Halt = 0x0000_0003,
+    Query = 0x0000_0004,
+    Set = 0x0000_0005,
+    Reset = 0x0000_0006,
+    IndicateStatus = 0x0000_0007,
+    KeepAlive = 0x0000_0008,
+    InitializeComplete = 0x8000_0002,
+    QueryComplete = 0x8000_0004,
+    SetComplete = 0x8000_0005,
+    ResetComplete = 0x8000_0006,
+    KeepAliveComplete = 0x8000_0008,
+}
+
+/// RNDIS status values
+pub mod status {
+    pub const SUCCESS: u32 = 0x0000_0000;
+    pub const FAILURE: u32 = 0xc000_0001;
+    pub const INVALID_DATA: u32 = 0xc001_0015;
+    pub const NOT_SUPPORTED: u32 = 0xc000_00bb;
+    pub const MEDIA_CONNECT: u32 = 0x4001_000b;
+    pub const MEDIA_DISCONNECT: u32 = 0x4001_000c;
+}
+
+/// NDIS object identifiers supported by QUERY and SET messages
+pub mod oid {
+    pub const GEN_SUPPORTED_LIST: u32 = 0x0001_0101;
+    pub const GEN_HARDWARE_STATUS: u32 = 0x0001_0102;
+    pub const GEN_MEDIA_SUPPORTED: u32 = 0x0001_0103;
+    pub const GEN_MEDIA_IN_USE: u32 = 0x0001_0104;
+    pub const GEN_MAXIMUM_FRAME_SIZE: u32 = 0x0001_0106;
+    pub const GEN_LINK_SPEED: u32 = 0x0001_0107;
+    pub const GEN_TRANSMIT_BLOCK_SIZE: u32 = 0x0001_010a;
+    pub const GEN_RECEIVE_BLOCK_SIZE: u32 = 0x0001_010b;
+    pub const GEN_VENDOR_ID: u32 = 0x0001_010c;
+    pub const GEN_VENDOR_DESCRIPTION: u32 = 0x0001_010d;
+    pub const GEN_CURRENT_PACKET_FILTER: u32 = 0x0001_010e;
+    pub const GEN_MAXIMUM_TOTAL_SIZE: u32 = 0x0001_0111;
+    pub const GEN_MEDIA_CONNECT_STATUS: u32 = 0x0001_0114;
+    pub const GEN_PHYSICAL_MEDIUM: u32 = 0x0001_0202;
+    pub const GEN_XMIT_OK: u32 = 0x0002_0101;
+    pub const GEN_RCV_OK: u32 = 0x0002_0102;
+    pub const GEN_XMIT_ERROR: u32 = 0x0002_0103;
+    pub const GEN_RCV_ERROR: u32 = 0x0002_0104;
+    pub const GEN_RCV_NO_BUFFER: u32 = 0x0002_0105;
+    pub const ETHERNET_PERMANENT_ADDRESS: u32 = 0x0101_0101;
+    pub const ETHERNET_CURRENT_ADDRESS: u32 = 0x0101_0102;
+    pub const ETHERNET_MULTICAST_LIST: u32 = 0x0101_0103;
+    pub const ETHERNET_MAXIMUM_LIST_SIZE: u32 = 0x0101_0104;
+    pub const ETHERNET_RCV_ERROR_ALIGNMENT: u32 = 0x0102_0101;
+    pub const ETHERNET_XMIT_ONE_COLLISION: u32 = 0x0102_0102;
+    pub const ETHERNET_XMIT_MORE_COLLISIONS: u32 = 0x0102_0103;
+}
+
+/// OIDs answered by [RndisFunction::query]
+const SUPPORTED_OIDS: [u32; 26] = [
+    oid::GEN_SUPPORTED_LIST,
+    oid::GEN_HARDWARE_STATUS,
+    oid::GEN_MEDIA_SUPPORTED,
+    oid::GEN_MEDIA_IN_USE,
+    oid::GEN_MAXIMUM_FRAME_SIZE,
+    oid::GEN_LINK_SPEED,
+    oid::GEN_TRANSMIT_BLOCK_SIZE,
+    oid::GEN_RECEIVE_BLOCK_SIZE,
+    oid::GEN_VENDOR_ID,
+    oid::GEN_VENDOR_DESCRIPTION,
+    oid::GEN_CURRENT_PACKET_FILTER,
+    oid::GEN_MAXIMUM_TOTAL_SIZE,
+    oid::GEN_MEDIA_CONNECT_STATUS,
+    oid::GEN_PHYSICAL_MEDIUM,
+    oid::GEN_XMIT_OK,
+    oid::GEN_RCV_OK,
+    oid::GEN_XMIT_ERROR,
+    oid::GEN_RCV_ERROR,
+    oid::GEN_RCV_NO_BUFFER,
+    oid::ETHERNET_PERMANENT_ADDRESS,
+    oid::ETHERNET_CURRENT_ADDRESS,
+    oid::ETHERNET_MULTICAST_LIST,
+    oid::ETHERNET_MAXIMUM_LIST_SIZE,
+    oid::ETHERNET_RCV_ERROR_ALIGNMENT,
+    oid::ETHERNET_XMIT_ONE_COLLISION,
+    oid::ETHERNET_XMIT_MORE_COLLISIONS,
+];
+
+/// Maximum Ethernet payload size
+const MAXIMUM_FRAME_SIZE: u32 = 1500;
+/// Link speed in units of 100 bits per second (100 Mbit/s)
+const LINK_SPEED: u32 = 1_000_000;
+
+/// State of the RNDIS control state machine
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RndisState {
+    /// Waiting for an INITIALIZE message
+    Uninitialized,
+    /// Initialized, but the host has not set a packet filter yet
+    Initialized,
+    /// The host set a packet filter and exchanges packets
+    DataInitialized,
+}
+
+/// Header common to all RNDIS control messages
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "12")]
+pub struct RndisMessageHeader {
+    #[packed_field(bytes = "0..=3", endian = "lsb")]
+    pub message_type: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "4..=7", endian = "lsb")]
+    pub message_length: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "8..=11", endian = "lsb")]
+    pub request_id: Integer<u32, packed_bits::Bits<32>>,
+}
+
+/// QUERY and SET request. The information buffer offset is relative to the
+/// request ID field.
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "28")]
+pub struct RndisOidRequest {
+    #[packed_field(bytes = "0..=3", endian = "lsb")]
+    pub message_type: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "4..=7", endian = "lsb")]
+    pub message_length: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "8..=11", endian = "lsb")]
+    pub request_id: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "12..=15", endian = "lsb")]
+    pub oid: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "16..=19", endian = "lsb")]
+    pub information_buffer_length: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "20..=23", endian = "lsb")]
+    pub information_buffer_offset: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "24..=27", endian = "lsb")]
+    pub device_vc_handle: Integer<u32, packed_bits::Bits<32>>,
+}
+
+/// INITIALIZE_CMPLT message
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "52")]
+pub struct RndisInitializeComplete {
+    #[packed_field(bytes = "0..=3", endian = "lsb")]
+    pub message_type: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "4..=7", endian = "lsb")]
+    pub message_length: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "8..=11", endian = "lsb")]
+    pub request_id: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "12..=15", endian = "lsb")]
+    pub status: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "16..=19", endian = "lsb")]
+    pub major_version: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "20..=23", endian = "lsb")]
+    pub minor_version: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "24..=27", endian = "lsb")]
+    pub device_flags: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "28..=31", endian = "lsb")]
+    pub medium: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "32..=35", endian = "lsb")]
+    pub max_packets_per_transfer: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "36..=39", endian = "lsb")]
+    pub max_transfer_size: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "40..=43", endian = "lsb")]
+    pub packet_alignment_factor: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "44..=47", endian = "lsb")]
+    pub af_list_offset: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "48..=51", endian = "lsb")]
+    pub af_list_size: Integer<u32, packed_bits::Bits<32>>,
+}
+
+/// Header of a packet message wrapping an Ethernet frame. Offsets are
+/// relative to the data offset field.
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "44")]
+pub struct RndisPacketHeader {
+    #[packed_field(bytes = "0..=3", endian = "lsb")]
+    pub message_type: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "4..=7", endian = "lsb")]
+    pub message_length: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "8..=11", endian = "lsb")]
+    pub data_offset: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "12..=15", endian = "lsb")]
+    pub data_length: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "16..=19", endian = "lsb")]
+    pub oob_data_offset: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "20..=23", endian = "lsb")]
+    pub oob_data_length: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "24..=27", endian = "lsb")]
+    pub num_oob_data_elements: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "28..=31", endian = "lsb")]
+    pub per_packet_info_offset: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "32..=35", endian = "lsb")]
+    pub per_packet_info_length: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "36..=39", endian = "lsb")]
+    pub vc_handle: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "40..=43", endian = "lsb")]
+    pub reserved: Integer<u32, packed_bits::Bits<32>>,
+}
+
+/// RNDIS control state machine of a CDC communication interface
+#[derive(Debug, Clone)]
+pub struct RndisFunction {
+    pub state: RndisState,
+    /// MAC address used by the host for its network interface
+    pub mac_address: [u8; 6],
+    /// NDIS packet filter set by the host
+    pub packet_filter: u32,
+    /// Whether the medium is reported as connected
+    pub connected: bool,
+    /// Vendor description reported to the host
+    pub vendor_description: String,
+    /// Responses waiting to be read with GET_ENCAPSULATED_RESPONSE
+    responses: VecDeque<Vec<u8>>,
+}
+
+impl RndisFunction {
+    pub fn new(mac_address: [u8; 6]) -> Self {
+        Self {
+            state: RndisState::Uninitialized,
+            mac_address,
+            packet_filter: 0,
+            connected: true,
+            vendor_description: "Virtual RNDIS".to_string(),
+            responses: VecDeque::new(),
+        }
+    }
+
+    /// Handle a control message sent with SEND_ENCAPSULATED_COMMAND. Returns
+    /// true if a response was queued, in which case the host must be sent
+    /// the [RESPONSE_AVAILABLE] notification.
+    pub fn handle_command(&mut self, message: &[u8]) -> Result<bool, Box<dyn Error>> {
+        let Some(header) = message.get(..12) else {
+            return Err("RNDIS message is shorter than its header".into());
+        };
+        let header = RndisMessageHeader::unpack_from_slice(header)?;
+        let message_type = header.message_type.to_primitive();
+        let Some(message_type) = RndisMessageType::from_primitive(message_type) else {
+            return Err(format!("Unknown RNDIS message type {message_type:#x}").into());
+        };
+        let request_id = header.request_id.to_primitive();
+        #[cfg(feature = "log")]
+        log::debug!("RNDIS {message_type:?} request {request_id}");
+
+        let response = match message_type {
+            RndisMessageType::Initialize => {
+                self.state = RndisState::Initialized;
+                self.packet_filter = 0;
+                self.initialize_complete(request_id)?
+            }
+            RndisMessageType::Halt => {
+                self.state = RndisState::Uninitialized;
+                self.responses.clear();
+                return Ok(false);
+            }
+            RndisMessageType::Query => {
+                let request = RndisOidRequest::unpack_from_slice(message.get(..28).unwrap_or(&[]))?;
+                let oid = request.oid.to_primitive();
+                match self.query(oid) {
+                    Some(data) => query_complete(request_id, status::SUCCESS, &data),
+                    None => {
+                        #[cfg(feature = "log")]
+                        log::debug!("RNDIS query for unsupported OID {oid:#x}");
+                        query_complete(request_id, status::NOT_SUPPORTED, &[])
+                    }
+                }
+            }
+            RndisMessageType::Set => {
+                let request = RndisOidRequest::unpack_from_slice(message.get(..28).unwrap_or(&[]))?;
+                let offset = 8 + request.information_buffer_offset.to_primitive() as usize;
+                let length = request.information_buffer_length.to_primitive() as usize;
+                let status = match message.get(offset..offset + length) {
+                    Some(data) => self.set(request.oid.to_primitive(), data),
+                    None => status::INVALID_DATA,
+                };
+                complete(RndisMessageType::SetComplete, &[request_id, status])
+            }
+            RndisMessageType::Reset => {
+                self.packet_filter = 0;
+                if self.state == RndisState::DataInitialized {
+                    self.state = RndisState::Initialized;
+                }
+                self.responses.clear();
+                // Status, AddressingReset
+                complete(RndisMessageType::ResetComplete, &[status::SUCCESS, 0])
+            }
+            RndisMessageType::KeepAlive => complete(
+                RndisMessageType::KeepAliveComplete,
+                &[request_id, status::SUCCESS],
+            ),
+            _ => return Err(format!("Unexpected RNDIS message {message_type:?}").into()),
+        };
+
+        self.responses.push_back(response);
+        Ok(true)
+    }
+
+    /// Returns the next response for GET_ENCAPSULATED_RESPONSE. If there is
+    /// none, a single zero byte is returned as required by the specification.
+    pub fn next_response(&mut self) -> Vec<u8> {
+        self.responses.pop_front().unwrap_or_else(|| vec![0])
+    }
+
+    /// Returns the value of the given OID, or None if it is not supported
+    pub fn query(&self, oid: u32) -> Option<Vec<u8>> {
+        let value = match oid {
+            oid::GEN_SUPPORTED_LIST => {
+                return Some(
+                    SUPPORTED_OIDS
+                        .iter()
+                        .flat_map(|o| o.to_le_bytes())
+                        .collect(),
+                );
+            }
+            oid::GEN_VENDOR_DESCRIPTION => {
+                let mut description = self.vendor_description.as_bytes().to_vec();
+                description.push(0);
+                return Some(description);
+            }
+            oid::ETHERNET_PERMANENT_ADDRESS | oid::ETHERNET_CURRENT_ADDRESS => {
+                return Some(self.mac_address.to_vec());
+            }
+            oid::ETHERNET_MULTICAST_LIST => return Some(Vec::new()),
+            // Ready, 802.3 medium, unspecified physical medium
+            oid::GEN_HARDWARE_STATUS
+            | oid::GEN_MEDIA_SUPPORTED
+            | oid::GEN_MEDIA_IN_USE
+            | oid::GEN_PHYSICAL_MEDIUM => 0,
+            oid::GEN_MAXIMUM_FRAME_SIZE => MAXIMUM_FRAME_SIZE,
+            oid::GEN_LINK_SPEED => LINK_SPEED,
+            oid::GEN_TRANSMIT_BLOCK_SIZE | oid::GEN_RECEIVE_BLOCK_SIZE => MAX_TRANSFER_SIZE,
+            oid::GEN_MAXIMUM_TOTAL_SIZE => MAX_TRANSFER_SIZE,
+            oid::GEN_VENDOR_ID => 0x00ff_ffff,
+            oid::GEN_CURRENT_PACKET_FILTER => self.packet_filter,
+            // 0 = connected, 1 = disconnected
+            oid::GEN_MEDIA_CONNECT_STATUS => !self.connected as u32,
+            oid::ETHERNET_MAXIMUM_LIST_SIZE => 32,
+            // Statistics are not tracked
+            oid::GEN_XMIT_OK
+            | oid::GEN_RCV_OK
+            | oid::GEN_XMIT_ERROR
+            | oid::GEN_RCV_ERROR
+            | oid::GEN_RCV_NO_BUFFER
+            | oid::ETHERNET_RCV_ERROR_ALIGNMENT
+            | oid::ETHERNET_XMIT_ONE_COLLISION
+            | oid::ETHERNET_XMIT_MORE_COLLISIONS => 0,
+            _ => return None,
+        };
+        Some(value.to_le_bytes().to_vec())
+    }
+
+    /// Set the given OID to the given value. Returns the RNDIS status.
+    pub fn set(&mut self, oid: u32, data: &[u8]) -> u32 {
+        match oid {
+            oid::GEN_CURRENT_PACKET_FILTER => {
+                let Some(filter) = data.get(..4) else {
+                    return status::INVALID_DATA;
+                };
+                self.packet_filter =
+                    u32::from_le_bytes([filter[0], filter[1], filter[2], filter[3]]);
+                self.state = match self.packet_filter {
+                    0 => RndisState::Initialized,
+                    _ => RndisState::DataInitialized,
+                };
+                status::SUCCESS
+            }
+            // Multicast frames are not filtered by address
+            oid::ETHERNET_MULTICAST_LIST => status::SUCCESS,
+            _ => {
+                #[cfg(feature = "log")]
+                log::debug!("RNDIS set for unsupported OID {oid:#x}");
+                status::NOT_SUPPORTED
+            }
+        }
+    }
+
+    fn initialize_complete(&self, request_id: u32) -> Result<Vec<u8>, PackingError> {
+        let message = RndisInitializeComplete {
+            message_type: Integer::from_primitive(
+                RndisMessageType::InitializeComplete.to_primitive(),
+            ),
+            message_length: Integer::from_primitive(52),
+            request_id: Integer::from_primitive(request_id),
+            status: Integer::from_primitive(status::SUCCESS),
+            major_version: Integer::from_primitive(1),
+            minor_version: Integer::from_primitive(0),
+            // Connectionless
+            device_flags: Integer::from_primitive(1),
+            // 802.3
+            medium: Integer::from_primitive(0),
+            max_packets_per_transfer: Integer::from_primitive(1),
+            max_transfer_size: Integer::from_primitive(MAX_TRANSFER_SIZE),
+            // 2^0 byte alignment
+            packet_alignment_factor: Integer::from_primitive(0),
+            af_list_offset: Integer::from_primitive(0),
+            af_list_size: Integer::from_primitive(0),
+        };
+        message.pack_to_vec()
+    }
+}
+
+/// Build a completion message with the given fields following the header
+fn complete(message_type: RndisMessageType, fields: &[u32]) -> Vec<u8> {
+    let length = 8 + 4 * fields.len() as u32;
+    let mut message = message_type.to_primitive().to_le_bytes().to_vec();
+    message.extend_from_slice(&length.to_le_bytes());
+    for field in fields {
+        message.extend_from_slice(&field.to_le_bytes());
+    }
+    message
+}
+
+/// Build a QUERY_CMPLT message with the given information buffer
+fn query_complete(request_id: u32, status: u32, data: &[u8]) -> Vec<u8> {
+    // The information buffer follows the 24 byte message, and its offset is
+    // relative to the request ID field
+    let mut message = complete(
+        RndisMessageType::QueryComplete,
+        &[request_id, status, data.len() as u32, 16],
+    );
+    message[4..8].copy_from_slice(&(24 + data.len() as u32).to_le_bytes());
+    message.extend_from_slice(data);
+    message
+}
+
+/// Returns the Ethernet frames of the packet messages in the given bulk
+/// transfer sent by the host
+pub fn parse_packets(data: &[u8]) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = data.get(offset..offset + PACKET_HEADER_SIZE) {
+        let header = RndisPacketHeader::unpack_from_slice(header)?;
+        let message_type = header.message_type.to_primitive();
+        if message_type != RndisMessageType::Packet.to_primitive() {
+            return Err(format!("Unexpected RNDIS message type {message_type:#x}").into());
+        }
+        let start = offset + 8 + header.data_offset.to_primitive() as usize;
+        let end = start + header.data_length.to_primitive() as usize;
+        let Some(frame) = data.get(start..end) else {
+            return Err("RNDIS packet data is out of bounds".into());
+        };
+        frames.push(frame.to_vec());
+
+        let length = header.message_length.to_primitive() as usize;
+        if length == 0 {
+            break;
+        }
+        offset += length;
+    }
+
+    Ok(frames)
+}
+
+/// Wrap the given Ethernet frame in a packet message for the host
+pub fn build_packet(frame: &[u8]) -> Vec<u8> {
+    let zero = Integer::from_primitive(0);
+    let header = RndisPacketHeader {
+        message_type: Integer::from_primitive(RndisMessageType::Packet.to_primitive()),
+        message_length: Integer::from_primitive((PACKET_HEADER_SIZE + frame.len()) as u32),
+        data_offset: Integer::from_primitive(PACKET_HEADER_SIZE as u32 - 8),
+        data_length: Integer::from_primitive(frame.len() as u32),
+        oob_data_offset: zero,
+        oob_data_length: zero,
+        num_oob_data_elements: zero,
+        per_packet_info_offset: zero,
+        per_packet_info_length: zero,
+        vc_handle: zero,
+        reserved: zero,
+    };
+    let mut packet = header.pack().unwrap_or([0; PACKET_HEADER_SIZE]).to_vec();
+    packet.extend_from_slice(frame);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC_ADDRESS: [u8; 6] = [0x02, 0x00, 0xde, 0xad, 0xbe, 0xef];
+
+    /// Build a control message with the given fields following the header
+    fn message(message_type: RndisMessageType, request_id: u32, fields: &[u32]) -> Vec<u8> {
+        let mut message = complete(message_type, &[request_id]);
+        for field in fields {
+            message.extend_from_slice(&field.to_le_bytes());
+        }
+        let length = message.len() as u32;
+        message[4..8].copy_from_slice(&length.to_le_bytes());
+        message
+    }
+
+    /// Build a QUERY or SET message with the given information buffer
+    fn oid_request(message_type: RndisMessageType, oid: u32, data: &[u8]) -> Vec<u8> {
+        // The information buffer follows the 28 byte request
+        let mut request = message(message_type, 7, &[oid, data.len() as u32, 20, 0]);
+        request.extend_from_slice(data);
+        let length = request.len() as u32;
+        request[4..8].copy_from_slice(&length.to_le_bytes());
+        request
+    }
+
+    fn field(message: &[u8], index: usize) -> u32 {
+        let offset = 4 * index;
+        u32::from_le_bytes(message[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn initialized() -> RndisFunction {
+        let mut rndis = RndisFunction::new(MAC_ADDRESS);
+        let initialize = message(RndisMessageType::Initialize, 1, &[1, 0, 0x4000]);
+        assert!(rndis.handle_command(&initialize).unwrap());
+        rndis.next_response();
+        rndis
+    }
+
+    #[test]
+    fn initialize() {
+        let mut rndis = RndisFunction::new(MAC_ADDRESS);
+        let initialize = message(RndisMessageType::Initialize, 1, &[1, 0, 0x4000]);
+        assert!(rndis.handle_command(&initialize).unwrap());
+        assert_eq!(rndis.state, RndisState::Initialized);
+
+        let response = rndis.next_response();
+        assert_eq!(response.len(), 52);
+        let complete = RndisInitializeComplete::unpack_from_slice(&response).unwrap();
+        assert_eq!(
+            complete.message_type.to_primitive(),
+            RndisMessageType::InitializeComplete.to_primitive()
+        );
+        assert_eq!(complete.message_length.to_primitive(), 52);
+        assert_eq!(complete.request_id.to_primitive(), 1);
+        assert_eq!(complete.status.to_primitive(), status::SUCCESS);
+        assert_eq!(complete.max_transfer_size.to_primitive(), MAX_TRANSFER_SIZE);
+
+        // Without a queued response a single zero byte is returned
+        assert_eq!(rndis.next_response(), [0]);
+    }
+
+    #[test]
+    fn query() {
+        let mut rndis = initialized();
+        let query = oid_request(RndisMessageType::Query, oid::GEN_MAXIMUM_FRAME_SIZE, &[]);
+        assert!(rndis.handle_command(&query).unwrap());
+        let response = rndis.next_response();
+        assert_eq!(response.len(), 28);
+        assert_eq!(
+            field(&response, 0),
+            RndisMessageType::QueryComplete.to_primitive()
+        );
+        assert_eq!(field(&response, 1), 28);
+        assert_eq!(field(&response, 2), 7);
+        assert_eq!(field(&response, 3), status::SUCCESS);
+        // Information buffer length and offset from the request ID
+        assert_eq!(field(&response, 4), 4);
+        assert_eq!(field(&response, 5), 16);
+        assert_eq!(field(&response, 6), MAXIMUM_FRAME_SIZE);
+
+        let query = oid_request(RndisMessageType::Query, oid::ETHERNET_CURRENT_ADDRESS, &[]);
+        rndis.handle_command(&query).unwrap();
+        assert_eq!(rndis.next_response()[24..], MAC_ADDRESS);
+
+        let query = oid_request(RndisMessageType::Query, 0x00ff_0000, &[]);
+        rndis.handle_command(&query).unwrap();
+        let response = rndis.next_response();
+        assert_eq!(response.len(), 24);
+        assert_eq!(field(&response, 3), status::NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn set_packet_filter() {
+        let mut rndis = initialized();
+        let filter = 0x0000_000fu32.to_le_bytes();
+        let set = oid_request(
+            RndisMessageType::Set,
+            oid::GEN_CURRENT_PACKET_FILTER,
+            &filter,
+        );
+        assert!(rndis.handle_command(&set).unwrap());
+        let response = rndis.next_response();
+        assert_eq!(response.len(), 16);
+        assert_eq!(
+            field(&response, 0),
+            RndisMessageType::SetComplete.to_primitive()
+        );
+        assert_eq!(field(&response, 2), 7);
+        assert_eq!(field(&response, 3), status::SUCCESS);
+        assert_eq!(rndis.packet_filter, 0x0f);
+        assert_eq!(rndis.state, RndisState::DataInitialized);
+
+        // Clearing the filter stops the data path
+        let set = oid_request(
+            RndisMessageType::Set,
+            oid::GEN_CURRENT_PACKET_FILTER,
+            &[0; 4],
+        );
+        rndis.handle_command(&set).unwrap();
+        rndis.next_response();
+        assert_eq!(rndis.state, RndisState::Initialized);
+
+        let set = oid_request(RndisMessageType::Set, oid::GEN_LINK_SPEED, &[0; 4]);
+        rndis.handle_command(&set).unwrap();
+        assert_eq!(field(&rndis.next_response(), 3), status::NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn keep_alive_reset_and_halt() {
+        let mut rndis = initialized();
+        let keep_alive = message(RndisMessageType::KeepAlive, 9, &[]);
+        assert!(rndis.handle_command(&keep_alive).unwrap());
+        assert_eq!(
+            rndis.next_response(),
+            complete(RndisMessageType::KeepAliveComplete, &[9, status::SUCCESS])
+        );
+
+        rndis.packet_filter = 0x0f;
+        rndis.state = RndisState::DataInitialized;
+        let reset = message(RndisMessageType::Reset, 0, &[]);
+        assert!(rndis.handle_command(&reset).unwrap());
+        assert_eq!(
+            rndis.next_response(),
+            complete(RndisMessageType::ResetComplete, &[status::SUCCESS, 0])
+        );
+        assert_eq!(rndis.packet_filter, 0);
+        assert_eq!(rndis.state, RndisState::Initialized);
+
+        // Halt drops pending responses and is not answered
+        rndis.handle_command(&keep_alive).unwrap();
+        let halt = message(RndisMessageType::Halt, 10, &[]);
+        assert!(!rndis.handle_command(&halt).unwrap());
+        assert_eq!(rndis.state, RndisState::Uninitialized);
+        assert_eq!(rndis.next_response(), [0]);
+    }
+
+    #[test]
+    fn reject_malformed_commands() {
+        let mut rndis = initialized();
+        let keep_alive = message(RndisMessageType::KeepAlive, 9, &[]);
+        assert!(rndis.handle_command(&keep_alive[..8]).is_err());
+
+        let mut unknown = keep_alive.clone();
+        unknown[0..4].copy_from_slice(&0x42u32.to_le_bytes());
+        assert!(rndis.handle_command(&unknown).is_err());
+
+        // Completion messages are only sent by the device
+        let complete = message(RndisMessageType::KeepAliveComplete, 9, &[status::SUCCESS]);
+        assert!(rndis.handle_command(&complete).is_err());
+
+        // OID requests shorter than their fixed fields
+        let query = message(RndisMessageType::Query, 7, &[oid::GEN_LINK_SPEED]);
+        assert!(rndis.handle_command(&query).is_err());
+
+        // Information buffer past the end of the message
+        let mut set = oid_request(RndisMessageType::Set, oid::GEN_CURRENT_PACKET_FILTER, &[]);
+        set[16..20].copy_from_slice(&4u32.to_le_bytes());
+        assert!(rndis.handle_command(&set).unwrap());
+        assert_eq!(field(&rndis.next_response(), 3), status::INVALID_DATA);
+        assert_eq!(rndis.next_response(), [0]);
+    }
+
+    #[test]
+    fn packet_messages() {
+        let first = [0xaa; 60];
+        let second = [0x55; 1514];
+        let mut transfer = build_packet(&first);
+        assert_eq!(transfer.len(), PACKET_HEADER_SIZE + first.len());
+        transfer.extend(build_packet(&second));
+        let frames = parse_packets(&transfer).unwrap();
+        assert_eq!(frames, [first.to_vec(), second.to_vec()]);
+
+        // Data past the end of the transfer
+        assert!(parse_packets(&transfer[..PACKET_HEADER_SIZE + 10]).is_err());
+
+        // Control messages are not accepted on the data path
+        let mut packet = build_packet(&first);
+        packet[0..4].copy_from_slice(&RndisMessageType::KeepAlive.to_primitive().to_le_bytes());
+        assert!(parse_packets(&packet).is_err());
+
+        // Trailing bytes shorter than a header are ignored
+        let mut packet = build_packet(&first);
+        packet.push(0);
+        assert_eq!(parse_packets(&packet).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,673 @@
+//! DFU (Device Firmware Upgrade) class
+//! https://www.usb.org/sites/default/files/DFU_1.1.pdf
+
+use std::{
+    fmt::{Debug, Display},
+    sync::{Arc, Mutex},
+};
+
+use packed_struct::prelude::*;
+
+use super::{EndpointDescriptor, Interface, InterfaceClass, InterfaceDescriptor};
+
+/// DFU interface subclass code (Application Specific class)
+pub const DFU_SUBCLASS: u8 = 0x01;
+/// Descriptor type of the DFU functional descriptor
+pub const DFU_FUNCTIONAL_DESCRIPTOR_TYPE: u8 = 0x21;
+/// Size of the DFU functional descriptor
+pub const DFU_FUNCTIONAL_DESCRIPTOR_SIZE: usize = 9;
+/// Version of the DFU specification (bcdDFUVersion)
+pub const DFU_VERSION: u16 = 0x0110;
+/// Default largest block of a DNLOAD or UPLOAD request (wTransferSize)
+pub const DEFAULT_TRANSFER_SIZE: u16 = 1024;
+/// Default time the host waits for the device to re-enumerate after DETACH,
+/// in milliseconds (wDetachTimeOut)
+pub const DEFAULT_DETACH_TIMEOUT: u16 = 1000;
+
+/// DFU interface protocol codes
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DfuMode {
+    /// The application is running and switches to DFU mode on DETACH
+    Runtime = 0x01,
+    /// The device is in DFU mode and accepts firmware transfers
+    Dfu = 0x02,
+}
+
+/// Bits of the bmAttributes field of the DFU functional descriptor
+pub mod attributes {
+    /// The device accepts DNLOAD requests
+    pub const CAN_DNLOAD: u8 = 0x01;
+    /// The device accepts UPLOAD requests
+    pub const CAN_UPLOAD: u8 = 0x02;
+    /// The device stays in DFU mode after the manifestation phase instead
+    /// of waiting for a reset
+    pub const MANIFESTATION_TOLERANT: u8 = 0x04;
+    /// The device re-enumerates on DETACH without waiting for a reset
+    pub const WILL_DETACH: u8 = 0x08;
+}
+
+/// DFU class requests (bRequest)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum DfuRequest {
+    Detach = 0x00,
+    Dnload = 0x01,
+    Upload = 0x02,
+    GetStatus = 0x03,
+    ClrStatus = 0x04,
+    GetState = 0x05,
+    Abort = 0x06,
+}
+
+/// States of the DFU state machine (bState)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum DfuState {
+    AppIdle = 0,
+    AppDetach = 1,
+    DfuIdle = 2,
+    DnloadSync = 3,
+    DnBusy = 4,
+    DnloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    ManifestWaitReset = 8,
+    UploadIdle = 9,
+    Error = 10,
+}
+
+/// Status codes reported by GETSTATUS (bStatus)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum DfuStatus {
+    Ok = 0x00,
+    /// The file is not targeted for this device
+    ErrTarget = 0x01,
+    /// The file fails a vendor specific verification
+    ErrFile = 0x02,
+    /// Unable to write memory
+    ErrWrite = 0x03,
+    /// Memory erase failed
+    ErrErase = 0x04,
+    /// Memory erase check failed
+    ErrCheckErased = 0x05,
+    /// Program memory failed
+    ErrProg = 0x06,
+    /// Programmed memory failed verification
+    ErrVerify = 0x07,
+    /// The address is out of range
+    ErrAddress = 0x08,
+    /// The download ended while the firmware is incomplete
+    ErrNotDone = 0x09,
+    /// The firmware is corrupt and cannot run
+    ErrFirmware = 0x0a,
+    ErrVendor = 0x0b,
+    /// Unexpected USB reset
+    ErrUsbr = 0x0c,
+    /// Unexpected power on reset
+    ErrPor = 0x0d,
+    ErrUnknown = 0x0e,
+    /// A request was stalled
+    ErrStalledPkt = 0x0f,
+}
+
+/// DFU functional descriptor, following the interface descriptor
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "9")]
+pub struct DfuFunctionalDescriptor {
+    #[packed_field(bytes = "0")]
+    pub b_length: u8,
+    #[packed_field(bytes = "1")]
+    pub b_descriptor_type: u8,
+    /// DFU capabilities (see [attributes])
+    #[packed_field(bytes = "2")]
+    pub bm_attributes: u8,
+    /// Time the host waits for re-enumeration after DETACH, in milliseconds
+    #[packed_field(bytes = "3..=4", endian = "lsb")]
+    pub w_detach_time_out: Integer<u16, packed_bits::Bits<16>>,
+    /// Largest block of a DNLOAD or UPLOAD request
+    #[packed_field(bytes = "5..=6", endian = "lsb")]
+    pub w_transfer_size: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "7..=8", endian = "lsb")]
+    pub bcd_dfu_version: Integer<u16, packed_bits::Bits<16>>,
+}
+
+impl DfuFunctionalDescriptor {
+    /// Create the descriptor of a device that can download and upload
+    /// firmware, and that re-enumerates by itself
+    pub fn new() -> Self {
+        Self {
+            b_length: DFU_FUNCTIONAL_DESCRIPTOR_SIZE as u8,
+            b_descriptor_type: DFU_FUNCTIONAL_DESCRIPTOR_TYPE,
+            bm_attributes: attributes::CAN_DNLOAD
+                | attributes::CAN_UPLOAD
+                | attributes::MANIFESTATION_TOLERANT
+                | attributes::WILL_DETACH,
+            w_detach_time_out: Integer::from_primitive(DEFAULT_DETACH_TIMEOUT),
+            w_transfer_size: Integer::from_primitive(DEFAULT_TRANSFER_SIZE),
+            bcd_dfu_version: Integer::from_primitive(DFU_VERSION),
+        }
+    }
+}
+
+impl Default for DfuFunctionalDescriptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Storage receiving the firmware downloaded by the host, and providing the
+/// firmware uploaded to the host. Errors are reported to the host with
+/// GETSTATUS.
+pub trait FirmwareSink {
+    /// Called when a download starts, before its first block is written
+    fn begin(&mut self) -> Result<(), DfuStatus> {
+        Ok(())
+    }
+    /// Write a block of the firmware at the given offset
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), DfuStatus>;
+    /// Called once all blocks were written, to verify and install the
+    /// firmware
+    fn manifest(&mut self) -> Result<(), DfuStatus> {
+        Ok(())
+    }
+    /// Called when the host aborts a download
+    fn abort(&mut self) {}
+    /// Read up to `len` bytes of the firmware at the given offset for an
+    /// upload. Returning fewer bytes ends the upload.
+    fn read(&mut self, _offset: usize, _len: usize) -> Result<Vec<u8>, DfuStatus> {
+        Err(DfuStatus::ErrTarget)
+    }
+}
+
+/// Firmware sink shared between a DFU interface and the code emulating the
+/// device
+pub type SharedFirmwareSink = Arc<Mutex<dyn FirmwareSink + Send>>;
+
+/// Events of a DFU interface
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DfuEvent {
+    /// The host asked the application to switch to DFU mode, within the
+    /// given timeout in milliseconds
+    Detach { timeout: u16 },
+    /// The host started downloading firmware
+    DownloadStarted,
+    /// The host aborted the download
+    DownloadAborted,
+    /// The downloaded firmware of the given size was installed. Devices
+    /// that are not manifestation tolerant now wait for a reset.
+    Manifested { size: usize },
+}
+
+/// Callback called with the events of a DFU interface
+pub type DfuEventHandler = Arc<Mutex<dyn FnMut(DfuEvent) + Send>>;
+
+/// Event handlers of a DFU interface
+#[derive(Clone, Default)]
+pub struct DfuEventHandlers(Vec<DfuEventHandler>);
+
+impl DfuEventHandlers {
+    /// Dispatch the given event to all handlers
+    pub fn dispatch(&self, event: DfuEvent) {
+        for handler in self.0.iter() {
+            if let Ok(mut handler) = handler.lock() {
+                handler(event);
+            }
+        }
+    }
+}
+
+impl Debug for DfuEventHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DfuEventHandlers")
+            .field("count", &self.0.len())
+            .finish()
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct MachineState {
+    state: DfuState,
+    status: DfuStatus,
+    /// Offset of the next block to download or upload
+    offset: usize,
+    /// Whether the manifestation phase of the current download is complete
+    manifested: bool,
+}
+
+/// State machine of a DFU interface, shared between the interface and the
+/// code emulating the device
+#[derive(Debug, Clone)]
+pub struct DfuMachine(Arc<Mutex<MachineState>>);
+
+impl DfuMachine {
+    /// Create the state machine of an interface in the given mode
+    pub fn new(mode: DfuMode) -> Self {
+        let state = match mode {
+            DfuMode::Runtime => DfuState::AppIdle,
+            DfuMode::Dfu => DfuState::DfuIdle,
+        };
+        Self(Arc::new(Mutex::new(MachineState {
+            state,
+            status: DfuStatus::Ok,
+            offset: 0,
+            manifested: false,
+        })))
+    }
+
+    /// Returns the current state
+    pub fn state(&self) -> DfuState {
+        self.0
+            .lock()
+            .map(|machine| machine.state)
+            .unwrap_or(DfuState::Error)
+    }
+
+    /// Returns the status reported by GETSTATUS
+    pub fn status(&self) -> DfuStatus {
+        self.0
+            .lock()
+            .map(|machine| machine.status)
+            .unwrap_or(DfuStatus::ErrUnknown)
+    }
+}
+
+/// DFU interface definition, in runtime or DFU mode
+#[derive(Clone)]
+pub struct DfuInterface {
+    pub iface: InterfaceDescriptor,
+    pub functional_descriptor: DfuFunctionalDescriptor,
+    pub machine: DfuMachine,
+    pub firmware: Option<SharedFirmwareSink>,
+    pub handlers: DfuEventHandlers,
+}
+
+impl DfuInterface {
+    pub fn new() -> Self {
+        let iface = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            b_interface_class: InterfaceClass::ApplicationSpecific,
+            b_interface_subclass: DFU_SUBCLASS,
+            b_interface_protocol: DfuMode::Runtime as u8,
+            ..InterfaceDescriptor::new()
+        };
+
+        Self {
+            iface,
+            functional_descriptor: DfuFunctionalDescriptor::new(),
+            machine: DfuMachine::new(DfuMode::Runtime),
+            firmware: None,
+            handlers: DfuEventHandlers::default(),
+        }
+    }
+
+    /// Returns the mode of the interface
+    pub fn mode(&self) -> DfuMode {
+        match self.iface.b_interface_protocol {
+            2 => DfuMode::Dfu,
+            _ => DfuMode::Runtime,
+        }
+    }
+
+    /// Returns true if the functional descriptor has the given attribute
+    /// (see [attributes])
+    pub fn has_attribute(&self, attribute: u8) -> bool {
+        self.functional_descriptor.bm_attributes & attribute != 0
+    }
+
+    /// Register a handler for the events of the interface
+    pub fn on_event<F>(&mut self, handler: F)
+    where
+        F: FnMut(DfuEvent) + Send + 'static,
+    {
+        let handler: DfuEventHandler = Arc::new(Mutex::new(handler));
+        self.handlers.0.push(handler);
+    }
+
+    /// Handle a DFU request with the given wValue, data and wLength. Returns
+    /// the data of the reply, or None if the request must be stalled.
+    pub fn handle_request(
+        &self,
+        request: DfuRequest,
+        value: u16,
+        data: &[u8],
+        length: u16,
+    ) -> Option<Vec<u8>> {
+        let Ok(mut machine) = self.machine.0.lock() else {
+            return None;
+        };
+        let mut event = None;
+        let reply = match self.mode() {
+            DfuMode::Runtime => match request {
+                DfuRequest::Detach => {
+                    machine.state = DfuState::AppDetach;
+                    event = Some(DfuEvent::Detach { timeout: value });
+                    Some(Vec::new())
+                }
+                DfuRequest::GetStatus => Some(status_reply(&machine, machine.state)),
+                DfuRequest::GetState => Some(vec![machine.state as u8]),
+                _ => None,
+            },
+            DfuMode::Dfu => {
+                self.handle_dfu_request(&mut machine, &mut event, request, data, length)
+            }
+        };
+        drop(machine);
+
+        if let Some(event) = event {
+            self.handlers.dispatch(event);
+        }
+        reply
+    }
+
+    /// Run the DFU mode state machine
+    fn handle_dfu_request(
+        &self,
+        machine: &mut MachineState,
+        event: &mut Option<DfuEvent>,
+        request: DfuRequest,
+        data: &[u8],
+        length: u16,
+    ) -> Option<Vec<u8>> {
+        use DfuState::*;
+
+        let reply = match (request, machine.state) {
+            (DfuRequest::GetStatus, _) => Some(self.get_status(machine, event)),
+            (DfuRequest::GetState, _) => Some(vec![machine.state as u8]),
+            (DfuRequest::ClrStatus, Error) => {
+                machine.state = DfuIdle;
+                machine.status = DfuStatus::Ok;
+                Some(Vec::new())
+            }
+            (DfuRequest::Abort, DfuIdle | DnloadSync | DnloadIdle | ManifestSync | UploadIdle) => {
+                if matches!(machine.state, DnloadSync | DnloadIdle | ManifestSync) {
+                    let _ = self.with_firmware(|firmware| {
+                        firmware.abort();
+                        Ok(())
+                    });
+                    *event = Some(DfuEvent::DownloadAborted);
+                }
+                machine.state = DfuIdle;
+                machine.offset = 0;
+                Some(Vec::new())
+            }
+            (DfuRequest::Dnload, DfuIdle | DnloadIdle)
+                if self.has_attribute(attributes::CAN_DNLOAD) =>
+            {
+                self.download(machine, event, data)
+            }
+            (DfuRequest::Upload, DfuIdle | UploadIdle)
+                if self.has_attribute(attributes::CAN_UPLOAD) =>
+            {
+                self.upload(machine, length as usize)
+            }
+            _ => None,
+        };
+
+        // Requests that are invalid in the current state are stalled and
+        // move to the error state
+        if reply.is_none() && machine.state != Error {
+            machine.state = Error;
+            machine.status = DfuStatus::ErrStalledPkt;
+        }
+        reply
+    }
+
+    /// Handle DNLOAD, where a block of zero length ends the download
+    fn download(
+        &self,
+        machine: &mut MachineState,
+        event: &mut Option<DfuEvent>,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        if data.is_empty() {
+            if machine.state == DfuState::DfuIdle {
+                return None;
+            }
+            machine.state = DfuState::ManifestSync;
+            machine.manifested = false;
+            return Some(Vec::new());
+        }
+
+        if machine.state == DfuState::DfuIdle {
+            machine.offset = 0;
+            *event = Some(DfuEvent::DownloadStarted);
+            if let Err(status) = self.with_firmware(|firmware| firmware.begin()) {
+                machine.fail(status);
+                return Some(Vec::new());
+            }
+        }
+
+        // Errors are reported by the next GETSTATUS
+        let offset = machine.offset;
+        match self.with_firmware(|firmware| firmware.write(offset, data)) {
+            Ok(()) => {
+                machine.offset += data.len();
+                machine.state = DfuState::DnloadSync;
+            }
+            Err(status) => machine.fail(status),
+        }
+        Some(Vec::new())
+    }
+
+    /// Handle UPLOAD, where a block shorter than requested ends the upload
+    fn upload(&self, machine: &mut MachineState, length: usize) -> Option<Vec<u8>> {
+        let transfer_size = self.functional_descriptor.w_transfer_size.to_primitive();
+        let length = length.min(transfer_size as usize);
+        if machine.state == DfuState::DfuIdle {
+            machine.offset = 0;
+        }
+
+        let offset = machine.offset;
+        let mut block = match self.with_firmware(|firmware| firmware.read(offset, length)) {
+            Ok(block) => block,
+            Err(status) => {
+                machine.fail(status);
+                return None;
+            }
+        };
+        block.truncate(length);
+        if block.len() < length {
+            machine.state = DfuState::DfuIdle;
+            machine.offset = 0;
+        } else {
+            machine.state = DfuState::UploadIdle;
+            machine.offset += block.len();
+        }
+        Some(block)
+    }
+
+    /// Handle GETSTATUS, which completes the pending download step
+    fn get_status(&self, machine: &mut MachineState, event: &mut Option<DfuEvent>) -> Vec<u8> {
+        match machine.state {
+            DfuState::DnloadSync => {
+                machine.state = DfuState::DnloadIdle;
+                status_reply(machine, machine.state)
+            }
+            // Install the firmware, then report the end of the
+            // manifestation phase with the next GETSTATUS
+            DfuState::ManifestSync if !machine.manifested => {
+                if let Err(status) = self.with_firmware(|firmware| firmware.manifest()) {
+                    machine.fail(status);
+                    return status_reply(machine, machine.state);
+                }
+                machine.manifested = true;
+                *event = Some(DfuEvent::Manifested {
+                    size: machine.offset,
+                });
+                machine.state = match self.has_attribute(attributes::MANIFESTATION_TOLERANT) {
+                    true => DfuState::ManifestSync,
+                    false => DfuState::ManifestWaitReset,
+                };
+                status_reply(machine, DfuState::Manifest)
+            }
+            DfuState::ManifestSync => {
+                machine.state = DfuState::DfuIdle;
+                machine.manifested = false;
+                machine.offset = 0;
+                status_reply(machine, machine.state)
+            }
+            state => status_reply(machine, state),
+        }
+    }
+
+    /// Call the given function with the firmware sink, failing with
+    /// errTARGET if there is none
+    fn with_firmware<T, F>(&self, f: F) -> Result<T, DfuStatus>
+    where
+        F: FnOnce(&mut (dyn FirmwareSink + Send)) -> Result<T, DfuStatus>,
+    {
+        let Some(firmware) = self.firmware.as_ref() else {
+            return Err(DfuStatus::ErrTarget);
+        };
+        let Ok(mut firmware) = firmware.lock() else {
+            return Err(DfuStatus::ErrUnknown);
+        };
+        f(&mut *firmware)
+    }
+
+    /// Serialize the interface into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.get_size());
+        result.append(&mut self.iface.pack_to_vec()?);
+        result.append(&mut self.functional_descriptor.pack_to_vec()?);
+
+        Ok(result)
+    }
+
+    /// Returns the byte serialized size of the interface
+    pub fn get_size(&self) -> usize {
+        9 + DFU_FUNCTIONAL_DESCRIPTOR_SIZE
+    }
+
+    /// Returns the interface class
+    pub fn get_class(&self) -> InterfaceClass {
+        self.iface.b_interface_class
+    }
+
+    /// Set the interface number for this interface
+    pub fn set_interface_number(&mut self, num: u8) {
+        self.iface.b_interface_number = num;
+    }
+
+    /// Returns the endpoint descriptors of the interface, which has none
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        &[]
+    }
+}
+
+impl MachineState {
+    /// Move to the error state with the given status
+    fn fail(&mut self, status: DfuStatus) {
+        #[cfg(feature = "log")]
+        log::debug!("DFU error: {status:?}");
+        self.state = DfuState::Error;
+        self.status = status;
+    }
+}
+
+/// Returns the reply to GETSTATUS: bStatus, bwPollTimeout, bState and
+/// iString. The host can poll again immediately.
+fn status_reply(machine: &MachineState, state: DfuState) -> Vec<u8> {
+    vec![machine.status as u8, 0, 0, 0, state as u8, 0]
+}
+
+impl Display for DfuInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = [
+            format!("{}", self.iface),
+            format!("{}", self.functional_descriptor),
+        ];
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+impl Debug for DfuInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DfuInterface")
+            .field("iface", &self.iface)
+            .field("functional_descriptor", &self.functional_descriptor)
+            .field("machine", &self.machine)
+            .field("firmware", &self.firmware.is_some())
+            .field("handlers", &self.handlers)
+            .finish()
+    }
+}
+
+impl Default for DfuInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [Interface] builder for constructing a DFU interface.
+pub struct DfuInterfaceBuilder {
+    iface: DfuInterface,
+}
+
+impl DfuInterfaceBuilder {
+    pub fn new() -> Self {
+        Self {
+            iface: DfuInterface::default(),
+        }
+    }
+
+    /// Construct the new Interface configuration.
+    pub fn build(&self) -> Interface {
+        #[cfg(feature = "log")]
+        log::debug!("DFU Interface: {}", self.iface);
+        Interface::Dfu(self.iface.clone())
+    }
+
+    /// Set the mode of the interface. Devices switch from the runtime mode
+    /// interface to a DFU mode interface on DETACH.
+    pub fn mode(&mut self, mode: DfuMode) -> &mut Self {
+        self.iface.iface.b_interface_protocol = mode as u8;
+        self.iface.machine = DfuMachine::new(mode);
+        self
+    }
+
+    /// Set the capabilities of the interface (see [attributes])
+    pub fn attributes(&mut self, attributes: u8) -> &mut Self {
+        self.iface.functional_descriptor.bm_attributes = attributes;
+        self
+    }
+
+    /// Set the time the host waits for re-enumeration after DETACH, in
+    /// milliseconds
+    pub fn detach_timeout(&mut self, timeout: u16) -> &mut Self {
+        self.iface.functional_descriptor.w_detach_time_out = Integer::from_primitive(timeout);
+        self
+    }
+
+    /// Set the largest block of a DNLOAD or UPLOAD request
+    pub fn transfer_size(&mut self, size: u16) -> &mut Self {
+        self.iface.functional_descriptor.w_transfer_size = Integer::from_primitive(size);
+        self
+    }
+
+    /// Set the firmware sink of a DFU mode interface
+    pub fn firmware(&mut self, firmware: SharedFirmwareSink) -> &mut Self {
+        self.iface.firmware = Some(firmware);
+        self
+    }
+
+    /// Set the alternate setting of the interface. DFU mode interfaces can
+    /// have one alternate setting per memory region.
+    pub fn alternate_setting(&mut self, alt: u8) -> &mut Self {
+        self.iface.iface.b_alternate_setting = alt;
+        self
+    }
+
+    /// Handle the events of the interface. See [DfuInterface::on_event].
+    pub fn on_event<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: FnMut(DfuEvent) + Send + 'static,
+    {
+        self.iface.on_event(handler);
+        self
+    }
+}
+
+impl Default for DfuInterfaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
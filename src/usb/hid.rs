@@ -1,10 +1,20 @@
 //! HID (Human Interface Device)
 //! https://www.usb.org/sites/default/files/hid1_11.pdf
 
-use std::fmt::Display;
+pub mod parser;
+pub mod presets;
+
+use std::{
+    fmt::{Debug, Display},
+    sync::{Arc, Mutex},
+};
 
 use packed_struct::prelude::*;
 
+pub use self::parser::{
+    CollectionType, Field, MainItemFlags, ReportDescriptor, ReportLayout, Usage,
+};
+
 use super::{
     Direction, EndpointDescriptor, Interface, InterfaceClass, InterfaceDescriptor, Recipient,
     SetupRequest, StandardRequest, Type,
@@ -80,9 +90,9 @@ pub enum HidRequestType {
     SetProtocol = 0x0b,
 }
 
-impl From<StandardRequest> for HidRequestType {
-    fn from(value: StandardRequest) -> Self {
-        match value.to_primitive() {
+impl From<u8> for HidRequestType {
+    fn from(value: u8) -> Self {
+        match value {
             0x01 => Self::GetReport,
             0x02 => Self::GetIdle,
             0x03 => Self::GetProtocol,
@@ -104,7 +114,11 @@ pub enum HidRequest {
     Unknown,
     GetReport(HidReportRequest),
     SetReport(HidReportRequest),
+    /// GetIdle request, whose duration is always 0
+    GetIdle(HidSetIdleRequest),
     SetIdle(HidSetIdleRequest),
+    GetProtocol(HidProtocolRequest),
+    SetProtocol(HidProtocolRequest),
 }
 
 // TODO: implement TryFrom instead
@@ -113,11 +127,11 @@ impl From<SetupRequest> for HidRequest {
         let request_type = HidRequestType::from(setup.b_request);
         match request_type {
             HidRequestType::GetReport => Self::GetReport(setup.into()),
-            HidRequestType::GetIdle => todo!(),
-            HidRequestType::GetProtocol => todo!(),
+            HidRequestType::GetIdle => Self::GetIdle(setup.into()),
+            HidRequestType::GetProtocol => Self::GetProtocol(setup.into()),
             HidRequestType::SetReport => Self::SetReport(setup.into()),
             HidRequestType::SetIdle => Self::SetIdle(setup.into()),
-            HidRequestType::SetProtocol => todo!(),
+            HidRequestType::SetProtocol => Self::SetProtocol(setup.into()),
             _ => Self::Unknown,
         }
     }
@@ -157,6 +171,47 @@ impl From<SetupRequest> for HidSetIdleRequest {
     }
 }
 
+/// Protocol of a boot interface selected with SetProtocol (wValue)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq, Default)]
+pub enum HidProtocol {
+    Boot = 0x00,
+    /// Protocol of boot interfaces after reset
+    #[default]
+    Report = 0x01,
+}
+
+/// GetProtocol and SetProtocol request
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "8")]
+pub struct HidProtocolRequest {
+    /// byte 0
+    #[packed_field(bits = "0", ty = "enum")]
+    pub bm_request_type_direction: Direction,
+    #[packed_field(bits = "1..=2", ty = "enum")]
+    pub bm_request_type_kind: Type,
+    #[packed_field(bits = "3..=7", ty = "enum")]
+    pub bm_request_type_recipient: Recipient,
+    // byte 1
+    #[packed_field(bytes = "1", ty = "enum")]
+    pub b_request: HidRequestType,
+    // byte 2-3 (wValue), 0 for GetProtocol
+    #[packed_field(bytes = "2..=3", endian = "lsb")]
+    pub protocol: Integer<u16, packed_bits::Bits<16>>,
+    // byte 4-5 (wIndex)
+    #[packed_field(bytes = "4..=5", endian = "lsb")]
+    pub interface: Integer<u16, packed_bits::Bits<16>>,
+    // byte 6-7 (wLength)
+    #[packed_field(bytes = "6..=7", endian = "lsb")]
+    pub _unused: Integer<u16, packed_bits::Bits<16>>,
+}
+
+impl From<SetupRequest> for HidProtocolRequest {
+    fn from(value: SetupRequest) -> Self {
+        let data = value.pack().unwrap();
+        HidProtocolRequest::unpack(&data).unwrap()
+    }
+}
+
 /// HID report type
 #[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
 pub enum HidReportType {
@@ -200,7 +255,7 @@ impl From<SetupRequest> for HidReportRequest {
 }
 
 impl HidReportRequest {
-    pub fn new(report: &[u8]) -> Self {
+    pub fn new(interface: u16, report: &[u8]) -> Self {
         Self {
             bm_request_type_direction: Direction::In,
             bm_request_type_kind: Type::Class,
@@ -208,7 +263,7 @@ impl HidReportRequest {
             b_request: HidRequestType::GetReport,
             report_id: 0,
             report_type: HidReportType::Input,
-            interface: Integer::from_primitive(2), // TODO: don't hardcode this
+            interface: Integer::from_primitive(interface),
             report_length: Integer::from_primitive(report.len() as u16),
         }
     }
@@ -229,6 +284,68 @@ pub enum InterfaceProtocol {
     Mouse = 0x02,
 }
 
+/// Callback invoked with a report sent by the host. The data includes the
+/// report ID byte if the interface uses report IDs.
+pub type HidReportHandler = Arc<Mutex<dyn FnMut(&[u8]) + Send>>;
+
+/// Callback returning the report requested by the host with GET_REPORT. The
+/// data should include the report ID byte if the interface uses report IDs.
+pub type HidReportProvider = Arc<Mutex<dyn FnMut() -> Vec<u8> + Send>>;
+
+/// Report handlers of an HID interface, keyed by report type and report ID.
+/// A report ID of 0 matches any report without a more specific handler.
+#[derive(Clone, Default)]
+pub struct HidReportHandlers {
+    set_report: Vec<(HidReportType, u8, HidReportHandler)>,
+    get_report: Vec<(HidReportType, u8, HidReportProvider)>,
+}
+
+impl HidReportHandlers {
+    /// Returns the handler for reports of the given type and ID sent by the host
+    pub fn set_report_handler(
+        &self,
+        report_type: HidReportType,
+        report_id: u8,
+    ) -> Option<HidReportHandler> {
+        Self::lookup(&self.set_report, report_type, report_id)
+    }
+
+    /// Returns the provider of reports of the given type and ID requested by
+    /// the host
+    pub fn get_report_provider(
+        &self,
+        report_type: HidReportType,
+        report_id: u8,
+    ) -> Option<HidReportProvider> {
+        Self::lookup(&self.get_report, report_type, report_id)
+    }
+
+    fn lookup<T: Clone>(
+        entries: &[(HidReportType, u8, T)],
+        report_type: HidReportType,
+        report_id: u8,
+    ) -> Option<T> {
+        let matching = |id: u8| {
+            entries
+                .iter()
+                .find(|(t, i, _)| *t == report_type && *i == id)
+                .map(|(_, _, handler)| handler.clone())
+        };
+        matching(report_id).or_else(|| matching(0))
+    }
+}
+
+impl Debug for HidReportHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let set_report: Vec<_> = self.set_report.iter().map(|(t, i, _)| (t, i)).collect();
+        let get_report: Vec<_> = self.get_report.iter().map(|(t, i, _)| (t, i)).collect();
+        f.debug_struct("HidReportHandlers")
+            .field("set_report", &set_report)
+            .field("get_report", &get_report)
+            .finish()
+    }
+}
+
 /// Human Interface Device (HID) interface definition
 #[derive(Debug, Clone)]
 pub struct HidInterface {
@@ -237,6 +354,10 @@ pub struct HidInterface {
     pub report_descriptors: Vec<&'static [u8]>,
     pub report_descriptor_info: Vec<HidReportDescriptorInfo>,
     pub endpoint_descriptors: Vec<EndpointDescriptor>,
+    /// Report IDs declared by the report descriptors. Empty if the interface
+    /// does not use report IDs.
+    pub report_ids: Vec<u8>,
+    pub handlers: HidReportHandlers,
 }
 
 impl HidInterface {
@@ -259,6 +380,8 @@ impl HidInterface {
             report_descriptors: Vec::new(),
             report_descriptor_info: Vec::new(),
             endpoint_descriptors: Vec::new(),
+            report_ids: Vec::new(),
+            handlers: HidReportHandlers::default(),
         }
     }
 
@@ -273,16 +396,10 @@ impl HidInterface {
         let mut bytes = self.iface.pack_to_vec()?;
         result.append(&mut bytes);
 
-        // Pack the HID descriptor
-        let mut bytes = self.descriptor.pack_to_vec()?;
+        // Pack the HID descriptor along with its report descriptor entries
+        let mut bytes = self.pack_hid_descriptor()?;
         result.append(&mut bytes);
 
-        // Pack the report descriptors
-        for report_desc in self.report_descriptor_info.iter() {
-            let mut bytes = report_desc.pack_to_vec()?;
-            result.append(&mut bytes);
-        }
-
         // Pack the endpoint descriptors
         for endpoint_desc in self.endpoint_descriptors.iter() {
             let mut bytes = endpoint_desc.pack_to_vec()?;
@@ -292,6 +409,18 @@ impl HidInterface {
         Ok(result)
     }
 
+    /// Serialize the HID class descriptor, including the type and length of
+    /// each report descriptor, as returned by GET_DESCRIPTOR(HID)
+    pub fn pack_hid_descriptor(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result = self.descriptor.pack_to_vec()?;
+        for report_desc in self.report_descriptor_info.iter() {
+            let mut bytes = report_desc.pack_to_vec()?;
+            result.append(&mut bytes);
+        }
+
+        Ok(result)
+    }
+
     /// Returns the byte serialized size of the interface
     pub fn get_size(&self) -> usize {
         // InterfaceDesc + HidDesc + (HidReportDesc * count) + (EndpointDesc * count)
@@ -307,6 +436,85 @@ impl HidInterface {
     pub fn set_interface_number(&mut self, num: u8) {
         self.iface.b_interface_number = num;
     }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        self.endpoint_descriptors.as_slice()
+    }
+
+    /// Returns true if the reports of this interface are prefixed with a
+    /// report ID byte
+    pub fn uses_report_ids(&self) -> bool {
+        !self.report_ids.is_empty()
+    }
+
+    /// Returns true if this is a boot interface, which must answer
+    /// GET_PROTOCOL and SET_PROTOCOL requests
+    pub fn is_boot_interface(&self) -> bool {
+        self.iface.b_interface_subclass == HidSubclass::Boot as u8
+    }
+
+    /// Register a handler for output or feature reports with the given ID
+    /// sent by the host, either with SET_REPORT or on an interrupt OUT
+    /// endpoint. A report ID of 0 handles any report without a more
+    /// specific handler.
+    pub fn on_set_report<F>(&mut self, report_type: HidReportType, report_id: u8, handler: F)
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        let handler: HidReportHandler = Arc::new(Mutex::new(handler));
+        self.handlers
+            .set_report
+            .push((report_type, report_id, handler));
+    }
+
+    /// Register a provider for reports with the given ID requested by the
+    /// host with GET_REPORT. A report ID of 0 provides any report without a
+    /// more specific provider.
+    pub fn on_get_report<F>(&mut self, report_type: HidReportType, report_id: u8, provider: F)
+    where
+        F: FnMut() -> Vec<u8> + Send + 'static,
+    {
+        let provider: HidReportProvider = Arc::new(Mutex::new(provider));
+        self.handlers
+            .get_report
+            .push((report_type, report_id, provider));
+    }
+
+    /// Dispatch a report sent by the host to the matching handler. Returns
+    /// true if a handler was found.
+    pub fn handle_set_report(
+        &self,
+        report_type: HidReportType,
+        report_id: u8,
+        data: &[u8],
+    ) -> bool {
+        let Some(handler) = self.handlers.set_report_handler(report_type, report_id) else {
+            return false;
+        };
+        if let Ok(mut handler) = handler.lock() {
+            handler(data);
+        }
+        true
+    }
+
+    /// Dispatch an output report received on an interrupt OUT endpoint to
+    /// the matching handler. Returns true if a handler was found.
+    pub fn handle_output_report(&self, data: &[u8]) -> bool {
+        let report_id = match self.uses_report_ids() {
+            true => data.first().copied().unwrap_or_default(),
+            false => 0,
+        };
+        self.handle_set_report(HidReportType::Output, report_id, data)
+    }
+
+    /// Returns the report requested by the host from the matching provider,
+    /// or None if no provider was found.
+    pub fn handle_get_report(&self, report_type: HidReportType, report_id: u8) -> Option<Vec<u8>> {
+        let provider = self.handlers.get_report_provider(report_type, report_id)?;
+        let mut provider = provider.lock().ok()?;
+        Some(provider())
+    }
 }
 
 impl Display for HidInterface {
@@ -378,6 +586,15 @@ impl HidInterfaceBuilder {
         self.iface.report_descriptors.push(report_desc);
         self.iface.report_descriptor_info.push(info);
 
+        // Keep track of the report IDs to route reports sent by the host
+        if let Ok(parsed) = ReportDescriptor::parse(report_desc) {
+            for id in parsed.report_ids() {
+                if id != 0 && !self.iface.report_ids.contains(&id) {
+                    self.iface.report_ids.push(id);
+                }
+            }
+        }
+
         // Increment the number of descriptors in the interface
         self.iface.descriptor.b_num_descriptors += 1;
         self.iface.descriptor.b_length += 3; // Add to the total size
@@ -391,6 +608,36 @@ impl HidInterfaceBuilder {
         self.iface.iface.b_num_endpoints = self.iface.endpoint_descriptors.len() as u8;
         self
     }
+
+    /// Handle output or feature reports with the given ID sent by the host.
+    /// See [HidInterface::on_set_report].
+    pub fn on_set_report<F>(
+        &mut self,
+        report_type: HidReportType,
+        report_id: u8,
+        handler: F,
+    ) -> &mut Self
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        self.iface.on_set_report(report_type, report_id, handler);
+        self
+    }
+
+    /// Provide reports with the given ID requested by the host. See
+    /// [HidInterface::on_get_report].
+    pub fn on_get_report<F>(
+        &mut self,
+        report_type: HidReportType,
+        report_id: u8,
+        provider: F,
+    ) -> &mut Self
+    where
+        F: FnMut() -> Vec<u8> + Send + 'static,
+    {
+        self.iface.on_get_report(report_type, report_id, provider);
+        self
+    }
 }
 
 impl Default for HidInterfaceBuilder {
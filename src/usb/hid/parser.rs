@@ -0,0 +1,1285 @@
+//! HID Report Descriptor parser
+//! https://www.usb.org/sites/default/files/hid1_11.pdf (6.2.2 Report Descriptor)
+//!
+//! A report descriptor is a flat list of items that describe the layout of
+//! every input, output, and feature report a device supports. Parsing the
+//! descriptor with [ReportDescriptor::parse] decodes those items into a tree
+//! of [Collection]s and a [ReportLayout] for every report, which can be used
+//! to read and write values in a report buffer by [Usage] instead of by
+//! hand-written byte offsets.
+
+use std::{error::Error, fmt::Display, str::FromStr};
+
+use super::HidReportType;
+
+/// Maximum length of a report in bytes, including the report ID byte. This
+/// is the largest report buffer accepted by the Linux HID core.
+pub const MAX_REPORT_LENGTH: usize = 16384;
+
+/// Item type (bType)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ItemType {
+    Main = 0,
+    Global = 1,
+    Local = 2,
+    Reserved = 3,
+}
+
+impl From<u8> for ItemType {
+    fn from(value: u8) -> Self {
+        match value & 0x03 {
+            0 => Self::Main,
+            1 => Self::Global,
+            2 => Self::Local,
+            _ => Self::Reserved,
+        }
+    }
+}
+
+/// Main item tags (bTag)
+pub mod main_tag {
+    pub const INPUT: u8 = 0x08;
+    pub const OUTPUT: u8 = 0x09;
+    pub const COLLECTION: u8 = 0x0a;
+    pub const FEATURE: u8 = 0x0b;
+    pub const END_COLLECTION: u8 = 0x0c;
+}
+
+/// Global item tags (bTag)
+pub mod global_tag {
+    pub const USAGE_PAGE: u8 = 0x00;
+    pub const LOGICAL_MINIMUM: u8 = 0x01;
+    pub const LOGICAL_MAXIMUM: u8 = 0x02;
+    pub const PHYSICAL_MINIMUM: u8 = 0x03;
+    pub const PHYSICAL_MAXIMUM: u8 = 0x04;
+    pub const UNIT_EXPONENT: u8 = 0x05;
+    pub const UNIT: u8 = 0x06;
+    pub const REPORT_SIZE: u8 = 0x07;
+    pub const REPORT_ID: u8 = 0x08;
+    pub const REPORT_COUNT: u8 = 0x09;
+    pub const PUSH: u8 = 0x0a;
+    pub const POP: u8 = 0x0b;
+}
+
+/// Local item tags (bTag)
+pub mod local_tag {
+    pub const USAGE: u8 = 0x00;
+    pub const USAGE_MINIMUM: u8 = 0x01;
+    pub const USAGE_MAXIMUM: u8 = 0x02;
+    pub const DESIGNATOR_INDEX: u8 = 0x03;
+    pub const DESIGNATOR_MINIMUM: u8 = 0x04;
+    pub const DESIGNATOR_MAXIMUM: u8 = 0x05;
+    pub const STRING_INDEX: u8 = 0x07;
+    pub const STRING_MINIMUM: u8 = 0x08;
+    pub const STRING_MAXIMUM: u8 = 0x09;
+    pub const DELIMITER: u8 = 0x0a;
+}
+
+/// A single item from a report descriptor. Long items are kept as-is, but
+/// are not interpreted by the parser.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub item_type: ItemType,
+    pub tag: u8,
+    pub data: Vec<u8>,
+    /// Set if this is a long item (bTag is the bLongItemTag)
+    pub long: bool,
+}
+
+impl Item {
+    /// Returns the item data as an unsigned value
+    pub fn unsigned(&self) -> u32 {
+        let mut value = 0u32;
+        for (i, byte) in self.data.iter().take(4).enumerate() {
+            value |= (*byte as u32) << (8 * i);
+        }
+        value
+    }
+
+    /// Returns the item data as a sign-extended value
+    pub fn signed(&self) -> i32 {
+        let value = self.unsigned();
+        match self.data.len() {
+            1 => value as u8 as i8 as i32,
+            2 => value as u16 as i16 as i32,
+            _ => value as i32,
+        }
+    }
+}
+
+impl Display for Item {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.long {
+            return write!(
+                f,
+                "Long Item ({:#04x}, {} bytes)",
+                self.tag,
+                self.data.len()
+            );
+        }
+        let name = match (self.item_type, self.tag) {
+            (ItemType::Main, main_tag::INPUT) => "Input",
+            (ItemType::Main, main_tag::OUTPUT) => "Output",
+            (ItemType::Main, main_tag::COLLECTION) => "Collection",
+            (ItemType::Main, main_tag::FEATURE) => "Feature",
+            (ItemType::Main, main_tag::END_COLLECTION) => "End Collection",
+            (ItemType::Global, global_tag::USAGE_PAGE) => "Usage Page",
+            (ItemType::Global, global_tag::LOGICAL_MINIMUM) => "Logical Minimum",
+            (ItemType::Global, global_tag::LOGICAL_MAXIMUM) => "Logical Maximum",
+            (ItemType::Global, global_tag::PHYSICAL_MINIMUM) => "Physical Minimum",
+            (ItemType::Global, global_tag::PHYSICAL_MAXIMUM) => "Physical Maximum",
+            (ItemType::Global, global_tag::UNIT_EXPONENT) => "Unit Exponent",
+            (ItemType::Global, global_tag::UNIT) => "Unit",
+            (ItemType::Global, global_tag::REPORT_SIZE) => "Report Size",
+            (ItemType::Global, global_tag::REPORT_ID) => "Report ID",
+            (ItemType::Global, global_tag::REPORT_COUNT) => "Report Count",
+            (ItemType::Global, global_tag::PUSH) => "Push",
+            (ItemType::Global, global_tag::POP) => "Pop",
+            (ItemType::Local, local_tag::USAGE) => "Usage",
+            (ItemType::Local, local_tag::USAGE_MINIMUM) => "Usage Minimum",
+            (ItemType::Local, local_tag::USAGE_MAXIMUM) => "Usage Maximum",
+            (ItemType::Local, local_tag::DESIGNATOR_INDEX) => "Designator Index",
+            (ItemType::Local, local_tag::DESIGNATOR_MINIMUM) => "Designator Minimum",
+            (ItemType::Local, local_tag::DESIGNATOR_MAXIMUM) => "Designator Maximum",
+            (ItemType::Local, local_tag::STRING_INDEX) => "String Index",
+            (ItemType::Local, local_tag::STRING_MINIMUM) => "String Minimum",
+            (ItemType::Local, local_tag::STRING_MAXIMUM) => "String Maximum",
+            (ItemType::Local, local_tag::DELIMITER) => "Delimiter",
+            _ => "Reserved",
+        };
+        if self.data.is_empty() {
+            write!(f, "{name}")
+        } else {
+            write!(f, "{name} ({:#x})", self.unsigned())
+        }
+    }
+}
+
+/// Usage pages defined in the HID Usage Tables
+/// https://usb.org/document-library/hid-usage-tables-15
+pub mod usage_page {
+    pub const GENERIC_DESKTOP: u16 = 0x01;
+    pub const SIMULATION: u16 = 0x02;
+    pub const GAME: u16 = 0x05;
+    pub const GENERIC_DEVICE: u16 = 0x06;
+    pub const KEYBOARD: u16 = 0x07;
+    pub const LED: u16 = 0x08;
+    pub const BUTTON: u16 = 0x09;
+    pub const ORDINAL: u16 = 0x0a;
+    pub const CONSUMER: u16 = 0x0c;
+    pub const DIGITIZER: u16 = 0x0d;
+    pub const PID: u16 = 0x0f;
+    pub const VENDOR_DEFINED_START: u16 = 0xff00;
+}
+
+/// An HID usage, made up of a usage page and a usage ID within that page.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Usage {
+    pub page: u16,
+    pub id: u16,
+}
+
+impl Usage {
+    /// Generic Desktop: X
+    pub const X: Usage = Usage::generic_desktop(0x30);
+    /// Generic Desktop: Y
+    pub const Y: Usage = Usage::generic_desktop(0x31);
+    /// Generic Desktop: Z
+    pub const Z: Usage = Usage::generic_desktop(0x32);
+    /// Generic Desktop: Rx
+    pub const RX: Usage = Usage::generic_desktop(0x33);
+    /// Generic Desktop: Ry
+    pub const RY: Usage = Usage::generic_desktop(0x34);
+    /// Generic Desktop: Rz
+    pub const RZ: Usage = Usage::generic_desktop(0x35);
+    /// Generic Desktop: Slider
+    pub const SLIDER: Usage = Usage::generic_desktop(0x36);
+    /// Generic Desktop: Dial
+    pub const DIAL: Usage = Usage::generic_desktop(0x37);
+    /// Generic Desktop: Wheel
+    pub const WHEEL: Usage = Usage::generic_desktop(0x38);
+    /// Generic Desktop: Hat switch
+    pub const HAT_SWITCH: Usage = Usage::generic_desktop(0x39);
+
+    pub const fn new(page: u16, id: u16) -> Self {
+        Self { page, id }
+    }
+
+    /// Returns a usage on the Generic Desktop page
+    pub const fn generic_desktop(id: u16) -> Self {
+        Self::new(usage_page::GENERIC_DESKTOP, id)
+    }
+
+    /// Returns the usage for the given button number (starting at 1)
+    pub const fn button(num: u16) -> Self {
+        Self::new(usage_page::BUTTON, num)
+    }
+
+    /// Returns a usage on the Keyboard/Keypad page
+    pub const fn keyboard(id: u16) -> Self {
+        Self::new(usage_page::KEYBOARD, id)
+    }
+
+    /// Returns a usage on the Consumer page
+    pub const fn consumer(id: u16) -> Self {
+        Self::new(usage_page::CONSUMER, id)
+    }
+
+    /// Returns a usage on the Digitizers page
+    pub const fn digitizer(id: u16) -> Self {
+        Self::new(usage_page::DIGITIZER, id)
+    }
+
+    /// Returns the 32-bit extended usage value (page << 16 | id)
+    pub fn extended(&self) -> u32 {
+        ((self.page as u32) << 16) | self.id as u32
+    }
+}
+
+impl From<u32> for Usage {
+    /// Create a usage from a 32-bit extended usage value
+    fn from(value: u32) -> Self {
+        Self::new((value >> 16) as u16, value as u16)
+    }
+}
+
+/// Names of Generic Desktop usages that can be used to look up a [Usage]
+const GENERIC_DESKTOP_NAMES: &[(u16, &str)] = &[
+    (0x01, "Pointer"),
+    (0x02, "Mouse"),
+    (0x04, "Joystick"),
+    (0x05, "Gamepad"),
+    (0x06, "Keyboard"),
+    (0x07, "Keypad"),
+    (0x30, "X"),
+    (0x31, "Y"),
+    (0x32, "Z"),
+    (0x33, "Rx"),
+    (0x34, "Ry"),
+    (0x35, "Rz"),
+    (0x36, "Slider"),
+    (0x37, "Dial"),
+    (0x38, "Wheel"),
+    (0x39, "Hat switch"),
+    (0x80, "System Control"),
+    (0x81, "System Power Down"),
+    (0x82, "System Sleep"),
+    (0x83, "System Wake Up"),
+];
+
+impl Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.page {
+            usage_page::GENERIC_DESKTOP => {
+                if let Some((_, name)) = GENERIC_DESKTOP_NAMES.iter().find(|(id, _)| *id == self.id)
+                {
+                    return write!(f, "{name}");
+                }
+            }
+            usage_page::BUTTON => return write!(f, "Button {}", self.id),
+            usage_page::KEYBOARD => return write!(f, "Key {:#04x}", self.id),
+            usage_page::LED => return write!(f, "LED {:#04x}", self.id),
+            _ => (),
+        }
+        write!(f, "{:#06x}:{:#06x}", self.page, self.id)
+    }
+}
+
+impl FromStr for Usage {
+    type Err = &'static str;
+
+    /// Parse a usage from its name (e.g. "X", "Button 1", "Key 0x04") or
+    /// from a "page:id" pair (e.g. "0xff00:0x01").
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        fn parse_num(value: &str) -> Option<u16> {
+            let value = value.trim();
+            match value.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16).ok(),
+                None => value.parse().ok(),
+            }
+        }
+
+        if let Some((id, _)) = GENERIC_DESKTOP_NAMES
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(s))
+        {
+            return Ok(Usage::generic_desktop(*id));
+        }
+        if let Some(num) = s.strip_prefix("Button ") {
+            return parse_num(num)
+                .map(Usage::button)
+                .ok_or("Invalid button number");
+        }
+        if let Some(num) = s.strip_prefix("Key ") {
+            return parse_num(num)
+                .map(Usage::keyboard)
+                .ok_or("Invalid key code");
+        }
+        if let Some(num) = s.strip_prefix("LED ") {
+            return parse_num(num)
+                .map(|id| Usage::new(usage_page::LED, id))
+                .ok_or("Invalid LED usage");
+        }
+        if let Some((page, id)) = s.split_once(':') {
+            let (Some(page), Some(id)) = (parse_num(page), parse_num(id)) else {
+                return Err("Invalid usage page or usage id");
+            };
+            return Ok(Usage::new(page, id));
+        }
+
+        Err("Unknown usage name")
+    }
+}
+
+/// Collection type of a Collection item
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CollectionType {
+    Physical,
+    Application,
+    Logical,
+    Report,
+    NamedArray,
+    UsageSwitch,
+    UsageModifier,
+    Reserved(u8),
+    VendorDefined(u8),
+}
+
+impl From<u8> for CollectionType {
+    fn from(value: u8) -> Self {
+        match value {
+            0x00 => Self::Physical,
+            0x01 => Self::Application,
+            0x02 => Self::Logical,
+            0x03 => Self::Report,
+            0x04 => Self::NamedArray,
+            0x05 => Self::UsageSwitch,
+            0x06 => Self::UsageModifier,
+            0x80..=0xff => Self::VendorDefined(value),
+            _ => Self::Reserved(value),
+        }
+    }
+}
+
+/// Data flags from an Input, Output, or Feature main item
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct MainItemFlags(pub u32);
+
+impl MainItemFlags {
+    /// Data (false) or Constant (true)
+    pub fn is_constant(&self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+
+    /// Array (false) or Variable (true)
+    pub fn is_variable(&self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+
+    /// Absolute (false) or Relative (true)
+    pub fn is_relative(&self) -> bool {
+        self.0 & (1 << 2) != 0
+    }
+
+    /// No Wrap (false) or Wrap (true)
+    pub fn is_wrap(&self) -> bool {
+        self.0 & (1 << 3) != 0
+    }
+
+    /// Linear (false) or Non Linear (true)
+    pub fn is_non_linear(&self) -> bool {
+        self.0 & (1 << 4) != 0
+    }
+
+    /// Preferred State (false) or No Preferred (true)
+    pub fn is_no_preferred(&self) -> bool {
+        self.0 & (1 << 5) != 0
+    }
+
+    /// No Null position (false) or Null state (true)
+    pub fn has_null_state(&self) -> bool {
+        self.0 & (1 << 6) != 0
+    }
+
+    /// Non Volatile (false) or Volatile (true). Only used for Output and
+    /// Feature items.
+    pub fn is_volatile(&self) -> bool {
+        self.0 & (1 << 7) != 0
+    }
+
+    /// Bit Field (false) or Buffered Bytes (true)
+    pub fn is_buffered_bytes(&self) -> bool {
+        self.0 & (1 << 8) != 0
+    }
+}
+
+/// A single value within a report. Every element of a main item's Report
+/// Count becomes its own field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    /// The type of report this field is part of
+    pub report_type: HidReportType,
+    /// The report ID this field is part of (0 if report IDs are not used)
+    pub report_id: u8,
+    /// Offset of the field in bits from the start of the report, including
+    /// the report ID byte if report IDs are used.
+    pub bit_offset: usize,
+    /// Size of the field in bits
+    pub bit_size: usize,
+    /// Usage of a variable field. For array fields this is the first usage
+    /// that can be reported.
+    pub usage: Usage,
+    /// All usages that an array field can report. The value stored in the
+    /// report is the index into this list offset by the logical minimum.
+    /// Empty for variable fields.
+    pub usages: Vec<Usage>,
+    pub logical_minimum: i32,
+    pub logical_maximum: i32,
+    pub physical_minimum: i32,
+    pub physical_maximum: i32,
+    pub unit_exponent: i32,
+    pub unit: u32,
+    pub flags: MainItemFlags,
+}
+
+impl Field {
+    /// Returns true if this field holds one of several usages rather than
+    /// a value for a single usage.
+    pub fn is_array(&self) -> bool {
+        !self.flags.is_variable()
+    }
+
+    /// Returns true if the field can represent the given usage
+    pub fn has_usage(&self, usage: Usage) -> bool {
+        if self.flags.is_constant() {
+            return false;
+        }
+        if self.is_array() {
+            return self.usages.contains(&usage);
+        }
+        self.usage == usage
+    }
+
+    /// Read the raw value of this field from the given report buffer. The
+    /// value is sign-extended if the logical minimum is negative.
+    pub fn read(&self, report: &[u8]) -> Option<i32> {
+        if self.bit_size == 0 || self.bit_size > 32 {
+            return None;
+        }
+        if self.bit_offset + self.bit_size > report.len() * 8 {
+            return None;
+        }
+
+        let mut value = 0u32;
+        for i in 0..self.bit_size {
+            let bit = self.bit_offset + i;
+            if report[bit / 8] & (1 << (bit % 8)) != 0 {
+                value |= 1 << i;
+            }
+        }
+
+        // Sign-extend the value if the logical range is signed
+        if self.logical_minimum < 0 && self.bit_size < 32 {
+            let shift = 32 - self.bit_size;
+            return Some(((value << shift) as i32) >> shift);
+        }
+
+        Some(value as i32)
+    }
+
+    /// Write the given raw value into this field in the given report buffer.
+    pub fn write(&self, report: &mut [u8], value: i32) -> Result<(), Box<dyn Error>> {
+        if self.bit_size == 0 || self.bit_size > 32 {
+            return Err(format!("Unsupported field size: {} bits", self.bit_size).into());
+        }
+        if self.bit_offset + self.bit_size > report.len() * 8 {
+            return Err("Report buffer is too small for field".into());
+        }
+
+        let value = value as u32;
+        for i in 0..self.bit_size {
+            let bit = self.bit_offset + i;
+            if value & (1 << i) != 0 {
+                report[bit / 8] |= 1 << (bit % 8);
+            } else {
+                report[bit / 8] &= !(1 << (bit % 8));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Clamp the given value to the logical range of this field. Ranges
+    /// where the maximum is less than the minimum are treated as unbounded.
+    pub fn clamp(&self, value: i32) -> i32 {
+        if self.logical_maximum < self.logical_minimum {
+            return value;
+        }
+        value.clamp(self.logical_minimum, self.logical_maximum)
+    }
+}
+
+impl Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = if self.flags.is_constant() {
+            "Constant"
+        } else if self.is_array() {
+            "Array"
+        } else {
+            "Variable"
+        };
+        write!(
+            f,
+            "{kind} {} (offset: {}, size: {}, logical: {}..={})",
+            self.usage, self.bit_offset, self.bit_size, self.logical_minimum, self.logical_maximum
+        )
+    }
+}
+
+/// A collection of fields and other collections
+#[derive(Debug, Clone, PartialEq)]
+pub struct Collection {
+    pub collection_type: CollectionType,
+    pub usage: Usage,
+    pub collections: Vec<Collection>,
+    pub fields: Vec<Field>,
+}
+
+impl Collection {
+    fn new(collection_type: CollectionType, usage: Usage) -> Self {
+        Self {
+            collection_type,
+            usage,
+            collections: Vec::new(),
+            fields: Vec::new(),
+        }
+    }
+}
+
+/// Layout of a single report which can be used to read and write the
+/// values of usages in a report buffer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportLayout {
+    pub report_type: HidReportType,
+    /// The report ID (0 if report IDs are not used)
+    pub report_id: u8,
+    /// Total size of the report in bits, including the report ID byte
+    pub size_bits: usize,
+    pub fields: Vec<Field>,
+}
+
+impl ReportLayout {
+    fn new(report_type: HidReportType, report_id: u8) -> Self {
+        let size_bits = if report_id == 0 { 0 } else { 8 };
+        Self {
+            report_type,
+            report_id,
+            size_bits,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Size of the report in bytes, including the report ID byte
+    pub fn size(&self) -> usize {
+        self.size_bits.div_ceil(8)
+    }
+
+    /// Returns a new zeroed report buffer with the report ID set
+    pub fn new_report(&self) -> Vec<u8> {
+        let mut report = vec![0; self.size()];
+        if self.report_id != 0 {
+            report[0] = self.report_id;
+        }
+        report
+    }
+
+    /// Returns the first field that can represent the given usage
+    pub fn field(&self, usage: Usage) -> Option<&Field> {
+        self.fields.iter().find(|field| field.has_usage(usage))
+    }
+
+    /// Returns all usages that can be read or written with this layout
+    pub fn usages(&self) -> Vec<Usage> {
+        let mut usages = Vec::new();
+        for field in self.fields.iter().filter(|f| !f.flags.is_constant()) {
+            let candidates = if field.is_array() {
+                field.usages.clone()
+            } else {
+                vec![field.usage]
+            };
+            for usage in candidates {
+                if !usages.contains(&usage) {
+                    usages.push(usage);
+                }
+            }
+        }
+        usages
+    }
+
+    /// Read the value of the given usage from the report buffer. For usages
+    /// that are part of an array field, returns 1 if the usage is present
+    /// in the array and 0 otherwise.
+    pub fn get(&self, report: &[u8], usage: Usage) -> Option<i32> {
+        let field = self.field(usage)?;
+        if !field.is_array() {
+            return field.read(report);
+        }
+
+        // Look through every element of the array for the usage
+        let index = field.usages.iter().position(|u| *u == usage)? as i32;
+        let value = field.logical_minimum + index;
+        let present = self
+            .fields
+            .iter()
+            .filter(|f| f.is_array() && f.usages == field.usages)
+            .any(|f| f.read(report) == Some(value));
+
+        Some(present as i32)
+    }
+
+    /// Write the value of the given usage into the report buffer. Values are
+    /// clamped to the logical range of the field. For usages that are part
+    /// of an array field, a non-zero value adds the usage to the first free
+    /// array slot, and zero removes it.
+    pub fn set(&self, report: &mut [u8], usage: Usage, value: i32) -> Result<(), Box<dyn Error>> {
+        let Some(field) = self.field(usage) else {
+            return Err(format!("No field exists for usage {usage}").into());
+        };
+        if !field.is_array() {
+            return field.write(report, field.clamp(value));
+        }
+
+        // Find all the array elements that share this usage list
+        let index = field.usages.iter().position(|u| *u == usage).unwrap_or(0) as i32;
+        let usage_value = field.logical_minimum + index;
+        let elements: Vec<&Field> = self
+            .fields
+            .iter()
+            .filter(|f| f.is_array() && f.usages == field.usages)
+            .collect();
+        let is_set: Vec<bool> = elements
+            .iter()
+            .map(|f| f.read(report) == Some(usage_value))
+            .collect();
+
+        // Remove the usage from the array
+        if value == 0 {
+            for (element, _) in elements.iter().zip(is_set).filter(|(_, set)| *set) {
+                element.write(report, 0)?;
+            }
+            return Ok(());
+        }
+
+        // Add the usage to the first empty slot of the array
+        if is_set.contains(&true) {
+            return Ok(());
+        }
+        let empty = elements.iter().find(|f| {
+            let value = f.read(report).unwrap_or(0);
+            value == 0 || value < f.logical_minimum || value > f.logical_maximum
+        });
+        let Some(element) = empty else {
+            return Err(format!("No free array slot for usage {usage}").into());
+        };
+        element.write(report, usage_value)
+    }
+}
+
+impl Display for ReportLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut text = vec![format!(
+            "{:?} Report {} ({} bytes)",
+            self.report_type,
+            self.report_id,
+            self.size()
+        )];
+        for field in self.fields.iter() {
+            text.push(format!("  {field}"));
+        }
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+/// A parsed HID report descriptor
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportDescriptor {
+    /// All items decoded from the descriptor in order
+    pub items: Vec<Item>,
+    /// Top-level collections (typically Application collections)
+    pub collections: Vec<Collection>,
+    /// The layout of every report defined in the descriptor
+    pub reports: Vec<ReportLayout>,
+}
+
+/// Global item state
+#[derive(Debug, Clone, Default)]
+struct GlobalState {
+    usage_page: u16,
+    logical_minimum: i32,
+    logical_maximum: i32,
+    physical_minimum: i32,
+    physical_maximum: i32,
+    unit_exponent: i32,
+    unit: u32,
+    report_size: u32,
+    report_id: u8,
+    report_count: u32,
+}
+
+/// Local item state, which is reset after every main item
+#[derive(Debug, Clone, Default)]
+struct LocalState {
+    /// Usages with a flag indicating if the usage page was included
+    usages: Vec<(u32, bool)>,
+    usage_minimum: Option<(u32, bool)>,
+}
+
+impl LocalState {
+    /// Resolve all usages against the given usage page
+    fn resolve(&self, usage_page: u16) -> Vec<Usage> {
+        self.usages
+            .iter()
+            .map(|(value, extended)| {
+                if *extended {
+                    Usage::from(*value)
+                } else {
+                    Usage::new(usage_page, *value as u16)
+                }
+            })
+            .collect()
+    }
+}
+
+impl ReportDescriptor {
+    /// Decode the given report descriptor bytes into a list of items
+    pub fn items(data: &[u8]) -> Result<Vec<Item>, Box<dyn Error>> {
+        let mut items = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let prefix = data[i];
+
+            // Long items
+            if prefix == 0xfe {
+                let Some(header) = data.get(i + 1..i + 3) else {
+                    return Err(format!("Truncated long item at offset {i}").into());
+                };
+                let size = header[0] as usize;
+                let Some(item_data) = data.get(i + 3..i + 3 + size) else {
+                    return Err(format!("Truncated long item at offset {i}").into());
+                };
+                items.push(Item {
+                    item_type: ItemType::Reserved,
+                    tag: header[1],
+                    data: item_data.to_vec(),
+                    long: true,
+                });
+                i += 3 + size;
+                continue;
+            }
+
+            // Short items
+            let size = match prefix & 0x03 {
+                3 => 4,
+                size => size as usize,
+            };
+            let Some(item_data) = data.get(i + 1..i + 1 + size) else {
+                return Err(format!("Truncated item at offset {i}").into());
+            };
+            items.push(Item {
+                item_type: ItemType::from(prefix >> 2),
+                tag: prefix >> 4,
+                data: item_data.to_vec(),
+                long: false,
+            });
+            i += 1 + size;
+        }
+
+        Ok(items)
+    }
+
+    /// Parse the given report descriptor bytes
+    pub fn parse(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let items = Self::items(data)?;
+        let mut global = GlobalState::default();
+        let mut global_stack: Vec<GlobalState> = Vec::new();
+        let mut local = LocalState::default();
+        let mut reports: Vec<ReportLayout> = Vec::new();
+        let mut uses_report_ids = false;
+
+        // The root collection holds the top-level collections and any fields
+        // declared outside of a collection.
+        let mut stack = vec![Collection::new(
+            CollectionType::Application,
+            Usage::new(0, 0),
+        )];
+
+        for item in items.iter() {
+            if item.long {
+                continue;
+            }
+            match item.item_type {
+                ItemType::Main => {
+                    match item.tag {
+                        main_tag::INPUT | main_tag::OUTPUT | main_tag::FEATURE => {
+                            let report_type = match item.tag {
+                                main_tag::INPUT => HidReportType::Input,
+                                main_tag::OUTPUT => HidReportType::Output,
+                                _ => HidReportType::Feature,
+                            };
+                            let flags = MainItemFlags(item.unsigned());
+                            let usages = local.resolve(global.usage_page);
+
+                            // Find or create the layout for this report
+                            let report_id = global.report_id;
+                            let position = reports.iter().position(|r| {
+                                r.report_type == report_type && r.report_id == report_id
+                            });
+                            let layout = match position {
+                                Some(idx) => &mut reports[idx],
+                                None => {
+                                    reports.push(ReportLayout::new(report_type, report_id));
+                                    reports.last_mut().unwrap()
+                                }
+                            };
+
+                            // Bound the report before creating a field for
+                            // every element of the Report Count
+                            let count = global.report_count as usize;
+                            let bits = count.saturating_mul(global.report_size as usize);
+                            if count > MAX_REPORT_LENGTH * 8
+                                || layout.size_bits + bits > MAX_REPORT_LENGTH * 8
+                            {
+                                let err =
+                                    format!("Report {report_id} exceeds {MAX_REPORT_LENGTH} bytes");
+                                return Err(err.into());
+                            }
+
+                            for i in 0..count {
+                                let usage = if flags.is_variable() {
+                                    usages
+                                        .get(i)
+                                        .or(usages.last())
+                                        .copied()
+                                        .unwrap_or(Usage::new(global.usage_page, 0))
+                                } else {
+                                    usages
+                                        .first()
+                                        .copied()
+                                        .unwrap_or(Usage::new(global.usage_page, 0))
+                                };
+                                let field = Field {
+                                    report_type,
+                                    report_id,
+                                    bit_offset: layout.size_bits,
+                                    bit_size: global.report_size as usize,
+                                    usage,
+                                    usages: if flags.is_variable() {
+                                        Vec::new()
+                                    } else {
+                                        usages.clone()
+                                    },
+                                    logical_minimum: global.logical_minimum,
+                                    logical_maximum: global.logical_maximum,
+                                    physical_minimum: global.physical_minimum,
+                                    physical_maximum: global.physical_maximum,
+                                    unit_exponent: global.unit_exponent,
+                                    unit: global.unit,
+                                    flags,
+                                };
+                                layout.size_bits += global.report_size as usize;
+                                layout.fields.push(field.clone());
+                                stack.last_mut().unwrap().fields.push(field);
+                            }
+                        }
+                        main_tag::COLLECTION => {
+                            let usages = local.resolve(global.usage_page);
+                            let usage = usages.first().copied().unwrap_or(Usage::new(0, 0));
+                            let kind = CollectionType::from(item.unsigned() as u8);
+                            stack.push(Collection::new(kind, usage));
+                        }
+                        main_tag::END_COLLECTION => {
+                            if stack.len() < 2 {
+                                return Err("End Collection without a Collection".into());
+                            }
+                            let collection = stack.pop().unwrap();
+                            stack.last_mut().unwrap().collections.push(collection);
+                        }
+                        _ => {
+                            let err = format!("Unknown main item tag: {:#x}", item.tag);
+                            return Err(err.into());
+                        }
+                    }
+                    local = LocalState::default();
+                }
+                ItemType::Global => match item.tag {
+                    global_tag::USAGE_PAGE => global.usage_page = item.unsigned() as u16,
+                    global_tag::LOGICAL_MINIMUM => global.logical_minimum = item.signed(),
+                    global_tag::LOGICAL_MAXIMUM => {
+                        // The maximum is only signed if the minimum is negative
+                        global.logical_maximum = if global.logical_minimum < 0 {
+                            item.signed()
+                        } else {
+                            item.unsigned() as i32
+                        };
+                    }
+                    global_tag::PHYSICAL_MINIMUM => global.physical_minimum = item.signed(),
+                    global_tag::PHYSICAL_MAXIMUM => {
+                        global.physical_maximum = if global.physical_minimum < 0 {
+                            item.signed()
+                        } else {
+                            item.unsigned() as i32
+                        };
+                    }
+                    global_tag::UNIT_EXPONENT => {
+                        // Unit exponents are stored as a 4-bit signed value
+                        let value = item.unsigned() as i32;
+                        global.unit_exponent = if value > 7 && value < 16 {
+                            value - 16
+                        } else {
+                            item.signed()
+                        };
+                    }
+                    global_tag::UNIT => global.unit = item.unsigned(),
+                    global_tag::REPORT_SIZE => {
+                        global.report_size = item.unsigned();
+                        if global.report_size > 32 {
+                            let err = format!("Invalid report size: {}", global.report_size);
+                            return Err(err.into());
+                        }
+                    }
+                    global_tag::REPORT_ID => {
+                        let id = item.unsigned();
+                        if id == 0 || id > 255 {
+                            return Err(format!("Invalid report ID: {id}").into());
+                        }
+                        if !uses_report_ids && !reports.is_empty() {
+                            return Err("Report ID declared after reports without an ID".into());
+                        }
+                        uses_report_ids = true;
+                        global.report_id = id as u8;
+                    }
+                    global_tag::REPORT_COUNT => global.report_count = item.unsigned(),
+                    global_tag::PUSH => global_stack.push(global.clone()),
+                    global_tag::POP => {
+                        let Some(state) = global_stack.pop() else {
+                            return Err("Pop without a matching Push".into());
+                        };
+                        global = state;
+                    }
+                    _ => {
+                        let err = format!("Unknown global item tag: {:#x}", item.tag);
+                        return Err(err.into());
+                    }
+                },
+                ItemType::Local => {
+                    let extended = item.data.len() == 4;
+                    match item.tag {
+                        local_tag::USAGE => local.usages.push((item.unsigned(), extended)),
+                        local_tag::USAGE_MINIMUM => {
+                            local.usage_minimum = Some((item.unsigned(), extended));
+                        }
+                        local_tag::USAGE_MAXIMUM => {
+                            let Some((minimum, min_extended)) = local.usage_minimum.take() else {
+                                return Err("Usage Maximum without a Usage Minimum".into());
+                            };
+                            let maximum = item.unsigned();
+                            if maximum < minimum || maximum - minimum > 0xffff {
+                                return Err("Invalid usage range".into());
+                            }
+                            for usage in minimum..=maximum {
+                                local.usages.push((usage, extended || min_extended));
+                            }
+                        }
+                        // Designators, strings, and delimiters are not used
+                        // to lay out reports.
+                        _ => (),
+                    }
+                }
+                ItemType::Reserved => (),
+            }
+        }
+
+        if stack.len() != 1 {
+            return Err("Collection without an End Collection".into());
+        }
+        let root = stack.pop().unwrap();
+
+        Ok(Self {
+            items,
+            collections: root.collections,
+            reports,
+        })
+    }
+
+    /// Returns the report IDs used by this descriptor. If report IDs are not
+    /// used, this returns a list with only report ID 0.
+    pub fn report_ids(&self) -> Vec<u8> {
+        let mut ids = Vec::new();
+        for report in self.reports.iter() {
+            if !ids.contains(&report.report_id) {
+                ids.push(report.report_id);
+            }
+        }
+        ids
+    }
+
+    /// Returns the layout of the given report
+    pub fn layout(&self, report_type: HidReportType, report_id: u8) -> Option<&ReportLayout> {
+        self.reports
+            .iter()
+            .find(|r| r.report_type == report_type && r.report_id == report_id)
+    }
+
+    /// Returns the fields of the given report
+    pub fn fields(&self, report_type: HidReportType, report_id: u8) -> &[Field] {
+        match self.layout(report_type, report_id) {
+            Some(layout) => layout.fields.as_slice(),
+            None => &[],
+        }
+    }
+
+    /// Returns the first report layout of the given type that contains the
+    /// given usage.
+    pub fn find(&self, report_type: HidReportType, usage: Usage) -> Option<&ReportLayout> {
+        self.reports
+            .iter()
+            .filter(|r| r.report_type == report_type)
+            .find(|r| r.field(usage).is_some())
+    }
+}
+
+impl Display for ReportDescriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text: Vec<String> = self.reports.iter().map(|r| r.to_string()).collect();
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::hid::presets::{
+        ConsumerControl, Gamepad, Mouse, SystemControl, Touchscreen,
+        CONSUMER_CONTROL_REPORT_DESCRIPTOR, GAMEPAD_REPORT_DESCRIPTOR, KEYBOARD_REPORT_DESCRIPTOR,
+        MOUSE_REPORT_DESCRIPTOR, SYSTEM_CONTROL_REPORT_DESCRIPTOR, TOUCHSCREEN_REPORT_DESCRIPTOR,
+    };
+
+    #[cfg(feature = "steam-deck")]
+    #[test]
+    fn parse_steam_deck_controller() {
+        use crate::devices::steam_deck::descriptor::CONTROLLER_DESCRIPTOR;
+
+        let desc = ReportDescriptor::parse(&CONTROLLER_DESCRIPTOR).unwrap();
+        assert_eq!(desc.report_ids(), [0]);
+        assert_eq!(desc.collections.len(), 1);
+        for report_type in [HidReportType::Input, HidReportType::Feature] {
+            let layout = desc.layout(report_type, 0).unwrap();
+            assert_eq!(layout.size(), 64);
+            assert_eq!(layout.fields.len(), 64);
+        }
+        assert!(desc.layout(HidReportType::Output, 0).is_none());
+    }
+
+    #[test]
+    fn parse_keyboard_preset() {
+        let desc = ReportDescriptor::parse(KEYBOARD_REPORT_DESCRIPTOR).unwrap();
+        let input = desc.layout(HidReportType::Input, 0).unwrap();
+        assert_eq!(input.size(), 8);
+        let output = desc.layout(HidReportType::Output, 0).unwrap();
+        assert_eq!(output.size(), 1);
+    }
+
+    #[test]
+    fn parse_presets() {
+        let presets = [
+            (MOUSE_REPORT_DESCRIPTOR, Mouse::new(1).report()),
+            (GAMEPAD_REPORT_DESCRIPTOR, Gamepad::new(1).report()),
+            (
+                CONSUMER_CONTROL_REPORT_DESCRIPTOR,
+                ConsumerControl::new(1).report(),
+            ),
+            (
+                SYSTEM_CONTROL_REPORT_DESCRIPTOR,
+                SystemControl::new(1).report(),
+            ),
+            (TOUCHSCREEN_REPORT_DESCRIPTOR, Touchscreen::new(1).report()),
+        ];
+        for (data, report) in presets {
+            let desc = ReportDescriptor::parse(data).unwrap();
+            // The input report produced by the preset matches its layout
+            let report_id = match desc.report_ids().as_slice() {
+                [0] => 0,
+                _ => report[0],
+            };
+            let input = desc.layout(HidReportType::Input, report_id).unwrap();
+            assert_eq!(input.size(), report.len());
+        }
+    }
+
+    #[test]
+    fn reject_oversized_report() {
+        let data = [
+            0x05, 0x01, // Usage Page (Generic Desktop)
+            0x09, 0x00, // Usage (Undefined)
+            0x75, 0x08, // Report Size (8)
+            0x97, 0xff, 0xff, 0xff, 0x7f, // Report Count (2147483647)
+            0x81, 0x02, // Input (Data,Var,Abs)
+        ];
+        assert!(ReportDescriptor::parse(&data).is_err());
+
+        // Zero-sized fields are bounded as well
+        let data = [
+            0x75, 0x00, // Report Size (0)
+            0x97, 0xff, 0xff, 0xff, 0x7f, // Report Count (2147483647)
+            0x81, 0x02, // Input (Data,Var,Abs)
+        ];
+        assert!(ReportDescriptor::parse(&data).is_err());
+    }
+
+    /// Mouse with three buttons, relative X/Y axes and a two key array
+    const MOUSE_WITH_KEYS: &[u8] = &[
+        0x05, 0x01, // Usage Page (Generic Desktop)
+        0x09, 0x02, // Usage (Mouse)
+        0xa1, 0x01, // Collection (Application)
+        0x85, 0x01, //   Report ID (1)
+        0x05, 0x09, //   Usage Page (Button)
+        0x19, 0x01, //   Usage Minimum (1)
+        0x29, 0x03, //   Usage Maximum (3)
+        0x15, 0x00, //   Logical Minimum (0)
+        0x25, 0x01, //   Logical Maximum (1)
+        0x75, 0x01, //   Report Size (1)
+        0x95, 0x03, //   Report Count (3)
+        0x81, 0x02, //   Input (Data,Var,Abs)
+        0x95, 0x05, //   Report Count (5)
+        0x81, 0x01, //   Input (Const)
+        0x05, 0x01, //   Usage Page (Generic Desktop)
+        0x09, 0x30, //   Usage (X)
+        0x09, 0x31, //   Usage (Y)
+        0x15, 0x81, //   Logical Minimum (-127)
+        0x25, 0x7f, //   Logical Maximum (127)
+        0x75, 0x08, //   Report Size (8)
+        0x95, 0x02, //   Report Count (2)
+        0x81, 0x06, //   Input (Data,Var,Rel)
+        0x05, 0x07, //   Usage Page (Keyboard)
+        0x19, 0x00, //   Usage Minimum (0)
+        0x29, 0x65, //   Usage Maximum (101)
+        0x15, 0x00, //   Logical Minimum (0)
+        0x25, 0x65, //   Logical Maximum (101)
+        0x95, 0x02, //   Report Count (2)
+        0x81, 0x00, //   Input (Data,Array,Abs)
+        0xc0, // End Collection
+    ];
+
+    fn mouse_layout() -> ReportLayout {
+        let desc = ReportDescriptor::parse(MOUSE_WITH_KEYS).unwrap();
+        assert_eq!(desc.report_ids(), [1]);
+        desc.layout(HidReportType::Input, 1).unwrap().clone()
+    }
+
+    #[test]
+    fn field_offsets() {
+        let layout = mouse_layout();
+        assert_eq!(layout.size(), 6);
+        assert_eq!(layout.new_report(), [1, 0, 0, 0, 0, 0]);
+
+        // Fields start after the report ID byte
+        let offsets = [
+            (Usage::button(1), 8, 1),
+            (Usage::button(3), 10, 1),
+            (Usage::X, 16, 8),
+            (Usage::Y, 24, 8),
+            (Usage::keyboard(0x04), 32, 8),
+        ];
+        for (usage, bit_offset, bit_size) in offsets {
+            let field = layout.field(usage).unwrap();
+            assert_eq!((field.bit_offset, field.bit_size), (bit_offset, bit_size));
+        }
+
+        // The padding cannot be addressed by usage
+        assert!(layout.fields.iter().any(|f| f.flags.is_constant()));
+        assert_eq!(layout.usages().len(), 3 + 2 + 102);
+    }
+
+    #[test]
+    fn signed_logical_range() {
+        let layout = mouse_layout();
+        let x = layout.field(Usage::X).unwrap();
+        assert_eq!((x.logical_minimum, x.logical_maximum), (-127, 127));
+
+        let mut report = layout.new_report();
+        layout.set(&mut report, Usage::X, -5).unwrap();
+        assert_eq!(report[2], 0xfb);
+        assert_eq!(layout.get(&report, Usage::X), Some(-5));
+
+        // Values outside of the logical range are clamped
+        layout.set(&mut report, Usage::X, -200).unwrap();
+        layout.set(&mut report, Usage::Y, 200).unwrap();
+        assert_eq!(&report[2..4], [0x81, 0x7f]);
+        assert_eq!(layout.get(&report, Usage::X), Some(-127));
+        assert_eq!(layout.get(&report, Usage::Y), Some(127));
+    }
+
+    #[test]
+    fn get_and_set_variable_fields() {
+        let layout = mouse_layout();
+        let mut report = layout.new_report();
+        layout.set(&mut report, Usage::button(2), 1).unwrap();
+        layout.set(&mut report, Usage::button(3), 1).unwrap();
+        assert_eq!(report[1], 0b110);
+        assert_eq!(layout.get(&report, Usage::button(1)), Some(0));
+        assert_eq!(layout.get(&report, Usage::button(2)), Some(1));
+
+        layout.set(&mut report, Usage::button(3), 0).unwrap();
+        assert_eq!(report[1], 0b010);
+        assert_eq!(report[0], 1);
+
+        // Usages without a field are rejected
+        assert!(layout.set(&mut report, Usage::WHEEL, 1).is_err());
+        assert_eq!(layout.get(&report, Usage::WHEEL), None);
+    }
+
+    #[test]
+    fn get_and_set_array_fields() {
+        let layout = mouse_layout();
+        let mut report = layout.new_report();
+        let (a, b, c) = (
+            Usage::keyboard(0x04),
+            Usage::keyboard(0x05),
+            Usage::keyboard(0x06),
+        );
+
+        layout.set(&mut report, a, 1).unwrap();
+        layout.set(&mut report, b, 1).unwrap();
+        assert_eq!(&report[4..], [0x04, 0x05]);
+        assert_eq!(layout.get(&report, a), Some(1));
+        assert_eq!(layout.get(&report, c), Some(0));
+
+        // Setting a usage twice does not take another slot
+        layout.set(&mut report, a, 1).unwrap();
+        assert_eq!(&report[4..], [0x04, 0x05]);
+        assert!(layout.set(&mut report, c, 1).is_err());
+
+        // Clearing a usage frees its slot
+        layout.set(&mut report, a, 0).unwrap();
+        assert_eq!(&report[4..], [0x00, 0x05]);
+        layout.set(&mut report, c, 1).unwrap();
+        assert_eq!(&report[4..], [0x06, 0x05]);
+    }
+
+    #[test]
+    fn parse_usage_names() {
+        assert_eq!("X".parse(), Ok(Usage::X));
+        assert_eq!("hat switch".parse(), Ok(Usage::HAT_SWITCH));
+        assert_eq!("Button 1".parse(), Ok(Usage::button(1)));
+        assert_eq!("Key 0x04".parse(), Ok(Usage::keyboard(0x04)));
+        assert_eq!("0xff00:0x01".parse(), Ok(Usage::new(0xff00, 0x01)));
+        assert!("Button one".parse::<Usage>().is_err());
+        assert!("Throttle".parse::<Usage>().is_err());
+
+        // Names round trip through Display
+        for usage in [Usage::RZ, Usage::button(12), Usage::new(0xff00, 0x01)] {
+            assert_eq!(usage.to_string().parse(), Ok(usage));
+        }
+    }
+}
@@ -0,0 +1,1078 @@
+//! Ready-made HID devices
+//!
+//! Each preset carries a report descriptor and an interrupt IN endpoint, and
+//! can build the [Interface] to add to a [crate::usb::ConfigurationBuilder].
+//! The preset keeps track of its own state and produces the matching input
+//! report bytes with `report()`, which can be passed to
+//! [crate::virtual_usb::VirtualUSBDevice::set_report] using the preset's
+//! endpoint number.
+//!
+//! The [Keyboard] and [Mouse] presets are boot interfaces, whose protocol
+//! requests are answered by the virtual device (see
+//! [crate::virtual_usb::VirtualUSBDevice::hid_protocol]).
+
+use std::{error::Error, time::Instant};
+
+use crate::usb::{
+    Direction, EndpointBuilder, EndpointDescriptor, Interface, SynchronizationType, TransferType,
+    UsageType,
+};
+
+use super::{
+    HidInterfaceBuilder, HidReportType, HidSubclass, InterfaceProtocol, ReportDescriptor,
+    ReportLayout, Usage,
+};
+
+/// Default polling interval of preset endpoints. This is 1ms at high speed
+/// and 4ms at full speed.
+pub const DEFAULT_INTERVAL: u8 = 4;
+
+/// Returns an interrupt IN endpoint descriptor for a preset
+fn interrupt_in_endpoint(num: u8, max_packet_size: u16, interval: u8) -> EndpointDescriptor {
+    EndpointBuilder::new()
+        .address_num(num)
+        .direction(Direction::In)
+        .transfer_type(TransferType::Interrupt)
+        .sync_type(SynchronizationType::NoSynchronization)
+        .usage_type(UsageType::Data)
+        .max_packet_size(max_packet_size)
+        .interval(interval)
+        .build()
+}
+
+/// Report descriptor of a boot protocol keyboard (HID 1.11 Appendix B.1)
+#[rustfmt::skip]
+pub const KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xa1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Keyboard)
+    0x19, 0xe0, //   Usage Minimum (Left Control)
+    0x29, 0xe7, //   Usage Maximum (Right GUI)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data,Var,Abs)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Const)
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (Num Lock)
+    0x29, 0x05, //   Usage Maximum (Kana)
+    0x91, 0x02, //   Output (Data,Var,Abs)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Const)
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x65, //   Logical Maximum (101)
+    0x05, 0x07, //   Usage Page (Keyboard)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0x65, //   Usage Maximum (101)
+    0x81, 0x00, //   Input (Data,Array,Abs)
+    0xc0,       // End Collection
+];
+
+/// Report descriptor of a boot protocol mouse with five buttons and a wheel
+#[rustfmt::skip]
+pub const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xa1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Button)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x05, //     Usage Maximum (5)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x05, //     Report Count (5)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data,Var,Abs)
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x03, //     Report Size (3)
+    0x81, 0x01, //     Input (Const)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x09, 0x38, //     Usage (Wheel)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7f, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x03, //     Report Count (3)
+    0x81, 0x06, //     Input (Data,Var,Rel)
+    0xc0,       //   End Collection
+    0xc0,       // End Collection
+];
+
+/// Report descriptor of a generic gamepad with 16 buttons, a hat switch and
+/// six 16-bit axes
+#[rustfmt::skip]
+pub const GAMEPAD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x05,       // Usage (Game Pad)
+    0xa1, 0x01,       // Collection (Application)
+    0x05, 0x09,       //   Usage Page (Button)
+    0x19, 0x01,       //   Usage Minimum (1)
+    0x29, 0x10,       //   Usage Maximum (16)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x01,       //   Logical Maximum (1)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x10,       //   Report Count (16)
+    0x81, 0x02,       //   Input (Data,Var,Abs)
+    0x05, 0x01,       //   Usage Page (Generic Desktop)
+    0x09, 0x39,       //   Usage (Hat switch)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x07,       //   Logical Maximum (7)
+    0x35, 0x00,       //   Physical Minimum (0)
+    0x46, 0x3b, 0x01, //   Physical Maximum (315)
+    0x65, 0x14,       //   Unit (Degrees)
+    0x75, 0x04,       //   Report Size (4)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x42,       //   Input (Data,Var,Abs,Null)
+    0x65, 0x00,       //   Unit (None)
+    0x45, 0x00,       //   Physical Maximum (0)
+    0x81, 0x01,       //   Input (Const)
+    0x09, 0x30,       //   Usage (X)
+    0x09, 0x31,       //   Usage (Y)
+    0x09, 0x32,       //   Usage (Z)
+    0x09, 0x35,       //   Usage (Rz)
+    0x09, 0x33,       //   Usage (Rx)
+    0x09, 0x34,       //   Usage (Ry)
+    0x16, 0x01, 0x80, //   Logical Minimum (-32767)
+    0x26, 0xff, 0x7f, //   Logical Maximum (32767)
+    0x75, 0x10,       //   Report Size (16)
+    0x95, 0x06,       //   Report Count (6)
+    0x81, 0x02,       //   Input (Data,Var,Abs)
+    0xc0,             // End Collection
+];
+
+/// Report descriptor of a consumer control device (media keys)
+#[rustfmt::skip]
+pub const CONSUMER_CONTROL_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x0c,       // Usage Page (Consumer)
+    0x09, 0x01,       // Usage (Consumer Control)
+    0xa1, 0x01,       // Collection (Application)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xff, 0x03, //   Logical Maximum (1023)
+    0x19, 0x00,       //   Usage Minimum (0)
+    0x2a, 0xff, 0x03, //   Usage Maximum (1023)
+    0x75, 0x10,       //   Report Size (16)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x00,       //   Input (Data,Array,Abs)
+    0xc0,             // End Collection
+];
+
+/// Report descriptor of a system control device (power and sleep keys)
+#[rustfmt::skip]
+pub const SYSTEM_CONTROL_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x80, // Usage (System Control)
+    0xa1, 0x01, // Collection (Application)
+    0x19, 0x81, //   Usage Minimum (System Power Down)
+    0x29, 0x83, //   Usage Maximum (System Wake Up)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x03, //   Report Count (3)
+    0x81, 0x02, //   Input (Data,Var,Abs)
+    0x95, 0x05, //   Report Count (5)
+    0x81, 0x01, //   Input (Const)
+    0xc0,       // End Collection
+];
+
+/// Report descriptor header of the touchscreen, up to the first finger
+#[rustfmt::skip]
+const TOUCHSCREEN_HEADER: &[u8] = &[
+    0x05, 0x0d, // Usage Page (Digitizer)
+    0x09, 0x04, // Usage (Touch Screen)
+    0xa1, 0x01, // Collection (Application)
+    0x85, Touchscreen::INPUT_REPORT_ID, //   Report ID
+];
+
+/// Report descriptor of a single finger of the touchscreen
+#[rustfmt::skip]
+const TOUCHSCREEN_FINGER: &[u8] = &[
+    0x05, 0x0d,       //   Usage Page (Digitizer)
+    0x09, 0x22,       //   Usage (Finger)
+    0xa1, 0x02,       //   Collection (Logical)
+    0x09, 0x42,       //     Usage (Tip Switch)
+    0x09, 0x47,       //     Usage (Confidence)
+    0x15, 0x00,       //     Logical Minimum (0)
+    0x25, 0x01,       //     Logical Maximum (1)
+    0x75, 0x01,       //     Report Size (1)
+    0x95, 0x02,       //     Report Count (2)
+    0x81, 0x02,       //     Input (Data,Var,Abs)
+    0x95, 0x06,       //     Report Count (6)
+    0x81, 0x03,       //     Input (Const,Var)
+    0x09, 0x51,       //     Usage (Contact Identifier)
+    0x26, 0xff, 0x00, //     Logical Maximum (255)
+    0x75, 0x08,       //     Report Size (8)
+    0x95, 0x01,       //     Report Count (1)
+    0x81, 0x02,       //     Input (Data,Var,Abs)
+    0x05, 0x01,       //     Usage Page (Generic Desktop)
+    0x55, 0x0e,       //     Unit Exponent (-2)
+    0x65, 0x11,       //     Unit (Centimeter)
+    0x26, 0xff, 0x0f, //     Logical Maximum (4095)
+    0x35, 0x00,       //     Physical Minimum (0)
+    0x46, 0xa2, 0x08, //     Physical Maximum (2210)
+    0x75, 0x10,       //     Report Size (16)
+    0x09, 0x30,       //     Usage (X)
+    0x81, 0x02,       //     Input (Data,Var,Abs)
+    0x46, 0xd8, 0x04, //     Physical Maximum (1240)
+    0x09, 0x31,       //     Usage (Y)
+    0x81, 0x02,       //     Input (Data,Var,Abs)
+    0x55, 0x00,       //     Unit Exponent (0)
+    0x65, 0x00,       //     Unit (None)
+    0x45, 0x00,       //     Physical Maximum (0)
+    0xc0,             //   End Collection
+];
+
+/// Report descriptor footer of the touchscreen, after the last finger
+#[rustfmt::skip]
+const TOUCHSCREEN_FOOTER: &[u8] = &[
+    0x05, 0x0d,                   //   Usage Page (Digitizer)
+    0x55, 0x0c,                   //   Unit Exponent (-4)
+    0x66, 0x01, 0x10,             //   Unit (Seconds)
+    0x27, 0xff, 0xff, 0x00, 0x00, //   Logical Maximum (65535)
+    0x75, 0x10,                   //   Report Size (16)
+    0x95, 0x01,                   //   Report Count (1)
+    0x09, 0x56,                   //   Usage (Scan Time)
+    0x81, 0x02,                   //   Input (Data,Var,Abs)
+    0x55, 0x00,                   //   Unit Exponent (0)
+    0x65, 0x00,                   //   Unit (None)
+    0x09, 0x54,                   //   Usage (Contact Count)
+    0x25, Touchscreen::MAX_CONTACTS,    //   Logical Maximum
+    0x75, 0x08,                   //   Report Size (8)
+    0x81, 0x02,                   //   Input (Data,Var,Abs)
+    0x85, Touchscreen::FEATURE_REPORT_ID, //   Report ID
+    0x09, 0x55,                   //   Usage (Contact Count Maximum)
+    0xb1, 0x02,                   //   Feature (Data,Var,Abs)
+    0xc0,                         // End Collection
+];
+
+const TOUCHSCREEN_REPORT_DESCRIPTOR_SIZE: usize = TOUCHSCREEN_HEADER.len()
+    + TOUCHSCREEN_FINGER.len() * Touchscreen::MAX_CONTACTS as usize
+    + TOUCHSCREEN_FOOTER.len();
+
+/// Concatenate the touchscreen header, one finger collection per contact
+/// and the footer.
+const fn touchscreen_report_descriptor() -> [u8; TOUCHSCREEN_REPORT_DESCRIPTOR_SIZE] {
+    let mut descriptor = [0; TOUCHSCREEN_REPORT_DESCRIPTOR_SIZE];
+    let mut offset = 0;
+    let mut i = 0;
+    while i < TOUCHSCREEN_HEADER.len() {
+        descriptor[offset] = TOUCHSCREEN_HEADER[i];
+        offset += 1;
+        i += 1;
+    }
+    let mut finger = 0;
+    while finger < Touchscreen::MAX_CONTACTS {
+        let mut i = 0;
+        while i < TOUCHSCREEN_FINGER.len() {
+            descriptor[offset] = TOUCHSCREEN_FINGER[i];
+            offset += 1;
+            i += 1;
+        }
+        finger += 1;
+    }
+    let mut i = 0;
+    while i < TOUCHSCREEN_FOOTER.len() {
+        descriptor[offset] = TOUCHSCREEN_FOOTER[i];
+        offset += 1;
+        i += 1;
+    }
+    descriptor
+}
+
+/// Report descriptor of a Windows compatible multi-touch touchscreen with
+/// ten contacts reported in parallel mode
+pub const TOUCHSCREEN_REPORT_DESCRIPTOR: &[u8] = &touchscreen_report_descriptor();
+
+/// Keyboard usage IDs (HID Usage Tables, Keyboard/Keypad page)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyCode {
+    A = 0x04,
+    B = 0x05,
+    C = 0x06,
+    D = 0x07,
+    E = 0x08,
+    F = 0x09,
+    G = 0x0a,
+    H = 0x0b,
+    I = 0x0c,
+    J = 0x0d,
+    K = 0x0e,
+    L = 0x0f,
+    M = 0x10,
+    N = 0x11,
+    O = 0x12,
+    P = 0x13,
+    Q = 0x14,
+    R = 0x15,
+    S = 0x16,
+    T = 0x17,
+    U = 0x18,
+    V = 0x19,
+    W = 0x1a,
+    X = 0x1b,
+    Y = 0x1c,
+    Z = 0x1d,
+    Num1 = 0x1e,
+    Num2 = 0x1f,
+    Num3 = 0x20,
+    Num4 = 0x21,
+    Num5 = 0x22,
+    Num6 = 0x23,
+    Num7 = 0x24,
+    Num8 = 0x25,
+    Num9 = 0x26,
+    Num0 = 0x27,
+    Enter = 0x28,
+    Escape = 0x29,
+    Backspace = 0x2a,
+    Tab = 0x2b,
+    Space = 0x2c,
+    Minus = 0x2d,
+    Equal = 0x2e,
+    LeftBracket = 0x2f,
+    RightBracket = 0x30,
+    Backslash = 0x31,
+    NonUsHash = 0x32,
+    Semicolon = 0x33,
+    Apostrophe = 0x34,
+    Grave = 0x35,
+    Comma = 0x36,
+    Dot = 0x37,
+    Slash = 0x38,
+    CapsLock = 0x39,
+    F1 = 0x3a,
+    F2 = 0x3b,
+    F3 = 0x3c,
+    F4 = 0x3d,
+    F5 = 0x3e,
+    F6 = 0x3f,
+    F7 = 0x40,
+    F8 = 0x41,
+    F9 = 0x42,
+    F10 = 0x43,
+    F11 = 0x44,
+    F12 = 0x45,
+    PrintScreen = 0x46,
+    ScrollLock = 0x47,
+    Pause = 0x48,
+    Insert = 0x49,
+    Home = 0x4a,
+    PageUp = 0x4b,
+    Delete = 0x4c,
+    End = 0x4d,
+    PageDown = 0x4e,
+    Right = 0x4f,
+    Left = 0x50,
+    Down = 0x51,
+    Up = 0x52,
+    NumLock = 0x53,
+    KpSlash = 0x54,
+    KpAsterisk = 0x55,
+    KpMinus = 0x56,
+    KpPlus = 0x57,
+    KpEnter = 0x58,
+    Kp1 = 0x59,
+    Kp2 = 0x5a,
+    Kp3 = 0x5b,
+    Kp4 = 0x5c,
+    Kp5 = 0x5d,
+    Kp6 = 0x5e,
+    Kp7 = 0x5f,
+    Kp8 = 0x60,
+    Kp9 = 0x61,
+    Kp0 = 0x62,
+    KpDot = 0x63,
+    NonUsBackslash = 0x64,
+    Application = 0x65,
+    LeftCtrl = 0xe0,
+    LeftShift = 0xe1,
+    LeftAlt = 0xe2,
+    LeftMeta = 0xe3,
+    RightCtrl = 0xe4,
+    RightShift = 0xe5,
+    RightAlt = 0xe6,
+    RightMeta = 0xe7,
+}
+
+impl KeyCode {
+    /// Returns true if the key is a modifier (Ctrl, Shift, Alt or Meta)
+    pub fn is_modifier(&self) -> bool {
+        (*self as u8) >= KeyCode::LeftCtrl as u8
+    }
+
+    /// Returns the bit of the key in the modifier byte of a boot keyboard
+    /// report, or None if the key is not a modifier.
+    pub fn modifier_bit(&self) -> Option<u8> {
+        if !self.is_modifier() {
+            return None;
+        }
+        Some(1 << (*self as u8 - KeyCode::LeftCtrl as u8))
+    }
+}
+
+/// Keyboard LEDs set by the host with the keyboard output report
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyboardLed {
+    NumLock = 0x01,
+    CapsLock = 0x02,
+    ScrollLock = 0x04,
+    Compose = 0x08,
+    Kana = 0x10,
+}
+
+/// Boot protocol keyboard with up to six simultaneous non-modifier keys
+#[derive(Debug, Clone)]
+pub struct Keyboard {
+    /// Number of the interrupt IN endpoint
+    pub endpoint: u8,
+    /// Polling interval of the endpoint (bInterval)
+    pub interval: u8,
+    modifiers: u8,
+    keys: Vec<KeyCode>,
+    leds: u8,
+}
+
+impl Keyboard {
+    /// Size of the keyboard input report in bytes
+    pub const REPORT_SIZE: usize = 8;
+    /// Error code reported in every key slot when too many keys are pressed
+    const ERROR_ROLL_OVER: u8 = 0x01;
+
+    /// Create a new keyboard using the given IN endpoint number
+    pub fn new(endpoint: u8) -> Self {
+        Self {
+            endpoint,
+            interval: DEFAULT_INTERVAL,
+            modifiers: 0,
+            keys: Vec::new(),
+            leds: 0,
+        }
+    }
+
+    /// Build the HID interface of the keyboard
+    pub fn interface(&self) -> Interface {
+        HidInterfaceBuilder::new()
+            .subclass(HidSubclass::Boot)
+            .protocol(InterfaceProtocol::Keyboard)
+            .report_descriptor(KEYBOARD_REPORT_DESCRIPTOR)
+            .endpoint_descriptor(interrupt_in_endpoint(
+                self.endpoint,
+                Self::REPORT_SIZE as u16,
+                self.interval,
+            ))
+            .build()
+    }
+
+    /// Press the given key
+    pub fn press(&mut self, key: KeyCode) {
+        if let Some(bit) = key.modifier_bit() {
+            self.modifiers |= bit;
+            return;
+        }
+        if !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+    }
+
+    /// Release the given key
+    pub fn release(&mut self, key: KeyCode) {
+        if let Some(bit) = key.modifier_bit() {
+            self.modifiers &= !bit;
+            return;
+        }
+        self.keys.retain(|k| *k != key);
+    }
+
+    /// Release all keys
+    pub fn release_all(&mut self) {
+        self.modifiers = 0;
+        self.keys.clear();
+    }
+
+    /// Returns true if the given key is pressed
+    pub fn is_pressed(&self, key: KeyCode) -> bool {
+        match key.modifier_bit() {
+            Some(bit) => self.modifiers & bit != 0,
+            None => self.keys.contains(&key),
+        }
+    }
+
+    /// Returns the input report for the current key state. If more than six
+    /// non-modifier keys are pressed, every key slot reports ErrorRollOver.
+    pub fn report(&self) -> Vec<u8> {
+        let mut report = vec![0; Self::REPORT_SIZE];
+        report[0] = self.modifiers;
+        if self.keys.len() > 6 {
+            report[2..].fill(Self::ERROR_ROLL_OVER);
+            return report;
+        }
+        for (slot, key) in self.keys.iter().enumerate() {
+            report[2 + slot] = *key as u8;
+        }
+        report
+    }
+
+    /// Update the LED state from an output report sent by the host
+    pub fn set_output_report(&mut self, report: &[u8]) {
+        if let Some(leds) = report.first() {
+            self.leds = *leds;
+        }
+    }
+
+    /// Returns true if the host turned on the given LED
+    pub fn led(&self, led: KeyboardLed) -> bool {
+        self.leds & led as u8 != 0
+    }
+}
+
+/// Mouse buttons
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MouseButton {
+    Left = 0x01,
+    Right = 0x02,
+    Middle = 0x04,
+    Back = 0x08,
+    Forward = 0x10,
+}
+
+/// Boot protocol mouse with five buttons and a wheel.
+///
+/// Motion is relative, so reports should be sent with
+/// [crate::virtual_usb::VirtualUSBDevice::queue_report] using
+/// [Mouse::take_report], which consumes the accumulated motion.
+#[derive(Debug, Clone)]
+pub struct Mouse {
+    /// Number of the interrupt IN endpoint
+    pub endpoint: u8,
+    /// Polling interval of the endpoint (bInterval)
+    pub interval: u8,
+    buttons: u8,
+    x: i32,
+    y: i32,
+    wheel: i32,
+}
+
+impl Mouse {
+    /// Size of the mouse input report in bytes
+    pub const REPORT_SIZE: usize = 4;
+
+    /// Create a new mouse using the given IN endpoint number
+    pub fn new(endpoint: u8) -> Self {
+        Self {
+            endpoint,
+            interval: DEFAULT_INTERVAL,
+            buttons: 0,
+            x: 0,
+            y: 0,
+            wheel: 0,
+        }
+    }
+
+    /// Build the HID interface of the mouse
+    pub fn interface(&self) -> Interface {
+        HidInterfaceBuilder::new()
+            .subclass(HidSubclass::Boot)
+            .protocol(InterfaceProtocol::Mouse)
+            .report_descriptor(MOUSE_REPORT_DESCRIPTOR)
+            .endpoint_descriptor(interrupt_in_endpoint(
+                self.endpoint,
+                Self::REPORT_SIZE as u16,
+                self.interval,
+            ))
+            .build()
+    }
+
+    /// Press the given button
+    pub fn press(&mut self, button: MouseButton) {
+        self.buttons |= button as u8;
+    }
+
+    /// Release the given button
+    pub fn release(&mut self, button: MouseButton) {
+        self.buttons &= !(button as u8);
+    }
+
+    /// Returns true if the given button is pressed
+    pub fn is_pressed(&self, button: MouseButton) -> bool {
+        self.buttons & button as u8 != 0
+    }
+
+    /// Move the pointer by the given relative amount
+    pub fn move_rel(&mut self, dx: i32, dy: i32) {
+        self.x = self.x.saturating_add(dx);
+        self.y = self.y.saturating_add(dy);
+    }
+
+    /// Scroll the wheel by the given number of detents. Positive values
+    /// scroll up.
+    pub fn scroll(&mut self, amount: i32) {
+        self.wheel = self.wheel.saturating_add(amount);
+    }
+
+    /// Returns true if there is motion that has not been reported yet
+    pub fn has_motion(&self) -> bool {
+        self.x != 0 || self.y != 0 || self.wheel != 0
+    }
+
+    /// Returns the input report for the current state without consuming
+    /// any motion.
+    pub fn report(&self) -> Vec<u8> {
+        let x = self.x.clamp(-127, 127) as i8;
+        let y = self.y.clamp(-127, 127) as i8;
+        let wheel = self.wheel.clamp(-127, 127) as i8;
+        vec![self.buttons, x as u8, y as u8, wheel as u8]
+    }
+
+    /// Returns the input report for the current state and consumes the
+    /// reported motion. Motion larger than a single report can hold is
+    /// carried over to the next report.
+    pub fn take_report(&mut self) -> Vec<u8> {
+        let report = self.report();
+        self.x -= report[1] as i8 as i32;
+        self.y -= report[2] as i8 as i32;
+        self.wheel -= report[3] as i8 as i32;
+        report
+    }
+}
+
+/// Gamepad axes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Axis {
+    /// Left stick X
+    X,
+    /// Left stick Y
+    Y,
+    /// Right stick X
+    Z,
+    /// Right stick Y
+    Rz,
+    /// Left trigger
+    Rx,
+    /// Right trigger
+    Ry,
+}
+
+impl From<Axis> for Usage {
+    fn from(axis: Axis) -> Self {
+        match axis {
+            Axis::X => Usage::X,
+            Axis::Y => Usage::Y,
+            Axis::Z => Usage::Z,
+            Axis::Rz => Usage::RZ,
+            Axis::Rx => Usage::RX,
+            Axis::Ry => Usage::RY,
+        }
+    }
+}
+
+/// Gamepad hat switch (D-pad) positions
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Hat {
+    Up = 0,
+    UpRight = 1,
+    Right = 2,
+    DownRight = 3,
+    Down = 4,
+    DownLeft = 5,
+    Left = 6,
+    UpLeft = 7,
+    /// Outside of the logical range, which the descriptor declares as the
+    /// null state
+    Centered = 8,
+}
+
+/// Generic gamepad with 16 buttons, a hat switch and six axes
+#[derive(Debug, Clone)]
+pub struct Gamepad {
+    /// Number of the interrupt IN endpoint
+    pub endpoint: u8,
+    /// Polling interval of the endpoint (bInterval)
+    pub interval: u8,
+    layout: ReportLayout,
+    report: Vec<u8>,
+}
+
+impl Gamepad {
+    /// Number of buttons on the gamepad
+    pub const BUTTONS: u8 = 16;
+
+    /// Create a new gamepad using the given IN endpoint number
+    pub fn new(endpoint: u8) -> Self {
+        let descriptor = ReportDescriptor::parse(GAMEPAD_REPORT_DESCRIPTOR).unwrap();
+        let layout = descriptor.layout(HidReportType::Input, 0).unwrap().clone();
+        let report = layout.new_report();
+        let mut gamepad = Self {
+            endpoint,
+            interval: DEFAULT_INTERVAL,
+            layout,
+            report,
+        };
+        gamepad.set_hat(Hat::Centered);
+        gamepad
+    }
+
+    /// Build the HID interface of the gamepad
+    pub fn interface(&self) -> Interface {
+        HidInterfaceBuilder::new()
+            .report_descriptor(GAMEPAD_REPORT_DESCRIPTOR)
+            .endpoint_descriptor(interrupt_in_endpoint(
+                self.endpoint,
+                self.layout.size() as u16,
+                self.interval,
+            ))
+            .build()
+    }
+
+    /// Set the value of the given axis
+    pub fn set_axis(&mut self, axis: Axis, value: i16) {
+        let _ = self.layout.set(&mut self.report, axis.into(), value as i32);
+    }
+
+    /// Returns the value of the given axis
+    pub fn axis(&self, axis: Axis) -> i16 {
+        self.layout
+            .get(&self.report, axis.into())
+            .unwrap_or_default() as i16
+    }
+
+    /// Press the given button (1-16)
+    pub fn press(&mut self, button: u8) {
+        let _ = self
+            .layout
+            .set(&mut self.report, Usage::button(button as u16), 1);
+    }
+
+    /// Release the given button (1-16)
+    pub fn release(&mut self, button: u8) {
+        let _ = self
+            .layout
+            .set(&mut self.report, Usage::button(button as u16), 0);
+    }
+
+    /// Returns true if the given button (1-16) is pressed
+    pub fn is_pressed(&self, button: u8) -> bool {
+        self.layout.get(&self.report, Usage::button(button as u16)) == Some(1)
+    }
+
+    /// Set the position of the hat switch
+    pub fn set_hat(&mut self, hat: Hat) {
+        // Written directly since the centered position is outside of the
+        // logical range of the field.
+        if let Some(field) = self.layout.field(Usage::HAT_SWITCH) {
+            let _ = field.write(&mut self.report, hat as i32);
+        }
+    }
+
+    /// Returns the input report for the current state
+    pub fn report(&self) -> Vec<u8> {
+        self.report.clone()
+    }
+}
+
+/// Commonly used usages of the Consumer page
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConsumerUsage {
+    BrightnessUp = 0x006f,
+    BrightnessDown = 0x0070,
+    ScanNextTrack = 0x00b5,
+    ScanPreviousTrack = 0x00b6,
+    Stop = 0x00b7,
+    Eject = 0x00b8,
+    PlayPause = 0x00cd,
+    Mute = 0x00e2,
+    VolumeUp = 0x00e9,
+    VolumeDown = 0x00ea,
+    Calculator = 0x0192,
+    Browser = 0x0196,
+    Search = 0x0221,
+    Home = 0x0223,
+    Back = 0x0224,
+    Forward = 0x0225,
+    Refresh = 0x0227,
+}
+
+/// Consumer control device (media keys) reporting one usage at a time
+#[derive(Debug, Clone)]
+pub struct ConsumerControl {
+    /// Number of the interrupt IN endpoint
+    pub endpoint: u8,
+    /// Polling interval of the endpoint (bInterval)
+    pub interval: u8,
+    pressed: Option<u16>,
+}
+
+impl ConsumerControl {
+    /// Size of the consumer control input report in bytes
+    pub const REPORT_SIZE: usize = 2;
+
+    /// Create a new consumer control device using the given IN endpoint
+    /// number
+    pub fn new(endpoint: u8) -> Self {
+        Self {
+            endpoint,
+            interval: DEFAULT_INTERVAL,
+            pressed: None,
+        }
+    }
+
+    /// Build the HID interface of the consumer control device
+    pub fn interface(&self) -> Interface {
+        HidInterfaceBuilder::new()
+            .report_descriptor(CONSUMER_CONTROL_REPORT_DESCRIPTOR)
+            .endpoint_descriptor(interrupt_in_endpoint(
+                self.endpoint,
+                Self::REPORT_SIZE as u16,
+                self.interval,
+            ))
+            .build()
+    }
+
+    /// Press the given usage, releasing any previously pressed usage
+    pub fn press(&mut self, usage: ConsumerUsage) {
+        self.press_raw(usage as u16);
+    }
+
+    /// Press the given Consumer page usage ID (0-1023)
+    pub fn press_raw(&mut self, usage_id: u16) {
+        self.pressed = Some(usage_id.min(0x3ff));
+    }
+
+    /// Release the pressed usage
+    pub fn release(&mut self) {
+        self.pressed = None;
+    }
+
+    /// Returns the input report for the current state
+    pub fn report(&self) -> Vec<u8> {
+        self.pressed.unwrap_or(0).to_le_bytes().to_vec()
+    }
+}
+
+/// System control usages of the Generic Desktop page
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SystemControlUsage {
+    PowerDown = 0x81,
+    Sleep = 0x82,
+    WakeUp = 0x83,
+}
+
+/// System control device with power down, sleep and wake up keys
+#[derive(Debug, Clone)]
+pub struct SystemControl {
+    /// Number of the interrupt IN endpoint
+    pub endpoint: u8,
+    /// Polling interval of the endpoint (bInterval)
+    pub interval: u8,
+    buttons: u8,
+}
+
+impl SystemControl {
+    /// Size of the system control input report in bytes
+    pub const REPORT_SIZE: usize = 1;
+
+    /// Create a new system control device using the given IN endpoint number
+    pub fn new(endpoint: u8) -> Self {
+        Self {
+            endpoint,
+            interval: DEFAULT_INTERVAL,
+            buttons: 0,
+        }
+    }
+
+    /// Build the HID interface of the system control device
+    pub fn interface(&self) -> Interface {
+        HidInterfaceBuilder::new()
+            .report_descriptor(SYSTEM_CONTROL_REPORT_DESCRIPTOR)
+            .endpoint_descriptor(interrupt_in_endpoint(
+                self.endpoint,
+                Self::REPORT_SIZE as u16,
+                self.interval,
+            ))
+            .build()
+    }
+
+    /// Press the given key
+    pub fn press(&mut self, usage: SystemControlUsage) {
+        self.buttons |= Self::bit(usage);
+    }
+
+    /// Release the given key
+    pub fn release(&mut self, usage: SystemControlUsage) {
+        self.buttons &= !Self::bit(usage);
+    }
+
+    /// Returns the input report for the current state
+    pub fn report(&self) -> Vec<u8> {
+        vec![self.buttons]
+    }
+
+    fn bit(usage: SystemControlUsage) -> u8 {
+        1 << (usage as u8 - SystemControlUsage::PowerDown as u8)
+    }
+}
+
+/// A finger touching the [Touchscreen]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Contact {
+    id: u8,
+    x: u16,
+    y: u16,
+    touching: bool,
+}
+
+/// Multi-touch touchscreen compatible with the Windows touchscreen
+/// requirements, which the Linux hid-multitouch driver binds to.
+///
+/// Fingers are placed, moved and lifted by contact ID. Each change should be
+/// sent with [crate::virtual_usb::VirtualUSBDevice::queue_report] using
+/// [Touchscreen::take_report], so that lifted fingers are reported once with
+/// their tip switch released. The Contact Count Maximum feature report is
+/// answered automatically by the interface.
+#[derive(Debug, Clone)]
+pub struct Touchscreen {
+    /// Number of the interrupt IN endpoint
+    pub endpoint: u8,
+    /// Polling interval of the endpoint (bInterval)
+    pub interval: u8,
+    contacts: Vec<Contact>,
+    started: Instant,
+}
+
+impl Touchscreen {
+    /// Maximum number of simultaneous contacts
+    pub const MAX_CONTACTS: u8 = 10;
+    /// Maximum logical X coordinate
+    pub const MAX_X: u16 = 4095;
+    /// Maximum logical Y coordinate
+    pub const MAX_Y: u16 = 4095;
+    /// Report ID of the input report with the contacts
+    pub const INPUT_REPORT_ID: u8 = 1;
+    /// Report ID of the Contact Count Maximum feature report
+    pub const FEATURE_REPORT_ID: u8 = 2;
+    /// Size of the input report in bytes, including the report ID
+    pub const REPORT_SIZE: usize = 1 + 6 * Self::MAX_CONTACTS as usize + 2 + 1;
+    /// Size of a single contact in the input report
+    const CONTACT_SIZE: usize = 6;
+
+    /// Create a new touchscreen using the given IN endpoint number
+    pub fn new(endpoint: u8) -> Self {
+        Self {
+            endpoint,
+            interval: DEFAULT_INTERVAL,
+            contacts: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Build the HID interface of the touchscreen. The interface answers
+    /// GET_REPORT requests for the Contact Count Maximum feature report.
+    pub fn interface(&self) -> Interface {
+        HidInterfaceBuilder::new()
+            .report_descriptor(TOUCHSCREEN_REPORT_DESCRIPTOR)
+            .endpoint_descriptor(interrupt_in_endpoint(
+                self.endpoint,
+                Self::REPORT_SIZE as u16,
+                self.interval,
+            ))
+            .on_get_report(HidReportType::Feature, Self::FEATURE_REPORT_ID, || {
+                vec![Self::FEATURE_REPORT_ID, Self::MAX_CONTACTS]
+            })
+            .build()
+    }
+
+    /// Place a finger with the given contact ID at the given position. If
+    /// the finger is already touching, it is moved instead.
+    pub fn place(&mut self, id: u8, x: u16, y: u16) -> Result<(), Box<dyn Error>> {
+        let x = x.min(Self::MAX_X);
+        let y = y.min(Self::MAX_Y);
+        if let Some(contact) = self.contacts.iter_mut().find(|c| c.id == id) {
+            *contact = Contact {
+                id,
+                x,
+                y,
+                touching: true,
+            };
+            return Ok(());
+        }
+        if self.contacts.len() >= Self::MAX_CONTACTS as usize {
+            return Err(format!("Too many contacts: {}", Self::MAX_CONTACTS).into());
+        }
+        self.contacts.push(Contact {
+            id,
+            x,
+            y,
+            touching: true,
+        });
+        Ok(())
+    }
+
+    /// Move the finger with the given contact ID to the given position
+    pub fn move_to(&mut self, id: u8, x: u16, y: u16) -> Result<(), Box<dyn Error>> {
+        let Some(contact) = self.contacts.iter_mut().find(|c| c.id == id && c.touching) else {
+            return Err(format!("Contact {id} is not touching").into());
+        };
+        contact.x = x.min(Self::MAX_X);
+        contact.y = y.min(Self::MAX_Y);
+        Ok(())
+    }
+
+    /// Lift the finger with the given contact ID. The finger is reported
+    /// once more with its tip switch released.
+    pub fn lift(&mut self, id: u8) {
+        if let Some(contact) = self.contacts.iter_mut().find(|c| c.id == id) {
+            contact.touching = false;
+        }
+    }
+
+    /// Lift all fingers
+    pub fn lift_all(&mut self) {
+        for contact in self.contacts.iter_mut() {
+            contact.touching = false;
+        }
+    }
+
+    /// Returns the number of fingers touching the screen
+    pub fn touching(&self) -> usize {
+        self.contacts.iter().filter(|c| c.touching).count()
+    }
+
+    /// Returns the input report for the current contacts, including fingers
+    /// that were lifted since the last call to [Touchscreen::take_report].
+    pub fn report(&self) -> Vec<u8> {
+        let mut report = vec![0; Self::REPORT_SIZE];
+        report[0] = Self::INPUT_REPORT_ID;
+        for (slot, contact) in self.contacts.iter().enumerate() {
+            let offset = 1 + slot * Self::CONTACT_SIZE;
+            // Tip switch and confidence
+            report[offset] = if contact.touching { 0x03 } else { 0x02 };
+            report[offset + 1] = contact.id;
+            report[offset + 2..offset + 4].copy_from_slice(&contact.x.to_le_bytes());
+            report[offset + 4..offset + 6].copy_from_slice(&contact.y.to_le_bytes());
+        }
+
+        // Scan time is expressed in 100µs units and wraps around
+        let scan_time = (self.started.elapsed().as_micros() / 100) as u16;
+        let offset = 1 + Self::MAX_CONTACTS as usize * Self::CONTACT_SIZE;
+        report[offset..offset + 2].copy_from_slice(&scan_time.to_le_bytes());
+        report[offset + 2] = self.contacts.len() as u8;
+        report
+    }
+
+    /// Returns the input report for the current contacts and forgets the
+    /// fingers that were lifted.
+    pub fn take_report(&mut self) -> Vec<u8> {
+        let report = self.report();
+        self.contacts.retain(|c| c.touching);
+        report
+    }
+}
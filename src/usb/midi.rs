@@ -0,0 +1,568 @@
+//! USB MIDI 1.0 (MIDIStreaming subclass of the audio class)
+//! https://www.usb.org/sites/default/files/midi10.pdf
+//!
+//! MIDI messages are exchanged on bulk endpoints as 32-bit event packets,
+//! each carrying up to three bytes of a message with the number of the
+//! virtual cable and a Code Index Number (CIN) classifying the bytes.
+
+use std::fmt::Display;
+
+use packed_struct::prelude::*;
+
+use super::{
+    uac::{AudioSubclass, CS_ENDPOINT, CS_INTERFACE},
+    Direction, EndpointBuilder, EndpointDescriptor, Interface, InterfaceClass, InterfaceDescriptor,
+    SynchronizationType, TransferType, UsageType,
+};
+
+/// Version of the MIDIStreaming specification (bcdMSC)
+pub const MSC_VERSION: u16 = 0x0100;
+/// Size of a USB-MIDI event packet
+pub const EVENT_PACKET_SIZE: usize = 4;
+/// Number of virtual cables of an endpoint
+pub const MAX_CABLES: u8 = 16;
+
+/// MIDIStreaming class-specific interface descriptor subtypes
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MsDescriptorSubtype {
+    Header = 0x01,
+    MidiInJack = 0x02,
+    MidiOutJack = 0x03,
+    Element = 0x04,
+}
+
+/// Subtype of the class-specific endpoint descriptor (MS_GENERAL)
+pub const MS_GENERAL: u8 = 0x01;
+
+/// Jack types (bJackType)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum JackType {
+    /// Jack connected to a bulk endpoint
+    Embedded = 0x01,
+    /// Jack standing for a physical MIDI connector
+    External = 0x02,
+}
+
+/// Code Index Numbers, classifying the bytes of an event packet
+pub mod cin {
+    /// Two-byte system common message
+    pub const SYSTEM_COMMON_2: u8 = 0x2;
+    /// Three-byte system common message
+    pub const SYSTEM_COMMON_3: u8 = 0x3;
+    /// SysEx starts or continues with three bytes
+    pub const SYSEX_START: u8 = 0x4;
+    /// Single-byte system common message, or SysEx ends with one byte
+    pub const SYSEX_END_1: u8 = 0x5;
+    /// SysEx ends with two bytes
+    pub const SYSEX_END_2: u8 = 0x6;
+    /// SysEx ends with three bytes
+    pub const SYSEX_END_3: u8 = 0x7;
+    /// Single byte, such as a real-time message
+    pub const SINGLE_BYTE: u8 = 0xf;
+}
+
+/// Returns the number of MIDI bytes carried by a packet with the given CIN
+pub fn cin_length(cin: u8) -> usize {
+    match cin & 0x0f {
+        cin::SYSEX_END_1 | cin::SINGLE_BYTE => 1,
+        cin::SYSTEM_COMMON_2 | cin::SYSEX_END_2 | 0xc | 0xd => 2,
+        0x0 | 0x1 => 0,
+        _ => 3,
+    }
+}
+
+/// Returns the length of the message starting with the given status byte,
+/// or None for SysEx and undefined status bytes
+fn message_length(status: u8) -> Option<usize> {
+    match status {
+        0x80..=0xbf | 0xe0..=0xef => Some(3),
+        0xc0..=0xdf => Some(2),
+        0xf1 | 0xf3 => Some(2),
+        0xf2 => Some(3),
+        0xf6 | 0xf8..=0xff => Some(1),
+        _ => None,
+    }
+}
+
+/// Returns the CIN of a complete message other than SysEx
+fn message_cin(message: &[u8]) -> u8 {
+    let status = message[0];
+    match status {
+        0x80..=0xef => status >> 4,
+        0xf8..=0xff => cin::SINGLE_BYTE,
+        _ => match message.len() {
+            1 => cin::SYSEX_END_1,
+            2 => cin::SYSTEM_COMMON_2,
+            _ => cin::SYSTEM_COMMON_3,
+        },
+    }
+}
+
+/// Build an event packet for the given cable
+fn packet(cable: u8, cin: u8, bytes: &[u8]) -> [u8; EVENT_PACKET_SIZE] {
+    let mut packet = [(cable & 0x0f) << 4 | cin, 0, 0, 0];
+    packet[1..1 + bytes.len()].copy_from_slice(bytes);
+    packet
+}
+
+/// Encoder turning the MIDI byte stream of a cable into event packets,
+/// with running status and SysEx spanning several writes
+#[derive(Debug, Clone, Default)]
+pub struct PacketEncoder {
+    /// Running status of channel messages
+    running_status: Option<u8>,
+    /// Bytes of the message being encoded
+    message: Vec<u8>,
+    /// Whether a SysEx message is in progress
+    sysex: bool,
+}
+
+impl PacketEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encode the given MIDI bytes for the given cable. Incomplete messages
+    /// are kept until the next call.
+    pub fn encode(&mut self, cable: u8, bytes: &[u8]) -> Vec<[u8; EVENT_PACKET_SIZE]> {
+        let mut packets = Vec::new();
+        for &byte in bytes {
+            // Real-time messages may appear anywhere, even within messages
+            if byte >= 0xf8 {
+                packets.push(packet(cable, cin::SINGLE_BYTE, &[byte]));
+                continue;
+            }
+
+            // Any other status byte ends an unterminated SysEx
+            if self.sysex && (byte < 0x80 || byte == 0xf7) {
+                self.message.push(byte);
+                if byte == 0xf7 {
+                    let cin = match self.message.len() {
+                        1 => cin::SYSEX_END_1,
+                        2 => cin::SYSEX_END_2,
+                        _ => cin::SYSEX_END_3,
+                    };
+                    packets.push(packet(cable, cin, &self.message));
+                    self.message.clear();
+                    self.sysex = false;
+                } else if self.message.len() == 3 {
+                    packets.push(packet(cable, cin::SYSEX_START, &self.message));
+                    self.message.clear();
+                }
+                continue;
+            }
+
+            if byte & 0x80 != 0 {
+                self.message.clear();
+                self.sysex = byte == 0xf0;
+                self.running_status = match byte {
+                    0x80..=0xef => Some(byte),
+                    _ => None,
+                };
+                self.message.push(byte);
+            } else if self.message.is_empty() {
+                // Data byte without status: use the running status, or
+                // drop it
+                let Some(status) = self.running_status else {
+                    continue;
+                };
+                self.message.extend_from_slice(&[status, byte]);
+            } else {
+                self.message.push(byte);
+            }
+
+            let Some(&status) = self.message.first() else {
+                continue;
+            };
+            match message_length(status) {
+                Some(len) if self.message.len() >= len => {
+                    packets.push(packet(cable, message_cin(&self.message), &self.message));
+                    self.message.clear();
+                }
+                Some(_) => (),
+                None if !self.sysex => self.message.clear(),
+                None => (),
+            }
+        }
+        packets
+    }
+}
+
+/// Decoder turning the event packets of a cable into complete MIDI
+/// messages, joining SysEx messages
+#[derive(Debug, Clone, Default)]
+pub struct PacketDecoder {
+    /// SysEx message being received
+    sysex: Vec<u8>,
+}
+
+impl PacketDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode an event packet, and return the message it completes, if any
+    pub fn decode(&mut self, packet: &[u8; EVENT_PACKET_SIZE]) -> Option<Vec<u8>> {
+        let cin = packet[0] & 0x0f;
+        let bytes = &packet[1..1 + cin_length(cin)];
+        match cin {
+            0x0 | 0x1 => None,
+            cin::SYSEX_START => {
+                self.sysex.extend_from_slice(bytes);
+                None
+            }
+            cin::SYSEX_END_1 | cin::SYSEX_END_2 | cin::SYSEX_END_3 => {
+                // A single-byte system common message is not part of SysEx
+                if cin == cin::SYSEX_END_1 && self.sysex.is_empty() && bytes[0] != 0xf7 {
+                    return Some(bytes.to_vec());
+                }
+                self.sysex.extend_from_slice(bytes);
+                Some(std::mem::take(&mut self.sysex))
+            }
+            _ => Some(bytes.to_vec()),
+        }
+    }
+}
+
+/// Returns the virtual cable number of an event packet
+pub fn packet_cable(packet: &[u8; EVENT_PACKET_SIZE]) -> u8 {
+    packet[0] >> 4
+}
+
+/// MIDIStreaming interface definition, with one embedded and one external
+/// jack per virtual cable and direction. Cables from the host enter on the
+/// bulk OUT endpoint and cables to the host leave on the bulk IN endpoint.
+#[derive(Debug, Clone)]
+pub struct MidiStreamingInterface {
+    pub iface: InterfaceDescriptor,
+    /// Number of cables from the host to the device
+    pub out_cables: u8,
+    /// Number of cables from the device to the host
+    pub in_cables: u8,
+    pub endpoint_descriptors: Vec<EndpointDescriptor>,
+}
+
+impl MidiStreamingInterface {
+    pub fn new() -> Self {
+        let iface = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            b_interface_class: InterfaceClass::Audio,
+            b_interface_subclass: AudioSubclass::MidiStreaming as u8,
+            b_interface_protocol: 0,
+            ..InterfaceDescriptor::new()
+        };
+
+        Self {
+            iface,
+            out_cables: 1,
+            in_cables: 1,
+            endpoint_descriptors: Vec::new(),
+        }
+    }
+
+    /// Returns the IDs of the embedded jacks of the cables from the host
+    fn embedded_in_jacks(&self) -> impl Iterator<Item = u8> {
+        (0..self.out_cables).map(|cable| 1 + cable * 2)
+    }
+
+    /// Returns the IDs of the embedded jacks of the cables to the host
+    fn embedded_out_jacks(&self) -> impl Iterator<Item = u8> {
+        let first = 1 + self.out_cables * 2;
+        (0..self.in_cables).map(move |cable| first + cable * 2)
+    }
+
+    /// Returns the class-specific interface descriptors: the header
+    /// followed by the jacks. Every cable has an embedded jack wired to an
+    /// external jack.
+    fn class_descriptors(&self) -> Vec<u8> {
+        let in_jack = MsDescriptorSubtype::MidiInJack as u8;
+        let out_jack = MsDescriptorSubtype::MidiOutJack as u8;
+        let embedded = JackType::Embedded as u8;
+        let external = JackType::External as u8;
+
+        let mut jacks = Vec::new();
+        for id in self.embedded_in_jacks() {
+            jacks.extend_from_slice(&[6, CS_INTERFACE, in_jack, embedded, id, 0]);
+            jacks.extend_from_slice(&[9, CS_INTERFACE, out_jack, external, id + 1, 1, id, 1, 0]);
+        }
+        for id in self.embedded_out_jacks() {
+            jacks.extend_from_slice(&[6, CS_INTERFACE, in_jack, external, id + 1, 0]);
+            jacks.extend_from_slice(&[9, CS_INTERFACE, out_jack, embedded, id, 1, id + 1, 1, 0]);
+        }
+
+        let subtype = MsDescriptorSubtype::Header as u8;
+        let total_length = ((7 + jacks.len()) as u16).to_le_bytes();
+        let mut desc = vec![7, CS_INTERFACE, subtype];
+        desc.extend_from_slice(&MSC_VERSION.to_le_bytes());
+        desc.extend_from_slice(&total_length);
+        desc.append(&mut jacks);
+        desc
+    }
+
+    /// Serialize the given endpoint descriptor, in its 9 byte audio
+    /// variant, followed by the class-specific descriptor listing the
+    /// embedded jacks of the endpoint
+    fn endpoint_descriptors(&self, desc: &EndpointDescriptor) -> Result<Vec<u8>, PackingError> {
+        let jacks: Vec<u8> = match desc.direction() {
+            Direction::Out => self.embedded_in_jacks().collect(),
+            Direction::In => self.embedded_out_jacks().collect(),
+        };
+        let mut result = desc.pack_to_vec()?;
+        result[0] = 9;
+        result.extend_from_slice(&[0, 0]);
+        result.extend_from_slice(&[4 + jacks.len() as u8, CS_ENDPOINT, MS_GENERAL]);
+        result.push(jacks.len() as u8);
+        result.extend_from_slice(&jacks);
+        Ok(result)
+    }
+
+    /// Returns the size of the endpoint descriptors with their
+    /// class-specific descriptors
+    fn endpoint_size(&self) -> usize {
+        self.endpoint_descriptors
+            .iter()
+            .map(|desc| match desc.direction() {
+                Direction::Out => 9 + 4 + self.out_cables as usize,
+                Direction::In => 9 + 4 + self.in_cables as usize,
+            })
+            .sum()
+    }
+
+    /// Returns the endpoint number of the bulk endpoint in the given
+    /// direction
+    pub fn bulk_endpoint(&self, direction: Direction) -> Option<u8> {
+        self.endpoint_descriptors
+            .iter()
+            .find(|desc| desc.direction() == direction)
+            .map(|desc| desc.number())
+    }
+
+    /// Serialize the interface into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.get_size());
+        result.append(&mut self.iface.pack_to_vec()?);
+        result.append(&mut self.class_descriptors());
+        for endpoint_desc in self.endpoint_descriptors.iter() {
+            result.append(&mut self.endpoint_descriptors(endpoint_desc)?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the byte serialized size of the interface
+    pub fn get_size(&self) -> usize {
+        9 + self.class_descriptors().len() + self.endpoint_size()
+    }
+
+    /// Returns the interface class
+    pub fn get_class(&self) -> InterfaceClass {
+        self.iface.b_interface_class
+    }
+
+    /// Set the interface number for this interface
+    pub fn set_interface_number(&mut self, num: u8) {
+        self.iface.b_interface_number = num;
+    }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        self.endpoint_descriptors.as_slice()
+    }
+}
+
+impl Display for MidiStreamingInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut text = vec![
+            format!("{}", self.iface),
+            format!("Cables: {} out, {} in", self.out_cables, self.in_cables),
+        ];
+        for desc in self.endpoint_descriptors.iter() {
+            text.push(format!("{}", desc));
+        }
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+impl Default for MidiStreamingInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [Interface] builder for constructing a MIDIStreaming interface. The
+/// function also needs an AudioControl interface built with
+/// [UacControlInterfaceBuilder::midi_streaming].
+///
+/// [UacControlInterfaceBuilder::midi_streaming]: super::uac::UacControlInterfaceBuilder::midi_streaming
+pub struct MidiStreamingInterfaceBuilder {
+    iface: MidiStreamingInterface,
+}
+
+impl MidiStreamingInterfaceBuilder {
+    pub fn new() -> Self {
+        Self {
+            iface: MidiStreamingInterface::default(),
+        }
+    }
+
+    /// Construct the new Interface configuration.
+    pub fn build(&self) -> Interface {
+        #[cfg(feature = "log")]
+        log::debug!("MIDI Streaming Interface: {}", self.iface);
+        Interface::MidiStreaming(self.iface.clone())
+    }
+
+    /// Set the number of cables from the host and to the host, up to 16
+    /// each. Hosts show each cable as a MIDI port.
+    pub fn cables(&mut self, out_cables: u8, in_cables: u8) -> &mut Self {
+        self.iface.out_cables = out_cables.min(MAX_CABLES);
+        self.iface.in_cables = in_cables.min(MAX_CABLES);
+        self
+    }
+
+    /// Add a bulk OUT and a bulk IN endpoint with the given endpoint numbers
+    /// and max packet size (64 for full speed, 512 for high speed).
+    pub fn bulk_endpoints(&mut self, out_num: u8, in_num: u8, max_packet_size: u16) -> &mut Self {
+        for (num, direction) in [(out_num, Direction::Out), (in_num, Direction::In)] {
+            let descriptor = EndpointBuilder::new()
+                .address_num(num)
+                .direction(direction)
+                .transfer_type(TransferType::Bulk)
+                .sync_type(SynchronizationType::NoSynchronization)
+                .usage_type(UsageType::Data)
+                .max_packet_size(max_packet_size)
+                .build();
+            self.endpoint_descriptor(descriptor);
+        }
+        self
+    }
+
+    /// Add the given endpoint to the interface
+    pub fn endpoint_descriptor(&mut self, descriptor: EndpointDescriptor) -> &mut Self {
+        self.iface.endpoint_descriptors.push(descriptor);
+        self.iface.iface.b_num_endpoints = self.iface.endpoint_descriptors.len() as u8;
+        self
+    }
+}
+
+impl Default for MidiStreamingInterfaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode the given packets into the messages they complete
+    fn decode(packets: &[[u8; EVENT_PACKET_SIZE]]) -> Vec<Vec<u8>> {
+        let mut decoder = PacketDecoder::new();
+        packets.iter().filter_map(|p| decoder.decode(p)).collect()
+    }
+
+    #[test]
+    fn code_index_numbers() {
+        let messages: [(&[u8], u8); 12] = [
+            (&[0x81, 0x3c, 0x00], 0x8),
+            (&[0x91, 0x3c, 0x7f], 0x9),
+            (&[0xa1, 0x3c, 0x10], 0xa),
+            (&[0xb1, 0x07, 0x64], 0xb),
+            (&[0xc1, 0x05], 0xc),
+            (&[0xd1, 0x20], 0xd),
+            (&[0xe1, 0x00, 0x40], 0xe),
+            (&[0xf1, 0x12], cin::SYSTEM_COMMON_2),
+            (&[0xf2, 0x10, 0x02], cin::SYSTEM_COMMON_3),
+            (&[0xf3, 0x04], cin::SYSTEM_COMMON_2),
+            (&[0xf6], cin::SYSEX_END_1),
+            (&[0xf8], cin::SINGLE_BYTE),
+        ];
+        for (message, cin) in messages {
+            let packets = PacketEncoder::new().encode(3, message);
+            assert_eq!(packets.len(), 1, "{message:02x?}");
+            let packet = packets[0];
+            assert_eq!(packet[0], 0x30 | cin, "{message:02x?}");
+            assert_eq!(packet_cable(&packet), 3);
+            assert_eq!(cin_length(cin), message.len());
+            assert_eq!(&packet[1..1 + message.len()], message);
+            // Unused bytes are zero
+            assert!(packet[1 + message.len()..].iter().all(|b| *b == 0));
+            assert_eq!(decode(&packets), [message]);
+        }
+
+        // Reserved CINs carry no message
+        assert_eq!(
+            decode(&[[0x00, 0x90, 0x3c, 0x7f], [0x01, 0xf0, 0x00, 0x00]]).len(),
+            0
+        );
+    }
+
+    #[test]
+    fn running_status() {
+        let mut encoder = PacketEncoder::new();
+        let packets = encoder.encode(0, &[0x90, 0x3c, 0x7f, 0x3e, 0x7f]);
+        assert_eq!(
+            packets,
+            [[0x09, 0x90, 0x3c, 0x7f], [0x09, 0x90, 0x3e, 0x7f]]
+        );
+
+        // Messages and running status span calls
+        assert!(encoder.encode(0, &[0x40]).is_empty());
+        assert_eq!(encoder.encode(0, &[0x00, 0x41]), [[0x09, 0x90, 0x40, 0x00]]);
+        assert_eq!(encoder.encode(0, &[0x00]), [[0x09, 0x90, 0x41, 0x00]]);
+
+        // Real-time messages do not interrupt the message
+        let packets = encoder.encode(0, &[0xb0, 0x07, 0xf8, 0x64]);
+        assert_eq!(
+            packets,
+            [[0x0f, 0xf8, 0x00, 0x00], [0x0b, 0xb0, 0x07, 0x64]]
+        );
+
+        // System common messages cancel the running status
+        let packets = encoder.encode(0, &[0xf6, 0x07, 0x64]);
+        assert_eq!(packets, [[0x05, 0xf6, 0x00, 0x00]]);
+
+        // Data bytes without a status are dropped
+        assert!(PacketEncoder::new().encode(0, &[0x3c, 0x7f]).is_empty());
+    }
+
+    #[test]
+    fn split_sysex() {
+        let endings: [(&[u8], &[[u8; EVENT_PACKET_SIZE]]); 4] = [
+            (&[0xf0, 0xf7], &[[0x06, 0xf0, 0xf7, 0x00]]),
+            (
+                &[0xf0, 0x01, 0x02, 0xf7],
+                &[[0x04, 0xf0, 0x01, 0x02], [0x05, 0xf7, 0x00, 0x00]],
+            ),
+            (
+                &[0xf0, 0x01, 0x02, 0x03, 0xf7],
+                &[[0x04, 0xf0, 0x01, 0x02], [0x06, 0x03, 0xf7, 0x00]],
+            ),
+            (
+                &[0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7],
+                &[[0x04, 0xf0, 0x7e, 0x7f], [0x07, 0x06, 0x01, 0xf7]],
+            ),
+        ];
+        for (message, expected) in endings {
+            let packets = PacketEncoder::new().encode(0, message);
+            assert_eq!(packets, expected);
+            assert_eq!(decode(&packets), [message]);
+        }
+
+        // SysEx spans calls, with real-time messages in between
+        let mut encoder = PacketEncoder::new();
+        let mut packets = encoder.encode(1, &[0xf0, 0x43, 0x10]);
+        packets.extend(encoder.encode(1, &[0x4c, 0xfe, 0x00]));
+        packets.extend(encoder.encode(1, &[0x00, 0x7e, 0x00, 0xf7]));
+        let messages = decode(&packets);
+        let sysex = [0xf0, 0x43, 0x10, 0x4c, 0x00, 0x00, 0x7e, 0x00, 0xf7];
+        assert_eq!(messages, [&[0xfe][..], &sysex]);
+
+        // A status byte ends an unterminated SysEx
+        let packets = PacketEncoder::new().encode(0, &[0xf0, 0x01, 0x02, 0x03, 0x90, 0x3c, 0x7f]);
+        assert_eq!(
+            packets,
+            [[0x04, 0xf0, 0x01, 0x02], [0x09, 0x90, 0x3c, 0x7f]]
+        );
+    }
+}
@@ -0,0 +1,340 @@
+//! MSC (Mass Storage Class) with the Bulk-Only Transport
+//! https://www.usb.org/sites/default/files/usbmassbulk_10.pdf
+
+pub mod block;
+pub mod scsi;
+
+use std::{
+    fmt::{Debug, Display},
+    sync::{Arc, Mutex},
+};
+
+use packed_struct::prelude::*;
+
+use super::{
+    Direction, EndpointBuilder, EndpointDescriptor, Interface, InterfaceClass, InterfaceDescriptor,
+    SynchronizationType, TransferType, UsageType,
+};
+
+/// Signature of a Command Block Wrapper ("USBC")
+pub const CBW_SIGNATURE: u32 = 0x4342_5355;
+/// Signature of a Command Status Wrapper ("USBS")
+pub const CSW_SIGNATURE: u32 = 0x5342_5355;
+/// Size of a Command Block Wrapper
+pub const CBW_SIZE: usize = 31;
+/// Size of a Command Status Wrapper
+pub const CSW_SIZE: usize = 13;
+
+/// Mass storage interface subclass codes (command set)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MscSubclass {
+    /// SCSI command set not reported
+    NotReported = 0x00,
+    /// Reduced Block Commands
+    Rbc = 0x01,
+    /// MMC-5 (ATAPI)
+    Mmc5 = 0x02,
+    /// UFI (floppy drives)
+    Ufi = 0x04,
+    /// SCSI transparent command set
+    Scsi = 0x06,
+}
+
+/// Mass storage interface protocol codes (transport)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MscProtocol {
+    /// Control/Bulk/Interrupt transport with command completion interrupt
+    Cbi = 0x00,
+    /// Control/Bulk/Interrupt transport without command completion interrupt
+    Cb = 0x01,
+    /// Bulk-Only Transport
+    BulkOnly = 0x50,
+}
+
+/// Bulk-Only Transport class requests (bRequest)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum MscRequest {
+    GetMaxLun = 0xfe,
+    BulkOnlyReset = 0xff,
+}
+
+/// Status of a command reported in the Command Status Wrapper
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum CswStatus {
+    Passed = 0x00,
+    Failed = 0x01,
+    PhaseError = 0x02,
+}
+
+/// Callback called when the host resets the transport with BULK_ONLY_RESET
+pub type MscResetHandler = Arc<Mutex<dyn FnMut() + Send>>;
+
+/// Reset handlers of a mass storage interface
+#[derive(Clone, Default)]
+pub struct MscResetHandlers(Vec<MscResetHandler>);
+
+impl MscResetHandlers {
+    /// Call all handlers
+    pub fn dispatch(&self) {
+        for handler in self.0.iter() {
+            if let Ok(mut handler) = handler.lock() {
+                handler();
+            }
+        }
+    }
+}
+
+impl Debug for MscResetHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MscResetHandlers")
+            .field("count", &self.0.len())
+            .finish()
+    }
+}
+
+/// Mass storage interface definition using the Bulk-Only Transport
+#[derive(Debug, Clone)]
+pub struct MscInterface {
+    pub iface: InterfaceDescriptor,
+    pub endpoint_descriptors: Vec<EndpointDescriptor>,
+    /// Highest logical unit number, reported with GET_MAX_LUN
+    pub max_lun: u8,
+    pub handlers: MscResetHandlers,
+}
+
+impl MscInterface {
+    pub fn new() -> Self {
+        let iface = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            b_interface_class: InterfaceClass::MassStorage,
+            b_interface_subclass: MscSubclass::Scsi as u8,
+            b_interface_protocol: MscProtocol::BulkOnly as u8,
+            ..InterfaceDescriptor::new()
+        };
+
+        Self {
+            iface,
+            endpoint_descriptors: Vec::new(),
+            max_lun: 0,
+            handlers: MscResetHandlers::default(),
+        }
+    }
+
+    /// Register a handler called when the host sends BULK_ONLY_RESET. The
+    /// handler must return the transport to the state where it waits for the
+    /// next Command Block Wrapper.
+    pub fn on_reset<F>(&mut self, handler: F)
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let handler: MscResetHandler = Arc::new(Mutex::new(handler));
+        self.handlers.0.push(handler);
+    }
+
+    /// Handle BULK_ONLY_RESET
+    pub fn handle_bulk_only_reset(&self) {
+        self.handlers.dispatch();
+    }
+
+    /// Serialize the interface into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.get_size());
+        result.append(&mut self.iface.pack_to_vec()?);
+        for endpoint_desc in self.endpoint_descriptors.iter() {
+            result.append(&mut endpoint_desc.pack_to_vec()?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the byte serialized size of the interface
+    pub fn get_size(&self) -> usize {
+        9 + (7 * self.endpoint_descriptors.len())
+    }
+
+    /// Returns the interface class
+    pub fn get_class(&self) -> InterfaceClass {
+        self.iface.b_interface_class
+    }
+
+    /// Set the interface number for this interface
+    pub fn set_interface_number(&mut self, num: u8) {
+        self.iface.b_interface_number = num;
+    }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        self.endpoint_descriptors.as_slice()
+    }
+}
+
+impl Display for MscInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut text = vec![format!("{}", self.iface)];
+        for desc in self.endpoint_descriptors.iter() {
+            text.push(format!("{}", desc));
+        }
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+impl Default for MscInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [Interface] builder for constructing a Bulk-Only mass storage interface.
+pub struct MscInterfaceBuilder {
+    iface: MscInterface,
+}
+
+impl MscInterfaceBuilder {
+    pub fn new() -> Self {
+        Self {
+            iface: MscInterface::default(),
+        }
+    }
+
+    /// Construct the new Interface configuration.
+    pub fn build(&self) -> Interface {
+        #[cfg(feature = "log")]
+        log::debug!("MSC Interface: {}", self.iface);
+        Interface::MassStorage(self.iface.clone())
+    }
+
+    /// Set the command set of the interface
+    pub fn subclass(&mut self, subclass: MscSubclass) -> &mut Self {
+        self.iface.iface.b_interface_subclass = subclass as u8;
+        self
+    }
+
+    /// Set the highest logical unit number reported with GET_MAX_LUN
+    pub fn max_lun(&mut self, max_lun: u8) -> &mut Self {
+        self.iface.max_lun = max_lun;
+        self
+    }
+
+    /// Handle BULK_ONLY_RESET requests. See [MscInterface::on_reset].
+    pub fn on_reset<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: FnMut() + Send + 'static,
+    {
+        self.iface.on_reset(handler);
+        self
+    }
+
+    /// Add a bulk IN and a bulk OUT endpoint with the given endpoint numbers
+    /// and max packet size (64 for full speed, 512 for high speed).
+    pub fn bulk_endpoints(&mut self, in_num: u8, out_num: u8, max_packet_size: u16) -> &mut Self {
+        for (num, direction) in [(in_num, Direction::In), (out_num, Direction::Out)] {
+            let descriptor = EndpointBuilder::new()
+                .address_num(num)
+                .direction(direction)
+                .transfer_type(TransferType::Bulk)
+                .sync_type(SynchronizationType::NoSynchronization)
+                .usage_type(UsageType::Data)
+                .max_packet_size(max_packet_size)
+                .build();
+            self.endpoint_descriptor(descriptor);
+        }
+        self
+    }
+
+    /// Add the given endpoint to the interface
+    pub fn endpoint_descriptor(&mut self, descriptor: EndpointDescriptor) -> &mut Self {
+        self.iface.endpoint_descriptors.push(descriptor);
+        self.iface.iface.b_num_endpoints = self.iface.endpoint_descriptors.len() as u8;
+        self
+    }
+}
+
+impl Default for MscInterfaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Command Block Wrapper sent by the host on the bulk OUT endpoint
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "31")]
+pub struct CommandBlockWrapper {
+    #[packed_field(bytes = "0..=3", endian = "lsb")]
+    pub d_cbw_signature: Integer<u32, packed_bits::Bits<32>>,
+    /// Tag echoed in the Command Status Wrapper
+    #[packed_field(bytes = "4..=7", endian = "lsb")]
+    pub d_cbw_tag: Integer<u32, packed_bits::Bits<32>>,
+    /// Number of bytes the host expects to transfer in the data stage
+    #[packed_field(bytes = "8..=11", endian = "lsb")]
+    pub d_cbw_data_transfer_length: Integer<u32, packed_bits::Bits<32>>,
+    /// Bit 7: direction of the data stage (0 = OUT, 1 = IN)
+    #[packed_field(bytes = "12")]
+    pub bm_cbw_flags: u8,
+    #[packed_field(bytes = "13")]
+    pub b_cbw_lun: u8,
+    /// Length of the command block (1 to 16)
+    #[packed_field(bytes = "14")]
+    pub b_cbw_cb_length: u8,
+    #[packed_field(bytes = "15..=30")]
+    pub cbwcb: [u8; 16],
+}
+
+impl CommandBlockWrapper {
+    /// Returns true if the signature and command block length are valid
+    pub fn is_valid(&self) -> bool {
+        self.d_cbw_signature.to_primitive() == CBW_SIGNATURE
+            && (1..=16).contains(&self.b_cbw_cb_length)
+    }
+
+    /// Returns the direction of the data stage
+    pub fn direction(&self) -> Direction {
+        if self.bm_cbw_flags & 0x80 != 0 {
+            Direction::In
+        } else {
+            Direction::Out
+        }
+    }
+
+    /// Returns the number of bytes the host expects in the data stage
+    pub fn data_transfer_length(&self) -> usize {
+        self.d_cbw_data_transfer_length.to_primitive() as usize
+    }
+
+    /// Returns the logical unit the command is for
+    pub fn lun(&self) -> u8 {
+        self.b_cbw_lun & 0x0f
+    }
+
+    /// Returns the command block
+    pub fn command_block(&self) -> &[u8] {
+        let len = (self.b_cbw_cb_length as usize).min(self.cbwcb.len());
+        &self.cbwcb[..len]
+    }
+}
+
+/// Command Status Wrapper sent by the device on the bulk IN endpoint
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "13")]
+pub struct CommandStatusWrapper {
+    #[packed_field(bytes = "0..=3", endian = "lsb")]
+    pub d_csw_signature: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "4..=7", endian = "lsb")]
+    pub d_csw_tag: Integer<u32, packed_bits::Bits<32>>,
+    /// Difference between the expected and the processed amount of data
+    #[packed_field(bytes = "8..=11", endian = "lsb")]
+    pub d_csw_data_residue: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "12", ty = "enum")]
+    pub b_csw_status: CswStatus,
+}
+
+impl CommandStatusWrapper {
+    /// Create a status wrapper answering the given command
+    pub fn new(cbw: &CommandBlockWrapper, residue: u32, status: CswStatus) -> Self {
+        Self {
+            d_csw_signature: Integer::from_primitive(CSW_SIGNATURE),
+            d_csw_tag: cbw.d_cbw_tag,
+            d_csw_data_residue: Integer::from_primitive(residue),
+            b_csw_status: status,
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! Block devices backing the logical units of a mass storage interface
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+/// Default logical block size in bytes
+pub const DEFAULT_BLOCK_SIZE: u32 = 512;
+
+/// Storage medium addressed in fixed size logical blocks
+pub trait BlockDevice {
+    /// Size of a logical block in bytes
+    fn block_size(&self) -> u32;
+    /// Number of logical blocks
+    fn block_count(&self) -> u64;
+    /// Read consecutive blocks starting at the given logical block address.
+    /// The length of the buffer is a multiple of the block size.
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> io::Result<()>;
+    /// Write consecutive blocks starting at the given logical block address.
+    /// The length of the data is a multiple of the block size.
+    fn write_blocks(&mut self, lba: u64, data: &[u8]) -> io::Result<()>;
+    /// Flush written blocks to the underlying storage
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    /// Returns true if the medium is write protected
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for Box<D> {
+    fn block_size(&self) -> u32 {
+        (**self).block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> io::Result<()> {
+        (**self).read_blocks(lba, buf)
+    }
+
+    fn write_blocks(&mut self, lba: u64, data: &[u8]) -> io::Result<()> {
+        (**self).write_blocks(lba, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn is_read_only(&self) -> bool {
+        (**self).is_read_only()
+    }
+}
+
+/// Block device backed by a disk image file. Trailing bytes that do not fill
+/// a whole block are not accessible.
+#[derive(Debug)]
+pub struct FileImage {
+    file: File,
+    block_size: u32,
+    block_count: u64,
+    read_only: bool,
+}
+
+impl FileImage {
+    /// Open the disk image at the given path for reading and writing
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Self::from_file(file, false)
+    }
+
+    /// Open the disk image at the given path as a write protected medium
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Self::from_file(file, true)
+    }
+
+    /// Use the given open file as disk image
+    pub fn from_file(file: File, read_only: bool) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self {
+            file,
+            block_size: DEFAULT_BLOCK_SIZE,
+            block_count: len / DEFAULT_BLOCK_SIZE as u64,
+            read_only,
+        })
+    }
+
+    /// Use the given logical block size instead of 512 bytes
+    pub fn with_block_size(mut self, block_size: u32) -> io::Result<Self> {
+        if block_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Block size must not be 0",
+            ));
+        }
+        let len = self.file.metadata()?.len();
+        self.block_size = block_size;
+        self.block_count = len / block_size as u64;
+        Ok(self)
+    }
+}
+
+impl BlockDevice for FileImage {
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.read_exact_at(buf, lba * self.block_size as u64)
+    }
+
+    fn write_blocks(&mut self, lba: u64, data: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        self.file.write_all_at(data, lba * self.block_size as u64)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.file.sync_data()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+/// Block device kept in memory
+#[derive(Debug, Clone)]
+pub struct MemoryDisk {
+    data: Vec<u8>,
+    block_size: u32,
+}
+
+impl MemoryDisk {
+    /// Create a zeroed disk with the given number of blocks of the given size
+    pub fn new(block_count: u64, block_size: u32) -> Self {
+        Self {
+            data: vec![0; (block_count * block_size as u64) as usize],
+            block_size,
+        }
+    }
+
+    /// Use the given data as disk content. Trailing bytes that do not fill a
+    /// whole block are not accessible.
+    pub fn from_vec(data: Vec<u8>, block_size: u32) -> Self {
+        Self { data, block_size }
+    }
+
+    /// The content of the disk
+    pub fn data(&self) -> &[u8] {
+        self.data.as_slice()
+    }
+
+    /// The content of the disk
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        self.data.as_mut_slice()
+    }
+
+    /// Returns the content of the disk
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Returns the byte range of the given blocks
+    fn range(&self, lba: u64, len: usize) -> io::Result<std::ops::Range<usize>> {
+        let start = lba as usize * self.block_size as usize;
+        match start.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(start..end),
+            _ => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+impl BlockDevice for MemoryDisk {
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        match self.block_size {
+            0 => 0,
+            size => (self.data.len() / size as usize) as u64,
+        }
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> io::Result<()> {
+        let range = self.range(lba, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, data: &[u8]) -> io::Result<()> {
+        let range = self.range(lba, data.len())?;
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Write protected view of another block device
+#[derive(Debug, Clone)]
+pub struct ReadOnly<D: BlockDevice>(pub D);
+
+impl<D: BlockDevice> ReadOnly<D> {
+    pub fn new(device: D) -> Self {
+        Self(device)
+    }
+
+    /// Returns the wrapped block device
+    pub fn into_inner(self) -> D {
+        self.0
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for ReadOnly<D> {
+    fn block_size(&self) -> u32 {
+        self.0.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.0.block_count()
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.0.read_blocks(lba, buf)
+    }
+
+    fn write_blocks(&mut self, _lba: u64, _data: &[u8]) -> io::Result<()> {
+        Err(io::ErrorKind::PermissionDenied.into())
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,604 @@
+//! SCSI transparent command set for direct access block devices
+//! https://www.t10.org/drafts.htm (SPC and SBC)
+
+use std::{ops::Range, thread, time::Duration};
+
+use packed_struct::prelude::*;
+
+use super::block::BlockDevice;
+
+/// Size of fixed format sense data
+pub const SENSE_DATA_SIZE: usize = 18;
+/// Size of the standard INQUIRY data
+pub const INQUIRY_DATA_SIZE: usize = 36;
+
+/// SCSI operation codes
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum ScsiOpcode {
+    TestUnitReady = 0x00,
+    RequestSense = 0x03,
+    Inquiry = 0x12,
+    ModeSense6 = 0x1a,
+    StartStopUnit = 0x1b,
+    PreventAllowMediumRemoval = 0x1e,
+    ReadFormatCapacities = 0x23,
+    ReadCapacity10 = 0x25,
+    Read10 = 0x28,
+    Write10 = 0x2a,
+    Verify10 = 0x2f,
+    SynchronizeCache10 = 0x35,
+    ModeSense10 = 0x5a,
+    Read16 = 0x88,
+    Write16 = 0x8a,
+    /// SERVICE ACTION IN(16), used for READ CAPACITY(16)
+    ServiceActionIn16 = 0x9e,
+}
+
+/// Service action of READ CAPACITY(16)
+const READ_CAPACITY_16: u8 = 0x10;
+/// Mode page code of the caching mode page
+const CACHING_MODE_PAGE: u8 = 0x08;
+/// Mode page code requesting all mode pages
+const ALL_MODE_PAGES: u8 = 0x3f;
+
+/// Sense keys
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum SenseKey {
+    NoSense = 0x00,
+    RecoveredError = 0x01,
+    NotReady = 0x02,
+    MediumError = 0x03,
+    HardwareError = 0x04,
+    IllegalRequest = 0x05,
+    UnitAttention = 0x06,
+    DataProtect = 0x07,
+    AbortedCommand = 0x0b,
+}
+
+/// Error condition reported to the host with REQUEST SENSE
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SenseData {
+    pub key: SenseKey,
+    /// Additional sense code
+    pub asc: u8,
+    /// Additional sense code qualifier
+    pub ascq: u8,
+}
+
+impl SenseData {
+    pub const NO_SENSE: Self = Self::new(SenseKey::NoSense, 0x00, 0x00);
+    pub const NOT_READY_INIT_REQUIRED: Self = Self::new(SenseKey::NotReady, 0x04, 0x02);
+    pub const MEDIUM_NOT_PRESENT: Self = Self::new(SenseKey::NotReady, 0x3a, 0x00);
+    pub const MEDIUM_CHANGED: Self = Self::new(SenseKey::UnitAttention, 0x28, 0x00);
+    pub const MODE_PARAMETERS_CHANGED: Self = Self::new(SenseKey::UnitAttention, 0x2a, 0x01);
+    pub const WRITE_ERROR: Self = Self::new(SenseKey::MediumError, 0x0c, 0x00);
+    pub const UNRECOVERED_READ_ERROR: Self = Self::new(SenseKey::MediumError, 0x11, 0x00);
+    pub const INVALID_COMMAND: Self = Self::new(SenseKey::IllegalRequest, 0x20, 0x00);
+    pub const LBA_OUT_OF_RANGE: Self = Self::new(SenseKey::IllegalRequest, 0x21, 0x00);
+    pub const INVALID_FIELD_IN_CDB: Self = Self::new(SenseKey::IllegalRequest, 0x24, 0x00);
+    pub const MEDIUM_REMOVAL_PREVENTED: Self = Self::new(SenseKey::IllegalRequest, 0x53, 0x02);
+    pub const WRITE_PROTECTED: Self = Self::new(SenseKey::DataProtect, 0x27, 0x00);
+
+    pub const fn new(key: SenseKey, asc: u8, ascq: u8) -> Self {
+        Self { key, asc, ascq }
+    }
+
+    /// Returns the sense data in fixed format
+    pub fn to_fixed_format(&self) -> [u8; SENSE_DATA_SIZE] {
+        let mut data = [0; SENSE_DATA_SIZE];
+        // Current error, fixed format
+        data[0] = 0x70;
+        data[2] = self.key.to_primitive();
+        // Additional sense length
+        data[7] = (SENSE_DATA_SIZE - 8) as u8;
+        data[12] = self.asc;
+        data[13] = self.ascq;
+        data
+    }
+}
+
+impl Default for SenseData {
+    fn default() -> Self {
+        Self::NO_SENSE
+    }
+}
+
+/// Result of a SCSI command: the data to send to the host, or the error
+/// condition that REQUEST SENSE will report.
+pub type ScsiResult = Result<Vec<u8>, SenseData>;
+
+/// Kind of an injected fault
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FaultKind {
+    /// Reads fail with UNRECOVERED READ ERROR
+    ReadError,
+    /// Writes fail with WRITE ERROR
+    WriteError,
+    /// Reads and writes complete after the given delay
+    Delay(Duration),
+}
+
+/// Fault injected on a range of logical blocks. It applies to every read or
+/// write command touching one of the blocks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    /// Logical block addresses affected by the fault
+    pub lbas: Range<u64>,
+    pub kind: FaultKind,
+    /// Number of commands the fault still applies to, or None to apply it
+    /// until the faults are cleared
+    pub remaining: Option<u32>,
+}
+
+impl Fault {
+    pub fn new(lbas: Range<u64>, kind: FaultKind) -> Self {
+        Self {
+            lbas,
+            kind,
+            remaining: None,
+        }
+    }
+
+    /// Only apply the fault to the given number of commands, e.g. to
+    /// simulate a transient error that succeeds on retry
+    pub fn times(mut self, count: u32) -> Self {
+        self.remaining = Some(count);
+        self
+    }
+
+    /// Returns true if the fault affects the given block range
+    fn overlaps(&self, lba: u64, blocks: u64) -> bool {
+        self.lbas.start < lba.saturating_add(blocks) && lba < self.lbas.end
+    }
+}
+
+/// Direct access SCSI logical unit backed by a [BlockDevice]
+///
+/// The medium can be removed, replaced and write protected at runtime, and
+/// faults can be injected on ranges of logical blocks.
+#[derive(Debug)]
+pub struct ScsiDisk<D: BlockDevice> {
+    disk: Option<D>,
+    /// Vendor identification reported by INQUIRY (up to 8 characters)
+    pub vendor: String,
+    /// Product identification reported by INQUIRY (up to 16 characters)
+    pub product: String,
+    /// Product revision level reported by INQUIRY (up to 4 characters)
+    pub revision: String,
+    /// Unit serial number reported by the INQUIRY VPD page 0x80
+    pub serial: String,
+    /// Whether the medium is reported as removable
+    pub removable: bool,
+    sense: SenseData,
+    /// Condition reported to the next command with UNIT ATTENTION
+    unit_attention: Option<SenseData>,
+    write_protected: bool,
+    faults: Vec<Fault>,
+    prevent_removal: bool,
+    stopped: bool,
+}
+
+impl<D: BlockDevice> ScsiDisk<D> {
+    pub fn new(disk: D) -> Self {
+        Self::with_medium(Some(disk))
+    }
+
+    /// Create a logical unit without medium
+    pub fn empty() -> Self {
+        Self::with_medium(None)
+    }
+
+    fn with_medium(disk: Option<D>) -> Self {
+        Self {
+            disk,
+            vendor: "Linux".to_string(),
+            product: "Virtual Disk".to_string(),
+            revision: "0100".to_string(),
+            serial: "0123456789AB".to_string(),
+            removable: true,
+            sense: SenseData::NO_SENSE,
+            unit_attention: None,
+            write_protected: false,
+            faults: Vec::new(),
+            prevent_removal: false,
+            stopped: false,
+        }
+    }
+
+    /// The block device of the inserted medium
+    pub fn disk(&self) -> Option<&D> {
+        self.disk.as_ref()
+    }
+
+    /// The block device of the inserted medium
+    pub fn disk_mut(&mut self) -> Option<&mut D> {
+        self.disk.as_mut()
+    }
+
+    /// Returns the block device of the inserted medium
+    pub fn into_inner(self) -> Option<D> {
+        self.disk
+    }
+
+    /// Remove the medium. Commands accessing the medium then fail with
+    /// MEDIUM NOT PRESENT. Returns the removed block device.
+    pub fn eject(&mut self) -> Option<D> {
+        #[cfg(feature = "log")]
+        log::info!("Medium removed");
+        self.disk.take()
+    }
+
+    /// Insert the given medium, replacing the current one. The next command
+    /// fails with UNIT ATTENTION, MEDIUM CHANGED so the host rereads the
+    /// capacity. Returns the replaced block device.
+    pub fn insert(&mut self, disk: D) -> Option<D> {
+        #[cfg(feature = "log")]
+        log::info!("Medium changed");
+        self.unit_attention = Some(SenseData::MEDIUM_CHANGED);
+        self.stopped = false;
+        self.disk.replace(disk)
+    }
+
+    /// Returns true if a medium is inserted
+    pub fn has_medium(&self) -> bool {
+        self.disk.is_some()
+    }
+
+    /// Write protect the medium, in addition to block devices that are read
+    /// only themselves. The host is notified with UNIT ATTENTION, MODE
+    /// PARAMETERS CHANGED.
+    pub fn set_write_protected(&mut self, write_protected: bool) {
+        if write_protected != self.write_protected {
+            self.unit_attention = Some(SenseData::MODE_PARAMETERS_CHANGED);
+        }
+        self.write_protected = write_protected;
+    }
+
+    /// Returns true if writes to the medium are rejected
+    pub fn is_write_protected(&self) -> bool {
+        let read_only = self.disk.as_ref().is_some_and(|disk| disk.is_read_only());
+        self.write_protected || read_only
+    }
+
+    /// Inject the given fault
+    pub fn inject_fault(&mut self, fault: Fault) {
+        self.faults.push(fault);
+    }
+
+    /// Remove all injected faults
+    pub fn clear_faults(&mut self) {
+        self.faults.clear();
+    }
+
+    /// The injected faults that still apply
+    pub fn faults(&self) -> &[Fault] {
+        self.faults.as_slice()
+    }
+
+    /// Sense data of the last failed command
+    pub fn sense(&self) -> SenseData {
+        self.sense
+    }
+
+    /// Whether the host prevents medium removal with PREVENT ALLOW MEDIUM
+    /// REMOVAL
+    pub fn is_removal_prevented(&self) -> bool {
+        self.prevent_removal
+    }
+
+    /// Whether the host stopped the unit with START STOP UNIT
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Returns the number of bytes the given command expects from the host
+    /// in its data stage
+    pub fn data_out_length(&self, cdb: &[u8]) -> usize {
+        let blocks = match cdb.first().and_then(|op| ScsiOpcode::from_primitive(*op)) {
+            Some(ScsiOpcode::Write10) => field(cdb, 7, 2),
+            Some(ScsiOpcode::Write16) => field(cdb, 10, 4),
+            _ => return 0,
+        };
+        let block_size = self.disk.as_ref().map_or(0, |disk| disk.block_size());
+        (blocks.unwrap_or(0) * block_size as u64) as usize
+    }
+
+    /// Execute the given command block. Data sent by the host in the data
+    /// stage of the command is passed in `data`. Returns the data to send to
+    /// the host, or the error condition stored for REQUEST SENSE.
+    pub fn execute(&mut self, cdb: &[u8], data: &[u8]) -> ScsiResult {
+        let result = self.dispatch(cdb, data);
+        match result {
+            Ok(_) => {
+                if cdb.first() != Some(&(ScsiOpcode::RequestSense as u8)) {
+                    self.sense = SenseData::NO_SENSE;
+                }
+            }
+            Err(sense) => {
+                #[cfg(feature = "log")]
+                log::debug!("SCSI command {:#x?} failed: {sense:?}", cdb.first());
+                self.sense = sense;
+            }
+        }
+        result
+    }
+
+    fn dispatch(&mut self, cdb: &[u8], data: &[u8]) -> ScsiResult {
+        let Some(opcode) = cdb.first() else {
+            return Err(SenseData::INVALID_COMMAND);
+        };
+        let Some(opcode) = ScsiOpcode::from_primitive(*opcode) else {
+            #[cfg(feature = "log")]
+            log::debug!("Unsupported SCSI command {opcode:#x}");
+            return Err(SenseData::INVALID_COMMAND);
+        };
+        #[cfg(feature = "log")]
+        log::trace!("SCSI {opcode:?}");
+
+        // A pending unit attention condition fails the next command, except
+        // for commands that report it or do not depend on it
+        if !matches!(opcode, ScsiOpcode::Inquiry | ScsiOpcode::RequestSense) {
+            if let Some(sense) = self.unit_attention.take() {
+                return Err(sense);
+            }
+        }
+
+        // Commands that access the medium fail while there is none or the
+        // unit is stopped
+        let medium_access = !matches!(
+            opcode,
+            ScsiOpcode::Inquiry
+                | ScsiOpcode::RequestSense
+                | ScsiOpcode::StartStopUnit
+                | ScsiOpcode::PreventAllowMediumRemoval
+        );
+        if medium_access && self.disk.is_none() {
+            return Err(SenseData::MEDIUM_NOT_PRESENT);
+        }
+        if medium_access && self.stopped {
+            return Err(SenseData::NOT_READY_INIT_REQUIRED);
+        }
+
+        match opcode {
+            ScsiOpcode::TestUnitReady | ScsiOpcode::Verify10 => Ok(Vec::new()),
+            ScsiOpcode::RequestSense => {
+                let sense = self.unit_attention.take().unwrap_or(self.sense);
+                let mut sense = sense.to_fixed_format().to_vec();
+                sense.truncate(field(cdb, 4, 1)? as usize);
+                self.sense = SenseData::NO_SENSE;
+                Ok(sense)
+            }
+            ScsiOpcode::Inquiry => self.inquiry(cdb),
+            ScsiOpcode::ModeSense6 => self.mode_sense(cdb, false),
+            ScsiOpcode::ModeSense10 => self.mode_sense(cdb, true),
+            ScsiOpcode::StartStopUnit => {
+                let start = field(cdb, 4, 1)? & 0x01 != 0;
+                let load_eject = field(cdb, 4, 1)? & 0x02 != 0;
+                if !start && load_eject && self.prevent_removal {
+                    return Err(SenseData::MEDIUM_REMOVAL_PREVENTED);
+                }
+                self.stopped = !start;
+                Ok(Vec::new())
+            }
+            ScsiOpcode::PreventAllowMediumRemoval => {
+                self.prevent_removal = field(cdb, 4, 1)? & 0x01 != 0;
+                Ok(Vec::new())
+            }
+            ScsiOpcode::ReadFormatCapacities => {
+                let disk = self.medium()?;
+                let mut capacities = vec![0, 0, 0, 8];
+                let blocks = disk.block_count().min(u32::MAX as u64) as u32;
+                capacities.extend_from_slice(&blocks.to_be_bytes());
+                // Formatted media, followed by the 24-bit block length
+                capacities.push(0x02);
+                capacities.extend_from_slice(&disk.block_size().to_be_bytes()[1..]);
+                capacities.truncate(field(cdb, 7, 2)? as usize);
+                Ok(capacities)
+            }
+            ScsiOpcode::ReadCapacity10 => {
+                let disk = self.medium()?;
+                let last_lba = disk.block_count().saturating_sub(1);
+                let mut capacity = last_lba.min(u32::MAX as u64).to_be_bytes()[4..].to_vec();
+                capacity.extend_from_slice(&disk.block_size().to_be_bytes());
+                Ok(capacity)
+            }
+            ScsiOpcode::ServiceActionIn16 => {
+                if field(cdb, 1, 1)? & 0x1f != READ_CAPACITY_16 as u64 {
+                    return Err(SenseData::INVALID_FIELD_IN_CDB);
+                }
+                let disk = self.medium()?;
+                let last_lba = disk.block_count().saturating_sub(1);
+                let mut capacity = vec![0; 32];
+                capacity[..8].copy_from_slice(&last_lba.to_be_bytes());
+                capacity[8..12].copy_from_slice(&disk.block_size().to_be_bytes());
+                capacity.truncate(field(cdb, 10, 4)? as usize);
+                Ok(capacity)
+            }
+            ScsiOpcode::Read10 => self.read(field(cdb, 2, 4)?, field(cdb, 7, 2)?),
+            ScsiOpcode::Read16 => self.read(field(cdb, 2, 8)?, field(cdb, 10, 4)?),
+            ScsiOpcode::Write10 => self.write(field(cdb, 2, 4)?, field(cdb, 7, 2)?, data),
+            ScsiOpcode::Write16 => self.write(field(cdb, 2, 8)?, field(cdb, 10, 4)?, data),
+            ScsiOpcode::SynchronizeCache10 => match self.medium()?.flush() {
+                Ok(()) => Ok(Vec::new()),
+                Err(_) => Err(SenseData::WRITE_ERROR),
+            },
+        }
+    }
+
+    /// Returns the inserted medium
+    fn medium(&mut self) -> Result<&mut D, SenseData> {
+        self.disk.as_mut().ok_or(SenseData::MEDIUM_NOT_PRESENT)
+    }
+
+    /// Handle INQUIRY with the standard data or the supported VPD pages
+    fn inquiry(&self, cdb: &[u8]) -> ScsiResult {
+        let evpd = field(cdb, 1, 1)? & 0x01 != 0;
+        let page = field(cdb, 2, 1)? as u8;
+        let allocation_length = field(cdb, 3, 2)? as usize;
+
+        let mut data = match (evpd, page) {
+            (false, 0x00) => {
+                let mut data = vec![0; INQUIRY_DATA_SIZE];
+                // Direct access block device
+                data[0] = 0x00;
+                data[1] = if self.removable { 0x80 } else { 0x00 };
+                // SCSI-2, response data format 2
+                data[2] = 0x02;
+                data[3] = 0x02;
+                data[4] = (INQUIRY_DATA_SIZE - 5) as u8;
+                copy_padded(&mut data[8..16], &self.vendor);
+                copy_padded(&mut data[16..32], &self.product);
+                copy_padded(&mut data[32..36], &self.revision);
+                data
+            }
+            // Supported VPD pages
+            (true, 0x00) => vec![0x00, 0x00, 0x00, 0x02, 0x00, 0x80],
+            // Unit serial number
+            (true, 0x80) => {
+                let mut data = vec![0x00, 0x80, 0x00, self.serial.len() as u8];
+                data.extend_from_slice(self.serial.as_bytes());
+                data
+            }
+            _ => return Err(SenseData::INVALID_FIELD_IN_CDB),
+        };
+        data.truncate(allocation_length);
+        Ok(data)
+    }
+
+    /// Handle MODE SENSE(6) and MODE SENSE(10). Only the caching mode page
+    /// is reported, with the write cache disabled.
+    fn mode_sense(&self, cdb: &[u8], ten: bool) -> ScsiResult {
+        let page = field(cdb, 2, 1)? as u8 & 0x3f;
+        let allocation_length = match ten {
+            true => field(cdb, 7, 2)?,
+            false => field(cdb, 4, 1)?,
+        } as usize;
+
+        let mut pages = Vec::new();
+        match page {
+            CACHING_MODE_PAGE | ALL_MODE_PAGES => {
+                let mut caching = vec![0; 20];
+                caching[0] = CACHING_MODE_PAGE;
+                caching[1] = 18;
+                pages.append(&mut caching);
+            }
+            _ => return Err(SenseData::INVALID_FIELD_IN_CDB),
+        }
+
+        // The device specific parameter reports write protection
+        let device_specific = if self.is_write_protected() {
+            0x80
+        } else {
+            0x00
+        };
+        let mut data = match ten {
+            true => {
+                let len = (6 + pages.len()) as u16;
+                let mut header = len.to_be_bytes().to_vec();
+                header.extend_from_slice(&[0x00, device_specific, 0, 0, 0, 0]);
+                header
+            }
+            false => vec![(3 + pages.len()) as u8, 0x00, device_specific, 0],
+        };
+        data.append(&mut pages);
+        data.truncate(allocation_length);
+        Ok(data)
+    }
+
+    /// Returns an error if the given block range is outside of the medium
+    fn check_range(&mut self, lba: u64, blocks: u64) -> Result<(), SenseData> {
+        let block_count = self.medium()?.block_count();
+        match lba.checked_add(blocks) {
+            Some(end) if end <= block_count => Ok(()),
+            _ => Err(SenseData::LBA_OUT_OF_RANGE),
+        }
+    }
+
+    /// Apply the injected faults affecting the given block range. Returns
+    /// the error of the first matching error fault.
+    fn apply_faults(&mut self, lba: u64, blocks: u64, write: bool) -> Result<(), SenseData> {
+        let mut result = Ok(());
+        for fault in self.faults.iter_mut() {
+            if !fault.overlaps(lba, blocks) {
+                continue;
+            }
+            let error = match fault.kind {
+                FaultKind::Delay(delay) => {
+                    thread::sleep(delay);
+                    None
+                }
+                FaultKind::ReadError if !write => Some(SenseData::UNRECOVERED_READ_ERROR),
+                FaultKind::WriteError if write => Some(SenseData::WRITE_ERROR),
+                _ => continue,
+            };
+            if let Some(remaining) = fault.remaining.as_mut() {
+                *remaining = remaining.saturating_sub(1);
+            }
+            if let (Some(error), Ok(())) = (error, result) {
+                #[cfg(feature = "log")]
+                log::debug!("Injecting {:?} at {lba}", fault.kind);
+                result = Err(error);
+            }
+        }
+        self.faults.retain(|fault| fault.remaining != Some(0));
+        result
+    }
+
+    fn read(&mut self, lba: u64, blocks: u64) -> ScsiResult {
+        self.check_range(lba, blocks)?;
+        self.apply_faults(lba, blocks, false)?;
+        let disk = self.medium()?;
+        let mut data = vec![0; (blocks * disk.block_size() as u64) as usize];
+        match disk.read_blocks(lba, &mut data) {
+            Ok(()) => Ok(data),
+            Err(_e) => {
+                #[cfg(feature = "log")]
+                log::warn!("Failed to read {blocks} blocks at {lba}: {_e}");
+                Err(SenseData::UNRECOVERED_READ_ERROR)
+            }
+        }
+    }
+
+    fn write(&mut self, lba: u64, blocks: u64, data: &[u8]) -> ScsiResult {
+        if self.is_write_protected() {
+            return Err(SenseData::WRITE_PROTECTED);
+        }
+        self.check_range(lba, blocks)?;
+        self.apply_faults(lba, blocks, true)?;
+        let disk = self.medium()?;
+        let len = (blocks * disk.block_size() as u64) as usize;
+        let Some(data) = data.get(..len) else {
+            return Err(SenseData::INVALID_FIELD_IN_CDB);
+        };
+        match disk.write_blocks(lba, data) {
+            Ok(()) => Ok(Vec::new()),
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                Err(SenseData::WRITE_PROTECTED)
+            }
+            Err(_e) => {
+                #[cfg(feature = "log")]
+                log::warn!("Failed to write {blocks} blocks at {lba}: {_e}");
+                Err(SenseData::WRITE_ERROR)
+            }
+        }
+    }
+}
+
+/// Returns the big endian field of the given size at the given offset of the
+/// command block
+fn field(cdb: &[u8], offset: usize, size: usize) -> Result<u64, SenseData> {
+    let Some(bytes) = cdb.get(offset..offset + size) else {
+        return Err(SenseData::INVALID_FIELD_IN_CDB);
+    };
+    Ok(bytes
+        .iter()
+        .fold(0, |value, byte| (value << 8) | *byte as u64))
+}
+
+/// Copy the given text into the given field, padded with spaces
+fn copy_padded(field: &mut [u8], text: &str) {
+    field.fill(b' ');
+    for (dst, src) in field.iter_mut().zip(text.bytes()) {
+        *dst = src;
+    }
+}
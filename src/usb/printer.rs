@@ -0,0 +1,309 @@
+//! Printer class
+//! https://www.usb.org/sites/default/files/usbprint11a021811.pdf
+
+use std::{
+    fmt::{Debug, Display},
+    sync::{Arc, Mutex},
+};
+
+use packed_struct::prelude::*;
+
+use super::{
+    Direction, EndpointBuilder, EndpointDescriptor, Interface, InterfaceClass, InterfaceDescriptor,
+    SynchronizationType, TransferType, UsageType,
+};
+
+/// Printer interface subclass code
+pub const PRINTER_SUBCLASS: u8 = 0x01;
+
+/// Printer interface protocol codes
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PrinterProtocol {
+    /// Bulk OUT endpoint only
+    Unidirectional = 0x01,
+    /// Bulk OUT endpoint, and bulk IN endpoint for status
+    Bidirectional = 0x02,
+    /// Bidirectional with the IEEE 1284.4 packet protocol
+    Ieee1284_4 = 0x03,
+}
+
+/// Printer class requests (bRequest)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum PrinterRequest {
+    GetDeviceId = 0x00,
+    GetPortStatus = 0x01,
+    SoftReset = 0x02,
+}
+
+/// Bits of the status byte returned by GET_PORT_STATUS, named after the
+/// parallel port signals they emulate
+pub mod port_status {
+    /// Not Error: cleared when the printer has an error
+    pub const NOT_ERROR: u8 = 0x08;
+    /// Select: set when the printer is online
+    pub const SELECT: u8 = 0x10;
+    /// Paper Empty
+    pub const PAPER_EMPTY: u8 = 0x20;
+}
+
+/// Port status of a printer, shared between the interface and the code
+/// emulating the printer mechanism
+#[derive(Debug, Clone)]
+pub struct PortStatus(Arc<Mutex<u8>>);
+
+impl PortStatus {
+    /// Create the status of an online printer without error
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(
+            port_status::SELECT | port_status::NOT_ERROR,
+        )))
+    }
+
+    /// Returns the status byte (see [port_status])
+    pub fn get(&self) -> u8 {
+        self.0.lock().map(|status| *status).unwrap_or_default()
+    }
+
+    /// Set or clear the given bits of the status byte
+    fn set(&self, bits: u8, value: bool) {
+        if let Ok(mut status) = self.0.lock() {
+            if value {
+                *status |= bits;
+            } else {
+                *status &= !bits;
+            }
+        }
+    }
+
+    /// Report that the printer is out of paper
+    pub fn set_paper_empty(&self, empty: bool) {
+        self.set(port_status::PAPER_EMPTY, empty);
+    }
+
+    /// Report that the printer has an error
+    pub fn set_error(&self, error: bool) {
+        self.set(port_status::NOT_ERROR, !error);
+    }
+
+    /// Report whether the printer is online
+    pub fn set_selected(&self, selected: bool) {
+        self.set(port_status::SELECT, selected);
+    }
+}
+
+impl Default for PortStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Callback called when the host sends SOFT_RESET
+pub type PrinterResetHandler = Arc<Mutex<dyn FnMut() + Send>>;
+
+/// Soft reset handlers of a printer interface
+#[derive(Clone, Default)]
+pub struct PrinterResetHandlers(Vec<PrinterResetHandler>);
+
+impl PrinterResetHandlers {
+    /// Call all handlers
+    pub fn dispatch(&self) {
+        for handler in self.0.iter() {
+            if let Ok(mut handler) = handler.lock() {
+                handler();
+            }
+        }
+    }
+}
+
+impl Debug for PrinterResetHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrinterResetHandlers")
+            .field("count", &self.0.len())
+            .finish()
+    }
+}
+
+/// Printer interface definition
+#[derive(Debug, Clone)]
+pub struct PrinterInterface {
+    pub iface: InterfaceDescriptor,
+    pub endpoint_descriptors: Vec<EndpointDescriptor>,
+    /// IEEE 1284 device ID returned by GET_DEVICE_ID (e.g.
+    /// "MFG:Acme;MDL:LaserJet;CMD:PCL,PJL;CLS:PRINTER;")
+    pub device_id: String,
+    pub port_status: PortStatus,
+    pub handlers: PrinterResetHandlers,
+}
+
+impl PrinterInterface {
+    pub fn new() -> Self {
+        let iface = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            b_interface_class: InterfaceClass::Printer,
+            b_interface_subclass: PRINTER_SUBCLASS,
+            b_interface_protocol: PrinterProtocol::Bidirectional as u8,
+            ..InterfaceDescriptor::new()
+        };
+
+        Self {
+            iface,
+            endpoint_descriptors: Vec::new(),
+            device_id: "MFG:Generic;MDL:Virtual Printer;CMD:PS,PDF;CLS:PRINTER;".to_string(),
+            port_status: PortStatus::new(),
+            handlers: PrinterResetHandlers::default(),
+        }
+    }
+
+    /// Register a handler called when the host sends SOFT_RESET. The
+    /// handler should discard buffered print data.
+    pub fn on_soft_reset<F>(&mut self, handler: F)
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let handler: PrinterResetHandler = Arc::new(Mutex::new(handler));
+        self.handlers.0.push(handler);
+    }
+
+    /// Handle SOFT_RESET
+    pub fn handle_soft_reset(&self) {
+        self.handlers.dispatch();
+    }
+
+    /// Returns the reply to GET_DEVICE_ID: the device ID prefixed with its
+    /// big endian length, which includes the two length bytes.
+    pub fn device_id_response(&self) -> Vec<u8> {
+        let id = self.device_id.as_bytes();
+        let len = (id.len() + 2).min(u16::MAX as usize) as u16;
+        let mut data = len.to_be_bytes().to_vec();
+        data.extend_from_slice(&id[..len as usize - 2]);
+        data
+    }
+
+    /// Serialize the interface into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.get_size());
+        result.append(&mut self.iface.pack_to_vec()?);
+        for endpoint_desc in self.endpoint_descriptors.iter() {
+            result.append(&mut endpoint_desc.pack_to_vec()?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the byte serialized size of the interface
+    pub fn get_size(&self) -> usize {
+        9 + (7 * self.endpoint_descriptors.len())
+    }
+
+    /// Returns the interface class
+    pub fn get_class(&self) -> InterfaceClass {
+        self.iface.b_interface_class
+    }
+
+    /// Set the interface number for this interface
+    pub fn set_interface_number(&mut self, num: u8) {
+        self.iface.b_interface_number = num;
+    }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        self.endpoint_descriptors.as_slice()
+    }
+}
+
+impl Display for PrinterInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut text = vec![format!("{}", self.iface)];
+        for desc in self.endpoint_descriptors.iter() {
+            text.push(format!("{}", desc));
+        }
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+impl Default for PrinterInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [Interface] builder for constructing a printer interface.
+pub struct PrinterInterfaceBuilder {
+    iface: PrinterInterface,
+}
+
+impl PrinterInterfaceBuilder {
+    pub fn new() -> Self {
+        Self {
+            iface: PrinterInterface::default(),
+        }
+    }
+
+    /// Construct the new Interface configuration.
+    pub fn build(&self) -> Interface {
+        #[cfg(feature = "log")]
+        log::debug!("Printer Interface: {}", self.iface);
+        Interface::Printer(self.iface.clone())
+    }
+
+    /// Set the interface protocol. Unidirectional printers only have a bulk
+    /// OUT endpoint.
+    pub fn protocol(&mut self, protocol: PrinterProtocol) -> &mut Self {
+        self.iface.iface.b_interface_protocol = protocol as u8;
+        self
+    }
+
+    /// Set the IEEE 1284 device ID returned by GET_DEVICE_ID
+    pub fn device_id(&mut self, device_id: &str) -> &mut Self {
+        self.iface.device_id = device_id.to_string();
+        self
+    }
+
+    /// Share the given port status with the interface, so it can be changed
+    /// after the interface is built
+    pub fn port_status(&mut self, status: &PortStatus) -> &mut Self {
+        self.iface.port_status = status.clone();
+        self
+    }
+
+    /// Handle SOFT_RESET requests. See [PrinterInterface::on_soft_reset].
+    pub fn on_soft_reset<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: FnMut() + Send + 'static,
+    {
+        self.iface.on_soft_reset(handler);
+        self
+    }
+
+    /// Add a bulk endpoint with the given number, direction and max packet
+    /// size (64 for full speed, 512 for high speed).
+    pub fn bulk_endpoint(
+        &mut self,
+        num: u8,
+        direction: Direction,
+        max_packet_size: u16,
+    ) -> &mut Self {
+        let descriptor = EndpointBuilder::new()
+            .address_num(num)
+            .direction(direction)
+            .transfer_type(TransferType::Bulk)
+            .sync_type(SynchronizationType::NoSynchronization)
+            .usage_type(UsageType::Data)
+            .max_packet_size(max_packet_size)
+            .build();
+        self.endpoint_descriptor(descriptor)
+    }
+
+    /// Add the given endpoint to the interface
+    pub fn endpoint_descriptor(&mut self, descriptor: EndpointDescriptor) -> &mut Self {
+        self.iface.endpoint_descriptors.push(descriptor);
+        self.iface.iface.b_num_endpoints = self.iface.endpoint_descriptors.len() as u8;
+        self
+    }
+}
+
+impl Default for PrinterInterfaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
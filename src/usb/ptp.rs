@@ -0,0 +1,827 @@
+//! Still Image Capture Device class with the Picture Transfer Protocol
+//! (PTP, ISO 15740) and its Media Transfer Protocol (MTP) extension
+//! https://www.usb.org/sites/default/files/usb_still_img10.zip
+//!
+//! Operations, data and responses are exchanged on the bulk endpoints as
+//! containers with a 12 byte header, and events are sent as containers on
+//! the interrupt endpoint. Multi-byte values are little endian.
+
+use std::{
+    fmt::{Debug, Display},
+    sync::{Arc, Mutex},
+};
+
+use packed_struct::prelude::*;
+
+use super::{
+    Direction, EndpointBuilder, EndpointDescriptor, Interface, InterfaceClass, InterfaceDescriptor,
+    SynchronizationType, TransferType, UsageType,
+};
+
+/// Still Image Capture Device subclass code
+pub const STILL_IMAGE_SUBCLASS: u8 = 0x01;
+/// PIMA 15740 bulk-only protocol code
+pub const PTP_PROTOCOL: u8 = 0x01;
+/// Size of the header of containers
+pub const CONTAINER_HEADER_SIZE: usize = 12;
+/// Version of the PTP specification (1.00)
+pub const PTP_VERSION: u16 = 100;
+/// Vendor extension ID of MTP
+pub const MTP_VENDOR_EXTENSION_ID: u32 = 6;
+/// Version of the MTP vendor extension (1.00)
+pub const MTP_VENDOR_EXTENSION_VERSION: u16 = 100;
+/// Description of the MTP vendor extension in the device info
+pub const MTP_VENDOR_EXTENSION_DESC: &str = "microsoft.com: 1.0";
+/// Interface string identifying MTP devices
+pub const MTP_INTERFACE_NAME: &str = "MTP";
+
+/// Still Image class requests (bRequest)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum StillImageRequest {
+    CancelRequest = 0x64,
+    GetExtendedEventData = 0x65,
+    DeviceReset = 0x66,
+    GetDeviceStatus = 0x67,
+}
+
+/// Types of containers
+#[derive(PrimitiveEnum_u16, Debug, Copy, Clone, PartialEq)]
+pub enum ContainerType {
+    Command = 1,
+    Data = 2,
+    Response = 3,
+    Event = 4,
+}
+
+/// Operation codes
+pub mod operation {
+    pub const GET_DEVICE_INFO: u16 = 0x1001;
+    pub const OPEN_SESSION: u16 = 0x1002;
+    pub const CLOSE_SESSION: u16 = 0x1003;
+    pub const GET_STORAGE_IDS: u16 = 0x1004;
+    pub const GET_STORAGE_INFO: u16 = 0x1005;
+    pub const GET_NUM_OBJECTS: u16 = 0x1006;
+    pub const GET_OBJECT_HANDLES: u16 = 0x1007;
+    pub const GET_OBJECT_INFO: u16 = 0x1008;
+    pub const GET_OBJECT: u16 = 0x1009;
+    pub const GET_THUMB: u16 = 0x100a;
+    pub const DELETE_OBJECT: u16 = 0x100b;
+    pub const SEND_OBJECT_INFO: u16 = 0x100c;
+    pub const SEND_OBJECT: u16 = 0x100d;
+    pub const GET_PARTIAL_OBJECT: u16 = 0x101b;
+}
+
+/// Response codes
+pub mod response {
+    pub const OK: u16 = 0x2001;
+    pub const GENERAL_ERROR: u16 = 0x2002;
+    pub const SESSION_NOT_OPEN: u16 = 0x2003;
+    pub const INVALID_TRANSACTION_ID: u16 = 0x2004;
+    pub const OPERATION_NOT_SUPPORTED: u16 = 0x2005;
+    pub const PARAMETER_NOT_SUPPORTED: u16 = 0x2006;
+    pub const INCOMPLETE_TRANSFER: u16 = 0x2007;
+    pub const INVALID_STORAGE_ID: u16 = 0x2008;
+    pub const INVALID_OBJECT_HANDLE: u16 = 0x2009;
+    pub const INVALID_OBJECT_FORMAT_CODE: u16 = 0x200b;
+    pub const STORE_FULL: u16 = 0x200c;
+    pub const OBJECT_WRITE_PROTECTED: u16 = 0x200d;
+    pub const STORE_READ_ONLY: u16 = 0x200e;
+    pub const ACCESS_DENIED: u16 = 0x200f;
+    pub const NO_THUMBNAIL_PRESENT: u16 = 0x2010;
+    pub const NO_VALID_OBJECT_INFO: u16 = 0x2015;
+    pub const DEVICE_BUSY: u16 = 0x2019;
+    pub const INVALID_PARENT_OBJECT: u16 = 0x201a;
+    pub const INVALID_PARAMETER: u16 = 0x201d;
+    pub const SESSION_ALREADY_OPEN: u16 = 0x201e;
+    pub const TRANSACTION_CANCELLED: u16 = 0x201f;
+}
+
+/// Event codes
+pub mod event {
+    pub const CANCEL_TRANSACTION: u16 = 0x4001;
+    pub const OBJECT_ADDED: u16 = 0x4002;
+    pub const OBJECT_REMOVED: u16 = 0x4003;
+    pub const STORE_ADDED: u16 = 0x4004;
+    pub const STORE_REMOVED: u16 = 0x4005;
+    pub const OBJECT_INFO_CHANGED: u16 = 0x4007;
+    pub const DEVICE_INFO_CHANGED: u16 = 0x4008;
+    pub const STORE_FULL: u16 = 0x400a;
+    pub const DEVICE_RESET: u16 = 0x400b;
+    pub const STORAGE_INFO_CHANGED: u16 = 0x400c;
+}
+
+/// Object format codes
+pub mod format {
+    pub const UNDEFINED: u16 = 0x3000;
+    /// Folder
+    pub const ASSOCIATION: u16 = 0x3001;
+    pub const TEXT: u16 = 0x3004;
+    pub const HTML: u16 = 0x3005;
+    pub const WAV: u16 = 0x3008;
+    pub const MP3: u16 = 0x3009;
+    pub const AVI: u16 = 0x300a;
+    pub const MPEG: u16 = 0x300b;
+    pub const EXIF_JPEG: u16 = 0x3801;
+    pub const BMP: u16 = 0x3804;
+    pub const GIF: u16 = 0x3807;
+    pub const JFIF: u16 = 0x3808;
+    pub const PNG: u16 = 0x380b;
+    pub const TIFF: u16 = 0x380d;
+}
+
+/// Association type of folders (GenericFolder)
+pub const GENERIC_FOLDER: u16 = 0x0001;
+/// Parent handle of the objects at the root of a storage
+pub const ROOT_PARENT: u32 = 0;
+/// GetObjectHandles and DeleteObject: all objects. GetObjectHandles also
+/// uses it as parent for the objects at the root.
+pub const ALL_OBJECTS: u32 = 0xffff_ffff;
+
+/// Header of the containers exchanged on the bulk and interrupt endpoints
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "12")]
+pub struct ContainerHeader {
+    /// Size of the whole container, including the header
+    #[packed_field(bytes = "0..=3", endian = "lsb")]
+    pub length: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "4..=5", endian = "lsb")]
+    pub container_type: Integer<u16, packed_bits::Bits<16>>,
+    /// Operation, response or event code
+    #[packed_field(bytes = "6..=7", endian = "lsb")]
+    pub code: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "8..=11", endian = "lsb")]
+    pub transaction_id: Integer<u32, packed_bits::Bits<32>>,
+}
+
+impl ContainerHeader {
+    /// Returns the size of the whole container
+    pub fn length(&self) -> usize {
+        self.length.to_primitive() as usize
+    }
+
+    /// Returns the type of the container, if valid
+    pub fn container_type(&self) -> Option<ContainerType> {
+        ContainerType::from_primitive(self.container_type.to_primitive())
+    }
+}
+
+/// Operation, data, response or event container
+#[derive(Debug, Clone, PartialEq)]
+pub struct Container {
+    pub container_type: ContainerType,
+    pub code: u16,
+    pub transaction_id: u32,
+    /// Parameters of operations, responses and events, or data
+    pub payload: Vec<u8>,
+}
+
+impl Container {
+    /// Create a container with the given parameters
+    pub fn with_parameters(
+        container_type: ContainerType,
+        code: u16,
+        transaction_id: u32,
+        parameters: &[u32],
+    ) -> Self {
+        Self {
+            container_type,
+            code,
+            transaction_id,
+            payload: parameters.iter().flat_map(|p| p.to_le_bytes()).collect(),
+        }
+    }
+
+    /// Create a response container
+    pub fn response(code: u16, transaction_id: u32, parameters: &[u32]) -> Self {
+        Self::with_parameters(ContainerType::Response, code, transaction_id, parameters)
+    }
+
+    /// Create an event container
+    pub fn event(code: u16, transaction_id: u32, parameters: &[u32]) -> Self {
+        Self::with_parameters(ContainerType::Event, code, transaction_id, parameters)
+    }
+
+    /// Create a data container for the operation with the given code
+    pub fn data(code: u16, transaction_id: u32, data: Vec<u8>) -> Self {
+        Self {
+            container_type: ContainerType::Data,
+            code,
+            transaction_id,
+            payload: data,
+        }
+    }
+
+    /// Returns the parameters of an operation, response or event
+    pub fn parameters(&self) -> Vec<u32> {
+        self.payload
+            .chunks_exact(4)
+            .map(|p| u32::from_le_bytes([p[0], p[1], p[2], p[3]]))
+            .collect()
+    }
+
+    /// Returns the parameter at the given index, or 0 if it is missing
+    pub fn parameter(&self, index: usize) -> u32 {
+        self.parameters().get(index).copied().unwrap_or_default()
+    }
+
+    /// Returns the header of the container
+    pub fn header(&self) -> ContainerHeader {
+        let length = (CONTAINER_HEADER_SIZE + self.payload.len()).min(u32::MAX as usize);
+        ContainerHeader {
+            length: Integer::from_primitive(length as u32),
+            container_type: Integer::from_primitive(self.container_type.to_primitive()),
+            code: Integer::from_primitive(self.code),
+            transaction_id: Integer::from_primitive(self.transaction_id),
+        }
+    }
+
+    /// Parse a whole container
+    pub fn unpack(data: &[u8]) -> Result<Self, PackingError> {
+        if data.len() < CONTAINER_HEADER_SIZE {
+            return Err(PackingError::BufferTooSmall);
+        }
+        let header = ContainerHeader::unpack_from_slice(&data[..CONTAINER_HEADER_SIZE])?;
+        let Some(container_type) = header.container_type() else {
+            return Err(PackingError::InvalidValue);
+        };
+        let end = header.length().clamp(CONTAINER_HEADER_SIZE, data.len());
+        Ok(Self {
+            container_type,
+            code: header.code.to_primitive(),
+            transaction_id: header.transaction_id.to_primitive(),
+            payload: data[CONTAINER_HEADER_SIZE..end].to_vec(),
+        })
+    }
+
+    /// Serialize the container into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result = self.header().pack_to_vec()?;
+        result.extend_from_slice(&self.payload);
+        Ok(result)
+    }
+}
+
+/// Encoder of PTP datasets
+#[derive(Debug, Clone, Default)]
+pub struct DataWriter(Vec<u8>);
+
+impl DataWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.0.push(value);
+        self
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// Write a string as its number of UCS-2 characters, including the
+    /// terminating null, followed by the characters. Empty strings are a
+    /// single zero byte, and long strings are truncated to 254 characters.
+    pub fn string(&mut self, value: &str) -> &mut Self {
+        let chars: Vec<u16> = value.encode_utf16().take(254).collect();
+        if chars.is_empty() {
+            return self.u8(0);
+        }
+        self.u8(chars.len() as u8 + 1);
+        for c in chars {
+            self.u16(c);
+        }
+        self.u16(0)
+    }
+
+    /// Write an array of 16-bit values, preceded by its length
+    pub fn u16_array(&mut self, values: &[u16]) -> &mut Self {
+        self.u32(values.len() as u32);
+        for &value in values {
+            self.u16(value);
+        }
+        self
+    }
+
+    /// Write an array of 32-bit values, preceded by its length
+    pub fn u32_array(&mut self, values: &[u32]) -> &mut Self {
+        self.u32(values.len() as u32);
+        for &value in values {
+            self.u32(value);
+        }
+        self
+    }
+
+    /// Returns the encoded dataset
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Decoder of PTP datasets. Reads past the end of the data return None.
+#[derive(Debug, Clone)]
+pub struct DataReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> DataReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.data.get(self.offset..self.offset + N)?;
+        self.offset += N;
+        bytes.try_into().ok()
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes::<1>().map(|b| b[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    /// Read a string (see [DataWriter::string])
+    pub fn string(&mut self) -> Option<String> {
+        let len = self.u8()? as usize;
+        let chars = (0..len).map(|_| self.u16()).collect::<Option<Vec<u16>>>()?;
+        let chars: Vec<u16> = chars.into_iter().take_while(|&c| c != 0).collect();
+        Some(String::from_utf16_lossy(&chars))
+    }
+}
+
+/// DeviceInfo dataset, returned by GetDeviceInfo
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DeviceInfo {
+    pub vendor_extension_id: u32,
+    pub vendor_extension_version: u16,
+    pub vendor_extension_desc: String,
+    pub operations: Vec<u16>,
+    pub events: Vec<u16>,
+    pub capture_formats: Vec<u16>,
+    pub image_formats: Vec<u16>,
+    pub manufacturer: String,
+    pub model: String,
+    pub device_version: String,
+    pub serial_number: String,
+}
+
+impl DeviceInfo {
+    /// Serialize the dataset into bytes
+    pub fn pack_to_vec(&self) -> Vec<u8> {
+        let mut data = DataWriter::new();
+        data.u16(PTP_VERSION)
+            .u32(self.vendor_extension_id)
+            .u16(self.vendor_extension_version)
+            .string(&self.vendor_extension_desc)
+            // Functional mode: standard
+            .u16(0)
+            .u16_array(&self.operations)
+            .u16_array(&self.events)
+            // No device properties
+            .u16_array(&[])
+            .u16_array(&self.capture_formats)
+            .u16_array(&self.image_formats)
+            .string(&self.manufacturer)
+            .string(&self.model)
+            .string(&self.device_version)
+            .string(&self.serial_number);
+        data.into_vec()
+    }
+}
+
+/// Storage types of the StorageInfo dataset
+pub mod storage_type {
+    pub const FIXED_ROM: u16 = 0x0001;
+    pub const REMOVABLE_ROM: u16 = 0x0002;
+    pub const FIXED_RAM: u16 = 0x0003;
+    pub const REMOVABLE_RAM: u16 = 0x0004;
+}
+
+/// StorageInfo dataset, returned by GetStorageInfo
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageInfo {
+    /// See [storage_type]
+    pub storage_type: u16,
+    /// Whether the host may not change the objects of the storage
+    pub read_only: bool,
+    pub max_capacity: u64,
+    pub free_space: u64,
+    pub description: String,
+    pub volume_label: String,
+}
+
+impl Default for StorageInfo {
+    fn default() -> Self {
+        Self {
+            storage_type: storage_type::FIXED_RAM,
+            read_only: false,
+            max_capacity: 0,
+            free_space: 0,
+            description: String::new(),
+            volume_label: String::new(),
+        }
+    }
+}
+
+impl StorageInfo {
+    /// Serialize the dataset into bytes
+    pub fn pack_to_vec(&self) -> Vec<u8> {
+        let mut data = DataWriter::new();
+        data.u16(self.storage_type)
+            // Filesystem type: generic hierarchical
+            .u16(0x0002)
+            // Access capability: read-write, or read-only without deletion
+            .u16(self.read_only as u16)
+            .u64(self.max_capacity)
+            .u64(self.free_space)
+            // Free space in images: unused
+            .u32(0xffff_ffff)
+            .string(&self.description)
+            .string(&self.volume_label);
+        data.into_vec()
+    }
+}
+
+/// ObjectInfo dataset, describing an object of a storage
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ObjectInfo {
+    pub storage_id: u32,
+    /// See [format]
+    pub format: u16,
+    /// Whether the object cannot be deleted or modified
+    pub protected: bool,
+    pub size: u64,
+    pub thumb_format: u16,
+    pub thumb_size: u32,
+    pub thumb_width: u32,
+    pub thumb_height: u32,
+    pub image_width: u32,
+    pub image_height: u32,
+    pub image_bit_depth: u32,
+    /// Handle of the folder holding the object, or [ROOT_PARENT]
+    pub parent: u32,
+    /// [GENERIC_FOLDER] for folders
+    pub association_type: u16,
+    pub association_desc: u32,
+    pub sequence_number: u32,
+    pub filename: String,
+    /// Dates in the `YYYYMMDDThhmmss` format, or empty
+    pub capture_date: String,
+    pub modification_date: String,
+    pub keywords: String,
+}
+
+impl ObjectInfo {
+    /// Returns true if the object is a folder
+    pub fn is_folder(&self) -> bool {
+        self.format == format::ASSOCIATION
+    }
+
+    /// Serialize the dataset into bytes. Sizes of 4 GiB and more are
+    /// reported as 0xFFFFFFFF.
+    pub fn pack_to_vec(&self) -> Vec<u8> {
+        let mut data = DataWriter::new();
+        data.u32(self.storage_id)
+            .u16(self.format)
+            .u16(self.protected as u16)
+            .u32(self.size.min(u32::MAX as u64) as u32)
+            .u16(self.thumb_format)
+            .u32(self.thumb_size)
+            .u32(self.thumb_width)
+            .u32(self.thumb_height)
+            .u32(self.image_width)
+            .u32(self.image_height)
+            .u32(self.image_bit_depth)
+            .u32(self.parent)
+            .u16(self.association_type)
+            .u32(self.association_desc)
+            .u32(self.sequence_number)
+            .string(&self.filename)
+            .string(&self.capture_date)
+            .string(&self.modification_date)
+            .string(&self.keywords);
+        data.into_vec()
+    }
+
+    /// Parse the dataset sent by the host with SendObjectInfo
+    pub fn unpack(data: &[u8]) -> Option<Self> {
+        let mut data = DataReader::new(data);
+        Some(Self {
+            storage_id: data.u32()?,
+            format: data.u16()?,
+            protected: data.u16()? != 0,
+            size: data.u32()? as u64,
+            thumb_format: data.u16()?,
+            thumb_size: data.u32()?,
+            thumb_width: data.u32()?,
+            thumb_height: data.u32()?,
+            image_width: data.u32()?,
+            image_height: data.u32()?,
+            image_bit_depth: data.u32()?,
+            parent: data.u32()?,
+            association_type: data.u16()?,
+            association_desc: data.u32()?,
+            sequence_number: data.u32()?,
+            filename: data.string()?,
+            // Hosts may leave out the trailing strings
+            capture_date: data.string().unwrap_or_default(),
+            modification_date: data.string().unwrap_or_default(),
+            keywords: data.string().unwrap_or_default(),
+        })
+    }
+}
+
+/// Status of the device returned by GET_DEVICE_STATUS (a response code),
+/// shared between the interface and the code emulating the device
+#[derive(Debug, Clone)]
+pub struct DeviceStatus(Arc<Mutex<u16>>);
+
+impl DeviceStatus {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(response::OK)))
+    }
+
+    /// Returns the status (see [response])
+    pub fn get(&self) -> u16 {
+        self.0.lock().map(|status| *status).unwrap_or(response::OK)
+    }
+
+    /// Replace the status
+    pub fn set(&self, value: u16) {
+        if let Ok(mut status) = self.0.lock() {
+            *status = value;
+        }
+    }
+}
+
+impl Default for DeviceStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Events of a Still Image interface
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PtpEvent {
+    /// The host cancelled the transaction with the given ID
+    Cancel { transaction_id: u32 },
+    /// The host reset the device, closing the session
+    Reset,
+}
+
+/// Callback called with the events of a Still Image interface
+pub type PtpEventHandler = Arc<Mutex<dyn FnMut(PtpEvent) + Send>>;
+
+/// Event handlers of a Still Image interface
+#[derive(Clone, Default)]
+pub struct PtpEventHandlers(Vec<PtpEventHandler>);
+
+impl PtpEventHandlers {
+    /// Dispatch the given event to all handlers
+    pub fn dispatch(&self, event: PtpEvent) {
+        for handler in self.0.iter() {
+            if let Ok(mut handler) = handler.lock() {
+                handler(event);
+            }
+        }
+    }
+}
+
+impl Debug for PtpEventHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PtpEventHandlers")
+            .field("count", &self.0.len())
+            .finish()
+    }
+}
+
+/// Still Image interface definition
+#[derive(Debug, Clone)]
+pub struct PtpInterface {
+    pub iface: InterfaceDescriptor,
+    pub endpoint_descriptors: Vec<EndpointDescriptor>,
+    /// Interface string, "MTP" for MTP devices
+    pub name: Option<String>,
+    pub status: DeviceStatus,
+    pub handlers: PtpEventHandlers,
+}
+
+impl PtpInterface {
+    pub fn new() -> Self {
+        let iface = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            b_interface_class: InterfaceClass::Image,
+            b_interface_subclass: STILL_IMAGE_SUBCLASS,
+            b_interface_protocol: PTP_PROTOCOL,
+            ..InterfaceDescriptor::new()
+        };
+
+        Self {
+            iface,
+            endpoint_descriptors: Vec::new(),
+            name: None,
+            status: DeviceStatus::new(),
+            handlers: PtpEventHandlers::default(),
+        }
+    }
+
+    /// Register a handler for the events of the interface
+    pub fn on_event<F>(&mut self, handler: F)
+    where
+        F: FnMut(PtpEvent) + Send + 'static,
+    {
+        let handler: PtpEventHandler = Arc::new(Mutex::new(handler));
+        self.handlers.0.push(handler);
+    }
+
+    /// Set the index of the string descriptor holding the interface name
+    pub fn set_name_index(&mut self, index: u8) {
+        self.iface.i_interface = index;
+    }
+
+    /// Returns the reply to GET_DEVICE_STATUS
+    pub fn device_status(&self) -> Vec<u8> {
+        let mut data = vec![4, 0];
+        data.extend_from_slice(&self.status.get().to_le_bytes());
+        data
+    }
+
+    /// Returns the endpoint number of the bulk endpoint in the given
+    /// direction
+    pub fn bulk_endpoint(&self, direction: Direction) -> Option<u8> {
+        self.endpoint_descriptors
+            .iter()
+            .find(|desc| {
+                desc.direction() == direction && desc.transfer_type() == TransferType::Bulk
+            })
+            .map(|desc| desc.number())
+    }
+
+    /// Returns the endpoint number of the interrupt IN endpoint
+    pub fn interrupt_endpoint(&self) -> Option<u8> {
+        self.endpoint_descriptors
+            .iter()
+            .find(|desc| {
+                desc.direction() == Direction::In && desc.transfer_type() == TransferType::Interrupt
+            })
+            .map(|desc| desc.number())
+    }
+
+    /// Serialize the interface into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.get_size());
+        result.append(&mut self.iface.pack_to_vec()?);
+        for endpoint_desc in self.endpoint_descriptors.iter() {
+            result.append(&mut endpoint_desc.pack_to_vec()?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the byte serialized size of the interface
+    pub fn get_size(&self) -> usize {
+        9 + (7 * self.endpoint_descriptors.len())
+    }
+
+    /// Returns the interface class
+    pub fn get_class(&self) -> InterfaceClass {
+        self.iface.b_interface_class
+    }
+
+    /// Set the interface number for this interface
+    pub fn set_interface_number(&mut self, num: u8) {
+        self.iface.b_interface_number = num;
+    }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        self.endpoint_descriptors.as_slice()
+    }
+}
+
+impl Display for PtpInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut text = vec![format!("{}", self.iface)];
+        for desc in self.endpoint_descriptors.iter() {
+            text.push(format!("{}", desc));
+        }
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+impl Default for PtpInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [Interface] builder for constructing a Still Image interface.
+pub struct PtpInterfaceBuilder {
+    iface: PtpInterface,
+}
+
+impl PtpInterfaceBuilder {
+    pub fn new() -> Self {
+        Self {
+            iface: PtpInterface::default(),
+        }
+    }
+
+    /// Construct the new Interface configuration.
+    pub fn build(&self) -> Interface {
+        #[cfg(feature = "log")]
+        log::debug!("Still Image Interface: {}", self.iface);
+        Interface::StillImage(self.iface.clone())
+    }
+
+    /// Set the interface string. Hosts look for "MTP" in it to detect MTP
+    /// devices (see [MTP_INTERFACE_NAME]).
+    pub fn name(&mut self, name: &str) -> &mut Self {
+        self.iface.name = Some(name.to_string());
+        self
+    }
+
+    /// Share the given status with the interface, to be read with
+    /// GET_DEVICE_STATUS
+    pub fn status(&mut self, status: &DeviceStatus) -> &mut Self {
+        self.iface.status = status.clone();
+        self
+    }
+
+    /// Handle the events of the interface. See [PtpInterface::on_event].
+    pub fn on_event<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: FnMut(PtpEvent) + Send + 'static,
+    {
+        self.iface.on_event(handler);
+        self
+    }
+
+    /// Add a bulk OUT and a bulk IN endpoint with the given endpoint numbers
+    /// and max packet size (64 for full speed, 512 for high speed).
+    pub fn bulk_endpoints(&mut self, out_num: u8, in_num: u8, max_packet_size: u16) -> &mut Self {
+        for (num, direction) in [(out_num, Direction::Out), (in_num, Direction::In)] {
+            let descriptor = EndpointBuilder::new()
+                .address_num(num)
+                .direction(direction)
+                .transfer_type(TransferType::Bulk)
+                .sync_type(SynchronizationType::NoSynchronization)
+                .usage_type(UsageType::Data)
+                .max_packet_size(max_packet_size)
+                .build();
+            self.endpoint_descriptor(descriptor);
+        }
+        self
+    }
+
+    /// Add an interrupt IN endpoint with the given endpoint number, used for
+    /// event containers
+    pub fn interrupt_endpoint(&mut self, num: u8) -> &mut Self {
+        let descriptor = EndpointBuilder::new()
+            .address_num(num)
+            .direction(Direction::In)
+            .transfer_type(TransferType::Interrupt)
+            .sync_type(SynchronizationType::NoSynchronization)
+            .usage_type(UsageType::Data)
+            .max_packet_size(64)
+            .interval(8)
+            .build();
+        self.endpoint_descriptor(descriptor)
+    }
+
+    /// Add the given endpoint to the interface
+    pub fn endpoint_descriptor(&mut self, descriptor: EndpointDescriptor) -> &mut Self {
+        self.iface.endpoint_descriptors.push(descriptor);
+        self.iface.iface.b_num_endpoints = self.iface.endpoint_descriptors.len() as u8;
+        self
+    }
+}
+
+impl Default for PtpInterfaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
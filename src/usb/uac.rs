@@ -0,0 +1,1066 @@
+//! UAC (USB Audio Class) 1.0 and 2.0
+//! https://www.usb.org/document-library/audio-devices-rev-10-and-adopters-agreement
+//! https://www.usb.org/document-library/audio-devices-rev-20-and-adopters-agreement
+
+use std::{
+    fmt::{Debug, Display},
+    sync::{Arc, Mutex},
+};
+
+use packed_struct::prelude::*;
+
+use super::{
+    Direction, EndpointBuilder, EndpointDescriptor, Interface, InterfaceAssociationDescriptor,
+    InterfaceClass, InterfaceDescriptor, SynchronizationType, TransferType, UsageType,
+};
+
+/// Class-specific interface descriptor type (bDescriptorType)
+pub const CS_INTERFACE: u8 = 0x24;
+/// Class-specific endpoint descriptor type (bDescriptorType)
+pub const CS_ENDPOINT: u8 = 0x25;
+/// ID of the input terminal receiving the playback stream from the host
+pub const PLAYBACK_INPUT_TERMINAL_ID: u8 = 1;
+/// ID of the feature unit of the playback stream
+pub const PLAYBACK_FEATURE_UNIT_ID: u8 = 2;
+/// ID of the speaker output terminal
+pub const PLAYBACK_OUTPUT_TERMINAL_ID: u8 = 3;
+/// ID of the microphone input terminal
+pub const CAPTURE_INPUT_TERMINAL_ID: u8 = 4;
+/// ID of the feature unit of the capture stream
+pub const CAPTURE_FEATURE_UNIT_ID: u8 = 5;
+/// ID of the output terminal sending the capture stream to the host
+pub const CAPTURE_OUTPUT_TERMINAL_ID: u8 = 6;
+/// ID of the clock source shared by all terminals (UAC 2.0 only)
+pub const CLOCK_SOURCE_ID: u8 = 7;
+/// Lowest volume of the feature units, in 1/256 dB (-60 dB)
+pub const VOLUME_MIN: i16 = -60 * 256;
+/// Highest volume of the feature units, in 1/256 dB (0 dB)
+pub const VOLUME_MAX: i16 = 0;
+/// Volume step of the feature units, in 1/256 dB (1 dB)
+pub const VOLUME_RES: i16 = 256;
+
+/// Version of the audio class specification
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UacVersion {
+    /// Audio Device Class 1.0, supported by virtually all hosts
+    Uac1,
+    /// Audio Device Class 2.0, with clock entities and 32-bit sample rates
+    Uac2,
+}
+
+impl UacVersion {
+    /// Returns the bcdADC value of the AudioControl header
+    pub fn bcd_adc(&self) -> u16 {
+        match self {
+            UacVersion::Uac1 => 0x0100,
+            UacVersion::Uac2 => 0x0200,
+        }
+    }
+
+    /// Returns the protocol code of the audio interfaces
+    pub fn interface_protocol(&self) -> u8 {
+        match self {
+            UacVersion::Uac1 => 0x00,
+            UacVersion::Uac2 => 0x20,
+        }
+    }
+}
+
+/// Audio interface subclass codes
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AudioSubclass {
+    AudioControl = 0x01,
+    AudioStreaming = 0x02,
+    MidiStreaming = 0x03,
+}
+
+/// AudioControl class-specific descriptor subtypes (bDescriptorSubtype)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AcDescriptorSubtype {
+    Header = 0x01,
+    InputTerminal = 0x02,
+    OutputTerminal = 0x03,
+    FeatureUnit = 0x06,
+    ClockSource = 0x0a,
+}
+
+/// AudioStreaming class-specific descriptor subtypes (bDescriptorSubtype)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AsDescriptorSubtype {
+    General = 0x01,
+    FormatType = 0x02,
+}
+
+/// Terminal types (wTerminalType)
+pub mod terminal_type {
+    /// Streaming terminal exchanging audio with the host
+    pub const STREAMING: u16 = 0x0101;
+    pub const MICROPHONE: u16 = 0x0201;
+    pub const SPEAKER: u16 = 0x0301;
+}
+
+/// Audio class-specific request codes (bRequest) of UAC 1.0
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum Uac1Request {
+    SetCur = 0x01,
+    GetCur = 0x81,
+    GetMin = 0x82,
+    GetMax = 0x83,
+    GetRes = 0x84,
+}
+
+/// Audio class-specific request codes (bRequest) of UAC 2.0. The direction
+/// of the request tells whether the attribute is read or written.
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum Uac2Request {
+    Cur = 0x01,
+    Range = 0x02,
+}
+
+/// Feature unit control selectors (high byte of wValue)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum FeatureControl {
+    Mute = 0x01,
+    Volume = 0x02,
+}
+
+/// Clock source control selectors (high byte of wValue, UAC 2.0 only)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum ClockControl {
+    SamplingFrequency = 0x01,
+    ClockValid = 0x02,
+}
+
+/// Endpoint control selector of the sampling frequency (UAC 1.0 only)
+pub const SAMPLING_FREQ_CONTROL: u8 = 0x01;
+
+/// Attribute of a control read or written by the host
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ControlAttribute {
+    Cur,
+    Min,
+    Max,
+    Res,
+    Range,
+}
+
+impl ControlAttribute {
+    /// Decode the attribute of a GET request
+    fn from_get_request(version: UacVersion, request: u8) -> Option<Self> {
+        let attribute = match version {
+            UacVersion::Uac1 => match Uac1Request::from_primitive(request)? {
+                Uac1Request::GetCur => ControlAttribute::Cur,
+                Uac1Request::GetMin => ControlAttribute::Min,
+                Uac1Request::GetMax => ControlAttribute::Max,
+                Uac1Request::GetRes => ControlAttribute::Res,
+                Uac1Request::SetCur => return None,
+            },
+            UacVersion::Uac2 => match Uac2Request::from_primitive(request)? {
+                Uac2Request::Cur => ControlAttribute::Cur,
+                Uac2Request::Range => ControlAttribute::Range,
+            },
+        };
+        Some(attribute)
+    }
+}
+
+/// PCM format of an audio stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioFormat {
+    pub channels: u8,
+    /// Number of significant bits per sample (16, 24 or 32)
+    pub bit_resolution: u8,
+    /// Supported sample rates in Hz. The first one is the default.
+    pub sample_rates: Vec<u32>,
+}
+
+impl AudioFormat {
+    pub fn new(channels: u8, bit_resolution: u8, sample_rates: &[u32]) -> Self {
+        Self {
+            channels,
+            bit_resolution,
+            sample_rates: sample_rates.to_vec(),
+        }
+    }
+
+    /// Returns the number of bytes of a sample
+    pub fn subframe_size(&self) -> usize {
+        (self.bit_resolution as usize).div_ceil(8)
+    }
+
+    /// Returns the number of bytes of a frame, holding one sample of every
+    /// channel
+    pub fn frame_size(&self) -> usize {
+        self.subframe_size() * self.channels as usize
+    }
+
+    /// Returns the default sample rate
+    pub fn default_sample_rate(&self) -> u32 {
+        self.sample_rates.first().copied().unwrap_or(48_000)
+    }
+
+    /// Returns the largest number of bytes sent in a millisecond
+    pub fn max_packet_size(&self) -> u16 {
+        let max_rate = self.sample_rates.iter().copied().max().unwrap_or(48_000);
+        (max_rate.div_ceil(1000) as usize * self.frame_size()) as u16
+    }
+
+    /// Returns the spatial locations of the channels: front left and right
+    /// for stereo streams, none otherwise
+    fn channel_config(&self) -> u16 {
+        match self.channels {
+            2 => 0x0003,
+            _ => 0x0000,
+        }
+    }
+}
+
+/// Audio stream of an audio function
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioStream {
+    /// [Direction::Out] for playback streams sent by the host, and
+    /// [Direction::In] for capture streams sent to the host
+    pub direction: Direction,
+    pub format: AudioFormat,
+}
+
+impl AudioStream {
+    /// Returns the ID of the terminal exchanging the stream with the host
+    pub fn streaming_terminal_id(&self) -> u8 {
+        match self.direction {
+            Direction::Out => PLAYBACK_INPUT_TERMINAL_ID,
+            Direction::In => CAPTURE_OUTPUT_TERMINAL_ID,
+        }
+    }
+
+    /// Returns the ID of the feature unit of the stream
+    pub fn feature_unit_id(&self) -> u8 {
+        match self.direction {
+            Direction::Out => PLAYBACK_FEATURE_UNIT_ID,
+            Direction::In => CAPTURE_FEATURE_UNIT_ID,
+        }
+    }
+}
+
+/// Mute and volume of a feature unit
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct FeatureState {
+    pub mute: bool,
+    /// Volume in 1/256 dB
+    pub volume: i16,
+}
+
+/// State of the controls of an AudioControl interface
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct AudioControlState {
+    pub playback: FeatureState,
+    pub capture: FeatureState,
+    /// Sample rate of the clock source (UAC 2.0 only)
+    pub sample_rate: u32,
+}
+
+impl AudioControlState {
+    fn feature(&mut self, direction: Direction) -> &mut FeatureState {
+        match direction {
+            Direction::Out => &mut self.playback,
+            Direction::In => &mut self.capture,
+        }
+    }
+}
+
+/// State change of an audio function requested by the host
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UacEvent {
+    /// The host selected the sample rate of a stream
+    SampleRate(Direction, u32),
+    /// The host muted or unmuted a stream
+    Mute(Direction, bool),
+    /// The host changed the volume of a stream, in 1/256 dB
+    Volume(Direction, i16),
+    /// The host started (alternate setting 1) or stopped (alternate setting
+    /// 0) a stream
+    Streaming(Direction, bool),
+}
+
+/// Callback receiving the state changes requested by the host
+pub type UacEventHandler = Arc<Mutex<dyn FnMut(UacEvent) + Send>>;
+
+/// Event handlers of an audio interface
+#[derive(Clone, Default)]
+pub struct UacEventHandlers(Vec<UacEventHandler>);
+
+impl UacEventHandlers {
+    /// Dispatch the given event to all handlers
+    pub fn dispatch(&self, event: UacEvent) {
+        for handler in self.0.iter() {
+            if let Ok(mut handler) = handler.lock() {
+                handler(event);
+            }
+        }
+    }
+
+    fn push<F>(&mut self, handler: F)
+    where
+        F: FnMut(UacEvent) + Send + 'static,
+    {
+        let handler: UacEventHandler = Arc::new(Mutex::new(handler));
+        self.0.push(handler);
+    }
+}
+
+impl Debug for UacEventHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UacEventHandlers")
+            .field("count", &self.0.len())
+            .finish()
+    }
+}
+
+/// AudioControl interface definition, describing an input terminal, a
+/// feature unit with mute and volume controls and an output terminal for
+/// every stream. The AudioStreaming interfaces must be added to the
+/// configuration right after it, in the order of the streams, followed by
+/// the MIDIStreaming interfaces.
+#[derive(Debug, Clone)]
+pub struct UacControlInterface {
+    /// Association grouping the audio interfaces of the function
+    pub association: InterfaceAssociationDescriptor,
+    pub iface: InterfaceDescriptor,
+    pub version: UacVersion,
+    pub streams: Vec<AudioStream>,
+    /// Number of MIDIStreaming interfaces of the function
+    pub midi_interfaces: u8,
+    /// Control state, shared by all clones of the interface
+    pub state: Arc<Mutex<AudioControlState>>,
+    pub handlers: UacEventHandlers,
+}
+
+impl UacControlInterface {
+    pub fn new(version: UacVersion) -> Self {
+        let iface = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            b_interface_class: InterfaceClass::Audio,
+            b_interface_subclass: AudioSubclass::AudioControl as u8,
+            b_interface_protocol: version.interface_protocol(),
+            ..InterfaceDescriptor::new()
+        };
+
+        Self {
+            association: InterfaceAssociationDescriptor {
+                b_interface_count: 1,
+                b_function_class: InterfaceClass::Audio as u8,
+                b_function_sub_class: 0,
+                b_function_protocol: version.interface_protocol(),
+                ..InterfaceAssociationDescriptor::new()
+            },
+            iface,
+            version,
+            streams: Vec::new(),
+            midi_interfaces: 0,
+            state: Arc::new(Mutex::new(AudioControlState::default())),
+            handlers: UacEventHandlers::default(),
+        }
+    }
+
+    /// Register a handler for the state changes requested by the host
+    pub fn on_event<F>(&mut self, handler: F)
+    where
+        F: FnMut(UacEvent) + Send + 'static,
+    {
+        self.handlers.push(handler);
+    }
+
+    /// Returns the control state
+    pub fn control_state(&self) -> AudioControlState {
+        self.state.lock().map(|state| *state).unwrap_or_default()
+    }
+
+    /// Returns the sample rates supported by the clock source: the sample
+    /// rates of all streams, in increasing order
+    pub fn sample_rates(&self) -> Vec<u32> {
+        let mut rates: Vec<u32> = self
+            .streams
+            .iter()
+            .flat_map(|stream| stream.format.sample_rates.iter().copied())
+            .collect();
+        rates.sort_unstable();
+        rates.dedup();
+        rates
+    }
+
+    /// Returns the default sample rate of the clock source: the default
+    /// sample rate of the first stream
+    pub fn default_sample_rate(&self) -> u32 {
+        self.streams
+            .first()
+            .map(|stream| stream.format.default_sample_rate())
+            .unwrap_or_default()
+    }
+
+    /// Returns the stream whose feature unit has the given ID
+    fn stream_of_feature_unit(&self, unit: u8) -> Option<&AudioStream> {
+        self.streams
+            .iter()
+            .find(|stream| stream.feature_unit_id() == unit)
+    }
+
+    /// Handle a SET request for a control of the given entity. Returns false
+    /// if the request or its data is invalid.
+    pub fn handle_set(&self, request: u8, entity: u8, selector: u8, data: &[u8]) -> bool {
+        // SET_CUR has the same code in both versions
+        if request != Uac1Request::SetCur as u8 {
+            return false;
+        }
+        let Ok(mut state) = self.state.lock() else {
+            return false;
+        };
+
+        if entity == CLOCK_SOURCE_ID && self.version == UacVersion::Uac2 {
+            if ClockControl::from_primitive(selector) != Some(ClockControl::SamplingFrequency) {
+                return false;
+            }
+            let Some(rate) = data.get(..4) else {
+                return false;
+            };
+            let rate = u32::from_le_bytes([rate[0], rate[1], rate[2], rate[3]]);
+            if !self.sample_rates().contains(&rate) {
+                return false;
+            }
+            state.sample_rate = rate;
+            drop(state);
+            for stream in self.streams.iter() {
+                self.handlers
+                    .dispatch(UacEvent::SampleRate(stream.direction, rate));
+            }
+            return true;
+        }
+
+        let Some(stream) = self.stream_of_feature_unit(entity) else {
+            return false;
+        };
+        let direction = stream.direction;
+        let event = match FeatureControl::from_primitive(selector) {
+            Some(FeatureControl::Mute) => {
+                let Some(mute) = data.first() else {
+                    return false;
+                };
+                state.feature(direction).mute = *mute != 0;
+                UacEvent::Mute(direction, *mute != 0)
+            }
+            Some(FeatureControl::Volume) => {
+                let Some(volume) = data.get(..2) else {
+                    return false;
+                };
+                let volume =
+                    i16::from_le_bytes([volume[0], volume[1]]).clamp(VOLUME_MIN, VOLUME_MAX);
+                state.feature(direction).volume = volume;
+                UacEvent::Volume(direction, volume)
+            }
+            None => return false,
+        };
+        drop(state);
+        self.handlers.dispatch(event);
+        true
+    }
+
+    /// Handle a GET request for a control of the given entity. Returns None
+    /// if the request is not supported.
+    pub fn handle_get(&self, request: u8, entity: u8, selector: u8) -> Option<Vec<u8>> {
+        let attribute = ControlAttribute::from_get_request(self.version, request)?;
+        let state = self.control_state();
+
+        if entity == CLOCK_SOURCE_ID && self.version == UacVersion::Uac2 {
+            return match (ClockControl::from_primitive(selector)?, attribute) {
+                (ClockControl::SamplingFrequency, ControlAttribute::Cur) => {
+                    let rate = match state.sample_rate {
+                        0 => self.default_sample_rate(),
+                        rate => rate,
+                    };
+                    Some(rate.to_le_bytes().to_vec())
+                }
+                // Each supported rate is a subrange without resolution
+                (ClockControl::SamplingFrequency, ControlAttribute::Range) => {
+                    let rates = self.sample_rates();
+                    let mut data = (rates.len() as u16).to_le_bytes().to_vec();
+                    for rate in rates {
+                        data.extend_from_slice(&rate.to_le_bytes());
+                        data.extend_from_slice(&rate.to_le_bytes());
+                        data.extend_from_slice(&0u32.to_le_bytes());
+                    }
+                    Some(data)
+                }
+                (ClockControl::ClockValid, ControlAttribute::Cur) => Some(vec![1]),
+                _ => None,
+            };
+        }
+
+        let stream = self.stream_of_feature_unit(entity)?;
+        let feature = match stream.direction {
+            Direction::Out => state.playback,
+            Direction::In => state.capture,
+        };
+        match (FeatureControl::from_primitive(selector)?, attribute) {
+            (FeatureControl::Mute, ControlAttribute::Cur) => Some(vec![feature.mute as u8]),
+            (FeatureControl::Volume, ControlAttribute::Cur) => {
+                Some(feature.volume.to_le_bytes().to_vec())
+            }
+            (FeatureControl::Volume, ControlAttribute::Min) => {
+                Some(VOLUME_MIN.to_le_bytes().to_vec())
+            }
+            (FeatureControl::Volume, ControlAttribute::Max) => {
+                Some(VOLUME_MAX.to_le_bytes().to_vec())
+            }
+            (FeatureControl::Volume, ControlAttribute::Res) => {
+                Some(VOLUME_RES.to_le_bytes().to_vec())
+            }
+            (FeatureControl::Volume, ControlAttribute::Range) => {
+                let mut data = 1u16.to_le_bytes().to_vec();
+                for value in [VOLUME_MIN, VOLUME_MAX, VOLUME_RES] {
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the class-specific AudioControl descriptors: the header
+    /// followed by the clock source and the terminals and units of every
+    /// stream
+    fn class_descriptors(&self) -> Vec<u8> {
+        let mut entities = Vec::new();
+        if self.version == UacVersion::Uac2 {
+            // Internal clock, programmable if there is more than one rate,
+            // and read-only validity
+            let programmable = self.sample_rates().len() > 1;
+            let (attributes, controls) = if programmable {
+                (0x03, 0x07)
+            } else {
+                (0x01, 0x05)
+            };
+            let subtype = AcDescriptorSubtype::ClockSource as u8;
+            entities.extend_from_slice(&[8, CS_INTERFACE, subtype, CLOCK_SOURCE_ID]);
+            entities.extend_from_slice(&[attributes, controls, 0, 0]);
+        }
+        for stream in self.streams.iter() {
+            entities.append(&mut self.stream_descriptors(stream));
+        }
+
+        let mut desc = Vec::new();
+        let subtype = AcDescriptorSubtype::Header as u8;
+        let bcd_adc = self.version.bcd_adc().to_le_bytes();
+        match self.version {
+            UacVersion::Uac1 => {
+                let collection = self.streams.len() + self.midi_interfaces as usize;
+                let length = 8 + collection;
+                let total_length = ((length + entities.len()) as u16).to_le_bytes();
+                desc.extend_from_slice(&[length as u8, CS_INTERFACE, subtype]);
+                desc.extend_from_slice(&bcd_adc);
+                desc.extend_from_slice(&total_length);
+                desc.push(collection as u8);
+                // The streaming interfaces follow this interface
+                let first = self.iface.b_interface_number + 1;
+                desc.extend((0..collection as u8).map(|i| first + i));
+            }
+            UacVersion::Uac2 => {
+                let total_length = ((9 + entities.len()) as u16).to_le_bytes();
+                desc.extend_from_slice(&[9, CS_INTERFACE, subtype]);
+                desc.extend_from_slice(&bcd_adc);
+                desc.push(self.category());
+                desc.extend_from_slice(&total_length);
+                desc.push(0);
+            }
+        }
+        desc.append(&mut entities);
+        desc
+    }
+
+    /// Returns the UAC 2.0 function category: desktop speaker, microphone
+    /// or headset
+    fn category(&self) -> u8 {
+        let playback = self.streams.iter().any(|s| s.direction == Direction::Out);
+        let capture = self.streams.iter().any(|s| s.direction == Direction::In);
+        match (playback, capture) {
+            (true, true) => 0x04,
+            (false, true) => 0x03,
+            _ => 0x01,
+        }
+    }
+
+    /// Returns the input terminal, feature unit and output terminal
+    /// descriptors of the given stream
+    fn stream_descriptors(&self, stream: &AudioStream) -> Vec<u8> {
+        let (input_id, unit_id, output_id, input_type, output_type) = match stream.direction {
+            Direction::Out => (
+                PLAYBACK_INPUT_TERMINAL_ID,
+                PLAYBACK_FEATURE_UNIT_ID,
+                PLAYBACK_OUTPUT_TERMINAL_ID,
+                terminal_type::STREAMING,
+                terminal_type::SPEAKER,
+            ),
+            Direction::In => (
+                CAPTURE_INPUT_TERMINAL_ID,
+                CAPTURE_FEATURE_UNIT_ID,
+                CAPTURE_OUTPUT_TERMINAL_ID,
+                terminal_type::MICROPHONE,
+                terminal_type::STREAMING,
+            ),
+        };
+        let channels = stream.format.channels;
+        let channel_config = stream.format.channel_config();
+        let input = AcDescriptorSubtype::InputTerminal as u8;
+        let unit = AcDescriptorSubtype::FeatureUnit as u8;
+        let output = AcDescriptorSubtype::OutputTerminal as u8;
+
+        let mut desc = Vec::new();
+        match self.version {
+            UacVersion::Uac1 => {
+                desc.extend_from_slice(&[12, CS_INTERFACE, input, input_id]);
+                desc.extend_from_slice(&input_type.to_le_bytes());
+                desc.extend_from_slice(&[0, channels]);
+                desc.extend_from_slice(&channel_config.to_le_bytes());
+                desc.extend_from_slice(&[0, 0]);
+
+                // One byte of controls per channel, with mute and volume on
+                // the master channel
+                let length = 7 + (channels as usize + 1);
+                desc.extend_from_slice(&[length as u8, CS_INTERFACE, unit, unit_id, input_id, 1]);
+                desc.push(0x03);
+                desc.extend(std::iter::repeat_n(0, channels as usize));
+                desc.push(0);
+
+                desc.extend_from_slice(&[9, CS_INTERFACE, output, output_id]);
+                desc.extend_from_slice(&output_type.to_le_bytes());
+                desc.extend_from_slice(&[0, unit_id, 0]);
+            }
+            UacVersion::Uac2 => {
+                desc.extend_from_slice(&[17, CS_INTERFACE, input, input_id]);
+                desc.extend_from_slice(&input_type.to_le_bytes());
+                desc.extend_from_slice(&[0, CLOCK_SOURCE_ID, channels]);
+                desc.extend_from_slice(&(channel_config as u32).to_le_bytes());
+                desc.extend_from_slice(&[0, 0, 0, 0]);
+
+                // Four bytes of controls per channel, with read/write mute
+                // and volume on the master channel
+                let length = 6 + (channels as usize + 1) * 4;
+                desc.extend_from_slice(&[length as u8, CS_INTERFACE, unit, unit_id, input_id]);
+                desc.extend_from_slice(&0x0000_000fu32.to_le_bytes());
+                desc.extend(std::iter::repeat_n(0, channels as usize * 4));
+                desc.push(0);
+
+                desc.extend_from_slice(&[12, CS_INTERFACE, output, output_id]);
+                desc.extend_from_slice(&output_type.to_le_bytes());
+                desc.extend_from_slice(&[0, unit_id, CLOCK_SOURCE_ID, 0, 0, 0]);
+            }
+        }
+        desc
+    }
+
+    /// Serialize the interface into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.get_size());
+        result.append(&mut self.association.pack_to_vec()?);
+        result.append(&mut self.iface.pack_to_vec()?);
+        result.append(&mut self.class_descriptors());
+
+        Ok(result)
+    }
+
+    /// Returns the byte serialized size of the interface
+    pub fn get_size(&self) -> usize {
+        8 + 9 + self.class_descriptors().len()
+    }
+
+    /// Returns the interface class
+    pub fn get_class(&self) -> InterfaceClass {
+        self.iface.b_interface_class
+    }
+
+    /// Set the interface number for this interface. The AudioStreaming
+    /// interfaces are expected to be the next interfaces.
+    pub fn set_interface_number(&mut self, num: u8) {
+        self.iface.b_interface_number = num;
+        self.association.b_first_interface = num;
+    }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        &[]
+    }
+}
+
+impl Display for UacControlInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut text = vec![
+            format!("{}", self.association),
+            format!("{}", self.iface),
+            format!("{:?}", self.version),
+        ];
+        for stream in self.streams.iter() {
+            text.push(format!("{stream:?}"));
+        }
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+/// [Interface] builder for constructing an AudioControl interface.
+pub struct UacControlInterfaceBuilder {
+    iface: UacControlInterface,
+}
+
+impl UacControlInterfaceBuilder {
+    pub fn new(version: UacVersion) -> Self {
+        Self {
+            iface: UacControlInterface::new(version),
+        }
+    }
+
+    /// Construct the new Interface configuration.
+    pub fn build(&self) -> Interface {
+        #[cfg(feature = "log")]
+        log::debug!("UAC Control Interface: {}", self.iface);
+        let mut iface = self.iface.clone();
+        let rate = iface.default_sample_rate();
+        if let Ok(mut state) = iface.state.lock() {
+            state.sample_rate = rate;
+        }
+        iface.association.b_interface_count = 1 + iface.streams.len() as u8 + iface.midi_interfaces;
+        Interface::AudioControl(iface)
+    }
+
+    /// Add a stream with the given direction and format. Every stream needs
+    /// an AudioStreaming interface with the same direction and format.
+    pub fn stream(&mut self, direction: Direction, format: AudioFormat) -> &mut Self {
+        self.iface.streams.push(AudioStream { direction, format });
+        self
+    }
+
+    /// Add a MIDIStreaming interface to the function, which must follow
+    /// the AudioStreaming interfaces in the configuration
+    pub fn midi_streaming(&mut self) -> &mut Self {
+        self.iface.midi_interfaces += 1;
+        self
+    }
+
+    /// Handle the state changes requested by the host. See
+    /// [UacControlInterface::on_event].
+    pub fn on_event<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: FnMut(UacEvent) + Send + 'static,
+    {
+        self.iface.on_event(handler);
+        self
+    }
+
+    /// Set the index of the string descriptor naming the audio function
+    pub fn function_name_index(&mut self, index: u8) -> &mut Self {
+        self.iface.association.i_function = index;
+        self
+    }
+}
+
+/// AudioStreaming interface definition, exchanging the PCM stream of a
+/// terminal with the host on an isochronous endpoint. Alternate setting 0
+/// has no endpoint; the host selects alternate setting 1, which is only
+/// present once an endpoint is added, to start streaming.
+#[derive(Debug, Clone)]
+pub struct UacStreamingInterface {
+    pub iface: InterfaceDescriptor,
+    pub version: UacVersion,
+    pub stream: AudioStream,
+    pub endpoint_descriptors: Vec<EndpointDescriptor>,
+    /// Sample rate selected through the endpoint (UAC 1.0 only), shared by
+    /// all clones of the interface
+    pub sample_rate: Arc<Mutex<u32>>,
+    pub handlers: UacEventHandlers,
+}
+
+impl UacStreamingInterface {
+    pub fn new(version: UacVersion, direction: Direction, format: AudioFormat) -> Self {
+        let iface = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            b_interface_class: InterfaceClass::Audio,
+            b_interface_subclass: AudioSubclass::AudioStreaming as u8,
+            b_interface_protocol: version.interface_protocol(),
+            ..InterfaceDescriptor::new()
+        };
+        let sample_rate = format.default_sample_rate();
+
+        Self {
+            iface,
+            version,
+            stream: AudioStream { direction, format },
+            endpoint_descriptors: Vec::new(),
+            sample_rate: Arc::new(Mutex::new(sample_rate)),
+            handlers: UacEventHandlers::default(),
+        }
+    }
+
+    /// Register a handler for the state changes requested by the host
+    pub fn on_event<F>(&mut self, handler: F)
+    where
+        F: FnMut(UacEvent) + Send + 'static,
+    {
+        self.handlers.push(handler);
+    }
+
+    /// Returns the number of the audio endpoint
+    pub fn audio_endpoint(&self) -> Option<u8> {
+        self.endpoint_descriptors.first().map(|desc| desc.number())
+    }
+
+    /// Returns the sample rate selected through the endpoint
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+            .lock()
+            .map(|rate| *rate)
+            .unwrap_or_else(|_| self.stream.format.default_sample_rate())
+    }
+
+    /// Notify the handlers that the host selected the given alternate
+    /// setting
+    pub fn handle_set_alternate_setting(&self, alternate: u8) {
+        let event = UacEvent::Streaming(self.stream.direction, alternate != 0);
+        self.handlers.dispatch(event);
+    }
+
+    /// Handle a SET request for a control of the audio endpoint. Returns
+    /// false if the request or its data is invalid.
+    pub fn handle_endpoint_set(&self, request: u8, selector: u8, data: &[u8]) -> bool {
+        if self.version != UacVersion::Uac1
+            || request != Uac1Request::SetCur as u8
+            || selector != SAMPLING_FREQ_CONTROL
+        {
+            return false;
+        }
+        let Some(rate) = data.get(..3) else {
+            return false;
+        };
+        let rate = u32::from_le_bytes([rate[0], rate[1], rate[2], 0]);
+        if !self.stream.format.sample_rates.contains(&rate) {
+            return false;
+        }
+        if let Ok(mut sample_rate) = self.sample_rate.lock() {
+            *sample_rate = rate;
+        }
+        self.handlers
+            .dispatch(UacEvent::SampleRate(self.stream.direction, rate));
+        true
+    }
+
+    /// Handle a GET request for a control of the audio endpoint. Returns
+    /// None if the request is not supported.
+    pub fn handle_endpoint_get(&self, request: u8, selector: u8) -> Option<Vec<u8>> {
+        if self.version != UacVersion::Uac1 || selector != SAMPLING_FREQ_CONTROL {
+            return None;
+        }
+        let format = &self.stream.format;
+        let rate = match Uac1Request::from_primitive(request)? {
+            Uac1Request::GetCur => self.sample_rate(),
+            Uac1Request::GetMin => format.sample_rates.iter().copied().min()?,
+            Uac1Request::GetMax => format.sample_rates.iter().copied().max()?,
+            _ => return None,
+        };
+        Some(rate.to_le_bytes()[..3].to_vec())
+    }
+
+    /// Returns the class-specific AudioStreaming descriptors: the general
+    /// descriptor followed by the format type descriptor
+    fn class_descriptors(&self) -> Vec<u8> {
+        let format = &self.stream.format;
+        let link = self.stream.streaming_terminal_id();
+        let general = AsDescriptorSubtype::General as u8;
+        let format_type = AsDescriptorSubtype::FormatType as u8;
+        let subframe_size = format.subframe_size() as u8;
+
+        let mut desc = Vec::new();
+        match self.version {
+            UacVersion::Uac1 => {
+                // One frame of delay, PCM format
+                desc.extend_from_slice(&[7, CS_INTERFACE, general, link, 1, 0x01, 0x00]);
+
+                // Type I format with discrete sample rates
+                let length = 8 + 3 * format.sample_rates.len();
+                desc.extend_from_slice(&[length as u8, CS_INTERFACE, format_type, 0x01]);
+                desc.extend_from_slice(&[format.channels, subframe_size, format.bit_resolution]);
+                desc.push(format.sample_rates.len() as u8);
+                for rate in format.sample_rates.iter() {
+                    desc.extend_from_slice(&rate.to_le_bytes()[..3]);
+                }
+            }
+            UacVersion::Uac2 => {
+                // Type I PCM format
+                desc.extend_from_slice(&[16, CS_INTERFACE, general, link, 0, 0x01]);
+                desc.extend_from_slice(&0x0000_0001u32.to_le_bytes());
+                desc.push(format.channels);
+                desc.extend_from_slice(&(format.channel_config() as u32).to_le_bytes());
+                desc.push(0);
+
+                desc.extend_from_slice(&[6, CS_INTERFACE, format_type, 0x01]);
+                desc.extend_from_slice(&[subframe_size, format.bit_resolution]);
+            }
+        }
+        desc
+    }
+
+    /// Serialize the given endpoint descriptor followed by its
+    /// class-specific descriptor. UAC 1.0 uses the 9 byte audio variant of
+    /// the standard endpoint descriptor.
+    fn endpoint_descriptors(&self, desc: &EndpointDescriptor) -> Result<Vec<u8>, PackingError> {
+        let mut result = desc.pack_to_vec()?;
+        match self.version {
+            UacVersion::Uac1 => {
+                result[0] = 9;
+                result.extend_from_slice(&[0, 0]);
+                // Sampling frequency control, no lock delay
+                result.extend_from_slice(&[7, CS_ENDPOINT, 0x01, 0x01, 0, 0, 0]);
+            }
+            UacVersion::Uac2 => {
+                result.extend_from_slice(&[8, CS_ENDPOINT, 0x01, 0, 0, 0, 0, 0]);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Returns the interface descriptors of alternate setting 0 and, if the
+    /// interface has an endpoint, alternate setting 1
+    fn interface_descriptors(&self) -> Vec<InterfaceDescriptor> {
+        let inactive = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            ..self.iface
+        };
+        if self.endpoint_descriptors.is_empty() {
+            return vec![inactive];
+        }
+        let active = InterfaceDescriptor {
+            b_alternate_setting: 1,
+            ..self.iface
+        };
+        vec![inactive, active]
+    }
+
+    /// Returns the number of alternate settings of the interface
+    pub fn alternate_settings(&self) -> u8 {
+        if self.endpoint_descriptors.is_empty() {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Serialize the interface into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.get_size());
+        for iface in self.interface_descriptors() {
+            result.append(&mut iface.pack_to_vec()?);
+        }
+        result.append(&mut self.class_descriptors());
+        for endpoint_desc in self.endpoint_descriptors.iter() {
+            result.append(&mut self.endpoint_descriptors(endpoint_desc)?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the byte serialized size of the interface
+    pub fn get_size(&self) -> usize {
+        let endpoint_size = match self.version {
+            UacVersion::Uac1 => 9 + 7,
+            UacVersion::Uac2 => 7 + 8,
+        };
+        9 * self.alternate_settings() as usize
+            + self.class_descriptors().len()
+            + endpoint_size * self.endpoint_descriptors.len()
+    }
+
+    /// Returns the interface class
+    pub fn get_class(&self) -> InterfaceClass {
+        self.iface.b_interface_class
+    }
+
+    /// Set the interface number for this interface
+    pub fn set_interface_number(&mut self, num: u8) {
+        self.iface.b_interface_number = num;
+    }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        self.endpoint_descriptors.as_slice()
+    }
+}
+
+impl Display for UacStreamingInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut text: Vec<String> = self
+            .interface_descriptors()
+            .iter()
+            .map(|iface| format!("{}", iface))
+            .collect();
+        text.push(format!("{:?}", self.stream));
+        for desc in self.endpoint_descriptors.iter() {
+            text.push(format!("{}", desc));
+        }
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+/// [Interface] builder for constructing an AudioStreaming interface.
+pub struct UacStreamingInterfaceBuilder {
+    iface: UacStreamingInterface,
+}
+
+impl UacStreamingInterfaceBuilder {
+    /// Create a builder for the stream with the given direction and format,
+    /// which must match a stream of the AudioControl interface
+    pub fn new(version: UacVersion, direction: Direction, format: AudioFormat) -> Self {
+        Self {
+            iface: UacStreamingInterface::new(version, direction, format),
+        }
+    }
+
+    /// Construct the new Interface configuration.
+    pub fn build(&self) -> Interface {
+        #[cfg(feature = "log")]
+        log::debug!("UAC Streaming Interface: {}", self.iface);
+        Interface::AudioStreaming(self.iface.clone())
+    }
+
+    /// Handle the state changes requested by the host. See
+    /// [UacStreamingInterface::on_event].
+    pub fn on_event<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: FnMut(UacEvent) + Send + 'static,
+    {
+        self.iface.on_event(handler);
+        self
+    }
+
+    /// Add the isochronous audio endpoint with the given endpoint number,
+    /// sized for one millisecond of audio at the highest sample rate.
+    /// Playback endpoints are adaptive and capture endpoints asynchronous.
+    pub fn isochronous_endpoint(&mut self, num: u8) -> &mut Self {
+        let direction = self.iface.stream.direction;
+        let sync_type = match direction {
+            Direction::Out => SynchronizationType::Adaptive,
+            Direction::In => SynchronizationType::Asynchronous,
+        };
+        // One packet per millisecond at high speed
+        let descriptor = EndpointBuilder::new()
+            .address_num(num)
+            .direction(direction)
+            .transfer_type(TransferType::Isochronous)
+            .sync_type(sync_type)
+            .usage_type(UsageType::Data)
+            .max_packet_size(self.iface.stream.format.max_packet_size())
+            .interval(4)
+            .build();
+        self.endpoint_descriptor(descriptor)
+    }
+
+    /// Add the given endpoint to alternate setting 1 of the interface
+    pub fn endpoint_descriptor(&mut self, descriptor: EndpointDescriptor) -> &mut Self {
+        self.iface.endpoint_descriptors.push(descriptor);
+        self.iface.iface.b_num_endpoints = self.iface.endpoint_descriptors.len() as u8;
+        self
+    }
+}
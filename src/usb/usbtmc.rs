@@ -0,0 +1,506 @@
+//! USBTMC (Test and Measurement Class) with the USB488 subclass
+//! https://www.usb.org/sites/default/files/USBTMC_1_006a.zip
+
+use std::{
+    fmt::{Debug, Display},
+    sync::{Arc, Mutex},
+};
+
+use packed_struct::prelude::*;
+
+use super::{
+    Direction, EndpointBuilder, EndpointDescriptor, Interface, InterfaceClass, InterfaceDescriptor,
+    SynchronizationType, TransferType, UsageType,
+};
+
+/// USBTMC interface subclass code (Application Specific class)
+pub const USBTMC_SUBCLASS: u8 = 0x03;
+/// Size of the header of bulk messages
+pub const BULK_HEADER_SIZE: usize = 12;
+/// Version of the USBTMC specification (bcdUSBTMC)
+pub const USBTMC_VERSION: u16 = 0x0100;
+/// Version of the USB488 specification (bcdUSB488)
+pub const USB488_VERSION: u16 = 0x0100;
+/// Size of the reply to GET_CAPABILITIES
+pub const CAPABILITIES_SIZE: usize = 24;
+
+/// USBTMC interface protocol codes
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TmcProtocol {
+    /// Plain USBTMC
+    Usbtmc = 0x00,
+    /// USBTMC with the USB488 subclass for IEEE 488.2 instruments
+    Usb488 = 0x01,
+}
+
+/// USBTMC and USB488 class requests (bRequest)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum TmcRequest {
+    InitiateAbortBulkOut = 1,
+    CheckAbortBulkOutStatus = 2,
+    InitiateAbortBulkIn = 3,
+    CheckAbortBulkInStatus = 4,
+    InitiateClear = 5,
+    CheckClearStatus = 6,
+    GetCapabilities = 7,
+    IndicatorPulse = 64,
+    ReadStatusByte = 128,
+    RenControl = 160,
+    GoToLocal = 161,
+    LocalLockout = 162,
+}
+
+/// Status codes of the replies to class requests (USBTMC_status)
+pub mod status {
+    pub const SUCCESS: u8 = 0x01;
+    pub const PENDING: u8 = 0x02;
+    /// USB488: a READ_STATUS_BYTE reply is still queued on the interrupt IN
+    /// endpoint
+    pub const INTERRUPT_IN_BUSY: u8 = 0x20;
+    pub const FAILED: u8 = 0x80;
+    pub const TRANSFER_NOT_IN_PROGRESS: u8 = 0x81;
+    pub const SPLIT_NOT_IN_PROGRESS: u8 = 0x82;
+    pub const SPLIT_IN_PROGRESS: u8 = 0x83;
+}
+
+/// Bits of the capabilities reported by GET_CAPABILITIES
+pub mod capabilities {
+    /// The interface accepts INDICATOR_PULSE
+    pub const INDICATOR_PULSE: u8 = 0x04;
+    /// The interface only sends data
+    pub const TALK_ONLY: u8 = 0x02;
+    /// The interface only receives data
+    pub const LISTEN_ONLY: u8 = 0x01;
+    /// The device ends DEV_DEP_MSG_IN transfers on the termination
+    /// character
+    pub const TERM_CHAR: u8 = 0x01;
+
+    /// USB488: the interface is IEEE 488.2 compliant
+    pub const USB488_2: u8 = 0x04;
+    /// USB488: the interface accepts REN_CONTROL, GO_TO_LOCAL and
+    /// LOCAL_LOCKOUT
+    pub const REN_CONTROL: u8 = 0x02;
+    /// USB488: the interface accepts TRIGGER messages
+    pub const TRIGGER: u8 = 0x01;
+    /// USB488: the device understands all mandatory SCPI commands
+    pub const SCPI: u8 = 0x08;
+    /// USB488: the device is SR1 capable (service requests)
+    pub const SR1: u8 = 0x04;
+    /// USB488: the device is RL1 capable (remote/local)
+    pub const RL1: u8 = 0x02;
+    /// USB488: the device is DT1 capable (device trigger)
+    pub const DT1: u8 = 0x01;
+}
+
+/// Bits of the IEEE 488.2 status byte
+pub mod status_byte {
+    /// Message Available: output is queued
+    pub const MAV: u8 = 0x10;
+    /// Event Status Bit: an enabled standard event occurred
+    pub const ESB: u8 = 0x20;
+    /// Request Service: the device requests service
+    pub const RQS: u8 = 0x40;
+}
+
+/// IDs of the bulk messages (MsgID). DEV_DEP_MSG_IN answers
+/// REQUEST_DEV_DEP_MSG_IN with the same ID.
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum MsgId {
+    DevDepMsgOut = 1,
+    RequestDevDepMsgIn = 2,
+    VendorSpecificOut = 126,
+    RequestVendorSpecificIn = 127,
+    /// USB488: trigger the device
+    Trigger = 128,
+}
+
+/// Bits of bmTransferAttributes of bulk messages
+pub mod transfer_attributes {
+    /// DEV_DEP_MSG_OUT and DEV_DEP_MSG_IN: the last byte of the transfer is
+    /// the end of the message
+    pub const EOM: u8 = 0x01;
+    /// REQUEST_DEV_DEP_MSG_IN: end the transfer on the termination
+    /// character. DEV_DEP_MSG_IN: the transfer ends with it.
+    pub const TERM_CHAR: u8 = 0x02;
+}
+
+/// Header of the messages exchanged on the bulk endpoints
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "12")]
+pub struct BulkHeader {
+    #[packed_field(bytes = "0")]
+    pub msg_id: u8,
+    /// Transfer identifier, 1 to 255, echoed by DEV_DEP_MSG_IN
+    #[packed_field(bytes = "1")]
+    pub b_tag: u8,
+    #[packed_field(bytes = "2")]
+    pub b_tag_inverse: u8,
+    #[packed_field(bytes = "3")]
+    pub reserved: u8,
+    /// Number of message bytes, without the header and alignment bytes
+    #[packed_field(bytes = "4..=7", endian = "lsb")]
+    pub transfer_size: Integer<u32, packed_bits::Bits<32>>,
+    /// See [transfer_attributes]
+    #[packed_field(bytes = "8")]
+    pub bm_transfer_attributes: u8,
+    /// REQUEST_DEV_DEP_MSG_IN: the termination character
+    #[packed_field(bytes = "9")]
+    pub term_char: u8,
+    #[packed_field(bytes = "10..=11")]
+    pub reserved2: [u8; 2],
+}
+
+impl BulkHeader {
+    /// Create the header of a DEV_DEP_MSG_IN message answering the request
+    /// with the given tag
+    pub fn dev_dep_msg_in(b_tag: u8, transfer_size: u32, attributes: u8) -> Self {
+        Self {
+            msg_id: MsgId::RequestDevDepMsgIn.to_primitive(),
+            b_tag,
+            b_tag_inverse: !b_tag,
+            reserved: 0,
+            transfer_size: Integer::from_primitive(transfer_size),
+            bm_transfer_attributes: attributes,
+            term_char: 0,
+            reserved2: [0; 2],
+        }
+    }
+
+    /// Returns true if the tag is valid and matches its inverse
+    pub fn has_valid_tag(&self) -> bool {
+        self.b_tag != 0 && self.b_tag_inverse == !self.b_tag
+    }
+
+    /// Returns the number of message bytes following the header
+    pub fn transfer_size(&self) -> usize {
+        self.transfer_size.to_primitive() as usize
+    }
+
+    /// Returns the size of the whole message, padded to a multiple of 4
+    /// bytes
+    pub fn message_size(&self) -> usize {
+        (BULK_HEADER_SIZE + self.transfer_size()).next_multiple_of(4)
+    }
+}
+
+/// IEEE 488.2 status byte of an instrument, shared between the interface
+/// and the code emulating the instrument
+#[derive(Debug, Clone, Default)]
+pub struct StatusByte(Arc<Mutex<u8>>);
+
+impl StatusByte {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the status byte (see [status_byte])
+    pub fn get(&self) -> u8 {
+        self.0.lock().map(|status| *status).unwrap_or_default()
+    }
+
+    /// Replace the status byte
+    pub fn set(&self, value: u8) {
+        if let Ok(mut status) = self.0.lock() {
+            *status = value;
+        }
+    }
+
+    /// Returns the status byte for a serial poll, which clears RQS
+    pub fn poll(&self) -> u8 {
+        let Ok(mut status) = self.0.lock() else {
+            return 0;
+        };
+        let value = *status;
+        *status &= !status_byte::RQS;
+        value
+    }
+}
+
+/// Events of a USBTMC interface
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TmcEvent {
+    /// The host cleared the input and output buffers with INITIATE_CLEAR
+    Clear,
+    /// The host aborted the bulk OUT transfer with the given tag
+    AbortBulkOut { tag: u8 },
+    /// The host aborted the bulk IN transfer with the given tag
+    AbortBulkIn { tag: u8 },
+    /// The host asked the device to blink its activity indicator
+    IndicatorPulse,
+    /// USB488: the host asserted or released the REN line
+    RenControl { enabled: bool },
+    /// USB488: the host returned the device to local control
+    GoToLocal,
+    /// USB488: the host disabled the local controls of the device
+    LocalLockout,
+}
+
+/// Callback called with the events of a USBTMC interface
+pub type TmcEventHandler = Arc<Mutex<dyn FnMut(TmcEvent) + Send>>;
+
+/// Event handlers of a USBTMC interface
+#[derive(Clone, Default)]
+pub struct TmcEventHandlers(Vec<TmcEventHandler>);
+
+impl TmcEventHandlers {
+    /// Dispatch the given event to all handlers
+    pub fn dispatch(&self, event: TmcEvent) {
+        for handler in self.0.iter() {
+            if let Ok(mut handler) = handler.lock() {
+                handler(event);
+            }
+        }
+    }
+}
+
+impl Debug for TmcEventHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TmcEventHandlers")
+            .field("count", &self.0.len())
+            .finish()
+    }
+}
+
+/// USBTMC interface definition
+#[derive(Debug, Clone)]
+pub struct TmcInterface {
+    pub iface: InterfaceDescriptor,
+    pub endpoint_descriptors: Vec<EndpointDescriptor>,
+    /// USBTMC interface capabilities (see [capabilities])
+    pub interface_capabilities: u8,
+    /// USBTMC device capabilities (see [capabilities])
+    pub device_capabilities: u8,
+    /// USB488 interface capabilities (see [capabilities])
+    pub usb488_interface_capabilities: u8,
+    /// USB488 device capabilities (see [capabilities])
+    pub usb488_device_capabilities: u8,
+    pub status_byte: StatusByte,
+    pub handlers: TmcEventHandlers,
+}
+
+impl TmcInterface {
+    pub fn new() -> Self {
+        let iface = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            b_interface_class: InterfaceClass::ApplicationSpecific,
+            b_interface_subclass: USBTMC_SUBCLASS,
+            b_interface_protocol: TmcProtocol::Usb488 as u8,
+            ..InterfaceDescriptor::new()
+        };
+
+        Self {
+            iface,
+            endpoint_descriptors: Vec::new(),
+            interface_capabilities: capabilities::INDICATOR_PULSE,
+            device_capabilities: capabilities::TERM_CHAR,
+            usb488_interface_capabilities: capabilities::USB488_2 | capabilities::REN_CONTROL,
+            usb488_device_capabilities: capabilities::SCPI | capabilities::SR1 | capabilities::RL1,
+            status_byte: StatusByte::new(),
+            handlers: TmcEventHandlers::default(),
+        }
+    }
+
+    /// Returns the protocol of the interface
+    pub fn protocol(&self) -> TmcProtocol {
+        match self.iface.b_interface_protocol {
+            1 => TmcProtocol::Usb488,
+            _ => TmcProtocol::Usbtmc,
+        }
+    }
+
+    /// Register a handler for the events of the interface
+    pub fn on_event<F>(&mut self, handler: F)
+    where
+        F: FnMut(TmcEvent) + Send + 'static,
+    {
+        let handler: TmcEventHandler = Arc::new(Mutex::new(handler));
+        self.handlers.0.push(handler);
+    }
+
+    /// Returns the reply to GET_CAPABILITIES. The USB488 fields are zero for
+    /// plain USBTMC interfaces.
+    pub fn capabilities(&self) -> Vec<u8> {
+        let mut data = vec![0; CAPABILITIES_SIZE];
+        data[0] = status::SUCCESS;
+        data[2..4].copy_from_slice(&USBTMC_VERSION.to_le_bytes());
+        data[4] = self.interface_capabilities;
+        data[5] = self.device_capabilities;
+        if self.protocol() == TmcProtocol::Usb488 {
+            data[12..14].copy_from_slice(&USB488_VERSION.to_le_bytes());
+            data[14] = self.usb488_interface_capabilities;
+            data[15] = self.usb488_device_capabilities;
+        }
+        data
+    }
+
+    /// Returns the endpoint number of the bulk endpoint in the given
+    /// direction
+    pub fn bulk_endpoint(&self, direction: Direction) -> Option<u8> {
+        self.endpoint_descriptors
+            .iter()
+            .find(|desc| {
+                desc.direction() == direction && desc.transfer_type() == TransferType::Bulk
+            })
+            .map(|desc| desc.number())
+    }
+
+    /// Returns the endpoint number of the interrupt IN endpoint
+    pub fn interrupt_endpoint(&self) -> Option<u8> {
+        self.endpoint_descriptors
+            .iter()
+            .find(|desc| {
+                desc.direction() == Direction::In && desc.transfer_type() == TransferType::Interrupt
+            })
+            .map(|desc| desc.number())
+    }
+
+    /// Serialize the interface into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.get_size());
+        result.append(&mut self.iface.pack_to_vec()?);
+        for endpoint_desc in self.endpoint_descriptors.iter() {
+            result.append(&mut endpoint_desc.pack_to_vec()?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the byte serialized size of the interface
+    pub fn get_size(&self) -> usize {
+        9 + (7 * self.endpoint_descriptors.len())
+    }
+
+    /// Returns the interface class
+    pub fn get_class(&self) -> InterfaceClass {
+        self.iface.b_interface_class
+    }
+
+    /// Set the interface number for this interface
+    pub fn set_interface_number(&mut self, num: u8) {
+        self.iface.b_interface_number = num;
+    }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        self.endpoint_descriptors.as_slice()
+    }
+}
+
+impl Display for TmcInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut text = vec![format!("{}", self.iface)];
+        for desc in self.endpoint_descriptors.iter() {
+            text.push(format!("{}", desc));
+        }
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+impl Default for TmcInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [Interface] builder for constructing a USBTMC interface.
+pub struct TmcInterfaceBuilder {
+    iface: TmcInterface,
+}
+
+impl TmcInterfaceBuilder {
+    pub fn new() -> Self {
+        Self {
+            iface: TmcInterface::default(),
+        }
+    }
+
+    /// Construct the new Interface configuration.
+    pub fn build(&self) -> Interface {
+        #[cfg(feature = "log")]
+        log::debug!("USBTMC Interface: {}", self.iface);
+        Interface::TestMeasurement(self.iface.clone())
+    }
+
+    /// Set the protocol of the interface (USB488 by default)
+    pub fn protocol(&mut self, protocol: TmcProtocol) -> &mut Self {
+        self.iface.iface.b_interface_protocol = protocol as u8;
+        self
+    }
+
+    /// Set the USBTMC interface and device capabilities (see
+    /// [capabilities])
+    pub fn capabilities(&mut self, interface: u8, device: u8) -> &mut Self {
+        self.iface.interface_capabilities = interface;
+        self.iface.device_capabilities = device;
+        self
+    }
+
+    /// Set the USB488 interface and device capabilities (see
+    /// [capabilities])
+    pub fn usb488_capabilities(&mut self, interface: u8, device: u8) -> &mut Self {
+        self.iface.usb488_interface_capabilities = interface;
+        self.iface.usb488_device_capabilities = device;
+        self
+    }
+
+    /// Share the given status byte with the interface, to be read with
+    /// READ_STATUS_BYTE
+    pub fn status_byte(&mut self, status: &StatusByte) -> &mut Self {
+        self.iface.status_byte = status.clone();
+        self
+    }
+
+    /// Handle the events of the interface. See [TmcInterface::on_event].
+    pub fn on_event<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: FnMut(TmcEvent) + Send + 'static,
+    {
+        self.iface.on_event(handler);
+        self
+    }
+
+    /// Add a bulk OUT and a bulk IN endpoint with the given endpoint numbers
+    /// and max packet size (64 for full speed, 512 for high speed).
+    pub fn bulk_endpoints(&mut self, out_num: u8, in_num: u8, max_packet_size: u16) -> &mut Self {
+        for (num, direction) in [(out_num, Direction::Out), (in_num, Direction::In)] {
+            let descriptor = EndpointBuilder::new()
+                .address_num(num)
+                .direction(direction)
+                .transfer_type(TransferType::Bulk)
+                .sync_type(SynchronizationType::NoSynchronization)
+                .usage_type(UsageType::Data)
+                .max_packet_size(max_packet_size)
+                .build();
+            self.endpoint_descriptor(descriptor);
+        }
+        self
+    }
+
+    /// Add an interrupt IN endpoint with the given endpoint number, used by
+    /// USB488 interfaces for service requests and READ_STATUS_BYTE replies
+    pub fn interrupt_endpoint(&mut self, num: u8) -> &mut Self {
+        let descriptor = EndpointBuilder::new()
+            .address_num(num)
+            .direction(Direction::In)
+            .transfer_type(TransferType::Interrupt)
+            .sync_type(SynchronizationType::NoSynchronization)
+            .usage_type(UsageType::Data)
+            .max_packet_size(2)
+            .interval(8)
+            .build();
+        self.endpoint_descriptor(descriptor)
+    }
+
+    /// Add the given endpoint to the interface
+    pub fn endpoint_descriptor(&mut self, descriptor: EndpointDescriptor) -> &mut Self {
+        self.iface.endpoint_descriptors.push(descriptor);
+        self.iface.iface.b_num_endpoints = self.iface.endpoint_descriptors.len() as u8;
+        self
+    }
+}
+
+impl Default for TmcInterfaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,1010 @@
+//! UVC (USB Video Class) 1.0
+//! https://www.usb.org/document-library/video-class-v15-document-set
+
+use std::{
+    fmt::{Debug, Display},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use packed_struct::prelude::*;
+
+use super::{
+    Direction, EndpointBuilder, EndpointDescriptor, Interface, InterfaceAssociationDescriptor,
+    InterfaceClass, InterfaceDescriptor, SynchronizationType, TransferType, UsageType,
+};
+
+/// Version of the UVC specification the descriptors comply with (1.00)
+pub const BCD_UVC: u16 = 0x0100;
+/// Class-specific interface descriptor type (bDescriptorType)
+pub const CS_INTERFACE: u8 = 0x24;
+/// Clock frequency reported in the VideoControl header, in Hz
+pub const CLOCK_FREQUENCY: u32 = 48_000_000;
+/// ID of the camera input terminal
+pub const CAMERA_TERMINAL_ID: u8 = 1;
+/// ID of the processing unit, connected to the camera terminal
+pub const PROCESSING_UNIT_ID: u8 = 2;
+/// ID of the streaming output terminal, connected to the processing unit
+pub const OUTPUT_TERMINAL_ID: u8 = 3;
+/// Size of the payload headers sent before the video data
+pub const PAYLOAD_HEADER_SIZE: usize = 2;
+/// Size of the video probe and commit controls for UVC 1.0
+pub const PROBE_CONTROL_SIZE: usize = 26;
+
+/// Video interface subclass codes
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UvcSubclass {
+    VideoControl = 0x01,
+    VideoStreaming = 0x02,
+    VideoInterfaceCollection = 0x03,
+}
+
+/// VideoControl class-specific descriptor subtypes (bDescriptorSubtype)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum VcDescriptorSubtype {
+    Header = 0x01,
+    InputTerminal = 0x02,
+    OutputTerminal = 0x03,
+    SelectorUnit = 0x04,
+    ProcessingUnit = 0x05,
+    ExtensionUnit = 0x06,
+}
+
+/// VideoStreaming class-specific descriptor subtypes (bDescriptorSubtype)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum VsDescriptorSubtype {
+    InputHeader = 0x01,
+    FormatUncompressed = 0x04,
+    FrameUncompressed = 0x05,
+    FormatMjpeg = 0x06,
+    FrameMjpeg = 0x07,
+}
+
+/// Terminal types (wTerminalType)
+pub mod terminal_type {
+    /// Streaming terminal carrying the video to the host
+    pub const STREAMING: u16 = 0x0101;
+    /// Camera sensor
+    pub const CAMERA: u16 = 0x0201;
+}
+
+/// Video class-specific request codes (bRequest)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum UvcRequest {
+    SetCur = 0x01,
+    GetCur = 0x81,
+    GetMin = 0x82,
+    GetMax = 0x83,
+    GetRes = 0x84,
+    GetLen = 0x85,
+    GetInfo = 0x86,
+    GetDef = 0x87,
+}
+
+/// VideoStreaming interface control selectors (high byte of wValue)
+#[derive(PrimitiveEnum_u8, Debug, Copy, Clone, PartialEq)]
+pub enum VsControl {
+    Probe = 0x01,
+    Commit = 0x02,
+}
+
+/// Bits of the bmHeaderInfo field of payload headers
+pub mod payload_header {
+    /// Frame ID, toggled at the start of every video frame
+    pub const FID: u8 = 0x01;
+    /// End of frame
+    pub const EOF: u8 = 0x02;
+    /// Presentation time stamp present
+    pub const PTS: u8 = 0x04;
+    /// Source clock reference present
+    pub const SCR: u8 = 0x08;
+    /// Still image
+    pub const STI: u8 = 0x20;
+    /// Error in the device streaming
+    pub const ERR: u8 = 0x40;
+    /// End of header
+    pub const EOH: u8 = 0x80;
+}
+
+/// Capabilities reported with GET_INFO (support for GET and SET requests)
+const INFO_GET_SET: u8 = 0x03;
+
+/// Video formats of a VideoStreaming interface
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum VideoFormat {
+    /// Uncompressed packed YUV 4:2:2 with 16 bits per pixel (Y0 U Y1 V)
+    Yuy2,
+    /// Motion JPEG, each frame being a baseline JPEG image
+    Mjpeg,
+}
+
+impl VideoFormat {
+    /// GUID of uncompressed formats
+    const YUY2_GUID: [u8; 16] = [
+        b'Y', b'U', b'Y', b'2', 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b,
+        0x71,
+    ];
+}
+
+/// Frame size of a video format with its supported frame intervals
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoFrame {
+    pub width: u16,
+    pub height: u16,
+    /// Supported frame intervals in 100 ns units. The first one is the
+    /// default.
+    pub intervals: Vec<u32>,
+}
+
+impl VideoFrame {
+    /// Create a frame size supporting the given frame rates, the first one
+    /// being the default
+    pub fn new(width: u16, height: u16, frame_rates: &[u32]) -> Self {
+        let intervals = frame_rates
+            .iter()
+            .filter(|rate| **rate > 0)
+            .map(|rate| 10_000_000 / rate)
+            .collect();
+        Self {
+            width,
+            height,
+            intervals,
+        }
+    }
+
+    /// Returns the size of the largest frame in bytes. MJPEG frames are
+    /// assumed to be no larger than uncompressed ones.
+    pub fn max_frame_size(&self) -> u32 {
+        self.width as u32 * self.height as u32 * 2
+    }
+
+    /// Returns the default frame interval in 100 ns units
+    pub fn default_interval(&self) -> u32 {
+        self.intervals.first().copied().unwrap_or(333_333)
+    }
+
+    /// Returns the supported interval closest to the given one
+    fn closest_interval(&self, interval: u32) -> u32 {
+        self.intervals
+            .iter()
+            .copied()
+            .min_by_key(|supported| supported.abs_diff(interval))
+            .unwrap_or(interval)
+    }
+
+    /// Returns the bit rate in bits per second at the given frame interval
+    fn bit_rate(&self, interval: u32) -> u32 {
+        let bits = self.max_frame_size() as u64 * 8 * 10_000_000;
+        (bits / interval.max(1) as u64).min(u32::MAX as u64) as u32
+    }
+
+    /// Serialize the frame descriptor with the given index (starting at 1)
+    fn pack_to_vec(&self, format: VideoFormat, index: u8) -> Vec<u8> {
+        let subtype = match format {
+            VideoFormat::Yuy2 => VsDescriptorSubtype::FrameUncompressed,
+            VideoFormat::Mjpeg => VsDescriptorSubtype::FrameMjpeg,
+        };
+        let min_interval = self.intervals.iter().copied().min().unwrap_or(1);
+        let max_interval = self.intervals.iter().copied().max().unwrap_or(1);
+
+        let mut desc = Vec::with_capacity(self.get_size());
+        desc.extend_from_slice(&[self.get_size() as u8, CS_INTERFACE, subtype as u8, index, 0]);
+        desc.extend_from_slice(&self.width.to_le_bytes());
+        desc.extend_from_slice(&self.height.to_le_bytes());
+        desc.extend_from_slice(&self.bit_rate(max_interval).to_le_bytes());
+        desc.extend_from_slice(&self.bit_rate(min_interval).to_le_bytes());
+        desc.extend_from_slice(&self.max_frame_size().to_le_bytes());
+        desc.extend_from_slice(&self.default_interval().to_le_bytes());
+        desc.push(self.intervals.len() as u8);
+        for interval in self.intervals.iter() {
+            desc.extend_from_slice(&interval.to_le_bytes());
+        }
+        desc
+    }
+
+    /// Returns the byte serialized size of the frame descriptor
+    fn get_size(&self) -> usize {
+        26 + 4 * self.intervals.len()
+    }
+}
+
+/// Video format of a VideoStreaming interface with its frame sizes
+#[derive(Debug, Clone, PartialEq)]
+pub struct UvcFormat {
+    pub format: VideoFormat,
+    /// Frame sizes of the format. The first one is the default.
+    pub frames: Vec<VideoFrame>,
+}
+
+impl UvcFormat {
+    pub fn new(format: VideoFormat, frames: Vec<VideoFrame>) -> Self {
+        Self { format, frames }
+    }
+
+    /// Serialize the format descriptor with the given index (starting at 1)
+    /// followed by its frame descriptors
+    fn pack_to_vec(&self, index: u8) -> Vec<u8> {
+        let num_frames = self.frames.len() as u8;
+        let mut desc = Vec::with_capacity(self.get_size());
+        match self.format {
+            VideoFormat::Yuy2 => {
+                let subtype = VsDescriptorSubtype::FormatUncompressed as u8;
+                desc.extend_from_slice(&[27, CS_INTERFACE, subtype, index, num_frames]);
+                desc.extend_from_slice(&VideoFormat::YUY2_GUID);
+                // Bits per pixel, default frame, aspect ratio, interlacing
+                // and copy protection
+                desc.extend_from_slice(&[16, 1, 0, 0, 0, 0]);
+            }
+            VideoFormat::Mjpeg => {
+                let subtype = VsDescriptorSubtype::FormatMjpeg as u8;
+                desc.extend_from_slice(&[11, CS_INTERFACE, subtype, index, num_frames]);
+                // Fixed size samples, default frame, aspect ratio,
+                // interlacing and copy protection
+                desc.extend_from_slice(&[1, 1, 0, 0, 0, 0]);
+            }
+        }
+        for (i, frame) in self.frames.iter().enumerate() {
+            desc.append(&mut frame.pack_to_vec(self.format, i as u8 + 1));
+        }
+        desc
+    }
+
+    /// Returns the byte serialized size of the format and frame descriptors
+    fn get_size(&self) -> usize {
+        let format_size = match self.format {
+            VideoFormat::Yuy2 => 27,
+            VideoFormat::Mjpeg => 11,
+        };
+        format_size + self.frames.iter().map(|f| f.get_size()).sum::<usize>()
+    }
+}
+
+/// Video stream parameters committed by the host
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StreamFormat {
+    pub format: VideoFormat,
+    pub width: u16,
+    pub height: u16,
+    /// Frame interval in 100 ns units
+    pub frame_interval: u32,
+    /// Maximum size of a video frame in bytes
+    pub max_video_frame_size: u32,
+    /// Maximum size of a payload, including its header
+    pub max_payload_transfer_size: u32,
+}
+
+impl StreamFormat {
+    /// Returns the time between two frames
+    pub fn interval(&self) -> Duration {
+        Duration::from_nanos(self.frame_interval as u64 * 100)
+    }
+}
+
+/// Video probe and commit control, used by the host to negotiate the
+/// stream parameters
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq, Default)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "26")]
+pub struct VideoProbeControl {
+    /// Fields the host wants to keep fixed during negotiation
+    #[packed_field(bytes = "0..=1", endian = "lsb")]
+    pub bm_hint: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "2")]
+    pub b_format_index: u8,
+    #[packed_field(bytes = "3")]
+    pub b_frame_index: u8,
+    /// Frame interval in 100 ns units
+    #[packed_field(bytes = "4..=7", endian = "lsb")]
+    pub dw_frame_interval: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "8..=9", endian = "lsb")]
+    pub w_key_frame_rate: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "10..=11", endian = "lsb")]
+    pub w_p_frame_rate: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "12..=13", endian = "lsb")]
+    pub w_comp_quality: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "14..=15", endian = "lsb")]
+    pub w_comp_window_size: Integer<u16, packed_bits::Bits<16>>,
+    /// Latency of the device in milliseconds
+    #[packed_field(bytes = "16..=17", endian = "lsb")]
+    pub w_delay: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "18..=21", endian = "lsb")]
+    pub dw_max_video_frame_size: Integer<u32, packed_bits::Bits<32>>,
+    #[packed_field(bytes = "22..=25", endian = "lsb")]
+    pub dw_max_payload_transfer_size: Integer<u32, packed_bits::Bits<32>>,
+}
+
+/// State change of a VideoStreaming interface requested by the host
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UvcEvent {
+    /// The host committed the stream parameters with SET_CUR(COMMIT) and
+    /// is about to start streaming
+    Commit(StreamFormat),
+}
+
+/// Callback receiving the state changes requested by the host
+pub type UvcEventHandler = Arc<Mutex<dyn FnMut(UvcEvent) + Send>>;
+
+/// Event handlers of a VideoStreaming interface
+#[derive(Clone, Default)]
+pub struct UvcEventHandlers(Vec<UvcEventHandler>);
+
+impl UvcEventHandlers {
+    /// Dispatch the given event to all handlers
+    pub fn dispatch(&self, event: UvcEvent) {
+        for handler in self.0.iter() {
+            if let Ok(mut handler) = handler.lock() {
+                handler(event);
+            }
+        }
+    }
+}
+
+impl Debug for UvcEventHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UvcEventHandlers")
+            .field("count", &self.0.len())
+            .finish()
+    }
+}
+
+/// Negotiation state of a VideoStreaming interface
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct StreamingState {
+    /// Parameters of the current probe
+    pub probe: VideoProbeControl,
+    /// Parameters committed by the host, if any
+    pub commit: Option<VideoProbeControl>,
+}
+
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "13")]
+pub struct VcHeaderDescriptor {
+    #[packed_field(bytes = "0")]
+    pub b_length: u8,
+    #[packed_field(bytes = "1")]
+    pub b_descriptor_type: u8,
+    #[packed_field(bytes = "2")]
+    pub b_descriptor_subtype: u8,
+    #[packed_field(bytes = "3..=4", endian = "lsb")]
+    pub bcd_uvc: Integer<u16, packed_bits::Bits<16>>,
+    /// Total size of the class-specific VideoControl descriptors
+    #[packed_field(bytes = "5..=6", endian = "lsb")]
+    pub w_total_length: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "7..=10", endian = "lsb")]
+    pub dw_clock_frequency: Integer<u32, packed_bits::Bits<32>>,
+    /// Number of VideoStreaming interfaces of the function
+    #[packed_field(bytes = "11")]
+    pub b_in_collection: u8,
+    /// Interface number of the VideoStreaming interface
+    #[packed_field(bytes = "12")]
+    pub ba_interface_nr: u8,
+}
+
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "18")]
+pub struct CameraTerminalDescriptor {
+    #[packed_field(bytes = "0")]
+    pub b_length: u8,
+    #[packed_field(bytes = "1")]
+    pub b_descriptor_type: u8,
+    #[packed_field(bytes = "2")]
+    pub b_descriptor_subtype: u8,
+    #[packed_field(bytes = "3")]
+    pub b_terminal_id: u8,
+    #[packed_field(bytes = "4..=5", endian = "lsb")]
+    pub w_terminal_type: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "6")]
+    pub b_assoc_terminal: u8,
+    #[packed_field(bytes = "7")]
+    pub i_terminal: u8,
+    #[packed_field(bytes = "8..=9", endian = "lsb")]
+    pub w_objective_focal_length_min: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "10..=11", endian = "lsb")]
+    pub w_objective_focal_length_max: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "12..=13", endian = "lsb")]
+    pub w_ocular_focal_length: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "14")]
+    pub b_control_size: u8,
+    /// Supported camera controls. Controls reported here must be answered
+    /// by the device, so none are reported by default.
+    #[packed_field(bytes = "15..=17")]
+    pub bm_controls: [u8; 3],
+}
+
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "11")]
+pub struct ProcessingUnitDescriptor {
+    #[packed_field(bytes = "0")]
+    pub b_length: u8,
+    #[packed_field(bytes = "1")]
+    pub b_descriptor_type: u8,
+    #[packed_field(bytes = "2")]
+    pub b_descriptor_subtype: u8,
+    #[packed_field(bytes = "3")]
+    pub b_unit_id: u8,
+    #[packed_field(bytes = "4")]
+    pub b_source_id: u8,
+    #[packed_field(bytes = "5..=6", endian = "lsb")]
+    pub w_max_multiplier: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "7")]
+    pub b_control_size: u8,
+    /// Supported processing controls (brightness, contrast...)
+    #[packed_field(bytes = "8..=9")]
+    pub bm_controls: [u8; 2],
+    #[packed_field(bytes = "10")]
+    pub i_processing: u8,
+}
+
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "9")]
+pub struct OutputTerminalDescriptor {
+    #[packed_field(bytes = "0")]
+    pub b_length: u8,
+    #[packed_field(bytes = "1")]
+    pub b_descriptor_type: u8,
+    #[packed_field(bytes = "2")]
+    pub b_descriptor_subtype: u8,
+    #[packed_field(bytes = "3")]
+    pub b_terminal_id: u8,
+    #[packed_field(bytes = "4..=5", endian = "lsb")]
+    pub w_terminal_type: Integer<u16, packed_bits::Bits<16>>,
+    #[packed_field(bytes = "6")]
+    pub b_assoc_terminal: u8,
+    #[packed_field(bytes = "7")]
+    pub b_source_id: u8,
+    #[packed_field(bytes = "8")]
+    pub i_terminal: u8,
+}
+
+/// VideoControl interface definition, describing a camera terminal
+/// connected to a processing unit and a streaming output terminal. The
+/// VideoStreaming interface that belongs to it must be added to the
+/// configuration right after it.
+#[derive(Debug, Clone)]
+pub struct UvcControlInterface {
+    /// Association grouping the VideoControl and VideoStreaming interfaces,
+    /// required for video functions
+    pub association: InterfaceAssociationDescriptor,
+    pub iface: InterfaceDescriptor,
+    pub header: VcHeaderDescriptor,
+    pub camera: CameraTerminalDescriptor,
+    pub processing: ProcessingUnitDescriptor,
+    pub output: OutputTerminalDescriptor,
+}
+
+impl UvcControlInterface {
+    pub fn new() -> Self {
+        let iface = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            b_interface_class: InterfaceClass::Video,
+            b_interface_subclass: UvcSubclass::VideoControl as u8,
+            b_interface_protocol: 0,
+            ..InterfaceDescriptor::new()
+        };
+        let total_length = 13 + 18 + 11 + 9;
+
+        Self {
+            association: InterfaceAssociationDescriptor {
+                b_interface_count: 2,
+                b_function_class: InterfaceClass::Video as u8,
+                b_function_sub_class: UvcSubclass::VideoInterfaceCollection as u8,
+                ..InterfaceAssociationDescriptor::new()
+            },
+            iface,
+            header: VcHeaderDescriptor {
+                b_length: 13,
+                b_descriptor_type: CS_INTERFACE,
+                b_descriptor_subtype: VcDescriptorSubtype::Header as u8,
+                bcd_uvc: Integer::from_primitive(BCD_UVC),
+                w_total_length: Integer::from_primitive(total_length),
+                dw_clock_frequency: Integer::from_primitive(CLOCK_FREQUENCY),
+                b_in_collection: 1,
+                ba_interface_nr: 1,
+            },
+            camera: CameraTerminalDescriptor {
+                b_length: 18,
+                b_descriptor_type: CS_INTERFACE,
+                b_descriptor_subtype: VcDescriptorSubtype::InputTerminal as u8,
+                b_terminal_id: CAMERA_TERMINAL_ID,
+                w_terminal_type: Integer::from_primitive(terminal_type::CAMERA),
+                b_assoc_terminal: 0,
+                i_terminal: 0,
+                w_objective_focal_length_min: Integer::from_primitive(0),
+                w_objective_focal_length_max: Integer::from_primitive(0),
+                w_ocular_focal_length: Integer::from_primitive(0),
+                b_control_size: 3,
+                bm_controls: [0; 3],
+            },
+            processing: ProcessingUnitDescriptor {
+                b_length: 11,
+                b_descriptor_type: CS_INTERFACE,
+                b_descriptor_subtype: VcDescriptorSubtype::ProcessingUnit as u8,
+                b_unit_id: PROCESSING_UNIT_ID,
+                b_source_id: CAMERA_TERMINAL_ID,
+                w_max_multiplier: Integer::from_primitive(0),
+                b_control_size: 2,
+                bm_controls: [0; 2],
+                i_processing: 0,
+            },
+            output: OutputTerminalDescriptor {
+                b_length: 9,
+                b_descriptor_type: CS_INTERFACE,
+                b_descriptor_subtype: VcDescriptorSubtype::OutputTerminal as u8,
+                b_terminal_id: OUTPUT_TERMINAL_ID,
+                w_terminal_type: Integer::from_primitive(terminal_type::STREAMING),
+                b_assoc_terminal: 0,
+                b_source_id: PROCESSING_UNIT_ID,
+                i_terminal: 0,
+            },
+        }
+    }
+
+    /// Serialize the interface into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.get_size());
+        result.append(&mut self.association.pack_to_vec()?);
+        result.append(&mut self.iface.pack_to_vec()?);
+        result.append(&mut self.header.pack_to_vec()?);
+        result.append(&mut self.camera.pack_to_vec()?);
+        result.append(&mut self.processing.pack_to_vec()?);
+        result.append(&mut self.output.pack_to_vec()?);
+
+        Ok(result)
+    }
+
+    /// Returns the byte serialized size of the interface
+    pub fn get_size(&self) -> usize {
+        8 + 9 + self.header.w_total_length.to_primitive() as usize
+    }
+
+    /// Returns the interface class
+    pub fn get_class(&self) -> InterfaceClass {
+        self.iface.b_interface_class
+    }
+
+    /// Set the interface number for this interface. The VideoStreaming
+    /// interface is expected to be the next interface.
+    pub fn set_interface_number(&mut self, num: u8) {
+        self.iface.b_interface_number = num;
+        self.association.b_first_interface = num;
+        self.header.ba_interface_nr = num + 1;
+    }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        &[]
+    }
+}
+
+impl Display for UvcControlInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = [
+            format!("{}", self.association),
+            format!("{}", self.iface),
+            format!("{}", self.header),
+            format!("{}", self.camera),
+            format!("{}", self.processing),
+            format!("{}", self.output),
+        ];
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+impl Default for UvcControlInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [Interface] builder for constructing a VideoControl interface.
+pub struct UvcControlInterfaceBuilder {
+    iface: UvcControlInterface,
+}
+
+impl UvcControlInterfaceBuilder {
+    pub fn new() -> Self {
+        Self {
+            iface: UvcControlInterface::default(),
+        }
+    }
+
+    /// Construct the new Interface configuration.
+    pub fn build(&self) -> Interface {
+        #[cfg(feature = "log")]
+        log::debug!("UVC Control Interface: {}", self.iface);
+        Interface::VideoControl(self.iface.clone())
+    }
+
+    /// Set the index of the string descriptor naming the video function
+    pub fn function_name_index(&mut self, index: u8) -> &mut Self {
+        self.iface.association.i_function = index;
+        self
+    }
+}
+
+impl Default for UvcControlInterfaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// VideoStreaming interface definition, sending the video of the output
+/// terminal to the host on a bulk or isochronous IN endpoint.
+#[derive(Debug, Clone)]
+pub struct UvcStreamingInterface {
+    pub iface: InterfaceDescriptor,
+    /// Video formats, indexed from 1 by the probe and commit controls
+    pub formats: Vec<UvcFormat>,
+    /// If true, the endpoint belongs to alternate setting 1 and alternate
+    /// setting 0 has no endpoints, as required for isochronous endpoints.
+    pub alternate_setting: bool,
+    pub endpoint_descriptors: Vec<EndpointDescriptor>,
+    /// Negotiation state, shared by all clones of the interface
+    pub state: Arc<Mutex<StreamingState>>,
+    pub handlers: UvcEventHandlers,
+}
+
+impl UvcStreamingInterface {
+    pub fn new() -> Self {
+        let iface = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            b_interface_class: InterfaceClass::Video,
+            b_interface_subclass: UvcSubclass::VideoStreaming as u8,
+            b_interface_protocol: 0,
+            ..InterfaceDescriptor::new()
+        };
+
+        Self {
+            iface,
+            formats: Vec::new(),
+            alternate_setting: false,
+            endpoint_descriptors: Vec::new(),
+            state: Arc::new(Mutex::new(StreamingState::default())),
+            handlers: UvcEventHandlers::default(),
+        }
+    }
+
+    /// Register a handler for the state changes requested by the host
+    pub fn on_event<F>(&mut self, handler: F)
+    where
+        F: FnMut(UvcEvent) + Send + 'static,
+    {
+        let handler: UvcEventHandler = Arc::new(Mutex::new(handler));
+        self.handlers.0.push(handler);
+    }
+
+    /// Returns the number of the video IN endpoint
+    pub fn video_endpoint(&self) -> Option<u8> {
+        self.endpoint_descriptors
+            .iter()
+            .find(|desc| desc.direction() == Direction::In)
+            .map(|desc| desc.number())
+    }
+
+    /// Returns the negotiation state
+    pub fn streaming_state(&self) -> StreamingState {
+        self.state.lock().map(|state| *state).unwrap_or_default()
+    }
+
+    /// Returns the stream parameters committed by the host, if any
+    pub fn stream_format(&self) -> Option<StreamFormat> {
+        let commit = self.streaming_state().commit?;
+        self.to_stream_format(&commit)
+    }
+
+    /// Returns the default probe parameters: the first frame of the first
+    /// format at its default frame interval
+    pub fn default_probe(&self) -> VideoProbeControl {
+        let probe = VideoProbeControl {
+            b_format_index: 1,
+            b_frame_index: 1,
+            ..VideoProbeControl::default()
+        };
+        self.negotiate(&probe)
+    }
+
+    /// Returns the probe parameters the device supports that are closest to
+    /// the ones requested by the host
+    pub fn negotiate(&self, requested: &VideoProbeControl) -> VideoProbeControl {
+        let mut probe = *requested;
+        let Some((format_index, format)) = Self::find(&self.formats, requested.b_format_index)
+        else {
+            return probe;
+        };
+        let Some((frame_index, frame)) = Self::find(&format.frames, requested.b_frame_index) else {
+            return probe;
+        };
+        let interval = match requested.dw_frame_interval.to_primitive() {
+            0 => frame.default_interval(),
+            interval => frame.closest_interval(interval),
+        };
+
+        // Bulk endpoints send each frame in a single payload, while
+        // isochronous endpoints send one payload per packet
+        let max_payload = match self.endpoint_descriptors.first() {
+            Some(desc) if desc.transfer_type() == TransferType::Isochronous => {
+                let max_packet_size = desc.w_max_packet_size.to_primitive();
+                let transactions = 1 + ((max_packet_size >> 11) & 0x3) as u32;
+                (max_packet_size & 0x7ff) as u32 * transactions
+            }
+            _ => frame.max_frame_size() + PAYLOAD_HEADER_SIZE as u32,
+        };
+
+        probe.b_format_index = format_index;
+        probe.b_frame_index = frame_index;
+        probe.dw_frame_interval = Integer::from_primitive(interval);
+        probe.dw_max_video_frame_size = Integer::from_primitive(frame.max_frame_size());
+        probe.dw_max_payload_transfer_size = Integer::from_primitive(max_payload);
+        probe
+    }
+
+    /// Returns the item with the given index (starting at 1) and its index,
+    /// or the first item if the index is out of range
+    fn find<T>(items: &[T], index: u8) -> Option<(u8, &T)> {
+        match items.get((index as usize).wrapping_sub(1)) {
+            Some(item) => Some((index, item)),
+            None => items.first().map(|item| (1, item)),
+        }
+    }
+
+    /// Returns the stream parameters described by the given probe
+    pub fn to_stream_format(&self, probe: &VideoProbeControl) -> Option<StreamFormat> {
+        let format = self
+            .formats
+            .get((probe.b_format_index as usize).checked_sub(1)?)?;
+        let frame = format
+            .frames
+            .get((probe.b_frame_index as usize).checked_sub(1)?)?;
+        Some(StreamFormat {
+            format: format.format,
+            width: frame.width,
+            height: frame.height,
+            frame_interval: probe.dw_frame_interval.to_primitive(),
+            max_video_frame_size: probe.dw_max_video_frame_size.to_primitive(),
+            max_payload_transfer_size: probe.dw_max_payload_transfer_size.to_primitive(),
+        })
+    }
+
+    /// Handle SET_CUR for the probe or commit control with the given request
+    /// data. Returns false if the data is too short.
+    pub fn handle_set_cur(&self, control: VsControl, data: &[u8]) -> bool {
+        let Some(data) = data.get(..PROBE_CONTROL_SIZE) else {
+            return false;
+        };
+        let Ok(requested) = VideoProbeControl::unpack_from_slice(data) else {
+            return false;
+        };
+        let probe = self.negotiate(&requested);
+        if let Ok(mut state) = self.state.lock() {
+            match control {
+                VsControl::Probe => state.probe = probe,
+                VsControl::Commit => state.commit = Some(probe),
+            }
+        }
+
+        if control == VsControl::Commit {
+            if let Some(format) = self.to_stream_format(&probe) {
+                self.handlers.dispatch(UvcEvent::Commit(format));
+            }
+        }
+        true
+    }
+
+    /// Handle the GET requests of the probe or commit control. Returns
+    /// None if the request is not supported.
+    pub fn handle_get(
+        &self,
+        request: UvcRequest,
+        control: VsControl,
+    ) -> Result<Option<Vec<u8>>, PackingError> {
+        let state = self.streaming_state();
+        let current = match control {
+            VsControl::Probe => Some(state.probe).filter(|probe| probe.b_format_index != 0),
+            VsControl::Commit => state.commit,
+        };
+        let probe = match request {
+            UvcRequest::GetLen => return Ok(Some(vec![PROBE_CONTROL_SIZE as u8, 0])),
+            UvcRequest::GetInfo => return Ok(Some(vec![INFO_GET_SET])),
+            UvcRequest::GetCur => current.unwrap_or_else(|| self.default_probe()),
+            UvcRequest::GetMin | UvcRequest::GetMax | UvcRequest::GetDef => self.default_probe(),
+            _ => return Ok(None),
+        };
+        Ok(Some(probe.pack_to_vec()?))
+    }
+
+    /// Returns the class-specific VideoStreaming descriptors: the input
+    /// header followed by the format and frame descriptors
+    fn class_descriptors(&self) -> Vec<u8> {
+        let num_formats = self.formats.len();
+        let header_size = 13 + num_formats;
+        let total_length = header_size + self.formats.iter().map(|f| f.get_size()).sum::<usize>();
+        let endpoint_address = self
+            .video_endpoint()
+            .map(|num| num | 0x80)
+            .unwrap_or_default();
+
+        let mut desc = Vec::with_capacity(total_length);
+        let subtype = VsDescriptorSubtype::InputHeader as u8;
+        desc.extend_from_slice(&[header_size as u8, CS_INTERFACE, subtype, num_formats as u8]);
+        desc.extend_from_slice(&(total_length as u16).to_le_bytes());
+        // Endpoint, no dynamic format change, output terminal, no still
+        // image capture or hardware trigger, one byte of controls per format
+        desc.extend_from_slice(&[endpoint_address, 0, OUTPUT_TERMINAL_ID, 0, 0, 0, 1]);
+        desc.resize(header_size, 0);
+        for (i, format) in self.formats.iter().enumerate() {
+            desc.append(&mut format.pack_to_vec(i as u8 + 1));
+        }
+        desc
+    }
+
+    /// Returns the interface descriptors of alternate setting 0 and, if
+    /// present, alternate setting 1
+    fn interface_descriptors(&self) -> Vec<InterfaceDescriptor> {
+        if !self.alternate_setting {
+            return vec![self.iface];
+        }
+        let inactive = InterfaceDescriptor {
+            b_num_endpoints: 0,
+            ..self.iface
+        };
+        let active = InterfaceDescriptor {
+            b_alternate_setting: 1,
+            ..self.iface
+        };
+        vec![inactive, active]
+    }
+
+    /// Returns the number of alternate settings of the interface
+    pub fn alternate_settings(&self) -> u8 {
+        if self.alternate_setting {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Serialize the interface into bytes
+    pub fn pack_to_vec(&self) -> Result<Vec<u8>, PackingError> {
+        let mut result: Vec<u8> = Vec::with_capacity(self.get_size());
+        let mut ifaces = self.interface_descriptors().into_iter();
+        if let Some(iface) = ifaces.next() {
+            result.append(&mut iface.pack_to_vec()?);
+        }
+        result.append(&mut self.class_descriptors());
+        for iface in ifaces {
+            result.append(&mut iface.pack_to_vec()?);
+        }
+        for endpoint_desc in self.endpoint_descriptors.iter() {
+            result.append(&mut endpoint_desc.pack_to_vec()?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the byte serialized size of the interface
+    pub fn get_size(&self) -> usize {
+        let class_size =
+            13 + self.formats.len() + self.formats.iter().map(|f| f.get_size()).sum::<usize>();
+        (9 * self.alternate_settings() as usize)
+            + class_size
+            + (7 * self.endpoint_descriptors.len())
+    }
+
+    /// Returns the interface class
+    pub fn get_class(&self) -> InterfaceClass {
+        self.iface.b_interface_class
+    }
+
+    /// Set the interface number for this interface
+    pub fn set_interface_number(&mut self, num: u8) {
+        self.iface.b_interface_number = num;
+    }
+
+    /// Returns the endpoint descriptors of the interface
+    pub fn get_endpoints(&self) -> &[EndpointDescriptor] {
+        self.endpoint_descriptors.as_slice()
+    }
+}
+
+impl Display for UvcStreamingInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut text: Vec<String> = self
+            .interface_descriptors()
+            .iter()
+            .map(|iface| format!("{}", iface))
+            .collect();
+        for format in self.formats.iter() {
+            text.push(format!("{format:?}"));
+        }
+        for desc in self.endpoint_descriptors.iter() {
+            text.push(format!("{}", desc));
+        }
+        write!(f, "{}", text.join("\n"))
+    }
+}
+
+impl Default for UvcStreamingInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [Interface] builder for constructing a VideoStreaming interface.
+pub struct UvcStreamingInterfaceBuilder {
+    iface: UvcStreamingInterface,
+}
+
+impl UvcStreamingInterfaceBuilder {
+    pub fn new() -> Self {
+        Self {
+            iface: UvcStreamingInterface::default(),
+        }
+    }
+
+    /// Construct the new Interface configuration.
+    pub fn build(&self) -> Interface {
+        #[cfg(feature = "log")]
+        log::debug!("UVC Streaming Interface: {}", self.iface);
+        Interface::VideoStreaming(self.iface.clone())
+    }
+
+    /// Add a video format with its frame sizes. The first format added is
+    /// the default.
+    pub fn format(&mut self, format: UvcFormat) -> &mut Self {
+        self.iface.formats.push(format);
+        self
+    }
+
+    /// Handle the state changes requested by the host. See
+    /// [UvcStreamingInterface::on_event].
+    pub fn on_event<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: FnMut(UvcEvent) + Send + 'static,
+    {
+        self.iface.on_event(handler);
+        self
+    }
+
+    /// Add a bulk IN video endpoint with the given endpoint number and max
+    /// packet size (64 for full speed, 512 for high speed).
+    pub fn bulk_endpoint(&mut self, num: u8, max_packet_size: u16) -> &mut Self {
+        let descriptor = EndpointBuilder::new()
+            .address_num(num)
+            .direction(Direction::In)
+            .transfer_type(TransferType::Bulk)
+            .sync_type(SynchronizationType::NoSynchronization)
+            .usage_type(UsageType::Data)
+            .max_packet_size(max_packet_size)
+            .build();
+        self.endpoint_descriptor(descriptor)
+    }
+
+    /// Add an isochronous IN video endpoint with the given endpoint number
+    /// and max packet size, in alternate setting 1. The host selects
+    /// alternate setting 1 with SET_INTERFACE to start streaming.
+    pub fn isochronous_endpoint(&mut self, num: u8, max_packet_size: u16) -> &mut Self {
+        let descriptor = EndpointBuilder::new()
+            .address_num(num)
+            .direction(Direction::In)
+            .transfer_type(TransferType::Isochronous)
+            .sync_type(SynchronizationType::Asynchronous)
+            .usage_type(UsageType::Data)
+            .max_packet_size(max_packet_size)
+            .interval(1)
+            .build();
+        self.iface.alternate_setting = true;
+        self.endpoint_descriptor(descriptor)
+    }
+
+    /// Add the given endpoint to the interface
+    pub fn endpoint_descriptor(&mut self, descriptor: EndpointDescriptor) -> &mut Self {
+        self.iface.endpoint_descriptors.push(descriptor);
+        self.iface.iface.b_num_endpoints = self.iface.endpoint_descriptors.len() as u8;
+        self
+    }
+}
+
+impl Default for UvcStreamingInterfaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -4,6 +4,7 @@ use std::{
     path::Path,
 };
 
+use bitflags::bitflags;
 use libudev::{Context, Device};
 use packed_struct::prelude::*;
 
@@ -13,6 +14,8 @@ pub const SYSFS_PATH_MAX: usize = 256;
 pub const SYSFS_BUS_ID_SIZE: usize = 32;
 pub const MAX_STATUS_NAME: usize = 18;
 pub const USBIP_CMD_SIZE: usize = 48;
+pub const USBIP_ISO_PACKET_SIZE: usize = 16;
+pub const USBIP_MAX_ISO_PACKETS: i32 = 1024;
 pub const USBIP_CMD_SUBMIT: u32 = 1;
 pub const USBIP_CMD_UNLINK: u32 = 2;
 pub const USBIP_RET_SUBMIT: u32 = 3;
@@ -20,6 +23,56 @@ pub const USBIP_RET_UNLINK: u32 = 4;
 pub const USBIP_VHCI_BUS_TYPE: &str = "platform";
 pub const USBIP_VHCI_DEVICE_NAME: &str = "vhci_hcd.0";
 
+bitflags! {
+    /// USBIP_URB transfer flags of a CMD_SUBMIT, from
+    /// include/uapi/linux/usbip.h
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+    pub struct TransferFlags: u32 {
+        /// Fail IN transfers that return less data than requested
+        const SHORT_NOT_OK = 0x0000_0001;
+        /// Start isochronous transfers at the next available frame
+        const ISO_ASAP = 0x0000_0002;
+        const NO_TRANSFER_DMA_MAP = 0x0000_0004;
+        /// End OUT transfers of whole packets with a zero length packet
+        const ZERO_PACKET = 0x0000_0040;
+        const NO_INTERRUPT = 0x0000_0080;
+        const FREE_BUFFER = 0x0000_0100;
+        /// The transfer is an IN transfer
+        const DIR_IN = 0x0000_0200;
+        const DMA_MAP_SINGLE = 0x0001_0000;
+        const DMA_MAP_PAGE = 0x0002_0000;
+        const DMA_MAP_SG = 0x0004_0000;
+        const MAP_LOCAL = 0x0008_0000;
+        const SETUP_MAP_SINGLE = 0x0010_0000;
+        const SETUP_MAP_LOCAL = 0x0020_0000;
+        const DMA_SG_COMBINED = 0x0040_0000;
+        const ALIGNED_TEMP_BUFFER = 0x0080_0000;
+    }
+}
+
+/// Status of a RET_SUBMIT reply, as a negative errno value
+#[derive(PrimitiveEnum_i32, Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum UrbStatus {
+    /// The transfer completed successfully
+    #[default]
+    Ok = 0,
+    /// The endpoint stalled (EPIPE)
+    Stall = -32,
+    /// The transfer was unlinked by the host (ECONNRESET)
+    ConnReset = -104,
+    /// The endpoint was shut down (ESHUTDOWN)
+    Shutdown = -108,
+    /// The device sent more data than requested (EOVERFLOW)
+    Overflow = -75,
+    /// The transfer was short while short transfers were not accepted
+    /// (EREMOTEIO)
+    RemoteIo = -121,
+    /// The device is gone (ENODEV)
+    NoDevice = -19,
+    /// The transfer did not complete in time (ETIMEDOUT)
+    Timeout = -110,
+}
+
 /// Request direction. This is always from the perspective of the host (i.e. host computer)
 #[derive(PrimitiveEnum_u32, Debug, Copy, Clone, PartialEq)]
 pub enum UsbIpDirection {
@@ -34,6 +87,7 @@ pub enum HubSpeed {
 }
 
 /// Available USB Speeds
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum USBDeviceSpeed {
     USBSpeedUnknown = 0,   /* enumerating */
     USBSpeedLow = 1,       /* usb 1.1 */
@@ -106,6 +160,13 @@ pub struct USBIPHeaderCmdSubmit {
     pub setup: SetupRequest,
 }
 
+impl USBIPHeaderCmdSubmit {
+    /// Returns the decoded transfer flags. Unknown flags are kept.
+    pub fn flags(&self) -> TransferFlags {
+        TransferFlags::from_bits_retain(self.transfer_flags.to_primitive())
+    }
+}
+
 #[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
 #[packed_struct(bit_numbering = "msb0", size_bytes = "48")]
 pub struct USBIPHeaderRetSubmit {
@@ -150,6 +211,25 @@ pub struct USBIPHeaderRetUnlink {
     pub status: Integer<i32, packed_bits::Bits<32>>,
 }
 
+/// usbip_iso_packet_descriptor, sent after the transfer buffer of
+/// isochronous CMD_SUBMIT and RET_SUBMIT messages, one per packet
+#[derive(PackedStruct, Debug, Copy, Clone, PartialEq, Default)]
+#[packed_struct(bit_numbering = "msb0", size_bytes = "16")]
+pub struct USBIPIsoPacketDescriptor {
+    /// offset: offset of the packet in the transfer buffer
+    #[packed_field(bytes = "0..=3", endian = "msb")]
+    pub offset: Integer<u32, packed_bits::Bits<32>>,
+    /// length: maximum number of bytes of the packet
+    #[packed_field(bytes = "4..=7", endian = "msb")]
+    pub length: Integer<u32, packed_bits::Bits<32>>,
+    /// actual_length: number of bytes transferred, set by the server
+    #[packed_field(bytes = "8..=11", endian = "msb")]
+    pub actual_length: Integer<u32, packed_bits::Bits<32>>,
+    /// status: zero if the packet was transferred, otherwise an error code
+    #[packed_field(bytes = "12..=15", endian = "msb")]
+    pub status: Integer<i32, packed_bits::Bits<32>>,
+}
+
 /// USBIP Header Basic
 #[derive(PackedStruct, Debug, Copy, Clone, PartialEq)]
 #[packed_struct(bit_numbering = "msb0", size_bytes = "20")]
@@ -345,6 +425,20 @@ impl Driver {
         Ok(())
     }
 
+    /// Detach the device attached to the given port. The vhci-hcd driver
+    /// closes its side of the device socket.
+    pub fn detach_device(&mut self, port: u8) -> Result<(), Box<dyn Error>> {
+        let Some(device) = self.hc_device.as_mut() else {
+            return Err("Device driver has not been opened".into());
+        };
+
+        device.set_attribute_value("detach", port.to_string())?;
+        #[cfg(feature = "log")]
+        log::debug!("detached port: {port}");
+
+        Ok(())
+    }
+
     /// Returns a list of all USB ports from the virtual USB hub
     pub fn get_ports(&self) -> Result<Vec<VirtualUsbPort>, Box<dyn Error>> {
         let Some(ref device) = self.hc_device else {
@@ -377,9 +471,9 @@ impl Driver {
 
             let port = match VirtualUsbPort::try_from(line) {
                 Ok(port) => port,
-                Err(e) => {
+                Err(_e) => {
                     #[cfg(feature = "log")]
-                    log::warn!("Failed to parse port from status: {e:?}");
+                    log::warn!("Failed to parse port from status: {_e:?}");
                     continue;
                 }
             };
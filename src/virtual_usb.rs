@@ -1,29 +1,52 @@
+pub mod bulk;
+pub mod scheduler;
+
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
     error::Error,
     io::{Read, Write},
     os::fd::AsFd,
-    sync::mpsc::{channel, Receiver, Sender, TryRecvError},
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Mutex,
+    },
     thread,
+    time::Instant,
 };
 
 use packed_struct::{
     types::{Integer, IntegerAsBytes, SizedInteger},
-    PackedStruct, PackedStructSlice, PrimitiveEnum,
+    PackedStruct, PackedStructSlice, PackingError, PrimitiveEnum,
 };
 use socketpair::{socketpair_stream, SocketpairStream};
 
+pub use self::bulk::{BulkIn, BulkOut};
+use self::scheduler::{InterruptScheduler, IDLE_RATE_UNIT};
+
 use crate::{
     usb::{
-        hid::{HidDescriptorType, HidGetDescriptorRequest},
+        ccid::CcidRequest,
+        cdc::{ncm::NcmRequest, CdcInterface, CdcRequest},
+        dfu::DfuRequest,
+        hid::{
+            HidDescriptorType, HidGetDescriptorRequest, HidProtocol, HidProtocolRequest,
+            HidReportType, HidRequestType, HidSetIdleRequest,
+        },
+        msc::MscRequest,
+        printer::PrinterRequest,
+        ptp::{PtpEvent, StillImageRequest},
+        usbtmc::{self, TmcEvent, TmcProtocol, TmcRequest},
+        uvc::{UvcRequest, VsControl},
         Configuration, DescriptorType, DeviceClass, DeviceDescriptor, DeviceQualifierDescriptor,
-        Interface, LangId, Recipient, SetupRequest, StandardRequest, StringDescriptor,
-        ENDPOINT_MAX_COUNT, SELF_POWERED,
+        Direction, EndpointDescriptor, Interface, LangId, Recipient, SetupRequest, StandardRequest,
+        StringDescriptor, TransferType, Type, ENDPOINT_MAX_COUNT, SELF_POWERED,
     },
     usbip::{
-        Driver, USBDeviceSpeed, USBIPCommandHeader, USBIPHeaderBasic, USBIPHeaderCmdSubmit,
-        USBIPHeaderCmdUnlink, USBIPHeaderInit, USBIPHeaderRetSubmit, USBIPHeaderRetUnlink,
-        USBIPReplyHeader, UsbIpDirection, USBIP_CMD_SIZE, USBIP_CMD_SUBMIT, USBIP_CMD_UNLINK,
-        USBIP_RET_SUBMIT, USBIP_RET_UNLINK,
+        Driver, TransferFlags, USBDeviceSpeed, USBIPCommandHeader, USBIPHeaderBasic,
+        USBIPHeaderCmdSubmit, USBIPHeaderCmdUnlink, USBIPHeaderInit, USBIPHeaderRetSubmit,
+        USBIPHeaderRetUnlink, USBIPIsoPacketDescriptor, USBIPReplyHeader, UrbStatus,
+        UsbIpDirection, USBIP_CMD_SIZE, USBIP_CMD_SUBMIT, USBIP_CMD_UNLINK, USBIP_ISO_PACKET_SIZE,
+        USBIP_MAX_ISO_PACKETS, USBIP_RET_SUBMIT, USBIP_RET_UNLINK,
     },
 };
 
@@ -41,6 +64,8 @@ pub struct Info {
 pub struct Command {
     header: USBIPCommandHeader,
     payload: Vec<u8>,
+    /// Packet descriptors of isochronous transfers
+    iso_packets: Vec<USBIPIsoPacketDescriptor>,
 }
 
 impl Command {
@@ -54,19 +79,44 @@ impl Command {
 pub struct Reply {
     header: USBIPReplyHeader,
     payload: Vec<u8>,
+    /// Packet descriptors of isochronous transfers
+    iso_packets: Vec<USBIPIsoPacketDescriptor>,
 }
 
 impl Reply {
-    /// Create a new reply from the given transfer and data payload
+    /// Create a new reply from the given transfer and data payload. The data
+    /// of isochronous IN transfers is split into packets in order, filling
+    /// each packet up to its length (see [Reply::from_iso_xfer]).
     pub fn from_xfer(xfer: Xfer, data: &[u8]) -> Self {
+        let xfer = match xfer.into_iso() {
+            Ok(xfer) => {
+                let mut rest = data;
+                let packets: Vec<&[u8]> = xfer
+                    .packets()
+                    .iter()
+                    .map(|packet| {
+                        let (packet, tail) = rest.split_at(packet.length.min(rest.len()));
+                        rest = tail;
+                        packet
+                    })
+                    .collect();
+                return Self::from_iso_xfer(xfer, &packets);
+            }
+            Err(xfer) => xfer,
+        };
         let cmd = xfer.cmd;
         let header = cmd.base;
 
-        // Set the payload if this is an IN command (device -> host)
+        // Set the payload if this is an IN command (device -> host), or
+        // acknowledge the data received from the host for OUT commands
         let mut payload = Vec::with_capacity(data.len());
-        if header.direction == UsbIpDirection::In {
-            payload = data.to_vec();
-        }
+        let actual_length = match header.direction {
+            UsbIpDirection::In => {
+                payload = data.to_vec();
+                data.len()
+            }
+            UsbIpDirection::Out => xfer.data.len(),
+        };
 
         Self {
             header: USBIPReplyHeader::RetSubmit(USBIPHeaderRetSubmit {
@@ -78,16 +128,151 @@ impl Reply {
                     ep: header.ep,
                 },
                 status: Integer::from_primitive(0),
-                actual_length: Integer::from_primitive(data.len() as i32),
+                actual_length: Integer::from_primitive(actual_length as i32),
                 start_frame: Integer::from_primitive(0),
                 number_of_packets: Integer::from_primitive(0),
                 error_count: Integer::from_primitive(0),
             }),
             payload,
+            iso_packets: Vec::new(),
+        }
+    }
+
+    /// Create a new reply from the given isochronous transfer and the data
+    /// of each of its packets. Packet data longer than the packet is
+    /// truncated, and missing packets are sent empty. The data is ignored for
+    /// OUT transfers, whose packets are all acknowledged.
+    pub fn from_iso_xfer(xfer: IsoXfer, packets: &[&[u8]]) -> Self {
+        let results: Vec<IsoPacketResult> = packets
+            .iter()
+            .map(|data| IsoPacketResult {
+                data,
+                status: UrbStatus::Ok,
+            })
+            .collect();
+        Self::from_iso_results(xfer, &results)
+    }
+
+    /// Create a new reply from the given isochronous transfer and the result
+    /// of each of its packets. Packets with an error status transfer no data
+    /// and are counted in the error count of the reply.
+    pub fn from_iso_results(xfer: IsoXfer, results: &[IsoPacketResult]) -> Self {
+        let header = xfer.xfer.cmd.base;
+        let mut payload = Vec::new();
+        let mut iso_packets = Vec::with_capacity(xfer.packets().len());
+        let mut actual_length = 0;
+        let mut error_count = 0;
+        for (i, packet) in xfer.packets().iter().enumerate() {
+            let result = results.get(i);
+            let status = result.map(|result| result.status).unwrap_or_default();
+            // The data of IN packets is sent back to back, without the gaps
+            // between packets in the transfer buffer
+            let actual = match (status, header.direction) {
+                (UrbStatus::Ok, UsbIpDirection::In) => {
+                    let data = result.map(|result| result.data).unwrap_or_default();
+                    let data = &data[..data.len().min(packet.length)];
+                    payload.extend_from_slice(data);
+                    data.len()
+                }
+                (UrbStatus::Ok, UsbIpDirection::Out) => packet.length,
+                _ => {
+                    error_count += 1;
+                    0
+                }
+            };
+            actual_length += actual;
+            iso_packets.push(USBIPIsoPacketDescriptor {
+                offset: Integer::from_primitive(packet.offset as u32),
+                length: Integer::from_primitive(packet.length as u32),
+                actual_length: Integer::from_primitive(actual as u32),
+                status: Integer::from_primitive(status.to_primitive()),
+            });
+        }
+
+        Self {
+            header: USBIPReplyHeader::RetSubmit(USBIPHeaderRetSubmit {
+                base: USBIPHeaderBasic {
+                    command: Integer::from_primitive(USBIP_RET_SUBMIT),
+                    seqnum: header.seqnum,
+                    devid: header.devid,
+                    direction: header.direction,
+                    ep: header.ep,
+                },
+                status: Integer::from_primitive(0),
+                actual_length: Integer::from_primitive(actual_length as i32),
+                start_frame: xfer.xfer.cmd.start_frame,
+                number_of_packets: Integer::from_primitive(iso_packets.len() as i32),
+                error_count: Integer::from_primitive(error_count),
+            }),
+            payload,
+            iso_packets,
+        }
+    }
+
+    /// Create a new reply failing the given transfer with the given status,
+    /// without any data
+    pub fn with_status(xfer: Xfer, status: UrbStatus) -> Self {
+        let mut reply = Self::from_xfer(xfer, &[]);
+        reply.set_status(status);
+        if status != UrbStatus::Ok {
+            reply.set_actual_length(0);
+        }
+        reply
+    }
+
+    /// Returns the status of a RET_SUBMIT reply
+    pub fn status(&self) -> Option<UrbStatus> {
+        match self.header {
+            USBIPReplyHeader::RetSubmit(header) => {
+                UrbStatus::from_primitive(header.status.to_primitive())
+            }
+            USBIPReplyHeader::RetUnlink(_) => None,
+        }
+    }
+
+    /// Set the status of a RET_SUBMIT reply
+    fn set_status(&mut self, status: UrbStatus) {
+        if let USBIPReplyHeader::RetSubmit(header) = &mut self.header {
+            header.status = Integer::from_primitive(status.to_primitive());
+        }
+    }
+
+    /// Set the number of bytes transferred of a RET_SUBMIT reply, which is
+    /// the payload length for IN transfers
+    fn set_actual_length(&mut self, len: usize) {
+        if let USBIPReplyHeader::RetSubmit(header) = &mut self.header {
+            header.actual_length = Integer::from_primitive(len as i32);
+        }
+    }
+}
+
+/// Packet of an isochronous transfer
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct IsoPacket {
+    /// Offset of the packet in the transfer buffer
+    pub offset: usize,
+    /// Maximum number of bytes of the packet
+    pub length: usize,
+}
+
+impl From<&USBIPIsoPacketDescriptor> for IsoPacket {
+    fn from(desc: &USBIPIsoPacketDescriptor) -> Self {
+        Self {
+            offset: desc.offset.to_primitive() as usize,
+            length: desc.length.to_primitive() as usize,
         }
     }
 }
 
+/// Result of a packet of an isochronous transfer, see
+/// [Reply::from_iso_results]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct IsoPacketResult<'a> {
+    /// Data of IN packets
+    pub data: &'a [u8],
+    pub status: UrbStatus,
+}
+
 /// USB Transfer
 #[derive(Debug, Clone)]
 pub struct Xfer {
@@ -97,6 +282,8 @@ pub struct Xfer {
     pub data: Vec<u8>,
     /// Setup
     cmd: USBIPHeaderCmdSubmit,
+    /// Packets of isochronous transfers
+    iso_packets: Vec<IsoPacket>,
 }
 
 impl Xfer {
@@ -116,6 +303,82 @@ impl Xfer {
     pub fn direction(&self) -> UsbIpDirection {
         self.cmd.base.direction
     }
+
+    /// Returns the USBIP sequence number of the transfer
+    pub fn seqnum(&self) -> u32 {
+        self.cmd.base.seqnum.to_primitive()
+    }
+
+    /// Returns true if this is an isochronous transfer
+    pub fn is_isochronous(&self) -> bool {
+        !self.iso_packets.is_empty()
+    }
+
+    /// Convert the transfer into an [IsoXfer] if it is an isochronous
+    /// transfer, or return it unchanged otherwise
+    pub fn into_iso(self) -> Result<IsoXfer, Xfer> {
+        if self.is_isochronous() {
+            Ok(IsoXfer { xfer: self })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Returns the size of the transfer buffer. For IN transfers, this is the
+    /// maximum amount of data the host expects to receive.
+    pub fn buffer_length(&self) -> usize {
+        self.cmd.transfer_buffer_length.to_primitive().max(0) as usize
+    }
+
+    /// Returns the transfer flags set by the host
+    pub fn transfer_flags(&self) -> TransferFlags {
+        self.cmd.flags()
+    }
+}
+
+/// Isochronous USB transfer, made of packets transferred at every service
+/// interval of the endpoint. Unlike other OUT transfers, isochronous OUT
+/// transfers are not acknowledged automatically: they must be replied to
+/// with [Reply::from_iso_xfer] once their data has been consumed, which lets
+/// the device pace the host.
+#[derive(Debug, Clone)]
+pub struct IsoXfer {
+    xfer: Xfer,
+}
+
+impl IsoXfer {
+    /// Returns the endpoint number of the transfer
+    pub fn ep(&self) -> u8 {
+        self.xfer.ep
+    }
+
+    /// Returns the direction of the transfer
+    pub fn direction(&self) -> UsbIpDirection {
+        self.xfer.direction()
+    }
+
+    /// Returns the USBIP sequence number of the transfer
+    pub fn seqnum(&self) -> u32 {
+        self.xfer.seqnum()
+    }
+
+    /// Returns the frame number of the first packet requested by the host
+    pub fn start_frame(&self) -> i32 {
+        self.xfer.cmd.start_frame.to_primitive()
+    }
+
+    /// Returns the packets of the transfer
+    pub fn packets(&self) -> &[IsoPacket] {
+        self.xfer.iso_packets.as_slice()
+    }
+
+    /// Returns the data of the packet with the given index of an OUT
+    /// transfer
+    pub fn packet_data(&self, index: usize) -> Option<&[u8]> {
+        let packet = self.packets().get(index)?;
+        let end = (packet.offset + packet.length).min(self.xfer.data.len());
+        self.xfer.data.get(packet.offset.min(end)..end)
+    }
 }
 
 /// Virtual USB Device
@@ -127,31 +390,57 @@ pub struct VirtualUSBDevice {
     pub port: Option<u8>,
     /// The currently active configuration descriptor
     current_config: Option<Configuration>,
+    /// Alternate settings selected by the host with SET_INTERFACE, keyed by
+    /// interface number. Interfaces without an entry use setting 0.
+    alternate_settings: BTreeMap<u8, u8>,
+    /// Protocols selected by the host with SET_PROTOCOL for HID boot
+    /// interfaces, keyed by interface number
+    hid_protocols: BTreeMap<u8, HidProtocol>,
     /// Sender for writing replies to the USBIP unix socket
     replies: Option<Sender<Reply>>,
     /// Receiver for reading commands from the USBIP unix socket
     commands: Option<Receiver<Command>>,
     /// Sender for sending stop signal to reader thread
     stop_sender: Option<Sender<Sender<()>>>,
+    /// Scheduler for completing interrupt IN transfers with the latest report
+    scheduler: InterruptScheduler,
+    /// Streams of bulk IN endpoints, keyed by endpoint number
+    bulk_in: HashMap<u8, BulkIn>,
+    /// Streams of bulk OUT endpoints, keyed by endpoint number
+    bulk_out: HashMap<u8, BulkOut>,
+    /// Sequence numbers of IN transfers returned from read() that have not
+    /// been replied to yet
+    pending_xfers: Mutex<HashSet<u32>>,
+    /// Sequence numbers of pending IN transfers that the host has unlinked
+    unlinked_xfers: Mutex<HashSet<u32>>,
 }
 
 impl VirtualUSBDevice {
     /// Create a new Virtual USB device with the given standard USB descriptors
     pub fn new(info: Info) -> Self {
+        let bcd_usb = info.device_desc.bcd_usb.to_primitive();
+        let speed = VirtualUSBDevice::speed_from_bcd_usb(bcd_usb);
         Self {
             info,
             port: None,
             current_config: None,
+            alternate_settings: BTreeMap::new(),
+            hid_protocols: BTreeMap::new(),
             replies: None,
             commands: None,
             stop_sender: None,
+            scheduler: InterruptScheduler::new(speed),
+            bulk_in: HashMap::new(),
+            bulk_out: HashMap::new(),
+            pending_xfers: Mutex::new(HashSet::new()),
+            unlinked_xfers: Mutex::new(HashSet::new()),
         }
     }
 
     /// Start the VirtualUSBDevice
     pub fn start(&mut self) -> Result<(), Box<dyn Error>> {
         let bcd_usb = self.info.device_desc.bcd_usb.to_primitive();
-        let speed = VirtualUSBDevice::speed_from_bcd_usb(bcd_usb);
+        let speed = VirtualUSBDevice::speed_from_bcd_usb(bcd_usb) as u32;
 
         // Create a unix socket pair. One side is used by the vhci-hcd kernel
         // module, and the other is used by the VirtualUSBDevice.
@@ -174,6 +463,12 @@ impl VirtualUSBDevice {
 
         // Create a set of channels for communicating with the read/write threads
         let (writer_tx, writer_rx) = channel();
+        for bulk in self.bulk_in.values() {
+            bulk.set_replies(writer_tx.clone());
+        }
+        for bulk in self.bulk_out.values() {
+            bulk.set_replies(writer_tx.clone());
+        }
         self.replies = Some(writer_tx);
         let (reader_tx, reader_rx) = channel();
         self.commands = Some(reader_rx);
@@ -204,14 +499,14 @@ impl VirtualUSBDevice {
         // Send a stop signal and wait for the read thread to stop
         if let Some(stop_tx) = self.stop_sender.take() {
             let (tx, rx) = channel();
-            if let Err(e) = stop_tx.send(tx) {
+            if let Err(_e) = stop_tx.send(tx) {
                 #[cfg(feature = "log")]
-                log::debug!("Failed to send stop signal: {e:?}");
+                log::debug!("Failed to send stop signal: {_e:?}");
             } else {
                 thread::spawn(move || {
                     #[cfg(feature = "log")]
                     log::debug!("Waiting for read thread to stop");
-                    if let Err(e) = rx.recv() {
+                    if rx.recv().is_err() {
                         #[cfg(feature = "log")]
                         log::debug!("Failed to get response from read thread");
                     }
@@ -219,6 +514,20 @@ impl VirtualUSBDevice {
             }
         }
 
+        // Detach the device from the virtual USB hub, which closes the socket
+        // of the read/write threads. The device can be started again, and
+        // enumerates as a new device.
+        if let Some(port) = self.port.take() {
+            let mut driver = Driver::new();
+            if let Err(_e) = driver.open().and_then(|_| driver.detach_device(port)) {
+                #[cfg(feature = "log")]
+                log::debug!("Failed to detach port {port}: {_e:?}");
+            }
+        }
+        self.current_config = None;
+        self.alternate_settings.clear();
+        self.hid_protocols.clear();
+
         // Drop the channels to force the read/write threads to stop
         //self.replies = None;
         //self.commands = None;
@@ -242,13 +551,18 @@ impl VirtualUSBDevice {
         };
 
         // Check for any command messages from the read thread.
-        match commands.try_recv() {
+        let result = match commands.try_recv() {
             Ok(cmd) => self.handle_command(&cmd),
             Err(err) => match err {
                 TryRecvError::Empty => Ok(None),
                 TryRecvError::Disconnected => Err("Read thread stopped".to_string().into()),
             },
-        }
+        };
+
+        // Complete any scheduled interrupt transfers that are due
+        self.flush_scheduled()?;
+
+        result
     }
 
     /// Read from the virtual USB device in a blocking way.
@@ -264,156 +578,1381 @@ impl VirtualUSBDevice {
     ///  - setupReq: if ep==0, the Setup packet
     ///  - data: the payload data
     ///  - len: the length of data
+    ///
+    /// While interrupt transfers are pending on endpoints managed by the
+    /// scheduler (see [VirtualUSBDevice::set_report]), this will also return
+    /// Ok(None) once per polling interval so the latest report state can be
+    /// updated.
     pub fn blocking_read(&mut self) -> Result<Option<Xfer>, Box<dyn Error>> {
         let Some(commands) = self.commands.as_ref() else {
             return Err("Device is not started".to_string().into());
         };
 
-        // Check for any command messages from the read thread.
-        match commands.recv() {
-            Ok(cmd) => self.handle_command(&cmd),
-            Err(_) => Err("read thread stopped".to_string().into()),
+        // Check for any command messages from the read thread, waking up in
+        // time to complete any scheduled interrupt transfers.
+        let now = Instant::now();
+        let result = match self.scheduler.next_deadline(now) {
+            Some(deadline) => commands.recv_timeout(deadline.saturating_duration_since(now)),
+            None => commands.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let result = match result {
+            Ok(cmd) => self.handle_command(&cmd),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err("read thread stopped".to_string().into()),
+        };
+
+        // Complete any scheduled interrupt transfers that are due
+        self.flush_scheduled()?;
+
+        result
+    }
+
+    /// To write data to an IN endpoint, call write() with the endpoint, data,
+    /// and length. Replies to transfers that the host has unlinked in the
+    /// meantime are dropped.
+    pub fn write(&self, reply: Reply) -> Result<(), Box<dyn Error>> {
+        let Some(replies) = self.replies.as_ref() else {
+            return Err("Device is not started".to_string().into());
+        };
+        if let USBIPReplyHeader::RetSubmit(header) = reply.header {
+            let seqnum = header.base.seqnum.to_primitive();
+            if let Ok(mut pending) = self.pending_xfers.lock() {
+                pending.remove(&seqnum);
+            }
+            if let Ok(mut unlinked) = self.unlinked_xfers.lock() {
+                if unlinked.remove(&seqnum) {
+                    #[cfg(feature = "log")]
+                    log::debug!("Dropping reply to unlinked transfer {seqnum}");
+                    return Ok(());
+                }
+            }
+        }
+        replies.send(reply)?;
+
+        Ok(())
+    }
+
+    /// Set the latest report for the given interrupt IN endpoint number.
+    /// After a report has been set, IN transfers to the endpoint are no
+    /// longer returned from read(). Instead they are completed automatically
+    /// at the polling interval (bInterval) declared by the endpoint
+    /// descriptor, and only when the report has changed or the idle rate
+    /// set by the host with SET_IDLE has expired.
+    ///
+    /// The report data should include the report ID byte if the interface
+    /// uses report IDs.
+    pub fn set_report(&mut self, ep: u8, report_id: u8, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.schedule_endpoint(ep)?;
+        self.scheduler.set_report(ep, report_id, data);
+
+        // Send the report right away if a transfer is waiting for it
+        if self.replies.is_some() {
+            self.flush_scheduled()?;
+        }
+
+        Ok(())
+    }
+
+    /// Queue a report to be sent once on the given interrupt IN endpoint
+    /// number, even if it is identical to the previous one. Unlike
+    /// [VirtualUSBDevice::set_report], every queued report reaches the host,
+    /// which is needed for relative data like mouse motion.
+    pub fn queue_report(&mut self, ep: u8, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.schedule_endpoint(ep)?;
+        self.scheduler.queue_report(ep, data);
+        if self.replies.is_some() {
+            self.flush_scheduled()?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a SERIAL_STATE notification with the given UART state bitmap
+    /// (see [crate::usb::cdc::serial_state]) on the notification endpoint of
+    /// the CDC interface with the given interface number.
+    pub fn send_serial_state(&mut self, iface: u8, state: u16) -> Result<(), Box<dyn Error>> {
+        self.send_notification(iface, |cdc| cdc.serial_state_notification(state))
+    }
+
+    /// Send a NETWORK_CONNECTION notification with the given link state on
+    /// the notification endpoint of the CDC-ECM or CDC-NCM interface with the
+    /// given interface number. Hosts keep the link down until the device
+    /// reports that it is connected.
+    pub fn send_network_connection(
+        &mut self,
+        iface: u8,
+        connected: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        self.send_notification(iface, |cdc| cdc.network_connection_notification(connected))
+    }
+
+    /// Send a CONNECTION_SPEED_CHANGE notification with the given downlink
+    /// and uplink bit rates on the notification endpoint of the CDC interface
+    /// with the given interface number.
+    pub fn send_connection_speed_change(
+        &mut self,
+        iface: u8,
+        downlink: u32,
+        uplink: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        self.send_notification(iface, |cdc| {
+            cdc.connection_speed_change_notification(downlink, uplink)
+        })
+    }
+
+    /// Queue the notification built by the given function on the
+    /// notification endpoint of the CDC interface with the given number.
+    fn send_notification<F>(&mut self, iface: u8, build: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnOnce(&CdcInterface) -> Result<Vec<u8>, PackingError>,
+    {
+        let Some(config) = self.current_config.as_ref() else {
+            return Err("No active configuration".into());
+        };
+        let Some(Interface::Cdc(cdc)) = config.interfaces.get(iface as usize) else {
+            return Err(format!("No CDC interface exists with number {iface}").into());
+        };
+        let Some(ep) = cdc.notification_endpoint() else {
+            return Err(format!("CDC interface {iface} has no notification endpoint").into());
+        };
+        let notification = build(cdc)?;
+        self.queue_report(ep, &notification)
+    }
+
+    /// Returns a stream writing data to the host over the bulk IN endpoint
+    /// with the given number. IN transfers to the endpoint are no longer
+    /// returned from read(), and are answered with the data written to the
+    /// stream instead (see [BulkIn]).
+    pub fn bulk_in(&mut self, ep: u8) -> Result<BulkIn, Box<dyn Error>> {
+        if let Some(bulk) = self.bulk_in.get(&ep) {
+            return Ok(bulk.clone());
+        }
+        let max_packet_size = self.bulk_max_packet_size(ep, Direction::In)?;
+        let bulk = BulkIn::new(ep, max_packet_size);
+        if let Some(replies) = self.replies.as_ref() {
+            bulk.set_replies(replies.clone());
+        }
+        self.bulk_in.insert(ep, bulk.clone());
+        Ok(bulk)
+    }
+
+    /// Returns a stream reading data from the host over the bulk OUT
+    /// endpoint with the given number. OUT transfers to the endpoint are no
+    /// longer returned from read(), and their data is read from the stream
+    /// instead (see [BulkOut]).
+    pub fn bulk_out(&mut self, ep: u8) -> Result<BulkOut, Box<dyn Error>> {
+        if let Some(bulk) = self.bulk_out.get(&ep) {
+            return Ok(bulk.clone());
+        }
+        let max_packet_size = self.bulk_max_packet_size(ep, Direction::Out)?;
+        let bulk = BulkOut::new(ep, max_packet_size);
+        if let Some(replies) = self.replies.as_ref() {
+            bulk.set_replies(replies.clone());
+        }
+        self.bulk_out.insert(ep, bulk.clone());
+        Ok(bulk)
+    }
+
+    /// Returns the max packet size of the bulk endpoint with the given
+    /// number and direction
+    fn bulk_max_packet_size(&self, ep: u8, direction: Direction) -> Result<u16, Box<dyn Error>> {
+        let Some(endpoint) = self.find_endpoint(ep, direction) else {
+            return Err(format!("No {direction:?} endpoint exists with number {ep}").into());
+        };
+        if endpoint.transfer_type() != TransferType::Bulk {
+            return Err(format!("Endpoint {ep} is not a bulk endpoint").into());
+        }
+        Ok(endpoint.max_packet_size())
+    }
+
+    /// Returns the alternate setting selected by the host for the interface
+    /// with the given interface number
+    pub fn alternate_setting(&self, iface: u8) -> u8 {
+        self.alternate_settings
+            .get(&iface)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the protocol selected by the host for the HID boot interface
+    /// with the given interface number. Boot interfaces use the report
+    /// protocol until the host selects the boot protocol.
+    pub fn hid_protocol(&self, iface: u8) -> HidProtocol {
+        self.hid_protocols.get(&iface).copied().unwrap_or_default()
+    }
+
+    /// Returns true if the host has unlinked the IN transfer with the given
+    /// sequence number (see [Xfer::seqnum]) before it was replied to. Replies
+    /// to such transfers are dropped by [VirtualUSBDevice::write].
+    pub fn is_unlinked(&self, seqnum: u32) -> bool {
+        self.unlinked_xfers
+            .lock()
+            .map(|unlinked| unlinked.contains(&seqnum))
+            .unwrap_or_default()
+    }
+
+    /// Start scheduling the given interrupt IN endpoint number if it is not
+    /// already scheduled.
+    fn schedule_endpoint(&mut self, ep: u8) -> Result<(), Box<dyn Error>> {
+        if self.scheduler.is_scheduled(ep) {
+            return Ok(());
+        }
+        let Some(endpoint) = self.find_endpoint(ep, Direction::In) else {
+            return Err(format!("No IN endpoint exists with number {ep}").into());
+        };
+        if endpoint.transfer_type() != TransferType::Interrupt {
+            return Err(format!("Endpoint {ep} is not an interrupt endpoint").into());
+        }
+        self.scheduler.add_endpoint(ep, endpoint.b_interval);
+
+        Ok(())
+    }
+
+    /// Returns the descriptor of the endpoint with the given number and
+    /// direction.
+    fn find_endpoint(&self, ep: u8, direction: Direction) -> Option<EndpointDescriptor> {
+        let configs = self.current_config.iter().chain(self.info.configs.iter());
+        configs
+            .flat_map(|config| config.interfaces.iter())
+            .flat_map(|iface| iface.get_endpoints().iter())
+            .find(|desc| desc.number() == ep && desc.direction() == direction)
+            .copied()
+    }
+
+    /// Returns the interface of the current configuration that owns the
+    /// endpoint with the given number and direction.
+    fn find_interface(&self, ep: u8, direction: Direction) -> Option<&Interface> {
+        let config = self.current_config.as_ref()?;
+        config.interfaces.iter().find(|iface| {
+            iface
+                .get_endpoints()
+                .iter()
+                .any(|desc| desc.number() == ep && desc.direction() == direction)
+        })
+    }
+
+    /// Send replies for all scheduled interrupt transfers that are due
+    fn flush_scheduled(&mut self) -> Result<(), Box<dyn Error>> {
+        for reply in self.scheduler.poll(Instant::now()) {
+            self.write(reply)?;
+        }
+        Ok(())
+    }
+
+    /// Handle the given USB command. Standard USB transfers are automatically
+    /// handled. If it is not possible to handle, an [Xfer] will be returned
+    /// so it can be handled at another layer.
+    fn handle_command(&mut self, cmd: &Command) -> Result<Option<Xfer>, Box<dyn Error>> {
+        match cmd.header {
+            USBIPCommandHeader::CmdSubmit(header) => {
+                if header.base.ep.to_primitive() == 0 {
+                    self.handle_command_submit_ep0(cmd)
+                } else {
+                    self.handle_command_submit_epX(cmd)
+                }
+            }
+            USBIPCommandHeader::CmdUnlink(_) => {
+                self.handle_command_unlink(cmd)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Handle command submit to endpoint 0. Endpoint 0 (zero), the default
+    /// endpoint, is always assumed to be a control endpoint and never has a
+    /// descriptor.
+    fn handle_command_submit_ep0(&mut self, cmd: &Command) -> Result<Option<Xfer>, Box<dyn Error>> {
+        #[cfg(feature = "log")]
+        log::debug!("handle submit ep0");
+        let USBIPCommandHeader::CmdSubmit(header) = cmd.header else {
+            return Err("Invalid header for submit command".into());
+        };
+        let standard_type = header.setup.is_standard();
+
+        // Handle standard requests automatically
+        if standard_type {
+            self.handle_command_submit_ep0_standard_request(cmd, header.setup)?;
+            return Ok(None);
+        }
+
+        // Handle idle rate requests for HID interfaces with scheduled endpoints
+        if self.handle_command_submit_ep0_hid_idle(cmd, header.setup)? {
+            return Ok(None);
+        }
+
+        // Handle report requests for HID interfaces with registered handlers
+        if self.handle_command_submit_ep0_hid_report(cmd, header.setup)? {
+            return Ok(None);
+        }
+
+        // Handle protocol requests for HID boot interfaces
+        if self.handle_command_submit_ep0_hid_protocol(cmd, header.setup)? {
+            return Ok(None);
+        }
+
+        // Handle class requests for CDC interfaces
+        if self.handle_command_submit_ep0_cdc(cmd, header.setup)? {
+            return Ok(None);
+        }
+
+        // Handle Bulk-Only Transport requests for mass storage interfaces
+        if self.handle_command_submit_ep0_msc(cmd, header.setup)? {
+            return Ok(None);
+        }
+
+        // Handle class requests for printer interfaces
+        if self.handle_command_submit_ep0_printer(cmd, header.setup)? {
+            return Ok(None);
+        }
+
+        // Handle class requests for smart card reader interfaces
+        if self.handle_command_submit_ep0_ccid(cmd, header.setup)? {
+            return Ok(None);
+        }
+
+        // Handle the requests of the DFU state machine
+        if self.handle_command_submit_ep0_dfu(cmd, header.setup)? {
+            return Ok(None);
+        }
+
+        // Handle class requests for test and measurement interfaces
+        if self.handle_command_submit_ep0_usbtmc(cmd, header.setup)? {
+            return Ok(None);
+        }
+
+        // Handle class requests for still image interfaces
+        if self.handle_command_submit_ep0_ptp(cmd, header.setup)? {
+            return Ok(None);
+        }
+
+        // Handle probe and commit requests for video streaming interfaces
+        if self.handle_command_submit_ep0_uvc(cmd, header.setup)? {
+            return Ok(None);
+        }
+
+        // Handle control requests for audio entities and endpoints
+        if self.handle_command_submit_ep0_uac(cmd, header.setup)? {
+            return Ok(None);
+        }
+
+        // Otherwise, handle as a regular endpoint command
+        if let Some(mut xfer) = self.handle_command_submit_epX(cmd)? {
+            // Populate the setupReq member, since it's always expected for ep==0
+            xfer.cmd = header;
+            return Ok(Some(xfer));
+        }
+
+        Ok(None)
+    }
+
+    /// Handle command submit to any other USB endpoint.
+    #[allow(non_snake_case)]
+    fn handle_command_submit_epX(&mut self, cmd: &Command) -> Result<Option<Xfer>, Box<dyn Error>> {
+        #[cfg(feature = "log")]
+        log::debug!("handle submit epX");
+        let USBIPCommandHeader::CmdSubmit(header) = cmd.header else {
+            return Err("Invalid header for submit command".into());
+        };
+        match header.base.direction {
+            // OUT command (data from host->device)
+            UsbIpDirection::Out => self.handle_command_submit_epX_out(cmd),
+            // IN command (data from device->host)
+            UsbIpDirection::In => self.handle_command_submit_epX_in(cmd),
+        }
+    }
+
+    /// Handle command submit OUT to any other USB endpoint.
+    #[allow(non_snake_case)]
+    fn handle_command_submit_epX_out(&self, cmd: &Command) -> Result<Option<Xfer>, Box<dyn Error>> {
+        #[cfg(feature = "log")]
+        log::debug!("handle submit epX OUT");
+        let USBIPCommandHeader::CmdSubmit(header) = cmd.header else {
+            return Err("Invalid header for submit command".into());
+        };
+        let ep_idx = header.base.ep.to_primitive();
+        #[cfg(feature = "log")]
+        log::debug!("handle submit epX OUT {ep_idx}");
+        if ep_idx >= ENDPOINT_MAX_COUNT as u32 {
+            return Err("Invalid endpoint index".into());
+        }
+
+        // Isochronous transfers are replied to by user code, see [IsoXfer]
+        if !cmd.iso_packets.is_empty() {
+            let xfer = Xfer {
+                ep: ep_idx as u8,
+                data: cmd.payload.clone(),
+                cmd: header,
+                iso_packets: cmd.iso_packets.iter().map(IsoPacket::from).collect(),
+            };
+            if let Ok(mut pending) = self.pending_xfers.lock() {
+                pending.insert(xfer.seqnum());
+            }
+            return Ok(Some(xfer));
+        }
+
+        // Bulk streams acknowledge transfers once there is room for the data
+        if let Some(bulk) = self.bulk_out.get(&(ep_idx as u8)) {
+            let xfer = Xfer {
+                ep: ep_idx as u8,
+                data: cmd.payload.clone(),
+                cmd: header,
+                iso_packets: Vec::new(),
+            };
+            bulk.submit(xfer)?;
+            return Ok(None);
+        }
+
+        // Let host know that we received the data
+        self.reply(cmd, &[], UrbStatus::Ok)?;
+
+        // Route output reports to the handlers of the owning HID interface
+        let owner = self.find_interface(ep_idx as u8, Direction::Out);
+        if let Some(Interface::Hid(iface)) = owner {
+            if iface.handle_output_report(&cmd.payload) {
+                return Ok(None);
+            }
+        }
+
+        let xfer = Xfer {
+            // TODO: Double check this
+            ep: ep_idx as u8,
+            // TODO: Can we move?
+            data: cmd.payload.clone(),
+            cmd: header,
+            iso_packets: Vec::new(),
+        };
+
+        Ok(Some(xfer))
+    }
+
+    /// Handle command submit IN to any other USB endpoint.
+    #[allow(non_snake_case)]
+    fn handle_command_submit_epX_in(
+        &mut self,
+        cmd: &Command,
+    ) -> Result<Option<Xfer>, Box<dyn Error>> {
+        #[cfg(feature = "log")]
+        log::debug!("handle submit epX IN");
+        let USBIPCommandHeader::CmdSubmit(header) = cmd.header else {
+            return Err("Invalid header for submit command".into());
+        };
+        let ep_idx = header.base.ep.to_primitive();
+        #[cfg(feature = "log")]
+        log::debug!("handle submit epX IN {ep_idx}");
+        if ep_idx >= ENDPOINT_MAX_COUNT as u32 {
+            return Err("Invalid endpoint index".into());
+        }
+
+        // Notifications of CDC and CCID interfaces are only sent by the
+        // device itself (see [VirtualUSBDevice::send_serial_state]), so their
+        // transfers always wait on the interrupt scheduler.
+        let owner = self.find_interface(ep_idx as u8, Direction::In);
+        let notification_ep = match owner {
+            Some(Interface::Cdc(iface)) => iface.notification_endpoint(),
+            Some(Interface::SmartCard(iface)) => iface.interrupt_endpoint(),
+            _ => None,
+        };
+        if notification_ep == Some(ep_idx as u8) {
+            self.schedule_endpoint(ep_idx as u8)?;
+        }
+
+        // This is an IN transfer that must be handled by user code, unless
+        // the endpoint is managed by the interrupt scheduler.
+        let xfer = Xfer {
+            ep: ep_idx as u8,
+            data: cmd.payload.clone(),
+            cmd: header,
+            iso_packets: cmd.iso_packets.iter().map(IsoPacket::from).collect(),
+        };
+
+        // Bulk streams answer transfers once data was written to them
+        if let Some(bulk) = self.bulk_in.get(&xfer.ep) {
+            bulk.submit(xfer)?;
+            return Ok(None);
+        }
+
+        let xfer = self.scheduler.submit(xfer);
+        if let Some(xfer) = xfer.as_ref() {
+            if let Ok(mut pending) = self.pending_xfers.lock() {
+                pending.insert(xfer.seqnum());
+            }
+        }
+
+        Ok(xfer)
+    }
+
+    /// Handle unlinking
+    fn handle_command_unlink(&mut self, cmd: &Command) -> Result<(), Box<dyn Error>> {
+        #[cfg(feature = "log")]
+        log::debug!("handle unlink");
+        let USBIPCommandHeader::CmdUnlink(unlink) = cmd.header else {
+            return Err("Invalid header for unlink command".into());
+        };
+
+        // Drop the transfer if it is still waiting on the scheduler
+        let seqnum = unlink.seqnum.to_primitive();
+        if self.scheduler.unlink(seqnum) {
+            #[cfg(feature = "log")]
+            log::debug!("Unlinked scheduled transfer {seqnum}");
+        }
+
+        // Drop the transfer if it is still waiting on a bulk stream
+        let bulk_in = self.bulk_in.values().any(|bulk| bulk.unlink(seqnum));
+        let bulk_out = self.bulk_out.values().any(|bulk| bulk.unlink(seqnum));
+        if bulk_in || bulk_out {
+            #[cfg(feature = "log")]
+            log::debug!("Unlinked bulk transfer {seqnum}");
+        }
+
+        // Remember transfers still held by user code so their replies can
+        // be dropped. Replying to an unlinked transfer is a protocol error.
+        let pending = self
+            .pending_xfers
+            .lock()
+            .map(|mut pending| pending.remove(&seqnum))
+            .unwrap_or_default();
+        if pending {
+            if let Ok(mut unlinked) = self.unlinked_xfers.lock() {
+                unlinked.insert(seqnum);
+            }
+        }
+
+        self.reply(cmd, &[], UrbStatus::ConnReset)
+    }
+
+    /// Handle HID SET_IDLE and GET_IDLE requests for interfaces whose
+    /// interrupt IN endpoints are managed by the scheduler. Returns true if
+    /// the request was handled.
+    fn handle_command_submit_ep0_hid_idle(
+        &mut self,
+        cmd: &Command,
+        req: SetupRequest,
+    ) -> Result<bool, Box<dyn Error>> {
+        if req.request_type() != Type::Class || req.recipient() != Recipient::Interface {
+            return Ok(false);
+        }
+        let request_type = HidRequestType::from(req.b_request);
+        if !matches!(
+            request_type,
+            HidRequestType::SetIdle | HidRequestType::GetIdle
+        ) {
+            return Ok(false);
+        }
+
+        // Find the scheduled interrupt IN endpoints of the HID interface
+        let Some(config) = self.current_config.as_ref() else {
+            return Ok(false);
+        };
+        let iface_idx = (req.index() & 0x00FF) as usize;
+        let Some(Interface::Hid(iface)) = config.interfaces.get(iface_idx) else {
+            return Ok(false);
+        };
+        let endpoints: Vec<u8> = iface
+            .get_endpoints()
+            .iter()
+            .filter(|desc| desc.direction() == Direction::In)
+            .map(|desc| desc.number())
+            .filter(|ep| self.scheduler.is_scheduled(*ep))
+            .collect();
+        if endpoints.is_empty() {
+            return Ok(false);
+        }
+
+        let idle_req = HidSetIdleRequest::from(req);
+        let report_id = idle_req.report_id;
+        match request_type {
+            HidRequestType::SetIdle => {
+                // A duration of 0 means the report is only sent on change
+                let idle = match idle_req.duration {
+                    0 => None,
+                    duration => Some(IDLE_RATE_UNIT * duration as u32),
+                };
+                #[cfg(feature = "log")]
+                log::debug!("SetIdle for report {report_id}: {idle:?}");
+                for ep in endpoints {
+                    self.scheduler.set_idle(ep, report_id, idle);
+                }
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+            }
+            _ => {
+                let idle = self.scheduler.idle(endpoints[0], report_id);
+                let duration = idle
+                    .map(|idle| idle.as_millis() / IDLE_RATE_UNIT.as_millis())
+                    .unwrap_or(0);
+                self.reply(cmd, &[duration.min(255) as u8], UrbStatus::Ok)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Handle HID GET_REPORT and SET_REPORT requests for interfaces with a
+    /// registered handler for the requested report type and ID. Returns true
+    /// if the request was handled.
+    fn handle_command_submit_ep0_hid_report(
+        &self,
+        cmd: &Command,
+        req: SetupRequest,
+    ) -> Result<bool, Box<dyn Error>> {
+        if req.request_type() != Type::Class || req.recipient() != Recipient::Interface {
+            return Ok(false);
+        }
+        let request_type = HidRequestType::from(req.b_request);
+        if !matches!(
+            request_type,
+            HidRequestType::GetReport | HidRequestType::SetReport
+        ) {
+            return Ok(false);
+        }
+
+        let Some(config) = self.current_config.as_ref() else {
+            return Ok(false);
+        };
+        let iface_idx = (req.index() & 0x00FF) as usize;
+        let Some(Interface::Hid(iface)) = config.interfaces.get(iface_idx) else {
+            return Ok(false);
+        };
+
+        // wValue holds the report type in the high byte and ID in the low byte
+        let value = req.value();
+        let report_id = (value & 0x00FF) as u8;
+        let Some(report_type) = HidReportType::from_primitive((value >> 8) as u8) else {
+            return Ok(false);
+        };
+
+        match request_type {
+            HidRequestType::SetReport => {
+                if !iface.handle_set_report(report_type, report_id, &cmd.payload) {
+                    return Ok(false);
+                }
+                #[cfg(feature = "log")]
+                log::debug!("SetReport {report_type:?} {report_id} on interface {iface_idx}");
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+            }
+            _ => {
+                let Some(mut data) = iface.handle_get_report(report_type, report_id) else {
+                    return Ok(false);
+                };
+                #[cfg(feature = "log")]
+                log::debug!("GetReport {report_type:?} {report_id} on interface {iface_idx}");
+                data.truncate(req.length() as usize);
+                self.reply(cmd, &data, UrbStatus::Ok)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Handle HID GET_PROTOCOL and SET_PROTOCOL requests for boot
+    /// interfaces. Returns true if the request was handled.
+    fn handle_command_submit_ep0_hid_protocol(
+        &mut self,
+        cmd: &Command,
+        req: SetupRequest,
+    ) -> Result<bool, Box<dyn Error>> {
+        if req.request_type() != Type::Class || req.recipient() != Recipient::Interface {
+            return Ok(false);
+        }
+        let request_type = HidRequestType::from(req.b_request);
+        if !matches!(
+            request_type,
+            HidRequestType::GetProtocol | HidRequestType::SetProtocol
+        ) {
+            return Ok(false);
+        }
+
+        let Some(config) = self.current_config.as_ref() else {
+            return Ok(false);
+        };
+        let iface_idx = (req.index() & 0x00FF) as u8;
+        let Some(Interface::Hid(iface)) = config.interfaces.get(iface_idx as usize) else {
+            return Ok(false);
+        };
+        if !iface.is_boot_interface() {
+            return Ok(false);
+        }
+
+        match request_type {
+            HidRequestType::SetProtocol => {
+                let protocol_req = HidProtocolRequest::from(req);
+                let value = protocol_req.protocol.to_primitive();
+                let Some(protocol) = u8::try_from(value)
+                    .ok()
+                    .and_then(HidProtocol::from_primitive)
+                else {
+                    #[cfg(feature = "log")]
+                    log::debug!("Stall invalid protocol {value} on interface {iface_idx}");
+                    self.reply(cmd, &[], UrbStatus::Stall)?;
+                    return Ok(true);
+                };
+                #[cfg(feature = "log")]
+                log::debug!("SetProtocol {protocol:?} on interface {iface_idx}");
+                self.hid_protocols.insert(iface_idx, protocol);
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+            }
+            _ => {
+                let protocol = self.hid_protocol(iface_idx);
+                self.reply(cmd, &[protocol.to_primitive()], UrbStatus::Ok)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Handle CDC class requests: SET_LINE_CODING, GET_LINE_CODING,
+    /// SET_CONTROL_LINE_STATE and SEND_BREAK for ACM interfaces, and the
+    /// packet filter and NCM requests for networking interfaces. Returns true
+    /// if the request was handled.
+    fn handle_command_submit_ep0_cdc(
+        &mut self,
+        cmd: &Command,
+        req: SetupRequest,
+    ) -> Result<bool, Box<dyn Error>> {
+        if req.request_type() != Type::Class || req.recipient() != Recipient::Interface {
+            return Ok(false);
+        }
+
+        let Some(config) = self.current_config.as_ref() else {
+            return Ok(false);
+        };
+        let iface_idx = (req.index() & 0x00FF) as usize;
+        let Some(Interface::Cdc(iface)) = config.interfaces.get(iface_idx) else {
+            return Ok(false);
+        };
+
+        if let Some(request) = NcmRequest::from_primitive(req.request()) {
+            return self.handle_command_submit_ep0_ncm(cmd, req, iface, request);
+        }
+        let Some(request) = CdcRequest::from_primitive(req.request()) else {
+            return Ok(false);
+        };
+
+        match request {
+            CdcRequest::SetLineCoding => {
+                if !iface.handle_set_line_coding(&cmd.payload) {
+                    #[cfg(feature = "log")]
+                    log::debug!("Stall invalid line coding on interface {iface_idx}");
+                    self.reply(cmd, &[], UrbStatus::Stall)?;
+                    return Ok(true);
+                }
+                #[cfg(feature = "log")]
+                log::debug!(
+                    "SetLineCoding {:?} on interface {iface_idx}",
+                    iface.line_coding()
+                );
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+            }
+            CdcRequest::GetLineCoding => {
+                let mut data = iface.line_coding().pack_to_vec()?;
+                data.truncate(req.length() as usize);
+                self.reply(cmd, &data, UrbStatus::Ok)?;
+            }
+            CdcRequest::SetControlLineState => {
+                #[cfg(feature = "log")]
+                log::debug!(
+                    "SetControlLineState {:#x} on interface {iface_idx}",
+                    req.value()
+                );
+                iface.handle_set_control_line_state(req.value());
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+            }
+            CdcRequest::SendBreak => {
+                #[cfg(feature = "log")]
+                log::debug!("SendBreak {} on interface {iface_idx}", req.value());
+                iface.handle_send_break(req.value());
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+            }
+            CdcRequest::SetEthernetPacketFilter => {
+                #[cfg(feature = "log")]
+                log::debug!("SetEthernetPacketFilter {:#x} on {iface_idx}", req.value());
+                iface.handle_set_packet_filter(req.value());
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+            }
+            CdcRequest::SetEthernetMulticastFilters => {
+                // Multicast frames are not filtered by address
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+            }
+            CdcRequest::SendEncapsulatedCommand if iface.rndis.is_some() => {
+                let notification = match iface.handle_encapsulated_command(&cmd.payload) {
+                    Ok(notification) => notification,
+                    Err(_e) => {
+                        #[cfg(feature = "log")]
+                        log::debug!("Stall invalid RNDIS message on interface {iface_idx}: {_e}");
+                        self.reply(cmd, &[], UrbStatus::Stall)?;
+                        return Ok(true);
+                    }
+                };
+                let ep = iface.notification_endpoint();
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+
+                // Tell the host to fetch the response with GET_ENCAPSULATED_RESPONSE
+                if let (Some(ep), Some(notification)) = (ep, notification) {
+                    self.queue_report(ep, &notification)?;
+                }
+            }
+            CdcRequest::GetEncapsulatedResponse => {
+                // Without a pending response a single zero byte is returned
+                let mut data = iface.encapsulated_response().unwrap_or_else(|| vec![0]);
+                data.truncate(req.length() as usize);
+                self.reply(cmd, &data, UrbStatus::Ok)?;
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    /// Handle GET_MAX_LUN and BULK_ONLY_RESET for mass storage interfaces.
+    /// Returns true if the request was handled.
+    fn handle_command_submit_ep0_msc(
+        &self,
+        cmd: &Command,
+        req: SetupRequest,
+    ) -> Result<bool, Box<dyn Error>> {
+        if req.request_type() != Type::Class || req.recipient() != Recipient::Interface {
+            return Ok(false);
+        }
+
+        let Some(config) = self.current_config.as_ref() else {
+            return Ok(false);
+        };
+        let iface_idx = (req.index() & 0x00FF) as usize;
+        let Some(Interface::MassStorage(iface)) = config.interfaces.get(iface_idx) else {
+            return Ok(false);
+        };
+        let Some(request) = MscRequest::from_primitive(req.request()) else {
+            return Ok(false);
+        };
+
+        match request {
+            MscRequest::GetMaxLun => {
+                let mut data = vec![iface.max_lun];
+                data.truncate(req.length() as usize);
+                self.reply(cmd, &data, UrbStatus::Ok)?;
+            }
+            MscRequest::BulkOnlyReset => {
+                #[cfg(feature = "log")]
+                log::debug!("BulkOnlyReset on interface {iface_idx}");
+                iface.handle_bulk_only_reset();
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Handle ABORT, GET_CLOCK_FREQUENCIES and GET_DATA_RATES for smart card
+    /// reader interfaces. Returns true if the request was handled.
+    fn handle_command_submit_ep0_ccid(
+        &self,
+        cmd: &Command,
+        req: SetupRequest,
+    ) -> Result<bool, Box<dyn Error>> {
+        if req.request_type() != Type::Class || req.recipient() != Recipient::Interface {
+            return Ok(false);
+        }
+
+        let Some(config) = self.current_config.as_ref() else {
+            return Ok(false);
+        };
+        let iface_idx = (req.index() & 0x00FF) as usize;
+        let Some(Interface::SmartCard(iface)) = config.interfaces.get(iface_idx) else {
+            return Ok(false);
+        };
+        let Some(request) = CcidRequest::from_primitive(req.request()) else {
+            return Ok(false);
+        };
+
+        match request {
+            CcidRequest::Abort => {
+                // Commands are processed as soon as they are received, so
+                // there is never one to abort
+                #[cfg(feature = "log")]
+                log::debug!("Abort on interface {iface_idx}: {:#06x}", req.value());
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+            }
+            CcidRequest::GetClockFrequencies => {
+                let mut data = iface.clock_frequencies();
+                data.truncate(req.length() as usize);
+                self.reply(cmd, &data, UrbStatus::Ok)?;
+            }
+            CcidRequest::GetDataRates => {
+                let mut data = iface.data_rates();
+                data.truncate(req.length() as usize);
+                self.reply(cmd, &data, UrbStatus::Ok)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Handle the class requests of DFU interfaces. Requests that are not
+    /// valid in the current DFU state are stalled. Returns true if the
+    /// request was handled.
+    fn handle_command_submit_ep0_dfu(
+        &self,
+        cmd: &Command,
+        req: SetupRequest,
+    ) -> Result<bool, Box<dyn Error>> {
+        if req.request_type() != Type::Class || req.recipient() != Recipient::Interface {
+            return Ok(false);
+        }
+
+        let Some(config) = self.current_config.as_ref() else {
+            return Ok(false);
+        };
+        let iface_idx = (req.index() & 0x00FF) as usize;
+        let Some(Interface::Dfu(iface)) = config.interfaces.get(iface_idx) else {
+            return Ok(false);
+        };
+        let Some(request) = DfuRequest::from_primitive(req.request()) else {
+            return Ok(false);
+        };
+
+        match iface.handle_request(request, req.value(), &cmd.payload, req.length()) {
+            Some(mut data) => {
+                data.truncate(req.length() as usize);
+                self.reply(cmd, &data, UrbStatus::Ok)?;
+            }
+            None => {
+                #[cfg(feature = "log")]
+                log::debug!(
+                    "Stall DFU request {request:?} in state {:?}",
+                    iface.machine.state()
+                );
+                self.reply(cmd, &[], UrbStatus::Stall)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Handle the USBTMC and USB488 class requests of test and measurement
+    /// interfaces, addressed to the interface or to its bulk endpoints.
+    /// Messages are processed as soon as they are received, so aborts and
+    /// clears complete right away. Returns true if the request was handled.
+    fn handle_command_submit_ep0_usbtmc(
+        &mut self,
+        cmd: &Command,
+        req: SetupRequest,
+    ) -> Result<bool, Box<dyn Error>> {
+        if req.request_type() != Type::Class {
+            return Ok(false);
+        }
+        let Some(request) = TmcRequest::from_primitive(req.request()) else {
+            return Ok(false);
+        };
+
+        let iface = match req.recipient() {
+            Recipient::Interface => {
+                let Some(config) = self.current_config.as_ref() else {
+                    return Ok(false);
+                };
+                config.interfaces.get((req.index() & 0x00FF) as usize)
+            }
+            Recipient::Endpoint => {
+                // wIndex holds the endpoint address
+                let ep = (req.index() & 0x0F) as u8;
+                let direction = if req.index() & 0x80 != 0 {
+                    Direction::In
+                } else {
+                    Direction::Out
+                };
+                self.find_interface(ep, direction)
+            }
+            _ => return Ok(false),
+        };
+        let Some(Interface::TestMeasurement(iface)) = iface else {
+            return Ok(false);
+        };
+        let iface = iface.clone();
+
+        // USB488 requests are only valid on USB488 interfaces
+        let usb488 = iface.protocol() == TmcProtocol::Usb488;
+        let ren_control = iface.usb488_interface_capabilities & usbtmc::capabilities::REN_CONTROL;
+        let valid = match request {
+            TmcRequest::ReadStatusByte => usb488,
+            TmcRequest::RenControl | TmcRequest::GoToLocal | TmcRequest::LocalLockout => {
+                usb488 && ren_control != 0
+            }
+            _ => true,
+        };
+        if !valid {
+            self.reply(cmd, &[], UrbStatus::Stall)?;
+            return Ok(true);
+        }
+
+        // wValue holds the tag of the aborted transfer or status request
+        let tag = (req.value() & 0x00FF) as u8;
+        let success = usbtmc::status::SUCCESS;
+        let mut interrupt = None;
+        let (mut data, event) = match request {
+            TmcRequest::InitiateAbortBulkOut => {
+                self.clear_bulk_out(iface.bulk_endpoint(Direction::Out))?;
+                (vec![success, 0], Some(TmcEvent::AbortBulkOut { tag }))
+            }
+            TmcRequest::InitiateAbortBulkIn => {
+                self.clear_bulk_in(iface.bulk_endpoint(Direction::In))?;
+                (vec![success, tag], Some(TmcEvent::AbortBulkIn { tag }))
+            }
+            TmcRequest::CheckAbortBulkOutStatus | TmcRequest::CheckAbortBulkInStatus => {
+                (vec![success, 0, 0, 0, 0, 0, 0, 0], None)
+            }
+            TmcRequest::InitiateClear => {
+                self.clear_bulk_out(iface.bulk_endpoint(Direction::Out))?;
+                self.clear_bulk_in(iface.bulk_endpoint(Direction::In))?;
+                (vec![success], Some(TmcEvent::Clear))
+            }
+            TmcRequest::CheckClearStatus => (vec![success, 0], None),
+            TmcRequest::GetCapabilities => (iface.capabilities(), None),
+            TmcRequest::IndicatorPulse => {
+                let capabilities = iface.interface_capabilities;
+                match capabilities & usbtmc::capabilities::INDICATOR_PULSE {
+                    0 => (vec![usbtmc::status::FAILED], None),
+                    _ => (vec![success], Some(TmcEvent::IndicatorPulse)),
+                }
+            }
+            TmcRequest::ReadStatusByte => {
+                // With an interrupt IN endpoint, the status byte is sent
+                // there instead of in the reply
+                let status = iface.status_byte.poll();
+                match iface.interrupt_endpoint() {
+                    Some(ep) => {
+                        interrupt = Some((ep, [0x80 | tag, status]));
+                        (vec![success, tag, 0], None)
+                    }
+                    None => (vec![success, tag, status], None),
+                }
+            }
+            TmcRequest::RenControl => {
+                let enabled = req.value() & 0x00FF == 1;
+                (vec![success], Some(TmcEvent::RenControl { enabled }))
+            }
+            TmcRequest::GoToLocal => (vec![success], Some(TmcEvent::GoToLocal)),
+            TmcRequest::LocalLockout => (vec![success], Some(TmcEvent::LocalLockout)),
+        };
+        #[cfg(feature = "log")]
+        log::debug!("USBTMC request {request:?} with value {:#06x}", req.value());
+
+        data.truncate(req.length() as usize);
+        self.reply(cmd, &data, UrbStatus::Ok)?;
+        if let Some((ep, notification)) = interrupt {
+            self.queue_report(ep, &notification)?;
         }
+        if let Some(event) = event {
+            iface.handlers.dispatch(event);
+        }
+
+        Ok(true)
     }
 
-    /// To write data to an IN endpoint, call write() with the endpoint, data,
-    /// and length.
-    pub fn write(&self, reply: Reply) -> Result<(), Box<dyn Error>> {
-        let Some(replies) = self.replies.as_ref() else {
-            return Err("Device is not started".to_string().into());
+    /// Handle the class requests of still image interfaces. Cancelling and
+    /// resetting drop the data buffered on the bulk endpoints before the
+    /// device is notified. Returns true if the request was handled.
+    fn handle_command_submit_ep0_ptp(
+        &mut self,
+        cmd: &Command,
+        req: SetupRequest,
+    ) -> Result<bool, Box<dyn Error>> {
+        if req.request_type() != Type::Class || req.recipient() != Recipient::Interface {
+            return Ok(false);
+        }
+        let Some(request) = StillImageRequest::from_primitive(req.request()) else {
+            return Ok(false);
         };
-        replies.send(reply)?;
+        let Some(config) = self.current_config.as_ref() else {
+            return Ok(false);
+        };
+        let iface_idx = (req.index() & 0x00FF) as usize;
+        let Some(Interface::StillImage(iface)) = config.interfaces.get(iface_idx) else {
+            return Ok(false);
+        };
+        let iface = iface.clone();
+        #[cfg(feature = "log")]
+        log::debug!("Still image request {request:?}");
+
+        match request {
+            StillImageRequest::CancelRequest => {
+                // The data holds the cancellation code and transaction ID
+                let Some(id) = cmd.payload.get(2..6) else {
+                    self.reply(cmd, &[], UrbStatus::Stall)?;
+                    return Ok(true);
+                };
+                let transaction_id = u32::from_le_bytes([id[0], id[1], id[2], id[3]]);
+                self.clear_bulk_out(iface.bulk_endpoint(Direction::Out))?;
+                self.clear_bulk_in(iface.bulk_endpoint(Direction::In))?;
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+                iface.handlers.dispatch(PtpEvent::Cancel { transaction_id });
+            }
+            StillImageRequest::DeviceReset => {
+                self.clear_bulk_out(iface.bulk_endpoint(Direction::Out))?;
+                self.clear_bulk_in(iface.bulk_endpoint(Direction::In))?;
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+                iface.handlers.dispatch(PtpEvent::Reset);
+            }
+            StillImageRequest::GetDeviceStatus => {
+                let mut data = iface.device_status();
+                data.truncate(req.length() as usize);
+                self.reply(cmd, &data, UrbStatus::Ok)?;
+            }
+            StillImageRequest::GetExtendedEventData => {
+                // Events never carry extended data
+                self.reply(cmd, &[], UrbStatus::Stall)?;
+            }
+        }
+
+        Ok(true)
+    }
 
+    /// Drop the data received and not read yet on the given bulk OUT
+    /// endpoint
+    fn clear_bulk_out(&self, ep: Option<u8>) -> Result<(), Box<dyn Error>> {
+        if let Some(bulk) = ep.and_then(|ep| self.bulk_out.get(&ep)) {
+            bulk.clear()?;
+        }
         Ok(())
     }
 
-    /// Handle the given USB command. Standard USB transfers are automatically
-    /// handled. If it is not possible to handle, an [Xfer] will be returned
-    /// so it can be handled at another layer.
-    fn handle_command(&mut self, cmd: &Command) -> Result<Option<Xfer>, Box<dyn Error>> {
-        match cmd.header {
-            USBIPCommandHeader::CmdSubmit(header) => {
-                if header.base.ep.to_primitive() == 0 {
-                    self.handle_command_submit_ep0(cmd)
-                } else {
-                    self.handle_command_submit_epX(cmd)
-                }
-            }
-            USBIPCommandHeader::CmdUnlink(_) => {
-                self.handle_command_unlink(cmd)?;
-                Ok(None)
-            }
+    /// Drop the data written and not sent yet on the given bulk IN endpoint
+    fn clear_bulk_in(&self, ep: Option<u8>) -> Result<(), Box<dyn Error>> {
+        if let Some(bulk) = ep.and_then(|ep| self.bulk_in.get(&ep)) {
+            bulk.clear()?;
         }
+        Ok(())
     }
 
-    /// Handle command submit to endpoint 0. Endpoint 0 (zero), the default
-    /// endpoint, is always assumed to be a control endpoint and never has a
-    /// descriptor.
-    fn handle_command_submit_ep0(&mut self, cmd: &Command) -> Result<Option<Xfer>, Box<dyn Error>> {
-        #[cfg(feature = "log")]
-        log::debug!("handle submit ep0");
-        let USBIPCommandHeader::CmdSubmit(header) = cmd.header else {
-            return Err("Invalid header for submit command".into());
+    /// Handle GET_DEVICE_ID, GET_PORT_STATUS and SOFT_RESET for printer
+    /// interfaces. Returns true if the request was handled.
+    fn handle_command_submit_ep0_printer(
+        &self,
+        cmd: &Command,
+        req: SetupRequest,
+    ) -> Result<bool, Box<dyn Error>> {
+        if req.request_type() != Type::Class || req.recipient() != Recipient::Interface {
+            return Ok(false);
+        }
+        let Some(request) = PrinterRequest::from_primitive(req.request()) else {
+            return Ok(false);
         };
-        let standard_type = header.setup.is_standard();
 
-        // Handle standard requests automatically
-        if standard_type {
-            self.handle_command_submit_ep0_standard_request(cmd, header.setup)?;
-            return Ok(None);
-        }
+        // GET_DEVICE_ID has the interface number in the high byte of wIndex
+        // and the alternate setting in the low byte
+        let iface_idx = match request {
+            PrinterRequest::GetDeviceId => (req.index() >> 8) as usize,
+            _ => (req.index() & 0x00FF) as usize,
+        };
+        let Some(config) = self.current_config.as_ref() else {
+            return Ok(false);
+        };
+        let Some(Interface::Printer(iface)) = config.interfaces.get(iface_idx) else {
+            return Ok(false);
+        };
 
-        // Otherwise, handle as a regular endpoint command
-        if let Some(mut xfer) = self.handle_command_submit_epX(cmd)? {
-            // Populate the setupReq member, since it's always expected for ep==0
-            xfer.cmd = header;
-            return Ok(Some(xfer));
+        match request {
+            PrinterRequest::GetDeviceId => {
+                let mut data = iface.device_id_response();
+                data.truncate(req.length() as usize);
+                self.reply(cmd, &data, UrbStatus::Ok)?;
+            }
+            PrinterRequest::GetPortStatus => {
+                let mut data = vec![iface.port_status.get()];
+                data.truncate(req.length() as usize);
+                self.reply(cmd, &data, UrbStatus::Ok)?;
+            }
+            PrinterRequest::SoftReset => {
+                #[cfg(feature = "log")]
+                log::debug!("SoftReset on interface {iface_idx}");
+                iface.handle_soft_reset();
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+            }
         }
 
-        Ok(None)
+        Ok(true)
     }
 
-    /// Handle command submit to any other USB endpoint.
-    #[allow(non_snake_case)]
-    fn handle_command_submit_epX(&self, cmd: &Command) -> Result<Option<Xfer>, Box<dyn Error>> {
-        #[cfg(feature = "log")]
-        log::debug!("handle submit epX");
-        let USBIPCommandHeader::CmdSubmit(header) = cmd.header else {
-            return Err("Invalid header for submit command".into());
-        };
-        match header.base.direction {
-            // OUT command (data from host->device)
-            UsbIpDirection::Out => self.handle_command_submit_epX_out(cmd),
-            // IN command (data from device->host)
-            UsbIpDirection::In => self.handle_command_submit_epX_in(cmd),
+    /// Handle the probe and commit controls of video streaming interfaces.
+    /// Returns true if the request was handled.
+    fn handle_command_submit_ep0_uvc(
+        &self,
+        cmd: &Command,
+        req: SetupRequest,
+    ) -> Result<bool, Box<dyn Error>> {
+        if req.request_type() != Type::Class || req.recipient() != Recipient::Interface {
+            return Ok(false);
         }
-    }
 
-    /// Handle command submit OUT to any other USB endpoint.
-    #[allow(non_snake_case)]
-    fn handle_command_submit_epX_out(&self, cmd: &Command) -> Result<Option<Xfer>, Box<dyn Error>> {
-        #[cfg(feature = "log")]
-        log::debug!("handle submit epX OUT");
-        let USBIPCommandHeader::CmdSubmit(header) = cmd.header else {
-            return Err("Invalid header for submit command".into());
+        let Some(config) = self.current_config.as_ref() else {
+            return Ok(false);
         };
-        let ep_idx = header.base.ep.to_primitive();
-        #[cfg(feature = "log")]
-        log::debug!("handle submit epX OUT {ep_idx}");
-        if ep_idx >= ENDPOINT_MAX_COUNT as u32 {
-            return Err("Invalid endpoint index".into());
-        }
-
-        // Let host know that we received the data
-        self.reply(cmd, &[], cmd.payload.len() as i32)?;
-        let xfer = Xfer {
-            // TODO: Double check this
-            ep: ep_idx as u8,
-            // TODO: Can we move?
-            data: cmd.payload.clone(),
-            cmd: header,
+        let iface_idx = (req.index() & 0x00FF) as usize;
+        let Some(Interface::VideoStreaming(iface)) = config.interfaces.get(iface_idx) else {
+            return Ok(false);
+        };
+        let Some(request) = UvcRequest::from_primitive(req.request()) else {
+            return Ok(false);
+        };
+        // wValue holds the control selector in the high byte
+        let Some(control) = VsControl::from_primitive((req.value() >> 8) as u8) else {
+            return Ok(false);
         };
 
-        Ok(Some(xfer))
+        match request {
+            UvcRequest::SetCur => {
+                if !iface.handle_set_cur(control, &cmd.payload) {
+                    #[cfg(feature = "log")]
+                    log::debug!("Stall invalid {control:?} control on interface {iface_idx}");
+                    self.reply(cmd, &[], UrbStatus::Stall)?;
+                    return Ok(true);
+                }
+                #[cfg(feature = "log")]
+                log::debug!("SetCur {control:?} on interface {iface_idx}");
+                self.reply(cmd, &[], UrbStatus::Ok)?;
+            }
+            _ => {
+                let Some(mut data) = iface.handle_get(request, control)? else {
+                    return Ok(false);
+                };
+                data.truncate(req.length() as usize);
+                self.reply(cmd, &data, UrbStatus::Ok)?;
+            }
+        }
+
+        Ok(true)
     }
 
-    /// Handle command submit IN to any other USB endpoint.
-    #[allow(non_snake_case)]
-    fn handle_command_submit_epX_in(&self, cmd: &Command) -> Result<Option<Xfer>, Box<dyn Error>> {
-        #[cfg(feature = "log")]
-        log::debug!("handle submit epX IN");
-        let USBIPCommandHeader::CmdSubmit(header) = cmd.header else {
-            return Err("Invalid header for submit command".into());
-        };
-        let ep_idx = header.base.ep.to_primitive();
-        #[cfg(feature = "log")]
-        log::debug!("handle submit epX IN {ep_idx}");
-        if ep_idx >= ENDPOINT_MAX_COUNT as u32 {
-            return Err("Invalid endpoint index".into());
+    /// Handle the control requests of audio functions: requests for the
+    /// entities of AudioControl interfaces, addressed to the interface with
+    /// the entity ID in the high byte of wIndex, and requests for the
+    /// endpoints of AudioStreaming interfaces. Returns true if the request
+    /// was handled.
+    fn handle_command_submit_ep0_uac(
+        &self,
+        cmd: &Command,
+        req: SetupRequest,
+    ) -> Result<bool, Box<dyn Error>> {
+        if req.request_type() != Type::Class {
+            return Ok(false);
         }
+        // wValue holds the control selector in the high byte
+        let selector = (req.value() >> 8) as u8;
 
-        // This is an IN transfer that must be handled by user code
-        let xfer = Xfer {
-            ep: ep_idx as u8,
-            data: cmd.payload.clone(),
-            cmd: header,
+        let data = match req.recipient() {
+            Recipient::Interface => {
+                let Some(config) = self.current_config.as_ref() else {
+                    return Ok(false);
+                };
+                let iface_idx = (req.index() & 0x00FF) as usize;
+                let Some(Interface::AudioControl(iface)) = config.interfaces.get(iface_idx) else {
+                    return Ok(false);
+                };
+                let entity = (req.index() >> 8) as u8;
+                if req.direction() == Direction::Out {
+                    if !iface.handle_set(req.request(), entity, selector, &cmd.payload) {
+                        #[cfg(feature = "log")]
+                        log::debug!("Stall control {selector} request for entity {entity}");
+                        self.reply(cmd, &[], UrbStatus::Stall)?;
+                        return Ok(true);
+                    }
+                    #[cfg(feature = "log")]
+                    log::debug!("Set control {selector} of entity {entity}");
+                    self.reply(cmd, &[], UrbStatus::Ok)?;
+                    return Ok(true);
+                }
+                iface.handle_get(req.request(), entity, selector)
+            }
+            Recipient::Endpoint => {
+                // wIndex holds the endpoint address
+                let ep = (req.index() & 0x0F) as u8;
+                let direction = if req.index() & 0x80 != 0 {
+                    Direction::In
+                } else {
+                    Direction::Out
+                };
+                let Some(Interface::AudioStreaming(iface)) = self.find_interface(ep, direction)
+                else {
+                    return Ok(false);
+                };
+                if req.direction() == Direction::Out {
+                    if !iface.handle_endpoint_set(req.request(), selector, &cmd.payload) {
+                        #[cfg(feature = "log")]
+                        log::debug!("Stall control {selector} request for endpoint {ep}");
+                        self.reply(cmd, &[], UrbStatus::Stall)?;
+                        return Ok(true);
+                    }
+                    #[cfg(feature = "log")]
+                    log::debug!("Set control {selector} of endpoint {ep}");
+                    self.reply(cmd, &[], UrbStatus::Ok)?;
+                    return Ok(true);
+                }
+                iface.handle_endpoint_get(req.request(), selector)
+            }
+            _ => return Ok(false),
         };
 
-        Ok(Some(xfer))
+        let Some(mut data) = data else {
+            return Ok(false);
+        };
+        data.truncate(req.length() as usize);
+        self.reply(cmd, &data, UrbStatus::Ok)?;
+        Ok(true)
     }
 
-    /// Handle unlinking
-    fn handle_command_unlink(&self, cmd: &Command) -> Result<(), Box<dyn Error>> {
+    /// Handle NCM requests for the given NCM interface. Only 16-bit NTBs
+    /// without CRC are supported. Returns true if the request was handled.
+    fn handle_command_submit_ep0_ncm(
+        &self,
+        cmd: &Command,
+        req: SetupRequest,
+        iface: &CdcInterface,
+        request: NcmRequest,
+    ) -> Result<bool, Box<dyn Error>> {
+        if iface.ncm.is_none() {
+            return Ok(false);
+        }
         #[cfg(feature = "log")]
-        log::debug!("handle unlink");
-        let USBIPCommandHeader::CmdUnlink(_) = cmd.header else {
-            return Err("Invalid header for unlink command".into());
+        log::debug!("NCM request {request:?} with value {:#x}", req.value());
+
+        let state = iface.network_state();
+        let mut data = match request {
+            NcmRequest::GetNtbParameters => iface.ntb_parameters().pack_to_vec()?,
+            NcmRequest::GetNetAddress => iface.mac_address.unwrap_or_default().to_vec(),
+            NcmRequest::GetNtbFormat | NcmRequest::GetCrcMode => vec![0, 0],
+            NcmRequest::GetNtbInputSize => state.ntb_input_size.to_le_bytes().to_vec(),
+            NcmRequest::GetMaxDatagramSize => state.max_datagram_size.to_le_bytes().to_vec(),
+            NcmRequest::SetNtbInputSize => {
+                let status = if iface.handle_set_ntb_input_size(&cmd.payload) {
+                    UrbStatus::Ok
+                } else {
+                    UrbStatus::Stall
+                };
+                self.reply(cmd, &[], status)?;
+                return Ok(true);
+            }
+            NcmRequest::SetMaxDatagramSize => {
+                let status = if iface.handle_set_max_datagram_size(&cmd.payload) {
+                    UrbStatus::Ok
+                } else {
+                    UrbStatus::Stall
+                };
+                self.reply(cmd, &[], status)?;
+                return Ok(true);
+            }
+            NcmRequest::SetNtbFormat | NcmRequest::SetCrcMode => {
+                // Only 16-bit NTBs without CRC are supported
+                let status = if req.value() == 0 {
+                    UrbStatus::Ok
+                } else {
+                    UrbStatus::Stall
+                };
+                self.reply(cmd, &[], status)?;
+                return Ok(true);
+            }
+            NcmRequest::SetNetAddress => return Ok(false),
         };
 
-        // TODO: Do we need to do more?
-
-        let status = -104; // 104 == ECONNRESET
-        self.reply(cmd, &[], status)
+        data.truncate(req.length() as usize);
+        self.reply(cmd, &data, UrbStatus::Ok)?;
+        Ok(true)
     }
 
     /// Handle standard requests to endpoint zero
@@ -438,6 +1977,9 @@ impl VirtualUSBDevice {
             Recipient::Interface => {
                 self.handle_command_submit_ep0_standard_request_for_iface(cmd, req, direction)
             }
+            Recipient::Endpoint => {
+                self.handle_command_submit_ep0_standard_request_for_endpoint(cmd, req)
+            }
             _ => {
                 let err = format!("Unhandled recipient: {:?}", recipient);
                 Err(err.into())
@@ -461,8 +2003,8 @@ impl VirtualUSBDevice {
         // Handle the command based on the direction
         match direction {
             // IN command (data from device->host)
-            UsbIpDirection::In => match req.b_request {
-                StandardRequest::GetStatus => {
+            UsbIpDirection::In => match req.standard_request() {
+                Some(StandardRequest::GetStatus) => {
                     #[cfg(feature = "log")]
                     log::debug!("USB Request: GetStatus");
                     let Some(config) = self.current_config.as_ref() else {
@@ -479,10 +2021,10 @@ impl VirtualUSBDevice {
                     let data: [u8; 2] = (reply as i16).to_msb_bytes();
 
                     // Write the reply
-                    self.reply(cmd, &data, 0)?;
+                    self.reply(cmd, &data, UrbStatus::Ok)?;
                     Ok(())
                 }
-                StandardRequest::GetDescriptor => {
+                Some(StandardRequest::GetDescriptor) => {
                     #[cfg(feature = "log")]
                     log::debug!("USB Request: GetDescriptor");
                     // Get the descriptor type
@@ -550,8 +2092,12 @@ impl VirtualUSBDevice {
                         }
                     };
 
-                    // Get the status of the reply
-                    let status = if data.is_empty() { 1 } else { 0 };
+                    // Stall requests for descriptors the device does not have
+                    let status = if data.is_empty() {
+                        UrbStatus::Stall
+                    } else {
+                        UrbStatus::Ok
+                    };
 
                     // Truncate the data to the expected length
                     data.truncate(req.w_length.to_primitive() as usize);
@@ -560,7 +2106,7 @@ impl VirtualUSBDevice {
                     self.reply(cmd, data.as_slice(), status)?;
                     Ok(())
                 }
-                StandardRequest::SetConfiguration => {
+                Some(StandardRequest::SetConfiguration) => {
                     #[cfg(feature = "log")]
                     log::debug!("USB Request: SetConfiguration");
                     let config_val = req.w_value.to_primitive() & 0x00FF;
@@ -569,6 +2115,8 @@ impl VirtualUSBDevice {
                         if config_val as u8 == config.conf_desc.b_configuration_value {
                             // TODO: Don't copy
                             self.current_config = Some(config.clone());
+                            self.alternate_settings.clear();
+                            self.hid_protocols.clear();
                             ok = true;
                         }
                     }
@@ -577,7 +2125,7 @@ impl VirtualUSBDevice {
                     }
 
                     // Write the reply
-                    self.reply(cmd, vec![].as_slice(), 0)?;
+                    self.reply(cmd, vec![].as_slice(), UrbStatus::Ok)?;
                     Ok(())
                 }
                 _ => Err(
@@ -592,8 +2140,8 @@ impl VirtualUSBDevice {
                     return Err("Unexpected payload for EP0 standard request".into());
                 }
 
-                match req.b_request {
-                    StandardRequest::SetConfiguration => {
+                match req.standard_request() {
+                    Some(StandardRequest::SetConfiguration) => {
                         #[cfg(feature = "log")]
                         log::debug!("USB Request: SetConfiguration");
                         let config_val = req.w_value.to_primitive() & 0x00FF;
@@ -602,6 +2150,8 @@ impl VirtualUSBDevice {
                             if config_val as u8 == config.conf_desc.b_configuration_value {
                                 // TODO: Don't copy
                                 self.current_config = Some(config.clone());
+                                self.alternate_settings.clear();
+                                self.hid_protocols.clear();
                                 ok = true;
                             }
                         }
@@ -610,7 +2160,7 @@ impl VirtualUSBDevice {
                         }
 
                         // Write the reply
-                        self.reply(cmd, vec![].as_slice(), 0)?;
+                        self.reply(cmd, vec![].as_slice(), UrbStatus::Ok)?;
                         Ok(())
                     }
                     _ => Err(
@@ -634,8 +2184,8 @@ impl VirtualUSBDevice {
 
         match direction {
             // IN command (data from device->host)
-            UsbIpDirection::In => match req.b_request {
-                StandardRequest::GetDescriptor => {
+            UsbIpDirection::In => match req.standard_request() {
+                Some(StandardRequest::GetDescriptor) => {
                     #[cfg(feature = "log")]
                     log::debug!("USB Request: GetDescriptor");
                     // Get the interface descriptor this request is for
@@ -662,7 +2212,10 @@ impl VirtualUSBDevice {
                             // Handle the request based on type
                             match hid_req.b_descriptor_type {
                                 HidDescriptorType::Hid => {
-                                    todo!()
+                                    let mut desc = hid_iface.pack_hid_descriptor()?;
+                                    desc.truncate(req.length() as usize);
+                                    self.reply(cmd, &desc, UrbStatus::Ok)?;
+                                    Ok(())
                                 }
                                 HidDescriptorType::Report => {
                                     let Some(desc) = hid_iface.report_descriptors.get(desc_idx)
@@ -674,25 +2227,116 @@ impl VirtualUSBDevice {
                                     };
 
                                     // Write the reply
-                                    self.reply(cmd, desc, 0)?;
+                                    self.reply(cmd, desc, UrbStatus::Ok)?;
                                     Ok(())
                                 }
+                                // Physical descriptors are not supported
                                 HidDescriptorType::Physical => {
-                                    todo!()
+                                    #[cfg(feature = "log")]
+                                    log::debug!("Stall physical descriptor request");
+                                    self.reply(cmd, &[], UrbStatus::Stall)?;
+                                    Ok(())
                                 }
                             }
                         }
+                        // Other classes have no interface descriptors to fetch
+                        _ => {
+                            #[cfg(feature = "log")]
+                            log::debug!("Stall GetDescriptor for interface {iface_idx}");
+                            self.reply(cmd, &[], UrbStatus::Stall)?;
+                            Ok(())
+                        }
                     }
                 }
-                _ => todo!(),
+                Some(StandardRequest::GetInterface) => {
+                    #[cfg(feature = "log")]
+                    log::debug!("USB Request: GetInterface");
+                    let iface = (req.index() & 0x00FF) as u8;
+                    self.reply(cmd, &[self.alternate_setting(iface)], UrbStatus::Ok)?;
+                    Ok(())
+                }
+                // Interface status is reserved and always zero
+                Some(StandardRequest::GetStatus) => {
+                    #[cfg(feature = "log")]
+                    log::debug!("USB Request: GetStatus");
+                    let mut data = vec![0, 0];
+                    data.truncate(req.length() as usize);
+                    self.reply(cmd, &data, UrbStatus::Ok)
+                }
+                _ => {
+                    #[cfg(feature = "log")]
+                    log::debug!("Stall interface request {:?}", req.request());
+                    self.reply(cmd, &[], UrbStatus::Stall)
+                }
             },
             // OUT command (data from host->device)
-            UsbIpDirection::Out => todo!(),
+            UsbIpDirection::Out => match req.standard_request() {
+                Some(StandardRequest::SetInterface) => {
+                    #[cfg(feature = "log")]
+                    log::debug!("USB Request: SetInterface");
+                    let Some(config) = self.current_config.as_ref() else {
+                        return Err("No active configuration".into());
+                    };
+                    let iface = (req.index() & 0x00FF) as u8;
+                    let alternate = (req.value() & 0x00FF) as u8;
+                    let Some(interface) = config.interfaces.get(iface as usize) else {
+                        return Err(format!("No interface exists with number {iface}").into());
+                    };
+                    if alternate >= interface.alternate_settings() {
+                        #[cfg(feature = "log")]
+                        log::debug!("Stall invalid alternate setting {alternate} for {iface}");
+                        self.reply(cmd, &[], UrbStatus::Stall)?;
+                        return Ok(());
+                    }
+                    if let Interface::AudioStreaming(audio) = interface {
+                        audio.handle_set_alternate_setting(alternate);
+                    }
+                    self.alternate_settings.insert(iface, alternate);
+
+                    self.reply(cmd, &[], UrbStatus::Ok)?;
+                    Ok(())
+                }
+                _ => Err(format!(
+                    "Invalid host->device interface request: {:?}",
+                    req.request()
+                )
+                .into()),
+            },
+        }
+    }
+
+    /// Handle standard endpoint requests to endpoint zero. Endpoints are
+    /// never halted, so halt requests are acknowledged without effect. Hosts
+    /// clear the halt feature to recover from errors or stop bulk streams.
+    fn handle_command_submit_ep0_standard_request_for_endpoint(
+        &mut self,
+        cmd: &Command,
+        req: SetupRequest,
+    ) -> Result<(), Box<dyn Error>> {
+        #[cfg(feature = "log")]
+        log::debug!("handle submit ep0 standard request for endpoint");
+
+        match req.standard_request() {
+            Some(StandardRequest::GetStatus) => {
+                let mut data = vec![0, 0];
+                data.truncate(req.length() as usize);
+                self.reply(cmd, &data, UrbStatus::Ok)
+            }
+            Some(StandardRequest::ClearFeature | StandardRequest::SetFeature) => {
+                #[cfg(feature = "log")]
+                log::debug!(
+                    "USB Request: {:?} on endpoint {:#x}",
+                    req.request(),
+                    req.index()
+                );
+                self.reply(cmd, &[], UrbStatus::Ok)
+            }
+            _ => Err(format!("Invalid endpoint request: {:?}", req.request()).into()),
         }
     }
 
     /// Reply to the given command and write it to the USBIP unix socket.
-    fn reply(&self, cmd: &Command, data: &[u8], status: i32) -> Result<(), Box<dyn Error>> {
+    fn reply(&self, cmd: &Command, data: &[u8], status: UrbStatus) -> Result<(), Box<dyn Error>> {
         // Get the write channel to send replies
         let Some(replies) = self.replies.as_ref() else {
             return Err("Write thread is not running to send replies".into());
@@ -711,27 +2355,22 @@ impl VirtualUSBDevice {
                 //   - For IN transfers, either we're sending data (len>0) and have a
                 //   valid data pointer
                 //     (data!=null), or we're not sending data (len==0)
-                //   - For OUT transfers, we can't respond with any data, but the `len`
-                //   argument is used
-                //     to populate `actual_length` -- the amount of data sent to the
-                //     device
-                match header.direction {
+                //   - For OUT transfers, we can't respond with any data, but the
+                //   length of the received payload is used to populate
+                //   `actual_length` -- the amount of data sent to the device
+                let mut payload = Vec::with_capacity(data.len());
+                let actual_length = match header.direction {
                     UsbIpDirection::In => {
                         if data.is_empty() {
                             #[cfg(feature = "log")]
                             log::warn!("No data to send IN reply");
                         }
+                        payload = data.to_vec();
+                        data.len()
                     }
-                    UsbIpDirection::Out => {
-                        // TODO: Double check this
-                    }
-                }
-
-                // Set the payload if this is an IN command
-                let mut payload = Vec::with_capacity(data.len());
-                if header.direction == UsbIpDirection::In {
-                    payload = data.to_vec();
-                }
+                    UsbIpDirection::Out if status == UrbStatus::Ok => cmd.payload.len(),
+                    UsbIpDirection::Out => 0,
+                };
 
                 // Build a reply
                 Reply {
@@ -743,13 +2382,14 @@ impl VirtualUSBDevice {
                             direction: header.direction,
                             ep: header.ep,
                         },
-                        status: Integer::from_primitive(0),
-                        actual_length: Integer::from_primitive(data.len() as i32),
+                        status: Integer::from_primitive(status.to_primitive()),
+                        actual_length: Integer::from_primitive(actual_length as i32),
                         start_frame: Integer::from_primitive(0),
                         number_of_packets: Integer::from_primitive(0),
                         error_count: Integer::from_primitive(0),
                     }),
                     payload,
+                    iso_packets: Vec::new(),
                 }
             }
             USBIP_CMD_UNLINK => Reply {
@@ -761,9 +2401,10 @@ impl VirtualUSBDevice {
                         direction: header.direction,
                         ep: header.ep,
                     },
-                    status: Integer::from_primitive(status),
+                    status: Integer::from_primitive(status.to_primitive()),
                 }),
                 payload: Vec::with_capacity(0),
+                iso_packets: Vec::new(),
             },
             _ => return Err("Unknown command to reply to".into()),
         };
@@ -776,15 +2417,15 @@ impl VirtualUSBDevice {
 
     /// Returns the USB speed from the given bcdUSB value in the device
     /// descriptor.
-    fn speed_from_bcd_usb(bcd_usb: u16) -> u32 {
+    fn speed_from_bcd_usb(bcd_usb: u16) -> USBDeviceSpeed {
         match bcd_usb {
-            0x0100 => USBDeviceSpeed::USBSpeedFull as u32,
-            0x0110 => USBDeviceSpeed::USBSpeedFull as u32,
-            0x0200 => USBDeviceSpeed::USBSpeedHigh as u32,
-            0x0300 => USBDeviceSpeed::USBSpeedSuper as u32,
-            0x0310 => USBDeviceSpeed::USBSpeedSuperPlus as u32,
-            0x0320 => USBDeviceSpeed::USBSpeedSuperPlus as u32,
-            _ => USBDeviceSpeed::USBSpeedUnknown as u32,
+            0x0100 => USBDeviceSpeed::USBSpeedFull,
+            0x0110 => USBDeviceSpeed::USBSpeedFull,
+            0x0200 => USBDeviceSpeed::USBSpeedHigh,
+            0x0300 => USBDeviceSpeed::USBSpeedSuper,
+            0x0310 => USBDeviceSpeed::USBSpeedSuperPlus,
+            0x0320 => USBDeviceSpeed::USBSpeedSuperPlus,
+            _ => USBDeviceSpeed::USBSpeedUnknown,
         }
     }
 }
@@ -806,24 +2447,17 @@ impl WriteHandler {
 
     /// Run the write handler
     fn run(&mut self) {
-        loop {
-            // Wait for writes from the virtual USB device.
-            let reply = match self.virt_device.recv() {
-                Ok(msg) => msg,
-                Err(_) => {
-                    #[cfg(feature = "log")]
-                    log::debug!("Channel closed. Stopping write handler.");
-                    break;
-                }
-            };
-
+        // Wait for writes from the virtual USB device.
+        while let Ok(reply) = self.virt_device.recv() {
             // Write the reply to the unix socket
-            if let Err(e) = self.write(reply) {
+            if let Err(_e) = self.write(reply) {
                 #[cfg(feature = "log")]
-                log::debug!("Error writing reply: {e:?}");
-                break;
+                log::debug!("Error writing reply: {_e:?}");
+                return;
             }
         }
+        #[cfg(feature = "log")]
+        log::debug!("Channel closed. Stopping write handler.");
     }
 
     /// Write the given reply to the unix socket
@@ -846,25 +2480,36 @@ impl WriteHandler {
         if let Err(e) = result {
             return Err(format!("Failed to write message header: {e:?}").into());
         }
-        if reply.payload.is_empty() {
-            return Ok(());
-        }
 
         // Write the message payload to the socket if one exists
-        #[cfg(feature = "log")]
-        log::debug!("Writing payload with size: {}", reply.payload.len());
-        #[cfg(feature = "log")]
-        log::debug!("Payload: {:x?}", reply.payload.as_slice());
-        match self.socket.write(reply.payload.as_slice()) {
-            Ok(bytes_written) => {
-                #[cfg(feature = "log")]
-                log::debug!("Wrote {bytes_written} bytes")
-            }
-            Err(e) => {
-                return Err(format!("Failed to write message payload: {e:?}").into());
+        if !reply.payload.is_empty() {
+            #[cfg(feature = "log")]
+            log::debug!("Writing payload with size: {}", reply.payload.len());
+            #[cfg(feature = "log")]
+            log::debug!("Payload: {:x?}", reply.payload.as_slice());
+            match self.socket.write(reply.payload.as_slice()) {
+                Ok(_bytes_written) => {
+                    #[cfg(feature = "log")]
+                    log::debug!("Wrote {_bytes_written} bytes")
+                }
+                Err(e) => {
+                    return Err(format!("Failed to write message payload: {e:?}").into());
+                }
             }
         }
 
+        // Write the packet descriptors of isochronous transfers
+        if reply.iso_packets.is_empty() {
+            return Ok(());
+        }
+        let mut descriptors = Vec::with_capacity(reply.iso_packets.len() * USBIP_ISO_PACKET_SIZE);
+        for packet in reply.iso_packets.iter() {
+            descriptors.extend_from_slice(&packet.pack()?);
+        }
+        if let Err(e) = self.socket.write_all(&descriptors) {
+            return Err(format!("Failed to write ISO packet descriptors: {e:?}").into());
+        }
+
         Ok(())
     }
 }
@@ -896,7 +2541,7 @@ impl ReadHandler {
             // Check to see if the thread should stop
             match self.stop_rx.try_recv() {
                 Ok(tx) => {
-                    if let Err(e) = tx.send(()) {
+                    if tx.send(()).is_err() {
                         #[cfg(feature = "log")]
                         log::debug!("Failed to send stop confirmation");
                     }
@@ -914,9 +2559,9 @@ impl ReadHandler {
             // Read commands from the unix socket
             let cmd = match self.read() {
                 Ok(cmd) => cmd,
-                Err(e) => {
+                Err(_e) => {
                     #[cfg(feature = "log")]
-                    log::debug!("Error reading commands: {e:?}");
+                    log::debug!("Error reading commands: {_e:?}");
                     break;
                 }
             };
@@ -967,9 +2612,9 @@ impl ReadHandler {
                     log::debug!("{}", header.setup);
                 }
             }
-            USBIPCommandHeader::CmdUnlink(header) => {
+            USBIPCommandHeader::CmdUnlink(_header) => {
                 #[cfg(feature = "log")]
-                log::debug!("{header}");
+                log::debug!("{_header}");
             }
         }
 
@@ -984,11 +2629,13 @@ impl ReadHandler {
                 Command {
                     header,
                     payload: Vec::with_capacity(payload_length),
+                    iso_packets: Vec::new(),
                 }
             }
             USBIPCommandHeader::CmdUnlink(_) => Command {
                 header,
                 payload: Vec::with_capacity(0),
+                iso_packets: Vec::new(),
             },
         };
 
@@ -1001,6 +2648,24 @@ impl ReadHandler {
             let payload_buf = cmd.payload.as_mut_slice();
             self.socket.read_exact(payload_buf)?;
         }
+
+        // Read the packet descriptors of isochronous transfers, which follow
+        // the payload. Other transfers have no packets (0 or -1).
+        if let USBIPCommandHeader::CmdSubmit(submit) = header {
+            let number_of_packets = submit.number_of_packets.to_primitive();
+            if number_of_packets > USBIP_MAX_ISO_PACKETS {
+                let err = format!("Invalid number of ISO packets: {number_of_packets}");
+                return Err(err.into());
+            }
+            if number_of_packets > 0 {
+                let mut buf = vec![0; number_of_packets as usize * USBIP_ISO_PACKET_SIZE];
+                self.socket.read_exact(&mut buf)?;
+                for desc in buf.chunks_exact(USBIP_ISO_PACKET_SIZE) {
+                    cmd.iso_packets
+                        .push(USBIPIsoPacketDescriptor::unpack_from_slice(desc)?);
+                }
+            }
+        }
         #[cfg(feature = "log")]
         log::debug!("Cmd: {cmd:?}");
 
@@ -1043,6 +2708,12 @@ impl VirtualUSBDeviceBuilder {
         self
     }
 
+    /// Set the device protocol for the device
+    pub fn protocol(&mut self, protocol: u8) -> &mut Self {
+        self.info.device_desc.b_device_protocol = protocol;
+        self
+    }
+
     /// Add the given supported languages
     pub fn supported_langs(&mut self, langs: Vec<LangId>) -> &mut Self {
         self.info.string_descs.insert(0, langs.into());
@@ -1050,7 +2721,29 @@ impl VirtualUSBDeviceBuilder {
     }
 
     /// Add the given configuration
-    pub fn configuration(&mut self, config: Configuration) -> &mut Self {
+    pub fn configuration(&mut self, mut config: Configuration) -> &mut Self {
+        // Add the MAC address strings of networking interfaces and the
+        // names of still image interfaces
+        for iface in config.interfaces.iter_mut() {
+            match iface {
+                Interface::Cdc(cdc) => {
+                    if let Some(mac_address) = cdc.mac_address_string() {
+                        let idx = self.info.string_descs.len();
+                        self.info.string_descs.push(mac_address.into());
+                        cdc.set_mac_address_index(idx as u8);
+                    }
+                }
+                Interface::StillImage(ptp) => {
+                    if let Some(name) = ptp.name.as_deref() {
+                        let idx = self.info.string_descs.len();
+                        self.info.string_descs.push(name.into());
+                        ptp.set_name_index(idx as u8);
+                    }
+                }
+                _ => (),
+            }
+        }
+
         self.info.configs.push(config);
         self.info.device_desc.b_num_configurations = self.info.configs.len() as u8;
         self
@@ -0,0 +1,427 @@
+//! Byte streams over bulk endpoints
+//!
+//! The host reads and writes bulk endpoints with URBs of any length, which
+//! are split into packets of up to wMaxPacketSize bytes on the bus. A
+//! transfer ends with a short packet, or with a zero length packet (ZLP)
+//! when its length is a multiple of the packet size. [BulkIn] and [BulkOut]
+//! hide the URBs behind [Write] and [Read] streams, and keep track of where
+//! transfers end for protocols that exchange messages.
+//!
+//! Handles are obtained with [VirtualUSBDevice::bulk_in] and
+//! [VirtualUSBDevice::bulk_out]. Transfers to their endpoints are then no
+//! longer returned from [VirtualUSBDevice::read], but still need read() to
+//! be called to reach the handles.
+//!
+//! [VirtualUSBDevice::bulk_in]: super::VirtualUSBDevice::bulk_in
+//! [VirtualUSBDevice::bulk_out]: super::VirtualUSBDevice::bulk_out
+//! [VirtualUSBDevice::read]: super::VirtualUSBDevice::read
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io::{self, Read, Write},
+    sync::{mpsc::Sender, Arc, Mutex, MutexGuard},
+};
+
+use crate::usbip::{TransferFlags, UrbStatus};
+
+use super::{Reply, Xfer};
+
+/// Default number of bytes buffered by a bulk endpoint
+pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+/// Move the given transfer ends after dropping the given number of bytes
+/// from the front of the buffer, which must not go past the first end
+fn consume(ends: &mut VecDeque<usize>, len: usize) {
+    for end in ends.iter_mut() {
+        *end -= len;
+    }
+}
+
+#[derive(Debug)]
+struct BulkInState {
+    max_packet_size: usize,
+    capacity: usize,
+    zero_length_packets: bool,
+    replies: Option<Sender<Reply>>,
+    /// IN transfers waiting for data
+    pending: VecDeque<Xfer>,
+    /// Data written and not sent yet
+    data: VecDeque<u8>,
+    /// Offsets in the data where transfers end
+    ends: VecDeque<usize>,
+}
+
+impl BulkInState {
+    /// Returns true if the data ends with bytes not part of an ended
+    /// transfer
+    fn is_open(&self) -> bool {
+        self.data.len() > self.ends.back().copied().unwrap_or_default()
+    }
+
+    /// Answer pending transfers with the data written so far. Transfers are
+    /// only replied to once their buffer is full or the data written to
+    /// them ends.
+    fn complete(&mut self) -> io::Result<()> {
+        let Some(replies) = self.replies.clone() else {
+            return Ok(());
+        };
+        while let Some(xfer) = self.pending.front() {
+            let length = xfer.buffer_length();
+            let (len, ended) = match self.ends.front() {
+                Some(end) if *end <= length => (*end, true),
+                _ if self.data.len() >= length => (length, false),
+                _ => break,
+            };
+            let Some(xfer) = self.pending.pop_front() else {
+                break;
+            };
+
+            let data: Vec<u8> = self.data.drain(..len).collect();
+            if ended {
+                self.ends.pop_front();
+            }
+            consume(&mut self.ends, len);
+            // The host only sees the end of a transfer that fills the URB
+            // exactly when a zero length packet follows
+            let boundary = len > 0 && len % self.max_packet_size == 0;
+            if ended && len == length && boundary && self.zero_length_packets {
+                self.ends.push_front(0);
+            }
+
+            let short_not_ok = xfer.transfer_flags().contains(TransferFlags::SHORT_NOT_OK);
+            let mut reply = Reply::from_xfer(xfer, &data);
+            if len < length && short_not_ok {
+                reply.set_status(UrbStatus::RemoteIo);
+            }
+            replies.send(reply).map_err(|_| io::ErrorKind::BrokenPipe)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writable stream of data sent to the host over a bulk IN endpoint
+///
+/// Written data is sent once the host has a transfer pending on the
+/// endpoint that it fills, or once the transfer is ended with
+/// [Write::flush] or [BulkIn::write_transfer]. An ended transfer is sent as
+/// a short packet, or is followed by a zero length packet if it fills the
+/// URB of the host with whole packets. Transfers are answered when
+/// [VirtualUSBDevice::read](super::VirtualUSBDevice::read) is called, or
+/// right away when data is written to the handle.
+#[derive(Debug, Clone)]
+pub struct BulkIn {
+    ep: u8,
+    state: Arc<Mutex<BulkInState>>,
+}
+
+impl BulkIn {
+    pub(super) fn new(ep: u8, max_packet_size: u16) -> Self {
+        let state = BulkInState {
+            max_packet_size: max_packet_size.max(1) as usize,
+            capacity: DEFAULT_CAPACITY,
+            zero_length_packets: true,
+            replies: None,
+            pending: VecDeque::new(),
+            data: VecDeque::new(),
+            ends: VecDeque::new(),
+        };
+        Self {
+            ep,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, BulkInState>> {
+        self.state
+            .lock()
+            .map_err(|_| io::Error::other("Bulk endpoint state is poisoned"))
+    }
+
+    /// Returns the endpoint number
+    pub fn ep(&self) -> u8 {
+        self.ep
+    }
+
+    /// Returns the number of bytes written and not sent yet
+    pub fn len(&self) -> usize {
+        self.lock()
+            .map(|state| state.data.len())
+            .unwrap_or_default()
+    }
+
+    /// Returns true if all written data was sent
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Set the number of bytes that can be written before writes block
+    pub fn set_capacity(&self, capacity: usize) {
+        if let Ok(mut state) = self.lock() {
+            state.capacity = capacity;
+        }
+    }
+
+    /// Set whether transfers filling the URB of the host with whole packets
+    /// are followed by a zero length packet (the default). Protocols whose
+    /// transfers have a length known to the host, such as mass storage
+    /// data, must not send them.
+    pub fn set_zero_length_packets(&self, enabled: bool) {
+        if let Ok(mut state) = self.lock() {
+            state.zero_length_packets = enabled;
+        }
+    }
+
+    /// Drop the data written and not sent yet. Pending transfers of the
+    /// host keep waiting for new data.
+    pub fn clear(&self) -> io::Result<()> {
+        let mut state = self.lock()?;
+        state.data.clear();
+        state.ends.clear();
+        Ok(())
+    }
+
+    /// Write the given data as one transfer, which may be empty
+    pub fn write_transfer(&self, data: &[u8]) -> io::Result<()> {
+        let mut state = self.lock()?;
+        if state.data.len() + data.len() > state.capacity {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        if state.is_open() {
+            let end = state.data.len();
+            state.ends.push_back(end);
+        }
+        state.data.extend(data);
+        let end = state.data.len();
+        state.ends.push_back(end);
+        state.complete()
+    }
+
+    pub(super) fn set_replies(&self, replies: Sender<Reply>) {
+        if let Ok(mut state) = self.lock() {
+            state.replies = Some(replies);
+        }
+    }
+
+    /// Queue the given IN transfer and answer it if data is available
+    pub(super) fn submit(&self, xfer: Xfer) -> Result<(), Box<dyn Error>> {
+        let mut state = self.lock()?;
+        state.pending.push_back(xfer);
+        Ok(state.complete()?)
+    }
+
+    /// Drop the pending transfer with the given sequence number. Returns
+    /// true if the transfer was pending.
+    pub(super) fn unlink(&self, seqnum: u32) -> bool {
+        let Ok(mut state) = self.lock() else {
+            return false;
+        };
+        let len = state.pending.len();
+        state.pending.retain(|xfer| xfer.seqnum() != seqnum);
+        state.pending.len() != len
+    }
+}
+
+impl Write for BulkIn {
+    /// Queue as much of the given data as fits in the buffer. Fails with
+    /// [io::ErrorKind::WouldBlock] if the buffer is full.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.lock()?;
+        let len = buf
+            .len()
+            .min(state.capacity.saturating_sub(state.data.len()));
+        if len == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        state.data.extend(&buf[..len]);
+        state.complete()?;
+        Ok(len)
+    }
+
+    /// End the transfer of the data written so far, so it is sent to the
+    /// host without waiting for more data
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.lock()?;
+        if state.is_open() {
+            let end = state.data.len();
+            state.ends.push_back(end);
+        }
+        state.complete()
+    }
+}
+
+#[derive(Debug)]
+struct BulkOutState {
+    max_packet_size: usize,
+    capacity: usize,
+    replies: Option<Sender<Reply>>,
+    /// OUT transfers not acknowledged yet, waiting for room in the buffer
+    waiting: VecDeque<Xfer>,
+    /// Data received and not read yet
+    data: VecDeque<u8>,
+    /// Offsets in the data where transfers end
+    ends: VecDeque<usize>,
+}
+
+impl BulkOutState {
+    /// Move the data of waiting transfers to the buffer while there is room
+    /// for it, and acknowledge them. The host does not send more data on
+    /// the endpoint until its transfers are acknowledged.
+    fn accept(&mut self) -> io::Result<()> {
+        let Some(replies) = self.replies.clone() else {
+            return Ok(());
+        };
+        while self.data.len() < self.capacity {
+            let Some(xfer) = self.waiting.pop_front() else {
+                break;
+            };
+            let len = xfer.data.len();
+            self.data.extend(&xfer.data);
+            // A transfer continues in the next URB unless it ends with a
+            // short packet or a zero length packet
+            let zero_packet = xfer.transfer_flags().contains(TransferFlags::ZERO_PACKET);
+            if len % self.max_packet_size != 0 || len == 0 || zero_packet {
+                let end = self.data.len();
+                self.ends.push_back(end);
+            }
+
+            let mut reply = Reply::from_xfer(xfer, &[]);
+            reply.set_actual_length(len);
+            replies.send(reply).map_err(|_| io::ErrorKind::BrokenPipe)?;
+        }
+        Ok(())
+    }
+}
+
+/// Readable stream of data received from the host over a bulk OUT endpoint
+///
+/// Transfers from the host are acknowledged once their data fits in the
+/// buffer, so the host waits while the data is not read. Reads fail with
+/// [io::ErrorKind::WouldBlock] while no data was received. Data is only
+/// received when [VirtualUSBDevice::read](super::VirtualUSBDevice::read) is
+/// called.
+#[derive(Debug, Clone)]
+pub struct BulkOut {
+    ep: u8,
+    state: Arc<Mutex<BulkOutState>>,
+}
+
+impl BulkOut {
+    pub(super) fn new(ep: u8, max_packet_size: u16) -> Self {
+        let state = BulkOutState {
+            max_packet_size: max_packet_size.max(1) as usize,
+            capacity: DEFAULT_CAPACITY,
+            replies: None,
+            waiting: VecDeque::new(),
+            data: VecDeque::new(),
+            ends: VecDeque::new(),
+        };
+        Self {
+            ep,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, BulkOutState>> {
+        self.state
+            .lock()
+            .map_err(|_| io::Error::other("Bulk endpoint state is poisoned"))
+    }
+
+    /// Returns the endpoint number
+    pub fn ep(&self) -> u8 {
+        self.ep
+    }
+
+    /// Returns the number of bytes received and not read yet
+    pub fn len(&self) -> usize {
+        self.lock()
+            .map(|state| state.data.len())
+            .unwrap_or_default()
+    }
+
+    /// Returns true if no data is waiting to be read
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Set the number of bytes buffered before the host has to wait for
+    /// the data to be read. Transfers longer than the capacity can only be
+    /// received with [Read::read].
+    pub fn set_capacity(&self, capacity: usize) {
+        if let Ok(mut state) = self.lock() {
+            state.capacity = capacity;
+        }
+    }
+
+    /// Drop the data received and not read yet
+    pub fn clear(&self) -> io::Result<()> {
+        let mut state = self.lock()?;
+        state.data.clear();
+        state.ends.clear();
+        state.accept()
+    }
+
+    /// Read the next complete transfer, ended by the host with a short or
+    /// zero length packet, if one was received. Data of the transfer that
+    /// was already read with [Read::read] is not returned again.
+    pub fn read_transfer(&self) -> io::Result<Option<Vec<u8>>> {
+        let mut state = self.lock()?;
+        let Some(end) = state.ends.pop_front() else {
+            return Ok(None);
+        };
+        let data = state.data.drain(..end).collect();
+        consume(&mut state.ends, end);
+        state.accept()?;
+        Ok(Some(data))
+    }
+
+    pub(super) fn set_replies(&self, replies: Sender<Reply>) {
+        if let Ok(mut state) = self.lock() {
+            state.replies = Some(replies);
+        }
+    }
+
+    /// Queue the given OUT transfer and acknowledge it if there is room for
+    /// its data
+    pub(super) fn submit(&self, xfer: Xfer) -> Result<(), Box<dyn Error>> {
+        let mut state = self.lock()?;
+        state.waiting.push_back(xfer);
+        Ok(state.accept()?)
+    }
+
+    /// Drop the waiting transfer with the given sequence number. Returns
+    /// true if the transfer was waiting.
+    pub(super) fn unlink(&self, seqnum: u32) -> bool {
+        let Ok(mut state) = self.lock() else {
+            return false;
+        };
+        let len = state.waiting.len();
+        state.waiting.retain(|xfer| xfer.seqnum() != seqnum);
+        state.waiting.len() != len
+    }
+}
+
+impl Read for BulkOut {
+    /// Read the data received so far, up to the end of the current transfer
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.lock()?;
+        while state.ends.front() == Some(&0) {
+            state.ends.pop_front();
+        }
+        if state.data.is_empty() {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let available = state.ends.front().copied().unwrap_or(state.data.len());
+        let len = buf.len().min(available);
+        for (dst, src) in buf.iter_mut().zip(state.data.drain(..len)) {
+            *dst = src;
+        }
+        consume(&mut state.ends, len);
+        state.accept()?;
+        Ok(len)
+    }
+}
@@ -0,0 +1,346 @@
+//! Scheduling of interrupt IN transfers
+//!
+//! The host polls interrupt IN endpoints by keeping a URB pending on them.
+//! Real hardware only answers that URB once per polling interval, and HID
+//! devices additionally only send a report when its contents change or when
+//! the idle rate set with SET_IDLE expires. [InterruptScheduler] holds the
+//! pending IN transfers of each scheduled endpoint and completes them with
+//! the latest report state according to those rules.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::usbip::USBDeviceSpeed;
+
+use super::{Reply, Xfer};
+
+/// Length of a (full-speed) frame
+pub const FRAME: Duration = Duration::from_millis(1);
+/// Length of a (high-speed) micro-frame
+pub const MICROFRAME: Duration = Duration::from_micros(125);
+/// Unit of the HID idle rate (SET_IDLE/GET_IDLE duration)
+pub const IDLE_RATE_UNIT: Duration = Duration::from_millis(4);
+
+/// Returns the polling period of an interrupt endpoint with the given
+/// bInterval for a device operating at the given speed. Low and full speed
+/// devices express bInterval in frames (1-255), while high speed and faster
+/// devices express it as an exponent of micro-frames (2^(bInterval-1)).
+pub fn interrupt_period(speed: USBDeviceSpeed, b_interval: u8) -> Duration {
+    match speed {
+        USBDeviceSpeed::USBSpeedHigh
+        | USBDeviceSpeed::USBSpeedSuper
+        | USBDeviceSpeed::USBSpeedSuperPlus => {
+            let exponent = b_interval.clamp(1, 16) - 1;
+            MICROFRAME * (1u32 << exponent)
+        }
+        _ => FRAME * b_interval.max(1) as u32,
+    }
+}
+
+/// Latest state of a single report on a scheduled endpoint
+#[derive(Debug, Clone)]
+struct ScheduledReport {
+    report_id: u8,
+    /// The latest report data set by the user
+    data: Vec<u8>,
+    /// The last report data that was sent to the host
+    sent: Option<Vec<u8>>,
+    sent_at: Option<Instant>,
+    /// Idle rate of the report. None means the report is only sent when it
+    /// changes.
+    idle: Option<Duration>,
+}
+
+impl ScheduledReport {
+    /// Returns true if the report should be sent to the host. Reports
+    /// without data, such as those only given an idle rate, are never due.
+    fn is_due(&self, now: Instant) -> bool {
+        if self.data.is_empty() {
+            return false;
+        }
+        let (Some(sent), Some(sent_at)) = (self.sent.as_ref(), self.sent_at) else {
+            return true;
+        };
+        if *sent != self.data {
+            return true;
+        }
+        match self.idle {
+            Some(idle) => now >= sent_at + idle,
+            None => false,
+        }
+    }
+}
+
+/// An interrupt IN endpoint managed by the [InterruptScheduler]
+#[derive(Debug, Clone)]
+struct ScheduledEndpoint {
+    period: Duration,
+    next_poll: Instant,
+    /// Idle rate applied to reports that have not had their own idle set
+    default_idle: Option<Duration>,
+    reports: Vec<ScheduledReport>,
+    /// Reports that must be sent once each, in order, before any report state
+    queued: VecDeque<Vec<u8>>,
+    pending: VecDeque<Xfer>,
+}
+
+impl ScheduledEndpoint {
+    fn report_mut(&mut self, report_id: u8) -> &mut ScheduledReport {
+        let idx = match self.reports.iter().position(|r| r.report_id == report_id) {
+            Some(idx) => idx,
+            None => {
+                self.reports.push(ScheduledReport {
+                    report_id,
+                    data: Vec::new(),
+                    sent: None,
+                    sent_at: None,
+                    idle: self.default_idle,
+                });
+                self.reports.len() - 1
+            }
+        };
+        &mut self.reports[idx]
+    }
+}
+
+/// Completes pending interrupt IN transfers at the polling interval declared
+/// by the endpoint descriptor, using HID idle-rate semantics: a report is
+/// only sent if it changed since it was last sent, or if its idle rate
+/// expired.
+#[derive(Debug, Clone)]
+pub struct InterruptScheduler {
+    speed: USBDeviceSpeed,
+    endpoints: HashMap<u8, ScheduledEndpoint>,
+}
+
+impl InterruptScheduler {
+    /// Create a new scheduler for a device operating at the given speed
+    pub fn new(speed: USBDeviceSpeed) -> Self {
+        Self {
+            speed,
+            endpoints: HashMap::new(),
+        }
+    }
+
+    /// Start scheduling the given IN endpoint number with the given
+    /// bInterval from its endpoint descriptor.
+    pub fn add_endpoint(&mut self, ep: u8, b_interval: u8) {
+        let period = interrupt_period(self.speed, b_interval);
+        #[cfg(feature = "log")]
+        log::debug!("Scheduling endpoint {ep} with period {period:?}");
+        self.endpoints.insert(
+            ep,
+            ScheduledEndpoint {
+                period,
+                next_poll: Instant::now(),
+                default_idle: None,
+                reports: Vec::new(),
+                queued: VecDeque::new(),
+                pending: VecDeque::new(),
+            },
+        );
+    }
+
+    /// Returns true if the given endpoint number is managed by the scheduler
+    pub fn is_scheduled(&self, ep: u8) -> bool {
+        self.endpoints.contains_key(&ep)
+    }
+
+    /// Returns the polling period of the given endpoint
+    pub fn period(&self, ep: u8) -> Option<Duration> {
+        self.endpoints.get(&ep).map(|endpoint| endpoint.period)
+    }
+
+    /// Update the latest state of the given report on the given endpoint.
+    /// The report data should include the report ID byte if the interface
+    /// uses report IDs.
+    pub fn set_report(&mut self, ep: u8, report_id: u8, data: &[u8]) {
+        let Some(endpoint) = self.endpoints.get_mut(&ep) else {
+            return;
+        };
+        let report = endpoint.report_mut(report_id);
+        report.data.clear();
+        report.data.extend_from_slice(data);
+    }
+
+    /// Queue a report to be sent exactly once on the given endpoint, even if
+    /// it is identical to the previous report. Queued reports are sent in
+    /// order before any report state, which suits relative data such as
+    /// mouse motion.
+    pub fn queue_report(&mut self, ep: u8, data: &[u8]) {
+        let Some(endpoint) = self.endpoints.get_mut(&ep) else {
+            return;
+        };
+        endpoint.queued.push_back(data.to_vec());
+    }
+
+    /// Set the idle rate of the given report on the given endpoint. A report
+    /// ID of 0 applies the idle rate to all reports on the endpoint. An idle
+    /// rate of None means the report is only sent when it changes.
+    pub fn set_idle(&mut self, ep: u8, report_id: u8, idle: Option<Duration>) {
+        let Some(endpoint) = self.endpoints.get_mut(&ep) else {
+            return;
+        };
+        if report_id == 0 {
+            endpoint.default_idle = idle;
+            for report in endpoint.reports.iter_mut() {
+                report.idle = idle;
+            }
+            return;
+        }
+        endpoint.report_mut(report_id).idle = idle;
+    }
+
+    /// Returns the idle rate of the given report on the given endpoint
+    pub fn idle(&self, ep: u8, report_id: u8) -> Option<Duration> {
+        let endpoint = self.endpoints.get(&ep)?;
+        match endpoint.reports.iter().find(|r| r.report_id == report_id) {
+            Some(report) => report.idle,
+            None => endpoint.default_idle,
+        }
+    }
+
+    /// Queue the given IN transfer until a report is due on its endpoint.
+    /// Returns the transfer back if the endpoint is not scheduled.
+    pub fn submit(&mut self, xfer: Xfer) -> Option<Xfer> {
+        let Some(endpoint) = self.endpoints.get_mut(&xfer.ep) else {
+            return Some(xfer);
+        };
+        endpoint.pending.push_back(xfer);
+        None
+    }
+
+    /// Remove the pending transfer with the given sequence number. Returns
+    /// true if a transfer was removed.
+    pub fn unlink(&mut self, seqnum: u32) -> bool {
+        for endpoint in self.endpoints.values_mut() {
+            let position = endpoint
+                .pending
+                .iter()
+                .position(|xfer| xfer.seqnum() == seqnum);
+            if let Some(idx) = position {
+                endpoint.pending.remove(idx);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Complete all pending transfers that are due at the given time and
+    /// return the replies to send to the host. At most one transfer is
+    /// completed per endpoint per polling period.
+    pub fn poll(&mut self, now: Instant) -> Vec<Reply> {
+        let mut replies = Vec::new();
+        for endpoint in self.endpoints.values_mut() {
+            if endpoint.pending.is_empty() || now < endpoint.next_poll {
+                continue;
+            }
+            if let Some(data) = endpoint.queued.pop_front() {
+                let Some(xfer) = endpoint.pending.pop_front() else {
+                    continue;
+                };
+                // Never send more data than the host asked for
+                let length = xfer.buffer_length();
+                replies.push(Reply::from_xfer(xfer, &data[..data.len().min(length)]));
+            } else {
+                let Some(report) = endpoint.reports.iter_mut().find(|r| r.is_due(now)) else {
+                    continue;
+                };
+                let Some(xfer) = endpoint.pending.pop_front() else {
+                    continue;
+                };
+                let length = xfer.buffer_length();
+                let data = &report.data[..report.data.len().min(length)];
+                replies.push(Reply::from_xfer(xfer, data));
+                report.sent = Some(report.data.clone());
+                report.sent_at = Some(now);
+            }
+
+            // Avoid drifting from the polling interval, unless we fell behind
+            endpoint.next_poll += endpoint.period;
+            if endpoint.next_poll < now {
+                endpoint.next_poll = now + endpoint.period;
+            }
+        }
+
+        replies
+    }
+
+    /// Returns the next time the scheduler should be polled, or None if no
+    /// transfers are pending.
+    pub fn next_deadline(&self, now: Instant) -> Option<Instant> {
+        self.endpoints
+            .values()
+            .filter(|endpoint| !endpoint.pending.is_empty())
+            .map(|endpoint| {
+                if endpoint.next_poll > now {
+                    endpoint.next_poll
+                } else {
+                    now + endpoint.period
+                }
+            })
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use packed_struct::prelude::*;
+
+    use super::*;
+    use crate::usbip::{USBIPHeaderCmdSubmit, USBIP_CMD_SUBMIT};
+
+    const EP: u8 = 1;
+
+    /// Returns a pending interrupt IN transfer on the given endpoint
+    fn xfer(ep: u8, seqnum: u32) -> Xfer {
+        let mut data = [0; 48];
+        data[0..4].copy_from_slice(&USBIP_CMD_SUBMIT.to_be_bytes());
+        data[4..8].copy_from_slice(&seqnum.to_be_bytes());
+        data[12..16].copy_from_slice(&1u32.to_be_bytes());
+        data[16..20].copy_from_slice(&(ep as u32).to_be_bytes());
+        data[24..28].copy_from_slice(&64i32.to_be_bytes());
+        Xfer {
+            ep,
+            data: Vec::new(),
+            cmd: USBIPHeaderCmdSubmit::unpack(&data).unwrap(),
+            iso_packets: Vec::new(),
+        }
+    }
+
+    fn scheduler() -> InterruptScheduler {
+        let mut scheduler = InterruptScheduler::new(USBDeviceSpeed::USBSpeedFull);
+        scheduler.add_endpoint(EP, 1);
+        scheduler
+    }
+
+    #[test]
+    fn set_idle_before_set_report_sends_nothing() {
+        let mut scheduler = scheduler();
+        scheduler.set_idle(EP, 2, Some(IDLE_RATE_UNIT));
+        assert!(scheduler.submit(xfer(EP, 1)).is_none());
+        let now = Instant::now();
+        assert!(scheduler.poll(now).is_empty());
+
+        scheduler.set_report(EP, 2, &[2, 0x55]);
+        let replies = scheduler.poll(now + FRAME);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].payload, [2, 0x55]);
+    }
+
+    #[test]
+    fn unchanged_report_is_sent_when_idle_rate_expires() {
+        let mut scheduler = scheduler();
+        scheduler.set_report(EP, 0, &[1, 2, 3]);
+        scheduler.set_idle(EP, 0, Some(IDLE_RATE_UNIT));
+        let now = Instant::now();
+        scheduler.submit(xfer(EP, 1));
+        assert_eq!(scheduler.poll(now).len(), 1);
+
+        scheduler.submit(xfer(EP, 2));
+        assert!(scheduler.poll(now + FRAME).is_empty());
+        assert_eq!(scheduler.poll(now + IDLE_RATE_UNIT).len(), 1);
+    }
+}